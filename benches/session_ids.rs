@@ -0,0 +1,76 @@
+// Generates session ids from several threads, once with the RNG behind an
+// async RwLock the way post_new_session used to do it and once sharing the
+// SystemRandom directly. The shared variant should scale with threads while
+// the locked one pays for the lock on every id.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use tk_auth::session::SessionIdFormat;
+use tokio::sync::RwLock as TokioRwLock;
+
+const IDS_PER_THREAD: usize = 100_000;
+
+fn measure<F, Fut>(name: &str, threads: usize, generate: F) -> f64
+where
+    F: Fn() -> Fut + Clone + Send + 'static,
+    Fut: std::future::Future<Output = ()> + Send,
+{
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(threads)
+        .build()
+        .unwrap();
+    let started = Instant::now();
+    runtime.block_on(async {
+        let tasks: Vec<_> = (0..threads)
+            .map(|_| {
+                let generate = generate.clone();
+                tokio::spawn(async move {
+                    for _ in 0..IDS_PER_THREAD {
+                        generate().await;
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+    });
+    let per_sec = (threads * IDS_PER_THREAD) as f64 / started.elapsed().as_secs_f64();
+    println!(
+        "{:>8}, {:>2} threads: {:>12.0} ids/s",
+        name, threads, per_sec
+    );
+    per_sec
+}
+
+fn main() {
+    let format = Arc::new(SessionIdFormat::new(32, vec![], vec![(0, vec![7; 32])], 0));
+    let shared = Arc::new(ring::rand::SystemRandom::new());
+    let locked = Arc::new(TokioRwLock::new(ring::rand::SystemRandom::new()));
+
+    let max_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut threads = 1;
+    while threads <= max_threads.max(4) {
+        let with_lock = {
+            let (format, locked) = (format.clone(), locked.clone());
+            measure("locked", threads, move || {
+                let (format, locked) = (format.clone(), locked.clone());
+                async move {
+                    std::hint::black_box(format.generate(&(*locked.read().await)));
+                }
+            })
+        };
+        let without_lock = {
+            let (format, shared) = (format.clone(), shared.clone());
+            measure("shared", threads, move || {
+                let (format, shared) = (format.clone(), shared.clone());
+                async move {
+                    std::hint::black_box(format.generate(&*shared));
+                }
+            })
+        };
+        println!("{:>22} {:.2}x", "speedup:", without_lock / with_lock);
+        threads *= 2;
+    }
+}
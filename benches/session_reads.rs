@@ -0,0 +1,89 @@
+// Looks up sessions the way lookup_session does, from an increasing number
+// of threads: first spread over many sessions, which take different shard
+// locks, then all of the same session, which only takes its read lock while
+// nothing has to be written. Throughput should grow with the thread count
+// up to the number of cores in both cases instead of staying flat.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use tk_auth::config::ExpiryPolicy;
+use tk_auth::session::{Session, SessionIdFormat, SessionStore};
+
+const SESSIONS: usize = 10_000;
+const LOOKUPS_PER_THREAD: usize = 200_000;
+
+fn main() {
+    let rng = ring::rand::SystemRandom::new();
+    let format = Arc::new(SessionIdFormat::new(32, vec![], vec![(0, vec![7; 32])], 0));
+    let store = Arc::new(SessionStore::new());
+    let ids: Arc<Vec<String>> = Arc::new(
+        (0..SESSIONS)
+            .map(|_| {
+                let id = format.generate(&rng);
+                let encoded = String::from(&id);
+                let mut session = Session::new([127, 0, 0, 1].into(), None, 0);
+                session.set_expiry(
+                    ExpiryPolicy {
+                        idle_timeout_secs: Some(1800),
+                        absolute_lifetime_secs: None,
+                    },
+                    0,
+                );
+                store.insert(id, session);
+                encoded
+            })
+            .collect(),
+    );
+
+    for spread in [SESSIONS, 1] {
+        println!("{} sessions:", spread);
+        run(&format, &store, &ids, spread);
+    }
+}
+
+fn run(
+    format: &Arc<SessionIdFormat>,
+    store: &Arc<SessionStore>,
+    ids: &Arc<Vec<String>>,
+    spread: usize,
+) {
+    let max_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut baseline = None;
+    let mut threads = 1;
+    while threads <= max_threads.max(4) {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(threads)
+            .build()
+            .unwrap();
+        let started = Instant::now();
+        runtime.block_on(async {
+            let tasks: Vec<_> = (0..threads)
+                .map(|task| {
+                    let (format, store, ids) = (format.clone(), store.clone(), ids.clone());
+                    tokio::spawn(async move {
+                        for i in 0..LOOKUPS_PER_THREAD {
+                            let id = format.parse(&ids[(i * 7919 + task) % spread]).unwrap();
+                            let session = store.get(&id).unwrap();
+                            // Lookups within a few seconds, as under load.
+                            assert!(store.access(&id, &session, (i / 50_000) as u64).await);
+                            std::hint::black_box(session.read().await.etag());
+                        }
+                    })
+                })
+                .collect();
+            for task in tasks {
+                task.await.unwrap();
+            }
+        });
+        let per_sec = (threads * LOOKUPS_PER_THREAD) as f64 / started.elapsed().as_secs_f64();
+        let baseline = *baseline.get_or_insert(per_sec);
+        println!(
+            "{:>2} threads: {:>12.0} lookups/s ({:.2}x)",
+            threads,
+            per_sec,
+            per_sec / baseline
+        );
+        threads *= 2;
+    }
+}
@@ -0,0 +1,176 @@
+// Session store benchmarks at 10k, 100k and 1M sessions:
+//
+//     cargo bench --bench store [-- FILTER]
+//
+// FILTER runs only the cases whose name contains it, e.g. `lookup` or
+// `/1000000`. TK_AUTH_BENCH_SIZES overrides the store sizes as a comma
+// separated list. Results are kept in target/bench-store.json and every run
+// prints how each case changed since the previous one.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tk_auth::config::ExpiryPolicy;
+use tk_auth::session::{Session, SessionEvent, SessionIdFormat, SessionStore};
+use tk_auth::time;
+
+const DEFAULT_SIZES: [usize; 3] = [10_000, 100_000, 1_000_000];
+const OPERATIONS: usize = 100_000;
+const EXPIRED_PERCENT: usize = 1;
+
+struct Report {
+    filter: Option<String>,
+    previous: BTreeMap<String, f64>,
+    results: BTreeMap<String, f64>,
+}
+
+impl Report {
+    fn wants(&self, name: &str) -> bool {
+        self.filter
+            .as_deref()
+            .is_none_or(|filter| name.contains(filter))
+    }
+
+    fn record(&mut self, name: &str, elapsed: Duration, operations: usize, unit: &str) {
+        let nanos = elapsed.as_nanos() as f64 / operations as f64;
+        let change = match self.previous.get(name) {
+            Some(previous) => format!("{:+6.1}% vs last run", (nanos / previous - 1.0) * 100.0),
+            None => String::from("no previous run"),
+        };
+        println!("{:<24} {:>10.1} ns/{:<8} {}", name, nanos, unit, change);
+        self.results.insert(name.to_string(), nanos);
+    }
+}
+
+fn baseline_path() -> PathBuf {
+    let target = std::env::var_os("CARGO_TARGET_DIR").unwrap_or_else(|| "target".into());
+    PathBuf::from(target).join("bench-store.json")
+}
+
+fn sizes() -> Vec<usize> {
+    match std::env::var("TK_AUTH_BENCH_SIZES") {
+        Ok(sizes) => sizes
+            .split(',')
+            .map(|size| size.trim().parse().expect("invalid TK_AUTH_BENCH_SIZES"))
+            .collect(),
+        Err(_) => DEFAULT_SIZES.to_vec(),
+    }
+}
+
+// Picks ids spread over the whole store without the cost of an RNG call.
+fn pick(ids: &[String], i: usize) -> &str {
+    &ids[i.wrapping_mul(7919) % ids.len()]
+}
+
+async fn run_size(report: &mut Report, size: usize) {
+    let rng = ring::rand::SystemRandom::new();
+    let format = Arc::new(SessionIdFormat::new(32, vec![], vec![(0, vec![7; 32])], 0));
+    let store = SessionStore::new();
+    let mut ids = Vec::with_capacity(size);
+
+    // The store has to be filled for the other cases anyway, so creation is
+    // always measured and only its report is filtered.
+    let started = Instant::now();
+    for _ in 0..size {
+        let id = format.generate(&rng);
+        ids.push(String::from(&id));
+        store.insert(id, Session::new([127, 0, 0, 1].into(), None, 0));
+    }
+    let name = format!("create/{}", size);
+    if report.wants(&name) {
+        report.record(&name, started.elapsed(), size, "session");
+    }
+
+    let name = format!("lookup/{}", size);
+    if report.wants(&name) {
+        let started = Instant::now();
+        for i in 0..OPERATIONS {
+            let id = format.parse(pick(&ids, i)).unwrap();
+            let session = store.get(&id).unwrap();
+            std::hint::black_box(session.read().await.etag());
+        }
+        report.record(&name, started.elapsed(), OPERATIONS, "lookup");
+    }
+
+    let name = format!("authenticate/{}", size);
+    if report.wants(&name) {
+        let policy = ExpiryPolicy {
+            idle_timeout_secs: Some(1800),
+            absolute_lifetime_secs: Some(43200),
+        };
+        let started = Instant::now();
+        for i in 0..OPERATIONS {
+            let id = format.parse(pick(&ids, i)).unwrap();
+            let session = store.get(&id).unwrap();
+            let now = time::now_secs();
+            let mut session = session.write().await;
+            session.authenticated = true;
+            session.authenticated_at = Some(now);
+            session.user = Some(format!("user{}", i % 1000));
+            session.set_expiry(policy, now);
+            session.publish(SessionEvent::Authenticated {
+                user: String::from("user"),
+            });
+        }
+        report.record(&name, started.elapsed(), OPERATIONS, "login");
+    }
+
+    let name = format!("expiry_sweep/{}", size);
+    if report.wants(&name) {
+        let expired = ExpiryPolicy {
+            idle_timeout_secs: None,
+            absolute_lifetime_secs: Some(1),
+        };
+        let now = time::now_secs();
+        for id in ids.iter().step_by(100 / EXPIRED_PERCENT) {
+            let id = format.parse(id).unwrap();
+            let session = store.get(&id).unwrap();
+            let mut session = session.write().await;
+            session.set_expiry(expired, now - 10);
+            store.schedule_expiry(&id, session.expires_at.unwrap());
+        }
+        // Sweeps only visit due sessions, so the cost is per expired session
+        // and should stay flat as the store grows.
+        let started = Instant::now();
+        let removed = store.remove_expired(now).await;
+        let elapsed = started.elapsed();
+        assert_eq!(removed.len(), size.div_ceil(100 / EXPIRED_PERCENT));
+        report.record(&name, elapsed, removed.len(), "expired");
+    }
+}
+
+fn main() {
+    // cargo passes --bench to harness-less benchmarks, anything else that
+    // isn't a flag is a filter.
+    let filter = std::env::args().skip(1).find(|arg| !arg.starts_with('-'));
+    let previous = std::fs::read(baseline_path())
+        .ok()
+        .and_then(|previous| serde_json::from_slice(&previous).ok())
+        .unwrap_or_default();
+    let mut report = Report {
+        filter,
+        previous,
+        results: BTreeMap::new(),
+    };
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    for size in sizes() {
+        runtime.block_on(run_size(&mut report, size));
+    }
+
+    // Cases that didn't run this time keep their previous result.
+    let mut saved = report.previous;
+    saved.extend(report.results);
+    let saved = serde_json::to_vec_pretty(&saved).unwrap();
+    if let Err(err) = std::fs::write(baseline_path(), saved) {
+        println!(
+            "Failed to save results to {}: {}",
+            baseline_path().display(),
+            err
+        );
+    }
+}
@@ -0,0 +1,290 @@
+use std::sync::Arc;
+
+use subtle::ConstantTimeEq;
+
+use crate::client_info::ClientInfo;
+use crate::config;
+use crate::i18n;
+use crate::import;
+use crate::legacy_hash;
+use crate::session::{self, Session};
+use crate::{
+    enforce_memory_budget, error_response, expiry_policy, json_response, message_error_response,
+    sweep_expired, AppState, SESSION_STORE_FULL,
+};
+
+const MAX_BATCH_SESSIONS: usize = 10_000;
+
+pub fn authorized(state: &AppState, headers: &http::HeaderMap) -> bool {
+    let Some(admin) = &state.config.admin else {
+        return false;
+    };
+    let token = headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    bool::from(token.as_bytes().ct_eq(admin.bearer_token.as_bytes()))
+        || state
+            .request_signing
+            .verified(headers, config::SigningScope::Admin)
+}
+
+#[derive(serde::Deserialize)]
+pub struct ImportUsersQuery {
+    #[serde(default)]
+    dry_run: bool,
+    format: Option<String>,
+}
+
+pub async fn post_import_users(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(query): axum::extract::Query<ImportUsersQuery>,
+    headers: http::HeaderMap,
+    body: axum::body::Bytes,
+) -> axum::response::Response {
+    if state.config.admin.is_none() {
+        return error_response(404, "the admin API is not enabled");
+    }
+    if !authorized(&state, &headers) {
+        return error_response(401, "invalid admin token");
+    }
+    let content_type = headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(';').next().unwrap_or_default().trim());
+    let Some(format) = query
+        .format
+        .as_deref()
+        .or(content_type)
+        .and_then(import::Format::parse)
+    else {
+        return error_response(
+            415,
+            "send text/csv or application/json, or set format=csv|json",
+        );
+    };
+    let rows = match import::parse_rows(format, &body) {
+        Ok(rows) => rows,
+        Err(err) => return error_response(400, &err),
+    };
+
+    let report = if query.dry_run {
+        let mut data = state.users.read().await.clone();
+        import::apply(&mut data, rows, true)
+    } else {
+        match state
+            .users
+            .update(|data| Ok(import::apply(data, rows, false)))
+            .await
+        {
+            Ok(report) => report,
+            Err(err) => {
+                println!("Failed to save imported users: {}", err);
+                return error_response(500, "failed to save imported users");
+            }
+        }
+    };
+    if !query.dry_run {
+        println!(
+            "Imported users: {} created, {} updated, {} unchanged, {} failed",
+            report.created, report.updated, report.unchanged, report.failed
+        );
+    }
+    json_response(200, serde_json::to_value(report).unwrap())
+}
+
+pub async fn get_password_migration(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: http::HeaderMap,
+) -> axum::response::Response {
+    if state.config.admin.is_none() {
+        return error_response(404, "the admin API is not enabled");
+    }
+    if !authorized(&state, &headers) {
+        return error_response(401, "invalid admin token");
+    }
+    let data = state.users.read().await;
+    let mut legacy = std::collections::BTreeMap::<&str, usize>::new();
+    let mut pending = Vec::new();
+    let mut with_password = 0;
+    for user in data.users.values() {
+        let Some(password_hash) = &user.password_hash else {
+            continue;
+        };
+        with_password += 1;
+        if let Some(scheme) = legacy_hash::scheme(password_hash) {
+            *legacy.entry(scheme).or_default() += 1;
+            pending.push(serde_json::json!({
+                "user_name": user.user_name,
+                "scheme": scheme,
+            }));
+        }
+    }
+    let migrated = data
+        .users
+        .values()
+        .filter(|user| user.password_migrated_at.is_some())
+        .count();
+    json_response(
+        200,
+        serde_json::json!({
+            "users_with_password": with_password,
+            "legacy": legacy,
+            "migrated_on_login": migrated,
+            "pending": pending,
+        }),
+    )
+}
+
+// Runs the expiry sweep, and compacts the session journal into a snapshot
+// when sessions.persistence is on, instead of waiting for their intervals.
+pub async fn post_maintenance(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: http::HeaderMap,
+) -> axum::response::Response {
+    if state.config.admin.is_none() {
+        return error_response(404, "the admin API is not enabled");
+    }
+    if !authorized(&state, &headers) {
+        return error_response(401, "invalid admin token");
+    }
+    let started = std::time::Instant::now();
+    let expired = sweep_expired(&state).await;
+    let sweep_secs = started.elapsed().as_secs_f64();
+    let compaction = match state.sessions.request_compaction() {
+        Some(reply) => {
+            let started = std::time::Instant::now();
+            match reply.await {
+                Ok(Ok(sessions)) => Some(serde_json::json!({
+                    "sessions": sessions,
+                    "duration_secs": started.elapsed().as_secs_f64(),
+                })),
+                _ => return error_response(500, "failed to compact the session journal"),
+            }
+        }
+        None => None,
+    };
+    println!("Ran storage maintenance on request");
+    json_response(
+        200,
+        serde_json::json!({
+            "sweep": {
+                "expired_sessions": expired,
+                "duration_secs": sweep_secs,
+            },
+            "compaction": compaction,
+        }),
+    )
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchSessionsRequest {
+    count: Option<usize>,
+    #[serde(default)]
+    sessions: Vec<BatchSession>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchSession {
+    user: Option<String>,
+    description: Option<String>,
+}
+
+pub async fn post_batch_sessions(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    headers: http::HeaderMap,
+    body: axum::body::Bytes,
+) -> axum::response::Response {
+    if state.config.admin.is_none() {
+        return error_response(404, "the admin API is not enabled");
+    }
+    if !authorized(&state, &headers) {
+        return error_response(401, "invalid admin token");
+    }
+    let request: BatchSessionsRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(err) => {
+            return message_error_response(
+                400,
+                i18n::Message {
+                    template: "invalid batch: {error}",
+                    args: vec![("error", err.to_string())],
+                },
+            )
+        }
+    };
+    let batch = match (request.count, request.sessions) {
+        (Some(count), sessions) if sessions.is_empty() => (0..count)
+            .map(|_| BatchSession {
+                user: None,
+                description: None,
+            })
+            .collect(),
+        (None, sessions) if !sessions.is_empty() => sessions,
+        _ => return error_response(400, "send either count or a list of sessions"),
+    };
+    if batch.len() > MAX_BATCH_SESSIONS {
+        return message_error_response(
+            400,
+            i18n::Message {
+                template: "at most {max} sessions per batch",
+                args: vec![("max", MAX_BATCH_SESSIONS.to_string())],
+            },
+        );
+    }
+
+    let now = state.clock.now_secs();
+    let mut sessions = Vec::with_capacity(batch.len());
+    let mut ids = Vec::with_capacity(batch.len());
+    for entry in batch {
+        let mut session = Session::new(client.ip, None, now);
+        if let Some(description) = entry.description {
+            let description = description.trim();
+            if !session::valid_description(description) {
+                return message_error_response(
+                    400,
+                    i18n::Message {
+                        template:
+                            "descriptions must be 1 to {max} characters without control characters",
+                        args: vec![("max", session::MAX_DESCRIPTION_CHARS.to_string())],
+                    },
+                );
+            }
+            session.description = description.to_string();
+        }
+        if let Some(user) = entry.user {
+            if !state.users.is_active(&user).await {
+                return message_error_response(
+                    400,
+                    i18n::Message {
+                        template: "user {user} is deactivated",
+                        args: vec![("user", user.to_string())],
+                    },
+                );
+            }
+            session.set_expiry(expiry_policy(&state, Some(&user)).await, now);
+            session.authenticated = true;
+            session.authenticated_at = Some(now);
+            session.user = Some(user);
+        } else {
+            session.set_expiry(expiry_policy(&state, None).await, now);
+        }
+        let id = state.session_ids().generate(&state.rng);
+        ids.push(String::from(&id));
+        sessions.push((id, session));
+    }
+    let bytes = sessions
+        .iter()
+        .map(|(_, session)| session.approx_bytes())
+        .sum();
+    if !enforce_memory_budget(&state, bytes).await {
+        return SESSION_STORE_FULL.response();
+    }
+    state.sessions.insert_batch(sessions);
+    println!("Created {} sessions in a batch", ids.len());
+    json_response(200, serde_json::json!({ "ids": ids }))
+}
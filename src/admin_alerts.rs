@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use crate::config;
+use crate::http_client;
+use crate::AppState;
+
+// Addresses remembered per privileged user; past this the oldest is
+// forgotten.
+const MAX_IPS_PER_USER: usize = 50;
+
+struct FailedSignIns {
+    window_start: u64,
+    count: u32,
+}
+
+// Remembered addresses live in this instance's memory, so after a restart
+// every privileged user's first sign-in only teaches it their address again.
+#[derive(Default)]
+pub struct AdminAlerts {
+    config: Option<config::AdminAlertsConfig>,
+    client: Option<http_client::Client>,
+    failed_sign_ins: Mutex<Option<FailedSignIns>>,
+    privileged_ips: Mutex<HashMap<String, Vec<IpAddr>>>,
+}
+
+impl AdminAlerts {
+    pub fn new(config: &config::Config) -> io::Result<Self> {
+        let Some(alerts) = &config.admin_alerts else {
+            return Ok(Self::default());
+        };
+        Ok(Self {
+            config: Some(alerts.clone()),
+            client: Some(http_client::Client::new(&config.secrets.ca_path)?),
+            ..Self::default()
+        })
+    }
+
+    pub fn failed_sign_in(&self, now: u64) {
+        let Some(rule) = self
+            .config
+            .as_ref()
+            .and_then(|config| config.failed_sign_ins.as_ref())
+        else {
+            return;
+        };
+        let count = {
+            let mut failed = self.failed_sign_ins.lock().unwrap();
+            let failed = match &mut *failed {
+                Some(failed) if now < failed.window_start + rule.window_secs => failed,
+                failed => failed.insert(FailedSignIns {
+                    window_start: now,
+                    count: 0,
+                }),
+            };
+            failed.count += 1;
+            failed.count
+        };
+        if count == rule.threshold {
+            self.post(
+                "Failed sign-ins",
+                format!(
+                    "{} failed password sign-ins in less than {} seconds",
+                    count, rule.window_secs
+                ),
+            );
+        }
+    }
+
+    fn new_privileged_ip(&self, user: &str, ip: IpAddr) -> bool {
+        let mut users = self.privileged_ips.lock().unwrap();
+        let ips = users.entry(user.to_string()).or_default();
+        if ips.contains(&ip) {
+            return false;
+        }
+        if ips.len() >= MAX_IPS_PER_USER {
+            ips.remove(0);
+        }
+        ips.push(ip);
+        // The first address is only learned.
+        ips.len() > 1
+    }
+
+    // Posts to every webhook in the background.
+    fn post(&self, title: &str, text: String) {
+        let (Some(config), Some(client)) = (&self.config, &self.client) else {
+            return;
+        };
+        println!("Admin alert: {}: {}", title, text);
+        for webhook in &config.webhooks {
+            let body = message(webhook.kind, title, &text).to_string();
+            let client = client.clone();
+            let url = webhook.url.clone();
+            tokio::spawn(async move {
+                let response = client
+                    .request(
+                        "POST",
+                        &url,
+                        &[("Content-Type", "application/json")],
+                        body.as_bytes(),
+                    )
+                    .await;
+                match response {
+                    Ok(response) if (200..300).contains(&response.status) => {}
+                    Ok(response) => println!(
+                        "Posting admin alert to {} failed: status {}",
+                        url, response.status
+                    ),
+                    Err(err) => println!("Posting admin alert to {} failed: {}", url, err),
+                }
+            });
+        }
+    }
+}
+
+// Called after every authentication; alerts when a user in one of the
+// privileged roles signs in from an address they weren't seen at before.
+pub async fn signed_in(state: &AppState, user: &str, ip: IpAddr) {
+    let alerts = &state.admin_alerts;
+    let Some(rule) = alerts
+        .config
+        .as_ref()
+        .and_then(|config| config.privileged_sign_ins.as_ref())
+    else {
+        return;
+    };
+    let roles = state.users.roles(user).await;
+    if !roles.iter().any(|role| rule.roles.contains(role)) {
+        return;
+    }
+    if alerts.new_privileged_ip(user, ip) {
+        alerts.post(
+            "Privileged sign-in from a new address",
+            format!("{} ({}) signed in from {}", user, roles.join(", "), ip),
+        );
+    }
+}
+
+// Slack and Mattermost take the same incoming webhook JSON.
+fn message(kind: config::AlertWebhookKind, title: &str, text: &str) -> serde_json::Value {
+    match kind {
+        config::AlertWebhookKind::Slack => {
+            serde_json::json!({ "text": format!("*{}*\n{}", title, text) })
+        }
+        config::AlertWebhookKind::Mattermost => {
+            serde_json::json!({ "text": format!("**{}**\n{}", title, text) })
+        }
+        config::AlertWebhookKind::Discord => {
+            serde_json::json!({ "content": format!("**{}**\n{}", title, text) })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn learns_the_first_address_and_alerts_on_later_ones() {
+        let alerts = AdminAlerts::default();
+        let ip = |ip: &str| ip.parse().unwrap();
+        assert!(!alerts.new_privileged_ip("root", ip("192.0.2.1")));
+        assert!(!alerts.new_privileged_ip("root", ip("192.0.2.1")));
+        assert!(alerts.new_privileged_ip("root", ip("192.0.2.2")));
+        assert!(!alerts.new_privileged_ip("root", ip("192.0.2.2")));
+        assert!(!alerts.new_privileged_ip("zed", ip("192.0.2.2")));
+        assert_eq!(
+            message(config::AlertWebhookKind::Discord, "Title", "text"),
+            serde_json::json!({ "content": "**Title**\ntext" })
+        );
+    }
+}
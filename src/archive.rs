@@ -0,0 +1,348 @@
+use std::io;
+use std::path::Path;
+
+use zeroize::Zeroizing;
+
+use crate::config;
+use crate::encryption::{self, Encryption};
+use crate::persistence;
+use crate::secrets;
+use crate::session::SavedSession;
+use crate::time;
+use crate::users::{self, UserData};
+
+const FORMAT: &str = "tk-auth-archive";
+// Archives of later versions are refused; earlier ones are read as they
+// were written.
+const VERSION: u32 = 1;
+const SEALED_CONTEXT: &str = "tk-auth archive";
+
+// A copy of the users, their groups (which are their roles) and the active
+// sessions, to move them to another deployment or storage backend. Clients
+// such as request signing keys and identity providers are configured in the
+// config file rather than stored, so they move with it.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Archive {
+    format: String,
+    version: u32,
+    created_at: String,
+    // Either the contents, or with a key file, the contents encrypted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    contents: Option<Contents>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sealed: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Contents {
+    users: UserData,
+    #[serde(default)]
+    sessions: Vec<ArchivedSession>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ArchivedSession {
+    id: String,
+    session: SavedSession,
+}
+
+fn invalid_data(path: &str, err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, err))
+}
+
+// Archives are encrypted with a key of their own rather than the
+// deployment's, so they can be read by another one.
+pub fn read_key(path: &Path) -> io::Result<Encryption> {
+    let secret_base64 = Zeroizing::new(
+        std::fs::read_to_string(path)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?,
+    );
+    let secret = config::SecretKeyConfig::decode_secret("--key-file", 0, &secret_base64)
+        .map_err(|err| invalid_data(&path.display().to_string(), err))?;
+    Ok(Encryption::new(vec![(0, secret)], 0))
+}
+
+fn seal(contents: Contents, key: Option<&Encryption>, now: u64) -> Archive {
+    let mut archive = Archive {
+        format: String::from(FORMAT),
+        version: VERSION,
+        created_at: time::rfc3339(now),
+        contents: None,
+        sealed: None,
+    };
+    match key {
+        Some(key) => {
+            let plaintext = Zeroizing::new(serde_json::to_vec(&contents).unwrap());
+            archive.sealed = Some(key.seal(SEALED_CONTEXT, &plaintext));
+        }
+        None => archive.contents = Some(contents),
+    }
+    archive
+}
+
+// The archive of the users and sessions as written to a file.
+pub fn write(
+    users: UserData,
+    sessions: Vec<(String, SavedSession)>,
+    key: Option<&Encryption>,
+    now: u64,
+) -> Vec<u8> {
+    let contents = Contents {
+        users,
+        sessions: sessions
+            .into_iter()
+            .map(|(id, session)| ArchivedSession { id, session })
+            .collect(),
+    };
+    serde_json::to_vec(&seal(contents, key, now)).unwrap()
+}
+
+// The users and sessions of an archive `write` made.
+pub fn read(
+    archive: &[u8],
+    key: Option<&Encryption>,
+) -> Result<(UserData, Vec<(String, SavedSession)>), String> {
+    let archive: Archive = serde_json::from_slice(archive).map_err(|err| err.to_string())?;
+    let contents = open(archive, key)?;
+    let sessions = contents
+        .sessions
+        .into_iter()
+        .map(|archived| (archived.id, archived.session))
+        .collect();
+    Ok((contents.users, sessions))
+}
+
+fn open(archive: Archive, key: Option<&Encryption>) -> Result<Contents, String> {
+    if archive.format != FORMAT {
+        return Err(String::from("not a tk-auth archive"));
+    }
+    if archive.version > VERSION {
+        return Err(format!(
+            "archive version {} is newer than this tk-auth reads ({})",
+            archive.version, VERSION
+        ));
+    }
+    match (archive.contents, archive.sealed, key) {
+        (Some(contents), None, _) => Ok(contents),
+        (None, Some(sealed), Some(key)) => {
+            let opened = key.open(SEALED_CONTEXT, &sealed).map_err(|_| {
+                String::from("the archive could not be decrypted with the key file")
+            })?;
+            serde_json::from_slice(&opened.plaintext).map_err(|err| err.to_string())
+        }
+        (None, Some(_), None) => Err(String::from(
+            "the archive is encrypted, but no key file was given",
+        )),
+        _ => Err(String::from("the archive needs one of contents or sealed")),
+    }
+}
+
+struct Args {
+    key_file: Option<String>,
+    dry_run: bool,
+    force: bool,
+    path: String,
+}
+
+fn parse_args(
+    mut args: impl Iterator<Item = String>,
+    usage: impl Fn() -> io::Error,
+    allow_import_flags: bool,
+) -> io::Result<Args> {
+    let mut key_file = None;
+    let mut dry_run = false;
+    let mut force = false;
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--key-file" => key_file = Some(args.next().ok_or_else(&usage)?),
+            "--dry-run" if allow_import_flags => dry_run = true,
+            "--force" if allow_import_flags => force = true,
+            "--config" => {
+                args.next();
+            }
+            arg if arg.starts_with("--config=") => {}
+            arg if !arg.starts_with("--") && path.is_none() => path = Some(arg.to_string()),
+            _ => return Err(usage()),
+        }
+    }
+    Ok(Args {
+        key_file,
+        dry_run,
+        force,
+        path: path.ok_or_else(usage)?,
+    })
+}
+
+// The stores are read and written with the deployment's encryption keys.
+pub async fn store_encryption(
+    config: &config::Config,
+) -> io::Result<Option<std::sync::Arc<Encryption>>> {
+    let secret_provider = secrets::SecretProvider::new(&config.secrets)?;
+    encryption::Encryption::from_config(config, &secret_provider).await
+}
+
+pub fn users_path(config: &config::Config) -> io::Result<&Path> {
+    config.users.path.as_deref().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "archives need users.path to be configured",
+        )
+    })
+}
+
+pub async fn run_export(
+    config: &config::Config,
+    args: impl Iterator<Item = String>,
+) -> io::Result<()> {
+    let usage = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "usage: tk-auth export [--config PATH] [--key-file PATH] FILE",
+        )
+    };
+    let args = parse_args(args, usage, false)?;
+    let key = args
+        .key_file
+        .as_deref()
+        .map(Path::new)
+        .map(read_key)
+        .transpose()?;
+    let encryption = store_encryption(config).await?;
+    let (users, _) = users::read_file(users_path(config)?, encryption.as_deref())?;
+    let now = time::now_secs();
+    let sessions = if config.sessions.persistence.is_some() {
+        persistence::load(config, encryption.as_deref(), now)?
+    } else {
+        println!("sessions.persistence is off, so no sessions are exported");
+        Vec::new()
+    };
+    let summary = format!(
+        "{} users, {} groups and {} sessions",
+        users.users.len(),
+        users.groups.len(),
+        sessions.len()
+    );
+    let archive = write(users, sessions, key.as_ref(), now);
+    users::write_private(Path::new(&args.path), &archive)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", args.path, err)))?;
+    println!(
+        "Exported {} to {}{}",
+        summary,
+        args.path,
+        if key.is_some() { ", encrypted" } else { "" }
+    );
+    Ok(())
+}
+
+// Replaces the users and sessions with the archive's. The server has to be
+// stopped meanwhile, or it would write over them.
+pub async fn run_import(
+    config: &config::Config,
+    args: impl Iterator<Item = String>,
+) -> io::Result<()> {
+    let usage = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "usage: tk-auth import [--config PATH] [--key-file PATH] [--dry-run] [--force] FILE",
+        )
+    };
+    let args = parse_args(args, usage, true)?;
+    let key = args
+        .key_file
+        .as_deref()
+        .map(Path::new)
+        .map(read_key)
+        .transpose()?;
+    let contents = std::fs::read(&args.path)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", args.path, err)))?;
+    let archive: Archive =
+        serde_json::from_slice(&contents).map_err(|err| invalid_data(&args.path, err))?;
+    let created_at = archive.created_at.clone();
+    let contents = open(archive, key.as_ref()).map_err(|err| invalid_data(&args.path, err))?;
+
+    let encryption = store_encryption(config).await?;
+    let path = users_path(config)?;
+    let (existing, _) = users::read_file(path, encryption.as_deref())?;
+    let now = time::now_secs();
+    let existing_sessions = if config.sessions.persistence.is_some() {
+        persistence::load(config, encryption.as_deref(), now)?.len()
+    } else {
+        0
+    };
+    let sessions: Vec<(String, SavedSession)> = contents
+        .sessions
+        .into_iter()
+        .filter(|archived| !archived.session.is_expired(now))
+        .map(|archived| (archived.id, archived.session))
+        .collect();
+    println!(
+        "{}{} users, {} groups and {} unexpired sessions from an archive of {}, replacing {} \
+         users and {} sessions",
+        if args.dry_run { "Dry run: " } else { "" },
+        contents.users.users.len(),
+        contents.users.groups.len(),
+        sessions.len(),
+        created_at,
+        existing.users.len(),
+        existing_sessions
+    );
+    if config.sessions.persistence.is_none() && !sessions.is_empty() {
+        println!("sessions.persistence is off, so the sessions are skipped");
+    }
+    if args.dry_run {
+        return Ok(());
+    }
+    if !args.force
+        && (!existing.users.is_empty() || !existing.groups.is_empty() || existing_sessions > 0)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "there are users or sessions already, give --force to replace them",
+        ));
+    }
+    users::write_file(path, &contents.users, encryption.as_deref())
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+    if config.sessions.persistence.is_some() {
+        persistence::replace(config, encryption.as_deref(), &sessions, now)?;
+    }
+    // Session ids are signed, so they only work with the same keys.
+    println!(
+        "Imported; sessions are only valid with the sessions.signing_keys they were signed with"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seals_archives_with_the_key_file() {
+        let mut users = UserData::default();
+        users.users.insert(
+            String::from("u1"),
+            users::User::new(String::from("u1"), String::from("alice")),
+        );
+        let contents = Contents {
+            users,
+            sessions: Vec::new(),
+        };
+        let key = Encryption::new(vec![(0, Zeroizing::new(vec![b'k'; 32]))], 0);
+        let archive = seal(contents, Some(&key), 0);
+        assert!(archive.contents.is_none());
+        let json = serde_json::to_vec(&archive).unwrap();
+        assert!(!String::from_utf8_lossy(&json).contains("alice"));
+
+        let read = |json: &[u8]| serde_json::from_slice::<Archive>(json).unwrap();
+        assert!(open(read(&json), None).is_err());
+        let other = Encryption::new(vec![(0, Zeroizing::new(vec![b'o'; 32]))], 0);
+        assert!(open(read(&json), Some(&other)).is_err());
+        let opened = open(read(&json), Some(&key)).unwrap();
+        assert_eq!(opened.users.users["u1"].user_name, "alice");
+
+        let mut newer = read(&json);
+        newer.version = VERSION + 1;
+        assert!(open(newer, Some(&key)).is_err());
+    }
+}
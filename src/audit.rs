@@ -0,0 +1,66 @@
+use std::net::IpAddr;
+
+#[derive(serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent<'a> {
+    SessionBindingMismatch {
+        user: Option<&'a str>,
+        session_ip: IpAddr,
+        request_ip: IpAddr,
+        ip_changed: bool,
+        user_agent_changed: bool,
+        denied: bool,
+    },
+    AuthenticationFailed {
+        user: &'a str,
+        ip: IpAddr,
+    },
+    // The login form came back with its honeypot field filled in.
+    HoneypotFilled {
+        user: &'a str,
+        ip: IpAddr,
+    },
+    // Only recorded when GeoIP databases are configured.
+    Login {
+        user: &'a str,
+        ip: IpAddr,
+        country: Option<&'a str>,
+        asn: Option<u32>,
+        new_country: bool,
+    },
+    // distance_km leaves out the accuracy radius of both locations.
+    ImpossibleTravel {
+        user: &'a str,
+        ip: IpAddr,
+        country: Option<&'a str>,
+        previous_country: Option<&'a str>,
+        distance_km: u64,
+        elapsed_secs: u64,
+        denied: bool,
+    },
+    // Only decisions other than allow are recorded.
+    RiskDecision {
+        user: &'a str,
+        ip: IpAddr,
+        step_up: bool,
+        reason: Option<&'a str>,
+    },
+    // A user signed up through an identity provider with an address at a
+    // disposable email provider. Rejected sign-ups have no user name yet and
+    // are recorded as provider:subject.
+    DisposableEmail {
+        user: &'a str,
+        domain: &'a str,
+        rejected: bool,
+    },
+    // The user followed the link in a new device alert to sign it out.
+    DeviceDisowned {
+        user: &'a str,
+        device: &'a str,
+        revoked_sessions: usize,
+    },
+}
+
+pub fn record(event: AuditEvent) {
+    println!("AUDIT {}", serde_json::to_string(&event).unwrap());
+}
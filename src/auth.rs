@@ -0,0 +1,45 @@
+use std::io;
+use std::sync::Arc;
+
+use crate::config;
+use crate::htpasswd;
+#[cfg(feature = "pam")]
+use crate::pam;
+use crate::users;
+
+pub enum Backend {
+    None,
+    #[cfg(feature = "pam")]
+    Pam(pam::PamAuthenticator),
+    Htpasswd(Arc<htpasswd::Htpasswd>),
+    Local(Arc<users::UserStore>),
+}
+
+impl Backend {
+    pub fn from_config(
+        config: &config::AuthBackendConfig,
+        users: &Arc<users::UserStore>,
+    ) -> io::Result<Self> {
+        Ok(match config {
+            config::AuthBackendConfig::None => Self::None,
+            #[cfg(feature = "pam")]
+            config::AuthBackendConfig::Pam(pam) => Self::Pam(pam::PamAuthenticator::new(pam)),
+            #[cfg(not(feature = "pam"))]
+            config::AuthBackendConfig::Pam(_) => unreachable!(),
+            config::AuthBackendConfig::Htpasswd(htpasswd) => {
+                Self::Htpasswd(htpasswd::Htpasswd::open(htpasswd)?)
+            }
+            config::AuthBackendConfig::Local => Self::Local(users.clone()),
+        })
+    }
+
+    pub async fn verify(&self, user: &str, password: &str) -> Result<bool, String> {
+        match self {
+            Self::None => Ok(true),
+            #[cfg(feature = "pam")]
+            Self::Pam(pam) => pam.verify(user, password).await,
+            Self::Htpasswd(htpasswd) => htpasswd.verify(user, password).await,
+            Self::Local(users) => users.verify_password(user, password).await,
+        }
+    }
+}
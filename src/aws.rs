@@ -0,0 +1,195 @@
+use std::fmt::Write;
+use std::time::SystemTime;
+
+use zeroize::Zeroizing;
+
+use crate::config;
+use crate::http_client;
+use crate::time;
+
+pub struct Credentials {
+    access_key_id: String,
+    secret_access_key: Zeroizing<String>,
+    session_token: Option<Zeroizing<String>>,
+}
+
+impl Credentials {
+    pub fn from_env() -> Result<Self, String> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        Ok(Self {
+            access_key_id: var("AWS_ACCESS_KEY_ID").ok_or("AWS_ACCESS_KEY_ID is not set")?,
+            secret_access_key: Zeroizing::new(
+                var("AWS_SECRET_ACCESS_KEY").ok_or("AWS_SECRET_ACCESS_KEY is not set")?,
+            ),
+            session_token: var("AWS_SESSION_TOKEN").map(Zeroizing::new),
+        })
+    }
+}
+
+pub fn endpoint(aws: &config::AwsConfig, service: &str) -> String {
+    match &aws.endpoint {
+        Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+        None => format!("https://{}.{}.amazonaws.com", service, aws.region),
+    }
+}
+
+pub async fn json_request(
+    client: &http_client::Client,
+    aws: &config::AwsConfig,
+    service: &str,
+    target: &str,
+    body: &serde_json::Value,
+) -> Result<serde_json::Value, String> {
+    let credentials = Credentials::from_env()?;
+    let url = format!("{}/", endpoint(aws, service));
+    let uri: http::Uri = url
+        .parse()
+        .map_err(|_| format!("invalid AWS endpoint {}", url))?;
+    let host = uri
+        .authority()
+        .map(|authority| authority.as_str().to_string())
+        .ok_or_else(|| format!("invalid AWS endpoint {}", url))?;
+    let body = body.to_string();
+
+    let mut headers = vec![
+        (
+            String::from("content-type"),
+            String::from("application/x-amz-json-1.1"),
+        ),
+        (String::from("host"), host),
+        (String::from("x-amz-target"), target.to_string()),
+    ];
+    sign(
+        "POST",
+        "/",
+        &mut headers,
+        body.as_bytes(),
+        service,
+        &aws.region,
+        &credentials,
+        SystemTime::now(),
+    );
+    let headers: Vec<(&str, &str)> = headers
+        .iter()
+        .filter(|(name, _)| name != "host")
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect();
+
+    let response = client
+        .request("POST", &url, &headers, body.as_bytes())
+        .await
+        .map_err(|err| format!("{} {}: {}", service, target, err))?;
+    let value: serde_json::Value = serde_json::from_slice(&response.body)
+        .map_err(|err| format!("{} {}: invalid response: {}", service, target, err))?;
+    if response.status != 200 {
+        let kind = value["__type"].as_str().unwrap_or("unknown error");
+        return Err(format!(
+            "{} {}: {} ({})",
+            service, target, kind, response.status
+        ));
+    }
+    Ok(value)
+}
+
+// AWS Signature Version 4. `headers` must contain lower-case names and
+// gets the x-amz-* and authorization headers appended.
+#[allow(clippy::too_many_arguments)]
+pub fn sign(
+    method: &str,
+    path_and_query: &str,
+    headers: &mut Vec<(String, String)>,
+    payload: &[u8],
+    service: &str,
+    region: &str,
+    credentials: &Credentials,
+    now: SystemTime,
+) {
+    let (date, timestamp) = amz_date(now);
+    let payload_hash = hex(ring::digest::digest(&ring::digest::SHA256, payload).as_ref());
+    headers.push((String::from("x-amz-date"), timestamp.clone()));
+    headers.push((String::from("x-amz-content-sha256"), payload_hash.clone()));
+    if let Some(token) = &credentials.session_token {
+        headers.push((String::from("x-amz-security-token"), token.to_string()));
+    }
+    headers.sort();
+
+    let (path, query) = path_and_query
+        .split_once('?')
+        .unwrap_or((path_and_query, ""));
+    let mut query: Vec<&str> = query.split('&').filter(|pair| !pair.is_empty()).collect();
+    query.sort();
+    let canonical_query: Vec<String> = query
+        .iter()
+        .map(|pair| match pair.split_once('=') {
+            Some(_) => pair.to_string(),
+            None => format!("{}=", pair),
+        })
+        .collect();
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        path,
+        canonical_query.join("&"),
+        canonical_headers,
+        signed_headers,
+        payload_hash
+    );
+
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        timestamp,
+        scope,
+        hex(ring::digest::digest(&ring::digest::SHA256, canonical_request.as_bytes()).as_ref())
+    );
+    let signing_key = signing_key(&credentials.secret_access_key, &date, region, service);
+    let signature = hex(ring::hmac::sign(&signing_key, string_to_sign.as_bytes()).as_ref());
+    headers.push((
+        String::from("authorization"),
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            credentials.access_key_id, scope, signed_headers, signature
+        ),
+    ));
+}
+
+fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> ring::hmac::Key {
+    let secret = Zeroizing::new(format!("AWS4{}", secret));
+    let mut key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes());
+    for part in [date, region, service, "aws4_request"] {
+        let tag = ring::hmac::sign(&key, part.as_bytes());
+        key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, tag.as_ref());
+    }
+    key
+}
+
+fn amz_date(now: SystemTime) -> (String, String) {
+    let secs = time::unix_secs(now);
+    let (year, month, day) = time::civil_from_days((secs / 86400) as i64);
+    let seconds_of_day = secs % 86400;
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let timestamp = format!(
+        "{}T{:02}{:02}{:02}Z",
+        date,
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60
+    );
+    (date, timestamp)
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut out, byte| {
+        let _ = write!(out, "{:02x}", byte);
+        out
+    })
+}
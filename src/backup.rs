@@ -0,0 +1,539 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use base64::Engine;
+
+use crate::archive;
+use crate::aws;
+use crate::config;
+use crate::encryption::Encryption;
+use crate::http_client;
+use crate::persistence;
+use crate::session::SavedSession;
+use crate::time;
+use crate::users;
+use crate::AppState;
+
+const SERVICE: &str = "s3";
+const SUFFIX: &str = ".json";
+
+// Backups are named by when they were made, like 20261015T120000Z, so
+// they sort by time.
+fn backup_id(now: u64) -> String {
+    time::rfc3339(now).replace(['-', ':'], "")
+}
+
+// When the backup of an id was made, or None if it isn't one.
+pub fn id_secs(id: &str) -> Option<u64> {
+    if id.len() != 16 || !id.is_ascii() {
+        return None;
+    }
+    let time = format!(
+        "{}-{}-{}T{}:{}:{}Z",
+        &id[..4],
+        &id[4..6],
+        &id[6..8],
+        &id[9..11],
+        &id[11..13],
+        &id[13..15]
+    );
+    time::parse_rfc3339(&time).filter(|&secs| backup_id(secs) == id)
+}
+
+// RFC 3986 percent-encoding of everything but unreserved characters, as
+// SigV4 wants query values.
+fn uri_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                char::from(byte).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn xml_unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// The texts of the <name> elements of an XML response. S3's list results
+// are flat enough not to need a parser.
+fn xml_values<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    let mut values = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let Some(end) = rest.find(&close) else {
+            break;
+        };
+        values.push(&rest[..end]);
+        rest = &rest[end + close.len()..];
+    }
+    values
+}
+
+// The ids of the backups to delete so only the newest `keep` remain.
+fn expired(mut ids: Vec<String>, keep: usize) -> Vec<String> {
+    ids.sort();
+    let excess = ids.len().saturating_sub(keep);
+    ids.truncate(excess);
+    ids
+}
+
+pub struct Backup {
+    config: config::BackupConfig,
+    client: http_client::Client,
+    key: Option<Encryption>,
+}
+
+impl Backup {
+    pub fn from_config(config: &config::Config) -> io::Result<Option<Self>> {
+        let Some(backup) = &config.backup else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            config: backup.clone(),
+            client: http_client::Client::new(&config.secrets.ca_path)?,
+            key: backup
+                .key_file
+                .as_deref()
+                .map(archive::read_key)
+                .transpose()?,
+        }))
+    }
+
+    fn object_key(&self, id: &str) -> String {
+        format!("{}{}{}", self.config.prefix, id, SUFFIX)
+    }
+
+    // A path-style request, which S3-compatible stores support more widely
+    // than virtual-hosted buckets.
+    async fn request(
+        &self,
+        method: &str,
+        key: &str,
+        query: &str,
+        mut headers: Vec<(String, String)>,
+        body: &[u8],
+    ) -> Result<http_client::Response, String> {
+        let credentials = aws::Credentials::from_env()?;
+        let mut url = format!(
+            "{}/{}/{}",
+            aws::endpoint(&self.config.aws, SERVICE),
+            self.config.bucket,
+            key
+        );
+        if !query.is_empty() {
+            url = format!("{}?{}", url, query);
+        }
+        let uri: http::Uri = url
+            .parse()
+            .map_err(|_| format!("invalid backup URL {}", url))?;
+        let (Some(host), Some(path_and_query)) = (uri.authority(), uri.path_and_query()) else {
+            return Err(format!("invalid backup URL {}", url));
+        };
+        headers.push((String::from("host"), host.as_str().to_string()));
+        aws::sign(
+            method,
+            path_and_query.as_str(),
+            &mut headers,
+            body,
+            SERVICE,
+            &self.config.aws.region,
+            &credentials,
+            SystemTime::now(),
+        );
+        let headers: Vec<(&str, &str)> = headers
+            .iter()
+            .filter(|(name, _)| name != "host")
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        let response = self
+            .client
+            .request(method, &url, &headers, body)
+            .await
+            .map_err(|err| format!("{} s3://{}/{}: {}", method, self.config.bucket, key, err))?;
+        if !(200..300).contains(&response.status) {
+            let code = String::from_utf8_lossy(&response.body);
+            let code = xml_values(&code, "Code")
+                .first()
+                .map(|code| code.to_string());
+            return Err(format!(
+                "{} s3://{}/{}: {} ({})",
+                method,
+                self.config.bucket,
+                key,
+                code.as_deref().unwrap_or("unknown error"),
+                response.status
+            ));
+        }
+        Ok(response)
+    }
+
+    // The ids of the backups under the prefix, oldest first. Other objects
+    // there are left alone.
+    pub async fn list(&self) -> Result<Vec<String>, String> {
+        let mut ids = Vec::new();
+        let mut continuation: Option<String> = None;
+        loop {
+            let mut query = format!("list-type=2&prefix={}", uri_encode(&self.config.prefix));
+            if let Some(token) = &continuation {
+                query = format!("continuation-token={}&{}", uri_encode(token), query);
+            }
+            let response = self.request("GET", "", &query, Vec::new(), &[]).await?;
+            let xml = String::from_utf8_lossy(&response.body);
+            ids.extend(xml_values(&xml, "Key").into_iter().filter_map(|key| {
+                let id = key
+                    .strip_prefix(&self.config.prefix)?
+                    .strip_suffix(SUFFIX)?;
+                id_secs(id).map(|_| id.to_string())
+            }));
+            continuation = xml_values(&xml, "NextContinuationToken")
+                .first()
+                .map(|token| xml_unescape(token));
+            if continuation.is_none() {
+                break;
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    // The archive is uploaded with its SHA-256, which S3 checks it against,
+    // and keeps it as metadata too so downloads can be checked.
+    async fn upload(&self, id: &str, archive: &[u8]) -> Result<(), String> {
+        let digest = ring::digest::digest(&ring::digest::SHA256, archive);
+        let headers = vec![
+            (
+                String::from("content-type"),
+                String::from("application/json"),
+            ),
+            (
+                String::from("x-amz-checksum-sha256"),
+                base64::engine::general_purpose::STANDARD.encode(digest.as_ref()),
+            ),
+            (String::from("x-amz-meta-sha256"), aws::hex(digest.as_ref())),
+        ];
+        self.request("PUT", &self.object_key(id), "", headers, archive)
+            .await?;
+        Ok(())
+    }
+
+    // Checked against the digest it was uploaded with.
+    async fn download(&self, id: &str) -> Result<Vec<u8>, String> {
+        let key = self.object_key(id);
+        let response = self.request("GET", &key, "", Vec::new(), &[]).await?;
+        let digest = ring::digest::digest(&ring::digest::SHA256, &response.body);
+        match response.header("x-amz-meta-sha256") {
+            Some(expected) if expected == aws::hex(digest.as_ref()) => Ok(response.body),
+            Some(_) => Err(format!(
+                "s3://{}/{} doesn't match its checksum",
+                self.config.bucket, key
+            )),
+            None => Err(format!(
+                "s3://{}/{} has no checksum",
+                self.config.bucket, key
+            )),
+        }
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), String> {
+        self.request("DELETE", &self.object_key(id), "", Vec::new(), &[])
+            .await?;
+        Ok(())
+    }
+
+    // Backs up the users and unexpired sessions, then deletes the backups
+    // past the newest `keep`.
+    async fn run(&self, state: &AppState) -> Result<String, String> {
+        let now = state.clock.now_secs();
+        let users = state.users.read().await.clone();
+        let mut sessions: Vec<(String, SavedSession)> = Vec::new();
+        for (id, session) in state.sessions.snapshot() {
+            let session = session.read().await;
+            if !session.is_expired(now) {
+                sessions.push((String::from(&id), (&*session).into()));
+            }
+        }
+        let summary = format!(
+            "{} users and {} sessions",
+            users.users.len(),
+            sessions.len()
+        );
+        let archive = archive::write(users, sessions, self.key.as_ref(), now);
+        let id = backup_id(now);
+        self.upload(&id, &archive).await?;
+        println!(
+            "Backed up {} to s3://{}/{}",
+            summary,
+            self.config.bucket,
+            self.object_key(&id)
+        );
+        // A failure here is retried with the next backup.
+        let ids = match self.list().await {
+            Ok(ids) => ids,
+            Err(err) => {
+                println!("Failed to list backups to remove: {}", err);
+                return Ok(id);
+            }
+        };
+        for expired in expired(ids, self.config.keep) {
+            match self.delete(&expired).await {
+                Ok(()) => println!("Removed backup {}", expired),
+                Err(err) => println!("Failed to remove backup {}: {}", expired, err),
+            }
+        }
+        Ok(id)
+    }
+}
+
+// Backs up every interval_secs, counting from the newest backup in the
+// bucket, so restarts don't delay or repeat them.
+pub fn spawn(state: Arc<AppState>, backup: Option<Backup>) {
+    let Some(backup) = backup else {
+        return;
+    };
+    tokio::spawn(async move {
+        let latest = match backup.list().await {
+            Ok(ids) => ids.last().and_then(|id| id_secs(id)),
+            Err(err) => {
+                println!("Failed to list backups: {}", err);
+                None
+            }
+        };
+        let mut due = latest.map_or(0, |latest| latest + backup.config.interval_secs);
+        loop {
+            let wait = due.saturating_sub(state.clock.now_secs());
+            tokio::time::sleep(Duration::from_secs(wait)).await;
+            let started = Instant::now();
+            let result = backup.run(&state).await;
+            state.metrics.backups.record(started, result.is_ok());
+            if let Err(err) = result {
+                println!("Backup failed: {}", err);
+            }
+            due = state.clock.now_secs() + backup.config.interval_secs;
+        }
+    });
+}
+
+// What restoring would add, remove and change, by id.
+#[derive(Default)]
+struct Changes {
+    added: Vec<String>,
+    removed: Vec<String>,
+    changed: Vec<String>,
+}
+
+impl Changes {
+    fn between<'a, T: serde::Serialize + 'a>(
+        before: impl IntoIterator<Item = (&'a String, &'a T)>,
+        after: impl IntoIterator<Item = (&'a String, &'a T)>,
+    ) -> Self {
+        let values = |items: &mut dyn Iterator<Item = (&'a String, &'a T)>| {
+            items
+                .map(|(id, item)| (id, serde_json::to_value(item).unwrap()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        let before = values(&mut before.into_iter());
+        let after = values(&mut after.into_iter());
+        let mut changes = Self::default();
+        for (id, item) in &after {
+            match before.get(id) {
+                None => changes.added.push(id.to_string()),
+                Some(old) if old != item => changes.changed.push(id.to_string()),
+                Some(_) => {}
+            }
+        }
+        changes.removed = before
+            .keys()
+            .filter(|id| !after.contains_key(*id))
+            .map(|id| id.to_string())
+            .collect();
+        changes
+    }
+}
+
+// Users are listed by name, sessions only counted since their ids are
+// secret.
+fn describe(changes: &Changes, names: impl Fn(&str) -> Option<String>) -> String {
+    let list = |ids: &[String]| {
+        let names: Vec<String> = ids.iter().filter_map(|id| names(id)).collect();
+        if names.is_empty() {
+            ids.len().to_string()
+        } else {
+            format!("{} ({})", ids.len(), names.join(", "))
+        }
+    };
+    format!(
+        "{} added, {} removed, {} changed",
+        list(&changes.added),
+        list(&changes.removed),
+        list(&changes.changed)
+    )
+}
+
+// Puts back the users and sessions of a backup, with the session changes
+// the journal holds up to --at replayed on top. Users aren't journaled, so
+// they're as of the backup. Like tk-auth import, the server has to be
+// stopped meanwhile.
+pub async fn run_restore(
+    config: &config::Config,
+    mut args: impl Iterator<Item = String>,
+) -> io::Result<()> {
+    let usage = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "usage: tk-auth restore [--config PATH] --backup ID --at TIME [--dry-run]",
+        )
+    };
+    let mut id = None;
+    let mut at = None;
+    let mut dry_run = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--backup" => id = Some(args.next().ok_or_else(usage)?),
+            "--at" => at = Some(args.next().ok_or_else(usage)?),
+            "--dry-run" => dry_run = true,
+            "--config" => {
+                args.next();
+            }
+            arg if arg.starts_with("--config=") => {}
+            _ => return Err(usage()),
+        }
+    }
+    let (Some(id), Some(at)) = (id, at) else {
+        return Err(usage());
+    };
+    let from = id_secs(&id).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--backup takes the id of a backup, like 20261015T120000Z",
+        )
+    })?;
+    let to = time::parse_rfc3339(&at).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--at takes a UTC time, like 2026-10-15T12:00:00Z",
+        )
+    })?;
+    if to < from {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--at is before the backup was made",
+        ));
+    }
+    let backup = Backup::from_config(config)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "restore needs backup to be configured",
+        )
+    })?;
+    let archive = backup.download(&id).await.map_err(io::Error::other)?;
+    let (users, sessions) = archive::read(&archive, backup.key.as_ref())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", id, err)))?;
+
+    let encryption = archive::store_encryption(config).await?;
+    let path = archive::users_path(config)?;
+    let (existing, _) = users::read_file(path, encryption.as_deref())?;
+    let now = time::now_secs();
+    let persistence = config.sessions.persistence.is_some();
+    let mut sessions: HashMap<String, SavedSession> = sessions.into_iter().collect();
+    let mut existing_sessions = HashMap::new();
+    let mut replayed = 0;
+    if persistence {
+        replayed =
+            persistence::replay_journal(config, encryption.as_deref(), &mut sessions, from, to)?;
+        existing_sessions.extend(persistence::load(config, encryption.as_deref(), now)?);
+    } else {
+        println!("sessions.persistence is off, so the sessions are skipped");
+        sessions.clear();
+    }
+    sessions.retain(|_, session| !session.is_expired(now));
+
+    let user_changes = Changes::between(&existing.users, &users.users);
+    let user_name = |id: &str| {
+        users
+            .users
+            .get(id)
+            .or_else(|| existing.users.get(id))
+            .map(|user| user.user_name.clone())
+    };
+    println!(
+        "{} backup {} with {} journal entries up to {}:\n  \
+         users: {}\n  groups: {}\n  unexpired sessions: {}",
+        if dry_run {
+            "Dry run: restoring"
+        } else {
+            "Restoring"
+        },
+        id,
+        replayed,
+        time::rfc3339(to),
+        describe(&user_changes, user_name),
+        describe(&Changes::between(&existing.groups, &users.groups), |_| None),
+        describe(&Changes::between(&existing_sessions, &sessions), |_| None)
+    );
+    if dry_run {
+        return Ok(());
+    }
+    users::write_file(path, &users, encryption.as_deref())
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+    if persistence {
+        let sessions: Vec<(String, SavedSession)> = sessions.into_iter().collect();
+        persistence::replace(config, encryption.as_deref(), &sessions, now)?;
+    }
+    println!("Restored");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_newest_backups() {
+        let id = backup_id(1_792_065_600);
+        assert_eq!(id, "20261015T120000Z");
+        assert_eq!(id_secs(&id), Some(1_792_065_600));
+        assert_eq!(id_secs("20261015T126000Z"), None);
+        assert_eq!(id_secs("2026-10-15"), None);
+
+        let xml = "<ListBucketResult><Contents><Key>tk-auth/20261014T120000Z.json</Key>\
+                   </Contents><Contents><Key>tk-auth/notes.txt</Key></Contents>\
+                   <NextContinuationToken>a&amp;b</NextContinuationToken></ListBucketResult>";
+        assert_eq!(
+            xml_values(xml, "Key"),
+            ["tk-auth/20261014T120000Z.json", "tk-auth/notes.txt"]
+        );
+        assert_eq!(
+            xml_unescape(xml_values(xml, "NextContinuationToken")[0]),
+            "a&b"
+        );
+        assert_eq!(uri_encode("tk-auth/a+b"), "tk-auth%2Fa%2Bb");
+
+        let ids = ["20261013T120000Z", "20261015T120000Z", "20261014T120000Z"]
+            .map(String::from)
+            .to_vec();
+        assert_eq!(expired(ids.clone(), 2), ["20261013T120000Z"]);
+        assert!(expired(ids, 3).is_empty());
+
+        let before = BTreeMap::from([(String::from("a"), 1), (String::from("b"), 2)]);
+        let after = BTreeMap::from([(String::from("b"), 3), (String::from("c"), 4)]);
+        let changes = Changes::between(&before, &after);
+        assert_eq!(
+            describe(&changes, |id| (id == "c").then(|| String::from("carol"))),
+            "1 (carol) added, 1 removed, 1 changed"
+        );
+    }
+}
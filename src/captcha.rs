@@ -0,0 +1,231 @@
+use std::io;
+use std::net::IpAddr;
+
+use tk_auth_types::CaptchaChallenge;
+
+use crate::config::{self, CaptchaProvider};
+use crate::failures::FailureCounts;
+use crate::http_client;
+use crate::serialized_response;
+
+pub enum Check {
+    Passed,
+    Required,
+    Invalid,
+    Unavailable,
+}
+
+pub struct Captcha {
+    config: Option<config::CaptchaConfig>,
+    client: Option<http_client::Client>,
+    failures: FailureCounts,
+}
+
+impl Captcha {
+    pub fn new(config: &config::Config) -> io::Result<Self> {
+        let client = match &config.captcha {
+            Some(_) => Some(http_client::Client::new(&config.secrets.ca_path)?),
+            None => None,
+        };
+        Ok(Self {
+            config: config.captcha.clone(),
+            client,
+            failures: FailureCounts::new(
+                config
+                    .captcha
+                    .as_ref()
+                    .map_or(0, |captcha| captcha.failure_window_secs),
+            ),
+        })
+    }
+
+    // Whether signing in as `user` from `ip` needs a CAPTCHA, after too many
+    // recent failures for either of them. Without a user, only the network
+    // counts.
+    pub fn required(&self, user: Option<&str>, ip: IpAddr, now: u64) -> bool {
+        let Some(config) = &self.config else {
+            return false;
+        };
+        if config.after_failures == 0 {
+            return true;
+        }
+        // When too many users and networks are tracked to count new ones,
+        // everyone gets a CAPTCHA until old failures run out.
+        self.failures.is_full() || self.failures.count(user, ip, now) >= config.after_failures
+    }
+
+    // Without a user, only the network's count goes up.
+    pub fn record_failure(&self, user: Option<&str>, ip: IpAddr, now: u64) {
+        if self.config.is_some() {
+            self.failures.record(user, ip, now);
+        }
+    }
+
+    pub fn record_success(&self, user: &str) {
+        if self.config.is_some() {
+            self.failures.clear_user(user);
+        }
+    }
+
+    // Lets a sign-in go ahead if no CAPTCHA is needed or the token from the
+    // widget checks out. When the provider can't be reached, sign-ins that
+    // need a CAPTCHA are refused. With `step_up`, a CAPTCHA is needed anyway.
+    pub async fn check(
+        &self,
+        user: &str,
+        ip: IpAddr,
+        token: Option<&str>,
+        step_up: bool,
+        now: u64,
+    ) -> Check {
+        if !step_up && !self.required(Some(user), ip, now) {
+            return Check::Passed;
+        }
+        let Some(token) = token.filter(|token| !token.is_empty()) else {
+            return Check::Required;
+        };
+        match self.verify(token, ip).await {
+            Ok(true) => Check::Passed,
+            Ok(false) => Check::Invalid,
+            Err(err) => {
+                println!("CAPTCHA verification failed: {}", err);
+                Check::Unavailable
+            }
+        }
+    }
+
+    pub fn challenge(&self, error: &str) -> axum::response::Response {
+        let Some(config) = &self.config else {
+            return crate::error_response(401, error);
+        };
+        serialized_response(
+            401,
+            &CaptchaChallenge {
+                error: error.to_string(),
+                provider: name(config.provider).to_string(),
+                site_key: config.site_key.clone(),
+            },
+        )
+    }
+
+    // Asks the provider whether the token from the widget is valid.
+    async fn verify(&self, token: &str, ip: IpAddr) -> Result<bool, String> {
+        let (Some(config), Some(client)) = (&self.config, &self.client) else {
+            return Ok(true);
+        };
+        let url = config
+            .verify_url
+            .as_deref()
+            .unwrap_or(verify_url(config.provider));
+        let body = form_urlencoded::Serializer::new(String::new())
+            .append_pair("secret", &config.secret)
+            .append_pair("response", token)
+            .append_pair("remoteip", &ip.to_string())
+            .append_pair("sitekey", &config.site_key)
+            .finish();
+        let response = client
+            .request(
+                "POST",
+                url,
+                &[
+                    ("Content-Type", "application/x-www-form-urlencoded"),
+                    ("Accept", "application/json"),
+                ],
+                body.as_bytes(),
+            )
+            .await
+            .map_err(|err| err.to_string())?;
+        if response.status != 200 {
+            return Err(format!("{} returned status {}", url, response.status));
+        }
+        let result: serde_json::Value =
+            serde_json::from_slice(&response.body).map_err(|err| format!("{}: {}", url, err))?;
+        Ok(result["success"] == true)
+    }
+}
+
+fn name(provider: CaptchaProvider) -> &'static str {
+    match provider {
+        CaptchaProvider::Hcaptcha => "hcaptcha",
+        CaptchaProvider::Recaptcha => "recaptcha",
+        CaptchaProvider::Turnstile => "turnstile",
+    }
+}
+
+fn verify_url(provider: CaptchaProvider) -> &'static str {
+    match provider {
+        CaptchaProvider::Hcaptcha => "https://api.hcaptcha.com/siteverify",
+        CaptchaProvider::Recaptcha => "https://www.google.com/recaptcha/api/siteverify",
+        CaptchaProvider::Turnstile => "https://challenges.cloudflare.com/turnstile/v0/siteverify",
+    }
+}
+
+pub fn script_url(provider: CaptchaProvider) -> &'static str {
+    match provider {
+        CaptchaProvider::Hcaptcha => "https://js.hcaptcha.com/1/api.js",
+        CaptchaProvider::Recaptcha => "https://www.google.com/recaptcha/api.js",
+        CaptchaProvider::Turnstile => "https://challenges.cloudflare.com/turnstile/v0/api.js",
+    }
+}
+
+// The element the provider's script turns into the widget.
+pub fn widget_class(provider: CaptchaProvider) -> &'static str {
+    match provider {
+        CaptchaProvider::Hcaptcha => "h-captcha",
+        CaptchaProvider::Recaptcha => "g-recaptcha",
+        CaptchaProvider::Turnstile => "cf-turnstile",
+    }
+}
+
+// What the widget needs to load in the login page, per the providers' CSP
+// documentation.
+pub fn content_sources(provider: CaptchaProvider) -> &'static str {
+    match provider {
+        CaptchaProvider::Hcaptcha => "https://hcaptcha.com https://*.hcaptcha.com",
+        CaptchaProvider::Recaptcha => {
+            "https://www.google.com/recaptcha/ https://www.gstatic.com/recaptcha/ \
+             https://recaptcha.google.com/recaptcha/"
+        }
+        CaptchaProvider::Turnstile => "https://challenges.cloudflare.com",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requires_a_captcha_after_repeated_failures() {
+        let config = config::Config {
+            captcha: Some(config::CaptchaConfig {
+                provider: CaptchaProvider::Turnstile,
+                site_key: String::from("site"),
+                secret: String::from("secret").into(),
+                verify_url: None,
+                after_failures: 2,
+                failure_window_secs: 60,
+            }),
+            ..config::Config::default()
+        };
+        let captcha = Captcha {
+            config: config.captcha,
+            client: None,
+            failures: FailureCounts::new(60),
+        };
+        let ip = IpAddr::from([192, 0, 2, 1]);
+        let other_ip = IpAddr::from([198, 51, 100, 1]);
+        captcha.record_failure(Some("zed"), ip, 100);
+        assert!(!captcha.required(Some("zed"), ip, 100));
+        captcha.record_failure(Some("zed"), ip, 110);
+        assert!(captcha.required(Some("zed"), ip, 110));
+        assert!(captcha.required(Some("zed"), other_ip, 110));
+        assert!(captcha.required(Some("amy"), ip, 110));
+        assert!(captcha.required(None, ip, 110));
+        assert!(!captcha.required(Some("amy"), other_ip, 110));
+        assert!(!captcha.required(Some("zed"), ip, 160));
+
+        captcha.record_success("zed");
+        assert!(!captcha.required(Some("zed"), other_ip, 110));
+        assert!(captcha.required(Some("zed"), ip, 110));
+    }
+}
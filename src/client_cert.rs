@@ -0,0 +1,111 @@
+use std::io;
+use std::sync::Arc;
+
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+
+use crate::config;
+
+const COMMON_NAME_OID: &[u8] = &[0x55, 0x04, 0x03];
+
+#[derive(Clone)]
+pub struct ClientCertificate {
+    pub common_name: String,
+}
+
+impl ClientCertificate {
+    pub fn from_connection(connection: &tokio_rustls::rustls::ServerConnection) -> Option<Self> {
+        let certificate = connection.peer_certificates()?.first()?;
+        let common_name = subject_common_name(certificate.as_ref())?;
+        Some(Self { common_name })
+    }
+
+    pub fn user(&self, client_auth: &config::ClientAuthConfig) -> Option<String> {
+        if client_auth.users.is_empty() {
+            Some(self.common_name.clone())
+        } else {
+            client_auth.users.get(&self.common_name).cloned()
+        }
+    }
+}
+
+pub fn verifier(
+    client_auth: &config::ClientAuthConfig,
+) -> io::Result<Arc<dyn tokio_rustls::rustls::server::danger::ClientCertVerifier>> {
+    let mut roots = tokio_rustls::rustls::RootCertStore::empty();
+    for cert in tokio_rustls::rustls::pki_types::CertificateDer::pem_file_iter(&client_auth.ca_path)
+        .map_err(|err| io::Error::other(format!("{}: {}", client_auth.ca_path.display(), err)))?
+    {
+        let cert = cert.map_err(|err| {
+            io::Error::other(format!("{}: {}", client_auth.ca_path.display(), err))
+        })?;
+        roots.add(cert).map_err(io::Error::other)?;
+    }
+    let builder = tokio_rustls::rustls::server::WebPkiClientVerifier::builder(Arc::new(roots));
+    let builder = if client_auth.required {
+        builder
+    } else {
+        builder.allow_unauthenticated()
+    };
+    builder.build().map_err(io::Error::other)
+}
+
+fn subject_common_name(der: &[u8]) -> Option<String> {
+    let (_, certificate, _) = read_tlv(der)?;
+    let (_, mut tbs, _) = read_tlv(certificate)?;
+    // Skip the optional explicit version, then serial, signature algorithm,
+    // issuer and validity to reach the subject.
+    let (tag, _, rest) = read_tlv(tbs)?;
+    if tag == 0xa0 {
+        tbs = rest;
+    }
+    for _ in 0..4 {
+        tbs = read_tlv(tbs)?.2;
+    }
+    let (_, mut subject, _) = read_tlv(tbs)?;
+    while !subject.is_empty() {
+        let (_, mut set, rest) = read_tlv(subject)?;
+        subject = rest;
+        while !set.is_empty() {
+            let (_, attribute, rest) = read_tlv(set)?;
+            set = rest;
+            let (oid_tag, oid, value) = read_tlv(attribute)?;
+            if oid_tag == 0x06 && oid == COMMON_NAME_OID {
+                let (value_tag, value, _) = read_tlv(value)?;
+                return match value_tag {
+                    // UTF8String, PrintableString, IA5String, T61String
+                    0x0c | 0x13 | 0x16 | 0x14 => String::from_utf8(value.to_vec()).ok(),
+                    // BMPString
+                    0x1e => {
+                        let units: Vec<u16> = value
+                            .chunks_exact(2)
+                            .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+                            .collect();
+                        String::from_utf16(&units).ok()
+                    }
+                    _ => None,
+                };
+            }
+        }
+    }
+    None
+}
+
+fn read_tlv(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.first()?;
+    let first_len = *data.get(1)?;
+    let (len, header) = if first_len < 0x80 {
+        (usize::from(first_len), 2)
+    } else {
+        let len_bytes = usize::from(first_len & 0x7f);
+        if len_bytes == 0 || len_bytes > 4 {
+            return None;
+        }
+        let len = data
+            .get(2..2 + len_bytes)?
+            .iter()
+            .fold(0usize, |len, byte| (len << 8) | usize::from(*byte));
+        (len, 2 + len_bytes)
+    };
+    let value = data.get(header..header.checked_add(len)?)?;
+    Some((tag, value, &data[header + len..]))
+}
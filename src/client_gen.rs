@@ -0,0 +1,857 @@
+use std::io;
+use std::path::PathBuf;
+
+use serde_json::Value;
+
+use crate::openapi;
+
+const TYPESCRIPT_FILE: &str = "api.ts";
+const RUST_FILE: &str = "client.rs";
+const RUST_KEYWORDS: [&str; 12] = [
+    "as", "async", "crate", "enum", "fn", "impl", "mod", "ref", "self", "struct", "type", "use",
+];
+
+#[derive(Clone, Copy)]
+enum Lang {
+    TypeScript,
+    Rust,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Location {
+    Path,
+    Query,
+    Header,
+}
+
+struct Param {
+    name: String,
+    location: Location,
+    required: bool,
+    schema: Value,
+}
+
+struct Operation {
+    id: String,
+    method: String,
+    path: String,
+    summary: Option<String>,
+    params: Vec<Param>,
+    body: Option<(String, Value)>,
+    // The JSON schema of a 200 response, or None if the response is handed
+    // back as is, such as redirects, event streams and SCIM resources.
+    response: Option<Value>,
+}
+
+pub fn run_cli(mut args: impl Iterator<Item = String>) -> io::Result<()> {
+    let usage = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "usage: tk-auth generate-client --lang typescript|rust --out DIR",
+        )
+    };
+    let mut lang = None;
+    let mut out = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--lang" => {
+                lang = match args.next().as_deref() {
+                    Some("typescript") => Some(Lang::TypeScript),
+                    Some("rust") => Some(Lang::Rust),
+                    _ => return Err(usage()),
+                }
+            }
+            "--out" => out = Some(PathBuf::from(args.next().ok_or_else(usage)?)),
+            "--config" => {
+                args.next();
+            }
+            arg if arg.starts_with("--config=") => {}
+            _ => return Err(usage()),
+        }
+    }
+    let (Some(lang), Some(out)) = (lang, out) else {
+        return Err(usage());
+    };
+    let (file, source) = match lang {
+        Lang::TypeScript => (TYPESCRIPT_FILE, typescript(&openapi::document())),
+        Lang::Rust => (RUST_FILE, rust(&openapi::document())),
+    };
+    std::fs::create_dir_all(&out)?;
+    let path = out.join(file);
+    std::fs::write(&path, source)?;
+    println!("Wrote {}", path.display());
+    Ok(())
+}
+
+fn operations(document: &Value) -> Vec<Operation> {
+    let mut operations = Vec::new();
+    let Some(paths) = document["paths"].as_object() else {
+        return operations;
+    };
+    for (path, item) in paths {
+        let shared_params = item["parameters"].as_array().cloned().unwrap_or_default();
+        let Some(methods) = item.as_object() else {
+            continue;
+        };
+        for (method, operation) in methods {
+            let Some(id) = operation["operationId"].as_str() else {
+                continue;
+            };
+            let params = shared_params
+                .iter()
+                .chain(operation["parameters"].as_array().into_iter().flatten())
+                .filter_map(|param| {
+                    let location = match param["in"].as_str()? {
+                        "path" => Location::Path,
+                        "query" => Location::Query,
+                        "header" => Location::Header,
+                        _ => return None,
+                    };
+                    Some(Param {
+                        name: param["name"].as_str()?.to_string(),
+                        location,
+                        required: param["required"].as_bool().unwrap_or(false),
+                        schema: param["schema"].clone(),
+                    })
+                })
+                .collect();
+            let content = &operation["requestBody"]["content"];
+            let body = ["application/json", "application/x-www-form-urlencoded"]
+                .into_iter()
+                .find(|content_type| content.get(content_type).is_some())
+                .or_else(|| content.as_object()?.keys().next().map(String::as_str))
+                .map(|content_type| {
+                    (
+                        content_type.to_string(),
+                        content[content_type]["schema"].clone(),
+                    )
+                });
+            operations.push(Operation {
+                id: id.to_string(),
+                method: method.to_uppercase(),
+                path: path.clone(),
+                summary: operation["summary"].as_str().map(str::to_string),
+                params,
+                body,
+                response: operation["responses"]["200"]["content"]["application/json"]
+                    .get("schema")
+                    .map(|schema| {
+                        if schema["x-envelope"] == true {
+                            schema["properties"]["data"].clone()
+                        } else {
+                            schema.clone()
+                        }
+                    }),
+            });
+        }
+    }
+    operations
+}
+
+// A nullable reference has to be wrapped in allOf in OpenAPI 3.0.
+fn ref_name(schema: &Value) -> Option<&str> {
+    schema["$ref"]
+        .as_str()
+        .or_else(|| schema["allOf"][0]["$ref"].as_str())?
+        .rsplit('/')
+        .next()
+}
+
+fn is_required(schema: &Value, property: &str) -> bool {
+    schema["required"]
+        .as_array()
+        .is_some_and(|required| required.iter().any(|name| name == property))
+}
+
+fn pascal_case(name: &str) -> String {
+    name.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            chars.next().unwrap().to_ascii_uppercase().to_string() + chars.as_str()
+        })
+        .collect()
+}
+
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    for c in name.chars() {
+        if c.is_ascii_uppercase() {
+            if !snake.is_empty() && !snake.ends_with('_') {
+                snake.push('_');
+            }
+            snake.push(c.to_ascii_lowercase());
+        } else if c.is_ascii_alphanumeric() {
+            snake.push(c);
+        } else if !snake.ends_with('_') {
+            snake.push('_');
+        }
+    }
+    snake
+}
+
+fn comment_text(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace("*/", "* /")
+}
+
+fn typescript(document: &Value) -> String {
+    let mut out = String::from(TYPESCRIPT_PRELUDE);
+    if let Some(schemas) = document["components"]["schemas"].as_object() {
+        for (name, schema) in schemas {
+            out.push('\n');
+            if schema["properties"].is_object() {
+                out.push_str(&format!(
+                    "export interface {} {}\n",
+                    name,
+                    ts_object(schema, 0)
+                ));
+            } else {
+                out.push_str(&format!("export type {} = {};\n", name, ts_type(schema, 0)));
+            }
+        }
+    }
+    for operation in operations(document) {
+        out.push('\n');
+        out.push_str(&ts_operation(&operation));
+    }
+    out
+}
+
+fn ts_type(schema: &Value, indent: usize) -> String {
+    let base = if let Some(name) = ref_name(schema) {
+        name.to_string()
+    } else if let Some(values) = schema["enum"].as_array() {
+        values
+            .iter()
+            .map(|value| format!("'{}'", value.as_str().unwrap_or_default()))
+            .collect::<Vec<_>>()
+            .join(" | ")
+    } else {
+        match schema["type"].as_str() {
+            Some("string") => "string".to_string(),
+            Some("integer") | Some("number") => "number".to_string(),
+            Some("boolean") => "boolean".to_string(),
+            Some("array") => {
+                let items = ts_type(&schema["items"], indent);
+                if items.contains(['|', '{']) {
+                    format!("Array<{}>", items)
+                } else {
+                    format!("{}[]", items)
+                }
+            }
+            Some("object") if schema["properties"].is_object() => ts_object(schema, indent),
+            Some("object") if schema["additionalProperties"].is_object() => format!(
+                "Record<string, {}>",
+                ts_type(&schema["additionalProperties"], indent)
+            ),
+            _ => "unknown".to_string(),
+        }
+    };
+    if schema["nullable"] == true {
+        format!("{} | null", base)
+    } else {
+        base
+    }
+}
+
+fn ts_object(schema: &Value, indent: usize) -> String {
+    let tabs = "\t".repeat(indent + 1);
+    let mut out = String::from("{\n");
+    for (name, property) in schema["properties"].as_object().into_iter().flatten() {
+        if let Some(description) = property["description"].as_str() {
+            out.push_str(&format!("{}/** {} */\n", tabs, comment_text(description)));
+        }
+        out.push_str(&format!(
+            "{}{}{}: {};\n",
+            tabs,
+            ts_key(name),
+            if is_required(schema, name) { "" } else { "?" },
+            ts_type(property, indent + 1)
+        ));
+    }
+    out.push_str(&"\t".repeat(indent));
+    out.push('}');
+    out
+}
+
+fn ts_key(name: &str) -> String {
+    if name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        name.to_string()
+    } else {
+        format!("'{}'", name)
+    }
+}
+
+fn ts_operation(operation: &Operation) -> String {
+    let mut args = Vec::new();
+    if !operation.params.is_empty() {
+        let fields: Vec<_> = operation
+            .params
+            .iter()
+            .map(|param| {
+                format!(
+                    "{}{}: {}",
+                    ts_key(&param.name),
+                    if param.required { "" } else { "?" },
+                    ts_type(&param.schema, 0)
+                )
+            })
+            .collect();
+        // Leaving out params is fine when none of them are required.
+        let default = if operation.params.iter().any(|param| param.required) {
+            ""
+        } else {
+            " = {}"
+        };
+        args.push(format!("params: {{ {} }}{}", fields.join("; "), default));
+    }
+    if let Some((_, schema)) = &operation.body {
+        args.push(format!("body: {}", ts_type(schema, 0)));
+    }
+    let returns = match &operation.response {
+        Some(schema) => ts_type(schema, 0),
+        None => "Response".to_string(),
+    };
+    let mut path = format!("'{}'", operation.path);
+    let mut query = Vec::new();
+    let mut headers = Vec::new();
+    for param in &operation.params {
+        let value = format!("params[{}]", ts_string(&param.name));
+        match param.location {
+            Location::Path => {
+                path = format!(
+                    "{}.replace('{{{}}}', encodeURIComponent(String({})))",
+                    path, param.name, value
+                )
+            }
+            Location::Query => query.push(format!("{}: {}", ts_key(&param.name), value)),
+            Location::Header => headers.push(format!("{}: {}", ts_key(&param.name), value)),
+        }
+    }
+    let body = match &operation.body {
+        Some((content_type, _)) => format!("{{ type: '{}', data: body }}", content_type),
+        None => "undefined".to_string(),
+    };
+    let record = |entries: Vec<String>| {
+        if entries.is_empty() {
+            "{}".to_string()
+        } else {
+            format!("{{ {} }}", entries.join(", "))
+        }
+    };
+    let call = format!(
+        "call('{}', {}, {}, {}, {})",
+        operation.method,
+        path,
+        record(query),
+        record(headers),
+        body
+    );
+    let mut out = String::new();
+    if let Some(summary) = &operation.summary {
+        out.push_str(&format!("/** {} */\n", comment_text(summary)));
+    }
+    out.push_str(&format!(
+        "export async function {}({}): Promise<{}> {{\n",
+        operation.id,
+        args.join(", "),
+        returns
+    ));
+    match &operation.response {
+        Some(_) => out.push_str(&format!("\treturn decode(await {});\n", call)),
+        None => out.push_str(&format!("\treturn {};\n", call)),
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn ts_string(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "\\'"))
+}
+
+const TYPESCRIPT_PRELUDE: &str = "\
+// Generated by `tk-auth generate-client --lang typescript` from the OpenAPI
+// description. Don't edit it by hand, regenerate it instead.
+
+export class ApiError extends Error {
+\tconstructor(
+\t\treadonly status: number,
+\t\treadonly error: string,
+\t\t// Machine-readable, unlike the message in `error`.
+\t\treadonly code: string,
+\t\treadonly data: unknown = null
+\t) {
+\t\tsuper(error);
+\t}
+}
+
+let baseUrl = '';
+
+export function setBaseUrl(url: string) {
+\tbaseUrl = url.replace(/\\/$/, '');
+}
+
+type Body = { type: string; data: unknown };
+
+async function call(
+\tmethod: string,
+\tpath: string,
+\tquery: Record<string, unknown>,
+\theaders: Record<string, unknown>,
+\tbody?: Body
+): Promise<Response> {
+\tconst search = new URLSearchParams();
+\tfor (const [name, value] of Object.entries(query)) {
+\t\tif (value !== undefined && value !== null) search.append(name, String(value));
+\t}
+\tconst init: RequestInit = { method, headers: {} };
+\tconst initHeaders = init.headers as Record<string, string>;
+\tfor (const [name, value] of Object.entries(headers)) {
+\t\tif (value !== undefined && value !== null) initHeaders[name] = String(value);
+\t}
+\tif (body) {
+\t\tinitHeaders['Content-Type'] = body.type;
+\t\tif (body.type === 'application/json') {
+\t\t\tinit.body = JSON.stringify(body.data);
+\t\t} else if (body.type === 'application/x-www-form-urlencoded') {
+\t\t\tconst form = new URLSearchParams();
+\t\t\tfor (const [name, value] of Object.entries(body.data as object)) {
+\t\t\t\tif (value !== undefined && value !== null) form.append(name, String(value));
+\t\t\t}
+\t\t\tinit.body = form;
+\t\t} else {
+\t\t\tinit.body = String(body.data);
+\t\t}
+\t}
+\tconst queryString = search.toString();
+\treturn fetch(baseUrl + path + (queryString ? '?' + queryString : ''), init);
+}
+
+async function decode<T>(response: Response): Promise<T> {
+\tlet envelope: { code?: string; message?: string; data?: unknown } = {};
+\ttry {
+\t\tenvelope = await response.json();
+\t} catch {
+\t\t// Not an envelope, like errors from a proxy in front.
+\t}
+\tif (!response.ok) {
+\t\tthrow new ApiError(
+\t\t\tresponse.status,
+\t\t\tenvelope.message ?? response.statusText,
+\t\t\tenvelope.code ?? '',
+\t\t\tenvelope.data ?? null
+\t\t);
+\t}
+\treturn envelope.data as T;
+}
+";
+
+fn rust(document: &Value) -> String {
+    let mut generator = RustGenerator::default();
+    if let Some(schemas) = document["components"]["schemas"].as_object() {
+        for (name, schema) in schemas {
+            let ty = generator.rust_type(name, schema);
+            if ty != *name {
+                generator
+                    .items
+                    .push(format!("pub type {} = {};\n", name, ty));
+            }
+        }
+    }
+    let mut methods = Vec::new();
+    for operation in operations(document) {
+        methods.push(generator.rust_operation(&operation));
+    }
+    let mut out = String::from(RUST_PRELUDE);
+    for item in &generator.items {
+        out.push('\n');
+        out.push_str(item);
+    }
+    out.push_str("\nimpl<T: Transport> Client<T> {\n");
+    out.push_str(&methods.join("\n"));
+    out.push_str("}\n");
+    out
+}
+
+#[derive(Default)]
+struct RustGenerator {
+    items: Vec<String>,
+}
+
+impl RustGenerator {
+    // Defines structs and enums for object and enum schemas, named after
+    // where they appear, and returns the Rust type for the schema.
+    fn rust_type(&mut self, name: &str, schema: &Value) -> String {
+        if let Some(name) = ref_name(schema) {
+            return name.to_string();
+        }
+        if let Some(values) = schema["enum"].as_array() {
+            let values: Vec<_> = values.iter().filter_map(Value::as_str).collect();
+            let mut item = format!(
+                "#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, \
+                 serde::Deserialize)]\npub enum {} {{\n",
+                name
+            );
+            for value in &values {
+                item.push_str(&format!(
+                    "    #[serde(rename = \"{}\")]\n    {},\n",
+                    value,
+                    pascal_case(value)
+                ));
+            }
+            // Display gives the wire value, for enums in query parameters.
+            item.push_str(&format!(
+                "}}\n\nimpl std::fmt::Display for {} {{\n    \
+                 fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {{\n        \
+                 f.write_str(match self {{\n",
+                name
+            ));
+            for value in &values {
+                item.push_str(&format!(
+                    "            {}::{} => \"{}\",\n",
+                    name,
+                    pascal_case(value),
+                    value
+                ));
+            }
+            item.push_str("        })\n    }\n}\n");
+            self.items.push(item);
+            return name.to_string();
+        }
+        match schema["type"].as_str() {
+            Some("string") => "String".to_string(),
+            Some("integer") => "i64".to_string(),
+            Some("number") => "f64".to_string(),
+            Some("boolean") => "bool".to_string(),
+            Some("array") => format!(
+                "Vec<{}>",
+                self.rust_type(&format!("{}Item", name), &schema["items"])
+            ),
+            Some("object") if schema["properties"].is_object() => {
+                self.rust_struct(name, schema);
+                name.to_string()
+            }
+            Some("object") if schema["additionalProperties"].is_object() => format!(
+                "std::collections::BTreeMap<String, {}>",
+                self.rust_type(&format!("{}Value", name), &schema["additionalProperties"])
+            ),
+            _ => "serde_json::Value".to_string(),
+        }
+    }
+
+    fn rust_struct(&mut self, name: &str, schema: &Value) {
+        let mut item = format!(
+            "#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]\npub struct {} {{\n",
+            name
+        );
+        for (property_name, property) in schema["properties"].as_object().into_iter().flatten() {
+            let ty = self.rust_type(&format!("{}{}", name, pascal_case(property_name)), property);
+            let field = rust_field(property_name);
+            if let Some(description) = property["description"].as_str() {
+                item.push_str(&format!("    /// {}\n", comment_text(description)));
+            }
+            if field.trim_start_matches("r#") != property_name {
+                item.push_str(&format!("    #[serde(rename = \"{}\")]\n", property_name));
+            }
+            let ty = if !is_required(schema, property_name) {
+                item.push_str("    #[serde(default, skip_serializing_if = \"Option::is_none\")]\n");
+                format!("Option<{}>", ty)
+            } else if property["nullable"] == true {
+                format!("Option<{}>", ty)
+            } else {
+                ty
+            };
+            item.push_str(&format!("    pub {}: {},\n", field, ty));
+        }
+        item.push_str("}\n");
+        self.items.push(item);
+    }
+
+    fn rust_operation(&mut self, operation: &Operation) -> String {
+        let type_name = pascal_case(&operation.id);
+        let mut args = String::from("&self");
+        let mut path = format!("\"{}\".to_string()", operation.path);
+        let mut setup = String::new();
+        if !operation.params.is_empty() {
+            let mut item = format!(
+                "#[derive(Debug, Clone)]\npub struct {}Params {{\n",
+                type_name
+            );
+            for param in &operation.params {
+                let field = rust_field(&param.name);
+                let ty = self.rust_type(
+                    &format!("{}{}", type_name, pascal_case(&param.name)),
+                    &param.schema,
+                );
+                let value = if param.required {
+                    item.push_str(&format!("    pub {}: {},\n", field, ty));
+                    format!("Some(params.{}.to_string())", field)
+                } else {
+                    item.push_str(&format!("    pub {}: Option<{}>,\n", field, ty));
+                    format!("params.{}.as_ref().map(|value| value.to_string())", field)
+                };
+                match param.location {
+                    Location::Path => {
+                        path = format!(
+                            "{}.replace(\"{{{}}}\", &encode_path_segment(&params.{}.to_string()))",
+                            path, param.name, field
+                        )
+                    }
+                    Location::Query => setup.push_str(&format!(
+                        "        query.push((\"{}\", {}));\n",
+                        param.name, value
+                    )),
+                    Location::Header => setup.push_str(&format!(
+                        "        headers.push((\"{}\", {}));\n",
+                        param.name, value
+                    )),
+                }
+            }
+            item.push_str("}\n");
+            self.items.push(item);
+            args.push_str(&format!(", params: &{}Params", type_name));
+        }
+        let body = match &operation.body {
+            Some((content_type, schema)) => {
+                let ty = self.rust_type(&format!("{}Body", type_name), schema);
+                args.push_str(&format!(", body: &{}", ty));
+                let encode = match content_type.as_str() {
+                    "application/json" => "json_body(body)?",
+                    "application/x-www-form-urlencoded" => "form_body(body)?",
+                    _ => "body.to_string().into_bytes()",
+                };
+                format!("Some((\"{}\", {}))", content_type, encode)
+            }
+            None => "None".to_string(),
+        };
+        let returns = match &operation.response {
+            Some(schema) => self.rust_type(&format!("{}Response", type_name), schema),
+            None => "http::Response<Vec<u8>>".to_string(),
+        };
+
+        let mut out = String::new();
+        if let Some(summary) = &operation.summary {
+            out.push_str(&format!("    /// {}\n", comment_text(summary)));
+        }
+        out.push_str(&format!(
+            "    pub async fn {}({}) -> Result<{}, Error> {{\n",
+            snake_case(&operation.id),
+            args,
+            returns
+        ));
+        let mutable = |used: bool| if used { "mut " } else { "" };
+        out.push_str(&format!(
+            "        let {}query: Vec<(&str, Option<String>)> = Vec::new();\n",
+            mutable(setup.contains("query.push"))
+        ));
+        out.push_str(&format!(
+            "        let {}headers: Vec<(&str, Option<String>)> = Vec::new();\n",
+            mutable(setup.contains("headers.push"))
+        ));
+        out.push_str(&setup);
+        out.push_str(&format!(
+            "        let response = self\n            .call(\"{}\", {}, query, headers, {})\n            .await?;\n",
+            operation.method, path, body
+        ));
+        match &operation.response {
+            Some(_) => out.push_str("        decode(response)\n"),
+            None => out.push_str("        Ok(response)\n"),
+        }
+        out.push_str("    }\n");
+        out
+    }
+}
+
+fn rust_field(name: &str) -> String {
+    let field = snake_case(name);
+    if RUST_KEYWORDS.contains(&field.as_str()) {
+        format!("r#{}", field)
+    } else {
+        field
+    }
+}
+
+const RUST_PRELUDE: &str = "\
+// Generated by `tk-auth generate-client --lang rust` from the OpenAPI
+// description. Don't edit it by hand, regenerate it instead.
+//
+// Needs the http, form_urlencoded, serde and serde_json crates, which all
+// build for wasm32-unknown-unknown. Requests go through a Transport, so any
+// HTTP client can be plugged in, including one on the browser's fetch: its
+// futures don't have to be Send.
+
+pub trait Transport {
+    fn send(
+        &self,
+        request: http::Request<Vec<u8>>,
+    ) -> impl std::future::Future<Output = Result<http::Response<Vec<u8>>, String>>;
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Transport(String),
+    Encode(String),
+    Decode(String),
+    // A response other than 2xx, with the code and message of its
+    // envelope.
+    Api {
+        status: u16,
+        code: String,
+        error: String,
+    },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Transport(err) => write!(f, \"transport error: {}\", err),
+            Error::Encode(err) => write!(f, \"failed to encode request: {}\", err),
+            Error::Decode(err) => write!(f, \"failed to decode response: {}\", err),
+            Error::Api { status, error, .. } => write!(f, \"{} {}\", status, error),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub struct Client<T> {
+    base_url: String,
+    transport: T,
+}
+
+impl<T: Transport> Client<T> {
+    pub fn new(base_url: impl Into<String>, transport: T) -> Self {
+        Self {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            transport,
+        }
+    }
+
+    async fn call(
+        &self,
+        method: &str,
+        path: String,
+        query: Vec<(&str, Option<String>)>,
+        headers: Vec<(&str, Option<String>)>,
+        body: Option<(&str, Vec<u8>)>,
+    ) -> Result<http::Response<Vec<u8>>, Error> {
+        let mut serializer = form_urlencoded::Serializer::new(String::new());
+        for (name, value) in &query {
+            if let Some(value) = value {
+                serializer.append_pair(name, value);
+            }
+        }
+        let query = serializer.finish();
+        let mut uri = format!(\"{}{}\", self.base_url, path);
+        if !query.is_empty() {
+            uri.push('?');
+            uri.push_str(&query);
+        }
+        let mut request = http::Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            if let Some(value) = value {
+                request = request.header(name, value);
+            }
+        }
+        let body = match body {
+            Some((content_type, body)) => {
+                request = request.header(\"Content-Type\", content_type);
+                body
+            }
+            None => Vec::new(),
+        };
+        let request = request
+            .body(body)
+            .map_err(|err| Error::Encode(err.to_string()))?;
+        self.transport.send(request).await.map_err(Error::Transport)
+    }
+}
+
+fn encode_path_segment(segment: &str) -> String {
+    let mut encoded = String::new();
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || b\"-._~\".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!(\"%{:02X}\", byte));
+        }
+    }
+    encoded
+}
+
+fn json_body(body: &impl serde::Serialize) -> Result<Vec<u8>, Error> {
+    serde_json::to_vec(body).map_err(|err| Error::Encode(err.to_string()))
+}
+
+fn form_body(body: &impl serde::Serialize) -> Result<Vec<u8>, Error> {
+    let serde_json::Value::Object(fields) =
+        serde_json::to_value(body).map_err(|err| Error::Encode(err.to_string()))?
+    else {
+        return Err(Error::Encode(\"form bodies must be objects\".to_string()));
+    };
+    let mut serializer = form_urlencoded::Serializer::new(String::new());
+    for (name, value) in fields {
+        match value {
+            serde_json::Value::Null => {}
+            serde_json::Value::String(value) => {
+                serializer.append_pair(&name, &value);
+            }
+            value => {
+                serializer.append_pair(&name, &value.to_string());
+            }
+        }
+    }
+    Ok(serializer.finish().into_bytes())
+}
+
+#[derive(serde::Deserialize)]
+struct Envelope<R> {
+    code: String,
+    message: String,
+    data: Option<R>,
+}
+
+fn decode<R: serde::de::DeserializeOwned>(response: http::Response<Vec<u8>>) -> Result<R, Error> {
+    let status = response.status();
+    if !status.is_success() {
+        let envelope = serde_json::from_slice::<Envelope<serde_json::Value>>(response.body()).ok();
+        return Err(match envelope {
+            Some(envelope) => Error::Api {
+                status: status.as_u16(),
+                code: envelope.code,
+                error: envelope.message,
+            },
+            // Not an envelope, like errors from a proxy in front.
+            None => Error::Api {
+                status: status.as_u16(),
+                code: String::new(),
+                error: status.canonical_reason().unwrap_or_default().to_string(),
+            },
+        });
+    }
+    serde_json::from_slice::<Envelope<R>>(response.body())
+        .map_err(|err| Error::Decode(err.to_string()))?
+        .data
+        .ok_or_else(|| Error::Decode(\"no data in the response\".to_string()))
+}
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The web frontend imports the generated client, which has to be
+    // regenerated whenever the API description changes.
+    #[test]
+    fn web_client_matches_the_api_description() {
+        let committed = include_str!("../web/src/lib/api.ts");
+        assert!(
+            committed == typescript(&openapi::document()),
+            "web/src/lib/api.ts is out of date, run \
+             `tk-auth generate-client --lang typescript --out web/src/lib`"
+        );
+    }
+}
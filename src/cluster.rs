@@ -0,0 +1,197 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+
+use crate::config;
+use crate::devices;
+use crate::jwt::base64url_encode;
+use crate::redis;
+use crate::rng;
+use crate::session::SessionEvent;
+use crate::AppState;
+
+const MAX_RECONNECT_DELAY_SECS: u64 = 30;
+
+// Sessions and remember-me tokens live in the memory of the instance that
+// created them, so user-wide revocations have to be repeated by every other
+// instance behind the same load balancer.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Invalidation {
+    UserRevoked { user: String },
+    DeviceRevoked { user: String, device: String },
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Message {
+    node: String,
+    #[serde(flatten)]
+    invalidation: Invalidation,
+}
+
+pub struct Cluster {
+    node: String,
+    outgoing: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    receiver: std::sync::Mutex<Option<mpsc::UnboundedReceiver<Vec<u8>>>>,
+}
+
+impl Cluster {
+    pub fn new(config: Option<&config::ClusterConfig>, rng: &dyn rng::Rng) -> Self {
+        let mut node = [0u8; 8];
+        rng.fill(&mut node);
+        let (outgoing, receiver) = match config {
+            Some(_) => {
+                let (outgoing, receiver) = mpsc::unbounded_channel();
+                (Some(outgoing), Some(receiver))
+            }
+            None => (None, None),
+        };
+        Self {
+            node: base64url_encode(&node),
+            outgoing,
+            receiver: std::sync::Mutex::new(receiver),
+        }
+    }
+
+    pub fn publish(&self, invalidation: Invalidation) {
+        if let Some(outgoing) = &self.outgoing {
+            let message = Message {
+                node: self.node.clone(),
+                invalidation,
+            };
+            let _ = outgoing.send(serde_json::to_vec(&message).unwrap());
+        }
+    }
+}
+
+pub fn spawn(state: Arc<AppState>) {
+    let Some(cluster) = &state.config.cluster else {
+        return;
+    };
+    let Some(receiver) = state.cluster.receiver.lock().unwrap().take() else {
+        return;
+    };
+    // Validated on load.
+    let url = redis::Url::parse(&cluster.redis_url).unwrap();
+    tokio::spawn(publish_loop(url.clone(), cluster.channel.clone(), receiver));
+    tokio::spawn(subscribe_loop(state.clone(), url, cluster.channel.clone()));
+}
+
+fn backoff(delay: &mut u64) -> Duration {
+    let current = *delay;
+    *delay = (*delay * 2).min(MAX_RECONNECT_DELAY_SECS);
+    Duration::from_secs(current)
+}
+
+async fn publish_loop(
+    url: redis::Url,
+    channel: String,
+    mut receiver: mpsc::UnboundedReceiver<Vec<u8>>,
+) {
+    let mut connection = None;
+    let mut delay = 1;
+    while let Some(message) = receiver.recv().await {
+        // Keep trying the same message, since invalidations are idempotent and
+        // losing one would leave sessions alive elsewhere.
+        loop {
+            let publishing = match &mut connection {
+                Some(connection) => connection,
+                None => match redis::Connection::connect(&url).await {
+                    Ok(connected) => connection.insert(connected),
+                    Err(err) => {
+                        println!("Failed to connect to Redis to publish: {}", err);
+                        tokio::time::sleep(backoff(&mut delay)).await;
+                        continue;
+                    }
+                },
+            };
+            match publishing
+                .command(&[b"PUBLISH", channel.as_bytes(), &message])
+                .await
+            {
+                Ok(redis::Value::Integer(_)) => {
+                    delay = 1;
+                    break;
+                }
+                Ok(reply) => {
+                    println!("Redis rejected an invalidation: {:?}", reply);
+                    break;
+                }
+                Err(err) => {
+                    println!("Lost connection to Redis while publishing: {}", err);
+                    connection = None;
+                }
+            }
+        }
+    }
+}
+
+async fn subscribe_loop(state: Arc<AppState>, url: redis::Url, channel: String) {
+    let mut delay = 1;
+    loop {
+        if let Err(err) = subscribe(&state, &url, &channel, &mut delay).await {
+            println!("Redis subscription failed: {}", err);
+        }
+        // Anything published while disconnected is lost; pub/sub has no
+        // replay.
+        tokio::time::sleep(backoff(&mut delay)).await;
+    }
+}
+
+async fn subscribe(
+    state: &AppState,
+    url: &redis::Url,
+    channel: &str,
+    delay: &mut u64,
+) -> io::Result<()> {
+    let mut connection = redis::Connection::connect(url).await?;
+    connection.send(&[b"SUBSCRIBE", channel.as_bytes()]).await?;
+    println!("Subscribed to invalidations on Redis channel {}", channel);
+    *delay = 1;
+    loop {
+        let redis::Value::Array(Some(reply)) = connection.read().await? else {
+            continue;
+        };
+        let [redis::Value::Bulk(Some(kind)), _, redis::Value::Bulk(Some(payload))] = &reply[..]
+        else {
+            continue;
+        };
+        if kind != b"message" {
+            continue;
+        }
+        match serde_json::from_slice::<Message>(payload) {
+            Ok(message) if message.node == state.cluster.node => {}
+            Ok(message) => apply(state, message.invalidation).await,
+            Err(err) => println!("Ignoring malformed invalidation: {}", err),
+        }
+    }
+}
+
+async fn apply(state: &AppState, invalidation: Invalidation) {
+    match invalidation {
+        Invalidation::UserRevoked { user } => {
+            let sessions = state.sessions.remove_user(&user).await;
+            for session in &sessions {
+                session.write().await.publish(SessionEvent::Revoked);
+            }
+            let forgotten = state.remember_me.revoke_user(&user).await;
+            println!(
+                "Revoked {} sessions and {} remember-me tokens of {} for another instance",
+                sessions.len(),
+                forgotten,
+                user
+            );
+        }
+        Invalidation::DeviceRevoked { user, device } => {
+            state.devices.forget(&user, &device).await;
+            let (revoked, forgotten) = devices::revoke_device(state, &user, &device).await;
+            println!(
+                "Removed device {} of {} with {} sessions and {} remember-me tokens for another \
+                 instance",
+                device, user, revoked, forgotten
+            );
+        }
+    }
+}
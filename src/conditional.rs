@@ -0,0 +1,131 @@
+use std::sync::Arc;
+
+use crate::jwt::base64url_encode;
+use crate::time;
+
+// GET routes whose responses get an ETag from their content, so polling
+// clients can send it back in If-None-Match and get an empty 304 while
+// nothing changed.
+pub const ROUTES: [&str; 10] = [
+    "/api/sessions/mine",
+    "/api/devices",
+    "/api/notifications",
+    "/api/notification_preferences",
+    "/api/idp/identities",
+    "/api/admin/deliveries",
+    "/scim/v2/Users",
+    "/scim/v2/Users/:id",
+    "/scim/v2/Groups",
+    "/scim/v2/Groups/:id",
+];
+// Routes whose responses only change with the configuration. Besides the
+// ETag, these get the instance's start as Last-Modified, for
+// If-Modified-Since.
+pub const STATIC_ROUTES: [&str; 4] = [
+    "/api/branding",
+    "/api/idp/providers",
+    "/api/openapi.json",
+    "/scim/v2/ServiceProviderConfig",
+];
+// Larger responses go out without an ETag.
+const MAX_TAGGED_BODY_BYTES: u64 = 4 * 1024 * 1024;
+
+pub struct Route {
+    pub last_modified: Option<u64>,
+}
+
+fn not_modified(headers: &http::HeaderMap, etag: &str, last_modified: Option<u64>) -> bool {
+    // If-Modified-Since only counts without If-None-Match (RFC 9110, 13.2.2).
+    if headers.contains_key(http::header::IF_NONE_MATCH) {
+        return crate::etag_matches(headers, etag);
+    }
+    let since = headers
+        .get(http::header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(time::parse_http_date);
+    matches!((since, last_modified), (Some(since), Some(modified)) if modified <= since)
+}
+
+// Responses that set their own ETag, like /api/session_state, are left
+// alone.
+pub async fn layer(
+    axum::extract::State(route): axum::extract::State<Arc<Route>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    if request.method() != http::Method::GET && request.method() != http::Method::HEAD {
+        return next.run(request).await;
+    }
+    let request_headers = request.headers().clone();
+    let response = next.run(request).await;
+    let small = axum::body::HttpBody::size_hint(response.body())
+        .exact()
+        .is_some_and(|len| len <= MAX_TAGGED_BODY_BYTES);
+    if response.status() != http::StatusCode::OK
+        || response.headers().contains_key(http::header::ETAG)
+        || !small
+    {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let Ok(body) = axum::body::to_bytes(body, MAX_TAGGED_BODY_BYTES as usize).await else {
+        return crate::error_response(500, "failed to read the response");
+    };
+    let digest = ring::digest::digest(&ring::digest::SHA256, &body);
+    let etag = format!("W/\"{}\"", base64url_encode(&digest.as_ref()[..16]));
+    parts.headers.insert(
+        http::header::ETAG,
+        http::HeaderValue::from_str(&etag).unwrap(),
+    );
+    if let Some(last_modified) = route.last_modified {
+        parts.headers.insert(
+            http::header::LAST_MODIFIED,
+            http::HeaderValue::from_str(&time::http_date(last_modified)).unwrap(),
+        );
+    }
+    if not_modified(&request_headers, &etag, route.last_modified) {
+        parts.status = http::StatusCode::NOT_MODIFIED;
+        parts.headers.remove(http::header::CONTENT_LENGTH);
+        parts.headers.remove(http::header::CONTENT_TYPE);
+        return axum::response::Response::from_parts(parts, axum::body::Body::empty());
+    }
+    axum::response::Response::from_parts(parts, axum::body::Body::from(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_if_none_match_over_if_modified_since() {
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        assert_eq!(time::parse_http_date(date), Some(784111777));
+        assert_eq!(time::http_date(784111777), date);
+        assert_eq!(
+            time::parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"),
+            None
+        );
+
+        let headers = |pairs: &[(http::HeaderName, &'static str)]| {
+            let mut headers = http::HeaderMap::new();
+            for (name, value) in pairs {
+                headers.append(name, http::HeaderValue::from_static(value));
+            }
+            headers
+        };
+        let since = |value| headers(&[(http::header::IF_MODIFIED_SINCE, value)]);
+        assert!(not_modified(&since(date), "\"a\"", Some(784111777)));
+        assert!(!not_modified(&since(date), "\"a\"", Some(784111778)));
+        assert!(!not_modified(&since(date), "\"a\"", None));
+        assert!(!not_modified(&since("yesterday"), "\"a\"", Some(0)));
+
+        let none_match = |value| headers(&[(http::header::IF_NONE_MATCH, value)]);
+        assert!(not_modified(&none_match("\"b\", W/\"a\""), "\"a\"", None));
+        assert!(!not_modified(&none_match("\"b\""), "\"a\"", None));
+        let both = headers(&[
+            (http::header::IF_NONE_MATCH, "\"b\""),
+            (http::header::IF_MODIFIED_SINCE, date),
+        ]);
+        assert!(!not_modified(&both, "\"a\"", Some(0)));
+    }
+}
@@ -0,0 +1,2130 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use base64::Engine;
+
+use crate::cors;
+use crate::openapi;
+use crate::redis;
+use crate::security_headers;
+use crate::session;
+use crate::time;
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: String,
+    pub tls: Option<TlsConfig>,
+    pub limits: LimitsConfig,
+    pub sessions: SessionsConfig,
+    pub idempotency: Option<IdempotencyConfig>,
+    pub session_quota: Option<QuotaConfig>,
+    pub deprecations: Vec<DeprecationConfig>,
+    pub trusted_proxies: Vec<IpAddr>,
+    pub session_binding: SessionBindingConfig,
+    pub security_headers: SecurityHeadersConfig,
+    pub cors: CorsConfig,
+    pub secrets: SecretsConfig,
+    pub encryption: Option<EncryptionConfig>,
+    pub backup: Option<BackupConfig>,
+    pub health: HealthConfig,
+    pub auth_backend: AuthBackendConfig,
+    pub kerberos: Option<KerberosConfig>,
+    pub users: UsersConfig,
+    pub scim: Option<ScimConfig>,
+    pub forward_auth: Option<ForwardAuthConfig>,
+    pub admin: Option<AdminConfig>,
+    pub request_signing: Option<RequestSigningConfig>,
+    pub idp: Option<IdpConfig>,
+    pub remember_me: Option<RememberMeConfig>,
+    pub captcha: Option<CaptchaConfig>,
+    pub proof_of_work: Option<ProofOfWorkConfig>,
+    pub geoip: Option<GeoIpConfig>,
+    pub new_device_alerts: Option<NewDeviceAlertsConfig>,
+    pub risk: Option<RiskConfig>,
+    pub tarpit: Option<TarpitConfig>,
+    pub disposable_emails: Option<DisposableEmailsConfig>,
+    pub email: Option<EmailConfig>,
+    pub notifications: NotificationsConfig,
+    pub delivery_queue: Option<DeliveryQueueConfig>,
+    pub inbox: Option<InboxConfig>,
+    pub security_digest: Option<SecurityDigestConfig>,
+    pub admin_alerts: Option<AdminAlertsConfig>,
+    pub cluster: Option<ClusterConfig>,
+    pub pages: Option<PagesConfig>,
+    pub branding: BrandingConfig,
+    pub i18n: I18nConfig,
+    pub web: WebConfig,
+    pub api_docs: Option<ApiDocsConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: String::from("0.0.0.0:3000"),
+            tls: None,
+            limits: LimitsConfig::default(),
+            sessions: SessionsConfig::default(),
+            idempotency: None,
+            session_quota: None,
+            deprecations: Vec::new(),
+            trusted_proxies: Vec::new(),
+            session_binding: SessionBindingConfig::default(),
+            security_headers: SecurityHeadersConfig::default(),
+            cors: CorsConfig::default(),
+            secrets: SecretsConfig::default(),
+            encryption: None,
+            backup: None,
+            health: HealthConfig::default(),
+            auth_backend: AuthBackendConfig::default(),
+            kerberos: None,
+            users: UsersConfig::default(),
+            scim: None,
+            forward_auth: None,
+            admin: None,
+            request_signing: None,
+            idp: None,
+            remember_me: None,
+            captcha: None,
+            proof_of_work: None,
+            geoip: None,
+            new_device_alerts: None,
+            risk: None,
+            tarpit: None,
+            disposable_emails: None,
+            email: None,
+            notifications: NotificationsConfig::default(),
+            delivery_queue: None,
+            inbox: None,
+            security_digest: None,
+            admin_alerts: None,
+            cluster: None,
+            pages: None,
+            branding: BrandingConfig::default(),
+            i18n: I18nConfig::default(),
+            web: WebConfig::default(),
+            api_docs: None,
+        }
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    pub client_auth: Option<ClientAuthConfig>,
+}
+
+#[derive(Clone, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthBackendConfig {
+    #[default]
+    None,
+    Pam(PamConfig),
+    Htpasswd(HtpasswdConfig),
+    Local,
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[cfg_attr(not(feature = "pam"), allow(dead_code))]
+pub struct PamConfig {
+    pub service: String,
+    pub threads: usize,
+}
+
+impl Default for PamConfig {
+    fn default() -> Self {
+        Self {
+            service: String::from("tk-auth"),
+            threads: 4,
+        }
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HtpasswdConfig {
+    pub path: PathBuf,
+    #[serde(default = "default_htpasswd_reload_secs")]
+    pub reload_secs: u64,
+}
+
+fn default_htpasswd_reload_secs() -> u64 {
+    5
+}
+
+#[derive(Clone, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KerberosConfig {
+    pub keytab_path: Option<PathBuf>,
+    pub allowed_realms: Vec<String>,
+    pub users: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UsersConfig {
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScimConfig {
+    pub bearer_token: zeroize::Zeroizing<String>,
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    pub bearer_token: zeroize::Zeroizing<String>,
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RequestSigningConfig {
+    pub keys: Vec<RequestSigningKey>,
+    // How far a signed timestamp may be from the server's clock.
+    pub max_skew_secs: u64,
+}
+
+impl Default for RequestSigningConfig {
+    fn default() -> Self {
+        Self {
+            keys: Vec::new(),
+            max_skew_secs: 300,
+        }
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RequestSigningKey {
+    pub id: String,
+    pub secret_base64: zeroize::Zeroizing<String>,
+    // The API the key may sign requests to, in place of its bearer token.
+    pub scope: SigningScope,
+    // Requests signed with the key, on any route.
+    #[serde(default)]
+    pub quota: Option<QuotaConfig>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SigningScope {
+    Admin,
+    Scim,
+}
+
+// Tokens are kept in this instance's memory only: a restart forgets them and
+// other instances don't know them. So remember_me is refused together with
+// sessions.persistence or sessions.memcached, whose sessions survive both.
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RememberMeConfig {
+    pub lifetime_secs: u64,
+    pub cookie_name: String,
+    pub cookie_secure: bool,
+}
+
+impl Default for RememberMeConfig {
+    fn default() -> Self {
+        Self {
+            lifetime_secs: 30 * 24 * 60 * 60,
+            cookie_name: String::from("tk_auth_remember"),
+            cookie_secure: true,
+        }
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CaptchaConfig {
+    pub provider: CaptchaProvider,
+    pub site_key: String,
+    pub secret: zeroize::Zeroizing<String>,
+    // Defaults to the provider's siteverify endpoint.
+    #[serde(default)]
+    pub verify_url: Option<String>,
+    // Failed sign-ins for a user or from a network before a CAPTCHA is
+    // needed; 0 always needs one.
+    #[serde(default = "default_captcha_after_failures")]
+    pub after_failures: u32,
+    #[serde(default = "default_captcha_failure_window_secs")]
+    pub failure_window_secs: u64,
+}
+
+fn default_captcha_after_failures() -> u32 {
+    3
+}
+
+fn default_captcha_failure_window_secs() -> u64 {
+    15 * 60
+}
+
+#[derive(Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptchaProvider {
+    Hcaptcha,
+    Recaptcha,
+    Turnstile,
+}
+
+// Makes POST /api/new_session ask for a proof of work while more than
+// activate_above_per_sec sessions a second are being requested.
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProofOfWorkConfig {
+    pub difficulty_bits: u8,
+    pub activate_above_per_sec: u32,
+    pub challenge_ttl_secs: u64,
+}
+
+impl Default for ProofOfWorkConfig {
+    fn default() -> Self {
+        Self {
+            difficulty_bits: 18,
+            activate_above_per_sec: 100,
+            challenge_ttl_secs: 60,
+        }
+    }
+}
+
+// MaxMind DB files, such as GeoLite2-Country (or -City) and GeoLite2-ASN, to
+// locate sign-ins with. They are read at startup.
+#[derive(Clone, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeoIpConfig {
+    pub country_db_path: Option<PathBuf>,
+    pub asn_db_path: Option<PathBuf>,
+    // Needs a City database in country_db_path, only those have coordinates.
+    pub impossible_travel: Option<ImpossibleTravelConfig>,
+}
+
+// A sign-in is impossible travel when getting there from the user's last
+// sign-in would have been faster than max_speed_kmh.
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImpossibleTravelConfig {
+    pub max_speed_kmh: f64,
+    pub action: ImpossibleTravelAction,
+}
+
+impl Default for ImpossibleTravelConfig {
+    fn default() -> Self {
+        Self {
+            max_speed_kmh: 1000.0,
+            action: ImpossibleTravelAction::Deny,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImpossibleTravelAction {
+    Deny,
+    // Only records an audit event.
+    Audit,
+}
+
+// Sign-ins from a device a user hasn't used before are sent to the user over
+// channels, those of them that are configured. The alert's "this wasn't me"
+// link is on public_url, where this server is reachable.
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NewDeviceAlertsConfig {
+    pub public_url: String,
+    #[serde(default = "default_new_device_link_ttl_secs")]
+    pub link_ttl_secs: u64,
+    #[serde(default = "all_notification_channels")]
+    pub channels: Vec<NotificationChannel>,
+}
+
+fn default_new_device_link_ttl_secs() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn all_notification_channels() -> Vec<NotificationChannel> {
+    vec![
+        NotificationChannel::Email,
+        NotificationChannel::Webhook,
+        NotificationChannel::Sms,
+        NotificationChannel::Inbox,
+    ]
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationChannel {
+    // Through the email section, to the user's first email.
+    Email,
+    Webhook,
+    // To the user's first phone number.
+    Sms,
+    // Kept for the user to read through the API.
+    Inbox,
+}
+
+// Where notifications go besides email. The webhook gets them as JSON with
+// the user's emails and phone numbers, for deployments that deliver them
+// themselves.
+#[derive(Clone, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationsConfig {
+    pub webhook_url: Option<String>,
+    pub sms: Option<SmsConfig>,
+}
+
+// Text messages are posted to url as a form with To, From and Body, the way
+// Twilio's Messages API and compatible gateways take them, with username and
+// password as basic auth.
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SmsConfig {
+    pub url: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<zeroize::Zeroizing<String>>,
+    pub from: String,
+}
+
+// Notifications are kept in a file until they are delivered, and retried
+// with exponential backoff. Ones that still fail after max_attempts are
+// dead-lettered: kept for the admin API to show and retry, but not sent.
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeliveryQueueConfig {
+    // Defaults to users.path with .deliveries appended.
+    pub path: Option<PathBuf>,
+    pub max_attempts: u32,
+    pub initial_backoff_secs: u64,
+    pub max_backoff_secs: u64,
+    // How long delivered notifications are still listed.
+    pub keep_delivered_secs: u64,
+    // Past this, new notifications are dropped rather than queued.
+    pub max_deliveries: usize,
+}
+
+impl Default for DeliveryQueueConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            max_attempts: 8,
+            initial_backoff_secs: 30,
+            max_backoff_secs: 60 * 60,
+            keep_delivered_secs: 24 * 60 * 60,
+            max_deliveries: 10_000,
+        }
+    }
+}
+
+impl DeliveryQueueConfig {
+    pub fn path(&self, users: &UsersConfig) -> Option<PathBuf> {
+        self.path.clone().or_else(|| {
+            let mut path = users.path.clone()?.into_os_string();
+            path.push(".deliveries");
+            Some(PathBuf::from(path))
+        })
+    }
+}
+
+// Notifications kept per user for frontends to show, in a file next to the
+// user store like the delivery queue's.
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InboxConfig {
+    // Defaults to users.path with .inbox appended.
+    pub path: Option<PathBuf>,
+    // Past this, a user's oldest notifications are dropped.
+    pub max_per_user: usize,
+    pub keep_secs: u64,
+}
+
+impl Default for InboxConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            max_per_user: 100,
+            keep_secs: 90 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl InboxConfig {
+    pub fn path(&self, users: &UsersConfig) -> Option<PathBuf> {
+        self.path.clone().or_else(|| {
+            let mut path = users.path.clone()?.into_os_string();
+            path.push(".inbox");
+            Some(PathBuf::from(path))
+        })
+    }
+}
+
+// Emails users who turn it on in their notification preferences a summary
+// of their sign-ins, new devices and remember-me tokens every period.
+#[derive(Clone, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityDigestConfig {
+    pub period: DigestPeriod,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DigestPeriod {
+    #[default]
+    Weekly,
+    Monthly,
+}
+
+impl DigestPeriod {
+    pub fn secs(self) -> u64 {
+        match self {
+            Self::Weekly => 7 * 24 * 60 * 60,
+            Self::Monthly => 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+// Messages for the operators about suspicious activity, posted to chat
+// webhooks when one of the rules that are set matches.
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminAlertsConfig {
+    pub webhooks: Vec<AlertWebhook>,
+    #[serde(default)]
+    pub failed_sign_ins: Option<FailedSignInsAlert>,
+    #[serde(default)]
+    pub privileged_sign_ins: Option<PrivilegedSignInsAlert>,
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertWebhook {
+    pub kind: AlertWebhookKind,
+    pub url: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertWebhookKind {
+    Slack,
+    Discord,
+    Mattermost,
+}
+
+// Alerts once per window when failed password sign-ins across all users
+// reach threshold within it.
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FailedSignInsAlert {
+    pub threshold: u32,
+    pub window_secs: u64,
+}
+
+impl Default for FailedSignInsAlert {
+    fn default() -> Self {
+        Self {
+            threshold: 50,
+            window_secs: 5 * 60,
+        }
+    }
+}
+
+// Alerts when a user in one of roles signs in from an IP address they
+// weren't seen at before.
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PrivilegedSignInsAlert {
+    pub roles: Vec<String>,
+}
+
+// Password sign-ins are posted to url as JSON, with what is known about the
+// client and how often it tried lately, and go ahead as the JSON answer's
+// "decision" says.
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RiskConfig {
+    pub url: String,
+    #[serde(default = "default_risk_timeout_ms")]
+    pub timeout_ms: u64,
+    // What to do when the service doesn't answer in time or properly.
+    #[serde(default = "default_risk_on_error")]
+    pub on_error: RiskDecision,
+    #[serde(default = "default_risk_velocity_window_secs")]
+    pub velocity_window_secs: u64,
+}
+
+fn default_risk_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_risk_on_error() -> RiskDecision {
+    RiskDecision::Allow
+}
+
+fn default_risk_velocity_window_secs() -> u64 {
+    60 * 60
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RiskDecision {
+    Allow,
+    Deny,
+    // Signing in needs a CAPTCHA, which needs captcha to be configured.
+    StepUp,
+}
+
+// Password sign-ins for a user or from a network with after_failures recent
+// failures are delayed by base_delay_ms, doubling with each further failure
+// up to max_delay_ms. At most max_concurrent requests are held back at a
+// time; others are refused with 429.
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TarpitConfig {
+    pub after_failures: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub failure_window_secs: u64,
+    pub max_concurrent: usize,
+}
+
+impl Default for TarpitConfig {
+    fn default() -> Self {
+        Self {
+            after_failures: 3,
+            base_delay_ms: 500,
+            max_delay_ms: 10_000,
+            failure_window_secs: 15 * 60,
+            max_concurrent: 1000,
+        }
+    }
+}
+
+// Users created on first sign-in through an identity provider are checked
+// against a bundled list of disposable email providers, extended with
+// domains and the file at domains_path (one domain per line).
+// allowed_domains takes domains off the list.
+#[derive(Clone, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisposableEmailsConfig {
+    pub action: DisposableEmailAction,
+    pub domains: Vec<String>,
+    pub domains_path: Option<PathBuf>,
+    pub allowed_domains: Vec<String>,
+}
+
+#[derive(Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisposableEmailAction {
+    #[default]
+    Reject,
+    // Creates the user and records an audit event.
+    Flag,
+}
+
+// Mail goes out through the SMTP server at host. With tls "implicit" the
+// connection starts with TLS (port 465 by default), with "starttls" it is
+// upgraded before anything else is sent (587), and "none" (25) is only for
+// a relay on the same host. Files named <template>.txt in templates_dir
+// replace the built-in templates, and ones in a subdirectory named after a
+// language tag (de, pt-BR) are sent to users who prefer that language.
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmailConfig {
+    pub host: String,
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default = "default_email_tls")]
+    pub tls: EmailTls,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<zeroize::Zeroizing<String>>,
+    pub from: String,
+    // The name given in EHLO.
+    #[serde(default = "default_email_hello_name")]
+    pub hello_name: String,
+    // Connections open at a time; idle ones are kept for idle_timeout_secs.
+    #[serde(default = "default_email_pool_size")]
+    pub pool_size: usize,
+    #[serde(default = "default_email_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+    // For sending one message, connecting included.
+    #[serde(default = "default_email_timeout_secs")]
+    pub timeout_secs: u64,
+    #[serde(default)]
+    pub templates_dir: Option<PathBuf>,
+}
+
+fn default_email_tls() -> EmailTls {
+    EmailTls::Starttls
+}
+
+fn default_email_hello_name() -> String {
+    String::from("localhost")
+}
+
+fn default_email_pool_size() -> usize {
+    4
+}
+
+fn default_email_idle_timeout_secs() -> u64 {
+    60
+}
+
+fn default_email_timeout_secs() -> u64 {
+    30
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailTls {
+    Implicit,
+    Starttls,
+    None,
+}
+
+#[derive(Clone, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PagesConfig {
+    // Hosts ?rd= may send people to after signing in, besides paths on this
+    // host. Entries starting with a dot also match subdomains.
+    pub redirect_hosts: Vec<String>,
+    // Adds a field to the login form that people don't see but form-filling
+    // bots do. Submissions that fill it in are refused like a wrong password.
+    pub honeypot: bool,
+}
+
+#[derive(Clone, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct I18nConfig {
+    pub bundles_dir: Option<PathBuf>,
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebConfig {
+    // Serve the .br and .gz files next to each asset to clients that accept
+    // them, as built with the static adapter's precompress option.
+    pub precompressed: bool,
+    // For the content-hashed files under /web/_app/immutable/, which never
+    // change under the same name.
+    pub immutable_max_age_secs: u64,
+    // For everything else, such as index.html, which has to be revalidated
+    // to pick up new builds.
+    pub max_age_secs: u64,
+}
+
+impl Default for WebConfig {
+    fn default() -> Self {
+        Self {
+            precompressed: true,
+            immutable_max_age_secs: 365 * 24 * 60 * 60,
+            max_age_secs: 0,
+        }
+    }
+}
+
+// Serves Swagger UI at /api/docs. Its files come from swagger_ui_dir, such
+// as the dist directory of a vendored swagger-ui-dist package, so that no
+// script from another origin runs next to the login page and its cookies.
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiDocsConfig {
+    pub swagger_ui_dir: PathBuf,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrandingConfig {
+    pub product_name: String,
+    pub logo_url: Option<String>,
+    pub accent_color: String,
+    pub background_color: String,
+    pub footer_links: Vec<FooterLink>,
+}
+
+impl Default for BrandingConfig {
+    fn default() -> Self {
+        Self {
+            product_name: String::from("tk-auth"),
+            logo_url: None,
+            accent_color: String::from("#2563eb"),
+            background_color: String::from("#f4f4f5"),
+            footer_links: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FooterLink {
+    pub label: String,
+    pub url: String,
+}
+
+// Colors end up in the pages' CSS, so only plain hex colors are accepted.
+fn is_hex_color(color: &str) -> bool {
+    let Some(hex) = color.strip_prefix('#') else {
+        return false;
+    };
+    matches!(hex.len(), 3 | 6) && hex.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+fn is_link(url: &str) -> bool {
+    (url.starts_with("https://") || url.starts_with("http://") || url.starts_with('/'))
+        && !url.starts_with("//")
+        && !url.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterConfig {
+    pub redis_url: zeroize::Zeroizing<String>,
+    pub channel: String,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            redis_url: zeroize::Zeroizing::new(String::from("redis://127.0.0.1:6379")),
+            channel: String::from("tk-auth:invalidations"),
+        }
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IdpConfig {
+    pub public_url: String,
+    #[serde(default)]
+    pub after_login_url: Option<String>,
+    pub providers: Vec<IdentityProviderConfig>,
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IdentityProviderConfig {
+    pub id: String,
+    pub display_name: String,
+    pub issuer: String,
+    pub client_id: String,
+    #[serde(default)]
+    pub client_secret: Option<zeroize::Zeroizing<String>>,
+    #[serde(default = "default_idp_scopes")]
+    pub scopes: Vec<String>,
+    #[serde(default)]
+    pub link_verified_email: bool,
+    #[serde(default)]
+    pub create_users: bool,
+}
+
+fn default_idp_scopes() -> Vec<String> {
+    vec![
+        String::from("openid"),
+        String::from("email"),
+        String::from("profile"),
+    ]
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardAuthConfig {
+    pub cookie_name: String,
+    pub cookie_domain: Option<String>,
+    pub cookie_secure: bool,
+    pub login_url: Option<String>,
+}
+
+impl Default for ForwardAuthConfig {
+    fn default() -> Self {
+        Self {
+            cookie_name: String::from("tk_auth_session"),
+            cookie_domain: None,
+            cookie_secure: true,
+            login_url: None,
+        }
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientAuthConfig {
+    pub ca_path: PathBuf,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default)]
+    pub users: BTreeMap<String, String>,
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub request_timeout_secs: u64,
+    pub header_read_timeout_secs: u64,
+    pub max_header_bytes: usize,
+    pub max_headers: usize,
+    pub max_body_bytes: usize,
+    pub routes: BTreeMap<String, RouteLimitsConfig>,
+    // Connections past this wait in the listen backlog until one closes.
+    pub max_connections: Option<usize>,
+    // Requests past this are answered with 503 right away.
+    pub max_in_flight_requests: Option<usize>,
+    pub retry_after_secs: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            request_timeout_secs: 30,
+            header_read_timeout_secs: 10,
+            max_header_bytes: 16 * 1024,
+            max_headers: 64,
+            max_body_bytes: 64 * 1024,
+            routes: BTreeMap::from([
+                (
+                    String::from("/api/session_state"),
+                    RouteLimitsConfig {
+                        timeout_secs: Some(75),
+                        max_body_bytes: None,
+                    },
+                ),
+                (
+                    String::from("/api/admin/import_users"),
+                    RouteLimitsConfig {
+                        timeout_secs: None,
+                        max_body_bytes: Some(16 * 1024 * 1024),
+                    },
+                ),
+                (
+                    String::from("/api/sessions/batch"),
+                    RouteLimitsConfig {
+                        timeout_secs: None,
+                        max_body_bytes: Some(1024 * 1024),
+                    },
+                ),
+            ]),
+            max_connections: None,
+            max_in_flight_requests: None,
+            retry_after_secs: 1,
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn route_timeout_secs(&self, path: &str) -> u64 {
+        self.routes
+            .get(path)
+            .and_then(|route| route.timeout_secs)
+            .unwrap_or(self.request_timeout_secs)
+    }
+
+    pub fn route_max_body_bytes(&self, path: &str) -> usize {
+        self.routes
+            .get(path)
+            .and_then(|route| route.max_body_bytes)
+            .unwrap_or(self.max_body_bytes)
+    }
+}
+
+#[derive(Clone, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouteLimitsConfig {
+    pub timeout_secs: Option<u64>,
+    pub max_body_bytes: Option<usize>,
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionsConfig {
+    pub id_bytes: usize,
+    // Lengths of unsigned ids still accepted, to carry sessions over from
+    // a store that issued them. Such ids skip the signature check, so this
+    // is empty unless set for a migration.
+    pub legacy_id_bytes: Vec<usize>,
+    pub signing_keys: Vec<SecretKeyConfig>,
+    pub active_signing_key: Option<u8>,
+    pub max_data_bytes: usize,
+    pub expiry: ExpiryConfig,
+    pub reauth_max_age_secs: u64,
+    pub max_per_user: Option<usize>,
+    pub on_limit: SessionLimitMode,
+    pub rotate_on_authentication: bool,
+    pub memory_budget_bytes: Option<usize>,
+    pub on_memory_budget: MemoryBudgetMode,
+    pub persistence: Option<SessionPersistenceConfig>,
+    pub memcached: Option<MemcachedConfig>,
+}
+
+impl Default for SessionsConfig {
+    fn default() -> Self {
+        Self {
+            id_bytes: 32,
+            legacy_id_bytes: Vec::new(),
+            signing_keys: Vec::new(),
+            active_signing_key: None,
+            max_data_bytes: 16 * 1024,
+            expiry: ExpiryConfig::default(),
+            reauth_max_age_secs: 300,
+            max_per_user: None,
+            on_limit: SessionLimitMode::Reject,
+            rotate_on_authentication: false,
+            memory_budget_bytes: None,
+            on_memory_budget: MemoryBudgetMode::Reject,
+            persistence: None,
+            memcached: None,
+        }
+    }
+}
+
+// Keeps sessions across restarts and crashes: every change is appended to
+// a journal at path with .wal appended, which is compacted into a snapshot
+// at path every snapshot_interval_secs. Both hold session ids, so only the
+// owner can read them. Touches of sliding sessions are saved when they move
+// the deadline by a tenth of the idle timeout or a minute.
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionPersistenceConfig {
+    // Defaults to users.path with .sessions appended.
+    pub path: Option<PathBuf>,
+    pub snapshot_interval_secs: u64,
+    // Waits for each batch of changes to reach the disk, so they survive
+    // power losses too, not just crashes.
+    pub sync: bool,
+    // Keeps journal entries this long after they're in a snapshot, so
+    // tk-auth restore can replay them on top of a backup. At least
+    // backup.interval_secs allows restoring to any time since the newest.
+    pub keep_journal_secs: u64,
+}
+
+impl Default for SessionPersistenceConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            snapshot_interval_secs: 5 * 60,
+            sync: true,
+            keep_journal_secs: 0,
+        }
+    }
+}
+
+impl SessionPersistenceConfig {
+    pub fn path(&self, users: &UsersConfig) -> Option<PathBuf> {
+        self.path.clone().or_else(|| {
+            let mut path = users.path.clone()?.into_os_string();
+            path.push(".sessions");
+            Some(PathBuf::from(path))
+        })
+    }
+}
+
+// Keeps sessions in memcached, shared by every instance with the same
+// servers and key_prefix, for deployments that run memcached already. Each
+// lookup reads the session from memcached and changes are written behind,
+// with compare-and-swap so older copies never overwrite newer ones. Needs
+// the same sessions.signing_keys on every instance. memcached expires
+// sessions itself, but may also evict them when it runs out of memory,
+// which signs them out.
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemcachedConfig {
+    // host:port of each server. Sessions are spread over them by key.
+    pub servers: Vec<String>,
+    #[serde(default = "default_memcached_key_prefix")]
+    pub key_prefix: String,
+    #[serde(default = "default_memcached_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_memcached_key_prefix() -> String {
+    String::from("tk-auth:")
+}
+
+fn default_memcached_timeout_ms() -> u64 {
+    500
+}
+
+impl SessionsConfig {
+    pub fn active_signing_key(&self) -> u8 {
+        self.active_signing_key
+            .or_else(|| self.signing_keys.last().map(|key| key.id))
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdempotencyConfig {
+    // How long a key's response is replayed.
+    pub window_secs: u64,
+    // Keys remembered at once; past this, requests are handled as if they
+    // had no key.
+    pub max_keys: usize,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: 24 * 60 * 60,
+            max_keys: 10000,
+        }
+    }
+}
+
+// Requests a client may make per minute and per UTC day, counted in
+// windows that start on the clock's minute and day. Unset means no limit.
+#[derive(Clone, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaConfig {
+    pub per_minute: Option<u64>,
+    pub per_day: Option<u64>,
+}
+
+impl QuotaConfig {
+    fn validate(&self, name: &str) -> Result<(), String> {
+        match (self.per_minute, self.per_day) {
+            (None, None) => Err(format!("{} needs per_minute or per_day", name)),
+            (Some(0), _) | (_, Some(0)) => Err(format!("{} limits must not be 0", name)),
+            _ => Ok(()),
+        }
+    }
+}
+
+// A route on its way out. Its responses say so in Deprecation and Sunset
+// headers, and its use is reported at /api/admin/deprecations.
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeprecationConfig {
+    // As routed, such as /api/devices/:id.
+    pub route: String,
+    // YYYY-MM-DD in UTC, which may be in the future to announce it.
+    pub deprecated: String,
+    // The day the route is meant to be removed.
+    #[serde(default)]
+    pub sunset: Option<String>,
+    // A page about the deprecation, such as what to use instead.
+    #[serde(default)]
+    pub link: Option<String>,
+}
+
+impl DeprecationConfig {
+    pub fn deprecated_secs(&self) -> u64 {
+        time::parse_date(&self.deprecated).unwrap_or_default()
+    }
+
+    pub fn sunset_secs(&self) -> Option<u64> {
+        self.sunset.as_deref().and_then(time::parse_date)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let name = format!("deprecations entry for {}", self.route);
+        if !self.route.starts_with('/') {
+            return Err(format!("{} needs a route starting with /", name));
+        }
+        if time::parse_date(&self.deprecated).is_none() {
+            return Err(format!("{} needs deprecated as YYYY-MM-DD", name));
+        }
+        match &self.sunset {
+            Some(sunset) if time::parse_date(sunset).is_none() => {
+                return Err(format!("{} needs sunset as YYYY-MM-DD", name));
+            }
+            Some(_) if self.sunset_secs() < Some(self.deprecated_secs()) => {
+                return Err(format!("{} can't sunset before it's deprecated", name));
+            }
+            _ => {}
+        }
+        if self
+            .link
+            .as_deref()
+            .is_some_and(|link| !link.starts_with("https://") && !link.starts_with("http://"))
+        {
+            return Err(format!("{} needs an http(s) link", name));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionLimitMode {
+    Reject,
+    EvictOldest,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MemoryBudgetMode {
+    Reject,
+    // Anonymous sessions go first, then the least recently seen.
+    EvictIdle,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExpiryPolicy {
+    pub idle_timeout_secs: Option<u64>,
+    pub absolute_lifetime_secs: Option<u64>,
+}
+
+#[derive(Clone, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExpiryConfig {
+    pub idle_timeout_secs: Option<u64>,
+    pub absolute_lifetime_secs: Option<u64>,
+    pub roles: BTreeMap<String, ExpiryPolicy>,
+    // Sends an expiring_soon event this long before a session expires. Sent
+    // by the expiry sweep, so up to a minute late.
+    pub warn_before_secs: Option<u64>,
+}
+
+impl ExpiryConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.idle_timeout_secs.is_some()
+            || self.absolute_lifetime_secs.is_some()
+            || !self.roles.is_empty()
+    }
+
+    // Role policies replace the global one, so a role can get longer sessions
+    // as well as shorter ones. With several matching roles the strictest limit
+    // of each kind wins.
+    pub fn policy_for(&self, roles: &[String]) -> ExpiryPolicy {
+        let min = |a: Option<u64>, b: Option<u64>| match (a, b) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        roles
+            .iter()
+            .filter_map(|role| self.roles.get(role))
+            .copied()
+            .reduce(|a, b| ExpiryPolicy {
+                idle_timeout_secs: min(a.idle_timeout_secs, b.idle_timeout_secs),
+                absolute_lifetime_secs: min(a.absolute_lifetime_secs, b.absolute_lifetime_secs),
+            })
+            .unwrap_or(ExpiryPolicy {
+                idle_timeout_secs: self.idle_timeout_secs,
+                absolute_lifetime_secs: self.absolute_lifetime_secs,
+            })
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SecretKeyConfig {
+    pub id: u8,
+    pub secret_base64: Option<zeroize::Zeroizing<String>>,
+    pub secret_source: Option<SecretSource>,
+}
+
+impl SecretKeyConfig {
+    // `setting` names the list the key is in, for errors.
+    pub fn decode_secret(
+        setting: &str,
+        id: u8,
+        secret_base64: &str,
+    ) -> Result<zeroize::Zeroizing<Vec<u8>>, String> {
+        match base64::engine::general_purpose::STANDARD.decode(secret_base64.trim().as_bytes()) {
+            Ok(secret) if secret.len() >= 32 => Ok(zeroize::Zeroizing::new(secret)),
+            Ok(mut secret) => {
+                zeroize::Zeroize::zeroize(&mut secret);
+                Err(format!(
+                    "{} secret {} must be at least 32 bytes",
+                    setting, id
+                ))
+            }
+            Err(err) => Err(format!(
+                "{} secret {} is not valid base64: {}",
+                setting, id, err
+            )),
+        }
+    }
+}
+
+// Encrypts the personal data in users.path and the sessions.persistence
+// files with AES-256-GCM. New data is encrypted with active_key, which
+// defaults to the last key; the others only decrypt data written before,
+// which is encrypted with the active key again once its file is rewritten.
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncryptionConfig {
+    pub keys: Vec<SecretKeyConfig>,
+    #[serde(default)]
+    pub active_key: Option<u8>,
+}
+
+impl EncryptionConfig {
+    pub fn active_key(&self) -> u8 {
+        self.active_key
+            .or_else(|| self.keys.last().map(|key| key.id))
+            .unwrap_or(0)
+    }
+}
+
+// Uploads an archive of the users and sessions, as tk-auth export writes
+// them, to an S3-compatible bucket every interval_secs, and keeps the last
+// `keep` of them. Credentials come from the AWS_* environment variables.
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackupConfig {
+    pub bucket: String,
+    #[serde(default = "default_backup_prefix")]
+    pub prefix: String,
+    pub aws: AwsConfig,
+    #[serde(default = "default_backup_interval_secs")]
+    pub interval_secs: u64,
+    #[serde(default = "default_backup_keep")]
+    pub keep: usize,
+    // Encrypts the archives, like tk-auth export --key-file.
+    #[serde(default)]
+    pub key_file: Option<PathBuf>,
+}
+
+fn default_backup_prefix() -> String {
+    String::from("tk-auth/")
+}
+
+fn default_backup_interval_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_backup_keep() -> usize {
+    7
+}
+
+// Probes users.path and sessions.persistence every probe_interval_secs by
+// writing a file next to them, and sessions.memcached by asking each server
+// for its version. While one fails, /readyz reports the
+// instance as degraded, and with unready_when_degraded answers 503 so load
+// balancers send requests elsewhere.
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    pub probe_interval_secs: u64,
+    pub unready_when_degraded: bool,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            probe_interval_secs: 10,
+            unready_when_degraded: false,
+        }
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum SecretSource {
+    File { path: PathBuf },
+    Env { name: String },
+    Vault { path: String, field: String },
+    AwsSecretsManager { secret_id: String },
+    AwsKms { ciphertext_base64: String },
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecretsConfig {
+    pub refresh_secs: u64,
+    pub ca_path: PathBuf,
+    pub vault: Option<VaultConfig>,
+    pub aws: Option<AwsConfig>,
+}
+
+impl Default for SecretsConfig {
+    fn default() -> Self {
+        Self {
+            refresh_secs: 300,
+            ca_path: PathBuf::from("/etc/ssl/certs/ca-certificates.crt"),
+            vault: None,
+            aws: None,
+        }
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VaultConfig {
+    pub address: String,
+    #[serde(default = "default_vault_token_env")]
+    pub token_env: String,
+    pub token_path: Option<PathBuf>,
+}
+
+fn default_vault_token_env() -> String {
+    String::from("VAULT_TOKEN")
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AwsConfig {
+    pub region: String,
+    pub endpoint: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BindingMode {
+    Off,
+    Warn,
+    Deny,
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionBindingConfig {
+    pub mode: BindingMode,
+    pub ip: bool,
+    pub user_agent: bool,
+    pub ipv4_prefix_len: u8,
+    pub ipv6_prefix_len: u8,
+}
+
+impl Default for SessionBindingConfig {
+    fn default() -> Self {
+        Self {
+            mode: BindingMode::Off,
+            ip: true,
+            user_agent: true,
+            ipv4_prefix_len: 24,
+            ipv6_prefix_len: 64,
+        }
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityHeadersConfig {
+    pub strict_transport_security: Option<String>,
+    pub content_type_options: Option<String>,
+    pub frame_options: Option<String>,
+    pub content_security_policy: Option<String>,
+    pub referrer_policy: Option<String>,
+    pub api_cache_control: Option<String>,
+    pub extra: BTreeMap<String, String>,
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        Self {
+            strict_transport_security: Some(String::from("max-age=63072000; includeSubDomains")),
+            content_type_options: Some(String::from("nosniff")),
+            frame_options: Some(String::from("DENY")),
+            content_security_policy: Some(String::from("frame-ancestors 'none'")),
+            referrer_policy: Some(String::from("no-referrer")),
+            api_cache_control: Some(String::from("no-store")),
+            extra: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub exposed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age_secs: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "POST", "PUT", "PATCH", "DELETE"]
+                .map(String::from)
+                .to_vec(),
+            // What the API reads from browser clients besides the body:
+            // retries of writes, and conditional reads of session state.
+            allowed_headers: ["content-type", "idempotency-key", "if-none-match"]
+                .map(String::from)
+                .to_vec(),
+            // Browser clients can only pace themselves if they can read
+            // when to retry.
+            exposed_headers: [
+                "etag",
+                "retry-after",
+                "ratelimit-limit",
+                "ratelimit-remaining",
+                "ratelimit-reset",
+                "x-request-id",
+                "deprecation",
+                "sunset",
+            ]
+            .map(String::from)
+            .to_vec(),
+            allow_credentials: false,
+            max_age_secs: 600,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        let config: Self = serde_json::from_str(&contents).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), err),
+            )
+        })?;
+        config.validate().map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), err),
+            )
+        })?;
+        Ok(config)
+    }
+
+    pub fn has_notification_channel(&self, channel: NotificationChannel) -> bool {
+        match channel {
+            NotificationChannel::Email => self.email.is_some(),
+            NotificationChannel::Webhook => self.notifications.webhook_url.is_some(),
+            NotificationChannel::Sms => self.notifications.sms.is_some(),
+            NotificationChannel::Inbox => self.inbox.is_some(),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.limits.max_header_bytes < 8192 {
+            return Err(String::from(
+                "limits.max_header_bytes must be at least 8192",
+            ));
+        }
+        if self.limits.request_timeout_secs == 0
+            || self.limits.header_read_timeout_secs == 0
+            || self
+                .limits
+                .routes
+                .values()
+                .any(|route| route.timeout_secs == Some(0))
+        {
+            return Err(String::from("limits timeouts must be greater than zero"));
+        }
+        if self.limits.max_connections == Some(0) || self.limits.max_in_flight_requests == Some(0) {
+            return Err(String::from(
+                "limits.max_connections and limits.max_in_flight_requests must not be 0",
+            ));
+        }
+        let id_bytes_range = session::MIN_SESSION_ID_BYTES..=session::MAX_SESSION_ID_BYTES;
+        if std::iter::once(&self.sessions.id_bytes)
+            .chain(&self.sessions.legacy_id_bytes)
+            .any(|id_bytes| !id_bytes_range.contains(id_bytes))
+        {
+            return Err(format!(
+                "sessions.id_bytes and sessions.legacy_id_bytes must be between {} and {}",
+                session::MIN_SESSION_ID_BYTES,
+                session::MAX_SESSION_ID_BYTES
+            ));
+        }
+        let expiry = &self.sessions.expiry;
+        if std::iter::once((expiry.idle_timeout_secs, expiry.absolute_lifetime_secs))
+            .chain(
+                expiry
+                    .roles
+                    .values()
+                    .map(|policy| (policy.idle_timeout_secs, policy.absolute_lifetime_secs)),
+            )
+            .any(|(idle, absolute)| idle == Some(0) || absolute == Some(0))
+            || expiry.warn_before_secs == Some(0)
+        {
+            return Err(String::from(
+                "sessions.expiry timeouts must be greater than zero",
+            ));
+        }
+        if self.sessions.max_per_user == Some(0) {
+            return Err(String::from("sessions.max_per_user must be at least 1"));
+        }
+        if self.sessions.memory_budget_bytes == Some(0) {
+            return Err(String::from("sessions.memory_budget_bytes must not be 0"));
+        }
+        if let Some(persistence) = &self.sessions.persistence {
+            if persistence.path(&self.users).is_none() {
+                return Err(String::from(
+                    "sessions.persistence needs sessions.persistence.path or users.path to be \
+                     configured",
+                ));
+            }
+            if persistence.snapshot_interval_secs == 0 {
+                return Err(String::from(
+                    "sessions.persistence.snapshot_interval_secs must not be 0",
+                ));
+            }
+        }
+        if let Some(memcached) = &self.sessions.memcached {
+            if self.sessions.persistence.is_some() {
+                return Err(String::from(
+                    "only one of sessions.persistence and sessions.memcached can be configured",
+                ));
+            }
+            if memcached.servers.is_empty() {
+                return Err(String::from("sessions.memcached.servers must not be empty"));
+            }
+            for server in &memcached.servers {
+                match server.rsplit_once(':') {
+                    Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {}
+                    _ => {
+                        return Err(format!(
+                            "sessions.memcached.servers: {:?} is not host:port",
+                            server
+                        ))
+                    }
+                }
+            }
+            // Keys are limited to 250 bytes, without spaces or control
+            // characters.
+            if memcached.key_prefix.len() > 100
+                || !memcached
+                    .key_prefix
+                    .bytes()
+                    .all(|byte| byte.is_ascii_graphic())
+            {
+                return Err(String::from(
+                    "sessions.memcached.key_prefix must be at most 100 printable ASCII \
+                     characters without spaces",
+                ));
+            }
+            if memcached.timeout_ms == 0 {
+                return Err(String::from("sessions.memcached.timeout_ms must not be 0"));
+            }
+        }
+        if let Some(api_docs) = &self.api_docs {
+            if let Some(file) = openapi::SWAGGER_UI_FILES
+                .iter()
+                .find(|(file, _)| !api_docs.swagger_ui_dir.join(file).is_file())
+            {
+                return Err(format!(
+                    "api_docs.swagger_ui_dir: {} has no {}",
+                    api_docs.swagger_ui_dir.display(),
+                    file.0
+                ));
+            }
+        }
+        if self.remember_me.is_some()
+            && (self.sessions.persistence.is_some() || self.sessions.memcached.is_some())
+        {
+            return Err(String::from(
+                "remember_me can't be used with sessions.persistence or sessions.memcached, \
+                 its tokens are only kept in memory",
+            ));
+        }
+        if let Some(idempotency) = &self.idempotency {
+            if idempotency.window_secs == 0 || idempotency.max_keys == 0 {
+                return Err(String::from(
+                    "idempotency.window_secs and idempotency.max_keys must not be 0",
+                ));
+            }
+        }
+        if let Some(quota) = &self.session_quota {
+            quota.validate("session_quota")?;
+        }
+        for (i, deprecation) in self.deprecations.iter().enumerate() {
+            deprecation.validate()?;
+            if self.deprecations[..i]
+                .iter()
+                .any(|other| other.route == deprecation.route)
+            {
+                return Err(format!(
+                    "deprecations lists {} more than once",
+                    deprecation.route
+                ));
+            }
+        }
+        if self.session_binding.ipv4_prefix_len > 32 || self.session_binding.ipv6_prefix_len > 128 {
+            return Err(String::from(
+                "session_binding prefix lengths must be at most 32 (IPv4) and 128 (IPv6)",
+            ));
+        }
+        if let AuthBackendConfig::Pam(pam) = &self.auth_backend {
+            if !cfg!(feature = "pam") {
+                return Err(String::from(
+                    "auth_backend pam needs tk-auth to be built with the pam feature",
+                ));
+            }
+            if pam.threads == 0 {
+                return Err(String::from("auth_backend.pam.threads must be at least 1"));
+            }
+        }
+        if self.kerberos.is_some() && !cfg!(feature = "kerberos") {
+            return Err(String::from(
+                "kerberos needs tk-auth to be built with the kerberos feature",
+            ));
+        }
+        if self
+            .scim
+            .as_ref()
+            .is_some_and(|scim| scim.bearer_token.len() < 16)
+            || self
+                .admin
+                .as_ref()
+                .is_some_and(|admin| admin.bearer_token.len() < 16)
+        {
+            return Err(String::from(
+                "scim.bearer_token and admin.bearer_token must be at least 16 characters",
+            ));
+        }
+        if let Some(signing) = &self.request_signing {
+            if signing.keys.is_empty() || signing.max_skew_secs == 0 {
+                return Err(String::from(
+                    "request_signing needs keys, and max_skew_secs must not be 0",
+                ));
+            }
+            for (i, key) in signing.keys.iter().enumerate() {
+                if key.id.is_empty()
+                    || !key
+                        .id
+                        .bytes()
+                        .all(|byte| byte.is_ascii_graphic() && byte != b',')
+                    || signing.keys[..i].iter().any(|other| other.id == key.id)
+                {
+                    return Err(format!(
+                        "request_signing key id {:?} must be unique, non-empty and without \
+                         spaces or commas",
+                        key.id
+                    ));
+                }
+                let secret = base64::engine::general_purpose::STANDARD
+                    .decode(key.secret_base64.trim())
+                    .map(zeroize::Zeroizing::new);
+                match secret {
+                    Ok(secret) if secret.len() >= 32 => {}
+                    _ => {
+                        return Err(format!(
+                            "request_signing key {} needs a secret of at least 32 bytes in base64",
+                            key.id
+                        ))
+                    }
+                }
+                let configured = match key.scope {
+                    SigningScope::Admin => self.admin.is_some(),
+                    SigningScope::Scim => self.scim.is_some(),
+                };
+                if !configured {
+                    return Err(format!(
+                        "request_signing key {} is for an API that is not enabled",
+                        key.id
+                    ));
+                }
+                if let Some(quota) = &key.quota {
+                    quota.validate(&format!("request_signing key {} quota", key.id))?;
+                }
+            }
+        }
+        if (self.scim.is_some()
+            || self.admin.is_some()
+            || self.idp.is_some()
+            || matches!(self.auth_backend, AuthBackendConfig::Local))
+            && self.users.path.is_none()
+        {
+            return Err(String::from(
+                "scim, admin, idp and the local auth_backend need users.path to be configured",
+            ));
+        }
+        if let Some(proof_of_work) = &self.proof_of_work {
+            if !(1..=32).contains(&proof_of_work.difficulty_bits) {
+                return Err(String::from(
+                    "proof_of_work.difficulty_bits must be between 1 and 32",
+                ));
+            }
+            if proof_of_work.challenge_ttl_secs == 0 {
+                return Err(String::from(
+                    "proof_of_work.challenge_ttl_secs must not be 0",
+                ));
+            }
+        }
+        if let Some(geoip) = &self.geoip {
+            if geoip.country_db_path.is_none() && geoip.asn_db_path.is_none() {
+                return Err(String::from("geoip needs country_db_path or asn_db_path"));
+            }
+        }
+        let notifications = &self.notifications;
+        if !notifications
+            .webhook_url
+            .iter()
+            .chain(notifications.sms.as_ref().map(|sms| &sms.url))
+            .all(|url| url.starts_with("https://") || url.starts_with("http://"))
+        {
+            return Err(String::from(
+                "notifications.webhook_url and notifications.sms.url must be http(s) URLs",
+            ));
+        }
+        if let Some(sms) = &notifications.sms {
+            if sms.from.is_empty() || sms.username.is_some() != sms.password.is_some() {
+                return Err(String::from(
+                    "notifications.sms.from must be set, and username and password together",
+                ));
+            }
+        }
+        if let Some(alerts) = &self.new_device_alerts {
+            if !alerts
+                .channels
+                .iter()
+                .any(|channel| self.has_notification_channel(*channel))
+            {
+                return Err(String::from(
+                    "new_device_alerts.channels has no channel that is configured",
+                ));
+            }
+            if !alerts.public_url.starts_with("https://")
+                && !alerts.public_url.starts_with("http://")
+            {
+                return Err(String::from(
+                    "new_device_alerts.public_url must be an http(s) URL",
+                ));
+            }
+            if alerts.link_ttl_secs == 0 {
+                return Err(String::from(
+                    "new_device_alerts.link_ttl_secs must not be 0",
+                ));
+            }
+        }
+        if let Some(queue) = &self.delivery_queue {
+            if queue.path(&self.users).is_none() {
+                return Err(String::from(
+                    "delivery_queue needs delivery_queue.path or users.path to be configured",
+                ));
+            }
+            if queue.max_attempts == 0
+                || queue.initial_backoff_secs == 0
+                || queue.max_backoff_secs < queue.initial_backoff_secs
+                || queue.max_deliveries == 0
+            {
+                return Err(String::from(
+                    "delivery_queue.max_attempts, initial_backoff_secs and max_deliveries must \
+                     not be 0, and max_backoff_secs not less than initial_backoff_secs",
+                ));
+            }
+        }
+        if let Some(inbox) = &self.inbox {
+            if inbox.path(&self.users).is_none() {
+                return Err(String::from(
+                    "inbox needs inbox.path or users.path to be configured",
+                ));
+            }
+            if inbox.max_per_user == 0 || inbox.keep_secs == 0 {
+                return Err(String::from(
+                    "inbox.max_per_user and inbox.keep_secs must not be 0",
+                ));
+            }
+        }
+        if let Some(alerts) = &self.admin_alerts {
+            if alerts.webhooks.is_empty()
+                || !alerts.webhooks.iter().all(|webhook| {
+                    webhook.url.starts_with("https://") || webhook.url.starts_with("http://")
+                })
+            {
+                return Err(String::from(
+                    "admin_alerts.webhooks needs at least one webhook, with http(s) URLs",
+                ));
+            }
+            if alerts
+                .failed_sign_ins
+                .as_ref()
+                .is_some_and(|failed| failed.threshold == 0 || failed.window_secs == 0)
+            {
+                return Err(String::from(
+                    "admin_alerts.failed_sign_ins threshold and window_secs must not be 0",
+                ));
+            }
+            if alerts
+                .privileged_sign_ins
+                .as_ref()
+                .is_some_and(|privileged| privileged.roles.is_empty())
+            {
+                return Err(String::from(
+                    "admin_alerts.privileged_sign_ins.roles must not be empty",
+                ));
+            }
+        }
+        if self.security_digest.is_some() && self.email.is_none() {
+            return Err(String::from("security_digest needs email to be configured"));
+        }
+        if let Some(email) = &self.email {
+            let header_safe = |value: &str| !value.contains(['\r', '\n']);
+            if email.host.is_empty()
+                || !email.from.contains('@')
+                || !header_safe(&email.from)
+                || !header_safe(&email.hello_name)
+            {
+                return Err(String::from(
+                    "email.host must be set and email.from must be an email address",
+                ));
+            }
+            if email.username.is_some() != email.password.is_some() {
+                return Err(String::from(
+                    "email.username and email.password must be set together",
+                ));
+            }
+            if email.username.is_some() && email.tls == EmailTls::None {
+                return Err(String::from(
+                    "email.username needs tls to be implicit or starttls",
+                ));
+            }
+            if email.pool_size == 0 || email.timeout_secs == 0 {
+                return Err(String::from(
+                    "email.pool_size and email.timeout_secs must not be 0",
+                ));
+            }
+        }
+        if let Some(risk) = &self.risk {
+            if !risk.url.starts_with("https://") && !risk.url.starts_with("http://") {
+                return Err(String::from("risk.url must be an http(s) URL"));
+            }
+            if risk.timeout_ms == 0 || risk.velocity_window_secs == 0 {
+                return Err(String::from(
+                    "risk.timeout_ms and risk.velocity_window_secs must not be 0",
+                ));
+            }
+            if risk.on_error == RiskDecision::StepUp && self.captcha.is_none() {
+                return Err(String::from(
+                    "risk.on_error can only be step_up with captcha configured",
+                ));
+            }
+        }
+        if let Some(tarpit) = &self.tarpit {
+            if tarpit.after_failures == 0
+                || tarpit.base_delay_ms == 0
+                || tarpit.failure_window_secs == 0
+                || tarpit.max_concurrent == 0
+            {
+                return Err(String::from(
+                    "tarpit.after_failures, base_delay_ms, failure_window_secs and \
+                     max_concurrent must not be 0",
+                ));
+            }
+            // Delays mustn't run into the request timeout.
+            let timeout_ms = ["/api/authenticate", "/login"]
+                .iter()
+                .map(|path| self.limits.route_timeout_secs(path) * 1000)
+                .min()
+                .unwrap();
+            if tarpit.max_delay_ms < tarpit.base_delay_ms || tarpit.max_delay_ms >= timeout_ms {
+                return Err(String::from(
+                    "tarpit.max_delay_ms must be at least base_delay_ms and below the request \
+                     timeout of /api/authenticate and /login",
+                ));
+            }
+        }
+        if let Some(captcha) = &self.captcha {
+            if captcha.site_key.is_empty() || captcha.secret.is_empty() {
+                return Err(String::from(
+                    "captcha.site_key and captcha.secret must be set",
+                ));
+            }
+            if captcha
+                .verify_url
+                .as_deref()
+                .is_some_and(|url| !url.starts_with("https://") && !url.starts_with("http://"))
+            {
+                return Err(String::from("captcha.verify_url must be an http(s) URL"));
+            }
+            if captcha.failure_window_secs == 0 {
+                return Err(String::from("captcha.failure_window_secs must not be 0"));
+            }
+        }
+        if let Some(idp) = &self.idp {
+            if !idp.public_url.starts_with("https://") && !idp.public_url.starts_with("http://") {
+                return Err(String::from("idp.public_url must be an http(s) URL"));
+            }
+            if idp
+                .after_login_url
+                .as_deref()
+                .is_some_and(|url| http::HeaderValue::from_str(url).is_err())
+            {
+                return Err(String::from("idp.after_login_url must be a valid URL"));
+            }
+            for (i, provider) in idp.providers.iter().enumerate() {
+                if provider.id.is_empty()
+                    || !provider.id.bytes().all(|byte| {
+                        byte.is_ascii_lowercase() || byte.is_ascii_digit() || b"-_".contains(&byte)
+                    })
+                {
+                    return Err(format!(
+                        "idp provider id {:?} may only contain a-z, 0-9, - and _",
+                        provider.id
+                    ));
+                }
+                if idp.providers[..i]
+                    .iter()
+                    .any(|other| other.id == provider.id)
+                {
+                    return Err(format!("duplicate idp provider id {}", provider.id));
+                }
+                if !provider.scopes.iter().any(|scope| scope == "openid") {
+                    return Err(format!(
+                        "idp provider {} scopes must include openid",
+                        provider.id
+                    ));
+                }
+            }
+        }
+        if let Some(forward_auth) = &self.forward_auth {
+            let is_token = |value: &str| {
+                !value.is_empty()
+                    && value.bytes().all(|byte| {
+                        byte.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&byte)
+                    })
+            };
+            if !is_token(&forward_auth.cookie_name)
+                || forward_auth
+                    .cookie_domain
+                    .as_deref()
+                    .is_some_and(|domain| !is_token(domain))
+            {
+                return Err(String::from(
+                    "forward_auth.cookie_name and cookie_domain must be plain tokens",
+                ));
+            }
+        }
+        security_headers::SecurityHeaders::from_config(&self.security_headers)?;
+        let _ = cors::layer(&self.cors)?;
+        let signed_id_bytes = 1 + self.sessions.id_bytes + session::SIGNATURE_BYTES;
+        if self.sessions.legacy_id_bytes.contains(&signed_id_bytes) {
+            return Err(format!(
+                "sessions.legacy_id_bytes can't contain {}, the length of signed ids",
+                signed_id_bytes
+            ));
+        }
+        self.validate_secret_keys("sessions.signing_keys", &self.sessions.signing_keys)?;
+        if !self.sessions.signing_keys.is_empty()
+            && !self
+                .sessions
+                .signing_keys
+                .iter()
+                .any(|key| key.id == self.sessions.active_signing_key())
+        {
+            return Err(String::from(
+                "sessions.active_signing_key must name one of sessions.signing_keys",
+            ));
+        }
+        if let Some(encryption) = &self.encryption {
+            if encryption.keys.is_empty() {
+                return Err(String::from("encryption.keys must not be empty"));
+            }
+            self.validate_secret_keys("encryption.keys", &encryption.keys)?;
+            if !encryption
+                .keys
+                .iter()
+                .any(|key| key.id == encryption.active_key())
+            {
+                return Err(String::from(
+                    "encryption.active_key must name one of encryption.keys",
+                ));
+            }
+        }
+        if self.health.probe_interval_secs == 0 {
+            return Err(String::from("health.probe_interval_secs must not be 0"));
+        }
+        if let Some(backup) = &self.backup {
+            if backup.interval_secs == 0 || backup.keep == 0 {
+                return Err(String::from(
+                    "backup.interval_secs and backup.keep must not be 0",
+                ));
+            }
+            // Both are put in URLs as they are.
+            if backup.bucket.is_empty()
+                || !backup.bucket.bytes().all(|byte| {
+                    byte.is_ascii_lowercase() || byte.is_ascii_digit() || b".-".contains(&byte)
+                })
+                || !backup
+                    .prefix
+                    .bytes()
+                    .all(|byte| byte.is_ascii_alphanumeric() || b"/_.-".contains(&byte))
+            {
+                return Err(String::from(
+                    "backup.bucket must be a valid bucket name, and backup.prefix may only \
+                     contain letters, digits and / _ . -",
+                ));
+            }
+        }
+        let branding = &self.branding;
+        if branding.product_name.trim().is_empty() {
+            return Err(String::from("branding.product_name must not be empty"));
+        }
+        if !is_hex_color(&branding.accent_color) || !is_hex_color(&branding.background_color) {
+            return Err(String::from(
+                "branding colors must be hex colors such as #2563eb",
+            ));
+        }
+        if branding
+            .logo_url
+            .iter()
+            .chain(branding.footer_links.iter().map(|link| &link.url))
+            .any(|url| !is_link(url))
+        {
+            return Err(String::from(
+                "branding URLs must be http(s) URLs or paths starting with /",
+            ));
+        }
+        // The pages sign people in by setting the forward_auth cookie.
+        if self.pages.is_some() && self.forward_auth.is_none() {
+            return Err(String::from("pages needs forward_auth to be configured"));
+        }
+        // They also end up in the pages' Content-Security-Policy.
+        if let Some(host) = self
+            .pages
+            .iter()
+            .flat_map(|pages| &pages.redirect_hosts)
+            .find(|host| {
+                host.trim_start_matches('.').is_empty()
+                    || !host
+                        .bytes()
+                        .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'.')
+            })
+        {
+            return Err(format!("pages.redirect_hosts: invalid host {}", host));
+        }
+        if let Some(cluster) = &self.cluster {
+            redis::Url::parse(&cluster.redis_url)
+                .map_err(|err| format!("cluster.redis_url: {}", err))?;
+            if cluster.channel.is_empty() {
+                return Err(String::from("cluster.channel must not be empty"));
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_secret_keys(&self, setting: &str, keys: &[SecretKeyConfig]) -> Result<(), String> {
+        for (i, key) in keys.iter().enumerate() {
+            if keys[..i].iter().any(|other| other.id == key.id) {
+                return Err(format!("duplicate {} id {}", setting, key.id));
+            }
+            match (&key.secret_base64, &key.secret_source) {
+                (Some(secret_base64), None) => {
+                    SecretKeyConfig::decode_secret(setting, key.id, secret_base64)?;
+                }
+                (None, Some(source)) => self.validate_secret_source(source)?,
+                _ => {
+                    return Err(format!(
+                        "{} {} needs one of secret_base64 or secret_source",
+                        setting, key.id
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_secret_source(&self, source: &SecretSource) -> Result<(), String> {
+        match source {
+            SecretSource::Vault { .. } if self.secrets.vault.is_none() => Err(String::from(
+                "vault secret sources need secrets.vault to be configured",
+            )),
+            SecretSource::AwsSecretsManager { .. } | SecretSource::AwsKms { .. }
+                if self.secrets.aws.is_none() =>
+            {
+                Err(String::from(
+                    "AWS secret sources need secrets.aws to be configured",
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
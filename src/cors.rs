@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+use crate::config;
+
+pub fn layer(config: &config::CorsConfig) -> Result<tower_http::cors::CorsLayer, String> {
+    let wildcard = config.allowed_origins.iter().any(|origin| origin == "*");
+    if wildcard && config.allow_credentials {
+        return Err(String::from(
+            "cors.allowed_origins can't contain \"*\" when cors.allow_credentials is set",
+        ));
+    }
+    let origins = if wildcard {
+        tower_http::cors::AllowOrigin::any()
+    } else {
+        let origins = config
+            .allowed_origins
+            .iter()
+            .map(|origin| {
+                http::HeaderValue::from_str(origin.trim_end_matches('/'))
+                    .map_err(|_| format!("cors.allowed_origins: invalid origin {}", origin))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        tower_http::cors::AllowOrigin::list(origins)
+    };
+    let methods = config
+        .allowed_methods
+        .iter()
+        .map(|method| {
+            http::Method::from_bytes(method.as_bytes())
+                .map_err(|_| format!("cors.allowed_methods: invalid method {}", method))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let allowed_headers = header_names("cors.allowed_headers", &config.allowed_headers)?;
+    let exposed_headers = header_names("cors.exposed_headers", &config.exposed_headers)?;
+
+    Ok(tower_http::cors::CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods(methods)
+        .allow_headers(allowed_headers)
+        .expose_headers(exposed_headers)
+        .allow_credentials(config.allow_credentials)
+        .max_age(Duration::from_secs(config.max_age_secs)))
+}
+
+fn header_names(setting: &str, names: &[String]) -> Result<Vec<http::HeaderName>, String> {
+    names
+        .iter()
+        .map(|name| {
+            http::HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("{}: invalid header name {}", setting, name))
+        })
+        .collect()
+}
@@ -0,0 +1,353 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::admin;
+use crate::config::{self, NotificationChannel};
+use crate::jwt::base64url_encode;
+use crate::notify::{channel_name, Notification, Recipient};
+use crate::pagination;
+use crate::users;
+use crate::{error_response, json_response, AppState};
+
+const DELIVERY_ID_BYTES: usize = 16;
+// Besides being woken up when something is queued, the worker looks for
+// retries that are due this often.
+const POLL_INTERVAL_SECS: u64 = 5;
+
+#[derive(Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    DeadLettered,
+}
+
+// One notification over one channel.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct Delivery {
+    id: String,
+    channel: NotificationChannel,
+    recipient: Recipient,
+    notification: Notification,
+    status: DeliveryStatus,
+    attempts: u32,
+    created_at: u64,
+    // When the next attempt is due while pending, else when it was
+    // delivered or dead-lettered.
+    updated_at: u64,
+    last_error: Option<String>,
+}
+
+impl Delivery {
+    // Leaves out the recipient and the details, which may hold links that
+    // act on the user's account.
+    fn summary(&self) -> serde_json::Value {
+        serde_json::json!({
+            "id": self.id,
+            "channel": self.channel,
+            "event": self.notification.event.name(),
+            "user": self.notification.user,
+            "status": self.status,
+            "attempts": self.attempts,
+            "created_at": self.created_at,
+            "updated_at": self.updated_at,
+            "last_error": self.last_error,
+        })
+    }
+}
+
+// Notifications waiting to be delivered, written to the file after every
+// change so they survive restarts.
+#[derive(Default)]
+pub struct DeliveryQueue {
+    config: Option<config::DeliveryQueueConfig>,
+    path: Option<PathBuf>,
+    deliveries: tokio::sync::Mutex<Vec<Delivery>>,
+    wake: tokio::sync::Notify,
+}
+
+impl DeliveryQueue {
+    pub fn new(config: &config::Config) -> io::Result<Self> {
+        let Some(queue) = &config.delivery_queue else {
+            return Ok(Self::default());
+        };
+        // Validated on load.
+        let path = queue.path(&config.users).unwrap();
+        let deliveries = match std::fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: {}", path.display(), err),
+                )
+            })?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => {
+                return Err(io::Error::new(
+                    err.kind(),
+                    format!("{}: {}", path.display(), err),
+                ))
+            }
+        };
+        Ok(Self {
+            config: Some(queue.clone()),
+            path: Some(path),
+            deliveries: tokio::sync::Mutex::new(deliveries),
+            wake: tokio::sync::Notify::new(),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.is_some()
+    }
+
+    fn persist(&self, deliveries: &[Delivery]) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let contents = serde_json::to_vec_pretty(deliveries).unwrap();
+        if let Err(err) = users::write_private(path, &contents) {
+            println!(
+                "Failed to save the delivery queue: {}: {}",
+                path.display(),
+                err
+            );
+        }
+    }
+
+    pub async fn enqueue(
+        &self,
+        rng: &dyn crate::rng::Rng,
+        channel: NotificationChannel,
+        recipient: Recipient,
+        notification: Notification,
+        now: u64,
+    ) {
+        let Some(config) = &self.config else {
+            return;
+        };
+        let mut deliveries = self.deliveries.lock().await;
+        deliveries.retain(|delivery| {
+            delivery.status != DeliveryStatus::Delivered
+                || delivery.updated_at + config.keep_delivered_secs > now
+        });
+        if deliveries.len() >= config.max_deliveries {
+            println!(
+                "Delivery queue is full, dropped {} notification for {}",
+                notification.event.name(),
+                notification.user
+            );
+            return;
+        }
+        let mut id = [0u8; DELIVERY_ID_BYTES];
+        rng.fill(&mut id);
+        deliveries.push(Delivery {
+            id: base64url_encode(&id),
+            channel,
+            recipient,
+            notification,
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            created_at: now,
+            updated_at: now,
+            last_error: None,
+        });
+        self.persist(&deliveries);
+        self.wake.notify_one();
+    }
+
+    async fn due(&self, now: u64) -> Vec<Delivery> {
+        self.deliveries
+            .lock()
+            .await
+            .iter()
+            .filter(|delivery| {
+                delivery.status == DeliveryStatus::Pending && delivery.updated_at <= now
+            })
+            .cloned()
+            .collect()
+    }
+
+    // Records an attempt. Notifications the recipient has no address for
+    // count as delivered, since retrying wouldn't change that.
+    async fn attempted(&self, id: &str, result: io::Result<bool>, now: u64) {
+        let Some(config) = &self.config else {
+            return;
+        };
+        let mut deliveries = self.deliveries.lock().await;
+        let Some(delivery) = deliveries.iter_mut().find(|delivery| delivery.id == id) else {
+            return;
+        };
+        delivery.attempts += 1;
+        match result {
+            Ok(_) => {
+                delivery.status = DeliveryStatus::Delivered;
+                delivery.updated_at = now;
+                delivery.last_error = None;
+            }
+            Err(err) => {
+                delivery.last_error = Some(err.to_string());
+                if delivery.attempts >= config.max_attempts {
+                    delivery.status = DeliveryStatus::DeadLettered;
+                    delivery.updated_at = now;
+                } else {
+                    delivery.updated_at = now + backoff_secs(config, delivery.attempts);
+                }
+            }
+        }
+        self.persist(&deliveries);
+    }
+
+    // Queues an undelivered delivery again with a fresh set of attempts.
+    async fn retry(&self, id: &str, now: u64) -> Option<Delivery> {
+        let mut deliveries = self.deliveries.lock().await;
+        let delivery = deliveries
+            .iter_mut()
+            .find(|delivery| delivery.id == id && delivery.status != DeliveryStatus::Delivered)?;
+        delivery.status = DeliveryStatus::Pending;
+        delivery.attempts = 0;
+        delivery.updated_at = now;
+        let retried = delivery.clone();
+        self.persist(&deliveries);
+        self.wake.notify_one();
+        Some(retried)
+    }
+}
+
+// Doubles with each failed attempt, from initial_backoff_secs up to
+// max_backoff_secs.
+fn backoff_secs(config: &config::DeliveryQueueConfig, attempts: u32) -> u64 {
+    config
+        .initial_backoff_secs
+        .saturating_mul(1 << attempts.saturating_sub(1).min(32))
+        .min(config.max_backoff_secs)
+}
+
+pub fn spawn(state: Arc<AppState>) {
+    if !state.deliveries.is_enabled() {
+        return;
+    }
+    tokio::spawn(async move {
+        loop {
+            for delivery in state.deliveries.due(state.clock.now_secs()).await {
+                let event = delivery.notification.event.name();
+                let user = &delivery.notification.user;
+                let channel = channel_name(delivery.channel);
+                let result = match state.notifiers.for_channel(delivery.channel) {
+                    Some(notifier) => {
+                        notifier
+                            .notify(&delivery.recipient, &delivery.notification)
+                            .await
+                    }
+                    None => Err(io::Error::other("the channel isn't configured anymore")),
+                };
+                match &result {
+                    Ok(true) => println!("Sent {} notification for {} by {}", event, user, channel),
+                    Ok(false) => {}
+                    Err(err) => println!(
+                        "Sending {} notification for {} by {} failed: {}",
+                        event, user, channel, err
+                    ),
+                }
+                state
+                    .deliveries
+                    .attempted(&delivery.id, result, state.clock.now_secs())
+                    .await;
+            }
+            tokio::select! {
+                _ = state.deliveries.wake.notified() => {}
+                _ = tokio::time::sleep(Duration::from_secs(POLL_INTERVAL_SECS)) => {}
+            }
+        }
+    });
+}
+
+#[derive(serde::Deserialize)]
+pub struct DeliveriesQuery {
+    status: Option<DeliveryStatus>,
+}
+
+pub async fn get_deliveries(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Query(query): axum::extract::Query<DeliveriesQuery>,
+    axum::extract::Query(page): axum::extract::Query<pagination::PageQuery>,
+    headers: http::HeaderMap,
+) -> axum::response::Response {
+    if state.config.admin.is_none() {
+        return error_response(404, "the admin API is not enabled");
+    }
+    if !admin::authorized(&state, &headers) {
+        return error_response(401, "invalid admin token");
+    }
+    if !state.deliveries.is_enabled() {
+        return error_response(404, "the delivery queue is not enabled");
+    }
+    let deliveries = state.deliveries.deliveries.lock().await;
+    let count = |status| {
+        deliveries
+            .iter()
+            .filter(|delivery| delivery.status == status)
+            .count()
+    };
+    let listed = deliveries
+        .iter()
+        .filter(|delivery| query.status.is_none_or(|status| delivery.status == status))
+        .collect();
+    // Oldest first by default.
+    let key = |delivery: &&Delivery| (delivery.created_at, delivery.id.clone());
+    let (listed, next_cursor) = match pagination::page(listed, key, &page, pagination::Order::Asc) {
+        Ok(page) => page,
+        Err(err) => return error_response(400, &err),
+    };
+    let listed: Vec<_> = listed.into_iter().map(Delivery::summary).collect();
+    json_response(
+        200,
+        serde_json::json!({
+            "pending": count(DeliveryStatus::Pending),
+            "delivered": count(DeliveryStatus::Delivered),
+            "dead_lettered": count(DeliveryStatus::DeadLettered),
+            "deliveries": listed,
+            "next_cursor": next_cursor,
+        }),
+    )
+}
+
+pub async fn post_retry_delivery(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+    headers: http::HeaderMap,
+) -> axum::response::Response {
+    if state.config.admin.is_none() {
+        return error_response(404, "the admin API is not enabled");
+    }
+    if !admin::authorized(&state, &headers) {
+        return error_response(401, "invalid admin token");
+    }
+    match state.deliveries.retry(&id, state.clock.now_secs()).await {
+        Some(delivery) => {
+            println!("Retrying delivery {}", id);
+            json_response(200, delivery.summary())
+        }
+        None => error_response(404, "no undelivered delivery with this id"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_maximum() {
+        let config = config::DeliveryQueueConfig {
+            initial_backoff_secs: 30,
+            max_backoff_secs: 200,
+            ..config::DeliveryQueueConfig::default()
+        };
+        let backoffs: Vec<_> = [1, 2, 3, 4, 40]
+            .into_iter()
+            .map(|attempts| backoff_secs(&config, attempts))
+            .collect();
+        assert_eq!(backoffs, [30, 60, 120, 200, 200]);
+    }
+}
@@ -0,0 +1,89 @@
+use std::io;
+
+use crate::config;
+use crate::users::{self, Group, User, UserStore};
+
+// Name, display name, password, active and roles of each demo user. The
+// passwords are printed at startup, so this must never touch a real
+// deployment.
+const DEMO_USERS: [(&str, &str, &str, bool, &[&str]); 3] = [
+    (
+        "alice",
+        "Alice Admin",
+        "alice-demo",
+        true,
+        &["admin", "user"],
+    ),
+    ("bob", "Bob User", "bob-demo", true, &["user"]),
+    ("carol", "Carol Deactivated", "carol-demo", false, &["user"]),
+];
+const DEMO_ROLES: [&str; 2] = ["admin", "user"];
+
+// Demo accounts have well-known passwords, so they're only created in
+// memory and refused anywhere that looks like a real deployment.
+pub fn prepare(config: &mut config::Config) -> io::Result<()> {
+    let refuse = |reason: &str| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("refusing --seed-demo-data: {}", reason),
+        ))
+    };
+    if config.users.path.is_some() {
+        return refuse("users.path is set, the demo users would be saved");
+    }
+    if config.tls.is_some() {
+        return refuse("tls is configured, this looks like a production instance");
+    }
+    match config.auth_backend {
+        config::AuthBackendConfig::None => {
+            println!("Using the local auth backend for the demo users");
+            config.auth_backend = config::AuthBackendConfig::Local;
+        }
+        config::AuthBackendConfig::Local => {}
+        _ => return refuse("the demo users need auth_backend local"),
+    }
+    Ok(())
+}
+
+pub async fn seed(store: &UserStore) -> io::Result<()> {
+    store
+        .update(|data| {
+            let mut roles = Vec::new();
+            for role in DEMO_ROLES {
+                let group = Group::new(users::new_id(), role.to_string());
+                roles.push((role, group.id.clone()));
+                data.groups.insert(group.id.clone(), group);
+            }
+            for (user_name, display_name, password, active, user_roles) in DEMO_USERS {
+                let mut user = User::new(users::new_id(), user_name.to_string());
+                user.display_name = Some(display_name.to_string());
+                user.emails = vec![format!("{}@example.com", user_name)];
+                user.password_hash = Some(users::hash_password(password));
+                user.active = active;
+                for (role, group_id) in &roles {
+                    if user_roles.contains(role) {
+                        data.groups
+                            .get_mut(group_id)
+                            .unwrap()
+                            .members
+                            .push(user.id.clone());
+                    }
+                }
+                data.users.insert(user.id.clone(), user);
+            }
+            Ok(())
+        })
+        .await
+        .map_err(|err| io::Error::other(err.to_string()))?;
+    println!("Seeded demo users, which only exist until the server stops:");
+    for (user_name, _, password, active, user_roles) in DEMO_USERS {
+        println!(
+            "  {} / {} ({}{})",
+            user_name,
+            password,
+            user_roles.join(", "),
+            if active { "" } else { ", deactivated" }
+        );
+    }
+    Ok(())
+}
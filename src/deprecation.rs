@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::admin;
+use crate::client_info::ClientInfo;
+use crate::config;
+use crate::request_signing;
+use crate::time;
+use crate::{error_response, json_response, AppState};
+
+// Past this many clients of a route, new ones only count in its total.
+const MAX_CLIENTS_PER_ROUTE: usize = 1000;
+const MAX_USER_AGENT_LEN: usize = 200;
+
+struct ClientUsage {
+    requests: u64,
+    first_used: u64,
+    last_used: u64,
+    user_agent: Option<String>,
+}
+
+#[derive(Default)]
+struct RouteUsage {
+    requests: u64,
+    last_used: Option<u64>,
+    clients: HashMap<String, ClientUsage>,
+}
+
+// Use of deprecated routes, by route and client, since this instance
+// started. Operators can remove a route once nobody uses it anymore.
+#[derive(Default)]
+pub struct Deprecations {
+    usage: Mutex<HashMap<String, RouteUsage>>,
+}
+
+impl Deprecations {
+    // Counts a request, and returns whether it's the first of the client.
+    fn record(&self, route: &str, client: &str, user_agent: Option<&str>, now: u64) -> bool {
+        let mut usage = self.usage.lock().unwrap();
+        let route = usage.entry(route.to_string()).or_default();
+        route.requests += 1;
+        route.last_used = Some(now);
+        let user_agent = user_agent.map(|user_agent| {
+            user_agent
+                .chars()
+                .take(MAX_USER_AGENT_LEN)
+                .collect::<String>()
+        });
+        if let Some(usage) = route.clients.get_mut(client) {
+            usage.requests += 1;
+            usage.last_used = now;
+            usage.user_agent = user_agent;
+            return false;
+        }
+        if route.clients.len() < MAX_CLIENTS_PER_ROUTE {
+            route.clients.insert(
+                client.to_string(),
+                ClientUsage {
+                    requests: 1,
+                    first_used: now,
+                    last_used: now,
+                    user_agent,
+                },
+            );
+        }
+        true
+    }
+
+    fn report(&self, deprecations: &[config::DeprecationConfig]) -> serde_json::Value {
+        let usage = self.usage.lock().unwrap();
+        let routes: Vec<_> = deprecations
+            .iter()
+            .map(|deprecation| {
+                let route = usage.get(&deprecation.route);
+                let mut clients: Vec<_> = route.iter().flat_map(|route| &route.clients).collect();
+                clients.sort_by_key(|(_, usage)| std::cmp::Reverse(usage.last_used));
+                serde_json::json!({
+                    "route": deprecation.route,
+                    "deprecated": time::rfc3339(deprecation.deprecated_secs()),
+                    "sunset": deprecation.sunset_secs().map(time::rfc3339),
+                    "requests": route.map_or(0, |route| route.requests),
+                    "last_used": route.and_then(|route| route.last_used).map(time::rfc3339),
+                    "clients": clients
+                        .into_iter()
+                        .map(|(client, usage)| {
+                            serde_json::json!({
+                                "client": client,
+                                "requests": usage.requests,
+                                "first_used": time::rfc3339(usage.first_used),
+                                "last_used": time::rfc3339(usage.last_used),
+                                "user_agent": usage.user_agent,
+                            })
+                        })
+                        .collect::<Vec<_>>(),
+                })
+            })
+            .collect();
+        serde_json::json!({ "routes": routes })
+    }
+}
+
+// Signed requests are told apart by their key, others by their address.
+fn client_id(
+    state: &AppState,
+    path: &str,
+    headers: &http::HeaderMap,
+    client: &ClientInfo,
+) -> String {
+    let key = request_signing::is_signed_route(path)
+        .then(|| state.request_signing.verified_key(headers))
+        .flatten();
+    match key {
+        Some(id) => format!("key:{}", id),
+        None => format!("ip:{}", client.ip),
+    }
+}
+
+// RFC 9745's Deprecation, RFC 8594's Sunset and a Link to the details.
+fn apply(deprecation: &config::DeprecationConfig, response: &mut axum::response::Response) {
+    let headers = response.headers_mut();
+    headers.insert(
+        "deprecation",
+        http::HeaderValue::from_str(&format!("@{}", deprecation.deprecated_secs())).unwrap(),
+    );
+    if let Some(sunset) = deprecation.sunset_secs() {
+        headers.insert(
+            "sunset",
+            http::HeaderValue::from_str(&time::http_date(sunset)).unwrap(),
+        );
+    }
+    if let Some(link) = &deprecation.link {
+        if let Ok(value) = http::HeaderValue::from_str(&format!("<{}>; rel=\"deprecation\"", link))
+        {
+            headers.append(http::header::LINK, value);
+        }
+    }
+}
+
+// Marks the responses of deprecated routes and counts who still uses them,
+// logging each client's first request.
+pub async fn layer(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    matched_path: axum::extract::MatchedPath,
+    client: ClientInfo,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let Some(deprecation) = state
+        .config
+        .deprecations
+        .iter()
+        .find(|deprecation| deprecation.route == matched_path.as_str())
+    else {
+        return next.run(request).await;
+    };
+    let client_id = client_id(&state, request.uri().path(), request.headers(), &client);
+    let user_agent = client.user_agent.as_deref();
+    if state.deprecations.record(
+        &deprecation.route,
+        &client_id,
+        user_agent,
+        state.clock.now_secs(),
+    ) {
+        println!(
+            "Deprecated route {} used by {} ({})",
+            deprecation.route,
+            client_id,
+            user_agent.unwrap_or("no user agent")
+        );
+    }
+    let mut response = next.run(request).await;
+    apply(deprecation, &mut response);
+    response
+}
+
+pub async fn get_deprecations(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: http::HeaderMap,
+) -> axum::response::Response {
+    if state.config.admin.is_none() {
+        return error_response(404, "the admin API is not enabled");
+    }
+    if !admin::authorized(&state, &headers) {
+        return error_response(401, "invalid admin token");
+    }
+    json_response(200, state.deprecations.report(&state.config.deprecations))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_use_per_client() {
+        let deprecation = config::DeprecationConfig {
+            route: String::from("/api/negotiate"),
+            deprecated: String::from("2026-01-01"),
+            sunset: Some(String::from("2026-07-01")),
+            link: Some(String::from("https://example.com/negotiate")),
+        };
+        let deprecations = Deprecations::default();
+        assert!(deprecations.record("/api/negotiate", "ip:192.0.2.1", Some("a"), 10));
+        assert!(!deprecations.record("/api/negotiate", "ip:192.0.2.1", Some("b"), 20));
+        assert!(deprecations.record("/api/negotiate", "key:ci", None, 15));
+
+        let report = deprecations.report(std::slice::from_ref(&deprecation));
+        let route = &report["routes"][0];
+        assert_eq!(route["requests"], 3);
+        assert_eq!(route["sunset"], "2026-07-01T00:00:00Z");
+        assert_eq!(route["clients"][0]["client"], "ip:192.0.2.1");
+        assert_eq!(route["clients"][0]["requests"], 2);
+        assert_eq!(route["clients"][0]["user_agent"], "b");
+        assert_eq!(route["clients"][1]["first_used"], time::rfc3339(15));
+
+        let mut response = axum::response::Response::default();
+        apply(&deprecation, &mut response);
+        let headers = response.headers();
+        assert_eq!(headers["deprecation"], "@1767225600");
+        assert_eq!(headers["sunset"], "Wed, 01 Jul 2026 00:00:00 GMT");
+        assert_eq!(
+            headers[http::header::LINK],
+            "<https://example.com/negotiate>; rel=\"deprecation\""
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::error_response;
+
+const MAX_REQUEST_BODY_BYTES: usize = 16 * 1024 * 1024;
+const MAX_RESPONSE_HEAD_BYTES: usize = 64 * 1024;
+const MAX_RESPONSE_HEADERS: usize = 64;
+const RESPONSE_HEAD_TIMEOUT_SECS: u64 = 30;
+const READ_BUFFER_BYTES: usize = 16 * 1024;
+
+// Headers about a single connection, which aren't passed on.
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "host",
+];
+
+// Forwards /web to a frontend dev server, such as `npm run dev` in web/, in
+// place of web/build. Only meant for development: there's no TLS and no
+// connection reuse.
+pub struct DevProxy {
+    host: String,
+    port: u16,
+    authority: String,
+}
+
+impl DevProxy {
+    pub fn new(url: &str) -> Result<Self, String> {
+        let uri: http::Uri = url
+            .parse()
+            .map_err(|_| format!("invalid --dev-proxy URL {}", url))?;
+        let authority = match (uri.scheme_str(), uri.authority()) {
+            (Some("http"), Some(authority)) => authority,
+            _ => {
+                return Err(format!(
+                    "--dev-proxy needs an http://host:port URL, got {}",
+                    url
+                ))
+            }
+        };
+        Ok(Self {
+            host: authority.host().trim_matches(['[', ']']).to_string(),
+            port: authority.port_u16().unwrap_or(80),
+            authority: authority.to_string(),
+        })
+    }
+
+    pub fn authority(&self) -> &str {
+        &self.authority
+    }
+}
+
+fn is_hop_by_hop(name: &str) -> bool {
+    HOP_BY_HOP_HEADERS
+        .iter()
+        .any(|header| name.eq_ignore_ascii_case(header))
+}
+
+pub async fn proxy(
+    axum::extract::State(proxy): axum::extract::State<Arc<DevProxy>>,
+    request: axum::extract::Request,
+) -> axum::response::Response {
+    match forward(&proxy, request).await {
+        Ok(response) => response,
+        Err(err) => {
+            println!("Dev proxy to {} failed: {}", proxy.authority, err);
+            error_response(502, "the frontend dev server is unreachable")
+        }
+    }
+}
+
+// The request goes out as HTTP/1.0, so the response isn't chunked and its
+// body is everything until the dev server closes the connection, which can
+// be streamed through as is.
+async fn forward(
+    proxy: &DevProxy,
+    request: axum::extract::Request,
+) -> io::Result<axum::response::Response> {
+    let (parts, body) = request.into_parts();
+    let body = axum::body::to_bytes(body, MAX_REQUEST_BODY_BYTES)
+        .await
+        .map_err(io::Error::other)?;
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    let mut head = format!(
+        "{} {} HTTP/1.0\r\nHost: {}\r\nContent-Length: {}\r\n",
+        parts.method,
+        path,
+        proxy.authority,
+        body.len()
+    );
+    for (name, value) in &parts.headers {
+        if is_hop_by_hop(name.as_str()) || name == http::header::CONTENT_LENGTH {
+            continue;
+        }
+        if let Ok(value) = value.to_str() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    head.push_str("\r\n");
+
+    let mut stream = tokio::net::TcpStream::connect((proxy.host.as_str(), proxy.port)).await?;
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.flush().await?;
+
+    let (response, rest) = tokio::time::timeout(
+        Duration::from_secs(RESPONSE_HEAD_TIMEOUT_SECS),
+        read_response_head(&mut stream),
+    )
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no response"))??;
+    let body =
+        futures_util::stream::unfold((stream, Some(rest)), |(mut stream, rest)| async move {
+            if let Some(rest) = rest.filter(|rest| !rest.is_empty()) {
+                return Some((Ok(axum::body::Bytes::from(rest)), (stream, None)));
+            }
+            let mut buffer = vec![0; READ_BUFFER_BYTES];
+            match stream.read(&mut buffer).await {
+                Ok(0) => None,
+                Ok(read) => {
+                    buffer.truncate(read);
+                    Some((Ok(axum::body::Bytes::from(buffer)), (stream, None)))
+                }
+                Err(err) => Some((Err(err), (stream, None))),
+            }
+        });
+    response
+        .body(axum::body::Body::from_stream(body))
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+// Returns the status and headers, and whatever of the body was read along
+// with them.
+async fn read_response_head(
+    stream: &mut tokio::net::TcpStream,
+) -> io::Result<(http::response::Builder, Vec<u8>)> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let mut raw = Vec::new();
+    let mut buffer = [0; READ_BUFFER_BYTES];
+    loop {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Err(invalid("connection closed before the response"));
+        }
+        raw.extend_from_slice(&buffer[..read]);
+        let mut headers = [httparse::EMPTY_HEADER; MAX_RESPONSE_HEADERS];
+        let mut parsed = httparse::Response::new(&mut headers);
+        match parsed.parse(&raw) {
+            Ok(httparse::Status::Complete(head_len)) => {
+                let mut response =
+                    axum::response::Response::builder().status(parsed.code.unwrap_or(502));
+                for header in parsed.headers.iter() {
+                    if !is_hop_by_hop(header.name) {
+                        response = response.header(header.name, header.value);
+                    }
+                }
+                return Ok((response, raw.split_off(head_len)));
+            }
+            Ok(httparse::Status::Partial) if raw.len() < MAX_RESPONSE_HEAD_BYTES => {}
+            Ok(httparse::Status::Partial) => return Err(invalid("response head too large")),
+            Err(err) => return Err(invalid(&err.to_string())),
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::admin_alerts;
+use crate::audit;
+use crate::cluster::Invalidation;
+use crate::config;
+use crate::devices;
+use crate::email;
+use crate::i18n;
+use crate::jwt::base64url_encode;
+use crate::notify;
+use crate::pages::{escape, page};
+use crate::security_digest;
+use crate::session::Session;
+use crate::time;
+use crate::{error_response, AppState};
+
+const LINK_TOKEN_BYTES: usize = 32;
+// Past this many unexpired links, new devices are still alerted about but
+// the alert comes without a link.
+const MAX_LINKS: usize = 100_000;
+
+struct Link {
+    user: String,
+    device: String,
+    expires_at: u64,
+}
+
+// Links live in this instance's memory, so they stop working when it
+// restarts and have to be opened on the instance that sent them.
+pub struct DeviceAlerts {
+    config: Option<config::NewDeviceAlertsConfig>,
+    links: Mutex<HashMap<String, Link>>,
+}
+
+impl DeviceAlerts {
+    pub fn new(config: Option<&config::NewDeviceAlertsConfig>) -> Self {
+        Self {
+            config: config.cloned(),
+            links: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn link(
+        &self,
+        rng: &dyn crate::rng::Rng,
+        user: &str,
+        device: &str,
+        now: u64,
+    ) -> Option<String> {
+        let config = self.config.as_ref()?;
+        let mut links = self.links.lock().unwrap();
+        if links.len() >= MAX_LINKS {
+            links.retain(|_, link| link.expires_at > now);
+            if links.len() >= MAX_LINKS {
+                return None;
+            }
+        }
+        let mut token = [0u8; LINK_TOKEN_BYTES];
+        rng.fill(&mut token);
+        let token = base64url_encode(&token);
+        links.insert(
+            token.clone(),
+            Link {
+                user: user.to_string(),
+                device: device.to_string(),
+                expires_at: now + config.link_ttl_secs,
+            },
+        );
+        Some(format!(
+            "{}/device_alert?token={}",
+            config.public_url.trim_end_matches('/'),
+            token
+        ))
+    }
+
+    fn redeem(&self, token: &str, now: u64) -> Option<Link> {
+        self.links
+            .lock()
+            .unwrap()
+            .remove(token)
+            .filter(|link| link.expires_at > now)
+    }
+
+    fn is_valid(&self, token: &str, now: u64) -> bool {
+        self.links
+            .lock()
+            .unwrap()
+            .get(token)
+            .is_some_and(|link| link.expires_at > now)
+    }
+}
+
+// Called after every authentication. Records the device the user
+// authenticated from and, if the user signed in from other devices before,
+// notifies the user about the new one.
+pub async fn seen(state: &AppState, user: &str, session: &Session, now: u64) {
+    admin_alerts::signed_in(state, user, session.ip).await;
+    let user_agent = session.user_agent.as_deref();
+    let device = state.devices.seen(user, user_agent, now).await;
+    state.security_digest.record(
+        user,
+        now,
+        security_digest::Activity::SignIn {
+            device_name: devices::device_name(user_agent),
+            ip: session.ip,
+            country: session.location.country.clone(),
+        },
+    );
+    let Some(device) = device else {
+        return;
+    };
+    state.security_digest.record(
+        user,
+        now,
+        security_digest::Activity::NewDevice {
+            device_name: device.name.clone(),
+        },
+    );
+    let alerts = &state.device_alerts;
+    let Some(config) = &alerts.config else {
+        return;
+    };
+    if state.devices.of(user).await.len() < 2 {
+        return;
+    }
+    let revoke_url = alerts.link(&*state.rng, user, &device.id, now);
+    let revoke_url_expires_at = revoke_url.as_ref().map(|_| now + config.link_ttl_secs);
+    let notification = notify::Notification {
+        event: email::Template::NewDevice,
+        user: user.to_string(),
+        details: serde_json::json!({
+            "device_id": device.id,
+            "device_name": device.name,
+            "user_agent": user_agent,
+            "ip": session.ip.to_string(),
+            "country": session.location.country,
+            "asn": session.location.asn,
+            "time": time::rfc3339(now),
+            "revoke_url": revoke_url,
+            "revoke_url_expires_at": revoke_url_expires_at,
+        }),
+    };
+    notify::send(state, &config.channels, notification).await;
+}
+
+#[derive(serde::Deserialize)]
+pub struct DeviceAlertQuery {
+    token: String,
+}
+
+fn message_page(
+    state: &AppState,
+    language: i18n::Language,
+    status: u16,
+    title: &str,
+    message: &str,
+) -> axum::response::Response {
+    page(
+        state,
+        language,
+        status,
+        title,
+        &format!(
+            "<h1>{}</h1>\n<p>{}</p>\n",
+            escape(language.t(title)),
+            escape(language.t(message))
+        ),
+        false,
+    )
+}
+
+// Opening the link only asks for confirmation, so mail scanners that follow
+// links don't sign anyone out.
+pub async fn get_device_alert(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: http::HeaderMap,
+    axum::extract::Query(query): axum::extract::Query<DeviceAlertQuery>,
+) -> axum::response::Response {
+    if state.config.new_device_alerts.is_none() {
+        return error_response(404, "new device alerts are not enabled");
+    }
+    let language = state.translations.negotiate(&headers);
+    if !state
+        .device_alerts
+        .is_valid(&query.token, state.clock.now_secs())
+    {
+        return message_page(
+            &state,
+            language,
+            404,
+            "Link expired",
+            "This link has expired or was already used.",
+        );
+    }
+    let body = format!(
+        "<h1>{}</h1>\n\
+         <p>{}</p>\n\
+         <form method=\"post\" action=\"/device_alert\">\n\
+         <input type=\"hidden\" name=\"token\" value=\"{}\">\n\
+         <button type=\"submit\">{}</button>\n\
+         </form>\n",
+        escape(language.t("This wasn't me")),
+        escape(language.t(
+            "Signing out the new device ends all of its sessions. Change your password \
+             afterwards."
+        )),
+        escape(&query.token),
+        escape(language.t("Sign out the device"))
+    );
+    page(&state, language, 200, "This wasn't me", &body, false)
+}
+
+pub async fn post_device_alert(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: http::HeaderMap,
+    axum::extract::Form(form): axum::extract::Form<DeviceAlertQuery>,
+) -> axum::response::Response {
+    if state.config.new_device_alerts.is_none() {
+        return error_response(404, "new device alerts are not enabled");
+    }
+    let language = state.translations.negotiate(&headers);
+    let Some(link) = state
+        .device_alerts
+        .redeem(&form.token, state.clock.now_secs())
+    else {
+        return message_page(
+            &state,
+            language,
+            404,
+            "Link expired",
+            "This link has expired or was already used.",
+        );
+    };
+    state.devices.forget(&link.user, &link.device).await;
+    let (revoked, forgotten) = devices::revoke_device(&state, &link.user, &link.device).await;
+    state.cluster.publish(Invalidation::DeviceRevoked {
+        user: link.user.clone(),
+        device: link.device.clone(),
+    });
+    audit::record(audit::AuditEvent::DeviceDisowned {
+        user: &link.user,
+        device: &link.device,
+        revoked_sessions: revoked,
+    });
+    println!(
+        "Removed device {} of {} with {} sessions and {} remember-me tokens from a new device \
+         alert",
+        link.device, link.user, revoked, forgotten
+    );
+    message_page(
+        &state,
+        language,
+        200,
+        "Device signed out",
+        "The device was signed out. Change your password in case someone else knows it.",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links_expire_and_work_once() {
+        let alerts = DeviceAlerts::new(Some(&config::NewDeviceAlertsConfig {
+            public_url: String::from("https://auth.example.com/"),
+            link_ttl_secs: 60,
+            channels: vec![config::NotificationChannel::Webhook],
+        }));
+        let rng = crate::rng::SeededRng::new(1);
+        let link = alerts.link(&rng, "zed", "device", 100).unwrap();
+        let token = link
+            .strip_prefix("https://auth.example.com/device_alert?token=")
+            .unwrap();
+        assert!(alerts.is_valid(token, 159));
+        assert!(!alerts.is_valid(token, 160));
+        assert!(alerts.redeem(token, 160).is_none());
+
+        let link = alerts.link(&rng, "zed", "device", 100).unwrap();
+        let token = link.rsplit_once('=').unwrap().1;
+        let redeemed = alerts.redeem(token, 120).unwrap();
+        assert_eq!(
+            (redeemed.user.as_str(), redeemed.device.as_str()),
+            ("zed", "device")
+        );
+        assert!(alerts.redeem(token, 120).is_none());
+    }
+}
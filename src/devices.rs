@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use tokio::sync::RwLock as TokioRwLock;
+
+use crate::client_info::ClientInfo;
+use crate::cluster::Invalidation;
+use crate::i18n;
+use crate::jwt::base64url_encode;
+use crate::pagination;
+use crate::session::SessionEvent;
+use crate::{
+    error_response, json_response, lookup_session, message_error_response, AppState,
+    GetSessionQuery, SESSION_NOT_AUTHENTICATED,
+};
+
+const FINGERPRINT_BYTES: usize = 12;
+const MAX_DEVICES_PER_USER: usize = 32;
+
+// A device is whatever presents the same user agent, so the fingerprint can
+// be derived from any session without storing anything on it.
+pub fn fingerprint(user_agent: Option<&str>) -> String {
+    let digest = ring::digest::digest(
+        &ring::digest::SHA256,
+        user_agent.unwrap_or_default().as_bytes(),
+    );
+    base64url_encode(&digest.as_ref()[..FINGERPRINT_BYTES])
+}
+
+pub fn device_name(user_agent: Option<&str>) -> String {
+    let Some(user_agent) = user_agent else {
+        return String::from("Unknown device");
+    };
+    // Order matters: Edge and Opera also claim to be Chrome, and Chrome
+    // claims to be Safari.
+    let browser = [
+        ("Edg/", "Edge"),
+        ("OPR/", "Opera"),
+        ("Firefox/", "Firefox"),
+        ("Chrome/", "Chrome"),
+        ("Safari/", "Safari"),
+        ("curl/", "curl"),
+    ]
+    .into_iter()
+    .find(|(token, _)| user_agent.contains(token))
+    .map(|(_, name)| name);
+    let os = [
+        ("Android", "Android"),
+        ("iPhone", "iOS"),
+        ("iPad", "iPadOS"),
+        ("Windows", "Windows"),
+        ("Mac OS X", "macOS"),
+        ("Linux", "Linux"),
+    ]
+    .into_iter()
+    .find(|(token, _)| user_agent.contains(token))
+    .map(|(_, name)| name);
+    match (browser, os) {
+        (Some(browser), Some(os)) => format!("{} on {}", browser, os),
+        (Some(name), None) | (None, Some(name)) => String::from(name),
+        (None, None) => user_agent.chars().take(100).collect(),
+    }
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct Device {
+    pub id: String,
+    pub name: String,
+    pub first_seen: u64,
+    pub last_seen: u64,
+}
+
+pub struct DeviceRegistry {
+    devices: TokioRwLock<HashMap<String, Vec<Device>>>,
+}
+
+impl DeviceRegistry {
+    pub fn new() -> Self {
+        Self {
+            devices: TokioRwLock::new(HashMap::new()),
+        }
+    }
+
+    // Called whenever the user authenticates from a device. Returns the
+    // device if the user hasn't authenticated from it before.
+    pub async fn seen(&self, user: &str, user_agent: Option<&str>, now: u64) -> Option<Device> {
+        let id = fingerprint(user_agent);
+        let mut devices = self.devices.write().await;
+        let devices = devices.entry(user.to_string()).or_default();
+        if let Some(device) = devices.iter_mut().find(|device| device.id == id) {
+            device.last_seen = now;
+            return None;
+        }
+        if devices.len() >= MAX_DEVICES_PER_USER {
+            devices.sort_by_key(|device| std::cmp::Reverse(device.last_seen));
+            devices.truncate(MAX_DEVICES_PER_USER - 1);
+        }
+        let device = Device {
+            id,
+            name: device_name(user_agent),
+            first_seen: now,
+            last_seen: now,
+        };
+        devices.push(device.clone());
+        Some(device)
+    }
+
+    pub async fn of(&self, user: &str) -> Vec<Device> {
+        self.devices
+            .read()
+            .await
+            .get(user)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub async fn forget(&self, user: &str, id: &str) -> bool {
+        let mut devices = self.devices.write().await;
+        let Some(user_devices) = devices.get_mut(user) else {
+            return false;
+        };
+        let before = user_devices.len();
+        user_devices.retain(|device| device.id != id);
+        let forgotten = user_devices.len() != before;
+        if user_devices.is_empty() {
+            devices.remove(user);
+        }
+        forgotten
+    }
+}
+
+// Returns how many sessions and remember-me tokens were revoked.
+pub async fn revoke_device(state: &AppState, user: &str, id: &str) -> (usize, usize) {
+    let mut revoked = 0;
+    for (other_id, other) in state.sessions.sessions_of(user).await {
+        if fingerprint(other.read().await.user_agent.as_deref()) != id {
+            continue;
+        }
+        if state.sessions.remove(&other_id).is_some() {
+            other.write().await.publish(SessionEvent::Revoked);
+            revoked += 1;
+        }
+    }
+    let forgotten = state.remember_me.revoke_device(user, id).await;
+    (revoked, forgotten)
+}
+
+#[derive(serde::Serialize)]
+struct DeviceSummary {
+    #[serde(flatten)]
+    device: Device,
+    current: bool,
+    sessions: usize,
+}
+
+pub async fn get_devices(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    axum::extract::Query(query): axum::extract::Query<GetSessionQuery>,
+    axum::extract::Query(page): axum::extract::Query<pagination::PageQuery>,
+) -> axum::response::Response {
+    let session = match lookup_session(&state, &query.session_id, &client).await {
+        Ok((_, session)) => session,
+        Err(response) => return response,
+    };
+    let (user, current) = {
+        let session_locked = session.read().await;
+        match (&session_locked.user, session_locked.authenticated) {
+            (Some(user), true) => (
+                user.clone(),
+                fingerprint(session_locked.user_agent.as_deref()),
+            ),
+            _ => return SESSION_NOT_AUTHENTICATED.response(),
+        }
+    };
+    let mut devices: Vec<DeviceSummary> = state
+        .devices
+        .of(&user)
+        .await
+        .into_iter()
+        .map(|device| DeviceSummary {
+            current: device.id == current,
+            sessions: 0,
+            device,
+        })
+        .collect();
+    // The registry is only written on authentication, so activity since then
+    // comes from the sessions themselves.
+    let now = state.clock.now_secs();
+    for (_, other) in state.sessions.sessions_of(&user).await {
+        let other = other.read().await;
+        if !other.authenticated || other.is_expired(now) {
+            continue;
+        }
+        let id = fingerprint(other.user_agent.as_deref());
+        if let Some(summary) = devices.iter_mut().find(|summary| summary.device.id == id) {
+            summary.sessions += 1;
+            summary.device.last_seen = summary.device.last_seen.max(other.last_seen);
+        }
+    }
+    // Most recently added first by default.
+    let key = |summary: &DeviceSummary| (summary.device.first_seen, summary.device.id.clone());
+    let (devices, next_cursor) =
+        match pagination::page(devices, key, &page, pagination::Order::Desc) {
+            Ok(page) => page,
+            Err(err) => return error_response(400, &err),
+        };
+    json_response(
+        200,
+        serde_json::json!({ "devices": devices, "next_cursor": next_cursor }),
+    )
+}
+
+pub async fn delete_device(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    axum::extract::Path(id): axum::extract::Path<String>,
+    axum::extract::Query(query): axum::extract::Query<GetSessionQuery>,
+) -> axum::response::Response {
+    let session = match lookup_session(&state, &query.session_id, &client).await {
+        Ok((_, session)) => session,
+        Err(response) => return response,
+    };
+    let (user, authenticated_at) = {
+        let session_locked = session.read().await;
+        match (&session_locked.user, session_locked.authenticated_at) {
+            (Some(user), Some(authenticated_at)) if session_locked.authenticated => {
+                (user.clone(), authenticated_at)
+            }
+            _ => return SESSION_NOT_AUTHENTICATED.response(),
+        }
+    };
+    if state.clock.now_secs().saturating_sub(authenticated_at)
+        > state.config.sessions.reauth_max_age_secs
+    {
+        return error_response(403, "authenticate again before removing devices");
+    }
+    if !state.devices.forget(&user, &id).await {
+        return message_error_response(
+            404,
+            i18n::Message {
+                template: "no device {id}",
+                args: vec![("id", id.to_string())],
+            },
+        );
+    }
+
+    let (revoked, forgotten) = revoke_device(&state, &user, &id).await;
+    state.cluster.publish(Invalidation::DeviceRevoked {
+        user: user.clone(),
+        device: id.clone(),
+    });
+    println!(
+        "Removed device {} of {} with {} sessions and {} remember-me tokens",
+        id, user, revoked, forgotten
+    );
+    json_response(
+        200,
+        serde_json::json!({
+            "success": format!("device {} removed", id),
+            "revoked": revoked,
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_devices_after_browser_and_os() {
+        assert_eq!(
+            device_name(Some(
+                "Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0"
+            )),
+            "Firefox on Linux"
+        );
+        assert_eq!(
+            device_name(Some(
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 \
+                 (KHTML, like Gecko) Chrome/129.0.0.0 Safari/537.36 Edg/129.0.0.0"
+            )),
+            "Edge on Windows"
+        );
+        assert_eq!(device_name(Some("curl/8.5.0")), "curl");
+        assert_eq!(device_name(None), "Unknown device");
+        assert_ne!(fingerprint(Some("curl/8.5.0")), fingerprint(None));
+    }
+}
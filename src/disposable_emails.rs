@@ -0,0 +1,100 @@
+use std::collections::HashSet;
+use std::io;
+
+use crate::config;
+
+const BUNDLED_DOMAINS: &str = include_str!("disposable_email_domains.txt");
+
+// Email domains of disposable providers, from the bundled list plus the
+// deployment's own, minus the ones it allows.
+#[derive(Default)]
+pub struct DisposableEmails {
+    action: Option<config::DisposableEmailAction>,
+    domains: HashSet<String>,
+    allowed: HashSet<String>,
+}
+
+// One domain per line; empty lines and lines starting with # are skipped.
+fn parse_domains(list: &str) -> impl Iterator<Item = String> + '_ {
+    list.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(normalize)
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl DisposableEmails {
+    pub fn new(config: Option<&config::DisposableEmailsConfig>) -> io::Result<Self> {
+        let Some(config) = config else {
+            return Ok(Self::default());
+        };
+        let mut domains: HashSet<String> = parse_domains(BUNDLED_DOMAINS).collect();
+        domains.extend(config.domains.iter().map(|domain| normalize(domain)));
+        if let Some(path) = &config.domains_path {
+            let list = std::fs::read_to_string(path).map_err(|err| {
+                io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+            })?;
+            domains.extend(parse_domains(&list));
+        }
+        Ok(Self {
+            action: Some(config.action),
+            domains,
+            allowed: config
+                .allowed_domains
+                .iter()
+                .map(|domain| normalize(domain))
+                .collect(),
+        })
+    }
+
+    // The listed domain `email` belongs to, if any, along with what to do
+    // about it. Subdomains of a listed domain count as listed.
+    pub fn check(&self, email: &str) -> Option<(String, config::DisposableEmailAction)> {
+        let action = self.action?;
+        let domain = normalize(email.rsplit_once('@')?.1);
+        let mut candidate = domain.as_str();
+        loop {
+            if self.allowed.contains(candidate) {
+                return None;
+            }
+            if self.domains.contains(candidate) {
+                return Some((candidate.to_string(), action));
+            }
+            candidate = candidate.split_once('.')?.1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_listed_domains_and_their_subdomains() {
+        let emails = DisposableEmails::new(Some(&config::DisposableEmailsConfig {
+            domains: vec![String::from("Throwaway.Example.")],
+            allowed_domains: vec![String::from("ok.mailinator.com")],
+            ..config::DisposableEmailsConfig::default()
+        }))
+        .unwrap();
+        let domain = |email| emails.check(email).map(|(domain, _)| domain);
+        assert_eq!(
+            domain("zed@MAILINATOR.com").as_deref(),
+            Some("mailinator.com")
+        );
+        assert_eq!(
+            domain("zed@eu.throwaway.example").as_deref(),
+            Some("throwaway.example")
+        );
+        assert_eq!(domain("zed@ok.mailinator.com"), None);
+        assert_eq!(domain("zed@example.com"), None);
+        assert_eq!(domain("zed@notmailinator.com"), None);
+        assert_eq!(domain("zed"), None);
+        assert!(DisposableEmails::default()
+            .check("zed@mailinator.com")
+            .is_none());
+    }
+}
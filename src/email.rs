@@ -0,0 +1,834 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use zeroize::Zeroizing;
+
+use crate::config::{self, EmailTls};
+use crate::http_client;
+use crate::time;
+
+const MAX_REPLY_LINE_BYTES: u64 = 4096;
+const MAX_REPLY_LINES: usize = 100;
+const BODY_LINE_CHARS: usize = 76;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Template {
+    // Placeholders: user, url, expires_at.
+    Verification,
+    // Placeholders: user, url, expires_at.
+    PasswordReset,
+    // Placeholders: user, device_id, device_name, user_agent, ip, country,
+    // asn, time, revoke_url, revoke_url_expires_at.
+    NewDevice,
+    // Placeholders: user, period, sign_in_count, sign_ins, new_devices,
+    // remember_me_tokens; the last three are lists, one entry per line.
+    SecurityDigest,
+}
+
+impl Template {
+    const ALL: [Template; 4] = [
+        Template::Verification,
+        Template::PasswordReset,
+        Template::NewDevice,
+        Template::SecurityDigest,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Verification => "verification",
+            Self::PasswordReset => "password_reset",
+            Self::NewDevice => "new_device",
+            Self::SecurityDigest => "security_digest",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|template| template.name() == name)
+    }
+
+    fn builtin(self) -> &'static str {
+        match self {
+            Self::Verification => {
+                "Subject: Confirm your email address\n\
+                 \n\
+                 Hello {{user}},\n\
+                 \n\
+                 open this link to confirm your email address:\n\
+                 \n\
+                 {{url}}\n\
+                 \n\
+                 The link works until {{expires_at}}. If you didn't ask for this, you can \
+                 ignore this message.\n"
+            }
+            Self::PasswordReset => {
+                "Subject: Reset your password\n\
+                 \n\
+                 Hello {{user}},\n\
+                 \n\
+                 open this link to choose a new password:\n\
+                 \n\
+                 {{url}}\n\
+                 \n\
+                 The link works until {{expires_at}}. If you didn't ask for this, you can \
+                 ignore this message and your password stays the same.\n"
+            }
+            Self::NewDevice => {
+                "Subject: New sign-in to your account\n\
+                 \n\
+                 Hello {{user}},\n\
+                 \n\
+                 your account was signed in to from a device it wasn't used on before:\n\
+                 \n\
+                 Device: {{device_name}}\n\
+                 IP address: {{ip}}\n\
+                 Country: {{country}}\n\
+                 Time: {{time}}\n\
+                 \n\
+                 If this wasn't you, open this link to sign the device out, then change \
+                 your password:\n\
+                 \n\
+                 {{revoke_url}}\n"
+            }
+            Self::SecurityDigest => {
+                "Subject: Your account's security summary\n\
+                 \n\
+                 Hello {{user}},\n\
+                 \n\
+                 this is what happened on your account in the past {{period}}.\n\
+                 \n\
+                 Sign-ins ({{sign_in_count}}):\n\
+                 {{sign_ins}}\n\
+                 \n\
+                 New devices:\n\
+                 {{new_devices}}\n\
+                 \n\
+                 Devices that were kept signed in:\n\
+                 {{remember_me_tokens}}\n\
+                 \n\
+                 If something here wasn't you, change your password and sign out the \
+                 devices you don't recognize.\n"
+            }
+        }
+    }
+
+    // Made-up values for test messages, one for every placeholder.
+    fn sample_values(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Self::Verification | Self::PasswordReset => &[
+                ("user", "zed"),
+                ("url", "https://auth.example.com/example"),
+                ("expires_at", "2030-01-01T00:00:00Z"),
+            ],
+            Self::NewDevice => &[
+                ("user", "zed"),
+                ("device_id", "kjRz5zg04gai7ESh"),
+                ("device_name", "Firefox on Linux"),
+                (
+                    "user_agent",
+                    "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0",
+                ),
+                ("ip", "192.0.2.1"),
+                ("country", "DE"),
+                ("asn", "64496"),
+                ("time", "2030-01-01T00:00:00Z"),
+                (
+                    "revoke_url",
+                    "https://auth.example.com/device_alert?token=example",
+                ),
+                ("revoke_url_expires_at", "1893456000"),
+            ],
+            Self::SecurityDigest => &[
+                ("user", "zed"),
+                ("period", "week"),
+                ("sign_in_count", "2"),
+                (
+                    "sign_ins",
+                    "2030-01-01T00:00:00Z Firefox on Linux, 192.0.2.1 (DE)\n\
+                     2030-01-03T00:00:00Z Safari on iOS, 192.0.2.2 (DE)",
+                ),
+                ("new_devices", "2030-01-03T00:00:00Z Safari on iOS"),
+                ("remember_me_tokens", "None"),
+            ],
+        }
+    }
+}
+
+// A template is a "Subject:" line, an empty line and the body.
+fn parse_template(text: &str) -> Option<(String, String)> {
+    let text = text.replace("\r\n", "\n");
+    let (subject, body) = text.split_once("\n\n")?;
+    let subject = subject.strip_prefix("Subject:")?.trim();
+    if subject.contains('\n') {
+        return None;
+    }
+    Some((subject.to_string(), body.to_string()))
+}
+
+// Replaces {{name}} with the value of name; unknown names are left empty.
+pub fn render(text: &str, values: &[(&str, &str)]) -> String {
+    let mut rendered = String::new();
+    let mut rest = text;
+    while let Some((before, after)) = rest.split_once("{{") {
+        let Some((name, after)) = after.split_once("}}") else {
+            break;
+        };
+        rendered.push_str(before);
+        if let Some((_, value)) = values.iter().find(|(other, _)| *other == name.trim()) {
+            rendered.push_str(value);
+        }
+        rest = after;
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+// The names of the {{name}} placeholders in text.
+fn placeholders(text: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut rest = text;
+    while let Some((_, after)) = rest.split_once("{{") {
+        let Some((name, after)) = after.split_once("}}") else {
+            break;
+        };
+        names.push(name.trim());
+        rest = after;
+    }
+    names
+}
+
+// Parses a template and checks that it only uses placeholders the template
+// is rendered with.
+fn check_template(template: Template, text: &str) -> Result<(String, String), String> {
+    let (subject, body) =
+        parse_template(text).ok_or("must start with a Subject: line and an empty line")?;
+    let known = template.sample_values();
+    if let Some(unknown) = placeholders(&subject)
+        .into_iter()
+        .chain(placeholders(&body))
+        .find(|name| !known.iter().any(|(known, _)| known == name))
+    {
+        return Err(format!("has no placeholder {{{{{}}}}}", unknown));
+    }
+    Ok((subject, body))
+}
+
+// The <template>.txt files in dir. Other .txt files are refused, since they
+// are most likely misnamed templates.
+fn load_templates(dir: &Path) -> io::Result<HashMap<Template, (String, String)>> {
+    let with_path =
+        |path: &Path, err: &dyn std::fmt::Display| format!("{}: {}", path.display(), err);
+    let mut templates = HashMap::new();
+    let entries =
+        std::fs::read_dir(dir).map_err(|err| io::Error::new(err.kind(), with_path(dir, &err)))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|extension| extension != "txt") {
+            continue;
+        }
+        let name = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or_default();
+        let invalid = |message: &dyn std::fmt::Display| {
+            io::Error::new(io::ErrorKind::InvalidData, with_path(&path, message))
+        };
+        let template =
+            Template::from_name(name).ok_or_else(|| invalid(&"no such email template"))?;
+        let text = std::fs::read_to_string(&path)
+            .map_err(|err| io::Error::new(err.kind(), with_path(&path, &err)))?;
+        templates.insert(
+            template,
+            check_template(template, &text).map_err(|err| invalid(&err))?,
+        );
+    }
+    Ok(templates)
+}
+
+// Language tags are matched like de-at and de_AT would be the same.
+fn normalize_language(tag: &str) -> String {
+    tag.replace('_', "-").to_ascii_lowercase()
+}
+
+// The bare address out of `Name <address>`.
+fn envelope_address(address: &str) -> &str {
+    address
+        .rsplit_once('<')
+        .and_then(|(_, address)| address.strip_suffix('>'))
+        .unwrap_or(address)
+        .trim()
+}
+
+fn is_valid_address(address: &str) -> bool {
+    address.len() <= 254
+        && address
+            .split_once('@')
+            .is_some_and(|(local, domain)| !local.is_empty() && !domain.is_empty())
+        && !address.chars().any(|c| {
+            c.is_whitespace() || c.is_control() || matches!(c, '<' | '>' | ',' | ';' | '"')
+        })
+}
+
+// Non-ASCII subjects are sent as RFC 2047 encoded words.
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        return value.to_string();
+    }
+    let mut words = Vec::new();
+    let mut word = String::new();
+    for c in value.chars() {
+        if word.len() + c.len_utf8() > 45 {
+            words.push(std::mem::take(&mut word));
+        }
+        word.push(c);
+    }
+    words.push(word);
+    words
+        .iter()
+        .map(|word| {
+            format!(
+                "=?UTF-8?B?{}?=",
+                base64::engine::general_purpose::STANDARD.encode(word)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\r\n ")
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+struct Connection {
+    stream: BufReader<Box<dyn Stream>>,
+}
+
+impl Connection {
+    fn new(stream: Box<dyn Stream>) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    // Returns the text of the reply's lines, or an error if its code isn't
+    // one of `expected`.
+    async fn reply(&mut self, expected: &[u16]) -> io::Result<String> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+        let mut text = String::new();
+        for _ in 0..MAX_REPLY_LINES {
+            let mut line = String::new();
+            (&mut self.stream)
+                .take(MAX_REPLY_LINE_BYTES)
+                .read_line(&mut line)
+                .await?;
+            if !line.ends_with('\n') {
+                return Err(invalid("truncated or overlong SMTP reply"));
+            }
+            let line = line.trim_end();
+            let code: u16 = line
+                .get(..3)
+                .and_then(|code| code.parse().ok())
+                .ok_or_else(|| invalid("malformed SMTP reply"))?;
+            text.push_str(line.get(4..).unwrap_or_default());
+            text.push('\n');
+            if line.as_bytes().get(3) != Some(&b'-') {
+                if !expected.contains(&code) {
+                    return Err(io::Error::other(format!("SMTP server replied {}", line)));
+                }
+                return Ok(text);
+            }
+        }
+        Err(invalid("overlong SMTP reply"))
+    }
+
+    async fn command(&mut self, command: &str, expected: &[u16]) -> io::Result<String> {
+        let stream = self.stream.get_mut();
+        stream.write_all(command.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
+        stream.flush().await?;
+        self.reply(expected).await
+    }
+
+    async fn authenticate(
+        &mut self,
+        extensions: &str,
+        username: &str,
+        password: &str,
+    ) -> io::Result<()> {
+        let mechanisms: Vec<&str> = extensions
+            .lines()
+            .find_map(|line| {
+                let (keyword, mechanisms) = line.split_once([' ', '='])?;
+                keyword
+                    .eq_ignore_ascii_case("AUTH")
+                    .then(|| mechanisms.split_whitespace().collect())
+            })
+            .unwrap_or_default();
+        let offers = |name: &str| {
+            mechanisms
+                .iter()
+                .any(|other| other.eq_ignore_ascii_case(name))
+        };
+        let base64 =
+            |value: &str| Zeroizing::new(base64::engine::general_purpose::STANDARD.encode(value));
+        if offers("PLAIN") {
+            let credentials = Zeroizing::new(format!("\0{}\0{}", username, password));
+            let command = Zeroizing::new(format!("AUTH PLAIN {}", base64(&credentials).as_str()));
+            self.command(&command, &[235]).await?;
+        } else if offers("LOGIN") {
+            self.command("AUTH LOGIN", &[334]).await?;
+            self.command(&base64(username), &[334]).await?;
+            self.command(&base64(password), &[235]).await?;
+        } else {
+            return Err(io::Error::other(
+                "SMTP server offers neither AUTH PLAIN nor AUTH LOGIN",
+            ));
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, from: &str, to: &str, message: &[u8]) -> io::Result<()> {
+        self.command(&format!("MAIL FROM:<{}>", from), &[250])
+            .await?;
+        self.command(&format!("RCPT TO:<{}>", to), &[250, 251])
+            .await?;
+        self.command("DATA", &[354]).await?;
+        self.stream.get_mut().write_all(message).await?;
+        self.command(".", &[250]).await?;
+        Ok(())
+    }
+}
+
+// Sends templated mail through the configured SMTP server, keeping up to
+// pool_size connections open for reuse.
+pub struct EmailSender {
+    config: Option<config::EmailConfig>,
+    tls: Option<tokio_rustls::TlsConnector>,
+    templates: HashMap<Template, (String, String)>,
+    // By normalized language tag; templates missing there fall back to the
+    // ones above.
+    localized: HashMap<String, HashMap<Template, (String, String)>>,
+    idle: Mutex<Vec<(Connection, Instant)>>,
+    slots: tokio::sync::Semaphore,
+    message_ids: AtomicU64,
+}
+
+impl Default for EmailSender {
+    fn default() -> Self {
+        Self {
+            config: None,
+            tls: None,
+            templates: HashMap::new(),
+            localized: HashMap::new(),
+            idle: Mutex::new(Vec::new()),
+            slots: tokio::sync::Semaphore::new(0),
+            message_ids: AtomicU64::new(0),
+        }
+    }
+}
+
+impl EmailSender {
+    pub fn new(config: &config::Config) -> io::Result<Self> {
+        let Some(email) = &config.email else {
+            return Ok(Self::default());
+        };
+        let tls = tokio_rustls::rustls::ClientConfig::builder()
+            .with_root_certificates(http_client::root_certificates(&config.secrets.ca_path)?)
+            .with_no_client_auth();
+        let mut templates: HashMap<_, _> = Template::ALL
+            .into_iter()
+            .map(|template| (template, parse_template(template.builtin()).unwrap()))
+            .collect();
+        let mut localized = HashMap::new();
+        if let Some(dir) = &email.templates_dir {
+            templates.extend(load_templates(dir)?);
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                let Some(tag) = path.file_name().and_then(|name| name.to_str()) else {
+                    continue;
+                };
+                if path.is_dir() {
+                    localized.insert(normalize_language(tag), load_templates(&path)?);
+                }
+            }
+        }
+        Ok(Self {
+            config: Some(email.clone()),
+            tls: Some(tokio_rustls::TlsConnector::from(Arc::new(tls))),
+            templates,
+            localized,
+            idle: Mutex::new(Vec::new()),
+            slots: tokio::sync::Semaphore::new(email.pool_size),
+            message_ids: AtomicU64::new(0),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.is_some()
+    }
+
+    // The subject and body for `language`, trying the whole tag and then
+    // just its primary language, such as de for de-AT.
+    fn template(&self, template: Template, language: Option<&str>) -> &(String, String) {
+        let tag = language.map(normalize_language).unwrap_or_default();
+        let primary = tag.split('-').next().unwrap_or_default();
+        let localized = [tag.as_str(), primary]
+            .into_iter()
+            .find_map(|tag| self.localized.get(tag)?.get(&template));
+        localized.unwrap_or(&self.templates[&template])
+    }
+
+    pub async fn send(
+        &self,
+        template: Template,
+        language: Option<&str>,
+        to: &str,
+        values: &[(&str, &str)],
+    ) -> io::Result<()> {
+        let Some(config) = &self.config else {
+            return Err(io::Error::other("email is not configured"));
+        };
+        if !is_valid_address(to) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid email address {:?}", to),
+            ));
+        }
+        let (subject, body) = self.template(template, language);
+        let subject: String = render(subject, values)
+            .chars()
+            .filter(|c| !c.is_control())
+            .collect();
+        let message = self.message(config, to, &subject, &render(body, values));
+        tokio::time::timeout(
+            Duration::from_secs(config.timeout_secs),
+            self.deliver(config, to, &message),
+        )
+        .await
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{}: timed out", config.host),
+            )
+        })?
+    }
+
+    fn message(
+        &self,
+        config: &config::EmailConfig,
+        to: &str,
+        subject: &str,
+        body: &str,
+    ) -> Vec<u8> {
+        let now = time::now_secs();
+        let domain = envelope_address(&config.from)
+            .rsplit_once('@')
+            .map_or("localhost", |(_, domain)| domain);
+        let mut message = format!(
+            "From: {}\r\n\
+             To: {}\r\n\
+             Subject: {}\r\n\
+             Date: {}\r\n\
+             Message-ID: <{}.{}.{}@{}>\r\n\
+             MIME-Version: 1.0\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             Content-Transfer-Encoding: base64\r\n\
+             \r\n",
+            config.from,
+            to,
+            encode_header(subject),
+            time::rfc2822(now),
+            now,
+            std::process::id(),
+            self.message_ids.fetch_add(1, Ordering::Relaxed),
+            domain
+        );
+        // Base64 lines never start with a dot, so nothing needs dot-stuffing.
+        let body = body.replace("\r\n", "\n").replace('\n', "\r\n");
+        let encoded = base64::engine::general_purpose::STANDARD.encode(body);
+        for line in encoded.as_bytes().chunks(BODY_LINE_CHARS) {
+            message.push_str(std::str::from_utf8(line).unwrap());
+            message.push_str("\r\n");
+        }
+        message.into_bytes()
+    }
+
+    async fn deliver(
+        &self,
+        config: &config::EmailConfig,
+        to: &str,
+        message: &[u8],
+    ) -> io::Result<()> {
+        let _slot = self.slots.acquire().await.map_err(io::Error::other)?;
+        let idle = {
+            let mut idle = self.idle.lock().unwrap();
+            let timeout = Duration::from_secs(config.idle_timeout_secs);
+            idle.retain(|(_, since)| since.elapsed() < timeout);
+            idle.pop().map(|(connection, _)| connection)
+        };
+        // The server may have closed an idle connection in the meantime.
+        let mut connection = match idle {
+            Some(mut connection) => match connection.command("RSET", &[250]).await {
+                Ok(_) => connection,
+                Err(_) => self.connect(config).await?,
+            },
+            None => self.connect(config).await?,
+        };
+        connection
+            .send(envelope_address(&config.from), to, message)
+            .await?;
+        self.idle.lock().unwrap().push((connection, Instant::now()));
+        Ok(())
+    }
+
+    async fn connect(&self, config: &config::EmailConfig) -> io::Result<Connection> {
+        let tls = self.tls.as_ref().unwrap();
+        let server_name = || {
+            tokio_rustls::rustls::pki_types::ServerName::try_from(config.host.clone())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+        };
+        let port = config.port.unwrap_or(match config.tls {
+            EmailTls::Implicit => 465,
+            EmailTls::Starttls => 587,
+            EmailTls::None => 25,
+        });
+        let stream = tokio::net::TcpStream::connect((config.host.as_str(), port)).await?;
+        let mut connection = if config.tls == EmailTls::Implicit {
+            Connection::new(Box::new(tls.connect(server_name()?, stream).await?))
+        } else {
+            Connection::new(Box::new(stream))
+        };
+        connection.reply(&[220]).await?;
+        let hello = format!("EHLO {}", config.hello_name);
+        let mut extensions = connection.command(&hello, &[250]).await?;
+        if config.tls == EmailTls::Starttls {
+            connection.command("STARTTLS", &[220]).await?;
+            // Anything that came before the handshake could have been injected
+            // by someone in between.
+            if !connection.stream.buffer().is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "SMTP server sent data after STARTTLS",
+                ));
+            }
+            let stream = connection.stream.into_inner();
+            connection = Connection::new(Box::new(tls.connect(server_name()?, stream).await?));
+            extensions = connection.command(&hello, &[250]).await?;
+        }
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            connection
+                .authenticate(&extensions, username, password)
+                .await?;
+        }
+        Ok(connection)
+    }
+}
+
+pub async fn run_cli(
+    config: &config::Config,
+    mut args: impl Iterator<Item = String>,
+) -> io::Result<()> {
+    let usage = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "usage: tk-auth send-test-email [--config PATH] \
+             [--template verification|password_reset|new_device|security_digest] \
+             [--language TAG] ADDRESS",
+        )
+    };
+    let mut template = Template::Verification;
+    let mut language = None;
+    let mut to = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--template" => {
+                template = args
+                    .next()
+                    .and_then(|name| Template::from_name(&name))
+                    .ok_or_else(usage)?
+            }
+            "--language" => language = Some(args.next().ok_or_else(usage)?),
+            "--config" => {
+                args.next();
+            }
+            arg if arg.starts_with("--config=") => {}
+            arg if !arg.starts_with("--") && to.is_none() => to = Some(arg.to_string()),
+            _ => return Err(usage()),
+        }
+    }
+    let to = to.ok_or_else(usage)?;
+    let sender = EmailSender::new(config)?;
+    if !sender.is_enabled() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "email is not configured",
+        ));
+    }
+    sender
+        .send(template, language.as_deref(), &to, template.sample_values())
+        .await?;
+    println!("Sent the {} template to {}", template.name(), to);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sends_templated_mail_over_smtp() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let mut lines = BufReader::new(read).lines();
+            let mut commands = Vec::new();
+            let mut messages: Vec<Vec<String>> = Vec::new();
+            let mut in_data = false;
+            write.write_all(b"220 mx.example.com\r\n").await.unwrap();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                if in_data && line != "." {
+                    messages.last_mut().unwrap().push(line);
+                    continue;
+                }
+                in_data = line == "DATA";
+                let reply: &[u8] = match line.as_str() {
+                    "DATA" => {
+                        messages.push(Vec::new());
+                        b"354 go ahead\r\n"
+                    }
+                    line if line.starts_with("EHLO") => b"250-mx.example.com\r\n250 8BITMIME\r\n",
+                    _ => b"250 ok\r\n",
+                };
+                commands.push(line);
+                write.write_all(reply).await.unwrap();
+            }
+            (commands, messages)
+        });
+
+        let sender = EmailSender::new(&config::Config {
+            email: Some(config::EmailConfig {
+                host: String::from("127.0.0.1"),
+                port: Some(port),
+                tls: EmailTls::None,
+                username: None,
+                password: None,
+                from: String::from("tk-auth <noreply@auth.example.com>"),
+                hello_name: String::from("auth.example.com"),
+                pool_size: 1,
+                idle_timeout_secs: 60,
+                timeout_secs: 5,
+                templates_dir: None,
+            }),
+            ..config::Config::default()
+        })
+        .unwrap();
+        let values = Template::NewDevice.sample_values();
+        for _ in 0..2 {
+            sender
+                .send(Template::NewDevice, None, "zed@example.com", values)
+                .await
+                .unwrap();
+        }
+        let injected = "zed@example.com>\r\nRCPT TO:<eve@example.com";
+        assert!(sender
+            .send(Template::NewDevice, None, injected, values)
+            .await
+            .is_err());
+        drop(sender);
+
+        let (commands, messages) = server.await.unwrap();
+        // Both messages go over one connection, the second after a reset.
+        assert_eq!(
+            commands,
+            [
+                "EHLO auth.example.com",
+                "MAIL FROM:<noreply@auth.example.com>",
+                "RCPT TO:<zed@example.com>",
+                "DATA",
+                ".",
+                "RSET",
+                "MAIL FROM:<noreply@auth.example.com>",
+                "RCPT TO:<zed@example.com>",
+                "DATA",
+                ".",
+            ]
+        );
+        let message = &messages[0];
+        assert!(message.contains(&String::from("Subject: New sign-in to your account")));
+        let body_start = message.iter().position(String::is_empty).unwrap() + 1;
+        let body = base64::engine::general_purpose::STANDARD
+            .decode(message[body_start..].concat())
+            .unwrap();
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.starts_with("Hello zed,\r\n"));
+        assert!(body.contains("IP address: 192.0.2.1\r\n"));
+    }
+
+    #[test]
+    fn picks_templates_by_language_and_checks_them() {
+        for template in Template::ALL {
+            check_template(template, template.builtin()).unwrap();
+        }
+        let dir = std::env::temp_dir().join(format!("tk-auth-email-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("de")).unwrap();
+        std::fs::write(
+            dir.join("de").join("new_device.txt"),
+            "Subject: Neue Anmeldung\n\nHallo {{user}}, {{device_name}}\n",
+        )
+        .unwrap();
+        let config = config::Config {
+            email: Some(config::EmailConfig {
+                host: String::from("127.0.0.1"),
+                port: None,
+                tls: EmailTls::None,
+                username: None,
+                password: None,
+                from: String::from("noreply@auth.example.com"),
+                hello_name: String::from("auth.example.com"),
+                pool_size: 1,
+                idle_timeout_secs: 60,
+                timeout_secs: 5,
+                templates_dir: Some(dir.clone()),
+            }),
+            ..config::Config::default()
+        };
+        let sender = EmailSender::new(&config).unwrap();
+        let subject = |template, language| sender.template(template, language).0.clone();
+        assert_eq!(
+            subject(Template::NewDevice, Some("de_AT")),
+            "Neue Anmeldung"
+        );
+        assert_eq!(subject(Template::NewDevice, Some("DE")), "Neue Anmeldung");
+        assert_eq!(
+            subject(Template::NewDevice, Some("fr")),
+            "New sign-in to your account"
+        );
+        assert_eq!(
+            subject(Template::PasswordReset, Some("de")),
+            "Reset your password"
+        );
+
+        std::fs::write(
+            dir.join("de").join("password_reset.txt"),
+            "Subject: Passwort\n\n{{link}}\n",
+        )
+        .unwrap();
+        let err = EmailSender::new(&config).err().unwrap();
+        assert!(err.to_string().ends_with("has no placeholder {{link}}"));
+        std::fs::remove_file(dir.join("de").join("password_reset.txt")).unwrap();
+        std::fs::write(dir.join("new_devices.txt"), "Subject: x\n\nx\n").unwrap();
+        let err = EmailSender::new(&config).err().unwrap();
+        assert!(err.to_string().ends_with("no such email template"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
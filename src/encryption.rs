@@ -0,0 +1,186 @@
+use std::io;
+use std::sync::Arc;
+
+use ring::aead;
+use zeroize::Zeroizing;
+
+use crate::config;
+use crate::jwt::{base64url_decode, base64url_encode};
+use crate::rng::Rng;
+use crate::secrets;
+
+// Keys are derived rather than used as given, so a secret that's also a
+// signing key somewhere else doesn't end up as both.
+const KEY_INFO: &[u8] = b"tk-auth encryption at rest";
+
+struct Key {
+    id: u8,
+    key: aead::LessSafeKey,
+}
+
+// What a sealed value decrypted to.
+pub struct Opened {
+    pub plaintext: Zeroizing<Vec<u8>>,
+    // Sealed with a key other than the active one, so it should be sealed
+    // again when next written.
+    pub is_stale: bool,
+}
+
+// AES-256-GCM with the keys of the encryption setting. Sealed values are
+// the key id and the base64url of the nonce, ciphertext and tag, with a
+// context, like where the value is stored, as associated data so values
+// can't be moved elsewhere unnoticed.
+pub struct Encryption {
+    keys: Vec<Key>,
+    active: u8,
+    rng: ring::rand::SystemRandom,
+}
+
+impl Encryption {
+    // Keys are only loaded at startup; rotating one means adding it as the
+    // active key and restarting.
+    pub async fn from_config(
+        config: &config::Config,
+        secrets: &secrets::SecretProvider,
+    ) -> io::Result<Option<Arc<Self>>> {
+        let Some(encryption) = &config.encryption else {
+            return Ok(None);
+        };
+        let keys = secrets
+            .load_encryption_keys(encryption)
+            .await
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(Some(Arc::new(Self::new(keys, encryption.active_key()))))
+    }
+
+    pub fn new(keys: Vec<(u8, Zeroizing<Vec<u8>>)>, active: u8) -> Self {
+        let salt = ring::hkdf::Salt::new(ring::hkdf::HKDF_SHA256, &[]);
+        let keys = keys
+            .into_iter()
+            .map(|(id, secret)| {
+                let prk = salt.extract(&secret);
+                let okm = prk.expand(&[KEY_INFO], &aead::AES_256_GCM).unwrap();
+                Key {
+                    id,
+                    key: aead::LessSafeKey::new(aead::UnboundKey::from(okm)),
+                }
+            })
+            .collect();
+        Self {
+            keys,
+            active,
+            rng: ring::rand::SystemRandom::new(),
+        }
+    }
+
+    pub fn seal(&self, context: &str, plaintext: &[u8]) -> String {
+        // Validated on load.
+        let key = self.keys.iter().find(|key| key.id == self.active).unwrap();
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        self.rng.fill(&mut nonce);
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(plaintext);
+        let (_, in_out) = sealed.split_at_mut(aead::NONCE_LEN);
+        let tag = key
+            .key
+            .seal_in_place_separate_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(context.as_bytes()),
+                in_out,
+            )
+            .unwrap();
+        sealed.extend_from_slice(tag.as_ref());
+        format!("{}.{}", key.id, base64url_encode(&sealed))
+    }
+
+    pub fn open(&self, context: &str, sealed: &str) -> Result<Opened, String> {
+        let (id, sealed) = sealed
+            .split_once('.')
+            .ok_or_else(|| String::from("malformed encrypted value"))?;
+        let id: u8 = id
+            .parse()
+            .map_err(|_| String::from("malformed encrypted value"))?;
+        let key =
+            self.keys.iter().find(|key| key.id == id).ok_or_else(|| {
+                format!("encrypted with key {}, which is not in encryption.keys", id)
+            })?;
+        let sealed = base64url_decode(sealed)
+            .filter(|sealed| sealed.len() >= aead::NONCE_LEN + aead::AES_256_GCM.tag_len())
+            .ok_or_else(|| String::from("malformed encrypted value"))?;
+        let (nonce, ciphertext) = sealed.split_at(aead::NONCE_LEN);
+        let mut in_out = Zeroizing::new(ciphertext.to_vec());
+        let len = key
+            .key
+            .open_in_place(
+                aead::Nonce::try_assume_unique_for_key(nonce).unwrap(),
+                aead::Aad::from(context.as_bytes()),
+                &mut in_out,
+            )
+            .map_err(|_| format!("failed to decrypt a value with key {}", id))?
+            .len();
+        in_out.truncate(len);
+        Ok(Opened {
+            plaintext: in_out,
+            is_stale: id != self.active,
+        })
+    }
+
+    // JSON values are sealed into {"sealed": "..."}, which no field
+    // they stand in for can hold otherwise.
+    pub fn seal_value(&self, context: &str, value: &serde_json::Value) -> serde_json::Value {
+        let plaintext = Zeroizing::new(serde_json::to_vec(value).unwrap());
+        serde_json::json!({ "sealed": self.seal(context, &plaintext) })
+    }
+
+    // The value and whether it's stale, see Opened.
+    pub fn open_value(
+        &self,
+        context: &str,
+        sealed: &str,
+    ) -> Result<(serde_json::Value, bool), String> {
+        let opened = self.open(context, sealed)?;
+        let value = serde_json::from_slice(&opened.plaintext)
+            .map_err(|err| format!("invalid encrypted value: {}", err))?;
+        Ok((value, opened.is_stale))
+    }
+}
+
+// The sealed string of a value seal_value made.
+pub fn sealed(value: &serde_json::Value) -> Option<&str> {
+    match value {
+        serde_json::Value::Object(object) if object.len() == 1 => object.get("sealed")?.as_str(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_values_sealed_with_older_keys() {
+        let secret = |byte| Zeroizing::new(vec![byte; 32]);
+        let old = Encryption::new(vec![(1, secret(b'a'))], 1);
+        let rotated = Encryption::new(vec![(1, secret(b'a')), (2, secret(b'b'))], 2);
+
+        let sealed = old.seal("users/a/emails", b"a@example.com");
+        assert!(sealed.starts_with("1."));
+        assert_ne!(sealed, old.seal("users/a/emails", b"a@example.com"));
+        let opened = rotated.open("users/a/emails", &sealed).unwrap();
+        assert_eq!(&opened.plaintext[..], b"a@example.com");
+        assert!(opened.is_stale);
+        // Bound to where it was stored.
+        assert!(rotated.open("users/b/emails", &sealed).is_err());
+
+        let value = serde_json::json!(["a@example.com"]);
+        let sealed_value = rotated.seal_value("users/a/emails", &value);
+        let inner = super::sealed(&sealed_value).unwrap();
+        assert!(inner.starts_with("2."));
+        assert_eq!(
+            rotated.open_value("users/a/emails", inner).unwrap(),
+            (value, false)
+        );
+        assert!(old.open("users/a/emails", inner).is_err());
+        assert!(super::sealed(&serde_json::json!("2.abc")).is_none());
+    }
+}
@@ -0,0 +1,175 @@
+use std::sync::Arc;
+
+use tk_auth_types::codes;
+
+use crate::i18n;
+use crate::jwt::base64url_encode;
+use crate::AppState;
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LEN: usize = 128;
+// The API description is for tools, which expect it as is.
+const UNWRAPPED_ROUTES: [&str; 1] = ["/api/openapi.json"];
+
+// The code of an error whose message varies, so it can't be looked up by
+// message. Set as a response extension by the handler.
+#[derive(Clone, Copy)]
+pub struct Code(pub &'static str);
+
+// Request ids that proxies in front already assigned are kept, so logs on
+// both sides can be matched up.
+fn request_id(state: &AppState, headers: &http::HeaderMap) -> String {
+    let given = headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            (1..=MAX_REQUEST_ID_LEN).contains(&id.len())
+                && id
+                    .bytes()
+                    .all(|byte| byte.is_ascii_alphanumeric() || b"-_.".contains(&byte))
+        });
+    match given {
+        Some(id) => id.to_string(),
+        None => {
+            let mut id = [0u8; 16];
+            state.rng.fill(&mut id);
+            base64url_encode(&id)
+        }
+    }
+}
+
+// Splices the body into the envelope rather than parsing it, since
+// successful responses are passed on unchanged.
+fn wrap(code: &str, message: &str, data: Option<&[u8]>, request_id: &str) -> Vec<u8> {
+    let mut out = serde_json::to_vec(&serde_json::json!({
+        "code": code,
+        "message": message,
+        "request_id": request_id,
+    }))
+    .unwrap();
+    out.pop();
+    out.extend_from_slice(b",\"data\":");
+    out.extend_from_slice(data.unwrap_or(b"null"));
+    out.push(b'}');
+    out
+}
+
+// An error body's message, and the body itself as data when it has more
+// than the message.
+fn error_parts(body: &[u8]) -> Option<(String, Option<&[u8]>)> {
+    let serde_json::Value::Object(object) = serde_json::from_slice(body).ok()? else {
+        return None;
+    };
+    let message = object.get("error")?.as_str()?.to_string();
+    Some((message, (object.len() > 1).then_some(body)))
+}
+
+// Wraps the JSON responses of the API in tk_auth_types::Envelope, and gives
+// every error one, whatever its body. The message is translated for the
+// request's Accept-Language; the code never is. Other successful responses, like
+// redirects, event streams and 304s, are left alone. All responses get the
+// request id in X-Request-Id.
+pub async fn layer(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let path = request.uri().path();
+    if !path.starts_with("/api/") || UNWRAPPED_ROUTES.contains(&path) {
+        return next.run(request).await;
+    }
+    let request_id = request_id(&state, request.headers());
+    let language = state.translations.negotiate(request.headers());
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert(
+        REQUEST_ID_HEADER,
+        http::HeaderValue::from_str(&request_id).unwrap(),
+    );
+    headers.append(
+        http::header::VARY,
+        http::HeaderValue::from_static("accept-language"),
+    );
+    let status = response.status();
+    let is_json = response
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+    if !is_json && !status.is_client_error() && !status.is_server_error() {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    // API responses are built in memory; this only collects them.
+    let Ok(body) = axum::body::to_bytes(body, usize::MAX).await else {
+        return crate::error_response(500, "failed to read the response");
+    };
+    let reason = status.canonical_reason().unwrap_or_default();
+    let (code, message, data) = if status.is_success() {
+        (codes::OK, reason.to_string(), Some(&body[..]))
+    } else {
+        let (message, data) = is_json
+            .then(|| error_parts(&body))
+            .flatten()
+            .unwrap_or_else(|| (reason.to_string(), None));
+        // Codes are looked up by the English message, before translation.
+        let code = parts
+            .extensions
+            .get::<Code>()
+            .map(|code| code.0)
+            .or_else(|| codes::for_error(&message))
+            .unwrap_or_else(|| codes::for_status(status.as_u16()));
+        (code, message, data)
+    };
+    let translated = match parts.extensions.get::<i18n::Message>() {
+        Some(message) => language.render(message),
+        None => language.t(&message).to_string(),
+    };
+    if translated != message {
+        parts.headers.insert(
+            http::header::CONTENT_LANGUAGE,
+            http::HeaderValue::from_str(language.tag()).unwrap(),
+        );
+    }
+    let wrapped = wrap(code, &translated, data, &request_id);
+    parts.headers.remove(http::header::CONTENT_LENGTH);
+    parts.headers.insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("application/json"),
+    );
+    axum::response::Response::from_parts(parts, axum::body::Body::from(wrapped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps_bodies_and_error_details() {
+        let envelope =
+            |bytes: Vec<u8>| -> serde_json::Value { serde_json::from_slice(&bytes).unwrap() };
+        assert_eq!(
+            envelope(wrap("ok", "OK", Some(br#"{"id_base64":"a"}"#), "r")),
+            serde_json::json!({
+                "code": "ok",
+                "message": "OK",
+                "data": { "id_base64": "a" },
+                "request_id": "r",
+            })
+        );
+
+        let (message, data) = error_parts(br#"{"error":"malformed session id"}"#).unwrap();
+        assert_eq!(
+            codes::for_error(&message),
+            Some(codes::MALFORMED_SESSION_ID)
+        );
+        assert_eq!(
+            envelope(wrap("c", &message, data, "r"))["data"],
+            serde_json::Value::Null
+        );
+
+        let challenge = br#"{"error":"proof of work required","difficulty_bits":8}"#;
+        let (_, data) = error_parts(challenge).unwrap();
+        assert_eq!(data, Some(&challenge[..]));
+        assert!(error_parts(b"length limit exceeded").is_none());
+    }
+}
@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+// Past this many tracked users and networks, new ones aren't counted until
+// old failures run out, rather than letting made-up user names grow the map.
+const MAX_TRACKED_FAILURES: usize = 100_000;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum FailureKey {
+    User(String),
+    Network(IpAddr),
+}
+
+impl FailureKey {
+    // IPv6 clients usually have a whole /64 to pick addresses from.
+    fn network(ip: IpAddr) -> Self {
+        match ip.to_canonical() {
+            IpAddr::V6(ip) => Self::Network(IpAddr::from(
+                (u128::from(ip) & (u128::MAX << 64)).to_be_bytes(),
+            )),
+            ip => Self::Network(ip),
+        }
+    }
+}
+
+struct Failures {
+    count: u32,
+    window_start: u64,
+}
+
+// Failed sign-ins per user and per network within a window of
+// `window_secs`, counted from the first failure.
+pub struct FailureCounts {
+    window_secs: u64,
+    failures: Mutex<HashMap<FailureKey, Failures>>,
+}
+
+impl FailureCounts {
+    pub fn new(window_secs: u64) -> Self {
+        Self {
+            window_secs,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.failures.lock().unwrap().len() >= MAX_TRACKED_FAILURES
+    }
+
+    // The higher of the user's and the network's recent failures. Without a
+    // user, only the network counts.
+    pub fn count(&self, user: Option<&str>, ip: IpAddr, now: u64) -> u32 {
+        let failures = self.failures.lock().unwrap();
+        let recent = |key: &FailureKey| {
+            failures
+                .get(key)
+                .filter(|failures| now < failures.window_start + self.window_secs)
+                .map_or(0, |failures| failures.count)
+        };
+        let user = user.map_or(0, |user| recent(&FailureKey::User(user.to_string())));
+        user.max(recent(&FailureKey::network(ip)))
+    }
+
+    // Seconds until the failures `count` sees have all run out.
+    pub fn reset_in(&self, user: Option<&str>, ip: IpAddr, now: u64) -> u64 {
+        let failures = self.failures.lock().unwrap();
+        let user = user.map(|user| FailureKey::User(user.to_string()));
+        user.into_iter()
+            .chain([FailureKey::network(ip)])
+            .filter_map(|key| failures.get(&key))
+            .map(|failures| (failures.window_start + self.window_secs).saturating_sub(now))
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Without a user, only the network's count goes up.
+    pub fn record(&self, user: Option<&str>, ip: IpAddr, now: u64) {
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= MAX_TRACKED_FAILURES {
+            failures.retain(|_, failures| now < failures.window_start + self.window_secs);
+        }
+        let user = user.map(|user| FailureKey::User(user.to_string()));
+        for key in user.into_iter().chain([FailureKey::network(ip)]) {
+            if failures.len() >= MAX_TRACKED_FAILURES && !failures.contains_key(&key) {
+                continue;
+            }
+            let entry = failures.entry(key).or_insert(Failures {
+                count: 0,
+                window_start: now,
+            });
+            if now >= entry.window_start + self.window_secs {
+                entry.count = 0;
+                entry.window_start = now;
+            }
+            entry.count += 1;
+        }
+    }
+
+    // Only the user's count is reset: signing in to one account mustn't
+    // clear the failures a network piled up against others.
+    pub fn clear_user(&self, user: &str) {
+        self.failures
+            .lock()
+            .unwrap()
+            .remove(&FailureKey::User(user.to_string()));
+    }
+}
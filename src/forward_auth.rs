@@ -0,0 +1,166 @@
+use std::sync::Arc;
+
+use crate::client_info::ClientInfo;
+use crate::config;
+use crate::{error_response, lookup_session, AppState, NOT_AUTHENTICATED};
+
+pub fn session_cookie(headers: &http::HeaderMap, cookie_name: &str) -> Option<String> {
+    headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == cookie_name)
+        .map(|(_, value)| value.trim_matches('"').to_string())
+}
+
+pub fn set_cookie_header(
+    forward_auth: &config::ForwardAuthConfig,
+    session_id: &str,
+) -> http::HeaderValue {
+    let mut cookie = format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Lax",
+        forward_auth.cookie_name, session_id
+    );
+    if let Some(domain) = &forward_auth.cookie_domain {
+        cookie.push_str("; Domain=");
+        cookie.push_str(domain);
+    }
+    if forward_auth.cookie_secure {
+        cookie.push_str("; Secure");
+    }
+    http::HeaderValue::from_str(&cookie).unwrap()
+}
+
+pub fn add_session_cookie(
+    state: &AppState,
+    response: &mut axum::response::Response,
+    session_id: &str,
+) {
+    if let Some(forward_auth) = &state.config.forward_auth {
+        response.headers_mut().append(
+            http::header::SET_COOKIE,
+            set_cookie_header(forward_auth, session_id),
+        );
+    }
+}
+
+fn original_url(headers: &http::HeaderMap) -> Option<String> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    if let Some(url) = header("x-original-url") {
+        return Some(url.to_string());
+    }
+    let host = header("x-forwarded-host")?;
+    let proto = header("x-forwarded-proto").unwrap_or("https");
+    let uri = header("x-forwarded-uri").unwrap_or("/");
+    Some(format!("{}://{}{}", proto, host, uri))
+}
+
+fn unauthenticated(
+    forward_auth: &config::ForwardAuthConfig,
+    headers: &http::HeaderMap,
+) -> axum::response::Response {
+    let Some(login_url) = &forward_auth.login_url else {
+        return NOT_AUTHENTICATED.response();
+    };
+    let location = match original_url(headers) {
+        Some(url) => {
+            let separator = if login_url.contains('?') { '&' } else { '?' };
+            let query: String = form_urlencoded::Serializer::new(String::new())
+                .append_pair("rd", &url)
+                .finish();
+            format!("{}{}{}", login_url, separator, query)
+        }
+        None => login_url.clone(),
+    };
+    axum::response::Response::builder()
+        .status(302)
+        .header(http::header::LOCATION, location)
+        .body(axum::body::Body::empty())
+        .unwrap()
+}
+
+pub async fn get_forward_auth(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    headers: http::HeaderMap,
+) -> axum::response::Response {
+    let Some(forward_auth) = &state.config.forward_auth else {
+        return error_response(404, "forward authentication is not enabled");
+    };
+    let Some(session_id) = session_cookie(&headers, &forward_auth.cookie_name) else {
+        return unauthenticated(forward_auth, &headers);
+    };
+    let session = match lookup_session(&state, &session_id, &client).await {
+        Ok((_, session)) => session,
+        Err(response) if response.status() == 403 => return response,
+        Err(_) => return unauthenticated(forward_auth, &headers),
+    };
+    let user = {
+        let session_locked = session.read().await;
+        match &session_locked.user {
+            Some(user) if session_locked.authenticated => user.clone(),
+            _ => return unauthenticated(forward_auth, &headers),
+        }
+    };
+    if !state.users.is_active(&user).await {
+        return error_response(403, "user is deactivated");
+    }
+    let roles = state
+        .users
+        .roles(&user)
+        .await
+        .into_iter()
+        .filter(|role| http::HeaderValue::from_str(role).is_ok() && !role.contains(','))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let Ok(user_header) = http::HeaderValue::from_str(&user) else {
+        println!("Can't forward user name {:?} in a header", user);
+        return error_response(500, "user name can't be forwarded");
+    };
+    axum::response::Response::builder()
+        .status(200)
+        .header("x-auth-user", user_header)
+        .header("x-auth-roles", roles)
+        .body(axum::body::Body::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_session_cookie_among_others() {
+        let mut headers = http::HeaderMap::new();
+        headers.append(http::header::COOKIE, "theme=dark".parse().unwrap());
+        headers.append(
+            http::header::COOKIE,
+            "a=1; tk_auth_session=\"abc-_\"; b=2".parse().unwrap(),
+        );
+        assert_eq!(
+            session_cookie(&headers, "tk_auth_session").as_deref(),
+            Some("abc-_")
+        );
+        assert_eq!(session_cookie(&headers, "tk_auth"), None);
+    }
+
+    #[test]
+    fn rebuilds_original_url_for_redirects() {
+        let mut headers = http::HeaderMap::new();
+        headers.insert("x-forwarded-host", "app.example.com".parse().unwrap());
+        headers.insert("x-forwarded-uri", "/a?b=c".parse().unwrap());
+        let forward_auth = config::ForwardAuthConfig {
+            login_url: Some(String::from("https://auth.example.com/login")),
+            ..Default::default()
+        };
+        let response = unauthenticated(&forward_auth, &headers);
+        assert_eq!(response.status(), 302);
+        assert_eq!(
+            response.headers()[http::header::LOCATION],
+            "https://auth.example.com/login?rd=https%3A%2F%2Fapp.example.com%2Fa%3Fb%3Dc"
+        );
+    }
+}
@@ -0,0 +1,489 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+
+use tk_auth_types::errors;
+
+use crate::audit;
+use crate::config;
+
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+const DATA_SECTION_SEPARATOR_BYTES: usize = 16;
+const MAX_DECODE_DEPTH: usize = 32;
+const MAX_COUNTRIES_PER_USER: usize = 64;
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+// A database in the MaxMind DB format, such as GeoLite2-Country or
+// GeoLite2-ASN, read into memory as a whole.
+pub struct Database {
+    data: Vec<u8>,
+    node_count: u32,
+    record_size: u32,
+    ip_version: u64,
+    data_start: usize,
+}
+
+impl Database {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Self::parse(std::fs::read(path)?).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), err),
+            )
+        })
+    }
+
+    fn parse(data: Vec<u8>) -> Result<Self, String> {
+        let marker = data
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .ok_or("not a MaxMind DB file")?;
+        let metadata_start = marker + METADATA_MARKER.len();
+        let (metadata, _) = Decoder {
+            data: &data[metadata_start..],
+        }
+        .decode(0, 0)
+        .ok_or("invalid metadata")?;
+        let field = |name: &str| {
+            metadata[name]
+                .as_u64()
+                .ok_or(format!("metadata lacks {}", name))
+        };
+        let node_count = u32::try_from(field("node_count")?).map_err(|_| "too many nodes")?;
+        let record_size = field("record_size")? as u32;
+        let ip_version = field("ip_version")?;
+        if ![24, 28, 32].contains(&record_size) || ![4, 6].contains(&ip_version) {
+            return Err(String::from("unsupported record size or IP version"));
+        }
+        let tree_bytes = node_count as usize * record_size as usize / 4;
+        let data_start = tree_bytes + DATA_SECTION_SEPARATOR_BYTES;
+        if data_start > marker {
+            return Err(String::from("search tree is larger than the file"));
+        }
+        Ok(Self {
+            data,
+            node_count,
+            record_size,
+            ip_version,
+            data_start,
+        })
+    }
+
+    fn record(&self, node: u32, right: bool) -> Option<u32> {
+        let node_bytes = self.record_size as usize / 4;
+        let bytes = self
+            .data
+            .get(node as usize * node_bytes..(node as usize + 1) * node_bytes)?;
+        let be = |bytes: &[u8]| bytes.iter().fold(0u32, |acc, byte| acc << 8 | *byte as u32);
+        Some(match (self.record_size, right) {
+            (24, false) => be(&bytes[..3]),
+            (24, true) => be(&bytes[3..]),
+            (28, false) => (bytes[3] as u32 & 0xf0) << 20 | be(&bytes[..3]),
+            (28, true) => (bytes[3] as u32 & 0x0f) << 24 | be(&bytes[4..]),
+            (_, false) => be(&bytes[..4]),
+            (_, true) => be(&bytes[4..]),
+        })
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<serde_json::Value> {
+        let bits: Vec<bool> = match (ip.to_canonical(), self.ip_version) {
+            (IpAddr::V4(ip), 4) => bits(&ip.octets()),
+            // IPv4 addresses live at ::a.b.c.d in IPv6 databases.
+            (IpAddr::V4(ip), _) => bits(&ip.to_ipv6_compatible().octets()),
+            (IpAddr::V6(ip), 6) => bits(&ip.octets()),
+            (IpAddr::V6(_), _) => return None,
+        };
+        let mut node = 0;
+        for bit in bits {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, bit)?;
+        }
+        if node <= self.node_count {
+            return None;
+        }
+        let offset = (node - self.node_count) as usize - DATA_SECTION_SEPARATOR_BYTES;
+        let section = self.data.get(self.data_start..)?;
+        Decoder { data: section }
+            .decode(offset, 0)
+            .map(|(value, _)| value)
+    }
+}
+
+fn bits(octets: &[u8]) -> Vec<bool> {
+    octets
+        .iter()
+        .flat_map(|octet| (0..8).rev().map(move |bit| octet >> bit & 1 == 1))
+        .collect()
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl Decoder<'_> {
+    fn bytes(&self, offset: usize, len: usize) -> Option<&[u8]> {
+        self.data.get(offset..offset.checked_add(len)?)
+    }
+
+    fn uint(&self, offset: usize, len: usize) -> Option<u64> {
+        if len > 8 {
+            // Only uint128 gets this big; the values used here never are.
+            return Some(u64::MAX);
+        }
+        let bytes = self.bytes(offset, len)?;
+        Some(bytes.iter().fold(0u64, |acc, byte| acc << 8 | *byte as u64))
+    }
+
+    // Returns the value at `offset` and the offset just past it.
+    fn decode(&self, offset: usize, depth: usize) -> Option<(serde_json::Value, usize)> {
+        if depth > MAX_DECODE_DEPTH {
+            return None;
+        }
+        let control = *self.data.get(offset)?;
+        let mut offset = offset + 1;
+        let mut kind = control >> 5;
+        if kind == 1 {
+            let size = (control >> 3) & 0x3;
+            let low = (control & 0x7) as usize;
+            let pointer = match size {
+                0 => low << 8 | self.uint(offset, 1)? as usize,
+                1 => (low << 16 | self.uint(offset, 2)? as usize) + 2048,
+                2 => (low << 24 | self.uint(offset, 3)? as usize) + 526_336,
+                _ => self.uint(offset, 4)? as usize,
+            };
+            let (value, _) = self.decode(pointer, depth + 1)?;
+            return Some((value, offset + size as usize + 1));
+        }
+        if kind == 0 {
+            kind = 7 + *self.data.get(offset)?;
+            offset += 1;
+        }
+        let mut size = (control & 0x1f) as usize;
+        if size >= 29 {
+            let extra = size - 28;
+            let value = self.uint(offset, extra)? as usize;
+            size = [29, 285, 65_821][extra - 1] + value;
+            offset += extra;
+        }
+        let value = match kind {
+            2 => serde_json::Value::from(std::str::from_utf8(self.bytes(offset, size)?).ok()?),
+            3 => {
+                serde_json::Value::from(f64::from_be_bytes(self.bytes(offset, 8)?.try_into().ok()?))
+            }
+            4 => serde_json::Value::from(self.bytes(offset, size)?.to_vec()),
+            5 | 6 | 9 | 10 => serde_json::Value::from(self.uint(offset, size)?),
+            8 => serde_json::Value::from(self.uint(offset, size)? as u32 as i32),
+            7 => {
+                let mut map = serde_json::Map::new();
+                for _ in 0..size {
+                    let (key, next) = self.decode(offset, depth + 1)?;
+                    let (value, next) = self.decode(next, depth + 1)?;
+                    map.insert(key.as_str()?.to_string(), value);
+                    offset = next;
+                }
+                return Some((serde_json::Value::Object(map), offset));
+            }
+            11 => {
+                let mut array = Vec::new();
+                for _ in 0..size {
+                    let (value, next) = self.decode(offset, depth + 1)?;
+                    array.push(value);
+                    offset = next;
+                }
+                return Some((serde_json::Value::Array(array), offset));
+            }
+            14 => return Some((serde_json::Value::from(size != 0), offset)),
+            15 => {
+                serde_json::Value::from(f32::from_be_bytes(self.bytes(offset, 4)?.try_into().ok()?))
+            }
+            _ => return None,
+        };
+        let len = match kind {
+            3 => 8,
+            15 => 4,
+            _ => size,
+        };
+        Some((value, offset + len))
+    }
+}
+
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct Location {
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+// Only City databases have these.
+#[derive(Clone, Copy)]
+struct Coordinates {
+    latitude: f64,
+    longitude: f64,
+    accuracy_radius_km: f64,
+}
+
+impl Coordinates {
+    fn distance_km(&self, other: &Coordinates) -> f64 {
+        let (lat1, lat2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let dlat = lat2 - lat1;
+        let dlon = (other.longitude - self.longitude).to_radians();
+        let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+    }
+}
+
+struct LastLogin {
+    coordinates: Coordinates,
+    country: Option<String>,
+    at: u64,
+}
+
+#[derive(Default)]
+struct History {
+    countries: BTreeSet<String>,
+    last_login: Option<LastLogin>,
+}
+
+// Put into the response of a refused sign-in, for the login page to tell
+// why.
+#[derive(Clone, Copy)]
+pub struct ImpossibleTravel;
+
+impl ImpossibleTravel {
+    pub fn response(self) -> axum::response::Response {
+        let mut response = crate::error_response(403, errors::IMPOSSIBLE_TRAVEL);
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+#[derive(Default)]
+pub struct GeoIp {
+    country: Option<Database>,
+    asn: Option<Database>,
+    impossible_travel: Option<config::ImpossibleTravelConfig>,
+    // What each user did since the server started.
+    history: Mutex<HashMap<String, History>>,
+}
+
+impl GeoIp {
+    pub fn new(config: Option<&config::GeoIpConfig>) -> io::Result<Self> {
+        let open = |path: Option<&Path>| path.map(Database::open).transpose();
+        Ok(Self {
+            country: open(config.and_then(|config| config.country_db_path.as_deref()))?,
+            asn: open(config.and_then(|config| config.asn_db_path.as_deref()))?,
+            impossible_travel: config.and_then(|config| config.impossible_travel.clone()),
+            history: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn locate(&self, ip: IpAddr) -> Location {
+        self.lookup(ip).0
+    }
+
+    fn lookup(&self, ip: IpAddr) -> (Location, Option<Coordinates>) {
+        let country = self.country.as_ref().and_then(|db| db.lookup(ip));
+        let asn = self.asn.as_ref().and_then(|db| db.lookup(ip));
+        let coordinates = country.as_ref().and_then(|record| {
+            let location = &record["location"];
+            Some(Coordinates {
+                latitude: location["latitude"].as_f64()?,
+                longitude: location["longitude"].as_f64()?,
+                accuracy_radius_km: location["accuracy_radius"].as_f64().unwrap_or(0.0),
+            })
+        });
+        let location = Location {
+            country: country.and_then(|record| {
+                let country = &record["country"]["iso_code"];
+                let country = match country {
+                    serde_json::Value::Null => &record["registered_country"]["iso_code"],
+                    _ => country,
+                };
+                country.as_str().map(str::to_string)
+            }),
+            asn: asn.and_then(|record| {
+                record["autonomous_system_number"]
+                    .as_u64()
+                    .and_then(|asn| u32::try_from(asn).ok())
+            }),
+        };
+        (location, coordinates)
+    }
+
+    // Locates a sign-in and audits it, flagging countries the user hasn't
+    // signed in from before. The first sign-in of a user isn't flagged,
+    // there's nothing to compare it with. Sign-ins too far from the user's
+    // last one to have travelled in between are refused if so configured,
+    // and don't count as the last one then.
+    pub fn login(&self, user: &str, ip: IpAddr, now: u64) -> Result<Location, ImpossibleTravel> {
+        if self.country.is_none() && self.asn.is_none() {
+            return Ok(Location::default());
+        }
+        let (location, coordinates) = self.lookup(ip);
+        let mut history = self.history.lock().unwrap();
+        let history = history.entry(user.to_string()).or_default();
+        if let (Some(config), Some(coordinates), Some(last_login)) =
+            (&self.impossible_travel, coordinates, &history.last_login)
+        {
+            // Either location may be anywhere within its accuracy radius,
+            // so only the distance beyond both counts.
+            let distance_km = (coordinates.distance_km(&last_login.coordinates)
+                - coordinates.accuracy_radius_km
+                - last_login.coordinates.accuracy_radius_km)
+                .max(0.0);
+            let elapsed_secs = now.saturating_sub(last_login.at);
+            let speed_kmh = distance_km * 3600.0 / elapsed_secs.max(1) as f64;
+            if speed_kmh > config.max_speed_kmh {
+                let denied = config.action == config::ImpossibleTravelAction::Deny;
+                audit::record(audit::AuditEvent::ImpossibleTravel {
+                    user,
+                    ip,
+                    country: location.country.as_deref(),
+                    previous_country: last_login.country.as_deref(),
+                    distance_km: distance_km.round() as u64,
+                    elapsed_secs,
+                    denied,
+                });
+                if denied {
+                    return Err(ImpossibleTravel);
+                }
+            }
+        }
+        if let Some(coordinates) = coordinates {
+            history.last_login = Some(LastLogin {
+                coordinates,
+                country: location.country.clone(),
+                at: now,
+            });
+        }
+        let new_country = location.country.as_ref().is_some_and(|country| {
+            let known = &mut history.countries;
+            let new_country = !known.is_empty() && !known.contains(country);
+            if known.len() < MAX_COUNTRIES_PER_USER {
+                known.insert(country.clone());
+            }
+            new_country
+        });
+        audit::record(audit::AuditEvent::Login {
+            user,
+            ip,
+            country: location.country.as_deref(),
+            asn: location.asn,
+            new_country,
+        });
+        Ok(location)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A map header for a map with `entries` entries, and a short string.
+    fn map(entries: u8) -> u8 {
+        7 << 5 | entries
+    }
+
+    fn string(value: &str) -> Vec<u8> {
+        let mut bytes = vec![2 << 5 | value.len() as u8];
+        bytes.extend_from_slice(value.as_bytes());
+        bytes
+    }
+
+    fn uint16(value: u16) -> Vec<u8> {
+        let mut bytes = vec![5 << 5 | 2];
+        bytes.extend_from_slice(&value.to_be_bytes());
+        bytes
+    }
+
+    fn double(value: f64) -> Vec<u8> {
+        let mut bytes = vec![3 << 5 | 8];
+        bytes.extend_from_slice(&value.to_be_bytes());
+        bytes
+    }
+
+    fn city(country: &str, latitude: f64, longitude: f64) -> Vec<u8> {
+        let mut record = vec![map(2)];
+        record.extend(string("country"));
+        record.push(map(1));
+        record.extend(string("iso_code"));
+        record.extend(string(country));
+        record.extend(string("location"));
+        record.push(map(3));
+        record.extend(string("latitude"));
+        record.extend(double(latitude));
+        record.extend(string("longitude"));
+        record.extend(double(longitude));
+        record.extend(string("accuracy_radius"));
+        record.extend(uint16(20));
+        record
+    }
+
+    // One node: 0.0.0.0/1 points at `low`, 128.0.0.0/1 at `high` if any.
+    fn database(low: Vec<u8>, high: Option<Vec<u8>>) -> Database {
+        let node_count = 1u32;
+        let pointer = |offset: usize| node_count + (DATA_SECTION_SEPARATOR_BYTES + offset) as u32;
+        let mut db = Vec::new();
+        db.extend_from_slice(&pointer(0).to_be_bytes()[1..]);
+        match &high {
+            Some(_) => db.extend_from_slice(&pointer(low.len()).to_be_bytes()[1..]),
+            None => db.extend_from_slice(&node_count.to_be_bytes()[1..]),
+        }
+        db.extend_from_slice(&[0; DATA_SECTION_SEPARATOR_BYTES]);
+        db.extend(low);
+        db.extend(high.unwrap_or_default());
+        db.extend_from_slice(METADATA_MARKER);
+        db.push(map(3));
+        db.extend(string("node_count"));
+        db.extend(uint16(1));
+        db.extend(string("record_size"));
+        db.extend(uint16(24));
+        db.extend(string("ip_version"));
+        db.extend(uint16(4));
+        Database::parse(db).unwrap()
+    }
+
+    #[test]
+    fn looks_up_addresses_in_a_maxmind_db() {
+        let mut record = vec![map(1)];
+        record.extend(string("country"));
+        record.push(map(1));
+        record.extend(string("iso_code"));
+        record.extend(string("DE"));
+        let geoip = GeoIp {
+            country: Some(database(record, None)),
+            ..GeoIp::default()
+        };
+        let location = geoip.lookup(IpAddr::from([10, 1, 2, 3])).0;
+        assert_eq!(location.country.as_deref(), Some("DE"));
+        assert_eq!(location.asn, None);
+        assert!(geoip
+            .lookup(IpAddr::from([192, 0, 2, 1]))
+            .0
+            .country
+            .is_none());
+        assert!(Database::parse(b"not a database".to_vec()).is_err());
+    }
+
+    #[test]
+    fn refuses_impossible_travel() {
+        let geoip = GeoIp {
+            country: Some(database(
+                city("DE", 52.52, 13.40),
+                Some(city("US", 40.71, -74.01)),
+            )),
+            impossible_travel: Some(config::ImpossibleTravelConfig::default()),
+            ..GeoIp::default()
+        };
+        let berlin = IpAddr::from([10, 0, 0, 1]);
+        let new_york = IpAddr::from([192, 0, 2, 1]);
+        assert!(geoip.login("zed", berlin, 1000).is_ok());
+        // About 6,400 km in an hour.
+        assert!(geoip.login("zed", new_york, 1000 + 3600).is_err());
+        assert!(geoip.login("zed", berlin, 1000 + 3600).is_ok());
+        assert!(geoip.login("zed", new_york, 1000 + 12 * 3600).is_ok());
+    }
+}
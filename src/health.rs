@@ -0,0 +1,217 @@
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use tk_auth_types::errors;
+
+use crate::time;
+use crate::{json_response, AppState};
+
+// A probe that takes longer counts as failed, so a disk that hangs is
+// noticed rather than waited on.
+const PROBE_TIMEOUT_SECS: u64 = 5;
+
+struct Failure {
+    since: u64,
+    error: String,
+}
+
+// Whether a storage backend is writable, as its last probe or write found.
+pub struct Backend {
+    name: &'static str,
+    failure: RwLock<Option<Failure>>,
+    failures: AtomicU64,
+}
+
+impl Backend {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            failure: RwLock::new(None),
+            failures: AtomicU64::new(0),
+        }
+    }
+
+    // The error it's failing with, if it is.
+    pub fn failure(&self) -> Option<String> {
+        self.failure
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|failure| failure.error.clone())
+    }
+
+    // Logs only when the backend starts or stops failing.
+    pub fn record(&self, result: Result<(), String>) {
+        let mut failure = self.failure.write().unwrap();
+        match (result, failure.is_some()) {
+            (Ok(()), true) => {
+                *failure = None;
+                println!("Storage backend {} recovered", self.name);
+            }
+            (Ok(()), false) => {}
+            (Err(error), failing) => {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                if !failing {
+                    println!("Storage backend {} is failing: {}", self.name, error);
+                    *failure = Some(Failure {
+                        since: time::now_secs(),
+                        error,
+                    });
+                }
+            }
+        }
+    }
+
+    pub fn report(&self) -> serde_json::Value {
+        let failure = self.failure.read().unwrap();
+        serde_json::json!({
+            "backend": self.name,
+            "healthy": failure.is_none(),
+            "failing_since": failure.as_ref().map(|failure| time::rfc3339(failure.since)),
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // Failed probes and writes since startup.
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+}
+
+// The backends other than users.path, whose state the user store keeps.
+pub struct Health {
+    pub sessions: Backend,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            sessions: Backend::new("sessions"),
+        }
+    }
+}
+
+// The configured backends.
+pub fn backends(state: &AppState) -> Vec<&Backend> {
+    let mut backends = Vec::new();
+    if state.config.users.path.is_some() {
+        backends.push(&state.users.health);
+    }
+    if state.config.sessions.persistence.is_some() {
+        backends.push(&state.health.sessions);
+    }
+    if let Some(memcached) = state.sessions.memcached() {
+        backends.push(&memcached.health);
+    }
+    backends
+}
+
+// Writes, syncs and removes a file next to `path`, on a blocking thread.
+async fn probe(path: &Path) -> Result<(), String> {
+    let mut probe_path = path.to_path_buf().into_os_string();
+    probe_path.push(".probe");
+    let probe_path = PathBuf::from(probe_path);
+    let probe = tokio::task::spawn_blocking(move || -> io::Result<()> {
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&probe_path)?;
+        file.write_all(b"tk-auth\n")?;
+        file.sync_all()?;
+        std::fs::remove_file(&probe_path)
+    });
+    match tokio::time::timeout(Duration::from_secs(PROBE_TIMEOUT_SECS), probe).await {
+        Ok(Ok(Ok(()))) => Ok(()),
+        Ok(Ok(Err(err))) => Err(format!("{}: {}", path.display(), err)),
+        Ok(Err(err)) => Err(format!("{}: {}", path.display(), err)),
+        Err(_) => Err(format!(
+            "{}: timed out after {} seconds",
+            path.display(),
+            PROBE_TIMEOUT_SECS
+        )),
+    }
+}
+
+pub fn spawn(state: Arc<AppState>) {
+    let config = &state.config;
+    let users = config.users.path.clone();
+    let sessions = config
+        .sessions
+        .persistence
+        .as_ref()
+        .and_then(|persistence| persistence.path(&config.users));
+    let memcached = state.sessions.memcached().cloned();
+    if users.is_none() && sessions.is_none() && memcached.is_none() {
+        return;
+    }
+    let interval = Duration::from_secs(config.health.probe_interval_secs);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Some(path) = &users {
+                state.users.health.record(probe(path).await);
+            }
+            if let Some(path) = &sessions {
+                state.health.sessions.record(probe(path).await);
+            }
+            if let Some(memcached) = &memcached {
+                memcached.health.record(memcached.probe().await);
+            }
+        }
+    });
+}
+
+// While a backend fails, sessions are still looked up from memory and
+// their changes queued, so the instance stays ready unless
+// health.unready_when_degraded says otherwise.
+pub async fn get_readyz(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+) -> axum::response::Response {
+    let backends = backends(&state);
+    let degraded = backends.iter().any(|backend| backend.failure().is_some());
+    let mut body = serde_json::json!({
+        "status": if degraded { "degraded" } else { "ready" },
+        "backends": backends.iter().map(|backend| backend.report()).collect::<Vec<_>>(),
+        "queued_session_changes": state.metrics.journal_queued.load(Ordering::Relaxed),
+    });
+    if degraded && state.config.health.unready_when_degraded {
+        body["error"] = serde_json::Value::from(errors::STORAGE_UNAVAILABLE);
+        return json_response(503, body);
+    }
+    json_response(200, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn probes_and_recovers() {
+        let dir = std::env::temp_dir().join(format!("tk-auth-health-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let backend = Backend::new("users");
+        backend.record(probe(&dir.join("users.json")).await);
+        assert!(backend.failure().is_none());
+        assert!(!dir.join("users.json.probe").exists());
+
+        backend.record(probe(&dir.join("missing").join("users.json")).await);
+        assert!(backend.failure().unwrap().contains("missing"));
+        assert_eq!(backend.report()["healthy"], false);
+        backend.record(Err(String::from("still failing")));
+        assert!(backend.failure().unwrap().contains("missing"));
+        assert_eq!(backend.failures(), 2);
+
+        backend.record(Ok(()));
+        assert!(backend.failure().is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use base64::Engine;
+use subtle::ConstantTimeEq;
+use tokio::sync::RwLock as TokioRwLock;
+use zeroize::Zeroizing;
+
+use crate::bcrypt;
+use crate::config;
+
+const APR1_MAGIC: &str = "$apr1$";
+const CRYPT_BASE64: &[u8; 64] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+const MD5_SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+const MD5_CONSTANTS: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+pub struct Htpasswd {
+    path: PathBuf,
+    users: TokioRwLock<BTreeMap<String, String>>,
+}
+
+impl Htpasswd {
+    pub fn open(config: &config::HtpasswdConfig) -> io::Result<Arc<Self>> {
+        let users = load(&config.path)?;
+        let htpasswd = Arc::new(Self {
+            path: config.path.clone(),
+            users: TokioRwLock::new(users),
+        });
+        if config.reload_secs > 0 {
+            tokio::spawn(watch(
+                htpasswd.clone(),
+                Duration::from_secs(config.reload_secs),
+            ));
+        }
+        Ok(htpasswd)
+    }
+
+    pub async fn verify(&self, user: &str, password: &str) -> Result<bool, String> {
+        let Some(hash) = self.users.read().await.get(user).cloned() else {
+            return Ok(false);
+        };
+        let password = Zeroizing::new(password.to_string());
+        tokio::task::spawn_blocking(move || verify_hash(password.as_bytes(), &hash))
+            .await
+            .map_err(|err| err.to_string())
+    }
+}
+
+fn load(path: &Path) -> io::Result<BTreeMap<String, String>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+    let mut users = BTreeMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((user, hash)) = line.split_once(':') else {
+            println!("{}:{}: ignoring malformed line", path.display(), i + 1);
+            continue;
+        };
+        if !bcrypt::is_hash(hash) && !hash.starts_with(APR1_MAGIC) && !hash.starts_with("{SHA}") {
+            println!(
+                "{}:{}: ignoring {}, unsupported hash format",
+                path.display(),
+                i + 1,
+                user
+            );
+            continue;
+        }
+        users.insert(user.to_string(), hash.to_string());
+    }
+    Ok(users)
+}
+
+async fn watch(htpasswd: Arc<Htpasswd>, interval: Duration) {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|metadata| metadata.modified());
+    let mut last_modified: Option<SystemTime> = modified(&htpasswd.path).ok();
+    loop {
+        tokio::time::sleep(interval).await;
+        let Ok(current) = modified(&htpasswd.path) else {
+            continue;
+        };
+        if last_modified == Some(current) {
+            continue;
+        }
+        last_modified = Some(current);
+        match load(&htpasswd.path) {
+            Ok(users) => {
+                println!(
+                    "Reloaded {} ({} users)",
+                    htpasswd.path.display(),
+                    users.len()
+                );
+                *htpasswd.users.write().await = users;
+            }
+            Err(err) => println!("Failed to reload htpasswd file: {}", err),
+        }
+    }
+}
+
+pub fn verify_hash(password: &[u8], hash: &str) -> bool {
+    if bcrypt::is_hash(hash) {
+        bcrypt::verify(password, hash)
+    } else if let Some(rest) = hash.strip_prefix(APR1_MAGIC) {
+        let salt = rest.split('$').next().unwrap_or_default();
+        let expected = apr1(password, salt.as_bytes());
+        expected.as_bytes().ct_eq(hash.as_bytes()).into()
+    } else if let Some(expected) = hash.strip_prefix("{SHA}") {
+        let digest = ring::digest::digest(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY, password);
+        let encoded = base64::engine::general_purpose::STANDARD.encode(digest.as_ref());
+        encoded.as_bytes().ct_eq(expected.as_bytes()).into()
+    } else {
+        false
+    }
+}
+
+fn apr1(password: &[u8], salt: &[u8]) -> String {
+    let salt = &salt[..salt.len().min(8)];
+
+    let mut alternate = Md5::new();
+    alternate.update(password);
+    alternate.update(salt);
+    alternate.update(password);
+    let alternate = alternate.finish();
+
+    let mut context = Md5::new();
+    context.update(password);
+    context.update(APR1_MAGIC.as_bytes());
+    context.update(salt);
+    let mut remaining = password.len();
+    while remaining > 0 {
+        context.update(&alternate[..remaining.min(16)]);
+        remaining = remaining.saturating_sub(16);
+    }
+    let mut i = password.len();
+    while i > 0 {
+        if i & 1 == 1 {
+            context.update(&[0]);
+        } else {
+            context.update(&password[..1]);
+        }
+        i >>= 1;
+    }
+    let mut digest = context.finish();
+
+    for round in 0..1000 {
+        let mut context = Md5::new();
+        if round & 1 == 1 {
+            context.update(password);
+        } else {
+            context.update(&digest);
+        }
+        if round % 3 != 0 {
+            context.update(salt);
+        }
+        if round % 7 != 0 {
+            context.update(password);
+        }
+        if round & 1 == 1 {
+            context.update(&digest);
+        } else {
+            context.update(password);
+        }
+        digest = context.finish();
+    }
+
+    let mut encoded = format!("{}{}$", APR1_MAGIC, String::from_utf8_lossy(salt));
+    let groups = [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)];
+    for (a, b, c) in groups {
+        let value =
+            (u32::from(digest[a]) << 16) | (u32::from(digest[b]) << 8) | u32::from(digest[c]);
+        push_crypt_base64(&mut encoded, value, 4);
+    }
+    push_crypt_base64(&mut encoded, u32::from(digest[11]), 2);
+    encoded
+}
+
+fn push_crypt_base64(out: &mut String, mut value: u32, chars: usize) {
+    for _ in 0..chars {
+        out.push(char::from(CRYPT_BASE64[(value & 0x3f) as usize]));
+        value >>= 6;
+    }
+}
+
+struct Md5 {
+    state: [u32; 4],
+    buffer: Vec<u8>,
+    len: u64,
+}
+
+impl Md5 {
+    fn new() -> Self {
+        Self {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476],
+            buffer: Vec::with_capacity(64),
+            len: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        while !data.is_empty() {
+            let take = (64 - self.buffer.len()).min(data.len());
+            self.buffer.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.buffer.len() == 64 {
+                let block: [u8; 64] = self.buffer[..].try_into().unwrap();
+                self.compress(&block);
+                self.buffer.clear();
+            }
+        }
+    }
+
+    fn finish(mut self) -> [u8; 16] {
+        let bit_len = self.len.wrapping_mul(8);
+        self.update(&[0x80]);
+        while self.buffer.len() != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bit_len.to_le_bytes());
+        let mut digest = [0u8; 16];
+        for (chunk, word) in digest.chunks_mut(4).zip(self.state) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self, block: &[u8; 64]) {
+        let words: Vec<u32> = block
+            .chunks(4)
+            .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = self.state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(MD5_CONSTANTS[i])
+                .wrapping_add(words[g])
+                .rotate_left(MD5_SHIFTS[i / 16 * 4 + i % 4]);
+            (a, b, c, d) = (d, b.wrapping_add(rotated), b, c);
+        }
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_bcrypt_hashes() {
+        let hashes = [
+            (
+                "U*U",
+                "$2b$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW",
+            ),
+            (
+                "correct horse",
+                "$2y$04$abcdefghijklmnopqrstuujydOTSfIH/d5oUHpsygqV5X9xJLQc6e",
+            ),
+            (
+                "",
+                "$2a$04$......................w74bL5gU7LSJClZClCa.Pkz14aTv/XO",
+            ),
+        ];
+        for (password, hash) in hashes {
+            assert!(verify_hash(password.as_bytes(), hash), "{}", hash);
+            assert!(!verify_hash(b"wrong", hash), "{}", hash);
+        }
+    }
+
+    #[test]
+    fn bcrypt_uses_only_the_first_72_bytes() {
+        let hash = "$2b$04$0123456789ABCDEFGHIJKuLYxWbTZrA9pRJwWQhpUTe0V4dxPp6gW";
+        assert!(verify_hash(&[b'x'; 80], hash));
+        assert!(verify_hash(&[b'x'; 72], hash));
+        assert!(!verify_hash(&[b'x'; 71], hash));
+    }
+
+    #[test]
+    fn verifies_apr1_hashes() {
+        let hashes = [
+            ("", "$apr1$sAlT1234$mNy/S/SJj/NCjGtJyaoNn1"),
+            ("a", "$apr1$sAlT1234$t.GBJlDeeN3dNQ91oFo0G1"),
+            ("hunter2", "$apr1$sAlT1234$EUBqB9L/ejufQD8VOcfvE0"),
+            ("ppppppppppppppppp", "$apr1$sAlT1234$B4kG1jNu7w9m0JN9fd8tK."),
+            ("zażółć", "$apr1$sAlT1234$NmrWyZAXv3H7N3hcJnwb/0"),
+        ];
+        for (password, hash) in hashes {
+            assert!(verify_hash(password.as_bytes(), hash), "{}", hash);
+            assert!(!verify_hash(b"wrong", hash), "{}", hash);
+        }
+        let long = "q".repeat(64);
+        assert!(verify_hash(
+            long.as_bytes(),
+            "$apr1$sAlT1234$wFmfv.ursKlEtenCQnhdv."
+        ));
+    }
+
+    #[test]
+    fn verifies_sha1_hashes() {
+        assert!(verify_hash(b"hunter2", "{SHA}87u9ZqY9S/F0eUBXjsPQEDUw4h0="));
+        assert!(!verify_hash(
+            b"hunter3",
+            "{SHA}87u9ZqY9S/F0eUBXjsPQEDUw4h0="
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_hashes() {
+        for hash in [
+            "$2b$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOe",
+            "$2b$03$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW",
+            "$2x$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW",
+            "plaintext",
+            "",
+        ] {
+            assert!(!verify_hash(b"U*U", hash), "{}", hash);
+        }
+    }
+}
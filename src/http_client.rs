@@ -0,0 +1,223 @@
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+
+const MAX_RESPONSE_BYTES: usize = 1024 * 1024;
+const MAX_RESPONSE_HEADERS: usize = 64;
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub fn root_certificates(ca_path: &Path) -> io::Result<tokio_rustls::rustls::RootCertStore> {
+    let mut roots = tokio_rustls::rustls::RootCertStore::empty();
+    let certs = tokio_rustls::rustls::pki_types::CertificateDer::pem_file_iter(ca_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| io::Error::other(format!("{}: {}", ca_path.display(), err)))?;
+    roots.add_parsable_certificates(certs);
+    if roots.is_empty() {
+        return Err(io::Error::other(format!(
+            "{}: no usable CA certificates",
+            ca_path.display()
+        )));
+    }
+    Ok(roots)
+}
+
+#[derive(Clone)]
+pub struct Client {
+    tls: tokio_rustls::TlsConnector,
+}
+
+impl Client {
+    pub fn new(ca_path: &Path) -> io::Result<Self> {
+        let mut client_config = tokio_rustls::rustls::ClientConfig::builder()
+            .with_root_certificates(root_certificates(ca_path)?)
+            .with_no_client_auth();
+        client_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Self {
+            tls: tokio_rustls::TlsConnector::from(Arc::new(client_config)),
+        })
+    }
+
+    pub async fn request(
+        &self,
+        method: &str,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<Response> {
+        tokio::time::timeout(
+            Duration::from_secs(REQUEST_TIMEOUT_SECS),
+            self.request_inner(method, url, headers, body),
+        )
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("{}: timed out", url)))?
+    }
+
+    async fn request_inner(
+        &self,
+        method: &str,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<Response> {
+        let uri: http::Uri = url.parse().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("invalid URL {}", url))
+        })?;
+        let https = match uri.scheme_str() {
+            Some("https") => true,
+            Some("http") => false,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unsupported URL scheme in {}", url),
+                ))
+            }
+        };
+        let host = uri.host().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("no host in {}", url))
+        })?;
+        let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+        let authority = uri
+            .authority()
+            .map(|authority| authority.as_str())
+            .unwrap_or(host);
+        let path = uri
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or("/");
+
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+            method,
+            path,
+            authority,
+            body.len()
+        );
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+
+        let stream = tokio::net::TcpStream::connect((host.trim_matches(['[', ']']), port)).await?;
+        if https {
+            let server_name = tokio_rustls::rustls::pki_types::ServerName::try_from(
+                host.trim_matches(['[', ']']).to_string(),
+            )
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            let stream = self.tls.connect(server_name, stream).await?;
+            exchange(stream, request.as_bytes(), body).await
+        } else {
+            exchange(stream, request.as_bytes(), body).await
+        }
+    }
+}
+
+async fn exchange<S>(mut stream: S, head: &[u8], body: &[u8]) -> io::Result<Response>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(head).await?;
+    stream.write_all(body).await?;
+    stream.flush().await?;
+
+    let mut raw = Vec::new();
+    (&mut stream)
+        .take(MAX_RESPONSE_BYTES as u64 + 1)
+        .read_to_end(&mut raw)
+        .await
+        .or_else(|err| {
+            // Some servers close TLS connections without close_notify once the
+            // response is complete.
+            if err.kind() == io::ErrorKind::UnexpectedEof && !raw.is_empty() {
+                Ok(0)
+            } else {
+                Err(err)
+            }
+        })?;
+    if raw.len() > MAX_RESPONSE_BYTES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "response too large",
+        ));
+    }
+    parse_response(&raw)
+}
+
+fn parse_response(raw: &[u8]) -> io::Result<Response> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let mut headers = [httparse::EMPTY_HEADER; MAX_RESPONSE_HEADERS];
+    let mut parsed = httparse::Response::new(&mut headers);
+    let head_len = match parsed.parse(raw) {
+        Ok(httparse::Status::Complete(head_len)) => head_len,
+        Ok(httparse::Status::Partial) => return Err(invalid("truncated response")),
+        Err(err) => return Err(invalid(&err.to_string())),
+    };
+    let status = parsed.code.unwrap_or(0);
+    let headers: Vec<(String, String)> = parsed
+        .headers
+        .iter()
+        .map(|header| {
+            (
+                header.name.to_string(),
+                String::from_utf8_lossy(header.value).into_owned(),
+            )
+        })
+        .collect();
+    let mut response = Response {
+        status,
+        headers,
+        body: Vec::new(),
+    };
+    let rest = &raw[head_len..];
+    response.body = if response
+        .header("transfer-encoding")
+        .is_some_and(|value| value.eq_ignore_ascii_case("chunked"))
+    {
+        decode_chunked(rest).ok_or_else(|| invalid("malformed chunked body"))?
+    } else if let Some(length) = response.header("content-length") {
+        let length: usize = length
+            .trim()
+            .parse()
+            .map_err(|_| invalid("invalid Content-Length"))?;
+        rest.get(..length)
+            .ok_or_else(|| invalid("truncated body"))?
+            .to_vec()
+    } else {
+        rest.to_vec()
+    };
+    Ok(response)
+}
+
+fn decode_chunked(mut raw: &[u8]) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = raw.windows(2).position(|window| window == b"\r\n")?;
+        let size_line = std::str::from_utf8(&raw[..line_end]).ok()?;
+        let size_hex = size_line.split(';').next()?.trim();
+        let size = usize::from_str_radix(size_hex, 16).ok()?;
+        raw = &raw[line_end + 2..];
+        if size == 0 {
+            return Some(body);
+        }
+        body.extend_from_slice(raw.get(..size)?);
+        raw = raw.get(size..)?.strip_prefix(b"\r\n")?;
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::io;
+
+use crate::config;
+
+const MAX_ACCEPTED_LANGUAGES: usize = 16;
+
+// Messages are looked up by their English text, gettext style, so English
+// needs no bundle and anything without a translation stays English.
+struct Bundle {
+    tag: String,
+    messages: HashMap<String, String>,
+}
+
+// A message with values in it, such as "no device {id}", translated as a
+// whole before the values are filled in. Set as a response extension next
+// to the English rendering in the body.
+#[derive(Clone)]
+pub struct Message {
+    pub template: &'static str,
+    pub args: Vec<(&'static str, String)>,
+}
+
+impl Message {
+    pub fn english(&self) -> String {
+        Language { bundle: None }.render(self)
+    }
+}
+
+pub struct Translations {
+    // By lowercased language tag.
+    bundles: HashMap<String, Bundle>,
+}
+
+#[derive(Clone, Copy)]
+pub struct Language<'a> {
+    bundle: Option<&'a Bundle>,
+}
+
+impl Language<'_> {
+    pub fn tag(&self) -> &str {
+        self.bundle.map_or("en", |bundle| &bundle.tag)
+    }
+
+    pub fn t<'a>(&'a self, message: &'a str) -> &'a str {
+        self.bundle
+            .and_then(|bundle| bundle.messages.get(message))
+            .map_or(message, String::as_str)
+    }
+
+    pub fn render(&self, message: &Message) -> String {
+        message.args.iter().fold(
+            self.t(message.template).to_string(),
+            |text, (name, value)| text.replace(&format!("{{{}}}", name), value),
+        )
+    }
+}
+
+impl Translations {
+    // Every <language tag>.json in i18n.bundles_dir is a bundle, such as
+    // de.json or pt-BR.json, holding an object from English messages to
+    // their translations.
+    pub fn load(config: &config::I18nConfig) -> io::Result<Self> {
+        let mut bundles = HashMap::new();
+        let Some(dir) = &config.bundles_dir else {
+            return Ok(Self { bundles });
+        };
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let Some(tag) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let messages = serde_json::from_slice(&std::fs::read(&path)?).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: {}", path.display(), err),
+                )
+            })?;
+            bundles.insert(
+                tag.to_ascii_lowercase(),
+                Bundle {
+                    tag: tag.to_string(),
+                    messages,
+                },
+            );
+        }
+        let mut tags: Vec<_> = bundles.values().map(|bundle| bundle.tag.as_str()).collect();
+        tags.sort();
+        println!("Loaded translations for {}", tags.join(", "));
+        Ok(Self { bundles })
+    }
+
+    pub fn negotiate(&self, headers: &http::HeaderMap) -> Language<'_> {
+        let accept_language = headers
+            .get(http::header::ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        for tag in preferred_languages(accept_language) {
+            let tag = tag.to_ascii_lowercase();
+            if tag == "en" || tag.starts_with("en-") {
+                break;
+            }
+            let primary = tag.split('-').next().unwrap_or_default();
+            if let Some(bundle) = self.bundles.get(&tag).or_else(|| self.bundles.get(primary)) {
+                return Language {
+                    bundle: Some(bundle),
+                };
+            }
+        }
+        Language { bundle: None }
+    }
+}
+
+// Tags in order of preference, without the ones refused with q=0.
+fn preferred_languages(accept_language: &str) -> Vec<&str> {
+    let mut languages: Vec<(&str, f32)> = accept_language
+        .split(',')
+        .take(MAX_ACCEPTED_LANGUAGES)
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let tag = parts.next()?.trim();
+            let quality = match parts.find_map(|part| part.trim().strip_prefix("q=")) {
+                Some(quality) => quality.parse().ok()?,
+                None => 1.0,
+            };
+            (!tag.is_empty() && tag != "*" && quality > 0.0).then_some((tag, quality))
+        })
+        .collect();
+    languages.sort_by(|a, b| b.1.total_cmp(&a.1));
+    languages.into_iter().map(|(tag, _)| tag).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_from_accept_language() {
+        let bundle = |tag: &str, message: &str| Bundle {
+            tag: tag.to_string(),
+            messages: HashMap::from([(String::from("Sign in"), message.to_string())]),
+        };
+        let translations = Translations {
+            bundles: HashMap::from([
+                (String::from("de"), bundle("de", "Anmelden")),
+                (String::from("pt-br"), bundle("pt-BR", "Entrar")),
+            ]),
+        };
+        let negotiate = |accept_language: &str| {
+            let mut headers = http::HeaderMap::new();
+            headers.insert(
+                http::header::ACCEPT_LANGUAGE,
+                accept_language.parse().unwrap(),
+            );
+            let language = translations.negotiate(&headers);
+            (
+                language.tag().to_string(),
+                language.t("Sign in").to_string(),
+            )
+        };
+        assert_eq!(
+            negotiate("de-AT,de;q=0.9"),
+            ("de".into(), "Anmelden".into())
+        );
+        assert_eq!(
+            negotiate("fr, pt-BR;q=0.5"),
+            ("pt-BR".into(), "Entrar".into())
+        );
+        assert_eq!(
+            negotiate("en-US, de;q=0.8"),
+            ("en".into(), "Sign in".into())
+        );
+        assert_eq!(negotiate("de;q=0, *"), ("en".into(), "Sign in".into()));
+        assert_eq!(
+            negotiate("fr;q=0.2, de;q=0.7"),
+            ("de".into(), "Anmelden".into())
+        );
+        assert_eq!(
+            translations.negotiate(&http::HeaderMap::new()).t("User"),
+            "User"
+        );
+
+        let message = Message {
+            template: "no device {id}",
+            args: vec![("id", String::from("x"))],
+        };
+        assert_eq!(message.english(), "no device x");
+    }
+}
@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::client_info::ClientInfo;
+use crate::config;
+use crate::envelope;
+use crate::forward_auth;
+use crate::i18n;
+use crate::{error_response, AppState};
+
+// The routes that honor the header; the rest of the API ignores it.
+pub const ROUTES: [&str; 3] = [
+    "/api/new_session",
+    "/api/authenticate",
+    "/api/session/resume",
+];
+const HEADER: &str = "idempotency-key";
+const MAX_KEY_LEN: usize = 255;
+// Larger responses are passed on without being remembered. Those of the
+// routes above are far smaller.
+const MAX_STORED_BODY_BYTES: u64 = 16 * 1024;
+
+struct Stored {
+    status: http::StatusCode,
+    headers: http::HeaderMap,
+    code: Option<envelope::Code>,
+    message: Option<i18n::Message>,
+    body: axum::body::Bytes,
+}
+
+struct Entry {
+    // SHA-256 of the method, URI and body of the first request.
+    fingerprint: Vec<u8>,
+    created_at: u64,
+    // None while the first request is still being handled.
+    response: Option<Stored>,
+}
+
+enum Begin {
+    Handle,
+    Replay(axum::response::Response),
+    InProgress,
+    Mismatch,
+    Full,
+}
+
+// Keys are scoped to the client address and credentials, so a key seen from
+// one client can't be used to fetch another client's response, not even
+// from behind the same NAT. They live in this instance's memory only.
+#[derive(Default)]
+pub struct Idempotency {
+    entries: Mutex<HashMap<Key, Entry>>,
+}
+
+// The client address, a digest of the credentials the request came with,
+// and the Idempotency-Key.
+type Key = (IpAddr, Vec<u8>, String);
+
+impl Idempotency {
+    fn begin(
+        &self,
+        config: &config::IdempotencyConfig,
+        key: &Key,
+        fingerprint: &[u8],
+        now: u64,
+    ) -> Begin {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) if entry.created_at + config.window_secs > now => {
+                return match &entry.response {
+                    _ if entry.fingerprint != fingerprint => Begin::Mismatch,
+                    Some(stored) => Begin::Replay(stored.replay()),
+                    None => Begin::InProgress,
+                };
+            }
+            _ => {}
+        }
+        if entries.len() >= config.max_keys {
+            entries.retain(|_, entry| entry.created_at + config.window_secs > now);
+            if entries.len() >= config.max_keys {
+                return Begin::Full;
+            }
+        }
+        entries.insert(
+            key.clone(),
+            Entry {
+                fingerprint: fingerprint.to_vec(),
+                created_at: now,
+                response: None,
+            },
+        );
+        Begin::Handle
+    }
+
+    // Remembers the response of a handled request, or forgets the key
+    // without one so that the request can be tried again.
+    fn finish(&self, key: &Key, response: Option<Stored>) {
+        let mut entries = self.entries.lock().unwrap();
+        match response {
+            Some(response) => {
+                if let Some(entry) = entries.get_mut(key) {
+                    entry.response = Some(response);
+                }
+            }
+            None => {
+                if entries
+                    .get(key)
+                    .is_some_and(|entry| entry.response.is_none())
+                {
+                    entries.remove(key);
+                }
+            }
+        }
+    }
+}
+
+impl Stored {
+    fn replay(&self) -> axum::response::Response {
+        let mut response = axum::response::Response::new(axum::body::Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        if let Some(code) = self.code {
+            response.extensions_mut().insert(code);
+        }
+        if let Some(message) = &self.message {
+            response.extensions_mut().insert(message.clone());
+        }
+        response.headers_mut().insert(
+            "idempotent-replayed",
+            http::HeaderValue::from_static("true"),
+        );
+        response
+    }
+}
+
+// Forgets the key when the request doesn't finish, e.g. because the client
+// went away, so a retry isn't answered with 409 until the window ends.
+struct Pending<'a> {
+    idempotency: &'a Idempotency,
+    key: Option<Key>,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.idempotency.finish(&key, None);
+        }
+    }
+}
+
+// The session cookie and Authorization header, which by now holds the
+// bearer token or the key request_signing verified the request as signed by.
+fn credentials(headers: &http::HeaderMap, cookie_name: Option<&str>) -> Vec<u8> {
+    let mut digest = ring::digest::Context::new(&ring::digest::SHA256);
+    if let Some(authorization) = headers.get(http::header::AUTHORIZATION) {
+        digest.update(authorization.as_bytes());
+    }
+    digest.update(b"\n");
+    if let Some(cookie) =
+        cookie_name.and_then(|cookie_name| forward_auth::session_cookie(headers, cookie_name))
+    {
+        digest.update(cookie.as_bytes());
+    }
+    digest.finish().as_ref().to_vec()
+}
+
+fn fingerprint(parts: &http::request::Parts, body: &[u8]) -> Vec<u8> {
+    let mut digest = ring::digest::Context::new(&ring::digest::SHA256);
+    digest.update(parts.method.as_str().as_bytes());
+    digest.update(b" ");
+    digest.update(parts.uri.to_string().as_bytes());
+    digest.update(b"\n");
+    digest.update(body);
+    digest.finish().as_ref().to_vec()
+}
+
+// Answers a request repeated with the same Idempotency-Key with the response
+// to the first one. Server errors and 429s aren't remembered, since trying
+// again may succeed.
+pub async fn layer(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let (Some(config), Some(key)) = (&state.config.idempotency, request.headers().get(HEADER))
+    else {
+        return next.run(request).await;
+    };
+    let key = match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LEN => (
+            client.ip,
+            credentials(
+                request.headers(),
+                state
+                    .config
+                    .forward_auth
+                    .as_ref()
+                    .map(|forward_auth| forward_auth.cookie_name.as_str()),
+            ),
+            key.to_string(),
+        ),
+        _ => {
+            return error_response(
+                400,
+                "Idempotency-Key must be 1 to 255 visible ASCII characters",
+            )
+        }
+    };
+    let (parts, body) = request.into_parts();
+    // The route's body limit applies here already.
+    let Ok(body) = axum::body::to_bytes(body, usize::MAX).await else {
+        return error_response(413, "the request body is too large");
+    };
+    let now = state.clock.now_secs();
+    match state
+        .idempotency
+        .begin(config, &key, &fingerprint(&parts, &body), now)
+    {
+        Begin::Handle => {}
+        Begin::Replay(response) => return response,
+        Begin::InProgress => {
+            return error_response(409, "a request with this Idempotency-Key is in progress")
+        }
+        Begin::Mismatch => {
+            return error_response(422, "this Idempotency-Key was used for a different request")
+        }
+        Begin::Full => {
+            println!("Too many idempotency keys, handling a request without one");
+            let request = axum::extract::Request::from_parts(parts, axum::body::Body::from(body));
+            return next.run(request).await;
+        }
+    }
+    let mut pending = Pending {
+        idempotency: &state.idempotency,
+        key: Some(key),
+    };
+    let request = axum::extract::Request::from_parts(parts, axum::body::Body::from(body));
+    let response = next.run(request).await;
+    let status = response.status();
+    let small = axum::body::HttpBody::size_hint(response.body())
+        .exact()
+        .is_some_and(|len| len <= MAX_STORED_BODY_BYTES);
+    if status.is_server_error() || status == http::StatusCode::TOO_MANY_REQUESTS || !small {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let Ok(body) = axum::body::to_bytes(body, MAX_STORED_BODY_BYTES as usize).await else {
+        return error_response(500, "failed to read the response");
+    };
+    let key = pending.key.take().unwrap();
+    state.idempotency.finish(
+        &key,
+        Some(Stored {
+            status,
+            headers: parts.headers.clone(),
+            code: parts.extensions.get().copied(),
+            message: parts.extensions.get().cloned(),
+            body: body.clone(),
+        }),
+    );
+    axum::response::Response::from_parts(parts, axum::body::Body::from(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replays_responses_per_client_and_key() {
+        let config = config::IdempotencyConfig {
+            window_secs: 100,
+            max_keys: 2,
+        };
+        let idempotency = Idempotency::default();
+        let key = |ip: &str, key: &str| (ip.parse().unwrap(), Vec::new(), key.to_string());
+        let stored = || Stored {
+            status: http::StatusCode::OK,
+            headers: http::HeaderMap::new(),
+            code: None,
+            message: None,
+            body: axum::body::Bytes::from_static(b"{}"),
+        };
+        let begin =
+            |key, fingerprint: &[u8], now| match idempotency.begin(&config, &key, fingerprint, now)
+            {
+                Begin::Handle => "handle",
+                Begin::Replay(_) => "replay",
+                Begin::InProgress => "in progress",
+                Begin::Mismatch => "mismatch",
+                Begin::Full => "full",
+            };
+
+        assert_eq!(begin(key("192.0.2.1", "a"), b"1", 0), "handle");
+        assert_eq!(begin(key("192.0.2.1", "a"), b"1", 0), "in progress");
+        idempotency.finish(&key("192.0.2.1", "a"), Some(stored()));
+        assert_eq!(begin(key("192.0.2.1", "a"), b"1", 10), "replay");
+        assert_eq!(begin(key("192.0.2.1", "a"), b"2", 10), "mismatch");
+        assert_eq!(begin(key("192.0.2.2", "a"), b"1", 10), "handle");
+        assert_eq!(begin(key("192.0.2.2", "b"), b"1", 10), "full");
+
+        // Unfinished requests can be tried again.
+        idempotency.finish(&key("192.0.2.2", "a"), None);
+        assert_eq!(begin(key("192.0.2.2", "b"), b"1", 10), "handle");
+
+        // Expired keys make room and are handled anew.
+        assert_eq!(begin(key("192.0.2.1", "a"), b"2", 100), "handle");
+    }
+
+    #[test]
+    fn scopes_keys_to_credentials() {
+        let credentials = |header: http::HeaderName, value: &str| {
+            let mut headers = http::HeaderMap::new();
+            headers.insert(header, http::HeaderValue::from_str(value).unwrap());
+            credentials(&headers, Some("tk-session"))
+        };
+        let alice = credentials(http::header::COOKIE, "tk-session=alice; theme=dark");
+        let bob = credentials(http::header::COOKIE, "tk-session=bob; theme=dark");
+        assert_ne!(alice, bob);
+        assert_eq!(alice, credentials(http::header::COOKIE, "tk-session=alice"));
+        assert_ne!(
+            credentials(http::header::AUTHORIZATION, "Bearer alice"),
+            credentials(http::header::AUTHORIZATION, "Bearer bob")
+        );
+
+        // Two clients behind the same address, using the same key.
+        let idempotency = Idempotency::default();
+        let config = config::IdempotencyConfig {
+            window_secs: 100,
+            max_keys: 10,
+        };
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let alice = (ip, alice, String::from("a"));
+        let bob = (ip, bob, String::from("a"));
+        assert!(matches!(
+            idempotency.begin(&config, &alice, b"1", 0),
+            Begin::Handle
+        ));
+        idempotency.finish(
+            &alice,
+            Some(Stored {
+                status: http::StatusCode::OK,
+                headers: http::HeaderMap::new(),
+                code: None,
+                message: None,
+                body: axum::body::Bytes::from_static(b"{}"),
+            }),
+        );
+        assert!(matches!(
+            idempotency.begin(&config, &alice, b"1", 10),
+            Begin::Replay(_)
+        ));
+        assert!(matches!(
+            idempotency.begin(&config, &bob, b"1", 10),
+            Begin::Handle
+        ));
+    }
+}
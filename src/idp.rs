@@ -0,0 +1,951 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use subtle::ConstantTimeEq;
+use tokio::sync::RwLock as TokioRwLock;
+use zeroize::Zeroizing;
+
+use crate::audit;
+use crate::client_info::ClientInfo;
+use crate::config;
+use crate::forward_auth;
+use crate::http_client;
+use crate::i18n;
+use crate::jwt;
+use crate::rng;
+use crate::time;
+use crate::users::{self, LinkedIdentity, StoreError, User};
+use crate::{
+    authenticate_session, error_response, json_response, lookup_session, message_error_response,
+    move_to_new_id, AppState, SESSION_NOT_AUTHENTICATED,
+};
+
+const PENDING_LOGIN_TTL: Duration = Duration::from_secs(600);
+const MAX_PENDING_LOGINS: usize = 10_000;
+const DISCOVERY_TTL: Duration = Duration::from_secs(3600);
+const JWKS_MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+const CLOCK_SKEW_SECS: u64 = 60;
+// Ties a pending login to the browser that started it, so a callback URL
+// handed to someone else can't finish the login in their browser.
+const BROWSER_COOKIE: &str = "__Host-tk-auth-idp";
+
+#[derive(Clone)]
+struct Discovery {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+    jwks: serde_json::Value,
+    fetched_at: Instant,
+    jwks_fetched_at: Instant,
+}
+
+struct Provider {
+    config: config::IdentityProviderConfig,
+    discovery: TokioRwLock<Option<Discovery>>,
+}
+
+struct PendingLogin {
+    provider: String,
+    session_id: String,
+    nonce: String,
+    code_verifier: Zeroizing<String>,
+    link_user: Option<String>,
+    browser: String,
+    created: Instant,
+}
+
+pub struct IdentityProviders {
+    config: Option<config::IdpConfig>,
+    providers: Vec<Provider>,
+    client: Option<http_client::Client>,
+    pending: TokioRwLock<HashMap<String, PendingLogin>>,
+}
+
+impl IdentityProviders {
+    pub fn new(config: &config::Config) -> io::Result<Self> {
+        let client = match &config.idp {
+            Some(_) => Some(http_client::Client::new(&config.secrets.ca_path)?),
+            None => None,
+        };
+        Ok(Self {
+            config: config.idp.clone(),
+            providers: config
+                .idp
+                .iter()
+                .flat_map(|idp| &idp.providers)
+                .map(|provider| Provider {
+                    config: provider.clone(),
+                    discovery: TokioRwLock::new(None),
+                })
+                .collect(),
+            client,
+            pending: TokioRwLock::new(HashMap::new()),
+        })
+    }
+
+    fn provider(&self, id: &str) -> Option<&Provider> {
+        self.providers
+            .iter()
+            .find(|provider| provider.config.id == id)
+    }
+
+    fn redirect_uri(&self, provider: &Provider) -> String {
+        let public_url = self
+            .config
+            .as_ref()
+            .map(|idp| idp.public_url.trim_end_matches('/'))
+            .unwrap_or_default();
+        format!("{}/api/idp/{}/callback", public_url, provider.config.id)
+    }
+
+    async fn get_json(&self, url: &str) -> Result<serde_json::Value, String> {
+        let client = self.client.as_ref().unwrap();
+        let response = client
+            .request("GET", url, &[("Accept", "application/json")], b"")
+            .await
+            .map_err(|err| err.to_string())?;
+        if response.status != 200 {
+            return Err(format!("{} returned status {}", url, response.status));
+        }
+        serde_json::from_slice(&response.body).map_err(|err| format!("{}: {}", url, err))
+    }
+
+    async fn discovery(&self, provider: &Provider) -> Result<Discovery, String> {
+        if let Some(discovery) = &*provider.discovery.read().await {
+            if discovery.fetched_at.elapsed() < DISCOVERY_TTL {
+                return Ok(discovery.clone());
+            }
+        }
+        let issuer = &provider.config.issuer;
+        let metadata = self
+            .get_json(&format!(
+                "{}/.well-known/openid-configuration",
+                issuer.trim_end_matches('/')
+            ))
+            .await?;
+        let field = |name: &str| {
+            metadata
+                .get(name)
+                .and_then(|value| value.as_str())
+                .map(String::from)
+                .ok_or_else(|| format!("{} discovery document has no {}", issuer, name))
+        };
+        if field("issuer")? != *issuer {
+            return Err(format!(
+                "{} discovery document names another issuer",
+                issuer
+            ));
+        }
+        let jwks_uri = field("jwks_uri")?;
+        let jwks = self.get_json(&jwks_uri).await?;
+        let now = Instant::now();
+        let discovery = Discovery {
+            authorization_endpoint: field("authorization_endpoint")?,
+            token_endpoint: field("token_endpoint")?,
+            jwks_uri,
+            jwks,
+            fetched_at: now,
+            jwks_fetched_at: now,
+        };
+        *provider.discovery.write().await = Some(discovery.clone());
+        Ok(discovery)
+    }
+
+    async fn refresh_jwks(&self, provider: &Provider) -> Result<Option<serde_json::Value>, String> {
+        let mut discovery = provider.discovery.write().await;
+        let Some(discovery) = discovery.as_mut() else {
+            return Ok(None);
+        };
+        if discovery.jwks_fetched_at.elapsed() < JWKS_MIN_REFRESH_INTERVAL {
+            return Ok(None);
+        }
+        discovery.jwks = self.get_json(&discovery.jwks_uri).await?;
+        discovery.jwks_fetched_at = Instant::now();
+        Ok(Some(discovery.jwks.clone()))
+    }
+
+    async fn exchange_code(
+        &self,
+        provider: &Provider,
+        discovery: &Discovery,
+        code: &str,
+        pending: &PendingLogin,
+        now: u64,
+    ) -> Result<serde_json::Value, String> {
+        let redirect_uri = self.redirect_uri(provider);
+        let body = Zeroizing::new(
+            form_urlencoded::Serializer::new(String::new())
+                .append_pair("grant_type", "authorization_code")
+                .append_pair("code", code)
+                .append_pair("redirect_uri", &redirect_uri)
+                .append_pair("client_id", &provider.config.client_id)
+                .append_pair("code_verifier", &pending.code_verifier)
+                .finish(),
+        );
+        let authorization = provider.config.client_secret.as_ref().map(|secret| {
+            let encode = |value: &str| -> String {
+                form_urlencoded::byte_serialize(value.as_bytes()).collect()
+            };
+            Zeroizing::new(format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD.encode(format!(
+                    "{}:{}",
+                    encode(&provider.config.client_id),
+                    encode(secret)
+                ))
+            ))
+        });
+        let mut headers = vec![
+            ("Content-Type", "application/x-www-form-urlencoded"),
+            ("Accept", "application/json"),
+        ];
+        if let Some(authorization) = &authorization {
+            headers.push(("Authorization", authorization.as_str()));
+        }
+        let response = self
+            .client
+            .as_ref()
+            .unwrap()
+            .request("POST", &discovery.token_endpoint, &headers, body.as_bytes())
+            .await
+            .map_err(|err| err.to_string())?;
+        let tokens: serde_json::Value = serde_json::from_slice(&response.body)
+            .map_err(|err| format!("token response: {}", err))?;
+        if response.status != 200 {
+            return Err(format!(
+                "token endpoint returned status {}: {}",
+                response.status,
+                tokens
+                    .get("error")
+                    .and_then(|error| error.as_str())
+                    .unwrap_or("unknown error")
+            ));
+        }
+        let id_token = tokens
+            .get("id_token")
+            .and_then(|id_token| id_token.as_str())
+            .ok_or_else(|| String::from("token response has no id_token"))?;
+        let token = jwt::Token::decode(id_token)?;
+        if let Err(err) = token.verify(&discovery.jwks) {
+            // The provider may have rotated its keys since we fetched them.
+            match self.refresh_jwks(provider).await? {
+                Some(jwks) => token.verify(&jwks)?,
+                None => return Err(err),
+            }
+        }
+        validate_claims(&provider.config, &token.claims, &pending.nonce, now)?;
+        Ok(token.claims)
+    }
+}
+
+fn validate_claims(
+    provider: &config::IdentityProviderConfig,
+    claims: &serde_json::Value,
+    nonce: &str,
+    now: u64,
+) -> Result<(), String> {
+    let claim = |name: &str| claims.get(name);
+    if claim("iss").and_then(|iss| iss.as_str()) != Some(provider.issuer.as_str()) {
+        return Err(String::from("ID token has the wrong issuer"));
+    }
+    let audience_ok = match claim("aud") {
+        Some(serde_json::Value::String(aud)) => *aud == provider.client_id,
+        Some(serde_json::Value::Array(auds)) => auds
+            .iter()
+            .any(|aud| aud.as_str() == Some(provider.client_id.as_str())),
+        _ => false,
+    };
+    if !audience_ok {
+        return Err(String::from("ID token is not meant for this client"));
+    }
+    match claim("exp").and_then(|exp| exp.as_u64()) {
+        Some(exp) if exp + CLOCK_SKEW_SECS > now => {}
+        _ => return Err(String::from("ID token has expired")),
+    }
+    if claim("nonce").and_then(|claim| claim.as_str()) != Some(nonce) {
+        return Err(String::from("ID token nonce doesn't match"));
+    }
+    match claim("sub").and_then(|sub| sub.as_str()) {
+        Some(sub) if !sub.is_empty() => Ok(()),
+        _ => Err(String::from("ID token has no subject")),
+    }
+}
+
+fn random_token(rng: &dyn rng::Rng) -> String {
+    let mut bytes = [0u8; 32];
+    rng.fill(&mut bytes);
+    jwt::base64url_encode(&bytes)
+}
+
+#[derive(serde::Serialize)]
+struct ProviderInfo<'a> {
+    id: &'a str,
+    display_name: &'a str,
+    login_url: String,
+}
+
+pub async fn get_providers(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+) -> axum::response::Response {
+    let providers: Vec<ProviderInfo> = state
+        .idp
+        .providers
+        .iter()
+        .map(|provider| ProviderInfo {
+            id: &provider.config.id,
+            display_name: &provider.config.display_name,
+            login_url: format!("/api/idp/{}/login", provider.config.id),
+        })
+        .collect();
+    json_response(200, serde_json::json!({ "providers": providers }))
+}
+
+#[derive(serde::Deserialize)]
+pub struct LoginQuery {
+    session_id: String,
+    #[serde(default)]
+    link: bool,
+}
+
+pub async fn get_login(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    axum::extract::Path(provider_id): axum::extract::Path<String>,
+    axum::extract::Query(query): axum::extract::Query<LoginQuery>,
+) -> axum::response::Response {
+    let Some(provider) = state.idp.provider(&provider_id) else {
+        return message_error_response(
+            404,
+            i18n::Message {
+                template: "unknown identity provider {provider_id}",
+                args: vec![("provider_id", provider_id.to_string())],
+            },
+        );
+    };
+    let session = match lookup_session(&state, &query.session_id, &client).await {
+        Ok((_, session)) => session,
+        Err(response) => return response,
+    };
+    let link_user = {
+        let session_locked = session.read().await;
+        match (
+            query.link,
+            session_locked.authenticated,
+            &session_locked.user,
+        ) {
+            (true, true, Some(user)) => Some(user.clone()),
+            (true, _, _) => {
+                return error_response(400, "linking needs an authenticated session");
+            }
+            (false, true, _) => {
+                return message_error_response(
+                    400,
+                    i18n::Message {
+                        template: "session {session_id} already authenticated",
+                        args: vec![("session_id", query.session_id.to_string())],
+                    },
+                );
+            }
+            (false, false, _) => None,
+        }
+    };
+    let discovery = match state.idp.discovery(provider).await {
+        Ok(discovery) => discovery,
+        Err(err) => {
+            println!("Identity provider {} unavailable: {}", provider_id, err);
+            return error_response(502, "identity provider unavailable");
+        }
+    };
+
+    let (login_state, nonce, code_verifier, browser) = (
+        random_token(&state.rng),
+        random_token(&state.rng),
+        Zeroizing::new(random_token(&state.rng)),
+        random_token(&state.rng),
+    );
+    let code_challenge = jwt::base64url_encode(
+        ring::digest::digest(&ring::digest::SHA256, code_verifier.as_bytes()).as_ref(),
+    );
+    {
+        let mut pending = state.idp.pending.write().await;
+        pending.retain(|_, login| login.created.elapsed() < PENDING_LOGIN_TTL);
+        if pending.len() >= MAX_PENDING_LOGINS {
+            return error_response(503, "too many logins in progress");
+        }
+        pending.insert(
+            login_state.clone(),
+            PendingLogin {
+                provider: provider_id.clone(),
+                session_id: query.session_id.clone(),
+                nonce: nonce.clone(),
+                code_verifier,
+                link_user,
+                browser: browser.clone(),
+                created: Instant::now(),
+            },
+        );
+    }
+    let location = format!(
+        "{}{}{}",
+        discovery.authorization_endpoint,
+        if discovery.authorization_endpoint.contains('?') {
+            '&'
+        } else {
+            '?'
+        },
+        form_urlencoded::Serializer::new(String::new())
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &provider.config.client_id)
+            .append_pair("redirect_uri", &state.idp.redirect_uri(provider))
+            .append_pair("scope", &provider.config.scopes.join(" "))
+            .append_pair("state", &login_state)
+            .append_pair("nonce", &nonce)
+            .append_pair("code_challenge", &code_challenge)
+            .append_pair("code_challenge_method", "S256")
+            .finish()
+    );
+    axum::response::Response::builder()
+        .status(302)
+        .header(http::header::LOCATION, location)
+        .header(
+            http::header::SET_COOKIE,
+            format!(
+                "{}={}; Path=/; Secure; HttpOnly; SameSite=Lax; Max-Age={}",
+                BROWSER_COOKIE,
+                browser,
+                PENDING_LOGIN_TTL.as_secs()
+            ),
+        )
+        .body(axum::body::Body::empty())
+        .unwrap()
+}
+
+fn same_browser(headers: &http::HeaderMap, pending: &PendingLogin) -> bool {
+    forward_auth::session_cookie(headers, BROWSER_COOKIE)
+        .is_some_and(|browser| bool::from(browser.as_bytes().ct_eq(pending.browser.as_bytes())))
+}
+
+fn clear_browser_cookie(mut response: axum::response::Response) -> axum::response::Response {
+    response.headers_mut().append(
+        http::header::SET_COOKIE,
+        http::HeaderValue::from_str(&format!(
+            "{}=; Path=/; Secure; HttpOnly; SameSite=Lax; Max-Age=0",
+            BROWSER_COOKIE
+        ))
+        .unwrap(),
+    );
+    response
+}
+
+#[derive(serde::Deserialize)]
+pub struct CallbackQuery {
+    state: String,
+    code: Option<String>,
+    error: Option<String>,
+}
+
+enum Resolved {
+    Existing(String),
+    Linked(String),
+    Created(String),
+}
+
+fn resolve_identity(
+    provider: &config::IdentityProviderConfig,
+    data: &mut users::UserData,
+    claims: &serde_json::Value,
+    link_user: Option<&str>,
+    disposable_email: bool,
+) -> Result<Resolved, StoreError> {
+    let subject = match claims["sub"].as_str() {
+        Some(subject) if !subject.is_empty() => subject,
+        _ => return Err(StoreError::Invalid(String::from("ID token has no subject"))),
+    };
+    let identity = LinkedIdentity {
+        provider: provider.id.clone(),
+        subject: subject.to_string(),
+        linked_at: time::now_secs(),
+    };
+    let linked = data
+        .user_by_identity(&provider.id, subject)
+        .map(|user| user.user_name.clone());
+    if let Some(link_user) = link_user {
+        return match linked {
+            Some(user_name) if user_name.eq_ignore_ascii_case(link_user) => {
+                Ok(Resolved::Existing(user_name))
+            }
+            Some(_) => Err(StoreError::Conflict(String::from(
+                "this identity is linked to another user",
+            ))),
+            None => {
+                let user = data
+                    .users
+                    .values_mut()
+                    .find(|user| user.user_name.eq_ignore_ascii_case(link_user))
+                    .ok_or(StoreError::NotFound)?;
+                user.identities.push(identity);
+                Ok(Resolved::Linked(user.user_name.clone()))
+            }
+        };
+    }
+    if let Some(user_name) = linked {
+        return Ok(Resolved::Existing(user_name));
+    }
+
+    let email = claims["email"].as_str();
+    let email_verified = matches!(claims["email_verified"], serde_json::Value::Bool(true))
+        || claims["email_verified"].as_str() == Some("true");
+    if let (true, Some(email), true) = (provider.link_verified_email, email, email_verified) {
+        let mut matching = data.users.values_mut().filter(|user| {
+            user.emails
+                .iter()
+                .any(|other| other.eq_ignore_ascii_case(email))
+        });
+        if let (Some(user), None) = (matching.next(), matching.next()) {
+            user.identities.push(identity);
+            return Ok(Resolved::Linked(user.user_name.clone()));
+        }
+    }
+    if !provider.create_users {
+        return Err(StoreError::NotFound);
+    }
+    if disposable_email {
+        return Err(StoreError::Invalid(String::from(
+            "disposable email addresses can't be used to sign up",
+        )));
+    }
+    let user_name = claims["preferred_username"]
+        .as_str()
+        .or(email)
+        .map(String::from)
+        .unwrap_or_else(|| format!("{}:{}", provider.id, subject));
+    if data.user_by_name(&user_name).is_some() {
+        return Err(StoreError::Conflict(format!(
+            "user {} already exists",
+            user_name
+        )));
+    }
+    let mut user = User::new(users::new_id(), user_name.clone());
+    user.display_name = claims["name"].as_str().map(String::from);
+    user.given_name = claims["given_name"].as_str().map(String::from);
+    user.family_name = claims["family_name"].as_str().map(String::from);
+    user.preferred_language = claims["locale"].as_str().map(String::from);
+    user.emails = email.map(String::from).into_iter().collect();
+    user.identities.push(identity);
+    data.users.insert(user.id.clone(), user);
+    Ok(Resolved::Created(user_name))
+}
+
+fn after_login(state: &AppState, response: axum::response::Response) -> axum::response::Response {
+    let Some(after_login_url) = state
+        .config
+        .idp
+        .as_ref()
+        .and_then(|idp| idp.after_login_url.as_ref())
+    else {
+        return response;
+    };
+    if response.status() != 200 {
+        return response;
+    }
+    let (mut parts, _) = response.into_parts();
+    parts.status = http::StatusCode::FOUND;
+    parts.headers.remove(http::header::CONTENT_TYPE);
+    parts.headers.insert(
+        http::header::LOCATION,
+        http::HeaderValue::from_str(after_login_url).unwrap(),
+    );
+    axum::response::Response::from_parts(parts, axum::body::Body::empty())
+}
+
+pub async fn get_callback(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    headers: http::HeaderMap,
+    axum::extract::Path(provider_id): axum::extract::Path<String>,
+    axum::extract::Query(query): axum::extract::Query<CallbackQuery>,
+) -> axum::response::Response {
+    let pending = {
+        let mut pending = state.idp.pending.write().await;
+        // Only the browser that started the login may use up its state.
+        match pending.get(&query.state) {
+            Some(login) if !same_browser(&headers, login) => {
+                println!("Login through {} finished in another browser", provider_id);
+                return error_response(400, "the login was started in another browser");
+            }
+            _ => pending.remove(&query.state),
+        }
+    };
+    let Some(pending) = pending.filter(|pending| {
+        pending.provider == provider_id && pending.created.elapsed() < PENDING_LOGIN_TTL
+    }) else {
+        return error_response(400, "unknown or expired login state");
+    };
+    let Some(provider) = state.idp.provider(&provider_id) else {
+        return message_error_response(
+            404,
+            i18n::Message {
+                template: "unknown identity provider {provider_id}",
+                args: vec![("provider_id", provider_id.to_string())],
+            },
+        );
+    };
+    let code = match (&query.code, &query.error) {
+        (Some(code), None) => code,
+        (_, error) => {
+            println!(
+                "Identity provider {} refused login: {}",
+                provider_id,
+                error.as_deref().unwrap_or("no code")
+            );
+            return error_response(401, "identity provider refused the login");
+        }
+    };
+    let discovery = match state.idp.discovery(provider).await {
+        Ok(discovery) => discovery,
+        Err(err) => {
+            println!("Identity provider {} unavailable: {}", provider_id, err);
+            return error_response(502, "identity provider unavailable");
+        }
+    };
+    let claims = match state
+        .idp
+        .exchange_code(provider, &discovery, code, &pending, state.clock.now_secs())
+        .await
+    {
+        Ok(claims) => claims,
+        Err(err) => {
+            println!("Login through {} failed: {}", provider_id, err);
+            return error_response(401, "login through the identity provider failed");
+        }
+    };
+    let session = match lookup_session(&state, &pending.session_id, &client).await {
+        Ok((_, session)) => session,
+        Err(response) => return response,
+    };
+
+    let subject = match claims["sub"].as_str() {
+        Some(subject) if !subject.is_empty() => subject.to_string(),
+        _ => {
+            println!(
+                "Login through {} failed: ID token has no subject",
+                provider_id
+            );
+            return error_response(401, "login through the identity provider failed");
+        }
+    };
+    let disposable = claims["email"]
+        .as_str()
+        .and_then(|email| state.disposable_emails.check(email));
+    let reject_disposable = matches!(disposable, Some((_, config::DisposableEmailAction::Reject)));
+    let resolved = state
+        .users
+        .update(|data| {
+            resolve_identity(
+                &provider.config,
+                data,
+                &claims,
+                pending.link_user.as_deref(),
+                reject_disposable,
+            )
+        })
+        .await;
+    let user = match resolved {
+        Ok(Resolved::Existing(user)) => user,
+        Ok(Resolved::Linked(user)) => {
+            println!(
+                "Linked {} identity {} to user {}",
+                provider_id, subject, user
+            );
+            user
+        }
+        Ok(Resolved::Created(user)) => {
+            println!(
+                "Created user {} for {} identity {}",
+                user, provider_id, subject
+            );
+            if let Some((domain, _)) = &disposable {
+                audit::record(audit::AuditEvent::DisposableEmail {
+                    user: &user,
+                    domain,
+                    rejected: false,
+                });
+            }
+            user
+        }
+        Err(StoreError::NotFound) => {
+            let principal = format!("{}:{}", provider_id, subject);
+            audit::record(audit::AuditEvent::AuthenticationFailed {
+                user: &principal,
+                ip: client.ip,
+            });
+            return error_response(403, "this identity is not linked to any user");
+        }
+        Err(StoreError::Conflict(message)) => return error_response(409, &message),
+        Err(StoreError::Invalid(message)) => {
+            let principal = format!("{}:{}", provider_id, subject);
+            if let Some((domain, _)) = &disposable {
+                audit::record(audit::AuditEvent::DisposableEmail {
+                    user: &principal,
+                    domain,
+                    rejected: true,
+                });
+            }
+            return error_response(403, &message);
+        }
+        Err(err) => {
+            println!("Failed to save linked identity: {}", err);
+            return error_response(500, "failed to save linked identity");
+        }
+    };
+
+    // The session id went through the provider's redirect, so the browser
+    // gets a new one either way.
+    if pending.link_user.is_some() {
+        let mut session_locked = session.write().await;
+        if !session_locked.authenticated {
+            return SESSION_NOT_AUTHENTICATED.response();
+        }
+        let rotated = session_locked.rotate();
+        let id_base64 = move_to_new_id(
+            &state,
+            session_locked,
+            rotated,
+            &pending.session_id,
+            state.session_ids().generate(&state.rng),
+        );
+        let mut response = json_response(
+            200,
+            serde_json::json!({
+                "success": format!("{} identity linked to {}", provider_id, user),
+                "id_base64": id_base64,
+            }),
+        );
+        forward_auth::add_session_cookie(&state, &mut response, &id_base64);
+        return clear_browser_cookie(after_login(&state, response));
+    }
+    clear_browser_cookie(after_login(
+        &state,
+        authenticate_session(&state, &session, &pending.session_id, user, true).await,
+    ))
+}
+
+#[derive(serde::Deserialize)]
+pub struct IdentitiesQuery {
+    session_id: String,
+}
+
+async fn session_user(
+    state: &AppState,
+    session_id: &str,
+    client: &ClientInfo,
+) -> Result<String, axum::response::Response> {
+    let session = lookup_session(state, session_id, client).await?.1;
+    let session_locked = session.read().await;
+    match (&session_locked.user, session_locked.authenticated) {
+        (Some(user), true) => Ok(user.clone()),
+        _ => Err(SESSION_NOT_AUTHENTICATED.response()),
+    }
+}
+
+pub async fn get_identities(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    axum::extract::Query(query): axum::extract::Query<IdentitiesQuery>,
+) -> axum::response::Response {
+    let user_name = match session_user(&state, &query.session_id, &client).await {
+        Ok(user_name) => user_name,
+        Err(response) => return response,
+    };
+    let data = state.users.read().await;
+    let identities = data
+        .user_by_name(&user_name)
+        .map(|user| user.identities.clone())
+        .unwrap_or_default();
+    json_response(200, serde_json::json!({ "identities": identities }))
+}
+
+#[derive(serde::Deserialize)]
+pub struct UnlinkForm {
+    session_id: String,
+    provider: String,
+    subject: String,
+}
+
+pub async fn post_unlink(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    axum::extract::Form(form): axum::extract::Form<UnlinkForm>,
+) -> axum::response::Response {
+    let user_name = match session_user(&state, &form.session_id, &client).await {
+        Ok(user_name) => user_name,
+        Err(response) => return response,
+    };
+    let result = state
+        .users
+        .update(|data| {
+            let user = data
+                .users
+                .values_mut()
+                .find(|user| user.user_name.eq_ignore_ascii_case(&user_name))
+                .ok_or(StoreError::NotFound)?;
+            let before = user.identities.len();
+            user.identities.retain(|identity| {
+                identity.provider != form.provider || identity.subject != form.subject
+            });
+            if user.identities.len() == before {
+                return Err(StoreError::NotFound);
+            }
+            Ok(())
+        })
+        .await;
+    match result {
+        Ok(()) => {
+            println!(
+                "Unlinked {} identity {} from user {}",
+                form.provider, form.subject, user_name
+            );
+            json_response(
+                200,
+                serde_json::json!({ "success": format!("{} identity unlinked", form.provider) }),
+            )
+        }
+        Err(StoreError::NotFound) => error_response(404, "no such linked identity"),
+        Err(err) => {
+            println!("Failed to save unlinked identity: {}", err);
+            error_response(500, "failed to unlink identity")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider() -> config::IdentityProviderConfig {
+        serde_json::from_value(serde_json::json!({
+            "id": "corp",
+            "display_name": "Corp",
+            "issuer": "https://sso.example.com",
+            "client_id": "tk-auth",
+            "link_verified_email": true,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn validates_id_token_claims() {
+        let provider = provider();
+        let claims = serde_json::json!({
+            "iss": "https://sso.example.com",
+            "aud": ["other", "tk-auth"],
+            "sub": "123",
+            "exp": 1060,
+            "nonce": "n",
+        });
+        assert!(validate_claims(&provider, &claims, "n", 1000).is_ok());
+        assert!(validate_claims(&provider, &claims, "m", 1000).is_err());
+        let mut expired = claims.clone();
+        expired["exp"] = serde_json::json!(1000 - CLOCK_SKEW_SECS - 1);
+        assert!(validate_claims(&provider, &expired, "n", 1000).is_err());
+        let mut wrong_audience = claims;
+        wrong_audience["aud"] = serde_json::json!("other");
+        assert!(validate_claims(&provider, &wrong_audience, "n", 1000).is_err());
+    }
+
+    #[test]
+    fn resolves_linked_identities() {
+        let mut provider = provider();
+        let mut data = users::UserData::default();
+        let mut alice = User::new(String::from("1"), String::from("alice"));
+        alice.emails.push(String::from("alice@example.com"));
+        data.users.insert(alice.id.clone(), alice);
+        let claims = |sub: &str, email: &str, verified: bool| serde_json::json!({ "sub": sub, "email": email, "email_verified": verified });
+
+        let unverified = claims("a", "alice@example.com", false);
+        assert!(matches!(
+            resolve_identity(&provider, &mut data, &unverified, None, false),
+            Err(StoreError::NotFound)
+        ));
+        let verified = claims("a", "alice@example.com", true);
+        assert!(matches!(
+            resolve_identity(&provider, &mut data, &verified, None, false),
+            Ok(Resolved::Linked(user)) if user == "alice"
+        ));
+        assert!(matches!(
+            resolve_identity(&provider, &mut data, &unverified, None, false),
+            Ok(Resolved::Existing(user)) if user == "alice"
+        ));
+
+        data.users.insert(
+            String::from("2"),
+            User::new(String::from("2"), String::from("bob")),
+        );
+        assert!(matches!(
+            resolve_identity(&provider, &mut data, &unverified, Some("bob"), false),
+            Err(StoreError::Conflict(_))
+        ));
+        let other = claims("b", "b@example.com", false);
+        assert!(matches!(
+            resolve_identity(&provider, &mut data, &other, Some("bob"), false),
+            Ok(Resolved::Linked(user)) if user == "bob"
+        ));
+
+        provider.create_users = true;
+        let new = claims("c", "carol@example.com", false);
+        assert!(matches!(
+            resolve_identity(&provider, &mut data, &new, None, true),
+            Err(StoreError::Invalid(_))
+        ));
+        assert!(matches!(
+            resolve_identity(&provider, &mut data, &new, None, false),
+            Ok(Resolved::Created(user)) if user == "carol@example.com"
+        ));
+        assert_eq!(
+            data.user_by_identity("corp", "c").unwrap().user_name,
+            "carol@example.com"
+        );
+
+        let no_subject = serde_json::json!({ "email": "dave@example.com" });
+        assert!(matches!(
+            resolve_identity(&provider, &mut data, &no_subject, None, false),
+            Err(StoreError::Invalid(_))
+        ));
+        let empty_subject = claims("", "alice@example.com", true);
+        assert!(matches!(
+            resolve_identity(&provider, &mut data, &empty_subject, Some("bob"), false),
+            Err(StoreError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn checks_the_browser_cookie() {
+        let pending = PendingLogin {
+            provider: String::from("corp"),
+            session_id: String::from("s"),
+            nonce: String::from("n"),
+            code_verifier: Zeroizing::new(String::from("v")),
+            link_user: None,
+            browser: String::from("abc"),
+            created: Instant::now(),
+        };
+        let headers = |cookie: &str| {
+            let mut headers = http::HeaderMap::new();
+            headers.insert(
+                http::header::COOKIE,
+                http::HeaderValue::from_str(cookie).unwrap(),
+            );
+            headers
+        };
+        assert!(same_browser(
+            &headers("a=1; __Host-tk-auth-idp=abc"),
+            &pending
+        ));
+        assert!(!same_browser(&headers("__Host-tk-auth-idp=abd"), &pending));
+        assert!(!same_browser(&headers("tk-auth-idp=abc"), &pending));
+        assert!(!same_browser(&http::HeaderMap::new(), &pending));
+    }
+}
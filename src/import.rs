@@ -0,0 +1,390 @@
+use std::collections::BTreeSet;
+use std::io;
+use std::path::Path;
+
+use crate::config;
+use crate::encryption;
+use crate::secrets;
+use crate::time;
+use crate::users::{self, User, UserData};
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    Csv,
+    Json,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "csv" | "text/csv" => Some(Self::Csv),
+            "json" | "application/json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    fn from_path(path: &Path) -> Option<Self> {
+        Self::parse(path.extension()?.to_str()?)
+    }
+}
+
+#[derive(Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImportRow {
+    #[serde(alias = "username")]
+    user_name: String,
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default)]
+    password_hash: Option<String>,
+    #[serde(default)]
+    force_reset: bool,
+}
+
+#[derive(serde::Serialize)]
+pub struct RowReport {
+    row: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_name: Option<String>,
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Default, serde::Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub created: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub failed: usize,
+    pub rows: Vec<RowReport>,
+}
+
+pub fn parse_rows(
+    format: Format,
+    contents: &[u8],
+) -> Result<Vec<Result<ImportRow, String>>, String> {
+    match format {
+        Format::Json => {
+            let rows: Vec<serde_json::Value> = serde_json::from_slice(contents)
+                .map_err(|err| format!("expected a JSON array of users: {}", err))?;
+            Ok(rows
+                .into_iter()
+                .map(|row| serde_json::from_value(row).map_err(|err| err.to_string()))
+                .collect())
+        }
+        Format::Csv => {
+            let text = std::str::from_utf8(contents)
+                .map_err(|_| String::from("CSV file is not valid UTF-8"))?;
+            let mut records = parse_csv(text)?.into_iter();
+            let Some(header) = records.next() else {
+                return Ok(Vec::new());
+            };
+            let columns = header
+                .iter()
+                .map(|column| match column.trim().to_ascii_lowercase().as_str() {
+                    "user_name" | "username" => Ok(0),
+                    "email" => Ok(1),
+                    "password_hash" => Ok(2),
+                    "force_reset" => Ok(3),
+                    column => Err(format!("unknown CSV column {:?}", column)),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if !columns.contains(&0) {
+                return Err(String::from("CSV header needs a user_name column"));
+            }
+            Ok(records
+                .map(|record| {
+                    if record.len() != columns.len() {
+                        return Err(format!(
+                            "expected {} fields, found {}",
+                            columns.len(),
+                            record.len()
+                        ));
+                    }
+                    let mut row = ImportRow::default();
+                    for (column, value) in columns.iter().zip(record) {
+                        let value = value.trim().to_string();
+                        let optional = (!value.is_empty()).then(|| value.clone());
+                        match column {
+                            0 => row.user_name = value,
+                            1 => row.email = optional,
+                            2 => row.password_hash = optional,
+                            _ => {
+                                row.force_reset = match value.to_ascii_lowercase().as_str() {
+                                    "" | "false" | "no" | "0" => false,
+                                    "true" | "yes" | "1" => true,
+                                    _ => return Err(format!("invalid force_reset {:?}", value)),
+                                }
+                            }
+                        }
+                    }
+                    Ok(row)
+                })
+                .collect())
+        }
+    }
+}
+
+fn parse_csv(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut chars = text.chars().peekable();
+    let mut in_quotes = false;
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quotes = false,
+                c => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() && !quoted => {
+                in_quotes = true;
+                quoted = true;
+            }
+            ',' => {
+                record.push(std::mem::take(&mut field));
+                quoted = false;
+            }
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                quoted = false;
+                if record.len() > 1 || !record[0].is_empty() {
+                    records.push(std::mem::take(&mut record));
+                } else {
+                    record.clear();
+                }
+            }
+            c => field.push(c),
+        }
+    }
+    if in_quotes {
+        return Err(String::from("unterminated quoted CSV field"));
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn validate(row: &ImportRow) -> Result<(), String> {
+    if row.user_name.trim().is_empty() {
+        return Err(String::from("user_name is required"));
+    }
+    if let Some(password_hash) = &row.password_hash {
+        if !users::is_supported_hash(password_hash) {
+            return Err(String::from("password_hash is not a supported hash"));
+        }
+    }
+    Ok(())
+}
+
+// Re-running the same import is a no-op: existing users only change when
+// the row carries a different email or password hash, and force_reset only
+// applies together with a newly set password.
+fn import_row(data: &mut UserData, row: ImportRow) -> &'static str {
+    let existing = data.user_by_name(&row.user_name).cloned();
+    let mut user = existing
+        .clone()
+        .unwrap_or_else(|| User::new(users::new_id(), row.user_name.clone()));
+    let mut changed = existing.is_none();
+    if let Some(email) = row.email {
+        if user.emails.first() != Some(&email) {
+            user.emails.retain(|other| *other != email);
+            user.emails.insert(0, email);
+            changed = true;
+        }
+    }
+    if row.password_hash.is_some() && user.password_hash != row.password_hash {
+        user.password_hash = row.password_hash;
+        user.password_reset_required = row.force_reset;
+        changed = true;
+    } else if existing.is_none() {
+        user.password_reset_required = row.force_reset;
+    }
+    if !changed {
+        return "unchanged";
+    }
+    if existing.is_some() {
+        user.version += 1;
+    }
+    user.last_modified = time::now_secs();
+    data.users.insert(user.id.clone(), user);
+    if existing.is_some() {
+        "updated"
+    } else {
+        "created"
+    }
+}
+
+pub fn apply(
+    data: &mut UserData,
+    rows: Vec<Result<ImportRow, String>>,
+    dry_run: bool,
+) -> ImportReport {
+    let mut report = ImportReport {
+        dry_run,
+        ..Default::default()
+    };
+    let mut seen = BTreeSet::new();
+    for (i, row) in rows.into_iter().enumerate() {
+        let user_name = row.as_ref().ok().map(|row| row.user_name.clone());
+        let result = row.and_then(|row| {
+            validate(&row)?;
+            if !seen.insert(row.user_name.to_lowercase()) {
+                return Err(String::from("user_name appears more than once"));
+            }
+            Ok(import_row(data, row))
+        });
+        let (status, error) = match result {
+            Ok(status) => (status, None),
+            Err(err) => ("error", Some(err)),
+        };
+        match status {
+            "created" => report.created += 1,
+            "updated" => report.updated += 1,
+            "unchanged" => report.unchanged += 1,
+            _ => report.failed += 1,
+        }
+        report.rows.push(RowReport {
+            row: i + 1,
+            user_name,
+            status,
+            error,
+        });
+    }
+    report
+}
+
+pub async fn run_cli(
+    config: &config::Config,
+    mut args: impl Iterator<Item = String>,
+) -> io::Result<()> {
+    let usage = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "usage: tk-auth import-users [--config PATH] [--dry-run] [--format csv|json] FILE",
+        )
+    };
+    let mut dry_run = false;
+    let mut format = None;
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--format" => {
+                format = Some(
+                    args.next()
+                        .and_then(|name| Format::parse(&name))
+                        .ok_or_else(usage)?,
+                )
+            }
+            "--config" => {
+                args.next();
+            }
+            arg if arg.starts_with("--config=") => {}
+            arg if !arg.starts_with("--") && path.is_none() => path = Some(arg.to_string()),
+            _ => return Err(usage()),
+        }
+    }
+    let path = path.ok_or_else(usage)?;
+    let format = format
+        .or_else(|| Format::from_path(Path::new(&path)))
+        .ok_or_else(usage)?;
+    if config.users.path.is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "importing users needs users.path to be configured",
+        ));
+    }
+
+    let contents = std::fs::read(&path)?;
+    let rows = parse_rows(format, &contents)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, err)))?;
+    let secret_provider = secrets::SecretProvider::new(&config.secrets)?;
+    let encryption = encryption::Encryption::from_config(config, &secret_provider).await?;
+    let store = users::UserStore::open(&config.users, encryption)?;
+    let report = if dry_run {
+        let mut data = store.read().await.clone();
+        apply(&mut data, rows, true)
+    } else {
+        store
+            .update(|data| Ok(apply(data, rows, false)))
+            .await
+            .map_err(|err| io::Error::other(err.to_string()))?
+    };
+    for row in report.rows.iter().filter(|row| row.error.is_some()) {
+        println!(
+            "Row {} ({}): {}",
+            row.row,
+            row.user_name.as_deref().unwrap_or("?"),
+            row.error.as_deref().unwrap_or_default()
+        );
+    }
+    println!(
+        "{}{} created, {} updated, {} unchanged, {} failed",
+        if dry_run { "Dry run: " } else { "" },
+        report.created,
+        report.updated,
+        report.unchanged,
+        report.failed
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_quoted_csv_fields() {
+        let records = parse_csv("a,\"b,\"\"c\"\"\",\r\n\n\"x\ny\",z").unwrap();
+        assert_eq!(
+            records,
+            vec![
+                vec![String::from("a"), String::from("b,\"c\""), String::new()],
+                vec![String::from("x\ny"), String::from("z")],
+            ]
+        );
+        assert!(parse_csv("\"open").is_err());
+    }
+
+    #[test]
+    fn reimporting_is_idempotent() {
+        let hash = users::hash_password("secret");
+        let csv = format!(
+            "username,email,password_hash,force_reset\n\
+             alice,alice@example.com,\"{}\",no\n\
+             bob,,,yes\n\
+             ,nobody@example.com,,\n\
+             carol,,not-a-hash,\n\
+             Alice,,,\n",
+            hash
+        );
+        let mut data = UserData::default();
+        let rows = parse_rows(Format::Csv, csv.as_bytes()).unwrap();
+        let report = apply(&mut data, rows, false);
+        let statuses: Vec<_> = report.rows.iter().map(|row| row.status).collect();
+        assert_eq!(statuses, ["created", "created", "error", "error", "error"]);
+        assert!(data.user_by_name("bob").unwrap().password_reset_required);
+
+        let rows = parse_rows(Format::Csv, csv.as_bytes()).unwrap();
+        let report = apply(&mut data, rows, false);
+        assert_eq!(
+            (report.created, report.updated, report.unchanged),
+            (0, 0, 2)
+        );
+        assert_eq!(data.users.len(), 2);
+    }
+}
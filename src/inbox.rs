@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::client_info::ClientInfo;
+use crate::config;
+use crate::email::Template;
+use crate::notify::{session_user, Notification};
+use crate::pagination;
+use crate::users;
+use crate::{error_response, json_response, AppState, GetSessionQuery};
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct Item {
+    // Counts up per user.
+    id: u64,
+    event: Template,
+    details: serde_json::Value,
+    created_at: u64,
+    read: bool,
+}
+
+// Notifications per user name, written to the file after every change.
+#[derive(Default)]
+pub struct Inbox {
+    config: Option<config::InboxConfig>,
+    path: Option<PathBuf>,
+    items: tokio::sync::Mutex<HashMap<String, Vec<Item>>>,
+}
+
+impl Inbox {
+    pub fn new(config: &config::Config) -> io::Result<Self> {
+        let Some(inbox) = &config.inbox else {
+            return Ok(Self::default());
+        };
+        // Validated on load.
+        let path = inbox.path(&config.users).unwrap();
+        let items = match std::fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: {}", path.display(), err),
+                )
+            })?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                return Err(io::Error::new(
+                    err.kind(),
+                    format!("{}: {}", path.display(), err),
+                ))
+            }
+        };
+        Ok(Self {
+            config: Some(inbox.clone()),
+            path: Some(path),
+            items: tokio::sync::Mutex::new(items),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.is_some()
+    }
+
+    fn persist(&self, items: &HashMap<String, Vec<Item>>) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        users::write_private(path, &serde_json::to_vec_pretty(items).unwrap())
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))
+    }
+
+    pub async fn add(&self, notification: &Notification, now: u64) -> io::Result<()> {
+        let Some(config) = &self.config else {
+            return Err(io::Error::other("the inbox is not configured"));
+        };
+        let mut items = self.items.lock().await;
+        let user_items = items.entry(notification.user.clone()).or_default();
+        user_items.retain(|item| item.created_at + config.keep_secs > now);
+        if user_items.len() >= config.max_per_user {
+            user_items.drain(..=user_items.len() - config.max_per_user);
+        }
+        let id = user_items.last().map_or(1, |item| item.id + 1);
+        user_items.push(Item {
+            id,
+            event: notification.event,
+            details: notification.details.clone(),
+            created_at: now,
+            read: false,
+        });
+        self.persist(&items)
+    }
+
+    // Marks the user's notification `id`, or all of them, as read. Returns
+    // how many were unread.
+    async fn mark_read(&self, user: &str, id: Option<u64>) -> io::Result<Option<usize>> {
+        let mut items = self.items.lock().await;
+        let Some(user_items) = items.get_mut(user) else {
+            return Ok(id.is_none().then_some(0));
+        };
+        if id.is_some_and(|id| !user_items.iter().any(|item| item.id == id)) {
+            return Ok(None);
+        }
+        let mut marked = 0;
+        for item in user_items
+            .iter_mut()
+            .filter(|item| id.is_none_or(|id| item.id == id) && !item.read)
+        {
+            item.read = true;
+            marked += 1;
+        }
+        if marked > 0 {
+            self.persist(&items)?;
+        }
+        Ok(Some(marked))
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct NotificationsQuery {
+    session_id: String,
+    #[serde(default)]
+    unread_only: bool,
+}
+
+pub async fn get_notifications(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    axum::extract::Query(query): axum::extract::Query<NotificationsQuery>,
+    axum::extract::Query(page): axum::extract::Query<pagination::PageQuery>,
+) -> axum::response::Response {
+    if !state.inbox.is_enabled() {
+        return error_response(404, "the inbox is not enabled");
+    }
+    let user = match session_user(&state, &client, &query.session_id).await {
+        Ok((user, _)) => user,
+        Err(response) => return response,
+    };
+    let keep_secs = state
+        .config
+        .inbox
+        .as_ref()
+        .map_or(0, |inbox| inbox.keep_secs);
+    let now = state.clock.now_secs();
+    let items = state.inbox.items.lock().await;
+    let current: Vec<_> = items
+        .get(&user)
+        .into_iter()
+        .flatten()
+        .filter(|item| item.created_at + keep_secs > now)
+        .collect();
+    let unread = current.iter().filter(|item| !item.read).count();
+    let listed = current
+        .into_iter()
+        .filter(|item| !query.unread_only || !item.read)
+        .collect();
+    // Newest first by default.
+    let (listed, next_cursor) =
+        match pagination::page(listed, |item| item.id, &page, pagination::Order::Desc) {
+            Ok(page) => page,
+            Err(err) => return error_response(400, &err),
+        };
+    let notifications: Vec<_> = listed
+        .into_iter()
+        .map(|item| {
+            serde_json::json!({
+                "id": item.id,
+                "event": item.event.name(),
+                "details": item.details,
+                "created_at": item.created_at,
+                "read": item.read,
+            })
+        })
+        .collect();
+    json_response(
+        200,
+        serde_json::json!({
+            "notifications": notifications,
+            "unread": unread,
+            "next_cursor": next_cursor,
+        }),
+    )
+}
+
+async fn mark_read(
+    state: &AppState,
+    client: &ClientInfo,
+    session_id: &str,
+    id: Option<u64>,
+) -> axum::response::Response {
+    if !state.inbox.is_enabled() {
+        return error_response(404, "the inbox is not enabled");
+    }
+    let user = match session_user(state, client, session_id).await {
+        Ok((user, _)) => user,
+        Err(response) => return response,
+    };
+    match state.inbox.mark_read(&user, id).await {
+        Ok(Some(marked)) => json_response(200, serde_json::json!({ "marked_read": marked })),
+        Ok(None) => error_response(404, "no such notification"),
+        Err(err) => {
+            println!("Failed to save the inbox: {}", err);
+            error_response(500, "failed to save the inbox")
+        }
+    }
+}
+
+pub async fn post_notification_read(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    axum::extract::Path(id): axum::extract::Path<u64>,
+    axum::extract::Query(query): axum::extract::Query<GetSessionQuery>,
+) -> axum::response::Response {
+    mark_read(&state, &client, &query.session_id, Some(id)).await
+}
+
+pub async fn post_notifications_read_all(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    axum::extract::Query(query): axum::extract::Query<GetSessionQuery>,
+) -> axum::response::Response {
+    mark_read(&state, &client, &query.session_id, None).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn keeps_the_newest_notifications_per_user() {
+        let inbox = Inbox {
+            config: Some(config::InboxConfig {
+                max_per_user: 2,
+                keep_secs: 100,
+                ..config::InboxConfig::default()
+            }),
+            ..Inbox::default()
+        };
+        let notification = |user: &str| Notification {
+            event: Template::NewDevice,
+            user: user.to_string(),
+            details: serde_json::json!({}),
+        };
+        for now in [0, 10, 20] {
+            inbox.add(&notification("zed"), now).await.unwrap();
+        }
+        inbox.add(&notification("amy"), 20).await.unwrap();
+        let ids = |items: &HashMap<String, Vec<Item>>| -> Vec<u64> {
+            items["zed"].iter().map(|item| item.id).collect()
+        };
+        assert_eq!(ids(&*inbox.items.lock().await), [2, 3]);
+
+        assert_eq!(inbox.mark_read("zed", Some(3)).await.unwrap(), Some(1));
+        assert_eq!(inbox.mark_read("zed", Some(1)).await.unwrap(), None);
+        assert_eq!(inbox.mark_read("zed", None).await.unwrap(), Some(1));
+        assert_eq!(inbox.mark_read("bob", None).await.unwrap(), Some(0));
+
+        // The first one ran out.
+        inbox.add(&notification("zed"), 110).await.unwrap();
+        assert_eq!(ids(&*inbox.items.lock().await), [3, 4]);
+    }
+}
@@ -0,0 +1,210 @@
+use base64::Engine;
+
+pub fn base64url_decode(encoded: &str) -> Option<Vec<u8>> {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(encoded.trim_end_matches('='))
+        .ok()
+}
+
+pub fn base64url_encode(data: &[u8]) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(data)
+}
+
+pub struct Token<'a> {
+    pub header: serde_json::Value,
+    pub claims: serde_json::Value,
+    signing_input: &'a str,
+    signature: Vec<u8>,
+}
+
+impl<'a> Token<'a> {
+    pub fn decode(token: &'a str) -> Result<Self, String> {
+        let invalid = || String::from("malformed JWT");
+        let (signing_input, signature) = token.rsplit_once('.').ok_or_else(invalid)?;
+        let (header, claims) = signing_input.split_once('.').ok_or_else(invalid)?;
+        let json = |part: &str| -> Result<serde_json::Value, String> {
+            let value: serde_json::Value =
+                serde_json::from_slice(&base64url_decode(part).ok_or_else(invalid)?)
+                    .map_err(|_| invalid())?;
+            if value.is_object() {
+                Ok(value)
+            } else {
+                Err(invalid())
+            }
+        };
+        Ok(Self {
+            header: json(header)?,
+            claims: json(claims)?,
+            signing_input,
+            signature: base64url_decode(signature).ok_or_else(invalid)?,
+        })
+    }
+
+    pub fn kid(&self) -> Option<&str> {
+        self.header.get("kid").and_then(|kid| kid.as_str())
+    }
+
+    pub fn verify(&self, jwks: &serde_json::Value) -> Result<(), String> {
+        let alg = self
+            .header
+            .get("alg")
+            .and_then(|alg| alg.as_str())
+            .ok_or_else(|| String::from("JWT has no alg"))?;
+        let keys = jwks
+            .get("keys")
+            .and_then(|keys| keys.as_array())
+            .ok_or_else(|| String::from("JWKS has no keys"))?;
+        let candidates: Vec<&serde_json::Value> = keys
+            .iter()
+            .filter(|key| match self.kid() {
+                Some(kid) => key.get("kid").and_then(|k| k.as_str()) == Some(kid),
+                None => true,
+            })
+            .filter(|key| {
+                key.get("use")
+                    .and_then(|u| u.as_str())
+                    .is_none_or(|u| u == "sig")
+            })
+            .collect();
+        if candidates.is_empty() {
+            return Err(format!("no JWKS key matches kid {:?}", self.kid()));
+        }
+        let message = self.signing_input.as_bytes();
+        for key in candidates {
+            if verify_with_jwk(alg, key, message, &self.signature)? {
+                return Ok(());
+            }
+        }
+        Err(String::from("JWT signature is invalid"))
+    }
+}
+
+fn jwk_bytes(key: &serde_json::Value, name: &str) -> Result<Vec<u8>, String> {
+    key.get(name)
+        .and_then(|value| value.as_str())
+        .and_then(base64url_decode)
+        .ok_or_else(|| format!("JWK has no valid {}", name))
+}
+
+fn verify_with_jwk(
+    alg: &str,
+    key: &serde_json::Value,
+    message: &[u8],
+    signature: &[u8],
+) -> Result<bool, String> {
+    use ring::signature;
+
+    let kty = key
+        .get("kty")
+        .and_then(|kty| kty.as_str())
+        .unwrap_or_default();
+    if let Some(key_alg) = key.get("alg").and_then(|key_alg| key_alg.as_str()) {
+        if key_alg != alg {
+            return Ok(false);
+        }
+    }
+    let rsa = |params: &'static signature::RsaParameters| -> Result<bool, String> {
+        if kty != "RSA" {
+            return Ok(false);
+        }
+        let components = signature::RsaPublicKeyComponents {
+            n: jwk_bytes(key, "n")?,
+            e: jwk_bytes(key, "e")?,
+        };
+        Ok(components.verify(params, message, signature).is_ok())
+    };
+    let ec = |curve: &str,
+              algorithm: &'static signature::EcdsaVerificationAlgorithm|
+     -> Result<bool, String> {
+        if kty != "EC" || key.get("crv").and_then(|crv| crv.as_str()) != Some(curve) {
+            return Ok(false);
+        }
+        let mut point = vec![0x04];
+        point.extend(jwk_bytes(key, "x")?);
+        point.extend(jwk_bytes(key, "y")?);
+        Ok(signature::UnparsedPublicKey::new(algorithm, point)
+            .verify(message, signature)
+            .is_ok())
+    };
+    match alg {
+        "RS256" => rsa(&signature::RSA_PKCS1_2048_8192_SHA256),
+        "RS384" => rsa(&signature::RSA_PKCS1_2048_8192_SHA384),
+        "RS512" => rsa(&signature::RSA_PKCS1_2048_8192_SHA512),
+        "PS256" => rsa(&signature::RSA_PSS_2048_8192_SHA256),
+        "PS384" => rsa(&signature::RSA_PSS_2048_8192_SHA384),
+        "PS512" => rsa(&signature::RSA_PSS_2048_8192_SHA512),
+        "ES256" => ec("P-256", &signature::ECDSA_P256_SHA256_FIXED),
+        "ES384" => ec("P-384", &signature::ECDSA_P384_SHA384_FIXED),
+        "EdDSA" => {
+            if kty != "OKP" || key.get("crv").and_then(|crv| crv.as_str()) != Some("Ed25519") {
+                return Ok(false);
+            }
+            Ok(
+                signature::UnparsedPublicKey::new(&signature::ED25519, jwk_bytes(key, "x")?)
+                    .verify(message, signature)
+                    .is_ok(),
+            )
+        }
+        alg => Err(format!("unsupported JWT alg {}", alg)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+
+    fn sign_es256(header: &str, claims: &str) -> (String, serde_json::Value) {
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 =
+            ring::signature::EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                .unwrap();
+        let key_pair = ring::signature::EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            pkcs8.as_ref(),
+            &rng,
+        )
+        .unwrap();
+        let signing_input = format!(
+            "{}.{}",
+            base64url_encode(header.as_bytes()),
+            base64url_encode(claims.as_bytes())
+        );
+        let signature = key_pair.sign(&rng, signing_input.as_bytes()).unwrap();
+        let point = key_pair.public_key().as_ref();
+        let jwks = serde_json::json!({
+            "keys": [{
+                "kty": "EC",
+                "crv": "P-256",
+                "kid": "k1",
+                "x": base64url_encode(&point[1..33]),
+                "y": base64url_encode(&point[33..]),
+            }]
+        });
+        let token = format!("{}.{}", signing_input, base64url_encode(signature.as_ref()));
+        (token, jwks)
+    }
+
+    #[test]
+    fn verifies_es256_tokens() {
+        let (token, jwks) = sign_es256(r#"{"alg":"ES256","kid":"k1"}"#, r#"{"sub":"alice"}"#);
+        let decoded = Token::decode(&token).unwrap();
+        assert_eq!(decoded.claims["sub"], "alice");
+        assert!(decoded.verify(&jwks).is_ok());
+
+        let parts: Vec<&str> = token.split('.').collect();
+        let tampered = format!(
+            "{}.{}.{}",
+            parts[0],
+            base64url_encode(br#"{"sub":"mallory"}"#),
+            parts[2]
+        );
+        assert!(Token::decode(&tampered).unwrap().verify(&jwks).is_err());
+
+        let (other_kid, jwks) = sign_es256(r#"{"alg":"ES256","kid":"k2"}"#, "{}");
+        assert!(Token::decode(&other_kid).unwrap().verify(&jwks).is_err());
+        let (none_alg, jwks) = sign_es256(r#"{"alg":"none","kid":"k1"}"#, "{}");
+        assert!(Token::decode(&none_alg).unwrap().verify(&jwks).is_err());
+        assert!(Token::decode("not-a-jwt").is_err());
+    }
+}
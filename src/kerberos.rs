@@ -0,0 +1,233 @@
+use crate::config;
+
+pub struct Accepted {
+    pub principal: String,
+    pub output_token: Vec<u8>,
+}
+
+pub fn user_for_principal(kerberos: &config::KerberosConfig, principal: &str) -> Option<String> {
+    if let Some(user) = kerberos.users.get(principal) {
+        return Some(user.clone());
+    }
+    let (user, realm) = principal.rsplit_once('@')?;
+    if user.contains('/')
+        || !kerberos
+            .allowed_realms
+            .iter()
+            .any(|allowed| allowed == realm)
+    {
+        return None;
+    }
+    Some(user.to_string())
+}
+
+#[cfg(not(feature = "kerberos"))]
+pub fn register_keytab(_kerberos: &config::KerberosConfig) -> Result<(), String> {
+    Err(String::from(
+        "tk-auth was built without the kerberos feature",
+    ))
+}
+
+#[cfg(not(feature = "kerberos"))]
+pub fn accept(_token: &[u8]) -> Result<Accepted, String> {
+    Err(String::from(
+        "tk-auth was built without the kerberos feature",
+    ))
+}
+
+#[cfg(feature = "kerberos")]
+pub use gssapi::{accept, register_keytab};
+
+#[cfg(feature = "kerberos")]
+mod gssapi {
+    use std::ffi::{c_char, c_int, c_void, CString};
+    use std::os::unix::ffi::OsStrExt;
+
+    use super::Accepted;
+    use crate::config;
+
+    type OmUint32 = u32;
+    type GssName = *mut c_void;
+    type GssContext = *mut c_void;
+    type GssCredential = *mut c_void;
+
+    const GSS_S_COMPLETE: OmUint32 = 0;
+    const GSS_S_CONTINUE_NEEDED: OmUint32 = 1;
+    const GSS_C_GSS_CODE: c_int = 1;
+    const GSS_C_MECH_CODE: c_int = 2;
+
+    #[repr(C)]
+    struct GssBuffer {
+        length: usize,
+        value: *mut c_void,
+    }
+
+    impl GssBuffer {
+        fn empty() -> Self {
+            Self {
+                length: 0,
+                value: std::ptr::null_mut(),
+            }
+        }
+
+        fn to_vec(&self) -> Vec<u8> {
+            if self.value.is_null() {
+                return Vec::new();
+            }
+            // SAFETY: GSS-API returns `length` readable bytes at `value`.
+            unsafe { std::slice::from_raw_parts(self.value as *const u8, self.length) }.to_vec()
+        }
+    }
+
+    #[link(name = "gssapi_krb5")]
+    extern "C" {
+        fn gss_accept_sec_context(
+            minor_status: *mut OmUint32,
+            context_handle: *mut GssContext,
+            acceptor_cred_handle: GssCredential,
+            input_token_buffer: *const GssBuffer,
+            input_chan_bindings: *const c_void,
+            src_name: *mut GssName,
+            mech_type: *mut *const c_void,
+            output_token: *mut GssBuffer,
+            ret_flags: *mut OmUint32,
+            time_rec: *mut OmUint32,
+            delegated_cred_handle: *mut GssCredential,
+        ) -> OmUint32;
+        fn gss_display_name(
+            minor_status: *mut OmUint32,
+            input_name: GssName,
+            output_name_buffer: *mut GssBuffer,
+            output_name_type: *mut *const c_void,
+        ) -> OmUint32;
+        fn gss_display_status(
+            minor_status: *mut OmUint32,
+            status_value: OmUint32,
+            status_type: c_int,
+            mech_type: *const c_void,
+            message_context: *mut OmUint32,
+            status_string: *mut GssBuffer,
+        ) -> OmUint32;
+        fn gss_release_buffer(minor_status: *mut OmUint32, buffer: *mut GssBuffer) -> OmUint32;
+        fn gss_release_name(minor_status: *mut OmUint32, name: *mut GssName) -> OmUint32;
+        fn gss_delete_sec_context(
+            minor_status: *mut OmUint32,
+            context_handle: *mut GssContext,
+            output_token: *mut GssBuffer,
+        ) -> OmUint32;
+        fn krb5_gss_register_acceptor_identity(keytab: *const c_char) -> OmUint32;
+    }
+
+    pub fn register_keytab(kerberos: &config::KerberosConfig) -> Result<(), String> {
+        let Some(keytab_path) = &kerberos.keytab_path else {
+            return Ok(());
+        };
+        let keytab = CString::new(keytab_path.as_os_str().as_bytes())
+            .map_err(|_| format!("invalid keytab path {}", keytab_path.display()))?;
+        // SAFETY: the path is a valid NUL-terminated string that GSS-API copies.
+        let status = unsafe { krb5_gss_register_acceptor_identity(keytab.as_ptr()) };
+        if status != GSS_S_COMPLETE {
+            return Err(format!(
+                "{}: failed to register keytab",
+                keytab_path.display()
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn accept(token: &[u8]) -> Result<Accepted, String> {
+        let input = GssBuffer {
+            length: token.len(),
+            value: token.as_ptr() as *mut c_void,
+        };
+        let mut minor = 0;
+        let mut context: GssContext = std::ptr::null_mut();
+        let mut name: GssName = std::ptr::null_mut();
+        let mut output = GssBuffer::empty();
+        // SAFETY: every out-pointer refers to a live local, and everything
+        // GSS-API allocates is released before returning.
+        unsafe {
+            let major = gss_accept_sec_context(
+                &mut minor,
+                &mut context,
+                std::ptr::null_mut(),
+                &input,
+                std::ptr::null(),
+                &mut name,
+                std::ptr::null_mut(),
+                &mut output,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            );
+            let output_token = output.to_vec();
+            gss_release_buffer(&mut 0, &mut output);
+            if !context.is_null() {
+                gss_delete_sec_context(&mut 0, &mut context, std::ptr::null_mut());
+            }
+            let result = match major {
+                GSS_S_COMPLETE => display_name(name).map(|principal| Accepted {
+                    principal,
+                    output_token,
+                }),
+                GSS_S_CONTINUE_NEEDED => {
+                    Err(String::from("multi-round negotiation is not supported"))
+                }
+                _ => Err(format!(
+                    "{} ({})",
+                    status_message(major, GSS_C_GSS_CODE),
+                    status_message(minor, GSS_C_MECH_CODE)
+                )),
+            };
+            if !name.is_null() {
+                gss_release_name(&mut 0, &mut name);
+            }
+            result
+        }
+    }
+
+    unsafe fn display_name(name: GssName) -> Result<String, String> {
+        let mut minor = 0;
+        let mut buffer = GssBuffer::empty();
+        let major = gss_display_name(&mut minor, name, &mut buffer, std::ptr::null_mut());
+        if major != GSS_S_COMPLETE {
+            return Err(status_message(major, GSS_C_GSS_CODE));
+        }
+        let principal = String::from_utf8(buffer.to_vec())
+            .map_err(|_| String::from("principal name is not valid UTF-8"));
+        gss_release_buffer(&mut 0, &mut buffer);
+        principal
+    }
+
+    fn status_message(status: OmUint32, status_type: c_int) -> String {
+        let mut messages = Vec::new();
+        let mut message_context = 0;
+        loop {
+            let mut buffer = GssBuffer::empty();
+            // SAFETY: out-pointers are live locals; the buffer is released below.
+            let major = unsafe {
+                gss_display_status(
+                    &mut 0,
+                    status,
+                    status_type,
+                    std::ptr::null(),
+                    &mut message_context,
+                    &mut buffer,
+                )
+            };
+            if major != GSS_S_COMPLETE {
+                break;
+            }
+            messages.push(String::from_utf8_lossy(&buffer.to_vec()).into_owned());
+            unsafe { gss_release_buffer(&mut 0, &mut buffer) };
+            if message_context == 0 {
+                break;
+            }
+        }
+        if messages.is_empty() {
+            format!("GSS-API status {:#x}", status)
+        } else {
+            messages.join("; ")
+        }
+    }
+}
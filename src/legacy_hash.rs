@@ -0,0 +1,377 @@
+use std::num::NonZeroU32;
+
+use base64::Engine;
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+use crate::bcrypt;
+
+const MAX_PBKDF2_ITERATIONS: u32 = 10_000_000;
+const MAX_SCRYPT_LOG_N: u32 = 20;
+const MAX_SCRYPT_MEMORY_BYTES: u64 = 256 * 1024 * 1024;
+const MAX_SCRYPT_PARALLELISM: u32 = 16;
+
+// Passlib's "adapted base64" uses '.' instead of '+' and drops padding.
+const PASSLIB_BASE64: base64::engine::GeneralPurpose = base64::engine::GeneralPurpose::new(
+    &base64::alphabet::STANDARD,
+    base64::engine::GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(base64::engine::DecodePaddingMode::Indifferent),
+);
+
+enum LegacyHash {
+    Bcrypt,
+    Pbkdf2 {
+        algorithm: ring::pbkdf2::Algorithm,
+        scheme: &'static str,
+        iterations: NonZeroU32,
+        salt: Vec<u8>,
+        hash: Vec<u8>,
+    },
+    Scrypt {
+        log_n: u32,
+        r: u32,
+        p: u32,
+        salt: Vec<u8>,
+        hash: Vec<u8>,
+    },
+}
+
+impl LegacyHash {
+    fn parse(hash: &str) -> Option<Self> {
+        if bcrypt::is_hash(hash) {
+            return bcrypt::is_well_formed(hash).then_some(Self::Bcrypt);
+        }
+        if let Some(rest) = hash.strip_prefix("$scrypt$") {
+            // Passlib: $scrypt$ln=16,r=8,p=1$<salt>$<hash>
+            let mut parts = rest.split('$');
+            let mut params = parts.next()?.split(',');
+            let mut param = |name: &str| -> Option<u32> {
+                params
+                    .next()?
+                    .strip_prefix(name)?
+                    .strip_prefix('=')?
+                    .parse()
+                    .ok()
+            };
+            let (log_n, r, p) = (param("ln")?, param("r")?, param("p")?);
+            let salt = passlib_base64(parts.next()?)?;
+            let hash = passlib_base64(parts.next()?)?;
+            return Self::scrypt(log_n, r, p, salt, hash, parts.next());
+        }
+        if let Some(rest) = hash.strip_prefix("scrypt$") {
+            // Django: scrypt$<n>$<salt>$<r>$<p>$<hash>
+            let parts: Vec<&str> = rest.split('$').collect();
+            let [n, salt, r, p, hash] = parts[..] else {
+                return None;
+            };
+            let n: u32 = n.parse().ok()?;
+            if !n.is_power_of_two() {
+                return None;
+            }
+            let hash = base64::engine::general_purpose::STANDARD
+                .decode(hash)
+                .ok()?;
+            return Self::scrypt(
+                n.trailing_zeros(),
+                r.parse().ok()?,
+                p.parse().ok()?,
+                salt.as_bytes().to_vec(),
+                hash,
+                None,
+            );
+        }
+        if let Some(rest) = hash.strip_prefix("$pbkdf2") {
+            // Passlib: $pbkdf2-sha256$<iterations>$<salt>$<hash>
+            let (digest, rest) = rest.split_once('$')?;
+            let (algorithm, scheme) = pbkdf2_algorithm(digest.strip_prefix('-').unwrap_or("sha1"))?;
+            let parts: Vec<&str> = rest.split('$').collect();
+            let [iterations, salt, hash] = parts[..] else {
+                return None;
+            };
+            return Self::pbkdf2(
+                algorithm,
+                scheme,
+                iterations,
+                passlib_base64(salt)?,
+                passlib_base64(hash)?,
+            );
+        }
+        if let Some(rest) = hash.strip_prefix("pbkdf2_") {
+            // Django: pbkdf2_sha256$<iterations>$<salt>$<hash>
+            let parts: Vec<&str> = rest.split('$').collect();
+            let [digest, iterations, salt, hash] = parts[..] else {
+                return None;
+            };
+            let (algorithm, scheme) = pbkdf2_algorithm(digest)?;
+            let hash = base64::engine::general_purpose::STANDARD
+                .decode(hash)
+                .ok()?;
+            return Self::pbkdf2(
+                algorithm,
+                scheme,
+                iterations,
+                salt.as_bytes().to_vec(),
+                hash,
+            );
+        }
+        None
+    }
+
+    fn pbkdf2(
+        algorithm: ring::pbkdf2::Algorithm,
+        scheme: &'static str,
+        iterations: &str,
+        salt: Vec<u8>,
+        hash: Vec<u8>,
+    ) -> Option<Self> {
+        let iterations = NonZeroU32::new(iterations.parse().ok()?)?;
+        if iterations.get() > MAX_PBKDF2_ITERATIONS || hash.len() < 16 {
+            return None;
+        }
+        Some(Self::Pbkdf2 {
+            algorithm,
+            scheme,
+            iterations,
+            salt,
+            hash,
+        })
+    }
+
+    fn scrypt(
+        log_n: u32,
+        r: u32,
+        p: u32,
+        salt: Vec<u8>,
+        hash: Vec<u8>,
+        trailing: Option<&str>,
+    ) -> Option<Self> {
+        let memory = (128 * u64::from(r)) << log_n;
+        if trailing.is_some()
+            || !(1..=MAX_SCRYPT_LOG_N).contains(&log_n)
+            || r == 0
+            || !(1..=MAX_SCRYPT_PARALLELISM).contains(&p)
+            || memory > MAX_SCRYPT_MEMORY_BYTES
+            || hash.len() < 16
+        {
+            return None;
+        }
+        Some(Self::Scrypt {
+            log_n,
+            r,
+            p,
+            salt,
+            hash,
+        })
+    }
+
+    fn scheme(&self) -> &'static str {
+        match self {
+            Self::Bcrypt => "bcrypt",
+            Self::Pbkdf2 { scheme, .. } => scheme,
+            Self::Scrypt { .. } => "scrypt",
+        }
+    }
+}
+
+fn passlib_base64(encoded: &str) -> Option<Vec<u8>> {
+    PASSLIB_BASE64.decode(encoded.replace('.', "+")).ok()
+}
+
+fn pbkdf2_algorithm(digest: &str) -> Option<(ring::pbkdf2::Algorithm, &'static str)> {
+    match digest {
+        "sha1" => Some((ring::pbkdf2::PBKDF2_HMAC_SHA1, "pbkdf2_sha1")),
+        "sha256" => Some((ring::pbkdf2::PBKDF2_HMAC_SHA256, "pbkdf2_sha256")),
+        "sha512" => Some((ring::pbkdf2::PBKDF2_HMAC_SHA512, "pbkdf2_sha512")),
+        _ => None,
+    }
+}
+
+pub fn scheme(hash: &str) -> Option<&'static str> {
+    LegacyHash::parse(hash).map(|legacy| legacy.scheme())
+}
+
+pub fn verify(password: &[u8], hash: &str) -> bool {
+    match LegacyHash::parse(hash) {
+        Some(LegacyHash::Bcrypt) => bcrypt::verify(password, hash),
+        Some(LegacyHash::Pbkdf2 {
+            algorithm,
+            iterations,
+            salt,
+            hash,
+            ..
+        }) => ring::pbkdf2::verify(algorithm, iterations, &salt, password, &hash).is_ok(),
+        Some(LegacyHash::Scrypt {
+            log_n,
+            r,
+            p,
+            salt,
+            hash,
+        }) => {
+            let mut derived = scrypt(password, &salt, log_n, r as usize, p as usize, hash.len());
+            let matches = derived.ct_eq(&hash).into();
+            derived.zeroize();
+            matches
+        }
+        None => false,
+    }
+}
+
+fn scrypt(password: &[u8], salt: &[u8], log_n: u32, r: usize, p: usize, len: usize) -> Vec<u8> {
+    let one = NonZeroU32::new(1).unwrap();
+    let block_bytes = 128 * r;
+    let mut blocks = vec![0u8; block_bytes * p];
+    ring::pbkdf2::derive(
+        ring::pbkdf2::PBKDF2_HMAC_SHA256,
+        one,
+        salt,
+        password,
+        &mut blocks,
+    );
+    for block in blocks.chunks_exact_mut(block_bytes) {
+        ro_mix(block, r, 1 << log_n);
+    }
+    let mut derived = vec![0u8; len];
+    ring::pbkdf2::derive(
+        ring::pbkdf2::PBKDF2_HMAC_SHA256,
+        one,
+        &blocks,
+        password,
+        &mut derived,
+    );
+    blocks.zeroize();
+    derived
+}
+
+fn ro_mix(block: &mut [u8], r: usize, n: usize) {
+    let words = 32 * r;
+    let mut x: Vec<u32> = block
+        .chunks_exact(4)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        .collect();
+    let mut v = vec![0u32; words * n];
+    let mut scratch = vec![0u32; words];
+    for i in 0..n {
+        v[i * words..(i + 1) * words].copy_from_slice(&x);
+        block_mix(&x, &mut scratch, r);
+        std::mem::swap(&mut x, &mut scratch);
+    }
+    for _ in 0..n {
+        let j = (x[words - 16] as usize) & (n - 1);
+        for (word, other) in x.iter_mut().zip(&v[j * words..(j + 1) * words]) {
+            *word ^= other;
+        }
+        block_mix(&x, &mut scratch, r);
+        std::mem::swap(&mut x, &mut scratch);
+    }
+    for (bytes, word) in block.chunks_exact_mut(4).zip(&x) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    v.zeroize();
+    x.zeroize();
+    scratch.zeroize();
+}
+
+fn block_mix(input: &[u32], output: &mut [u32], r: usize) {
+    let mut x: [u32; 16] = input[(2 * r - 1) * 16..].try_into().unwrap();
+    for i in 0..2 * r {
+        for (word, other) in x.iter_mut().zip(&input[i * 16..(i + 1) * 16]) {
+            *word ^= other;
+        }
+        salsa20_8(&mut x);
+        // Even blocks go to the first half of the output, odd ones to the second.
+        let offset = (i / 2 + (i % 2) * r) * 16;
+        output[offset..offset + 16].copy_from_slice(&x);
+    }
+}
+
+fn salsa20_8(block: &mut [u32; 16]) {
+    let mut x = *block;
+    let quarter = |x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize| {
+        x[b] ^= x[a].wrapping_add(x[d]).rotate_left(7);
+        x[c] ^= x[b].wrapping_add(x[a]).rotate_left(9);
+        x[d] ^= x[c].wrapping_add(x[b]).rotate_left(13);
+        x[a] ^= x[d].wrapping_add(x[c]).rotate_left(18);
+    };
+    for _ in 0..4 {
+        quarter(&mut x, 0, 4, 8, 12);
+        quarter(&mut x, 5, 9, 13, 1);
+        quarter(&mut x, 10, 14, 2, 6);
+        quarter(&mut x, 15, 3, 7, 11);
+        quarter(&mut x, 0, 1, 2, 3);
+        quarter(&mut x, 5, 6, 7, 4);
+        quarter(&mut x, 10, 11, 8, 9);
+        quarter(&mut x, 15, 12, 13, 14);
+    }
+    for (word, mixed) in block.iter_mut().zip(x) {
+        *word = word.wrapping_add(mixed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrypt_matches_rfc_7914() {
+        let derived = scrypt(b"password", b"NaCl", 10, 8, 16, 64);
+        assert_eq!(
+            crate::aws::hex(&derived),
+            "fdbabe1c9d3472007856e7190d01e9fe7c6ad7cbc8237830e77376634b373162\
+             2eaf30d92e22a3886ff109279d9830dac727afb94a83ee6d8360cbdfa2cc0640"
+        );
+    }
+
+    #[test]
+    fn verifies_imported_hashes() {
+        let hashes = [
+            (
+                "pbkdf2_sha256",
+                "pbkdf2_sha256$1000$saltsalt$qQDPSZa3Ormyy9oK1Pu0ZLLwP2Svzmx3yu8OvDdq/J0=",
+            ),
+            (
+                "pbkdf2_sha512",
+                "$pbkdf2-sha512$1000$AQIDBAUGBwgJEBES$XwGXurK7jrtk0OLtgKb729j7F25nLAS5lgyLv7bVB\
+                 sNLEG0SPSvSwH7cGG5H6/PV31b4BaEs2jIR496F/qHkaA",
+            ),
+            (
+                "pbkdf2_sha1",
+                "$pbkdf2$1000$AQIDBAUGBwgJEBES$cVsYgHAcq.P5UKUf3gr0ncE1/Ts",
+            ),
+            (
+                "scrypt",
+                "$scrypt$ln=10,r=8,p=2$AQIDBAUGBwgJEBES$XwE7NDNLhxbv1Dg3ZssyAf0EPCW8AbpS+U1mWzPRpIE",
+            ),
+            (
+                "scrypt",
+                "scrypt$2048$djsalt$4$1$mxkQYj8L1MHLDizjS6gVHWragaLrZcjJsaqZGUav3f89ZDOXnb/Qx4j1es\
+                 yMT62PWy9exYJS+1syAthuU8N1RQ==",
+            ),
+        ];
+        for (expected_scheme, hash) in hashes {
+            assert_eq!(scheme(hash), Some(expected_scheme), "{}", hash);
+            assert!(verify(b"correct horse", hash), "{}", hash);
+            assert!(!verify(b"correct horse!", hash), "{}", hash);
+        }
+        assert_eq!(
+            scheme("$2b$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW"),
+            Some("bcrypt")
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_or_expensive_hashes() {
+        for hash in [
+            "$scrypt$ln=30,r=8,p=1$AQIDBAUGBwgJEBES$XwE7NDNLhxbv1Dg3ZssyAf0EPCW8AbpS+U1mWzPRpIE",
+            "$scrypt$r=8,ln=10,p=1$AQIDBAUGBwgJEBES$XwE7NDNLhxbv1Dg3ZssyAf0EPCW8AbpS+U1mWzPRpIE",
+            "scrypt$1000$djsalt$4$1$mxkQYj8L1MHLDizjS6gVHWragaLrZcjJsaqZGUav3f8=",
+            "pbkdf2_md5$1000$salt$qQDPSZa3Ormyy9oK1Pu0ZLLwP2Svzmx3yu8OvDdq/J0=",
+            "pbkdf2_sha256$0$salt$qQDPSZa3Ormyy9oK1Pu0ZLLwP2Svzmx3yu8OvDdq/J0=",
+            "$2b$05$short",
+            "plaintext",
+        ] {
+            assert_eq!(scheme(hash), None, "{}", hash);
+            assert!(!verify(b"correct horse", hash), "{}", hash);
+        }
+    }
+}
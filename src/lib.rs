@@ -0,0 +1,2147 @@
+use std::convert::Infallible;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::Engine;
+use tokio::sync::RwLock as TokioRwLock;
+
+mod admin;
+mod admin_alerts;
+mod archive;
+mod audit;
+mod auth;
+mod aws;
+mod backup;
+mod bcrypt;
+mod captcha;
+mod client_cert;
+mod client_gen;
+mod client_info;
+mod cluster;
+mod conditional;
+pub mod config;
+mod cors;
+mod delivery_queue;
+mod demo;
+mod deprecation;
+mod dev_proxy;
+mod device_alerts;
+mod devices;
+mod disposable_emails;
+mod email;
+mod encryption;
+mod envelope;
+mod failures;
+mod forward_auth;
+mod geoip;
+mod health;
+mod htpasswd;
+mod http_client;
+mod i18n;
+mod idempotency;
+mod idp;
+mod import;
+mod inbox;
+mod jwt;
+mod kerberos;
+mod legacy_hash;
+mod loadtest;
+mod memcached;
+mod metrics;
+mod notify;
+mod openapi;
+mod pages;
+mod pagination;
+#[cfg(feature = "pam")]
+mod pam;
+mod persistence;
+mod proof_of_work;
+mod quota;
+mod rate_limit;
+mod read_only;
+mod redis;
+mod remember_me;
+mod request_signing;
+mod risk;
+pub mod rng;
+mod scim;
+mod secrets;
+mod security_digest;
+mod security_headers;
+mod server;
+pub mod session;
+mod session_data;
+mod tarpit;
+pub mod time;
+mod users;
+mod web;
+mod websocket;
+
+use client_info::ClientInfo;
+use session::{Session, SessionEvent, SessionId};
+use tk_auth_types::{codes, errors};
+use tk_auth_types::{
+    AuthenticateForm, ErrorBody, GetSessionQuery, NewSessionQuery, NewSessionResponse,
+    RevokeAllSessionsForm, RevokeAllSessionsResponse, RevokeSessionForm, SessionList, SessionState,
+    SessionStateBatchEntry, SessionStateBatchRequest, SessionStateBatchResponse, SessionStateQuery,
+    SessionSummary, SuccessResponse, TouchSessionForm, TouchSessionResponse, UpdateSessionForm,
+};
+
+struct AppState {
+    config: config::Config,
+    sessions: session::SessionStore,
+    // Swapped wholesale when signing keys are reloaded, so readers only
+    // clone the Arc instead of holding a lock while parsing.
+    session_ids: std::sync::RwLock<Arc<session::SessionIdFormat>>,
+    // SystemRandom reads from the OS and can be shared between threads as is.
+    rng: Box<dyn rng::Rng>,
+    auth: auth::Backend,
+    users: Arc<users::UserStore>,
+    idp: idp::IdentityProviders,
+    captcha: captcha::Captcha,
+    proof_of_work: proof_of_work::ProofOfWork,
+    geoip: geoip::GeoIp,
+    remember_me: remember_me::RememberMeStore,
+    devices: devices::DeviceRegistry,
+    device_alerts: device_alerts::DeviceAlerts,
+    risk: risk::Risk,
+    disposable_emails: disposable_emails::DisposableEmails,
+    email: Arc<email::EmailSender>,
+    notifiers: notify::Notifiers,
+    deliveries: delivery_queue::DeliveryQueue,
+    inbox: Arc<inbox::Inbox>,
+    security_digest: security_digest::SecurityDigest,
+    admin_alerts: admin_alerts::AdminAlerts,
+    idempotency: idempotency::Idempotency,
+    request_signing: request_signing::RequestSigning,
+    quotas: quota::Quotas,
+    deprecations: deprecation::Deprecations,
+    read_only: read_only::ReadOnly,
+    health: health::Health,
+    tarpit: tarpit::Tarpit,
+    cluster: cluster::Cluster,
+    metrics: metrics::Metrics,
+    translations: Arc<i18n::Translations>,
+    clock: Arc<dyn time::Clock>,
+    // Only one request evicts at a time; the others wait and then usually
+    // find enough room.
+    memory_eviction: tokio::sync::Mutex<()>,
+}
+
+impl AppState {
+    fn new(
+        config: &config::Config,
+        signing_keys: secrets::SigningKeys,
+        auth: auth::Backend,
+        users: Arc<users::UserStore>,
+        idp: idp::IdentityProviders,
+        captcha: captcha::Captcha,
+        translations: Arc<i18n::Translations>,
+    ) -> Self {
+        let rng = rng::from_env();
+        let session_ids =
+            session::SessionIdFormat::from_config(&config.sessions, signing_keys, &rng);
+        let sessions = session::SessionStore::new();
+        if let Some(secs) = config.sessions.expiry.warn_before_secs {
+            sessions.warn_before_expiry(secs);
+        }
+        Self {
+            config: config.clone(),
+            sessions,
+            session_ids: std::sync::RwLock::new(Arc::new(session_ids)),
+            auth,
+            users,
+            idp,
+            captcha,
+            proof_of_work: proof_of_work::ProofOfWork::new(config.proof_of_work.as_ref(), &rng),
+            geoip: geoip::GeoIp::default(),
+            remember_me: remember_me::RememberMeStore::new(),
+            devices: devices::DeviceRegistry::new(),
+            device_alerts: device_alerts::DeviceAlerts::new(config.new_device_alerts.as_ref()),
+            risk: risk::Risk::default(),
+            disposable_emails: disposable_emails::DisposableEmails::default(),
+            email: Arc::new(email::EmailSender::default()),
+            notifiers: notify::Notifiers::default(),
+            deliveries: delivery_queue::DeliveryQueue::default(),
+            inbox: Arc::new(inbox::Inbox::default()),
+            security_digest: security_digest::SecurityDigest::new(config.security_digest.as_ref()),
+            admin_alerts: admin_alerts::AdminAlerts::default(),
+            idempotency: idempotency::Idempotency::default(),
+            request_signing: request_signing::RequestSigning::new(config.request_signing.as_ref()),
+            quotas: quota::Quotas::default(),
+            deprecations: deprecation::Deprecations::default(),
+            read_only: read_only::ReadOnly::default(),
+            health: health::Health::default(),
+            tarpit: tarpit::Tarpit::new(config.tarpit.as_ref()),
+            cluster: cluster::Cluster::new(config.cluster.as_ref(), &rng),
+            rng,
+            metrics: metrics::Metrics::default(),
+            translations,
+            clock: Arc::new(time::SystemClock),
+            memory_eviction: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    fn session_ids(&self) -> Arc<session::SessionIdFormat> {
+        self.session_ids.read().unwrap().clone()
+    }
+}
+
+fn bytes_response(status: u16, body: axum::body::Bytes) -> axum::response::Response {
+    let mut response = axum::response::Response::new(axum::body::Body::from(body));
+    *response.status_mut() = http::StatusCode::from_u16(status).unwrap();
+    response.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+// Serializes straight into the buffer that becomes the body, so callers with
+// a Serialize type don't need to go through serde_json::Value or a String.
+fn serialized_response(status: u16, body: &impl serde::Serialize) -> axum::response::Response {
+    bytes_response(status, serde_json::to_vec(body).unwrap().into())
+}
+
+fn json_response(status: u16, body: serde_json::Value) -> axum::response::Response {
+    serialized_response(status, &body)
+}
+
+fn error_response(status: u16, message: &str) -> axum::response::Response {
+    serialized_response(
+        status,
+        &ErrorBody {
+            error: message.into(),
+        },
+    )
+}
+
+// For errors whose message varies but that clients tell apart by the
+// envelope's code.
+fn coded_error_response(
+    status: u16,
+    code: &'static str,
+    message: &str,
+) -> axum::response::Response {
+    let mut response = error_response(status, message);
+    response.extensions_mut().insert(envelope::Code(code));
+    response
+}
+
+// For errors with values in their message, so the message can be
+// translated before they're filled in.
+fn message_error_response(status: u16, message: i18n::Message) -> axum::response::Response {
+    let mut response = error_response(status, &message.english());
+    response.extensions_mut().insert(message);
+    response
+}
+
+// Errors answered on hot paths, such as every forward_auth request without a
+// session, with their bodies written out ahead of time.
+struct StaticError {
+    status: u16,
+    body: &'static [u8],
+}
+
+impl StaticError {
+    fn response(&self) -> axum::response::Response {
+        bytes_response(self.status, axum::body::Bytes::from_static(self.body))
+    }
+}
+
+const MALFORMED_SESSION_ID: StaticError = StaticError {
+    status: 400,
+    body: br#"{"error":"malformed session id"}"#,
+};
+const SESSION_BOUND_ELSEWHERE: StaticError = StaticError {
+    status: 403,
+    body: br#"{"error":"session is bound to a different client"}"#,
+};
+const SESSION_NOT_AUTHENTICATED: StaticError = StaticError {
+    status: 400,
+    body: br#"{"error":"session is not authenticated"}"#,
+};
+const NOT_AUTHENTICATED: StaticError = StaticError {
+    status: 401,
+    body: br#"{"error":"not authenticated"}"#,
+};
+const OVERLOADED: StaticError = StaticError {
+    status: 503,
+    body: br#"{"error":"server is overloaded, try again later"}"#,
+};
+const SESSION_STORE_FULL: StaticError = StaticError {
+    status: 503,
+    body: br#"{"error":"the session store is full, try again later"}"#,
+};
+
+fn session_binding_allows(state: &AppState, session: &Session, client: &ClientInfo) -> bool {
+    let binding = &state.config.session_binding;
+    let ip_changed = binding.ip
+        && !client_info::same_network(
+            session.ip,
+            client.ip,
+            binding.ipv4_prefix_len,
+            binding.ipv6_prefix_len,
+        );
+    let user_agent_changed = binding.user_agent && session.user_agent != client.user_agent;
+    if !ip_changed && !user_agent_changed {
+        return true;
+    }
+
+    let denied = binding.mode == config::BindingMode::Deny;
+    audit::record(audit::AuditEvent::SessionBindingMismatch {
+        user: session.user.as_deref(),
+        session_ip: session.ip,
+        request_ip: client.ip,
+        ip_changed,
+        user_agent_changed,
+        denied,
+    });
+    !denied
+}
+
+async fn lookup_session(
+    state: &AppState,
+    session_id: &str,
+    client: &ClientInfo,
+) -> Result<(SessionId, Arc<TokioRwLock<Session>>), axum::response::Response> {
+    let parsed_id = state.session_ids().parse(session_id);
+    let parsed_id = match parsed_id {
+        Ok(parsed_id) => parsed_id,
+        Err(err) => {
+            if err == session::ParseError::InvalidSignature {
+                println!("Rejected session id with invalid signature");
+            }
+            return Err(MALFORMED_SESSION_ID.response());
+        }
+    };
+    state.sessions.refresh(&parsed_id).await;
+    let session = state.sessions.get(&parsed_id);
+    match session {
+        Some(session) => {
+            if state.config.session_binding.mode != config::BindingMode::Off
+                && !session_binding_allows(state, &(*session.read().await), client)
+            {
+                return Err(SESSION_BOUND_ELSEWHERE.response());
+            }
+            let now = state.clock.now_secs();
+            if !state.sessions.access(&parsed_id, &session, now).await {
+                session.write().await.publish(SessionEvent::Revoked);
+                state.sessions.remove(&parsed_id);
+                println!("Session {} expired", session_id);
+                let mut response = message_error_response(
+                    400,
+                    i18n::Message {
+                        template: "session {session_id} expired",
+                        args: vec![("session_id", session_id.to_string())],
+                    },
+                );
+                response
+                    .extensions_mut()
+                    .insert(envelope::Code(codes::SESSION_EXPIRED));
+                return Err(response);
+            }
+            Ok((parsed_id, session))
+        }
+        None => Err(session_not_found(session_id)),
+    }
+}
+
+// Looks a session up for someone other than its holder: no binding check
+// against the caller, and the idle timeout doesn't slide.
+async fn peek_session(state: &AppState, session_id: &str) -> Option<Arc<TokioRwLock<Session>>> {
+    let parsed_id = state.session_ids().parse(session_id).ok()?;
+    state.sessions.refresh(&parsed_id).await;
+    let session = state.sessions.get(&parsed_id)?;
+    let expired = session.read().await.is_expired(state.clock.now_secs());
+    (!expired).then_some(session)
+}
+
+fn session_not_found(session_id: &str) -> axum::response::Response {
+    let mut response = message_error_response(
+        400,
+        i18n::Message {
+            template: "session {session_id} doesn't exist",
+            args: vec![("session_id", session_id.to_string())],
+        },
+    );
+    response
+        .extensions_mut()
+        .insert(envelope::Code(codes::SESSION_NOT_FOUND));
+    response
+}
+
+async fn expiry_policy(state: &AppState, user: Option<&str>) -> config::ExpiryPolicy {
+    let expiry = &state.config.sessions.expiry;
+    let roles = match user {
+        Some(user) if !expiry.roles.is_empty() => state.users.roles(user).await,
+        _ => Vec::new(),
+    };
+    expiry.policy_for(&roles)
+}
+
+// Files the session under the user when it signs in as them, making room
+// for it or returning false when sessions.on_limit says to reject it
+// instead.
+async fn admit_session(state: &AppState, user: &str, id: &SessionId) -> bool {
+    let Some(max_per_user) = state.config.sessions.max_per_user else {
+        return true;
+    };
+    state.sessions.load_user(user).await;
+    let evict = state.config.sessions.on_limit != config::SessionLimitMode::Reject;
+    let now = state.clock.now_secs();
+    let Some(evicted) = state.sessions.admit(user, id, max_per_user, evict, now) else {
+        return false;
+    };
+    for id in evicted {
+        if let Some(session) = state.sessions.remove(&id) {
+            session.write().await.publish(SessionEvent::Revoked);
+            println!(
+                "Evicted session {} of {} over the session limit",
+                String::from(&id),
+                user
+            );
+        }
+    }
+    true
+}
+
+// Makes room for `bytes` more in the session store, or returns false when
+// sessions.on_memory_budget says to reject them instead.
+async fn enforce_memory_budget(state: &AppState, bytes: usize) -> bool {
+    let Some(budget) = state.config.sessions.memory_budget_bytes else {
+        return true;
+    };
+    let fits = || state.sessions.memory_bytes() + bytes <= budget;
+    if fits() {
+        return true;
+    }
+    if state.config.sessions.on_memory_budget == config::MemoryBudgetMode::Reject {
+        state
+            .metrics
+            .memory_budget_rejections
+            .fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+    let _evicting = state.memory_eviction.lock().await;
+    if fits() {
+        return true;
+    }
+    // Evicting down to 90% of the budget keeps a flood of new sessions from
+    // scanning the whole store for every one of them.
+    let target = (budget - budget / 10).saturating_sub(bytes);
+    let mut candidates = Vec::new();
+    for (id, session) in state.sessions.snapshot() {
+        let key = {
+            let session_locked = session.read().await;
+            (session_locked.authenticated, session_locked.last_seen)
+        };
+        candidates.push((key, id, session));
+    }
+    candidates.sort_by_key(|(key, ..)| *key);
+    let mut evicted = 0;
+    for (_, id, session) in candidates {
+        if state.sessions.memory_bytes() <= target {
+            break;
+        }
+        if state.sessions.remove(&id).is_some() {
+            session.write().await.publish(SessionEvent::Revoked);
+            evicted += 1;
+        }
+    }
+    state
+        .metrics
+        .memory_budget_evictions
+        .fetch_add(evicted, Ordering::Relaxed);
+    println!("Evicted {} sessions over the memory budget", evicted);
+    if fits() {
+        return true;
+    }
+    state
+        .metrics
+        .memory_budget_rejections
+        .fetch_add(1, Ordering::Relaxed);
+    false
+}
+
+const EXPIRY_SWEEP_INTERVAL_SECS: u64 = 60;
+
+// Returns the number of sessions removed.
+async fn sweep_expired(state: &AppState) -> usize {
+    let started = std::time::Instant::now();
+    let now = state.clock.now_secs();
+    let expired = state.sessions.remove_expired(now).await;
+    for session in &expired {
+        session.write().await.publish(SessionEvent::Revoked);
+    }
+    // Only a notice, the session itself doesn't change.
+    for (session, expires_at) in state.sessions.expiring(now).await {
+        let _ = session
+            .read()
+            .await
+            .events
+            .send(SessionEvent::ExpiringSoon { expires_at });
+    }
+    state.metrics.expiry_sweeps.record(started, true);
+    state
+        .metrics
+        .expired_sessions
+        .fetch_add(expired.len() as u64, std::sync::atomic::Ordering::Relaxed);
+    if !expired.is_empty() {
+        println!("Removed {} expired sessions", expired.len());
+    }
+    expired.len()
+}
+
+fn spawn_expiry_sweep(state: Arc<AppState>) {
+    if !state.config.sessions.expiry.is_enabled() {
+        return;
+    }
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(EXPIRY_SWEEP_INTERVAL_SECS)).await;
+            sweep_expired(&state).await;
+        }
+    });
+}
+
+async fn post_new_session(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    certificate: Option<axum::Extension<client_cert::ClientCertificate>>,
+    axum::extract::Query(query): axum::extract::Query<NewSessionQuery>,
+) -> axum::response::Response {
+    let now = state.clock.now_secs();
+    if state.proof_of_work.required(now) {
+        let error = match (&query.pow_challenge, &query.pow_nonce) {
+            (Some(challenge), Some(nonce)) if state.proof_of_work.verify(challenge, nonce, now) => {
+                None
+            }
+            (Some(_), Some(_)) => Some(errors::PROOF_OF_WORK_INVALID),
+            _ => Some(errors::PROOF_OF_WORK_REQUIRED),
+        };
+        if let Some(error) = error {
+            return serialized_response(
+                429,
+                &state.proof_of_work.challenge(&state.rng, error, now),
+            );
+        }
+    }
+    let mut session = Session::new(client.ip, client.user_agent, now);
+    if !enforce_memory_budget(&state, session.approx_bytes()).await {
+        return SESSION_STORE_FULL.response();
+    }
+    let session_id = state.session_ids().generate(&state.rng);
+    let client_auth = state
+        .config
+        .tls
+        .as_ref()
+        .and_then(|tls| tls.client_auth.as_ref());
+    let mut certificate_user = certificate
+        .zip(client_auth)
+        .and_then(|(axum::Extension(certificate), client_auth)| certificate.user(client_auth));
+    if let Some(user) = &certificate_user {
+        if !state.users.is_active(user).await {
+            println!("Ignoring client certificate of deactivated user {}", user);
+            certificate_user = None;
+        } else {
+            match state.geoip.login(user, client.ip, now) {
+                Ok(location) if admit_session(&state, user, &session_id).await => {
+                    session.location = location
+                }
+                Ok(_) => {
+                    println!("Ignoring client certificate of {}, too many sessions", user);
+                    certificate_user = None;
+                }
+                Err(_) => {
+                    println!("Ignoring client certificate of {}, impossible travel", user);
+                    certificate_user = None;
+                }
+            }
+        }
+    }
+    let policy = expiry_policy(&state, certificate_user.as_deref()).await;
+    session.set_expiry(policy, state.clock.now_secs());
+    if let Some(user) = &certificate_user {
+        session.user = Some(user.clone());
+        session.authenticated = true;
+        session.authenticated_at = Some(session.created);
+        device_alerts::seen(&state, user, &session, session.created).await;
+    }
+    state.sessions.insert(session_id.clone(), session);
+
+    println!("Created new session {}", String::from(&session_id));
+    let id_base64 = String::from(&session_id);
+    let mut response =
+        axum::response::IntoResponse::into_response(axum::response::Json(NewSessionResponse {
+            id_base64: id_base64.clone(),
+        }));
+    if let Some(user) = certificate_user {
+        println!(
+            "Session {} authenticated as {} by client certificate",
+            id_base64, user
+        );
+        forward_auth::add_session_cookie(&state, &mut response, &id_base64);
+    }
+    response
+}
+
+async fn post_authenticate(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    axum::extract::Form(form): axum::extract::Form<AuthenticateForm>,
+) -> axum::response::Response {
+    let session = match lookup_session(&state, &form.session_id, &client).await {
+        Ok((_, session)) => session,
+        Err(response) => return response,
+    };
+    // Authenticating again as the same user is how clients re-authenticate
+    // before sensitive operations such as revoking all sessions.
+    {
+        let session_locked = session.read().await;
+        if session_locked.authenticated && session_locked.user.as_deref() != Some(&form.user) {
+            return message_error_response(
+                400,
+                i18n::Message {
+                    template: "session {session_id} already authenticated",
+                    args: vec![("session_id", form.session_id.to_string())],
+                },
+            );
+        }
+    }
+    let now = state.clock.now_secs();
+    if !state.tarpit.wait(Some(&form.user), client.ip, now).await {
+        return error_response(429, errors::TOO_MANY_ATTEMPTS);
+    }
+    let step_up = match risk::assess(&state, &form.user, &client, now).await {
+        config::RiskDecision::Allow => false,
+        config::RiskDecision::StepUp => true,
+        config::RiskDecision::Deny => return error_response(403, errors::RISK_DENIED),
+    };
+    match state
+        .captcha
+        .check(
+            &form.user,
+            client.ip,
+            form.captcha_token.as_deref(),
+            step_up,
+            now,
+        )
+        .await
+    {
+        captcha::Check::Passed => {}
+        captcha::Check::Required => return state.captcha.challenge(errors::CAPTCHA_REQUIRED),
+        captcha::Check::Invalid => return state.captcha.challenge(errors::CAPTCHA_INVALID),
+        captcha::Check::Unavailable => {
+            return error_response(503, "captcha verification unavailable")
+        }
+    }
+    match state.auth.verify(&form.user, &form.password).await {
+        Ok(true) => {
+            state.captcha.record_success(&form.user);
+            state.tarpit.record_success(&form.user);
+        }
+        Ok(false) => {
+            audit::record(audit::AuditEvent::AuthenticationFailed {
+                user: &form.user,
+                ip: client.ip,
+            });
+            state
+                .captcha
+                .record_failure(Some(&form.user), client.ip, now);
+            state
+                .tarpit
+                .record_failure(Some(&form.user), client.ip, now);
+            state.admin_alerts.failed_sign_in(now);
+            return error_response(401, "invalid user or password");
+        }
+        Err(err) => {
+            println!("Authentication backend error: {}", err);
+            return error_response(503, "authentication backend unavailable");
+        }
+    }
+
+    let outcome = authenticate(&state, &session, &form.session_id, form.user.clone(), false).await;
+    let remember_token = match (&outcome, &state.config.remember_me) {
+        (Ok(_), Some(remember_me)) if form.remember_me => {
+            let now = state.clock.now_secs();
+            let user_agent = client.user_agent.as_deref();
+            state.security_digest.record(
+                &form.user,
+                now,
+                security_digest::Activity::RememberMeToken {
+                    device_name: devices::device_name(user_agent),
+                },
+            );
+            Some(
+                state
+                    .remember_me
+                    .issue(
+                        &state.rng,
+                        &form.user,
+                        &devices::fingerprint(user_agent),
+                        remember_me.lifetime_secs,
+                        now,
+                    )
+                    .await,
+            )
+        }
+        _ => None,
+    };
+    authentication_response(&state, &form.session_id, outcome, remember_token)
+}
+
+enum Authentication {
+    Fresh { id_base64: String },
+    Refreshed,
+}
+
+// Moves a session to a new id, as the old one may be known to someone
+// else. Listeners on the old id are told it's revoked.
+fn move_to_new_id(
+    state: &AppState,
+    mut session_locked: tokio::sync::RwLockWriteGuard<'_, Session>,
+    rotated: Session,
+    session_id: &str,
+    new_id: SessionId,
+) -> String {
+    session_locked.publish(SessionEvent::Revoked);
+    drop(session_locked);
+    let id_base64 = String::from(&new_id);
+    state.sessions.insert(new_id, rotated);
+    if let Ok(old_id) = state.session_ids().parse(session_id) {
+        state.sessions.remove(&old_id);
+    }
+    println!("Session {} rotated to {}", session_id, id_base64);
+    id_base64
+}
+
+// With always_rotate, the session gets a new id whatever
+// sessions.rotate_on_authentication says, for flows whose session id the
+// caller chose rather than the browser that signs in.
+async fn authenticate(
+    state: &AppState,
+    session: &TokioRwLock<Session>,
+    session_id: &str,
+    user: String,
+    always_rotate: bool,
+) -> Result<Authentication, axum::response::Response> {
+    if !state.users.is_active(&user).await {
+        return Err(error_response(403, "user is deactivated"));
+    }
+    let policy = expiry_policy(state, Some(&user)).await;
+    let now = state.clock.now_secs();
+    let mut session_locked = session.write().await;
+    if session_locked.authenticated && session_locked.user.as_deref() == Some(&user) {
+        session_locked.location = state
+            .geoip
+            .login(&user, session_locked.ip, now)
+            .map_err(geoip::ImpossibleTravel::response)?;
+        session_locked.authenticated_at = Some(now);
+        device_alerts::seen(state, &user, &session_locked, now).await;
+        println!("Session {} re-authenticated as {}", session_id, user);
+        if always_rotate {
+            let rotated = session_locked.rotate();
+            let new_id = state.session_ids().generate(&state.rng);
+            let id_base64 = move_to_new_id(state, session_locked, rotated, session_id, new_id);
+            return Ok(Authentication::Fresh { id_base64 });
+        }
+        if let Ok(id) = state.session_ids().parse(session_id) {
+            state.sessions.persist(&id, &session_locked);
+        }
+        return Ok(Authentication::Refreshed);
+    }
+    if session_locked.authenticated {
+        return Err(message_error_response(
+            400,
+            i18n::Message {
+                template: "session {session_id} already authenticated",
+                args: vec![("session_id", session_id.to_string())],
+            },
+        ));
+    }
+    session_locked.location = state
+        .geoip
+        .login(&user, session_locked.ip, now)
+        .map_err(geoip::ImpossibleTravel::response)?;
+
+    // Rotating the id on login keeps an id planted by someone else
+    // (session fixation) from ending up authenticated.
+    let rotate = always_rotate || state.config.sessions.rotate_on_authentication;
+    let id = if rotate {
+        state.session_ids().generate(&state.rng)
+    } else {
+        state
+            .session_ids()
+            .parse(session_id)
+            .map_err(|_| MALFORMED_SESSION_ID.response())?
+    };
+    if !admit_session(state, &user, &id).await {
+        return Err(error_response(409, "user has too many sessions"));
+    }
+    device_alerts::seen(state, &user, &session_locked, now).await;
+    let mut rotated = rotate.then(|| session_locked.rotate());
+    let authenticated = rotated.as_mut().unwrap_or(&mut session_locked);
+    authenticated.authenticated = true;
+    authenticated.authenticated_at = Some(now);
+    authenticated.user = Some(user.clone());
+    authenticated.set_expiry(policy, now);
+    authenticated.publish(SessionEvent::Authenticated { user });
+    let deadline = authenticated.expires_at;
+
+    let mut id_base64 = session_id.to_string();
+    if let Some(rotated) = rotated {
+        id_base64 = move_to_new_id(state, session_locked, rotated, session_id, id);
+    } else {
+        state.sessions.persist(&id, &session_locked);
+        // The user's expiry policy may be stricter than the anonymous one.
+        if let Some(deadline) = deadline {
+            state.sessions.schedule_expiry(&id, deadline);
+        }
+    }
+    Ok(Authentication::Fresh { id_base64 })
+}
+
+fn authentication_response(
+    state: &AppState,
+    session_id: &str,
+    outcome: Result<Authentication, axum::response::Response>,
+    remember_token: Option<String>,
+) -> axum::response::Response {
+    let (id_base64, message) = match outcome {
+        Ok(Authentication::Refreshed) => (
+            session_id.to_string(),
+            format!("session {} re-authenticated", session_id),
+        ),
+        Ok(Authentication::Fresh { id_base64 }) => {
+            let message = format!("session {} authenticated succesfully", id_base64);
+            (id_base64, message)
+        }
+        Err(response) => return response,
+    };
+    let mut body = serde_json::json!({
+        "success": message,
+        "id_base64": id_base64,
+    });
+    if let Some(remember_token) = &remember_token {
+        body["remember_token"] = serde_json::json!(remember_token);
+    }
+    let mut response = json_response(200, body);
+    forward_auth::add_session_cookie(state, &mut response, &id_base64);
+    if let (Some(remember_me), Some(remember_token)) = (&state.config.remember_me, remember_token) {
+        response.headers_mut().append(
+            http::header::SET_COOKIE,
+            remember_me::set_cookie_header(remember_me, &remember_token),
+        );
+    }
+    response
+}
+
+async fn authenticate_session(
+    state: &AppState,
+    session: &TokioRwLock<Session>,
+    session_id: &str,
+    user: String,
+    always_rotate: bool,
+) -> axum::response::Response {
+    let outcome = authenticate(state, session, session_id, user, always_rotate).await;
+    authentication_response(state, session_id, outcome, None)
+}
+
+#[derive(serde::Deserialize)]
+struct NegotiateQuery {
+    session_id: String,
+}
+
+async fn get_negotiate(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    axum::extract::Query(query): axum::extract::Query<NegotiateQuery>,
+    headers: axum::http::HeaderMap,
+) -> axum::response::Response {
+    let Some(kerberos_config) = &state.config.kerberos else {
+        return error_response(404, "negotiate authentication is not enabled");
+    };
+    let session = match lookup_session(&state, &query.session_id, &client).await {
+        Ok((_, session)) => session,
+        Err(response) => return response,
+    };
+    if session.read().await.authenticated {
+        return message_error_response(
+            400,
+            i18n::Message {
+                template: "session {session_id} already authenticated",
+                args: vec![("session_id", query.session_id.to_string())],
+            },
+        );
+    }
+
+    let challenge = |message: &str| {
+        let mut response = error_response(401, message);
+        response.headers_mut().insert(
+            http::header::WWW_AUTHENTICATE,
+            http::HeaderValue::from_static("Negotiate"),
+        );
+        response
+    };
+    let token = headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Negotiate "))
+        .and_then(|token| {
+            base64::engine::general_purpose::STANDARD
+                .decode(token.trim())
+                .ok()
+        });
+    let Some(token) = token else {
+        return challenge("negotiate authentication required");
+    };
+    let accepted = match tokio::task::spawn_blocking(move || kerberos::accept(&token)).await {
+        Ok(Ok(accepted)) => accepted,
+        Ok(Err(err)) => {
+            println!("Rejected negotiate token: {}", err);
+            return challenge("negotiate authentication failed");
+        }
+        Err(err) => {
+            println!("Negotiate worker failed: {}", err);
+            return error_response(500, "negotiate authentication failed");
+        }
+    };
+    let Some(user) = kerberos::user_for_principal(kerberos_config, &accepted.principal) else {
+        audit::record(audit::AuditEvent::AuthenticationFailed {
+            user: &accepted.principal,
+            ip: client.ip,
+        });
+        return error_response(403, "principal is not allowed to log in");
+    };
+
+    // The caller picked the session id, and may have handed it out, so it
+    // never becomes the authenticated one.
+    let mut response = authenticate_session(&state, &session, &query.session_id, user, true).await;
+    if !accepted.output_token.is_empty() {
+        let value = format!(
+            "Negotiate {}",
+            base64::engine::general_purpose::STANDARD.encode(&accepted.output_token)
+        );
+        response.headers_mut().insert(
+            http::header::WWW_AUTHENTICATE,
+            http::HeaderValue::from_str(&value).unwrap(),
+        );
+    }
+    response
+}
+
+async fn post_revoke_session(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    axum::extract::Form(form): axum::extract::Form<RevokeSessionForm>,
+) -> axum::response::Response {
+    let (session_id, session) = match lookup_session(&state, &form.session_id, &client).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    state.sessions.remove(&session_id);
+    session.write().await.publish(SessionEvent::Revoked);
+
+    println!("Revoked session {}", form.session_id);
+
+    serialized_response(
+        200,
+        &SuccessResponse {
+            success: format!("session {} revoked", form.session_id),
+        },
+    )
+}
+
+async fn patch_session(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    axum::extract::Form(form): axum::extract::Form<UpdateSessionForm>,
+) -> axum::response::Response {
+    let description = form.description.trim();
+    if !session::valid_description(description) {
+        return message_error_response(
+            400,
+            i18n::Message {
+                template: "description must be 1 to {max} characters without control characters",
+                args: vec![("max", session::MAX_DESCRIPTION_CHARS.to_string())],
+            },
+        );
+    }
+    let (session_id, session) = match lookup_session(&state, &form.session_id, &client).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    let mut session_locked = session.write().await;
+    session_locked.description = description.to_string();
+    state
+        .sessions
+        .resize(&session_id, session_locked.approx_bytes());
+    session_locked.publish(SessionEvent::Updated);
+    state.sessions.persist(&session_id, &session_locked);
+    serialized_response(
+        200,
+        &SuccessResponse {
+            success: format!("session {} updated", form.session_id),
+        },
+    )
+}
+
+async fn get_my_sessions(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    axum::extract::Query(query): axum::extract::Query<GetSessionQuery>,
+    axum::extract::Query(page): axum::extract::Query<pagination::PageQuery>,
+) -> axum::response::Response {
+    let (session_id, session) = match lookup_session(&state, &query.session_id, &client).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    let user = {
+        let session_locked = session.read().await;
+        match (&session_locked.user, session_locked.authenticated) {
+            (Some(user), true) => user.clone(),
+            _ => return SESSION_NOT_AUTHENTICATED.response(),
+        }
+    };
+    let now = state.clock.now_secs();
+    let mut sessions = Vec::new();
+    for (id, other) in state.sessions.sessions_of(&user).await {
+        let other = other.read().await;
+        if !other.authenticated || other.is_expired(now) {
+            continue;
+        }
+        let key = (other.created, id.list_key());
+        sessions.push((
+            key,
+            SessionSummary {
+                current: id == session_id,
+                description: other.description.clone(),
+                ip: other.ip,
+                user_agent: other.user_agent.clone(),
+                country: other.location.country.clone(),
+                asn: other.location.asn,
+                created: other.created,
+                last_seen: other.last_seen,
+                expires_at: other.expires_at,
+            },
+        ));
+    }
+    // Newest first by default.
+    let (sessions, next_cursor) = match pagination::page(
+        sessions,
+        |(key, _)| key.clone(),
+        &page,
+        pagination::Order::Desc,
+    ) {
+        Ok(page) => page,
+        Err(err) => return error_response(400, &err),
+    };
+    let sessions = sessions.into_iter().map(|(_, summary)| summary).collect();
+    serialized_response(
+        200,
+        &SessionList {
+            sessions,
+            next_cursor,
+        },
+    )
+}
+
+async fn post_revoke_all_sessions(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    axum::extract::Form(form): axum::extract::Form<RevokeAllSessionsForm>,
+) -> axum::response::Response {
+    let (session_id, session) = match lookup_session(&state, &form.session_id, &client).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    let (user, authenticated_at) = {
+        let session_locked = session.read().await;
+        match (&session_locked.user, session_locked.authenticated_at) {
+            (Some(user), Some(authenticated_at)) if session_locked.authenticated => {
+                (user.clone(), authenticated_at)
+            }
+            _ => return SESSION_NOT_AUTHENTICATED.response(),
+        }
+    };
+    if state.clock.now_secs().saturating_sub(authenticated_at)
+        > state.config.sessions.reauth_max_age_secs
+    {
+        return error_response(403, "authenticate again before revoking all sessions");
+    }
+
+    let mut revoked = 0;
+    for (id, other) in state.sessions.sessions_of(&user).await {
+        if id == session_id && !form.include_current {
+            continue;
+        }
+        if state.sessions.remove(&id).is_some() {
+            other.write().await.publish(SessionEvent::Revoked);
+            revoked += 1;
+        }
+    }
+    let forgotten = state.remember_me.revoke_user(&user).await;
+    state
+        .cluster
+        .publish(cluster::Invalidation::UserRevoked { user: user.clone() });
+    println!(
+        "Revoked {} sessions and {} remember-me tokens of {}",
+        revoked, forgotten, user
+    );
+    serialized_response(
+        200,
+        &RevokeAllSessionsResponse {
+            success: format!("{} sessions revoked", revoked),
+            revoked,
+        },
+    )
+}
+
+// lookup_session already moves the idle timeout forward, so all this has to
+// do is report the result.
+async fn post_touch_session(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    axum::extract::Form(form): axum::extract::Form<TouchSessionForm>,
+) -> axum::response::Response {
+    let session = match lookup_session(&state, &form.session_id, &client).await {
+        Ok((_, session)) => session,
+        Err(response) => return response,
+    };
+    let expires_at = session.read().await.expires_at;
+    serialized_response(200, &TouchSessionResponse { expires_at })
+}
+
+const LONG_POLL_DEFAULT_TIMEOUT_SECS: u64 = 30;
+const LONG_POLL_MAX_TIMEOUT_SECS: u64 = 60;
+const MAX_BATCH_SESSION_STATES: usize = 100;
+
+// Weak comparison (RFC 9110, 8.8.3.2): the ETags are weak, since the same
+// state goes out in envelopes with different request ids.
+fn etag_matches(headers: &http::HeaderMap, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.strip_prefix("W/").unwrap_or(tag).to_string();
+    let etag = opaque(etag);
+    headers
+        .get_all(http::header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|candidate| candidate.trim())
+        .any(|candidate| candidate == "*" || opaque(candidate) == etag)
+}
+
+fn session_state_response(
+    session: &Session,
+    headers: &http::HeaderMap,
+) -> axum::response::Response {
+    let etag = session.etag();
+    if etag_matches(headers, &etag) {
+        return axum::response::Response::builder()
+            .status(304)
+            .header(http::header::ETAG, etag)
+            .body(axum::body::Body::empty())
+            .unwrap();
+    }
+    axum::response::Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .header(http::header::ETAG, etag)
+        .body(axum::body::Body::from(serde_json::to_vec(session).unwrap()))
+        .unwrap()
+}
+
+async fn get_session_state(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    axum::extract::Query(query): axum::extract::Query<SessionStateQuery>,
+    headers: http::HeaderMap,
+) -> axum::response::Response {
+    let (session_id, session) = match lookup_session(&state, &query.session_id, &client).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+
+    let mut events = {
+        let session_locked = session.read().await;
+        if !query.wait || !etag_matches(&headers, &session_locked.etag()) {
+            return session_state_response(&session_locked, &headers);
+        }
+        session_locked.events.subscribe()
+    };
+
+    let route_timeout = state.config.limits.route_timeout_secs("/api/session_state");
+    let timeout = query
+        .timeout
+        .unwrap_or(LONG_POLL_DEFAULT_TIMEOUT_SECS)
+        .min(LONG_POLL_MAX_TIMEOUT_SECS)
+        .min(route_timeout.saturating_sub(1));
+    let _ = tokio::time::timeout(Duration::from_secs(timeout), events.recv()).await;
+
+    if !state.sessions.contains(&session_id) {
+        return session_not_found(&query.session_id);
+    }
+    let session_locked = session.read().await;
+    session_state_response(&session_locked, &headers)
+}
+
+// For services that check many sessions at once. The caller is a backend,
+// not the sessions' holder, so lookups neither check binding nor touch, and
+// it has to prove itself with the admin token or a signed request.
+async fn post_session_state_batch(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: http::HeaderMap,
+    body: axum::body::Bytes,
+) -> axum::response::Response {
+    if state.config.admin.is_none() {
+        return error_response(404, "the admin API is not enabled");
+    }
+    if !admin::authorized(&state, &headers) {
+        return error_response(401, "invalid admin token");
+    }
+    let request: SessionStateBatchRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(err) => {
+            return message_error_response(
+                400,
+                i18n::Message {
+                    template: "invalid batch: {error}",
+                    args: vec![("error", err.to_string())],
+                },
+            )
+        }
+    };
+    if request.session_ids.len() > MAX_BATCH_SESSION_STATES {
+        return message_error_response(
+            400,
+            i18n::Message {
+                template: "at most {max} session ids per batch",
+                args: vec![("max", MAX_BATCH_SESSION_STATES.to_string())],
+            },
+        );
+    }
+    let mut sessions = Vec::with_capacity(request.session_ids.len());
+    for session_id in &request.session_ids {
+        let entry = match peek_session(&state, session_id).await {
+            Some(session) => {
+                let session = session.read().await;
+                SessionStateBatchEntry {
+                    found: true,
+                    state: Some(SessionState {
+                        user: session.user.clone(),
+                        description: session.description.clone(),
+                        authenticated: session.authenticated,
+                        expires_at: session.expires_at,
+                    }),
+                }
+            }
+            None => SessionStateBatchEntry {
+                found: false,
+                state: None,
+            },
+        };
+        sessions.push(entry);
+    }
+    serialized_response(200, &SessionStateBatchResponse { sessions })
+}
+
+async fn get_session_stream(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    axum::extract::Query(query): axum::extract::Query<GetSessionQuery>,
+) -> axum::response::Response {
+    let session = match lookup_session(&state, &query.session_id, &client).await {
+        Ok((_, session)) => session,
+        Err(response) => return response,
+    };
+    let events = session.read().await.events.subscribe();
+
+    let stream =
+        futures_util::stream::unfold(Some((session, events, true)), |stream_state| async move {
+            let (session, mut events, initial) = stream_state?;
+            if !initial {
+                match events.recv().await {
+                    Ok(SessionEvent::Revoked)
+                    | Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                        let event = axum::response::sse::Event::default()
+                            .event("revoked")
+                            .data("{}");
+                        return Some((Ok::<_, Infallible>(event), None));
+                    }
+                    Ok(_) | Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {}
+                }
+            }
+            let data = serde_json::to_string(&(*session.read().await)).unwrap();
+            let event = axum::response::sse::Event::default()
+                .event("session")
+                .data(data);
+            Some((Ok(event), Some((session, events, false))))
+        });
+
+    axum::response::IntoResponse::into_response(
+        axum::response::sse::Sse::new(stream).keep_alive(axum::response::sse::KeepAlive::default()),
+    )
+}
+
+async fn get_session_events(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    axum::extract::Query(query): axum::extract::Query<GetSessionQuery>,
+    mut request: axum::extract::Request,
+) -> axum::response::Response {
+    let mut events = match lookup_session(&state, &query.session_id, &client).await {
+        Ok((_, session)) => session.read().await.events.subscribe(),
+        Err(response) => return response,
+    };
+    let (response, on_upgrade) = match websocket::accept(&mut request) {
+        Ok(accepted) => accepted,
+        Err(message) => return error_response(400, message),
+    };
+
+    tokio::spawn(async move {
+        let upgraded = match on_upgrade.await {
+            Ok(upgraded) => upgraded,
+            Err(_) => return,
+        };
+        let (mut reader, mut writer) = tokio::io::split(hyper_util::rt::TokioIo::new(upgraded));
+        let (incoming_tx, mut incoming_rx) = tokio::sync::mpsc::channel(8);
+        let reader_task = tokio::spawn(async move {
+            while let Ok(message) = websocket::read_message(&mut reader).await {
+                if incoming_tx.send(message).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => {
+                        let revoked = matches!(event, SessionEvent::Revoked);
+                        let text = serde_json::to_string(&event).unwrap();
+                        if websocket::write_text(&mut writer, &text).await.is_err() {
+                            break;
+                        }
+                        if revoked {
+                            let _ = websocket::write_close(&mut writer, 1000).await;
+                            break;
+                        }
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                        let _ = websocket::write_close(&mut writer, 1001).await;
+                        break;
+                    }
+                },
+                message = incoming_rx.recv() => match message {
+                    Some(websocket::Message::Ping(payload)) => {
+                        if websocket::write_pong(&mut writer, &payload).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(websocket::Message::Close) | None => {
+                        let _ = websocket::write_close(&mut writer, 1000).await;
+                        break;
+                    }
+                    Some(_) => {}
+                },
+            }
+        }
+        reader_task.abort();
+    });
+
+    response
+}
+
+async fn get_openapi() -> axum::response::Json<serde_json::Value> {
+    axum::response::Json(openapi::document())
+}
+
+async fn get_docs(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+) -> axum::response::Response {
+    if state.config.api_docs.is_none() {
+        return error_response(404, "the API docs are not enabled");
+    }
+    axum::response::IntoResponse::into_response(axum::response::Html(openapi::SWAGGER_UI_HTML))
+}
+
+async fn get_docs_file(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    axum::extract::Path(file): axum::extract::Path<String>,
+) -> axum::response::Response {
+    let Some(api_docs) = &state.config.api_docs else {
+        return error_response(404, "the API docs are not enabled");
+    };
+    let Some((name, content_type)) = openapi::SWAGGER_UI_FILES
+        .iter()
+        .find(|(name, _)| *name == file)
+    else {
+        return error_response(404, "no such file");
+    };
+    match tokio::fs::read(api_docs.swagger_ui_dir.join(name)).await {
+        Ok(contents) => axum::response::Response::builder()
+            .status(200)
+            .header(http::header::CONTENT_TYPE, *content_type)
+            .body(axum::body::Body::from(contents))
+            .unwrap(),
+        Err(err) => {
+            println!("Failed to read {}: {}", name, err);
+            error_response(500, "failed to read the file")
+        }
+    }
+}
+
+// The value of `--name value` or `--name=value`.
+fn option_from_args(name: &str) -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let Some(rest) = arg
+            .strip_prefix("--")
+            .and_then(|arg| arg.strip_prefix(name))
+        else {
+            continue;
+        };
+        if rest.is_empty() {
+            return args.next();
+        }
+        if let Some(value) = rest.strip_prefix('=') {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+fn config_path_from_args() -> Option<PathBuf> {
+    option_from_args("config")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("TK_AUTH_CONFIG").map(PathBuf::from))
+}
+
+pub async fn run() -> io::Result<()> {
+    println!("Hello, world!");
+
+    let mut config = match config_path_from_args() {
+        Some(path) => config::Config::load(&path)?,
+        None => config::Config::default(),
+    };
+    let seed_demo_data = std::env::args().any(|arg| arg == "--seed-demo-data");
+    if seed_demo_data {
+        demo::prepare(&mut config)?;
+    }
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("import-users") => return import::run_cli(&config, args).await,
+        Some("export") => return archive::run_export(&config, args).await,
+        Some("import") => return archive::run_import(&config, args).await,
+        Some("restore") => return backup::run_restore(&config, args).await,
+        Some("loadtest") => return loadtest::run_cli(&config, args).await,
+        Some("generate-client") => return client_gen::run_cli(args),
+        Some("send-test-email") => return email::run_cli(&config, args).await,
+        _ => {}
+    }
+    let security_headers = security_headers::SecurityHeaders::from_config(&config.security_headers)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let cors_layer =
+        cors::layer(&config.cors).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let dev_proxy = option_from_args("dev-proxy")
+        .map(|url| dev_proxy::DevProxy::new(&url))
+        .transpose()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let tls_acceptor = match &config.tls {
+        Some(tls) => Some(server::tls_acceptor(tls)?),
+        None => None,
+    };
+
+    let secret_provider = secrets::SecretProvider::new(&config.secrets)?;
+    let signing_keys = secret_provider
+        .load_signing_keys(&config.sessions)
+        .await
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let encryption = encryption::Encryption::from_config(&config, &secret_provider).await?;
+    let users = Arc::new(users::UserStore::open(&config.users, encryption.clone())?);
+    if seed_demo_data {
+        demo::seed(&users).await?;
+    }
+    let auth = auth::Backend::from_config(&config.auth_backend, &users)?;
+    if let Some(kerberos) = &config.kerberos {
+        kerberos::register_keytab(kerberos)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    }
+    let idp = idp::IdentityProviders::new(&config)?;
+    let captcha = captcha::Captcha::new(&config)?;
+    let backup = backup::Backup::from_config(&config)?;
+    let translations = Arc::new(i18n::Translations::load(&config.i18n)?);
+    let mut app_state = AppState::new(
+        &config,
+        signing_keys.clone(),
+        auth,
+        users,
+        idp,
+        captcha,
+        translations,
+    );
+    app_state.geoip = geoip::GeoIp::new(config.geoip.as_ref())?;
+    app_state.risk = risk::Risk::new(&config)?;
+    app_state.disposable_emails =
+        disposable_emails::DisposableEmails::new(config.disposable_emails.as_ref())?;
+    app_state.email = Arc::new(email::EmailSender::new(&config)?);
+    app_state.inbox = Arc::new(inbox::Inbox::new(&config)?);
+    app_state.notifiers =
+        notify::Notifiers::new(&config, app_state.email.clone(), app_state.inbox.clone())?;
+    app_state.deliveries = delivery_queue::DeliveryQueue::new(&config)?;
+    app_state.admin_alerts = admin_alerts::AdminAlerts::new(&config)?;
+    // Restored ids only verify with the keys they were signed with, not a
+    // random one of this run.
+    if config.sessions.persistence.is_some() && signing_keys.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "sessions.persistence needs sessions.signing_keys",
+        ));
+    }
+    if config.sessions.memcached.is_some() && signing_keys.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "sessions.memcached needs sessions.signing_keys, the same on every instance",
+        ));
+    }
+    let memcached = config
+        .sessions
+        .memcached
+        .as_ref()
+        .map(|memcached| Arc::new(memcached::Memcached::new(memcached, encryption.clone())));
+    let journal = persistence::Journal::open(
+        &config,
+        encryption,
+        &app_state.sessions,
+        app_state.clock.now_secs(),
+    )?;
+    let app_state = Arc::new(app_state);
+    persistence::spawn(app_state.clone(), journal);
+    memcached::spawn(app_state.clone(), memcached);
+    secrets::spawn_refresh(secret_provider, app_state.clone(), signing_keys);
+    spawn_expiry_sweep(app_state.clone());
+    cluster::spawn(app_state.clone());
+    security_digest::spawn(app_state.clone());
+    delivery_queue::spawn(app_state.clone());
+    backup::spawn(app_state.clone(), backup);
+    health::spawn(app_state.clone());
+    let api_routes: [(&str, axum::routing::MethodRouter<Arc<AppState>>); 48] = [
+        ("/api/new_session", axum::routing::post(post_new_session)),
+        ("/api/authenticate", axum::routing::post(post_authenticate)),
+        ("/api/negotiate", axum::routing::get(get_negotiate)),
+        (
+            "/api/forward_auth",
+            axum::routing::get(forward_auth::get_forward_auth),
+        ),
+        (
+            "/api/revoke_session",
+            axum::routing::post(post_revoke_session),
+        ),
+        ("/api/session", axum::routing::patch(patch_session)),
+        (
+            "/api/session/resume",
+            axum::routing::post(remember_me::post_resume_session),
+        ),
+        (
+            "/api/session/touch",
+            axum::routing::post(post_touch_session),
+        ),
+        ("/api/session_state", axum::routing::get(get_session_state)),
+        (
+            "/api/session_state/batch",
+            axum::routing::post(post_session_state_batch),
+        ),
+        ("/api/sessions/mine", axum::routing::get(get_my_sessions)),
+        (
+            "/api/sessions/revoke_all",
+            axum::routing::post(post_revoke_all_sessions),
+        ),
+        (
+            "/api/sessions/batch",
+            axum::routing::post(admin::post_batch_sessions),
+        ),
+        ("/api/devices", axum::routing::get(devices::get_devices)),
+        (
+            "/api/notification_preferences",
+            axum::routing::get(notify::get_notification_preferences)
+                .put(notify::put_notification_preferences),
+        ),
+        (
+            "/api/notifications",
+            axum::routing::get(inbox::get_notifications),
+        ),
+        (
+            "/api/notifications/:id/read",
+            axum::routing::post(inbox::post_notification_read),
+        ),
+        (
+            "/api/notifications/read_all",
+            axum::routing::post(inbox::post_notifications_read_all),
+        ),
+        (
+            "/api/devices/:id",
+            axum::routing::delete(devices::delete_device),
+        ),
+        (
+            "/api/session_data/:key",
+            axum::routing::get(session_data::get_session_data)
+                .put(session_data::put_session_data)
+                .delete(session_data::delete_session_data),
+        ),
+        (
+            "/api/session_stream",
+            axum::routing::get(get_session_stream),
+        ),
+        (
+            "/api/session_events",
+            axum::routing::get(get_session_events),
+        ),
+        ("/api/idp/providers", axum::routing::get(idp::get_providers)),
+        (
+            "/api/idp/:provider/login",
+            axum::routing::get(idp::get_login),
+        ),
+        (
+            "/api/idp/:provider/callback",
+            axum::routing::get(idp::get_callback),
+        ),
+        (
+            "/api/idp/identities",
+            axum::routing::get(idp::get_identities),
+        ),
+        ("/api/idp/unlink", axum::routing::post(idp::post_unlink)),
+        ("/api/branding", axum::routing::get(pages::get_branding)),
+        ("/api/openapi.json", axum::routing::get(get_openapi)),
+        ("/api/usage", axum::routing::get(quota::get_usage)),
+        ("/readyz", axum::routing::get(health::get_readyz)),
+        (
+            "/login",
+            axum::routing::get(pages::get_login).post(pages::post_login),
+        ),
+        (
+            "/device_alert",
+            axum::routing::get(device_alerts::get_device_alert)
+                .post(device_alerts::post_device_alert),
+        ),
+        ("/api/docs", axum::routing::get(get_docs)),
+        ("/api/docs/:file", axum::routing::get(get_docs_file)),
+        (
+            "/api/admin/import_users",
+            axum::routing::post(admin::post_import_users),
+        ),
+        (
+            "/api/admin/password_migration",
+            axum::routing::get(admin::get_password_migration),
+        ),
+        (
+            "/api/admin/metrics",
+            axum::routing::get(metrics::get_metrics),
+        ),
+        (
+            "/api/admin/maintenance",
+            axum::routing::post(admin::post_maintenance),
+        ),
+        (
+            "/api/admin/read_only",
+            axum::routing::get(read_only::get_read_only).put(read_only::put_read_only),
+        ),
+        (
+            "/api/admin/deliveries",
+            axum::routing::get(delivery_queue::get_deliveries),
+        ),
+        (
+            "/api/admin/deprecations",
+            axum::routing::get(deprecation::get_deprecations),
+        ),
+        (
+            "/api/admin/deliveries/:id/retry",
+            axum::routing::post(delivery_queue::post_retry_delivery),
+        ),
+        (
+            "/scim/v2/Users",
+            axum::routing::get(scim::get_users).post(scim::post_users),
+        ),
+        (
+            "/scim/v2/Users/:id",
+            axum::routing::get(scim::get_user)
+                .put(scim::put_user)
+                .patch(scim::patch_user)
+                .delete(scim::delete_user),
+        ),
+        (
+            "/scim/v2/Groups",
+            axum::routing::get(scim::get_groups).post(scim::post_groups),
+        ),
+        (
+            "/scim/v2/Groups/:id",
+            axum::routing::get(scim::get_group)
+                .put(scim::put_group)
+                .patch(scim::patch_group)
+                .delete(scim::delete_group),
+        ),
+        (
+            "/scim/v2/ServiceProviderConfig",
+            axum::routing::get(scim::get_service_provider_config),
+        ),
+    ];
+    if let Some(deprecation) = config.deprecations.iter().find(|deprecation| {
+        !api_routes
+            .iter()
+            .any(|(path, _)| *path == deprecation.route)
+    }) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("deprecations: there's no route {}", deprecation.route),
+        ));
+    }
+    let started_at = app_state.clock.now_secs();
+    let mut app = axum::Router::new();
+    for (path, mut method_router) in api_routes {
+        if idempotency::ROUTES.contains(&path) {
+            method_router = method_router.layer(axum::middleware::from_fn_with_state(
+                app_state.clone(),
+                idempotency::layer,
+            ));
+        }
+        if conditional::ROUTES.contains(&path) || conditional::STATIC_ROUTES.contains(&path) {
+            let last_modified = conditional::STATIC_ROUTES
+                .contains(&path)
+                .then_some(started_at);
+            method_router = method_router.layer(axum::middleware::from_fn_with_state(
+                Arc::new(conditional::Route { last_modified }),
+                conditional::layer,
+            ));
+        }
+        // Like quota::layer, inside request_signing::layer.
+        if config
+            .deprecations
+            .iter()
+            .any(|deprecation| deprecation.route == path)
+        {
+            method_router = method_router.layer(axum::middleware::from_fn_with_state(
+                app_state.clone(),
+                deprecation::layer,
+            ));
+        }
+        // Inside request_signing::layer, which tells which key signed.
+        if quota::is_enabled(&config) {
+            method_router = method_router.layer(axum::middleware::from_fn_with_state(
+                app_state.clone(),
+                quota::layer,
+            ));
+        }
+        if config.request_signing.is_some() && request_signing::is_signed_route(path) {
+            method_router = method_router.layer(axum::middleware::from_fn_with_state(
+                app_state.clone(),
+                request_signing::layer,
+            ));
+        }
+        // Outermost, so replayed responses carry the current quota.
+        if rate_limit::is_limited_route(path, &config) {
+            method_router = method_router.layer(axum::middleware::from_fn_with_state(
+                app_state.clone(),
+                rate_limit::layer,
+            ));
+        }
+        // Around even that, so refused requests don't count anywhere.
+        if read_only::ROUTES.contains(&path) {
+            method_router = method_router.layer(axum::middleware::from_fn_with_state(
+                app_state.clone(),
+                read_only::layer,
+            ));
+        }
+        app = app.route(
+            path,
+            server::limit_route(path, method_router, &config.limits),
+        );
+    }
+    let precompressed = config.web.precompressed;
+    let mut web_files = tower_http::services::ServeDir::new(web::ROOT);
+    if precompressed {
+        web_files = web_files.precompressed_br().precompressed_gzip();
+    }
+    let web = tower::ServiceBuilder::new()
+        .layer(axum::middleware::from_fn_with_state(
+            Arc::new(config.web.clone()),
+            web::cache_headers,
+        ))
+        .layer(tower_http::timeout::TimeoutLayer::new(Duration::from_secs(
+            config.limits.request_timeout_secs,
+        )))
+        .service(web_files.fallback(tower::service_fn(move |request| {
+            web::spa_fallback(request, precompressed)
+        })));
+    let mut app = match dev_proxy {
+        Some(dev_proxy) => {
+            println!(
+                "Proxying /web to the frontend dev server at {}",
+                dev_proxy.authority()
+            );
+            let proxy = axum::routing::any(dev_proxy::proxy).with_state(Arc::new(dev_proxy));
+            app.route("/web", proxy.clone())
+                .route("/web/", proxy.clone())
+                .route("/web/*path", proxy)
+        }
+        None => app.nest_service("/web", web),
+    };
+    if let Some(max_in_flight) = config.limits.max_in_flight_requests {
+        app = app.layer(axum::middleware::from_fn_with_state(
+            Arc::new(server::LoadShedding::new(
+                max_in_flight,
+                config.limits.retry_after_secs,
+            )),
+            server::shed_load,
+        ));
+    }
+    app = app.layer(axum::middleware::from_fn_with_state(
+        app_state.clone(),
+        envelope::layer,
+    ));
+    let app = app
+        .layer(axum::middleware::from_fn_with_state(
+            Arc::new(security_headers),
+            security_headers::layer,
+        ))
+        .layer(cors_layer)
+        .with_state(app_state);
+
+    let listener = tokio::net::TcpListener::bind(&config.listen).await?;
+    println!(
+        "Listening on {}{}",
+        config.listen,
+        if tls_acceptor.is_some() { " (TLS)" } else { "" }
+    );
+    server::serve(listener, app, tls_acceptor, config.limits).await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+    use time::Clock;
+
+    #[test]
+    fn static_errors_match_error_response() {
+        for (error, message) in [
+            (&MALFORMED_SESSION_ID, errors::MALFORMED_SESSION_ID),
+            (&SESSION_BOUND_ELSEWHERE, errors::SESSION_BOUND_ELSEWHERE),
+            (
+                &SESSION_NOT_AUTHENTICATED,
+                errors::SESSION_NOT_AUTHENTICATED,
+            ),
+            (&NOT_AUTHENTICATED, errors::NOT_AUTHENTICATED),
+            (&OVERLOADED, errors::OVERLOADED),
+            (&SESSION_STORE_FULL, errors::SESSION_STORE_FULL),
+        ] {
+            let expected = serde_json::to_vec(&ErrorBody {
+                error: message.into(),
+            })
+            .unwrap();
+            assert_eq!(error.body, &expected[..], "{}", message);
+        }
+    }
+
+    // Session is serialized directly on the hot paths, so this keeps it in
+    // step with the SessionState that clients parse.
+    #[test]
+    fn session_serializes_as_session_state() {
+        let mut session = Session::new(IpAddr::from([127, 0, 0, 1]), None, 100);
+        session.user = Some("zed".to_string());
+        session.authenticated = true;
+        session.expires_at = Some(200);
+        let state: tk_auth_types::SessionState =
+            serde_json::from_slice(&serde_json::to_vec(&session).unwrap()).unwrap();
+        assert_eq!(
+            state,
+            tk_auth_types::SessionState {
+                user: Some("zed".to_string()),
+                description: session.description.clone(),
+                authenticated: true,
+                expires_at: Some(200),
+            }
+        );
+    }
+
+    fn test_state(config: &config::Config) -> (AppState, Arc<time::ManualClock>) {
+        let users = Arc::new(users::UserStore::open(&config.users, None).unwrap());
+        let auth = auth::Backend::from_config(&config.auth_backend, &users).unwrap();
+        let idp = idp::IdentityProviders::new(config).unwrap();
+        let captcha = captcha::Captcha::new(config).unwrap();
+        let translations = Arc::new(i18n::Translations::load(&config.i18n).unwrap());
+        let mut state = AppState::new(config, Vec::new(), auth, users, idp, captcha, translations);
+        let clock = Arc::new(time::ManualClock::new(1000));
+        state.clock = clock.clone();
+        (state, clock)
+    }
+
+    fn test_client() -> ClientInfo {
+        ClientInfo {
+            ip: IpAddr::from([127, 0, 0, 1]),
+            user_agent: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn sessions_expire_by_the_clock() {
+        let (state, clock) = test_state(&config::Config::default());
+        let client = test_client();
+        let mut session = Session::new(client.ip, None, clock.now_secs());
+        let policy = config::ExpiryPolicy {
+            idle_timeout_secs: Some(100),
+            absolute_lifetime_secs: Some(250),
+        };
+        session.set_expiry(policy, clock.now_secs());
+        let id = state.session_ids().generate(&state.rng);
+        let id_base64 = String::from(&id);
+        state.sessions.insert(id, session);
+
+        // Each lookup slides the idle timeout, up to the absolute lifetime.
+        for _ in 0..2 {
+            clock.advance(99);
+            assert!(lookup_session(&state, &id_base64, &client).await.is_ok());
+        }
+        clock.advance(52);
+        assert!(lookup_session(&state, &id_base64, &client).await.is_err());
+        assert_eq!(state.sessions.count(), 0);
+    }
+
+    #[tokio::test]
+    async fn caller_chosen_session_ids_stay_unauthenticated() {
+        let mut config = config::Config::default();
+        config.sessions.rotate_on_authentication = false;
+        let (state, clock) = test_state(&config);
+        let client = test_client();
+        let id = state.session_ids().generate(&state.rng);
+        let id_base64 = String::from(&id);
+        state
+            .sessions
+            .insert(id, Session::new(client.ip, None, clock.now_secs()));
+
+        // As get_negotiate does with the session id in its query.
+        let (_, session) = lookup_session(&state, &id_base64, &client).await.unwrap();
+        let response =
+            authenticate_session(&state, &session, &id_base64, String::from("alice"), true).await;
+        assert_eq!(response.status(), 200);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let new_id = body["id_base64"].as_str().unwrap();
+        assert_ne!(new_id, id_base64);
+
+        assert!(lookup_session(&state, &id_base64, &client).await.is_err());
+        assert!(!session.read().await.authenticated);
+        let (_, session) = lookup_session(&state, new_id, &client).await.unwrap();
+        assert!(session.read().await.authenticated);
+    }
+
+    #[tokio::test]
+    async fn batch_lookups_leave_sessions_alone() {
+        let mut config = config::Config::default();
+        config.admin = Some(config::AdminConfig {
+            bearer_token: zeroize::Zeroizing::new(String::from("batch-test-token-0123")),
+        });
+        let (state, clock) = test_state(&config);
+        let client = test_client();
+        let mut session = Session::new(client.ip, None, clock.now_secs());
+        let policy = config::ExpiryPolicy {
+            idle_timeout_secs: Some(100),
+            absolute_lifetime_secs: None,
+        };
+        session.set_expiry(policy, clock.now_secs());
+        let expires_at = session.expires_at;
+        let id = state.session_ids().generate(&state.rng);
+        let id_base64 = String::from(&id);
+        state.sessions.insert(id, session);
+        let state = Arc::new(state);
+
+        let request = |token: &'static str| {
+            let body = serde_json::json!({ "session_ids": [&id_base64] }).to_string();
+            let mut headers = http::HeaderMap::new();
+            headers.insert(
+                http::header::AUTHORIZATION,
+                http::HeaderValue::from_static(token),
+            );
+            post_session_state_batch(axum::extract::State(state.clone()), headers, body.into())
+        };
+        // Only backends may look sessions up for others.
+        assert_eq!(request("Bearer wrong").await.status(), 401);
+        let batch = || async {
+            let response = request("Bearer batch-test-token-0123").await;
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()["sessions"][0].clone()
+        };
+        clock.advance(50);
+        let entry = batch().await;
+        assert_eq!(entry["found"], true);
+        assert_eq!(entry["state"]["expires_at"], serde_json::json!(expires_at));
+        clock.advance(51);
+        assert_eq!(batch().await["found"], false);
+    }
+
+    #[tokio::test]
+    async fn sweeps_warn_before_sessions_expire() {
+        let mut config = config::Config::default();
+        config.sessions.expiry.idle_timeout_secs = Some(100);
+        config.sessions.expiry.warn_before_secs = Some(30);
+        let (state, clock) = test_state(&config);
+        let mut session = Session::new(test_client().ip, None, clock.now_secs());
+        session.set_expiry(config.sessions.expiry.policy_for(&[]), clock.now_secs());
+        let mut events = session.events.subscribe();
+        let id = state.session_ids().generate(&state.rng);
+        state.sessions.insert(id, session);
+
+        // What /api/session_events sends on.
+        clock.advance(60);
+        assert_eq!(sweep_expired(&state).await, 0);
+        assert!(events.try_recv().is_err());
+        clock.advance(10);
+        sweep_expired(&state).await;
+        let event = serde_json::to_value(events.try_recv().unwrap()).unwrap();
+        assert_eq!(
+            event,
+            serde_json::json!({ "event": "expiring_soon", "expires_at": 1100 })
+        );
+        sweep_expired(&state).await;
+        assert!(events.try_recv().is_err());
+        clock.advance(30);
+        assert_eq!(sweep_expired(&state).await, 1);
+        assert!(matches!(events.try_recv(), Ok(SessionEvent::Revoked)));
+    }
+
+    #[tokio::test]
+    async fn session_limit_holds_for_concurrent_sign_ins() {
+        let mut config = config::Config::default();
+        config.sessions.max_per_user = Some(2);
+        let (state, clock) = test_state(&config);
+        let state = Arc::new(state);
+        let mut sign_ins = Vec::new();
+        for _ in 0..5 {
+            let session = Session::new(test_client().ip, None, clock.now_secs());
+            let id = state.session_ids().generate(&state.rng);
+            let id_base64 = String::from(&id);
+            let session = state.sessions.insert(id, session);
+            let state = state.clone();
+            sign_ins.push(tokio::spawn(async move {
+                authenticate(&state, &session, &id_base64, String::from("alice"), false)
+                    .await
+                    .is_ok()
+            }));
+        }
+        let mut admitted = 0;
+        for sign_in in sign_ins {
+            admitted += sign_in.await.unwrap() as usize;
+        }
+        assert_eq!(admitted, 2);
+        assert_eq!(state.sessions.sessions_of("alice").await.len(), 2);
+        assert!(state.sessions.sessions_of("bob").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn session_limit_evicts_the_oldest_sign_in() {
+        let mut config = config::Config::default();
+        config.sessions.max_per_user = Some(2);
+        config.sessions.on_limit = config::SessionLimitMode::EvictOldest;
+        let (state, clock) = test_state(&config);
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let session = Session::new(test_client().ip, None, clock.now_secs());
+            let id = state.session_ids().generate(&state.rng);
+            let id_base64 = String::from(&id);
+            let session = state.sessions.insert(id, session);
+            let outcome =
+                authenticate(&state, &session, &id_base64, String::from("alice"), false).await;
+            assert!(outcome.is_ok());
+            ids.push(id_base64);
+            clock.advance(1);
+        }
+        let mut left: Vec<_> = state
+            .sessions
+            .sessions_of("alice")
+            .await
+            .iter()
+            .map(|(id, _)| String::from(id))
+            .collect();
+        left.sort();
+        let mut newest = ids[1..].to_vec();
+        newest.sort();
+        assert_eq!(left, newest);
+        let first = state.session_ids().parse(&ids[0]).unwrap();
+        assert!(!state.sessions.contains(&first));
+    }
+
+    #[tokio::test]
+    async fn touches_that_move_the_deadline_are_persisted() {
+        let (state, clock) = test_state(&config::Config::default());
+        let (changes, recorded) = std::sync::mpsc::channel();
+        state.sessions.record_changes(changes);
+        let client = test_client();
+        let mut session = Session::new(client.ip, None, clock.now_secs());
+        let policy = config::ExpiryPolicy {
+            idle_timeout_secs: Some(100),
+            absolute_lifetime_secs: None,
+        };
+        session.set_expiry(policy, clock.now_secs());
+        let id = state.session_ids().generate(&state.rng);
+        let id_base64 = String::from(&id);
+        state.sessions.insert(id, session);
+        assert!(matches!(recorded.try_recv(), Ok(session::Change::Put(..))));
+
+        clock.advance(5);
+        lookup_session(&state, &id_base64, &client).await.unwrap();
+        assert!(recorded.try_recv().is_err());
+        clock.advance(45);
+        lookup_session(&state, &id_base64, &client).await.unwrap();
+        match recorded.try_recv() {
+            Ok(session::Change::Update(_, saved)) => assert_eq!(saved.expires_at(), Some(1150)),
+            _ => panic!("the touch wasn't persisted"),
+        }
+    }
+
+    #[tokio::test]
+    async fn touched_sessions_get_a_new_etag() {
+        let (state, clock) = test_state(&config::Config::default());
+        let client = test_client();
+        let mut session = Session::new(client.ip, None, clock.now_secs());
+        let policy = config::ExpiryPolicy {
+            idle_timeout_secs: Some(100),
+            absolute_lifetime_secs: None,
+        };
+        session.set_expiry(policy, clock.now_secs());
+        let id = state.session_ids().generate(&state.rng);
+        let id_base64 = String::from(&id);
+        state.sessions.insert(id, session);
+        let state = Arc::new(state);
+
+        let session_state = |etag: Option<http::HeaderValue>| {
+            let mut headers = http::HeaderMap::new();
+            headers.extend(etag.map(|etag| (http::header::IF_NONE_MATCH, etag)));
+            let query = SessionStateQuery {
+                session_id: id_base64.clone(),
+                wait: false,
+                timeout: None,
+            };
+            get_session_state(
+                axum::extract::State(state.clone()),
+                client.clone(),
+                axum::extract::Query(query),
+                headers,
+            )
+        };
+        let etag = session_state(None).await.headers()[http::header::ETAG].clone();
+        assert_eq!(session_state(Some(etag.clone())).await.status(), 304);
+        // Moves the deadline from 1100 to 1150.
+        clock.advance(50);
+        let response = session_state(Some(etag.clone())).await;
+        assert_eq!(response.status(), 200);
+        assert_ne!(response.headers()[http::header::ETAG], etag);
+    }
+}
@@ -0,0 +1,391 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+use crate::config;
+
+const MAX_RESPONSE_HEADERS: usize = 32;
+const MAX_RESPONSE_BYTES: usize = 64 * 1024;
+// In the mixed phase, one ticket in this many starts a new session and the
+// rest read an existing one.
+const MIXED_CREATE_EVERY: u64 = 10;
+
+struct Options {
+    host: String,
+    port: u16,
+    sessions: u64,
+    rps: u64,
+    duration_secs: u64,
+    connections: usize,
+    credentials: Option<(String, String)>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Operation {
+    Create,
+    Authenticate,
+    Read,
+}
+
+impl Operation {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Create => "create",
+            Self::Authenticate => "authenticate",
+            Self::Read => "read",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    latencies: Vec<Duration>,
+    statuses: BTreeMap<u16, u64>,
+    failures: u64,
+}
+
+// Session ids created so far, read round-robin.
+#[derive(Default)]
+struct Pool {
+    ids: Vec<String>,
+    next_read: usize,
+}
+
+struct Shared {
+    options: Options,
+    pool: Mutex<Pool>,
+    stats: Mutex<BTreeMap<Operation, Stats>>,
+}
+
+enum Ticket {
+    // Creates a session, and authenticates it when credentials are given.
+    NewSession,
+    Read,
+}
+
+// A keep-alive HTTP/1.1 connection to the target, reopened after errors.
+struct Connection {
+    stream: Option<tokio::net::TcpStream>,
+}
+
+impl Connection {
+    async fn request(
+        &mut self,
+        shared: &Shared,
+        method: &str,
+        path: &str,
+        body: &str,
+    ) -> io::Result<(u16, Vec<u8>)> {
+        let result = self.request_once(shared, method, path, body).await;
+        if result.is_err() {
+            self.stream = None;
+        }
+        result
+    }
+
+    async fn request_once(
+        &mut self,
+        shared: &Shared,
+        method: &str,
+        path: &str,
+        body: &str,
+    ) -> io::Result<(u16, Vec<u8>)> {
+        let options = &shared.options;
+        if self.stream.is_none() {
+            let stream =
+                tokio::net::TcpStream::connect((options.host.as_str(), options.port)).await?;
+            stream.set_nodelay(true)?;
+            self.stream = Some(stream);
+        }
+        let stream = self.stream.as_mut().unwrap();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}:{}\r\n\
+             Content-Type: application/x-www-form-urlencoded\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            options.host,
+            options.port,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await?;
+
+        let invalid =
+            |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+        let mut raw = Vec::new();
+        let mut buffer = [0u8; 8192];
+        loop {
+            let read = stream.read(&mut buffer).await?;
+            if read == 0 {
+                return Err(invalid("connection closed"));
+            }
+            raw.extend_from_slice(&buffer[..read]);
+            let mut headers = [httparse::EMPTY_HEADER; MAX_RESPONSE_HEADERS];
+            let mut response = httparse::Response::new(&mut headers);
+            let head_len = match response.parse(&raw) {
+                Ok(httparse::Status::Complete(head_len)) => head_len,
+                Ok(httparse::Status::Partial) if raw.len() < MAX_RESPONSE_BYTES => continue,
+                _ => return Err(invalid("malformed response")),
+            };
+            // The API always sends a Content-Length.
+            let content_length: usize = response
+                .headers
+                .iter()
+                .find(|header| header.name.eq_ignore_ascii_case("content-length"))
+                .and_then(|header| std::str::from_utf8(header.value).ok()?.parse().ok())
+                .ok_or_else(|| invalid("response without Content-Length"))?;
+            let status = response.code.unwrap_or(0);
+            while raw.len() < head_len + content_length {
+                if raw.len() > MAX_RESPONSE_BYTES {
+                    return Err(invalid("response too large"));
+                }
+                let read = stream.read(&mut buffer).await?;
+                if read == 0 {
+                    return Err(invalid("connection closed"));
+                }
+                raw.extend_from_slice(&buffer[..read]);
+            }
+            return Ok((status, raw[head_len..head_len + content_length].to_vec()));
+        }
+    }
+}
+
+// A status of None means the request failed without a response.
+fn record(shared: &Shared, operation: Operation, scheduled: Instant, status: Option<u16>) {
+    let mut stats = shared.stats.lock().unwrap();
+    let stats = stats.entry(operation).or_default();
+    stats.latencies.push(scheduled.elapsed());
+    match status {
+        Some(status) => *stats.statuses.entry(status).or_default() += 1,
+        None => stats.failures += 1,
+    }
+}
+
+async fn new_session(shared: &Shared, connection: &mut Connection, scheduled: Instant) {
+    let created = connection
+        .request(shared, "POST", "/api/new_session", "")
+        .await;
+    let status = created.as_ref().ok().map(|(status, _)| *status);
+    record(shared, Operation::Create, scheduled, status);
+    let id = match created {
+        Ok((200, body)) => serde_json::from_slice::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|body| Some(body["data"]["id_base64"].as_str()?.to_string())),
+        _ => None,
+    };
+    let Some(id) = id else {
+        return;
+    };
+    if let Some((user, password)) = &shared.options.credentials {
+        let body = form_urlencoded::Serializer::new(String::new())
+            .append_pair("session_id", &id)
+            .append_pair("user", user)
+            .append_pair("password", password)
+            .finish();
+        let started = Instant::now();
+        let authenticated = connection
+            .request(shared, "POST", "/api/authenticate", &body)
+            .await
+            .ok()
+            .map(|(status, _)| status);
+        record(shared, Operation::Authenticate, started, authenticated);
+    }
+    shared.pool.lock().unwrap().ids.push(id);
+}
+
+async fn read_session(shared: &Shared, connection: &mut Connection, scheduled: Instant) {
+    let id = {
+        let mut pool = shared.pool.lock().unwrap();
+        if pool.ids.is_empty() {
+            None
+        } else {
+            pool.next_read = (pool.next_read + 1) % pool.ids.len();
+            Some(pool.ids[pool.next_read].clone())
+        }
+    };
+    let Some(id) = id else {
+        return new_session(shared, connection, scheduled).await;
+    };
+    let path = format!("/api/session_state?session_id={}", id);
+    let result = connection
+        .request(shared, "GET", &path, "")
+        .await
+        .ok()
+        .map(|(status, _)| status);
+    record(shared, Operation::Read, scheduled, result);
+}
+
+async fn worker(
+    shared: Arc<Shared>,
+    tickets: Arc<tokio::sync::Mutex<mpsc::UnboundedReceiver<(Ticket, Instant)>>>,
+) {
+    let mut connection = Connection { stream: None };
+    loop {
+        let Some((ticket, scheduled)) = tickets.lock().await.recv().await else {
+            return;
+        };
+        match ticket {
+            Ticket::NewSession => new_session(&shared, &mut connection, scheduled).await,
+            Ticket::Read => read_session(&shared, &mut connection, scheduled).await,
+        }
+    }
+}
+
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    sorted[(sorted.len() * percent / 100).min(sorted.len() - 1)]
+}
+
+fn report(shared: &Shared, elapsed: Duration) {
+    let stats = shared.stats.lock().unwrap();
+    let total: usize = stats.values().map(|stats| stats.latencies.len()).sum();
+    println!(
+        "{} requests in {:.1}s, {:.0} per second",
+        total,
+        elapsed.as_secs_f64(),
+        total as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "{:<13} {:>8} {:>9} {:>9} {:>9} {:>9}  statuses",
+        "operation", "count", "p50 ms", "p90 ms", "p99 ms", "max ms"
+    );
+    for (operation, stats) in stats.iter() {
+        let mut latencies = stats.latencies.clone();
+        latencies.sort();
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        let mut statuses: Vec<String> = stats
+            .statuses
+            .iter()
+            .map(|(status, count)| format!("{}: {}", status, count))
+            .collect();
+        if stats.failures > 0 {
+            statuses.push(format!("failed: {}", stats.failures));
+        }
+        println!(
+            "{:<13} {:>8} {:>9.2} {:>9.2} {:>9.2} {:>9.2}  {}",
+            operation.name(),
+            latencies.len(),
+            ms(percentile(&latencies, 50)),
+            ms(percentile(&latencies, 90)),
+            ms(percentile(&latencies, 99)),
+            ms(*latencies.last().unwrap()),
+            statuses.join(", ")
+        );
+    }
+}
+
+fn parse_options(
+    config: &config::Config,
+    mut args: impl Iterator<Item = String>,
+) -> io::Result<Options> {
+    let usage = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "usage: tk-auth loadtest [--config PATH] [--target HOST:PORT] [--sessions N] \
+             [--rps N] [--duration SECS] [--connections N] [--user NAME --password PASSWORD]",
+        )
+    };
+    // The instance from the config by default, over loopback when it listens
+    // on all addresses.
+    let mut target = config
+        .listen
+        .replace("0.0.0.0:", "127.0.0.1:")
+        .replace("[::]:", "[::1]:");
+    let mut options = Options {
+        host: String::new(),
+        port: 0,
+        sessions: 1000,
+        rps: 100,
+        duration_secs: 10,
+        connections: 32,
+        credentials: None,
+    };
+    let mut user = None;
+    let mut password = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(usage);
+        match arg.as_str() {
+            "--target" => target = value()?,
+            "--sessions" => options.sessions = value()?.parse().map_err(|_| usage())?,
+            "--rps" => options.rps = value()?.parse().map_err(|_| usage())?,
+            "--duration" => options.duration_secs = value()?.parse().map_err(|_| usage())?,
+            "--connections" => options.connections = value()?.parse().map_err(|_| usage())?,
+            "--user" => user = Some(value()?),
+            "--password" => password = Some(value()?),
+            "--config" => {
+                value()?;
+            }
+            arg if arg.starts_with("--config=") => {}
+            _ => return Err(usage()),
+        }
+    }
+    let target = target.strip_prefix("http://").unwrap_or(&target);
+    let (host, port) = target.rsplit_once(':').ok_or_else(usage)?;
+    options.host = host.trim_matches(['[', ']']).to_string();
+    options.port = port.trim_end_matches('/').parse().map_err(|_| usage())?;
+    if options.rps == 0 || options.connections == 0 {
+        return Err(usage());
+    }
+    options.credentials = match (user, password) {
+        (Some(user), Some(password)) => Some((user, password)),
+        (None, None) => None,
+        _ => return Err(usage()),
+    };
+    Ok(options)
+}
+
+// Sends requests at a fixed rate regardless of how fast they're answered,
+// and measures latency from when each was due, so a slow server shows up as
+// latency instead of a lower request rate. First populates --sessions
+// sessions, then mixes reads with new sessions for --duration seconds.
+pub async fn run_cli(
+    config: &config::Config,
+    args: impl Iterator<Item = String>,
+) -> io::Result<()> {
+    let options = parse_options(config, args)?;
+    println!(
+        "Load testing {}:{} at {} requests per second over {} connections",
+        options.host, options.port, options.rps, options.connections
+    );
+    let connections = options.connections;
+    let shared = Arc::new(Shared {
+        options,
+        pool: Mutex::new(Pool::default()),
+        stats: Mutex::new(BTreeMap::new()),
+    });
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let receiver = Arc::new(tokio::sync::Mutex::new(receiver));
+    let workers: Vec<_> = (0..connections)
+        .map(|_| tokio::spawn(worker(shared.clone(), receiver.clone())))
+        .collect();
+
+    let started = Instant::now();
+    let mut interval =
+        tokio::time::interval(Duration::from_secs_f64(1.0 / shared.options.rps as f64));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Burst);
+    for _ in 0..shared.options.sessions {
+        let scheduled = interval.tick().await.into_std();
+        let _ = sender.send((Ticket::NewSession, scheduled));
+    }
+    let mixed_from = Instant::now();
+    let mut sent = 0u64;
+    while mixed_from.elapsed() < Duration::from_secs(shared.options.duration_secs) {
+        let scheduled = interval.tick().await.into_std();
+        let ticket = if sent.is_multiple_of(MIXED_CREATE_EVERY) {
+            Ticket::NewSession
+        } else {
+            Ticket::Read
+        };
+        let _ = sender.send((ticket, scheduled));
+        sent += 1;
+    }
+    drop(sender);
+    for worker in workers {
+        let _ = worker.await;
+    }
+    report(&shared, started.elapsed());
+    Ok(())
+}
@@ -0,0 +1,735 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use zeroize::Zeroizing;
+
+use crate::config;
+use crate::encryption::Encryption;
+use crate::health;
+use crate::jwt::base64url_encode;
+use crate::session::{Change, SavedSession, SessionId};
+use crate::time;
+use crate::AppState;
+
+// memcached's default item size limit.
+const MAX_VALUE_BYTES: usize = 1024 * 1024;
+const MAX_LINE_BYTES: u64 = 1024;
+// Expiration times further out than this are taken as Unix times.
+const MAX_RELATIVE_EXPTIME: u64 = 30 * 24 * 60 * 60;
+const MAX_CAS_ATTEMPTS: usize = 8;
+const MAX_IDLE_CONNECTIONS: usize = 32;
+const WRITE_CONCURRENCY: usize = 32;
+const RETRY_INTERVAL_SECS: u64 = 5;
+const MAX_QUEUED_CHANGES: usize = 100_000;
+const SEALED_CONTEXT: &str = "memcached session";
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Stored,
+    NotStored,
+    Exists,
+    NotFound,
+}
+
+// A connection speaking memcached's text protocol.
+struct Connection {
+    stream: BufReader<tokio::net::TcpStream>,
+}
+
+impl Connection {
+    async fn connect(address: &str) -> io::Result<Self> {
+        let stream = tokio::net::TcpStream::connect(address).await?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream: BufReader::new(stream),
+        })
+    }
+
+    // The value and its cas unique, if there is one.
+    async fn gets(&mut self, key: &str) -> io::Result<Option<(Vec<u8>, u64)>> {
+        self.write(format!("gets {}\r\n", key).as_bytes()).await?;
+        let mut found = None;
+        loop {
+            let line = self.read_line().await?;
+            if line == "END" {
+                return Ok(found);
+            }
+            // VALUE <key> <flags> <bytes> <cas unique>
+            let fields: Vec<&str> = line.split(' ').collect();
+            let ["VALUE", _, _, bytes, cas] = fields[..] else {
+                return Err(unexpected(&line));
+            };
+            let (Ok(bytes), Ok(cas)) = (bytes.parse::<usize>(), cas.parse::<u64>()) else {
+                return Err(unexpected(&line));
+            };
+            if bytes > MAX_VALUE_BYTES {
+                return Err(protocol_error(format!("value of {} bytes", bytes)));
+            }
+            let mut data = vec![0; bytes + 2];
+            self.stream.read_exact(&mut data).await?;
+            if !data.ends_with(b"\r\n") {
+                return Err(protocol_error("value isn't followed by a line break"));
+            }
+            data.truncate(bytes);
+            found = Some((data, cas));
+        }
+    }
+
+    // Runs set, add, or with the cas unique gets returned, cas.
+    async fn store(
+        &mut self,
+        command: &str,
+        key: &str,
+        value: &[u8],
+        exptime: u64,
+        cas: Option<u64>,
+    ) -> io::Result<Reply> {
+        let mut request = format!("{} {} 0 {} {}", command, key, exptime, value.len());
+        if let Some(cas) = cas {
+            request.push_str(&format!(" {}", cas));
+        }
+        let mut request = request.into_bytes();
+        request.extend_from_slice(b"\r\n");
+        request.extend_from_slice(value);
+        request.extend_from_slice(b"\r\n");
+        self.write(&request).await?;
+        let line = self.read_line().await?;
+        match line.as_str() {
+            "STORED" => Ok(Reply::Stored),
+            "NOT_STORED" => Ok(Reply::NotStored),
+            "EXISTS" => Ok(Reply::Exists),
+            "NOT_FOUND" => Ok(Reply::NotFound),
+            _ => Err(unexpected(&line)),
+        }
+    }
+
+    async fn delete(&mut self, key: &str) -> io::Result<()> {
+        self.write(format!("delete {}\r\n", key).as_bytes()).await?;
+        let line = self.read_line().await?;
+        match line.as_str() {
+            "DELETED" | "NOT_FOUND" => Ok(()),
+            _ => Err(unexpected(&line)),
+        }
+    }
+
+    async fn version(&mut self) -> io::Result<()> {
+        self.write(b"version\r\n").await?;
+        let line = self.read_line().await?;
+        match line.starts_with("VERSION ") {
+            true => Ok(()),
+            false => Err(unexpected(&line)),
+        }
+    }
+
+    async fn write(&mut self, request: &[u8]) -> io::Result<()> {
+        self.stream.get_mut().write_all(request).await
+    }
+
+    async fn read_line(&mut self) -> io::Result<String> {
+        let mut line = Vec::new();
+        let read = (&mut self.stream)
+            .take(MAX_LINE_BYTES)
+            .read_until(b'\n', &mut line)
+            .await?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "memcached closed the connection",
+            ));
+        }
+        let line = String::from_utf8(line).map_err(|_| protocol_error("reply isn't UTF-8"))?;
+        match line.strip_suffix("\r\n") {
+            Some(line) => Ok(line.to_string()),
+            None => Err(protocol_error(format!("malformed reply line {:?}", line))),
+        }
+    }
+}
+
+fn protocol_error(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+// ERROR, CLIENT_ERROR and SERVER_ERROR replies end up here too.
+fn unexpected(line: &str) -> io::Error {
+    protocol_error(format!("unexpected reply {:?}", line))
+}
+
+// memcached takes expiration times up to 30 days as relative, and later
+// ones as Unix times. None if the session has expired already.
+fn exptime(expires_at: Option<u64>, now: u64) -> Option<u64> {
+    match expires_at {
+        None => Some(0),
+        Some(expires_at) if expires_at <= now => None,
+        Some(expires_at) if expires_at - now <= MAX_RELATIVE_EXPTIME => Some(expires_at - now),
+        Some(expires_at) => Some(expires_at),
+    }
+}
+
+struct Server {
+    address: String,
+    idle: std::sync::Mutex<Vec<Connection>>,
+}
+
+#[derive(serde::Deserialize)]
+struct Entry {
+    id: String,
+    session: SavedSession,
+}
+
+// The storage keys of a user's sessions, with when they expire, so their
+// sessions can be found without listing every key.
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct UserIndex {
+    sessions: BTreeMap<String, Option<u64>>,
+}
+
+// Sessions kept in memcached (sessions.memcached), shared by every instance
+// with the same servers and key_prefix. Sessions are stored under a hash of
+// their id, encrypted when encryption is configured, and each user's under
+// an index of them.
+pub struct Memcached {
+    servers: Vec<Server>,
+    key_prefix: String,
+    timeout: Duration,
+    encryption: Option<Arc<Encryption>>,
+    // Lookups and writes only record failures, as they only reach some of
+    // the servers. Probes reach all of them, and record recoveries.
+    pub health: health::Backend,
+}
+
+impl Memcached {
+    pub fn new(config: &config::MemcachedConfig, encryption: Option<Arc<Encryption>>) -> Self {
+        Self {
+            servers: config
+                .servers
+                .iter()
+                .map(|address| Server {
+                    address: address.clone(),
+                    idle: std::sync::Mutex::new(Vec::new()),
+                })
+                .collect(),
+            key_prefix: config.key_prefix.clone(),
+            timeout: Duration::from_millis(config.timeout_ms),
+            encryption,
+            health: health::Backend::new("memcached"),
+        }
+    }
+
+    // Rendezvous hashing, so adding a server only moves the keys it takes
+    // over rather than most of them.
+    fn server(&self, key: &str) -> &Server {
+        self.servers
+            .iter()
+            .max_by_key(|server| {
+                let digest = ring::digest::digest(
+                    &ring::digest::SHA256,
+                    format!("{} {}", server.address, key).as_bytes(),
+                );
+                u64::from_be_bytes(digest.as_ref()[..8].try_into().unwrap())
+            })
+            .unwrap()
+    }
+
+    async fn connection(&self, server: &Server) -> Result<Connection, String> {
+        if let Some(connection) = server.idle.lock().unwrap().pop() {
+            return Ok(connection);
+        }
+        self.timed(server, Connection::connect(&server.address))
+            .await
+    }
+
+    fn release(&self, server: &Server, connection: Connection) {
+        let mut idle = server.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(connection);
+        }
+    }
+
+    // Connections that time out are dropped rather than released, as a
+    // late reply would be read by the next request.
+    async fn timed<T>(
+        &self,
+        server: &Server,
+        operation: impl std::future::Future<Output = io::Result<T>>,
+    ) -> Result<T, String> {
+        match tokio::time::timeout(self.timeout, operation).await {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(err)) => Err(format!("{}: {}", server.address, err)),
+            Err(_) => Err(format!(
+                "{}: timed out after {} ms",
+                server.address,
+                self.timeout.as_millis()
+            )),
+        }
+    }
+
+    async fn gets(&self, key: &str) -> Result<Option<(Vec<u8>, u64)>, String> {
+        let server = self.server(key);
+        let mut connection = self.connection(server).await?;
+        let value = self.timed(server, connection.gets(key)).await?;
+        self.release(server, connection);
+        Ok(value)
+    }
+
+    async fn store(
+        &self,
+        key: &str,
+        value: &[u8],
+        exptime: u64,
+        cas: Option<u64>,
+    ) -> Result<Reply, String> {
+        let server = self.server(key);
+        let mut connection = self.connection(server).await?;
+        let command = if cas.is_some() { "cas" } else { "add" };
+        let stored = self
+            .timed(server, connection.store(command, key, value, exptime, cas))
+            .await?;
+        self.release(server, connection);
+        Ok(stored)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), String> {
+        let server = self.server(key);
+        let mut connection = self.connection(server).await?;
+        self.timed(server, connection.delete(key)).await?;
+        self.release(server, connection);
+        Ok(())
+    }
+
+    // Asks every server for its version.
+    pub async fn probe(&self) -> Result<(), String> {
+        for server in &self.servers {
+            let mut connection = self.connection(server).await?;
+            self.timed(server, connection.version()).await?;
+            self.release(server, connection);
+        }
+        Ok(())
+    }
+
+    fn session_key(&self, storage_key: &str) -> String {
+        format!("{}s:{}", self.key_prefix, storage_key)
+    }
+
+    fn user_key(&self, user: &str) -> String {
+        let digest = ring::digest::digest(&ring::digest::SHA256, user.as_bytes());
+        format!("{}u:{}", self.key_prefix, base64url_encode(digest.as_ref()))
+    }
+
+    fn encode(&self, id: &SessionId, session: &SavedSession) -> Vec<u8> {
+        let plaintext = Zeroizing::new(
+            serde_json::to_vec(&serde_json::json!({
+                "id": String::from(id),
+                "session": session,
+            }))
+            .unwrap(),
+        );
+        match &self.encryption {
+            Some(encryption) => encryption.seal(SEALED_CONTEXT, &plaintext).into_bytes(),
+            None => plaintext.to_vec(),
+        }
+    }
+
+    // Values that can't be read, such as ones sealed with keys this
+    // instance doesn't have, are taken as missing.
+    fn decode(&self, key: &str, value: &[u8]) -> Option<Entry> {
+        let entry = match &self.encryption {
+            Some(encryption) => std::str::from_utf8(value)
+                .map_err(|err| err.to_string())
+                .and_then(|sealed| encryption.open(SEALED_CONTEXT, sealed))
+                .and_then(|opened| {
+                    serde_json::from_slice(&opened.plaintext).map_err(|err| err.to_string())
+                }),
+            None => serde_json::from_slice(value).map_err(|err| err.to_string()),
+        };
+        entry
+            .map_err(|err| println!("Ignoring unreadable session {} in memcached: {}", key, err))
+            .ok()
+    }
+
+    pub async fn fetch(&self, id: &SessionId) -> Result<Option<SavedSession>, String> {
+        let key = self.session_key(&id.storage_key());
+        let value = self.gets(&key).await;
+        if let Err(err) = &value {
+            self.health.record(Err(err.clone()));
+        }
+        let Some((value, _)) = value? else {
+            return Ok(None);
+        };
+        Ok(self
+            .decode(&key, &value)
+            .filter(|entry| entry.id == String::from(id))
+            .map(|entry| entry.session))
+    }
+
+    // The sessions of the user, as far as memcached still has them.
+    pub async fn sessions_of(&self, user: &str) -> Result<Vec<(SessionId, SavedSession)>, String> {
+        let Some((index, _)) = self.gets(&self.user_key(user)).await? else {
+            return Ok(Vec::new());
+        };
+        let index: UserIndex = serde_json::from_slice(&index).unwrap_or_default();
+        let mut sessions = Vec::new();
+        let mut missing = Vec::new();
+        for storage_key in index.sessions.into_keys() {
+            let key = self.session_key(&storage_key);
+            let entry = match self.gets(&key).await? {
+                Some((value, _)) => self.decode(&key, &value),
+                None => None,
+            };
+            match entry {
+                Some(entry) if entry.session.user() == Some(user) => {
+                    if let Some(id) = SessionId::restore(&entry.id) {
+                        sessions.push((id, entry.session));
+                    }
+                }
+                _ => missing.push(storage_key),
+            }
+        }
+        if !missing.is_empty() {
+            self.update_index(user, time::now_secs(), |index| {
+                for storage_key in &missing {
+                    index.sessions.remove(storage_key);
+                }
+            })
+            .await?;
+        }
+        Ok(sessions)
+    }
+
+    // Expired sessions are dropped from the index on every update, and the
+    // index expires with the last of them.
+    async fn update_index(
+        &self,
+        user: &str,
+        now: u64,
+        update: impl Fn(&mut UserIndex),
+    ) -> Result<(), String> {
+        let key = self.user_key(user);
+        for _ in 0..MAX_CAS_ATTEMPTS {
+            let current = self.gets(&key).await?;
+            let mut index: UserIndex = current
+                .as_ref()
+                .and_then(|(value, _)| serde_json::from_slice(value).ok())
+                .unwrap_or_default();
+            index
+                .sessions
+                .retain(|_, expires_at| expires_at.is_none_or(|expires_at| expires_at > now));
+            update(&mut index);
+            let cas = current.map(|(_, cas)| cas);
+            if index.sessions.is_empty() {
+                if cas.is_some() {
+                    self.delete(&key).await?;
+                }
+                return Ok(());
+            }
+            let expires_at = index
+                .sessions
+                .values()
+                .try_fold(0, |latest: u64, expires_at| {
+                    expires_at.map(|at| latest.max(at))
+                });
+            let value = serde_json::to_vec(&index).unwrap();
+            let exptime = exptime(expires_at, now).unwrap_or(0);
+            if self.store(&key, &value, exptime, cas).await? == Reply::Stored {
+                return Ok(());
+            }
+        }
+        Err(format!(
+            "{} kept changing, gave up after {} attempts",
+            key, MAX_CAS_ATTEMPTS
+        ))
+    }
+
+    // Stores the session unless memcached has a later version of it. With
+    // compare-and-swap, so concurrent writes of other instances aren't lost
+    // under it. Updates aren't stored if memcached doesn't have the session,
+    // as another instance removed it.
+    async fn put(
+        &self,
+        id: &SessionId,
+        session: &SavedSession,
+        create: bool,
+        now: u64,
+    ) -> Result<(), String> {
+        let storage_key = id.storage_key();
+        let key = self.session_key(&storage_key);
+        let Some(exptime) = exptime(session.expires_at(), now) else {
+            return self.delete(&key).await;
+        };
+        let value = self.encode(id, session);
+        for _ in 0..MAX_CAS_ATTEMPTS {
+            let (stored, previous_user) = match self.gets(&key).await? {
+                None if !create => return Ok(()),
+                None => (self.store(&key, &value, exptime, None).await?, None),
+                Some((current, cas)) => {
+                    let current = self.decode(&key, &current);
+                    if current
+                        .as_ref()
+                        .is_some_and(|current| current.session.version() > session.version())
+                    {
+                        return Ok(());
+                    }
+                    let previous_user =
+                        current.and_then(|current| current.session.user().map(str::to_string));
+                    (
+                        self.store(&key, &value, exptime, Some(cas)).await?,
+                        previous_user,
+                    )
+                }
+            };
+            match stored {
+                Reply::Stored => {}
+                Reply::NotFound if !create => return Ok(()),
+                _ => continue,
+            }
+            return match session.user() {
+                Some(user) if previous_user.as_deref() != Some(user) => {
+                    self.update_index(user, now, |index| {
+                        index
+                            .sessions
+                            .insert(storage_key.clone(), session.expires_at());
+                    })
+                    .await
+                }
+                _ => Ok(()),
+            };
+        }
+        Err(format!(
+            "{} kept changing, gave up after {} attempts",
+            key, MAX_CAS_ATTEMPTS
+        ))
+    }
+
+    async fn apply(&self, write: &Write, now: u64) -> Result<(), String> {
+        match &write.pending {
+            Pending::Put(session) => self.put(&write.id, session, true, now).await,
+            Pending::Update(session) => self.put(&write.id, session, false, now).await,
+            Pending::Remove => {
+                self.delete(&self.session_key(&write.id.storage_key()))
+                    .await
+            }
+        }
+    }
+}
+
+enum Pending {
+    Put(SavedSession),
+    Update(SavedSession),
+    Remove,
+}
+
+// The latest change of a session that isn't written yet, and how many
+// changes it stands for.
+struct Write {
+    id: SessionId,
+    pending: Pending,
+    changes: usize,
+}
+
+fn queue(queued: &mut HashMap<String, Write>, change: Change) {
+    let (id, pending) = match change {
+        Change::Put(id, session) => (id, Pending::Put(*session)),
+        Change::Update(id, session) => (id, Pending::Update(*session)),
+        Change::Remove(id) => (id, Pending::Remove),
+        // Sessions in memcached have no snapshots to compact, so these
+        // aren't sent.
+        Change::Compact(_) => return,
+    };
+    match queued.entry(String::from(&id)) {
+        std::collections::hash_map::Entry::Vacant(entry) => {
+            entry.insert(Write {
+                id,
+                pending,
+                changes: 1,
+            });
+        }
+        std::collections::hash_map::Entry::Occupied(mut entry) => {
+            let write = entry.get_mut();
+            write.changes += 1;
+            // Updates of a session that isn't stored yet still store it.
+            write.pending = match (&write.pending, pending) {
+                (Pending::Put(_), Pending::Update(session)) => Pending::Put(session),
+                (_, pending) => pending,
+            };
+        }
+    }
+}
+
+// Writes the queued changes, keeping the ones that failed queued. Whether
+// all were written.
+async fn write(
+    state: &AppState,
+    memcached: &Arc<Memcached>,
+    queued: &mut HashMap<String, Write>,
+) -> bool {
+    let now = state.clock.now_secs();
+    let mut writes = std::mem::take(queued).into_values();
+    let mut tasks = tokio::task::JoinSet::new();
+    let mut error = None;
+    loop {
+        while tasks.len() < WRITE_CONCURRENCY {
+            let Some(write) = writes.next() else {
+                break;
+            };
+            let memcached = memcached.clone();
+            tasks.spawn(async move {
+                let result = memcached.apply(&write, now).await;
+                (write, result)
+            });
+        }
+        let Some(done) = tasks.join_next().await else {
+            break;
+        };
+        let (write, result) = done.unwrap();
+        match result {
+            Ok(()) => state.sessions.written(&write.id, write.changes),
+            Err(err) => {
+                error.get_or_insert(err);
+                queued.insert(String::from(&write.id), write);
+            }
+        }
+    }
+    if queued.len() > MAX_QUEUED_CHANGES {
+        println!(
+            "Failed to write sessions to memcached, dropping {} queued changes",
+            queued.len()
+        );
+        for (_, write) in queued.drain() {
+            state.sessions.written(&write.id, write.changes);
+        }
+    }
+    state
+        .metrics
+        .journal_queued
+        .store(queued.len() as u64, Ordering::Relaxed);
+    match error {
+        Some(err) => {
+            memcached.health.record(Err(err));
+            false
+        }
+        None => true,
+    }
+}
+
+pub fn spawn(state: Arc<AppState>, memcached: Option<Arc<Memcached>>) {
+    let Some(memcached) = memcached else {
+        return;
+    };
+    let (sender, receiver) = std::sync::mpsc::channel();
+    state.sessions.share(memcached.clone(), sender);
+    // The store sends changes over a blocking channel, as the journal is
+    // written from a thread of its own.
+    let (forward, mut changes) = tokio::sync::mpsc::unbounded_channel();
+    std::thread::Builder::new()
+        .name(String::from("memcached-changes"))
+        .spawn(move || {
+            for change in receiver {
+                if forward.send(change).is_err() {
+                    return;
+                }
+            }
+        })
+        .unwrap();
+    tokio::spawn(async move {
+        let mut queued = HashMap::new();
+        // Set while memcached fails, so changes are written again at most
+        // every RETRY_INTERVAL_SECS, however many come in meanwhile.
+        let mut retry_at: Option<Instant> = None;
+        loop {
+            let change = match retry_at {
+                None => changes.recv().await,
+                Some(retry_at) => tokio::time::timeout_at(retry_at.into(), changes.recv())
+                    .await
+                    .ok()
+                    .flatten(),
+            };
+            match change {
+                Some(change) => queue(&mut queued, change),
+                None if retry_at.is_some_and(|retry_at| Instant::now() >= retry_at) => {
+                    retry_at = None
+                }
+                None => return,
+            }
+            while let Ok(change) = changes.try_recv() {
+                queue(&mut queued, change);
+            }
+            if retry_at.is_some() || queued.is_empty() {
+                continue;
+            }
+            if !write(&state, &memcached, &mut queued).await {
+                retry_at = Some(Instant::now() + Duration::from_secs(RETRY_INTERVAL_SECS));
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expires_relative_then_absolute() {
+        assert_eq!(exptime(None, 1000), Some(0));
+        assert_eq!(exptime(Some(1000), 1000), None);
+        assert_eq!(exptime(Some(4600), 1000), Some(3600));
+        let far = 1000 + MAX_RELATIVE_EXPTIME + 1;
+        assert_eq!(exptime(Some(far), 1000), Some(far));
+    }
+
+    #[tokio::test]
+    async fn speaks_the_text_protocol() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut requests = Vec::new();
+            for reply in [
+                &b"VALUE k 0 5 42\r\nhello\r\nEND\r\n"[..],
+                b"EXISTS\r\n",
+                b"END\r\n",
+                b"SERVER_ERROR out of memory\r\n",
+            ] {
+                let mut request = String::new();
+                stream.read_line(&mut request).await.unwrap();
+                if request.starts_with("cas") || request.starts_with("add") {
+                    stream.read_line(&mut request).await.unwrap();
+                }
+                requests.push(request);
+                stream.get_mut().write_all(reply).await.unwrap();
+            }
+            requests
+        });
+        let mut connection = Connection::connect(&address).await.unwrap();
+        assert_eq!(
+            connection.gets("k").await.unwrap(),
+            Some((b"hello".to_vec(), 42))
+        );
+        assert_eq!(
+            connection
+                .store("cas", "k", b"bye", 60, Some(42))
+                .await
+                .unwrap(),
+            Reply::Exists
+        );
+        assert_eq!(connection.gets("k").await.unwrap(), None);
+        assert!(connection
+            .store("add", "k", b"bye", 0, None)
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("SERVER_ERROR"));
+        assert_eq!(
+            server.await.unwrap(),
+            [
+                "gets k\r\n",
+                "cas k 0 60 3 42\r\nbye\r\n",
+                "gets k\r\n",
+                "add k 0 0 3\r\nbye\r\n",
+            ]
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::admin;
+use crate::health;
+use crate::{error_response, AppState};
+
+#[derive(Default)]
+pub struct Metrics {
+    pub memory_budget_evictions: AtomicU64,
+    pub memory_budget_rejections: AtomicU64,
+    pub expired_sessions: AtomicU64,
+    pub expiry_sweeps: Operation,
+    pub journal_writes: Operation,
+    pub snapshots: Operation,
+    pub backups: Operation,
+    pub journal_queued: AtomicU64,
+}
+
+// How often a storage operation ran and failed, and the time it took in
+// all.
+#[derive(Default)]
+pub struct Operation {
+    count: AtomicU64,
+    errors: AtomicU64,
+    micros: AtomicU64,
+}
+
+impl Operation {
+    pub fn record(&self, started: Instant, ok: bool) {
+        let micros = started.elapsed().as_micros().try_into().unwrap_or(u64::MAX);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.micros.fetch_add(micros, Ordering::Relaxed);
+        if !ok {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn write_metric(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: impl std::fmt::Display,
+) {
+    let _ = write!(
+        out,
+        "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+    );
+}
+
+// A summary without quantiles of the time the operations took, and a
+// counter of their failures, both labelled by operation.
+fn write_operations(out: &mut String, operations: &[(&str, &Operation)]) {
+    let _ = write!(
+        out,
+        "# HELP tk_auth_storage_duration_seconds Time spent in storage operations.\n\
+        # TYPE tk_auth_storage_duration_seconds summary\n"
+    );
+    for (name, operation) in operations {
+        let _ = write!(
+            out,
+            "tk_auth_storage_duration_seconds_sum{{operation=\"{name}\"}} {}\n\
+            tk_auth_storage_duration_seconds_count{{operation=\"{name}\"}} {}\n",
+            operation.micros.load(Ordering::Relaxed) as f64 / 1e6,
+            operation.count.load(Ordering::Relaxed),
+        );
+    }
+    let _ = write!(
+        out,
+        "# HELP tk_auth_storage_errors_total Storage operations that failed.\n\
+        # TYPE tk_auth_storage_errors_total counter\n"
+    );
+    for (name, operation) in operations {
+        let _ = writeln!(
+            out,
+            "tk_auth_storage_errors_total{{operation=\"{name}\"}} {}",
+            operation.errors.load(Ordering::Relaxed),
+        );
+    }
+}
+
+// Whether each storage backend is up, and how often its probes and writes
+// failed.
+fn write_backends(out: &mut String, backends: &[&health::Backend]) {
+    let _ = write!(
+        out,
+        "# HELP tk_auth_backend_up Whether a storage backend is healthy.\n\
+        # TYPE tk_auth_backend_up gauge\n"
+    );
+    for backend in backends {
+        let _ = writeln!(
+            out,
+            "tk_auth_backend_up{{backend=\"{}\"}} {}",
+            backend.name(),
+            u8::from(backend.failure().is_none()),
+        );
+    }
+    let _ = write!(
+        out,
+        "# HELP tk_auth_backend_failures_total Failed probes and writes of a storage backend.\n\
+        # TYPE tk_auth_backend_failures_total counter\n"
+    );
+    for backend in backends {
+        let _ = writeln!(
+            out,
+            "tk_auth_backend_failures_total{{backend=\"{}\"}} {}",
+            backend.name(),
+            backend.failures(),
+        );
+    }
+}
+
+// Prometheus text format, behind the admin token like the rest of the admin
+// API.
+pub async fn get_metrics(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: http::HeaderMap,
+) -> axum::response::Response {
+    if state.config.admin.is_none() {
+        return error_response(404, "the admin API is not enabled");
+    }
+    if !admin::authorized(&state, &headers) {
+        return error_response(401, "invalid admin token");
+    }
+    let mut out = String::new();
+    write_metric(
+        &mut out,
+        "tk_auth_sessions",
+        "gauge",
+        "Sessions in the store.",
+        state.sessions.count(),
+    );
+    write_metric(
+        &mut out,
+        "tk_auth_session_memory_bytes",
+        "gauge",
+        "Approximate memory used by sessions and their data.",
+        state.sessions.memory_bytes(),
+    );
+    if let Some(budget) = state.config.sessions.memory_budget_bytes {
+        write_metric(
+            &mut out,
+            "tk_auth_session_memory_budget_bytes",
+            "gauge",
+            "The configured sessions.memory_budget_bytes.",
+            budget,
+        );
+    }
+    write_metric(
+        &mut out,
+        "tk_auth_memory_budget_evictions_total",
+        "counter",
+        "Sessions evicted to stay within the memory budget.",
+        state
+            .metrics
+            .memory_budget_evictions
+            .load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut out,
+        "tk_auth_memory_budget_rejections_total",
+        "counter",
+        "Requests rejected because the memory budget was used up.",
+        state
+            .metrics
+            .memory_budget_rejections
+            .load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut out,
+        "tk_auth_expired_sessions_total",
+        "counter",
+        "Sessions removed by expiry sweeps.",
+        state.metrics.expired_sessions.load(Ordering::Relaxed),
+    );
+    write_operations(
+        &mut out,
+        &[
+            ("expiry_sweep", &state.metrics.expiry_sweeps),
+            ("journal_write", &state.metrics.journal_writes),
+            ("snapshot", &state.metrics.snapshots),
+            ("backup", &state.metrics.backups),
+            ("users_write", &state.users.writes),
+        ],
+    );
+    write_backends(&mut out, &health::backends(&state));
+    write_metric(
+        &mut out,
+        "tk_auth_journal_queued_records",
+        "gauge",
+        "Session changes waiting for the journal or memcached to be writable again.",
+        state.metrics.journal_queued.load(Ordering::Relaxed),
+    );
+    let mut response = axum::response::Response::new(axum::body::Body::from(out));
+    response.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"),
+    );
+    response
+}
@@ -0,0 +1,650 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use base64::Engine;
+
+use crate::client_info::ClientInfo;
+use crate::config::{self, NotificationChannel};
+use crate::email::{self, EmailSender, Template};
+use crate::http_client;
+use crate::i18n;
+use crate::inbox::Inbox;
+use crate::time;
+use crate::users::{StoreError, User};
+use crate::{
+    error_response, json_response, lookup_session, message_error_response, AppState,
+    GetSessionQuery, SESSION_NOT_AUTHENTICATED,
+};
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Notification {
+    pub event: Template,
+    pub user: String,
+    // An object whose fields are filled into the templates and sent to the
+    // webhook as they are.
+    pub details: serde_json::Value,
+}
+
+impl Notification {
+    // The user and the details as text for templates; null is left empty.
+    fn values(&self) -> Vec<(&str, String)> {
+        let details = self.details.as_object().into_iter().flatten();
+        let details = details.map(|(name, value)| {
+            let text = match value {
+                serde_json::Value::String(text) => text.clone(),
+                serde_json::Value::Null => String::new(),
+                value => value.to_string(),
+            };
+            (name.as_str(), text)
+        });
+        [("user", self.user.clone())]
+            .into_iter()
+            .chain(details)
+            .collect()
+    }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Recipient {
+    pub emails: Vec<String>,
+    pub phone_numbers: Vec<String>,
+    #[serde(default)]
+    pub preferred_language: Option<String>,
+}
+
+type Sending<'a> = Pin<Box<dyn Future<Output = io::Result<bool>> + Send + 'a>>;
+
+// A way of reaching users. `notify` resolves to false when the recipient
+// has no address for the channel.
+pub trait Notifier: Send + Sync {
+    fn channel(&self) -> NotificationChannel;
+
+    fn notify<'a>(
+        &'a self,
+        recipient: &'a Recipient,
+        notification: &'a Notification,
+    ) -> Sending<'a>;
+}
+
+struct EmailNotifier {
+    sender: Arc<EmailSender>,
+}
+
+impl Notifier for EmailNotifier {
+    fn channel(&self) -> NotificationChannel {
+        NotificationChannel::Email
+    }
+
+    fn notify<'a>(
+        &'a self,
+        recipient: &'a Recipient,
+        notification: &'a Notification,
+    ) -> Sending<'a> {
+        Box::pin(async move {
+            let Some(to) = recipient.emails.first() else {
+                return Ok(false);
+            };
+            let values = notification.values();
+            let values: Vec<_> = values
+                .iter()
+                .map(|(name, value)| (*name, value.as_str()))
+                .collect();
+            self.sender
+                .send(
+                    notification.event,
+                    recipient.preferred_language.as_deref(),
+                    to,
+                    &values,
+                )
+                .await?;
+            Ok(true)
+        })
+    }
+}
+
+struct WebhookNotifier {
+    client: http_client::Client,
+    url: String,
+}
+
+fn webhook_body(recipient: &Recipient, notification: &Notification) -> serde_json::Value {
+    let mut body = serde_json::json!({
+        "event": notification.event.name(),
+        "user": notification.user,
+        "emails": recipient.emails,
+        "phone_numbers": recipient.phone_numbers,
+        "preferred_language": recipient.preferred_language,
+    });
+    for (name, value) in notification.details.as_object().into_iter().flatten() {
+        body[name] = value.clone();
+    }
+    body
+}
+
+impl Notifier for WebhookNotifier {
+    fn channel(&self) -> NotificationChannel {
+        NotificationChannel::Webhook
+    }
+
+    fn notify<'a>(
+        &'a self,
+        recipient: &'a Recipient,
+        notification: &'a Notification,
+    ) -> Sending<'a> {
+        Box::pin(async move {
+            let body = webhook_body(recipient, notification).to_string();
+            let response = self
+                .client
+                .request(
+                    "POST",
+                    &self.url,
+                    &[("Content-Type", "application/json")],
+                    body.as_bytes(),
+                )
+                .await?;
+            if !(200..300).contains(&response.status) {
+                return Err(io::Error::other(format!(
+                    "{} returned status {}",
+                    self.url, response.status
+                )));
+            }
+            Ok(true)
+        })
+    }
+}
+
+struct SmsNotifier {
+    client: http_client::Client,
+    config: config::SmsConfig,
+}
+
+// Text messages are kept to about one segment, so they only carry the link.
+fn sms_text(event: Template) -> &'static str {
+    match event {
+        Template::Verification => "Confirm your email address: {{url}}",
+        Template::PasswordReset => "Reset your password: {{url}}",
+        Template::NewDevice => {
+            "New sign-in to {{user}} from {{device_name}}. Not you? {{revoke_url}}"
+        }
+        Template::SecurityDigest => {
+            "{{user}} was signed in to {{sign_in_count}} times in the past {{period}}"
+        }
+    }
+}
+
+impl Notifier for SmsNotifier {
+    fn channel(&self) -> NotificationChannel {
+        NotificationChannel::Sms
+    }
+
+    fn notify<'a>(
+        &'a self,
+        recipient: &'a Recipient,
+        notification: &'a Notification,
+    ) -> Sending<'a> {
+        Box::pin(async move {
+            let Some(to) = recipient.phone_numbers.first() else {
+                return Ok(false);
+            };
+            let values = notification.values();
+            let values: Vec<_> = values
+                .iter()
+                .map(|(name, value)| (*name, value.as_str()))
+                .collect();
+            let body = form_urlencoded::Serializer::new(String::new())
+                .append_pair("To", to)
+                .append_pair("From", &self.config.from)
+                .append_pair(
+                    "Body",
+                    &email::render(sms_text(notification.event), &values),
+                )
+                .finish();
+            let authorization = match (&self.config.username, &self.config.password) {
+                (Some(username), Some(password)) => Some(zeroize::Zeroizing::new(format!(
+                    "Basic {}",
+                    base64::engine::general_purpose::STANDARD.encode(format!(
+                        "{}:{}",
+                        username,
+                        password.as_str()
+                    ))
+                ))),
+                _ => None,
+            };
+            let mut headers = vec![("Content-Type", "application/x-www-form-urlencoded")];
+            if let Some(authorization) = &authorization {
+                headers.push(("Authorization", authorization.as_str()));
+            }
+            let response = self
+                .client
+                .request("POST", &self.config.url, &headers, body.as_bytes())
+                .await?;
+            if !(200..300).contains(&response.status) {
+                return Err(io::Error::other(format!(
+                    "{} returned status {}",
+                    self.config.url, response.status
+                )));
+            }
+            Ok(true)
+        })
+    }
+}
+
+struct InboxNotifier {
+    inbox: Arc<Inbox>,
+}
+
+impl Notifier for InboxNotifier {
+    fn channel(&self) -> NotificationChannel {
+        NotificationChannel::Inbox
+    }
+
+    fn notify<'a>(
+        &'a self,
+        _recipient: &'a Recipient,
+        notification: &'a Notification,
+    ) -> Sending<'a> {
+        Box::pin(async move {
+            self.inbox.add(notification, time::now_secs()).await?;
+            Ok(true)
+        })
+    }
+}
+
+// The channels this deployment has configured.
+#[derive(Default)]
+pub struct Notifiers {
+    notifiers: Vec<Arc<dyn Notifier>>,
+}
+
+impl Notifiers {
+    pub fn new(
+        config: &config::Config,
+        email: Arc<EmailSender>,
+        inbox: Arc<Inbox>,
+    ) -> io::Result<Self> {
+        let mut notifiers: Vec<Arc<dyn Notifier>> = Vec::new();
+        if email.is_enabled() {
+            notifiers.push(Arc::new(EmailNotifier { sender: email }));
+        }
+        if inbox.is_enabled() {
+            notifiers.push(Arc::new(InboxNotifier { inbox }));
+        }
+        let notifications = &config.notifications;
+        if notifications.webhook_url.is_some() || notifications.sms.is_some() {
+            let client = http_client::Client::new(&config.secrets.ca_path)?;
+            if let Some(url) = &notifications.webhook_url {
+                notifiers.push(Arc::new(WebhookNotifier {
+                    client: client.clone(),
+                    url: url.clone(),
+                }));
+            }
+            if let Some(sms) = &notifications.sms {
+                notifiers.push(Arc::new(SmsNotifier {
+                    client,
+                    config: sms.clone(),
+                }));
+            }
+        }
+        Ok(Self { notifiers })
+    }
+
+    pub fn for_channel(&self, channel: NotificationChannel) -> Option<Arc<dyn Notifier>> {
+        self.notifiers
+            .iter()
+            .find(|notifier| notifier.channel() == channel)
+            .cloned()
+    }
+}
+
+// The security notifications users can choose channels for, the channels
+// each can be sent over, and the ones it goes to until the user chooses.
+fn preference_events(config: &config::Config) -> Vec<PreferenceEvent> {
+    let mut events = Vec::new();
+    if let Some(alerts) = &config.new_device_alerts {
+        events.push(PreferenceEvent {
+            event: Template::NewDevice,
+            available: alerts.channels.clone(),
+            default: alerts.channels.clone(),
+        });
+    }
+    // Opt-in, so it's sent nowhere by default.
+    if config.security_digest.is_some() {
+        events.push(PreferenceEvent {
+            event: Template::SecurityDigest,
+            available: vec![NotificationChannel::Email],
+            default: Vec::new(),
+        });
+    }
+    let configured = |channels: Vec<NotificationChannel>| {
+        channels
+            .into_iter()
+            .filter(|channel| config.has_notification_channel(*channel))
+            .collect()
+    };
+    events
+        .into_iter()
+        .map(|event| PreferenceEvent {
+            available: configured(event.available),
+            default: configured(event.default),
+            ..event
+        })
+        .collect()
+}
+
+struct PreferenceEvent {
+    event: Template,
+    available: Vec<NotificationChannel>,
+    default: Vec<NotificationChannel>,
+}
+
+// The channels the user gets `event` over, by their choice or by default.
+pub fn chosen_channels(
+    config: &config::Config,
+    user: &User,
+    event: Template,
+) -> Vec<NotificationChannel> {
+    let Some(preference) = preference_events(config)
+        .into_iter()
+        .find(|preference| preference.event == event)
+    else {
+        return Vec::new();
+    };
+    match user.notification_preferences.get(event.name()) {
+        Some(chosen) => preference
+            .available
+            .into_iter()
+            .filter(|channel| chosen.contains(channel))
+            .collect(),
+        None => preference.default,
+    }
+}
+
+// Sends the notification over those of `channels` that are configured and
+// the user gets it over, in the background so it can't hold up the request,
+// or through the delivery queue when there is one.
+pub async fn send(state: &AppState, channels: &[NotificationChannel], notification: Notification) {
+    let (recipient, chosen) = match state.users.read().await.user_by_name(&notification.user) {
+        Some(user) => (
+            Recipient {
+                emails: user.emails.clone(),
+                phone_numbers: user.phone_numbers.clone(),
+                preferred_language: user.preferred_language.clone(),
+            },
+            Some(chosen_channels(&state.config, user, notification.event)),
+        ),
+        None => (
+            Recipient {
+                emails: Vec::new(),
+                phone_numbers: Vec::new(),
+                preferred_language: None,
+            },
+            None,
+        ),
+    };
+    let notifiers: Vec<_> = state
+        .notifiers
+        .notifiers
+        .iter()
+        .filter(|notifier| channels.contains(&notifier.channel()))
+        .filter(|notifier| {
+            chosen
+                .as_ref()
+                .is_none_or(|chosen| chosen.contains(&notifier.channel()))
+        })
+        .cloned()
+        .collect();
+    if notifiers.is_empty() {
+        return;
+    }
+    if state.deliveries.is_enabled() {
+        let now = state.clock.now_secs();
+        for notifier in notifiers {
+            state
+                .deliveries
+                .enqueue(
+                    &*state.rng,
+                    notifier.channel(),
+                    recipient.clone(),
+                    notification.clone(),
+                    now,
+                )
+                .await;
+        }
+        return;
+    }
+    tokio::spawn(async move {
+        let event = notification.event.name();
+        for notifier in notifiers {
+            let channel = channel_name(notifier.channel());
+            match notifier.notify(&recipient, &notification).await {
+                Ok(true) => println!(
+                    "Sent {} notification for {} by {}",
+                    event, notification.user, channel
+                ),
+                Ok(false) => {}
+                Err(err) => println!(
+                    "Sending {} notification for {} by {} failed: {}",
+                    event, notification.user, channel, err
+                ),
+            }
+        }
+    });
+}
+
+#[derive(serde::Serialize)]
+struct EventPreference {
+    event: &'static str,
+    channels: Vec<NotificationChannel>,
+    available_channels: Vec<NotificationChannel>,
+    // Whether channels is the user's choice rather than the default.
+    chosen: bool,
+}
+
+#[derive(serde::Deserialize)]
+pub struct PreferencesUpdate {
+    // Channels per event; null goes back to the default.
+    preferences: BTreeMap<String, Option<Vec<NotificationChannel>>>,
+}
+
+fn preferences_response(config: &config::Config, user: &User) -> axum::response::Response {
+    let preferences: Vec<_> = preference_events(config)
+        .into_iter()
+        .map(|preference| EventPreference {
+            event: preference.event.name(),
+            channels: chosen_channels(config, user, preference.event),
+            available_channels: preference.available,
+            chosen: user
+                .notification_preferences
+                .contains_key(preference.event.name()),
+        })
+        .collect();
+    json_response(200, serde_json::json!({ "preferences": preferences }))
+}
+
+pub async fn session_user(
+    state: &AppState,
+    client: &ClientInfo,
+    session_id: &str,
+) -> Result<(String, u64), axum::response::Response> {
+    let (_, session) = lookup_session(state, session_id, client).await?;
+    let session_locked = session.read().await;
+    match (&session_locked.user, session_locked.authenticated_at) {
+        (Some(user), Some(authenticated_at)) if session_locked.authenticated => {
+            Ok((user.clone(), authenticated_at))
+        }
+        _ => Err(SESSION_NOT_AUTHENTICATED.response()),
+    }
+}
+
+pub async fn get_notification_preferences(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    axum::extract::Query(query): axum::extract::Query<GetSessionQuery>,
+) -> axum::response::Response {
+    let user = match session_user(&state, &client, &query.session_id).await {
+        Ok((user, _)) => user,
+        Err(response) => return response,
+    };
+    match state.users.read().await.user_by_name(&user) {
+        Some(user) => preferences_response(&state.config, user),
+        None => error_response(404, "the user has no account to keep preferences in"),
+    }
+}
+
+// Turning notifications off needs a recent sign-in, so a stolen session
+// can't silence the alerts about it.
+pub async fn put_notification_preferences(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    axum::extract::Query(query): axum::extract::Query<GetSessionQuery>,
+    body: axum::body::Bytes,
+) -> axum::response::Response {
+    let update: PreferencesUpdate = match serde_json::from_slice(&body) {
+        Ok(update) => update,
+        Err(err) => {
+            return message_error_response(
+                400,
+                i18n::Message {
+                    template: "invalid preferences: {error}",
+                    args: vec![("error", err.to_string())],
+                },
+            )
+        }
+    };
+    let (user, authenticated_at) = match session_user(&state, &client, &query.session_id).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    if state.clock.now_secs().saturating_sub(authenticated_at)
+        > state.config.sessions.reauth_max_age_secs
+    {
+        return error_response(403, "authenticate again before changing notifications");
+    }
+    let events = preference_events(&state.config);
+    for (name, channels) in &update.preferences {
+        let Some(preference) = events.iter().find(|event| event.event.name() == name) else {
+            return message_error_response(
+                400,
+                i18n::Message {
+                    template: "no notification {name}",
+                    args: vec![("name", name.to_string())],
+                },
+            );
+        };
+        if let Some(channel) = channels
+            .iter()
+            .flatten()
+            .find(|channel| !preference.available.contains(channel))
+        {
+            return message_error_response(
+                400,
+                i18n::Message {
+                    template: "{name} isn't sent by {channel}",
+                    args: vec![
+                        ("name", name.to_string()),
+                        ("channel", channel_name(*channel).to_string()),
+                    ],
+                },
+            );
+        }
+    }
+    let updated = state
+        .users
+        .update(|data| {
+            let user = data
+                .users
+                .values_mut()
+                .find(|other| other.user_name.eq_ignore_ascii_case(&user))
+                .ok_or(StoreError::NotFound)?;
+            for (name, channels) in update.preferences {
+                match channels {
+                    Some(channels) => {
+                        user.notification_preferences.insert(name, channels);
+                    }
+                    None => {
+                        user.notification_preferences.remove(&name);
+                    }
+                }
+            }
+            user.last_modified = time::now_secs();
+            user.version += 1;
+            Ok(user.clone())
+        })
+        .await;
+    match updated {
+        Ok(updated) => {
+            println!("Updated notification preferences of {}", user);
+            preferences_response(&state.config, &updated)
+        }
+        Err(StoreError::NotFound) => {
+            error_response(404, "the user has no account to keep preferences in")
+        }
+        Err(err) => {
+            println!("Failed to save notification preferences: {}", err);
+            error_response(500, "failed to save notification preferences")
+        }
+    }
+}
+
+pub fn channel_name(channel: NotificationChannel) -> &'static str {
+    match channel {
+        NotificationChannel::Email => "email",
+        NotificationChannel::Webhook => "webhook",
+        NotificationChannel::Sms => "SMS",
+        NotificationChannel::Inbox => "inbox",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_details_into_webhook_and_texts() {
+        let notification = Notification {
+            event: Template::NewDevice,
+            user: String::from("zed"),
+            details: serde_json::json!({
+                "device_name": "Firefox on Linux",
+                "country": null,
+                "asn": 64496,
+                "revoke_url": "https://auth.example.com/x",
+            }),
+        };
+        let recipient = Recipient {
+            emails: vec![String::from("zed@example.com")],
+            phone_numbers: Vec::new(),
+            preferred_language: Some(String::from("de")),
+        };
+        assert_eq!(
+            webhook_body(&recipient, &notification),
+            serde_json::json!({
+                "event": "new_device",
+                "user": "zed",
+                "emails": ["zed@example.com"],
+                "phone_numbers": [],
+                "preferred_language": "de",
+                "device_name": "Firefox on Linux",
+                "country": null,
+                "asn": 64496,
+                "revoke_url": "https://auth.example.com/x",
+            })
+        );
+        let values = notification.values();
+        let values: Vec<_> = values
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect();
+        assert_eq!(
+            email::render("{{asn}}/{{country}}/{{missing}}", &values),
+            "64496//"
+        );
+        assert_eq!(
+            email::render(sms_text(Template::NewDevice), &values),
+            "New sign-in to zed from Firefox on Linux. Not you? https://auth.example.com/x"
+        );
+    }
+}
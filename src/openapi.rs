@@ -0,0 +1,2186 @@
+use serde_json::json;
+
+use crate::conditional;
+use crate::pagination;
+use crate::rate_limit;
+
+pub fn document() -> serde_json::Value {
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "tk-auth",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths(),
+        "components": {
+            "schemas": schemas(),
+            "securitySchemes": {
+                "adminBearer": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "The token configured in admin.bearer_token",
+                },
+                "scimBearer": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "The token configured in scim.bearer_token",
+                },
+                "requestSignature": {
+                    "type": "apiKey",
+                    "in": "header",
+                    "name": "Authorization",
+                    "description": "`TK-HMAC-SHA256 KeyId=<id>, Timestamp=<unix seconds>, \
+                        Signature=<signature>` with a key from request_signing.keys, accepted \
+                        in place of the bearer token of the key's scope. The signature is the \
+                        base64 HMAC-SHA256 of the method, the path with query, the timestamp \
+                        and the lowercase hex SHA-256 of the body, joined by newlines. The \
+                        timestamp must be within request_signing.max_skew_secs of the \
+                        server's clock, and each signature is accepted once.",
+                },
+            },
+        },
+    })
+}
+
+// Like schemas, paths are kept in two groups to stay under the macro
+// recursion limit.
+fn paths() -> serde_json::Value {
+    let mut paths = session_paths();
+    if let (Some(paths), serde_json::Value::Object(more)) = (paths.as_object_mut(), other_paths()) {
+        paths.extend(more);
+    }
+    describe_conditional_gets(&mut paths);
+    describe_paged_lists(&mut paths);
+    describe_rate_limits(&mut paths);
+    describe_quotas(&mut paths);
+    describe_envelope(&mut paths);
+    paths
+}
+
+// How envelope::layer wraps the JSON responses of /api/ paths, whose
+// schemas above describe the `data`. The client generator unwraps schemas
+// marked x-envelope.
+fn envelope_schema(data: serde_json::Value) -> serde_json::Value {
+    json!({
+        "type": "object",
+        "x-envelope": true,
+        "required": ["code", "message", "data", "request_id"],
+        "properties": {
+            "code": {
+                "type": "string",
+                "description": "`ok`, or a machine-readable error code such as \
+                    `session_not_found`, `quota_exceeded` or, for errors without their own, \
+                    one for the status such as `bad_request`",
+            },
+            "message": {
+                "type": "string",
+                "description": "Human-readable, translated with Accept-Language",
+            },
+            "data": data,
+            "request_id": {
+                "type": "string",
+                "description": "Also in X-Request-Id; taken from the request's X-Request-Id \
+                    when that is up to 128 letters, digits, `-`, `_` and `.`",
+            },
+        },
+    })
+}
+
+fn describe_envelope(paths: &mut serde_json::Value) {
+    let request_id = json!({ "schema": { "type": "string" } });
+    for (path, item) in paths.as_object_mut().unwrap() {
+        if !path.starts_with("/api/") {
+            continue;
+        }
+        let operations = item.as_object_mut().unwrap().values_mut();
+        for responses in operations.filter_map(|operation| operation.get_mut("responses")) {
+            for (status, response) in responses.as_object_mut().unwrap() {
+                response["headers"]["X-Request-Id"] = request_id.clone();
+                // Errors get an envelope whatever their body.
+                if (status.starts_with('4') || status.starts_with('5'))
+                    && response.get("content").is_none()
+                {
+                    response["content"] =
+                        json!({ "application/json": { "schema": schema_ref("ErrorResponse") } });
+                }
+                let Some(schema) = response
+                    .pointer_mut("/content/application~1json/schema")
+                    .filter(|schema| schema["$ref"] != "#/components/schemas/ErrorResponse")
+                else {
+                    continue;
+                };
+                *schema = envelope_schema(schema.take());
+            }
+        }
+    }
+}
+
+// quota::layer covers every route, so any operation can answer 429 to a
+// session or signing key over its quota.
+fn describe_quotas(paths: &mut serde_json::Value) {
+    let response = json!({
+        "description": "Over the quota of the session (session_quota) or of the signing key \
+            (request_signing.keys[].quota); see /api/usage",
+        "headers": {
+            "Retry-After": { "schema": { "type": "integer" } },
+            "RateLimit-Limit": { "schema": { "type": "integer" } },
+            "RateLimit-Remaining": { "schema": { "type": "integer" } },
+            "RateLimit-Reset": { "schema": { "type": "integer" } },
+        },
+        "content": {
+            "application/json": {
+                "schema": schema_ref("ErrorResponse"),
+            },
+        },
+    });
+    for path in paths.as_object_mut().unwrap().values_mut() {
+        // Besides the operations, a path can have its parameters.
+        let operations = path.as_object_mut().unwrap().values_mut();
+        for responses in operations.filter_map(|operation| operation.get_mut("responses")) {
+            responses
+                .as_object_mut()
+                .unwrap()
+                .entry("429")
+                .or_insert_with(|| response.clone());
+        }
+    }
+}
+
+// Adds the headers of rate_limit::layer to the responses of the throttled
+// operations.
+fn describe_rate_limits(paths: &mut serde_json::Value) {
+    let header = |description: &str| {
+        json!({
+            "description": description,
+            "schema": { "type": "integer", "minimum": 0 },
+        })
+    };
+    let headers = json!({
+        "RateLimit-Limit": header(
+            "With proof_of_work, the sessions per second that need no proof of work; \
+                with tarpit, the failed sign-ins before attempts are slowed down",
+        ),
+        "RateLimit-Remaining": header("What is left of RateLimit-Limit"),
+        "RateLimit-Reset": header("Seconds until the quota is back in full"),
+    });
+    for route in rate_limit::ROUTES {
+        let Some(post) = paths.get_mut(route).and_then(|path| path.get_mut("post")) else {
+            continue;
+        };
+        for response in post["responses"].as_object_mut().unwrap().values_mut() {
+            match response["headers"].as_object_mut() {
+                Some(existing) => existing.extend(headers.as_object().unwrap().clone()),
+                None => response["headers"] = headers.clone(),
+            }
+        }
+    }
+}
+
+// Adds the parameters of pagination::page to the list endpoints.
+fn describe_paged_lists(paths: &mut serde_json::Value) {
+    let lists = [
+        "/api/sessions/mine",
+        "/api/devices",
+        "/api/notifications",
+        "/api/admin/deliveries",
+    ];
+    for path in lists {
+        let parameters = paths[path]["get"]["parameters"].as_array_mut().unwrap();
+        parameters.extend([
+            json!({
+                "name": "limit",
+                "in": "query",
+                "required": false,
+                "schema": {
+                    "type": "integer",
+                    "minimum": 1,
+                    "maximum": pagination::MAX_LIMIT,
+                    "default": pagination::DEFAULT_LIMIT,
+                },
+            }),
+            json!({
+                "name": "cursor",
+                "in": "query",
+                "required": false,
+                "description": "`next_cursor` of the previous page",
+                "schema": { "type": "string" },
+            }),
+            json!({
+                "name": "order",
+                "in": "query",
+                "required": false,
+                "schema": { "type": "string", "enum": ["asc", "desc"] },
+            }),
+        ]);
+    }
+}
+
+// Adds the conditional request headers and the 304 to the GET operations
+// that conditional::layer covers.
+fn describe_conditional_gets(paths: &mut serde_json::Value) {
+    let routes = conditional::ROUTES
+        .iter()
+        .map(|route| (route, false))
+        .chain(conditional::STATIC_ROUTES.iter().map(|route| (route, true)));
+    for (route, is_static) in routes {
+        let path = route.replace(":id", "{id}");
+        let Some(get) = paths.get_mut(&path).and_then(|path| path.get_mut("get")) else {
+            continue;
+        };
+        let mut parameters = vec![json!({
+            "name": "If-None-Match",
+            "in": "header",
+            "required": false,
+            "schema": { "type": "string" },
+        })];
+        if is_static {
+            parameters.push(json!({
+                "name": "If-Modified-Since",
+                "in": "header",
+                "required": false,
+                "schema": { "type": "string" },
+            }));
+        }
+        match get["parameters"].as_array_mut() {
+            Some(existing) => existing.extend(parameters),
+            None => get["parameters"] = json!(parameters),
+        }
+        get["responses"]["304"] =
+            json!({ "description": "Unchanged since the given ETag or date" });
+    }
+}
+
+fn session_paths() -> serde_json::Value {
+    json!({
+        "/api/new_session": {
+            "post": {
+                "summary": "Create a new anonymous session",
+                "description": "When TLS client authentication is enabled and the client \
+                    presents a trusted certificate, the session is created already \
+                    authenticated as the user mapped from the certificate's subject common \
+                    name. While proof_of_work is configured and more sessions are requested \
+                    than it allows, a 429 hands out a challenge; repeat the request with it and \
+                    a nonce such that SHA-256 of `challenge:nonce` starts with `difficulty_bits` \
+                    zero bits. Each solved challenge creates one session.",
+                "operationId": "newSession",
+                "parameters": [
+                    {
+                        "name": "pow_challenge",
+                        "in": "query",
+                        "required": false,
+                        "schema": { "type": "string" },
+                    },
+                    {
+                        "name": "pow_nonce",
+                        "in": "query",
+                        "required": false,
+                        "schema": { "type": "string", "maxLength": 64 },
+                    },
+                    idempotency_key_header_parameter(),
+                ],
+                "responses": {
+                    "200": json_response("The created session", "NewSessionResponse"),
+                    "400": json_response("Malformed Idempotency-Key", "ErrorResponse"),
+                    "409": json_response(
+                        "A request with the same Idempotency-Key is in progress",
+                        "ErrorResponse",
+                    ),
+                    "422": idempotency_mismatch_response(),
+                    "429": json_response(
+                        "A proof of work is needed, or the one given was wrong or already used",
+                        "ProofOfWorkChallenge",
+                    ),
+                    "503": json_response(
+                        "The session store is over sessions.memory_budget_bytes, or read-only mode \
+                            is on",
+                        "ErrorResponse",
+                    ),
+                },
+            },
+        },
+        "/api/authenticate": {
+            "post": {
+                "summary": "Authenticate a session with user name and password",
+                "description": "With sessions.rotate_on_authentication the session moves to \
+                    a new id, returned as `id_base64`, keeping its description and data; the \
+                    old id is revoked. An authenticated session can be authenticated again as \
+                    the same user. That re-authenticates it for operations that need a recent \
+                    login, such as revoking all sessions. When captcha is configured, repeated \
+                    failures for a user or from a network make the next attempts need a \
+                    `captcha_token` from the provider's widget. With tarpit configured, they \
+                    make the next attempts answer more slowly.",
+                "operationId": "authenticate",
+                "parameters": [idempotency_key_header_parameter()],
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/x-www-form-urlencoded": {
+                            "schema": schema_ref("AuthenticateForm"),
+                        },
+                    },
+                },
+                "responses": {
+                    "200": json_response(
+                        "Session authenticated or re-authenticated",
+                        "AuthenticateResponse",
+                    ),
+                    "400": json_response(
+                        "Malformed or unknown session id, session authenticated as another \
+                            user, or malformed Idempotency-Key",
+                        "ErrorResponse",
+                    ),
+                    "401": json_response(
+                        "Invalid user or password, or a CAPTCHA is needed or failed, with \
+                            the provider and site key to show its widget. The risk service \
+                            may ask for a CAPTCHA too.",
+                        "CaptchaChallenge",
+                    ),
+                    "403": json_response(
+                        "The user is deactivated, the sign-in is impossible travel from the \
+                            last one, or the risk service refused it",
+                        "ErrorResponse",
+                    ),
+                    "409": json_response(
+                        "The user reached sessions.max_per_user, or a request with the same \
+                            Idempotency-Key is in progress",
+                        "ErrorResponse",
+                    ),
+                    "422": idempotency_mismatch_response(),
+                    "429": json_response(
+                        "The attempt would be slowed down, but too many already are",
+                        "ErrorResponse",
+                    ),
+                    "503": json_response(
+                        "The authentication backend or CAPTCHA provider is unavailable, or \
+                            read-only mode is on",
+                        "ErrorResponse",
+                    ),
+                },
+            },
+        },
+        "/api/negotiate": {
+            "get": {
+                "summary": "Authenticate a session with Kerberos (SPNEGO)",
+                "description": "Without an `Authorization: Negotiate` header, responds with \
+                    401 and `WWW-Authenticate: Negotiate` so the browser retries with a \
+                    Kerberos token. On success the session moves to a new id, returned as \
+                    id_base64 and authenticated as the user mapped from the client principal. \
+                    The id passed in is revoked and never becomes authenticated.",
+                "operationId": "negotiate",
+                "parameters": [session_id_query_parameter()],
+                "responses": {
+                    "200": json_response("Session authenticated", "AuthenticateResponse"),
+                    "400": json_response(
+                        "Malformed or unknown session id, or session already authenticated",
+                        "ErrorResponse",
+                    ),
+                    "401": json_response(
+                        "Missing or rejected negotiate token",
+                        "ErrorResponse",
+                    ),
+                    "403": json_response(
+                        "The principal is not allowed to log in, the user is deactivated, or \
+                            the sign-in is impossible travel from the last one",
+                        "ErrorResponse",
+                    ),
+                    "404": json_response(
+                        "Negotiate authentication is not enabled",
+                        "ErrorResponse",
+                    ),
+                    "409": json_response(
+                        "The user reached sessions.max_per_user",
+                        "ErrorResponse",
+                    ),
+                    "503": json_response(
+                        "Read-only mode is on, see /api/admin/read_only",
+                        "ErrorResponse",
+                    ),
+                },
+            },
+        },
+        "/api/forward_auth": {
+            "get": {
+                "summary": "Check a request for a reverse proxy (forward auth)",
+                "description": "Meant for nginx `auth_request` and Traefik `forwardAuth`. \
+                    Reads the session id from the forward_auth cookie, which is set when a \
+                    session gets authenticated. Authenticated sessions get 200 with \
+                    `X-Auth-User` and `X-Auth-Roles` (comma-separated group names). \
+                    Otherwise responds 401, or redirects to forward_auth.login_url with the \
+                    original URL in `rd` when that is configured.",
+                "operationId": "forwardAuth",
+                "responses": {
+                    "200": {
+                        "description": "The session is authenticated",
+                        "headers": {
+                            "X-Auth-User": { "schema": { "type": "string" } },
+                            "X-Auth-Roles": { "schema": { "type": "string" } },
+                        },
+                    },
+                    "302": { "description": "Redirect to the configured login URL" },
+                    "401": json_response("Not authenticated", "ErrorResponse"),
+                    "403": json_response(
+                        "Session bound to a different client, or user deactivated",
+                        "ErrorResponse",
+                    ),
+                    "404": json_response(
+                        "Forward authentication is not enabled",
+                        "ErrorResponse",
+                    ),
+                },
+            },
+        },
+        "/api/revoke_session": {
+            "post": {
+                "summary": "Revoke a session",
+                "operationId": "revokeSession",
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/x-www-form-urlencoded": {
+                            "schema": schema_ref("RevokeSessionForm"),
+                        },
+                    },
+                },
+                "responses": {
+                    "200": json_response("Session revoked", "SuccessResponse"),
+                    "400": json_response("Malformed or unknown session id", "ErrorResponse"),
+                },
+            },
+        },
+        "/api/session": {
+            "patch": {
+                "summary": "Update a session",
+                "description": "Sets the session's description, for example a device name, \
+                    which is shown in session lists. Publishes an updated event.",
+                "operationId": "updateSession",
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/x-www-form-urlencoded": {
+                            "schema": schema_ref("UpdateSessionForm"),
+                        },
+                    },
+                },
+                "responses": {
+                    "200": json_response("Session updated", "SuccessResponse"),
+                    "400": json_response(
+                        "Malformed, unknown or expired session id, or invalid description",
+                        "ErrorResponse",
+                    ),
+                },
+            },
+        },
+        "/api/session/touch": {
+            "post": {
+                "summary": "Keep a session alive",
+                "description": "Moves the idle timeout of the session forward, up to its \
+                    absolute lifetime, and returns the new expiry. Any other request that uses \
+                    the session does the same; this endpoint is for explicit keep-alives.",
+                "operationId": "touchSession",
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/x-www-form-urlencoded": {
+                            "schema": schema_ref("TouchSessionForm"),
+                        },
+                    },
+                },
+                "responses": {
+                    "200": json_response("New expiry of the session", "SessionExpiry"),
+                    "400": json_response(
+                        "Malformed, unknown or expired session id",
+                        "ErrorResponse",
+                    ),
+                },
+            },
+        },
+        "/api/session/resume": {
+            "post": {
+                "summary": "Resume a session with a remember-me token",
+                "description": "Creates a new authenticated session from a remember-me token \
+                    issued by `/api/authenticate` with `remember_me=true`. The token is taken \
+                    from the form or from the remember-me cookie and is single use: the \
+                    response carries its successor. Replaying an already used token revokes \
+                    all remember-me tokens of the user.",
+                "operationId": "resumeSession",
+                "parameters": [idempotency_key_header_parameter()],
+                "requestBody": {
+                    "required": false,
+                    "content": {
+                        "application/x-www-form-urlencoded": {
+                            "schema": schema_ref("ResumeSessionForm"),
+                        },
+                    },
+                },
+                "responses": {
+                    "200": json_response("Session authenticated", "AuthenticateResponse"),
+                    "400": json_response("Malformed Idempotency-Key", "ErrorResponse"),
+                    "401": json_response("Missing, invalid or expired token", "ErrorResponse"),
+                    "403": json_response(
+                        "User is not active, or the sign-in is impossible travel from the last \
+                            one",
+                        "ErrorResponse",
+                    ),
+                    "404": json_response("Remember-me is not enabled", "ErrorResponse"),
+                    "409": json_response(
+                        "User has reached sessions.max_per_user, or a request with the same \
+                            Idempotency-Key is in progress",
+                        "ErrorResponse",
+                    ),
+                    "422": idempotency_mismatch_response(),
+                    "503": json_response(
+                        "The session store is over sessions.memory_budget_bytes, or read-only mode \
+                            is on",
+                        "ErrorResponse",
+                    ),
+                },
+            },
+        },
+        "/api/session_state": {
+            "get": {
+                "summary": "Get the state of a session",
+                "description": "Responses carry an ETag. With `If-None-Match` set to the \
+                    current ETag the server answers 304, or with `wait=true` holds the \
+                    request until the session changes or the timeout elapses. A sliding \
+                    session's expires_at moving counts as a change.",
+                "operationId": "sessionState",
+                "parameters": [
+                    session_id_query_parameter(),
+                    {
+                        "name": "wait",
+                        "in": "query",
+                        "required": false,
+                        "schema": { "type": "boolean", "default": false },
+                    },
+                    {
+                        "name": "timeout",
+                        "in": "query",
+                        "required": false,
+                        "description": "Long-poll timeout in seconds, at most 60",
+                        "schema": { "type": "integer", "default": 30, "maximum": 60 },
+                    },
+                    {
+                        "name": "If-None-Match",
+                        "in": "header",
+                        "required": false,
+                        "schema": { "type": "string" },
+                    },
+                ],
+                "responses": {
+                    "200": json_response("Current session state", "Session"),
+                    "304": { "description": "Session unchanged since the given ETag" },
+                    "400": json_response("Malformed or unknown session id", "ErrorResponse"),
+                },
+            },
+        },
+        "/api/session_state/batch": {
+            "post": {
+                "summary": "Get the state of many sessions",
+                "description": "For backend services that validate many sessions at once. \
+                    At most 100 ids per request; ids that are malformed, unknown or expired \
+                    come back with `found: false`. The lookups are read-only: session binding \
+                    isn't checked against the calling service and idle timeouts don't slide. \
+                    Needs the admin token or a request signed by an admin key.",
+                "operationId": "sessionStateBatch",
+                "security": [{ "adminBearer": [] }, { "requestSignature": [] }],
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/json": {
+                            "schema": schema_ref("SessionStateBatchRequest"),
+                        },
+                    },
+                },
+                "responses": {
+                    "200": json_response(
+                        "One entry per requested id, in order",
+                        "SessionStateBatchResponse",
+                    ),
+                    "400": json_response("Invalid batch or too many ids", "ErrorResponse"),
+                    "401": json_response("Invalid admin token", "ErrorResponse"),
+                    "404": json_response("The admin API is not enabled", "ErrorResponse"),
+                },
+            },
+        },
+        "/api/sessions/mine": {
+            "get": {
+                "summary": "List the sessions of the current user",
+                "description": "Returns the live authenticated sessions of the user the \
+                    given session is authenticated as, newest first unless `order=asc`.",
+                "operationId": "mySessions",
+                "parameters": [session_id_query_parameter()],
+                "responses": {
+                    "200": json_response("Sessions of the user", "SessionList"),
+                    "400": json_response(
+                        "Malformed, unknown or expired session id, not authenticated, or an \
+                            invalid limit or cursor",
+                        "ErrorResponse",
+                    ),
+                },
+            },
+        },
+        "/api/sessions/revoke_all": {
+            "post": {
+                "summary": "Log out everywhere",
+                "description": "Revokes every other session of the current user, and the \
+                    current one too with `include_current=true`, along with all remember-me \
+                    tokens of the user. The session must have been authenticated within \
+                    sessions.reauth_max_age_secs.",
+                "operationId": "revokeAllSessions",
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/x-www-form-urlencoded": {
+                            "schema": schema_ref("RevokeAllSessionsForm"),
+                        },
+                    },
+                },
+                "responses": {
+                    "200": json_response("Sessions revoked", "RevokeAllSessionsResponse"),
+                    "400": json_response(
+                        "Malformed, unknown or expired session id, or not authenticated",
+                        "ErrorResponse",
+                    ),
+                    "403": json_response(
+                        "The session was authenticated too long ago",
+                        "ErrorResponse",
+                    ),
+                },
+            },
+        },
+        "/api/sessions/batch": {
+            "post": {
+                "summary": "Create sessions in bulk",
+                "description": "Creates `count` anonymous sessions, or one session per entry \
+                    of `sessions`, optionally already authenticated as the given user. \
+                    Meant for load tests and migrations; at most 10000 sessions per batch. \
+                    The batch isn't checked against sessions.max_per_user.",
+                "operationId": "batchSessions",
+                "security": [{ "adminBearer": [] }, { "requestSignature": [] }],
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/json": { "schema": schema_ref("BatchSessionsRequest") },
+                    },
+                },
+                "responses": {
+                    "200": json_response(
+                        "Ids of the new sessions, in order",
+                        "BatchSessionsResponse",
+                    ),
+                    "400": json_response("Invalid batch or deactivated user", "ErrorResponse"),
+                    "401": json_response("Invalid admin token", "ErrorResponse"),
+                    "404": json_response("The admin API is not enabled", "ErrorResponse"),
+                    "503": json_response(
+                        "The session store is over sessions.memory_budget_bytes, or read-only mode \
+                            is on",
+                        "ErrorResponse",
+                    ),
+                },
+            },
+        },
+        "/api/devices": {
+            "get": {
+                "summary": "List the devices of the current user",
+                "description": "Returns the devices the user has authenticated from, told \
+                    apart by user agent, with the number of live sessions on each. The \
+                    most recently added come first unless `order=asc`.",
+                "operationId": "myDevices",
+                "parameters": [session_id_query_parameter()],
+                "responses": {
+                    "200": json_response("Devices of the user", "DeviceList"),
+                    "400": json_response(
+                        "Malformed, unknown or expired session id, not authenticated, or an \
+                            invalid limit or cursor",
+                        "ErrorResponse",
+                    ),
+                },
+            },
+        },
+        "/api/devices/{id}": {
+            "delete": {
+                "summary": "Remove a device",
+                "description": "Forgets the device and revokes its sessions and remember-me \
+                    tokens. The session must have been authenticated within \
+                    sessions.reauth_max_age_secs.",
+                "operationId": "removeDevice",
+                "parameters": [
+                    {
+                        "name": "id",
+                        "in": "path",
+                        "required": true,
+                        "schema": { "type": "string" },
+                    },
+                    session_id_query_parameter(),
+                ],
+                "responses": {
+                    "200": json_response("Device removed", "RevokeAllSessionsResponse"),
+                    "400": json_response(
+                        "Malformed, unknown or expired session id, or not authenticated",
+                        "ErrorResponse",
+                    ),
+                    "403": json_response(
+                        "The session was authenticated too long ago",
+                        "ErrorResponse",
+                    ),
+                    "404": json_response("No such device", "ErrorResponse"),
+                },
+            },
+        },
+        "/api/notification_preferences": {
+            "get": {
+                "summary": "Show the current user's notification preferences",
+                "description": "Lists the security notifications the user can choose \
+                    channels for, with the channels they go to and those they could.",
+                "operationId": "notificationPreferences",
+                "parameters": [session_id_query_parameter()],
+                "responses": {
+                    "200": json_response(
+                        "Notification preferences of the user",
+                        "NotificationPreferences",
+                    ),
+                    "400": json_response(
+                        "Malformed, unknown or expired session id, or not authenticated",
+                        "ErrorResponse",
+                    ),
+                    "404": json_response(
+                        "The user isn't in the user store",
+                        "ErrorResponse",
+                    ),
+                },
+            },
+            "put": {
+                "summary": "Choose notification channels",
+                "description": "Sets the channels of the given notifications; null goes \
+                    back to the default and notifications left out stay as they are. The \
+                    session must have been authenticated within \
+                    sessions.reauth_max_age_secs.",
+                "operationId": "updateNotificationPreferences",
+                "parameters": [session_id_query_parameter()],
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/json": {
+                            "schema": {
+                                "$ref": "#/components/schemas/NotificationPreferencesUpdate",
+                            },
+                        },
+                    },
+                },
+                "responses": {
+                    "200": json_response(
+                        "Updated notification preferences",
+                        "NotificationPreferences",
+                    ),
+                    "400": json_response(
+                        "Malformed body, unknown notification or channel, or malformed, \
+                            unknown, expired or unauthenticated session id",
+                        "ErrorResponse",
+                    ),
+                    "403": json_response(
+                        "The session was authenticated too long ago",
+                        "ErrorResponse",
+                    ),
+                    "404": json_response(
+                        "The user isn't in the user store",
+                        "ErrorResponse",
+                    ),
+                },
+            },
+        },
+        "/api/notifications": {
+            "get": {
+                "summary": "List the current user's notifications",
+                "description": "Only available with inbox configured. Security notifications \
+                    sent to the inbox channel, newest first unless `order=asc`. `unread` \
+                    counts all unread notifications, not only those on the page.",
+                "operationId": "notifications",
+                "parameters": [
+                    session_id_query_parameter(),
+                    {
+                        "name": "unread_only",
+                        "in": "query",
+                        "required": false,
+                        "schema": { "type": "boolean", "default": false },
+                    },
+                ],
+                "responses": {
+                    "200": json_response("Notifications of the user", "NotificationInbox"),
+                    "400": json_response(
+                        "Malformed, unknown or expired session id, not authenticated, or an \
+                            invalid limit or cursor",
+                        "ErrorResponse",
+                    ),
+                    "404": json_response("The inbox is not enabled", "ErrorResponse"),
+                },
+            },
+        },
+        "/api/notifications/{id}/read": {
+            "post": {
+                "summary": "Mark a notification as read",
+                "operationId": "markNotificationRead",
+                "parameters": [
+                    {
+                        "name": "id",
+                        "in": "path",
+                        "required": true,
+                        "schema": { "type": "integer" },
+                    },
+                    session_id_query_parameter(),
+                ],
+                "responses": {
+                    "200": json_response("Marked as read", "MarkedRead"),
+                    "400": json_response(
+                        "Malformed, unknown or expired session id, or not authenticated",
+                        "ErrorResponse",
+                    ),
+                    "404": json_response(
+                        "The inbox is not enabled, or the user has no such notification",
+                        "ErrorResponse",
+                    ),
+                },
+            },
+        },
+        "/api/notifications/read_all": {
+            "post": {
+                "summary": "Mark all of the current user's notifications as read",
+                "operationId": "markAllNotificationsRead",
+                "parameters": [session_id_query_parameter()],
+                "responses": {
+                    "200": json_response("Marked as read", "MarkedRead"),
+                    "400": json_response(
+                        "Malformed, unknown or expired session id, or not authenticated",
+                        "ErrorResponse",
+                    ),
+                    "404": json_response("The inbox is not enabled", "ErrorResponse"),
+                },
+            },
+        },
+        "/api/session_data/{key}": {
+            "parameters": [
+                {
+                    "name": "key",
+                    "in": "path",
+                    "required": true,
+                    "description": "1 to 128 characters of A-Z, a-z, 0-9, '.', '_' and '-'",
+                    "schema": { "type": "string" },
+                },
+                session_id_query_parameter(),
+            ],
+            "get": {
+                "summary": "Read a value stored on a session",
+                "operationId": "getSessionData",
+                "responses": {
+                    "200": {
+                        "description": "The stored JSON value",
+                        "content": { "application/json": { "schema": {} } },
+                    },
+                    "400": json_response("Malformed or unknown session id", "ErrorResponse"),
+                    "404": json_response("Nothing stored under the key", "ErrorResponse"),
+                },
+            },
+            "put": {
+                "summary": "Store a value on a session",
+                "description": "Any JSON value can be stored. All keys and values of a session \
+                    together are limited to sessions.max_data_bytes.",
+                "operationId": "putSessionData",
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/json": { "schema": {} } },
+                },
+                "responses": {
+                    "200": json_response("Value stored", "SuccessResponse"),
+                    "400": json_response(
+                        "Malformed or unknown session id, invalid key or invalid JSON",
+                        "ErrorResponse",
+                    ),
+                    "403": json_response("The session isn't authenticated", "ErrorResponse"),
+                    "413": json_response(
+                        "The session data would get too large",
+                        "ErrorResponse",
+                    ),
+                    "503": json_response(
+                        "The session store is over sessions.memory_budget_bytes",
+                        "ErrorResponse",
+                    ),
+                },
+            },
+            "delete": {
+                "summary": "Delete a value stored on a session",
+                "operationId": "deleteSessionData",
+                "responses": {
+                    "200": json_response("Value deleted", "SuccessResponse"),
+                    "400": json_response("Malformed or unknown session id", "ErrorResponse"),
+                    "403": json_response("The session isn't authenticated", "ErrorResponse"),
+                    "404": json_response("Nothing stored under the key", "ErrorResponse"),
+                },
+            },
+        },
+        "/api/session_stream": {
+            "get": {
+                "summary": "Stream the state of a session as server-sent events",
+                "description": "Sends a `session` event with the Session JSON immediately and \
+                    again whenever the session changes. A final `revoked` event is sent when \
+                    the session is revoked, after which the stream ends.",
+                "operationId": "sessionStream",
+                "parameters": [session_id_query_parameter()],
+                "responses": {
+                    "200": {
+                        "description": "Event stream",
+                        "content": {
+                            "text/event-stream": { "schema": { "type": "string" } },
+                        },
+                    },
+                    "400": json_response("Malformed or unknown session id", "ErrorResponse"),
+                },
+            },
+        },
+        "/api/session_events": {
+            "get": {
+                "summary": "Stream events of a session over WebSocket",
+                "description": "Upgrades to a WebSocket connection. Each text message is a JSON \
+                    SessionEvent. The server closes the connection after a revoked event.",
+                "operationId": "sessionEvents",
+                "parameters": [session_id_query_parameter()],
+                "responses": {
+                    "101": { "description": "Switching to the WebSocket protocol" },
+                    "400": json_response(
+                        "Malformed or unknown session id, or not a WebSocket upgrade",
+                        "ErrorResponse",
+                    ),
+                },
+            },
+        },
+    })
+}
+
+fn other_paths() -> serde_json::Value {
+    json!({
+        "/api/branding": {
+            "get": {
+                "summary": "Get the configured branding",
+                "description": "The product name, logo, colors and footer links the \
+                    server-rendered pages use, for clients that want to match them.",
+                "operationId": "branding",
+                "responses": {
+                    "200": json_response("The branding", "Branding"),
+                },
+            },
+        },
+        "/api/usage": {
+            "get": {
+                "summary": "Get the request quota usage of the caller",
+                "description": "Only available with session_quota or a quota on a \
+                    request_signing key. A request signed with a key gets the key's usage; \
+                    otherwise that of the session in `session_id`. Counts are per minute and \
+                    per UTC day, kept by each instance. A null `limit` means no limit.",
+                "operationId": "usage",
+                "security": [{}, { "requestSignature": [] }],
+                "parameters": [
+                    {
+                        "name": "session_id",
+                        "in": "query",
+                        "required": false,
+                        "schema": { "type": "string" },
+                    },
+                ],
+                "responses": {
+                    "200": json_response("Requests counted and left", "QuotaUsage"),
+                    "401": json_response(
+                        "Neither signed nor given an existing session",
+                        "ErrorResponse",
+                    ),
+                    "404": json_response("Quotas are not enabled", "ErrorResponse"),
+                },
+            },
+        },
+        "/readyz": {
+            "get": {
+                "summary": "Tell whether this instance is ready to serve",
+                "description": "Lists the storage backends with whether their last probe or \
+                    write succeeded. While one fails, sessions are still served from memory \
+                    and their changes queued, so the status is degraded but the response a 200, \
+                    unless health.unready_when_degraded is set.",
+                "operationId": "readiness",
+                "responses": {
+                    "200": json_response("Ready, or degraded", "Readiness"),
+                    "503": json_response(
+                        "Degraded, with health.unready_when_degraded",
+                        "ErrorResponse",
+                    ),
+                },
+            },
+        },
+        "/api/idp/providers": {
+            "get": {
+                "summary": "List the configured upstream identity providers",
+                "operationId": "idpProviders",
+                "responses": {
+                    "200": json_response(
+                        "Providers to offer on the login page",
+                        "IdpProviders",
+                    ),
+                },
+            },
+        },
+        "/api/idp/{provider}/login": {
+            "get": {
+                "summary": "Start a login through an upstream identity provider",
+                "description": "Redirects to the provider's authorization endpoint (OpenID \
+                    Connect authorization code flow with PKCE). With `link=true` the session \
+                    must already be authenticated, and the identity the provider returns \
+                    gets linked to the session's user instead. Sets the __Host-tk-auth-idp \
+                    cookie that ties the login to this browser.",
+                "operationId": "idpLogin",
+                "parameters": [
+                    idp_provider_path_parameter(),
+                    session_id_query_parameter(),
+                    {
+                        "name": "link",
+                        "in": "query",
+                        "required": false,
+                        "schema": { "type": "boolean", "default": false },
+                    },
+                ],
+                "responses": {
+                    "302": { "description": "Redirect to the identity provider" },
+                    "400": json_response(
+                        "Malformed or unknown session id, or wrong session state for the mode",
+                        "ErrorResponse",
+                    ),
+                    "404": json_response("Unknown identity provider", "ErrorResponse"),
+                    "502": json_response(
+                        "The identity provider is unavailable",
+                        "ErrorResponse",
+                    ),
+                    "503": json_response(
+                        "Read-only mode is on, see /api/admin/read_only",
+                        "ErrorResponse",
+                    ),
+                },
+            },
+        },
+        "/api/idp/{provider}/callback": {
+            "get": {
+                "summary": "Finish a login through an upstream identity provider",
+                "description": "The redirect URI registered with the provider. Signs in the \
+                    local user linked to the returned identity. Unlinked identities are linked \
+                    by verified email or get a new user when the provider allows it, unless \
+                    disposable_emails refuses their email address. Only works in the browser \
+                    that started the login (the __Host-tk-auth-idp cookie). The session always \
+                    moves to a new id, returned as id_base64; the old one stops working. \
+                    Redirects to idp.after_login_url when that is configured.",
+                "operationId": "idpCallback",
+                "parameters": [
+                    idp_provider_path_parameter(),
+                    {
+                        "name": "state",
+                        "in": "query",
+                        "required": true,
+                        "schema": { "type": "string" },
+                    },
+                    {
+                        "name": "code",
+                        "in": "query",
+                        "required": false,
+                        "schema": { "type": "string" },
+                    },
+                ],
+                "responses": {
+                    "200": json_response(
+                        "Session authenticated or identity linked",
+                        "AuthenticateResponse",
+                    ),
+                    "302": { "description": "Redirect to idp.after_login_url" },
+                    "400": json_response(
+                        "Unknown or expired login state, or the login was started in another \
+                            browser",
+                        "ErrorResponse",
+                    ),
+                    "401": json_response("The identity provider login failed", "ErrorResponse"),
+                    "403": json_response(
+                        "The identity isn't linked to a user, the user is deactivated, the new \
+                            user's email address is disposable, or the sign-in is impossible \
+                            travel from the last one",
+                        "ErrorResponse",
+                    ),
+                    "409": json_response(
+                        "The identity is linked to another user, the user name is taken, or \
+                            the user reached sessions.max_per_user",
+                        "ErrorResponse",
+                    ),
+                    "503": json_response(
+                        "Read-only mode is on, see /api/admin/read_only",
+                        "ErrorResponse",
+                    ),
+                },
+            },
+        },
+        "/api/idp/identities": {
+            "get": {
+                "summary": "List the identities linked to the session's user",
+                "operationId": "idpIdentities",
+                "parameters": [session_id_query_parameter()],
+                "responses": {
+                    "200": json_response("Linked identities", "LinkedIdentities"),
+                    "400": json_response(
+                        "Malformed or unknown session id, or session not authenticated",
+                        "ErrorResponse",
+                    ),
+                },
+            },
+        },
+        "/api/idp/unlink": {
+            "post": {
+                "summary": "Unlink an identity from the session's user",
+                "operationId": "idpUnlink",
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/x-www-form-urlencoded": {
+                            "schema": schema_ref("UnlinkIdentityForm"),
+                        },
+                    },
+                },
+                "responses": {
+                    "200": json_response("Identity unlinked", "SuccessResponse"),
+                    "400": json_response(
+                        "Malformed or unknown session id, or session not authenticated",
+                        "ErrorResponse",
+                    ),
+                    "404": json_response("No such linked identity", "ErrorResponse"),
+                },
+            },
+        },
+        "/api/admin/import_users": {
+            "post": {
+                "summary": "Import users in bulk",
+                "description": "Accepts CSV with a header row or a JSON array of objects, \
+                    with the fields user_name, email, password_hash and force_reset. \
+                    Besides argon2, password_hash may be a bcrypt, scrypt or PBKDF2 hash \
+                    (passlib or Django format), which is rehashed to argon2id on the \
+                    user's next successful login. Existing users are matched by user name, so re-running \
+                    an import only applies what changed. Rows that fail are reported and \
+                    skipped; the rest are imported.",
+                "operationId": "importUsers",
+                "security": [{ "adminBearer": [] }, { "requestSignature": [] }],
+                "parameters": [
+                    {
+                        "name": "dry_run",
+                        "in": "query",
+                        "schema": { "type": "boolean", "default": false },
+                    },
+                    {
+                        "name": "format",
+                        "in": "query",
+                        "description": "Overrides the format implied by Content-Type",
+                        "schema": { "type": "string", "enum": ["csv", "json"] },
+                    },
+                ],
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "text/csv": { "schema": { "type": "string" } },
+                        "application/json": {
+                            "schema": { "type": "array", "items": { "type": "object" } },
+                        },
+                    },
+                },
+                "responses": {
+                    "200": json_response("Per-row import report", "ImportReport"),
+                    "400": json_response("The file could not be parsed", "ErrorResponse"),
+                    "401": json_response("Invalid admin token", "ErrorResponse"),
+                    "404": json_response("The admin API is not enabled", "ErrorResponse"),
+                    "415": json_response("Unknown import format", "ErrorResponse"),
+                },
+            },
+        },
+        "/api/admin/password_migration": {
+            "get": {
+                "summary": "Report progress of migrating imported password hashes",
+                "operationId": "passwordMigration",
+                "security": [{ "adminBearer": [] }, { "requestSignature": [] }],
+                "responses": {
+                    "200": json_response("Migration progress", "PasswordMigration"),
+                    "401": json_response("Invalid admin token", "ErrorResponse"),
+                    "404": json_response("The admin API is not enabled", "ErrorResponse"),
+                },
+            },
+        },
+        "/api/admin/metrics": {
+            "get": {
+                "summary": "Metrics in the Prometheus text format",
+                "description": "Reports the number of sessions, their approximate memory use, \
+                    how often sessions.memory_budget_bytes led to evictions or rejections, \
+                    the sessions removed by expiry sweeps, and the time taken and failures \
+                    of storage operations by operation: expiry_sweep, journal_write, \
+                    snapshot and users_write.",
+                "operationId": "metrics",
+                "security": [{ "adminBearer": [] }, { "requestSignature": [] }],
+                "responses": {
+                    "200": {
+                        "description": "The metrics",
+                        "content": { "text/plain": { "schema": { "type": "string" } } },
+                    },
+                    "401": json_response("Invalid admin token", "ErrorResponse"),
+                    "404": json_response("The admin API is not enabled", "ErrorResponse"),
+                },
+            },
+        },
+        "/api/admin/maintenance": {
+            "post": {
+                "summary": "Run storage maintenance now",
+                "description": "Removes expired sessions, and with sessions.persistence \
+                    compacts the session journal into a snapshot, without waiting for their \
+                    intervals. `compaction` is null without sessions.persistence.",
+                "operationId": "runMaintenance",
+                "security": [{ "adminBearer": [] }, { "requestSignature": [] }],
+                "responses": {
+                    "200": json_response("What maintenance did", "Maintenance"),
+                    "401": json_response("Invalid admin token", "ErrorResponse"),
+                    "404": json_response("The admin API is not enabled", "ErrorResponse"),
+                    "500": json_response("The snapshot could not be written", "ErrorResponse"),
+                },
+            },
+        },
+        "/api/admin/read_only": {
+            "get": {
+                "summary": "Tell whether read-only mode is on",
+                "operationId": "getReadOnly",
+                "security": [{ "adminBearer": [] }, { "requestSignature": [] }],
+                "responses": {
+                    "200": json_response("The mode", "ReadOnly"),
+                    "401": json_response("Invalid admin token", "ErrorResponse"),
+                    "404": json_response("The admin API is not enabled", "ErrorResponse"),
+                },
+            },
+            "put": {
+                "summary": "Turn read-only mode on or off",
+                "description": "While on, sessions can still be looked up, checked and \
+                    revoked, but routes that create or sign in sessions answer 503 with the \
+                    code read_only and the message in `maintenance_message`, for migrating the \
+                    stores safely. The mode is kept in this instance's memory only.",
+                "operationId": "setReadOnly",
+                "security": [{ "adminBearer": [] }, { "requestSignature": [] }],
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/json": {
+                            "schema": {
+                                "type": "object",
+                                "required": ["read_only"],
+                                "properties": {
+                                    "read_only": { "type": "boolean" },
+                                    "message": {
+                                        "type": "string",
+                                        "description": "Shown to users who try to sign in",
+                                    },
+                                },
+                            },
+                        },
+                    },
+                },
+                "responses": {
+                    "200": json_response("The mode", "ReadOnly"),
+                    "400": json_response("Invalid request", "ErrorResponse"),
+                    "401": json_response("Invalid admin token", "ErrorResponse"),
+                    "404": json_response("The admin API is not enabled", "ErrorResponse"),
+                },
+            },
+        },
+        "/api/admin/deliveries": {
+            "get": {
+                "summary": "List queued notifications",
+                "description": "Only available with delivery_queue configured. Delivered \
+                    notifications are listed for delivery_queue.keep_delivered_secs, oldest \
+                    first unless `order=desc`.",
+                "operationId": "listDeliveries",
+                "security": [{ "adminBearer": [] }, { "requestSignature": [] }],
+                "parameters": [
+                    {
+                        "name": "status",
+                        "in": "query",
+                        "required": false,
+                        "schema": { "$ref": "#/components/schemas/DeliveryStatus" },
+                    },
+                ],
+                "responses": {
+                    "200": json_response("The queued notifications", "Deliveries"),
+                    "400": json_response("Invalid limit or cursor", "ErrorResponse"),
+                    "401": json_response("Invalid admin token", "ErrorResponse"),
+                    "404": json_response(
+                        "The admin API or the delivery queue is not enabled",
+                        "ErrorResponse",
+                    ),
+                },
+            },
+        },
+        "/api/admin/deprecations": {
+            "get": {
+                "summary": "Report use of deprecated routes",
+                "description": "Lists the routes in deprecations with the clients that used \
+                    them since this instance started, most recent first. Signed requests are \
+                    counted by key, others by address. Responses of these routes carry \
+                    Deprecation and Sunset headers, and a Link to the deprecation's page.",
+                "operationId": "deprecations",
+                "security": [{ "adminBearer": [] }, { "requestSignature": [] }],
+                "responses": {
+                    "200": json_response("Use of the deprecated routes", "Deprecations"),
+                    "401": json_response("Invalid admin token", "ErrorResponse"),
+                    "404": json_response("The admin API is not enabled", "ErrorResponse"),
+                },
+            },
+        },
+        "/api/admin/deliveries/{id}/retry": {
+            "post": {
+                "summary": "Retry a pending or dead-lettered notification now",
+                "description": "Starts over with delivery_queue.max_attempts attempts.",
+                "operationId": "retryDelivery",
+                "security": [{ "adminBearer": [] }, { "requestSignature": [] }],
+                "parameters": [
+                    {
+                        "name": "id",
+                        "in": "path",
+                        "required": true,
+                        "schema": { "type": "string" },
+                    },
+                ],
+                "responses": {
+                    "200": json_response("Queued again", "Delivery"),
+                    "401": json_response("Invalid admin token", "ErrorResponse"),
+                    "404": json_response(
+                        "The admin API is not enabled, or no undelivered delivery has this id",
+                        "ErrorResponse",
+                    ),
+                },
+            },
+        },
+        "/scim/v2/Users": {
+            "get": scim_operation(
+                "List users",
+                "listScimUsers",
+                "Supports `filter` (eq, ne, co, sw, ew and pr joined by `and`), \
+                    `startIndex` and `count`.",
+            ),
+            "post": scim_operation("Provision a user", "createScimUser", ""),
+        },
+        "/scim/v2/Users/{id}": {
+            "parameters": [scim_id_path_parameter()],
+            "get": scim_operation("Get a user", "getScimUser", ""),
+            "put": scim_operation("Replace a user", "replaceScimUser", ""),
+            "patch": scim_operation(
+                "Modify a user with a PatchOp request",
+                "patchScimUser",
+                "Setting `active` to false revokes all sessions of the user.",
+            ),
+            "delete": scim_operation(
+                "Deprovision a user",
+                "deleteScimUser",
+                "Revokes all sessions of the user.",
+            ),
+        },
+        "/scim/v2/Groups": {
+            "get": scim_operation("List groups", "listScimGroups", ""),
+            "post": scim_operation("Create a group", "createScimGroup", ""),
+        },
+        "/scim/v2/Groups/{id}": {
+            "parameters": [scim_id_path_parameter()],
+            "get": scim_operation("Get a group", "getScimGroup", ""),
+            "put": scim_operation("Replace a group", "replaceScimGroup", ""),
+            "patch": scim_operation(
+                "Modify a group with a PatchOp request",
+                "patchScimGroup",
+                "",
+            ),
+            "delete": scim_operation("Delete a group", "deleteScimGroup", ""),
+        },
+        "/scim/v2/ServiceProviderConfig": {
+            "get": scim_operation(
+                "Describe the supported SCIM features",
+                "getScimServiceProviderConfig",
+                "",
+            ),
+        },
+    })
+}
+
+// A single json! literal with every schema exceeds the macro recursion limit,
+// so they are kept in two groups.
+fn schemas() -> serde_json::Value {
+    let mut schemas = session_schemas();
+    if let (Some(schemas), serde_json::Value::Object(more)) =
+        (schemas.as_object_mut(), other_schemas())
+    {
+        schemas.extend(more);
+    }
+    schemas
+}
+
+fn session_schemas() -> serde_json::Value {
+    json!({
+        "Session": {
+            "type": "object",
+            "required": ["user", "description", "authenticated", "expires_at"],
+            "properties": {
+                "user": { "type": "string", "nullable": true },
+                "description": { "type": "string" },
+                "authenticated": { "type": "boolean" },
+                "expires_at": {
+                    "type": "integer",
+                    "nullable": true,
+                    "description": "Unix time at which the session expires, or null if \
+                        it never does. Sessions with an idle timeout move this forward on \
+                        every request that uses them.",
+                },
+            },
+        },
+        "NewSessionResponse": {
+            "type": "object",
+            "required": ["id_base64"],
+            "properties": {
+                "id_base64": {
+                    "type": "string",
+                    "description": "URL-safe base64 session id without padding",
+                },
+            },
+        },
+        "AuthenticateForm": {
+            "type": "object",
+            "required": ["session_id", "user", "password"],
+            "properties": {
+                "session_id": { "type": "string" },
+                "user": { "type": "string" },
+                "password": { "type": "string", "format": "password" },
+                "remember_me": {
+                    "type": "boolean",
+                    "default": false,
+                    "description": "Also issue a remember-me token, if remember-me is enabled",
+                },
+                "captcha_token": {
+                    "type": "string",
+                    "description": "Token from the CAPTCHA widget, once a 401 asked for one",
+                },
+            },
+        },
+        "ResumeSessionForm": {
+            "type": "object",
+            "properties": {
+                "remember_token": { "type": "string" },
+            },
+        },
+        "RevokeSessionForm": {
+            "type": "object",
+            "required": ["session_id"],
+            "properties": {
+                "session_id": { "type": "string" },
+            },
+        },
+        "UpdateSessionForm": {
+            "type": "object",
+            "required": ["session_id", "description"],
+            "properties": {
+                "session_id": { "type": "string" },
+                "description": { "type": "string", "minLength": 1, "maxLength": 100 },
+            },
+        },
+        "TouchSessionForm": {
+            "type": "object",
+            "required": ["session_id"],
+            "properties": {
+                "session_id": { "type": "string" },
+            },
+        },
+        "SessionList": {
+            "type": "object",
+            "properties": {
+                "sessions": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "current": {
+                                "type": "boolean",
+                                "description": "Whether this is the session asking",
+                            },
+                            "description": { "type": "string" },
+                            "ip": { "type": "string" },
+                            "user_agent": { "type": "string", "nullable": true },
+                            "country": {
+                                "type": "string",
+                                "nullable": true,
+                                "description": "ISO 3166-1 code of the country signed in from",
+                            },
+                            "asn": {
+                                "type": "integer",
+                                "nullable": true,
+                                "description": "Autonomous system signed in from",
+                            },
+                            "created": { "type": "integer" },
+                            "last_seen": { "type": "integer" },
+                            "expires_at": { "type": "integer", "nullable": true },
+                        },
+                    },
+                },
+                "next_cursor": next_cursor_property(),
+            },
+        },
+        "SessionStateBatchRequest": {
+            "type": "object",
+            "required": ["session_ids"],
+            "properties": {
+                "session_ids": {
+                    "type": "array",
+                    "maxItems": 100,
+                    "items": { "type": "string" },
+                },
+            },
+        },
+        "SessionStateBatchResponse": {
+            "type": "object",
+            "required": ["sessions"],
+            "properties": {
+                "sessions": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["found", "state"],
+                        "properties": {
+                            "found": { "type": "boolean" },
+                            "state": {
+                                "allOf": [schema_ref("Session")],
+                                "nullable": true,
+                            },
+                        },
+                    },
+                },
+            },
+        },
+        "BatchSessionsRequest": {
+            "type": "object",
+            "properties": {
+                "count": { "type": "integer", "minimum": 0, "maximum": 10000 },
+                "sessions": {
+                    "type": "array",
+                    "maxItems": 10000,
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "user": { "type": "string" },
+                            "description": { "type": "string", "maxLength": 100 },
+                        },
+                    },
+                },
+            },
+        },
+        "BatchSessionsResponse": {
+            "type": "object",
+            "required": ["ids"],
+            "properties": {
+                "ids": { "type": "array", "items": { "type": "string" } },
+            },
+        },
+        "DeviceList": {
+            "type": "object",
+            "properties": {
+                "devices": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "id": { "type": "string" },
+                            "name": {
+                                "type": "string",
+                                "description": "Browser and OS read from the user agent",
+                            },
+                            "first_seen": { "type": "integer" },
+                            "last_seen": { "type": "integer" },
+                            "current": {
+                                "type": "boolean",
+                                "description": "Whether the session asking is on this device",
+                            },
+                            "sessions": { "type": "integer" },
+                        },
+                    },
+                },
+                "next_cursor": next_cursor_property(),
+            },
+        },
+        "NotificationPreferences": {
+            "type": "object",
+            "required": ["preferences"],
+            "properties": {
+                "preferences": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["event", "channels", "available_channels", "chosen"],
+                        "properties": {
+                            "event": {
+                                "type": "string",
+                                "description": "new_device, or security_digest, which is only \
+                                    sent once the user chooses email for it",
+                                "example": "new_device",
+                            },
+                            "channels": {
+                                "type": "array",
+                                "items": { "$ref": "#/components/schemas/NotificationChannel" },
+                            },
+                            "available_channels": {
+                                "type": "array",
+                                "items": { "$ref": "#/components/schemas/NotificationChannel" },
+                            },
+                            "chosen": {
+                                "type": "boolean",
+                                "description": "Whether channels is the user's choice \
+                                    rather than the default",
+                            },
+                        },
+                    },
+                },
+            },
+        },
+        "NotificationPreferencesUpdate": {
+            "type": "object",
+            "required": ["preferences"],
+            "properties": {
+                "preferences": {
+                    "type": "object",
+                    "description": "Channels per notification; null goes back to the default",
+                    "additionalProperties": {
+                        "type": "array",
+                        "nullable": true,
+                        "items": { "$ref": "#/components/schemas/NotificationChannel" },
+                    },
+                },
+            },
+        },
+        "NotificationChannel": {
+            "type": "string",
+            "enum": ["email", "webhook", "sms", "inbox"],
+        },
+        "NotificationInbox": {
+            "type": "object",
+            "required": ["notifications", "unread"],
+            "properties": {
+                "notifications": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["id", "event", "details", "created_at", "read"],
+                        "properties": {
+                            "id": { "type": "integer" },
+                            "event": { "type": "string", "example": "new_device" },
+                            "details": {
+                                "type": "object",
+                                "description": "The same fields the webhook gets, such as \
+                                    device_name, ip and revoke_url for new_device",
+                                "additionalProperties": true,
+                            },
+                            "created_at": { "type": "integer" },
+                            "read": { "type": "boolean" },
+                        },
+                    },
+                },
+                "unread": { "type": "integer" },
+                "next_cursor": next_cursor_property(),
+            },
+        },
+        "QuotaUsage": {
+            "type": "object",
+            "required": ["client", "per_minute", "per_day"],
+            "properties": {
+                "client": { "type": "string", "enum": ["session", "key"] },
+                "per_minute": schema_ref("QuotaWindow"),
+                "per_day": schema_ref("QuotaWindow"),
+            },
+        },
+        "QuotaWindow": {
+            "type": "object",
+            "required": ["limit", "used", "reset_secs"],
+            "properties": {
+                "limit": { "type": "integer", "nullable": true },
+                "used": { "type": "integer" },
+                "reset_secs": {
+                    "type": "integer",
+                    "description": "Seconds until the window ends",
+                },
+            },
+        },
+        "MarkedRead": {
+            "type": "object",
+            "required": ["marked_read"],
+            "properties": {
+                "marked_read": {
+                    "type": "integer",
+                    "description": "How many notifications were unread before",
+                },
+            },
+        },
+        "RevokeAllSessionsForm": {
+            "type": "object",
+            "required": ["session_id"],
+            "properties": {
+                "session_id": { "type": "string" },
+                "include_current": { "type": "boolean", "default": false },
+            },
+        },
+        "RevokeAllSessionsResponse": {
+            "type": "object",
+            "required": ["success", "revoked"],
+            "properties": {
+                "success": { "type": "string" },
+                "revoked": { "type": "integer" },
+            },
+        },
+        "SessionExpiry": {
+            "type": "object",
+            "required": ["expires_at"],
+            "properties": {
+                "expires_at": { "type": "integer", "nullable": true },
+            },
+        },
+        "SessionEvent": {
+            "type": "object",
+            "required": ["event"],
+            "properties": {
+                "event": {
+                    "type": "string",
+                    "enum": ["authenticated", "updated", "expiring_soon", "revoked"],
+                },
+                "user": {
+                    "type": "string",
+                    "description": "Present on authenticated events",
+                },
+                "expires_at": {
+                    "type": "integer",
+                    "description": "Present on expiring_soon events, sent \
+                        sessions.expiry.warn_before_secs before the session expires",
+                },
+            },
+        },
+    })
+}
+
+fn other_schemas() -> serde_json::Value {
+    json!({
+        "AuthenticateResponse": {
+            "type": "object",
+            "required": ["success"],
+            "properties": {
+                "success": { "type": "string" },
+                "id_base64": {
+                    "type": "string",
+                    "description": "Id of the authenticated session, which differs from the \
+                        submitted one when ids are rotated on authentication",
+                },
+                "remember_token": {
+                    "type": "string",
+                    "description": "Remember-me token for `/api/session/resume`, also set as \
+                        a cookie",
+                },
+            },
+        },
+        "SuccessResponse": {
+            "type": "object",
+            "required": ["success"],
+            "properties": {
+                "success": { "type": "string" },
+            },
+        },
+        "Branding": {
+            "type": "object",
+            "required": ["product_name", "accent_color", "background_color", "footer_links"],
+            "properties": {
+                "product_name": { "type": "string" },
+                "logo_url": { "type": "string", "nullable": true },
+                "accent_color": { "type": "string", "example": "#2563eb" },
+                "background_color": { "type": "string", "example": "#f4f4f5" },
+                "footer_links": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["label", "url"],
+                        "properties": {
+                            "label": { "type": "string" },
+                            "url": { "type": "string" },
+                        },
+                    },
+                },
+            },
+        },
+        "IdpProviders": {
+            "type": "object",
+            "properties": {
+                "providers": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "id": { "type": "string" },
+                            "display_name": { "type": "string" },
+                            "login_url": { "type": "string" },
+                        },
+                    },
+                },
+            },
+        },
+        "LinkedIdentities": {
+            "type": "object",
+            "properties": {
+                "identities": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "provider": { "type": "string" },
+                            "subject": { "type": "string" },
+                            "linked_at": { "type": "integer" },
+                        },
+                    },
+                },
+            },
+        },
+        "UnlinkIdentityForm": {
+            "type": "object",
+            "required": ["session_id", "provider", "subject"],
+            "properties": {
+                "session_id": { "type": "string" },
+                "provider": { "type": "string" },
+                "subject": { "type": "string" },
+            },
+        },
+        "ImportReport": {
+            "type": "object",
+            "properties": {
+                "dry_run": { "type": "boolean" },
+                "created": { "type": "integer" },
+                "updated": { "type": "integer" },
+                "unchanged": { "type": "integer" },
+                "failed": { "type": "integer" },
+                "rows": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "row": { "type": "integer" },
+                            "user_name": { "type": "string" },
+                            "status": {
+                                "type": "string",
+                                "enum": ["created", "updated", "unchanged", "error"],
+                            },
+                            "error": { "type": "string" },
+                        },
+                    },
+                },
+            },
+        },
+        "PasswordMigration": {
+            "type": "object",
+            "properties": {
+                "users_with_password": { "type": "integer" },
+                "legacy": {
+                    "type": "object",
+                    "description": "Number of users still on each legacy scheme",
+                    "additionalProperties": { "type": "integer" },
+                },
+                "migrated_on_login": { "type": "integer" },
+                "pending": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "user_name": { "type": "string" },
+                            "scheme": { "type": "string" },
+                        },
+                    },
+                },
+            },
+        },
+        "Readiness": {
+            "type": "object",
+            "required": ["status", "backends", "queued_session_changes"],
+            "properties": {
+                "status": { "type": "string", "enum": ["ready", "degraded"] },
+                "backends": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["backend", "healthy", "failing_since"],
+                        "properties": {
+                            "backend": { "type": "string", "enum": ["users", "sessions"] },
+                            "healthy": { "type": "boolean" },
+                            "failing_since": {
+                                "type": "string",
+                                "format": "date-time",
+                                "nullable": true,
+                            },
+                        },
+                    },
+                },
+                "queued_session_changes": { "type": "integer" },
+            },
+        },
+        "ReadOnly": {
+            "type": "object",
+            "required": ["read_only", "message", "since"],
+            "properties": {
+                "read_only": { "type": "boolean" },
+                "message": { "type": "string", "nullable": true },
+                "since": { "type": "string", "format": "date-time", "nullable": true },
+            },
+        },
+        "Maintenance": {
+            "type": "object",
+            "required": ["sweep", "compaction"],
+            "properties": {
+                "sweep": {
+                    "type": "object",
+                    "required": ["expired_sessions", "duration_secs"],
+                    "properties": {
+                        "expired_sessions": { "type": "integer" },
+                        "duration_secs": { "type": "number" },
+                    },
+                },
+                "compaction": {
+                    "type": "object",
+                    "nullable": true,
+                    "required": ["sessions", "duration_secs"],
+                    "properties": {
+                        "sessions": {
+                            "type": "integer",
+                            "description": "Sessions in the new snapshot",
+                        },
+                        "duration_secs": { "type": "number" },
+                    },
+                },
+            },
+        },
+        "DeliveryStatus": {
+            "type": "string",
+            "enum": ["pending", "delivered", "dead_lettered"],
+        },
+        "Delivery": {
+            "type": "object",
+            "required": [
+                "id", "channel", "event", "user", "status", "attempts", "created_at",
+                "updated_at",
+            ],
+            "properties": {
+                "id": { "type": "string" },
+                "channel": { "$ref": "#/components/schemas/NotificationChannel" },
+                "event": { "type": "string", "example": "new_device" },
+                "user": { "type": "string" },
+                "status": { "$ref": "#/components/schemas/DeliveryStatus" },
+                "attempts": { "type": "integer" },
+                "created_at": { "type": "integer" },
+                "updated_at": {
+                    "type": "integer",
+                    "description": "When the next attempt is due while pending, else when \
+                        the notification was delivered or dead-lettered",
+                },
+                "last_error": { "type": "string", "nullable": true },
+            },
+        },
+        "Deliveries": {
+            "type": "object",
+            "required": ["pending", "delivered", "dead_lettered", "deliveries"],
+            "properties": {
+                "pending": { "type": "integer" },
+                "delivered": { "type": "integer" },
+                "dead_lettered": { "type": "integer" },
+                "deliveries": {
+                    "type": "array",
+                    "items": { "$ref": "#/components/schemas/Delivery" },
+                },
+                "next_cursor": next_cursor_property(),
+            },
+        },
+        "DeprecatedRoute": {
+            "type": "object",
+            "required": ["route", "deprecated", "sunset", "requests", "last_used", "clients"],
+            "properties": {
+                "route": { "type": "string", "example": "/api/devices/:id" },
+                "deprecated": { "type": "string", "format": "date-time" },
+                "sunset": { "type": "string", "format": "date-time", "nullable": true },
+                "requests": { "type": "integer" },
+                "last_used": { "type": "string", "format": "date-time", "nullable": true },
+                "clients": {
+                    "type": "array",
+                    "items": { "$ref": "#/components/schemas/DeprecatedRouteClient" },
+                },
+            },
+        },
+        "DeprecatedRouteClient": {
+            "type": "object",
+            "required": ["client", "requests", "first_used", "last_used", "user_agent"],
+            "properties": {
+                "client": { "type": "string", "example": "key:ci" },
+                "requests": { "type": "integer" },
+                "first_used": { "type": "string", "format": "date-time" },
+                "last_used": { "type": "string", "format": "date-time" },
+                "user_agent": { "type": "string", "nullable": true },
+            },
+        },
+        "Deprecations": {
+            "type": "object",
+            "required": ["routes"],
+            "properties": {
+                "routes": {
+                    "type": "array",
+                    "items": { "$ref": "#/components/schemas/DeprecatedRoute" },
+                },
+            },
+        },
+        "ErrorResponse": envelope_schema(json!({
+            "type": "object",
+            "nullable": true,
+            "description": "Details of errors that have them, null otherwise",
+        })),
+        "ProofOfWorkChallenge": {
+            "type": "object",
+            "required": ["error", "challenge", "difficulty_bits", "expires_at"],
+            "properties": {
+                "error": { "type": "string" },
+                "challenge": { "type": "string" },
+                "difficulty_bits": { "type": "integer", "minimum": 1, "maximum": 32 },
+                "expires_at": { "type": "integer" },
+            },
+        },
+        "CaptchaChallenge": {
+            "type": "object",
+            "required": ["error"],
+            "properties": {
+                "error": { "type": "string" },
+                "provider": {
+                    "type": "string",
+                    "enum": ["hcaptcha", "recaptcha", "turnstile"],
+                    "description": "Present when a CAPTCHA is needed",
+                },
+                "site_key": {
+                    "type": "string",
+                    "description": "Present when a CAPTCHA is needed",
+                },
+            },
+        },
+    })
+}
+
+fn schema_ref(name: &str) -> serde_json::Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn json_response(description: &str, schema: &str) -> serde_json::Value {
+    json!({
+        "description": description,
+        "content": {
+            "application/json": {
+                "schema": schema_ref(schema),
+            },
+        },
+    })
+}
+
+fn session_id_query_parameter() -> serde_json::Value {
+    json!({
+        "name": "session_id",
+        "in": "query",
+        "required": true,
+        "schema": { "type": "string" },
+    })
+}
+
+// While idempotency is configured, repeating a request with the same key
+// from the same address returns the first response.
+fn idempotency_key_header_parameter() -> serde_json::Value {
+    json!({
+        "name": "Idempotency-Key",
+        "in": "header",
+        "required": false,
+        "description": "Repeating the request with the same key within \
+            idempotency.window_secs returns the original response, marked with an \
+            `Idempotent-Replayed: true` header, instead of acting again. Keys are \
+            scoped to the client address, session cookie and Authorization header",
+        "schema": { "type": "string", "minLength": 1, "maxLength": 255 },
+    })
+}
+
+fn idempotency_mismatch_response() -> serde_json::Value {
+    json_response(
+        "The Idempotency-Key was used for a different request",
+        "ErrorResponse",
+    )
+}
+
+fn next_cursor_property() -> serde_json::Value {
+    json!({
+        "type": "string",
+        "nullable": true,
+        "description": "Pass as `cursor` to get the next page; null on the last page",
+    })
+}
+
+fn idp_provider_path_parameter() -> serde_json::Value {
+    json!({
+        "name": "provider",
+        "in": "path",
+        "required": true,
+        "schema": { "type": "string" },
+    })
+}
+
+fn scim_operation(summary: &str, operation_id: &str, description: &str) -> serde_json::Value {
+    let mut operation = json!({
+        "summary": summary,
+        "operationId": operation_id,
+        "tags": ["SCIM"],
+        "security": [{ "scimBearer": [] }, { "requestSignature": [] }],
+        "responses": {
+            "default": {
+                "description": "A SCIM 2.0 resource, list response or error (RFC 7644)",
+                "content": {
+                    "application/scim+json": {
+                        "schema": { "type": "object" },
+                    },
+                },
+            },
+        },
+    });
+    if !description.is_empty() {
+        operation["description"] = json!(description);
+    }
+    operation
+}
+
+fn scim_id_path_parameter() -> serde_json::Value {
+    json!({
+        "name": "id",
+        "in": "path",
+        "required": true,
+        "schema": { "type": "string" },
+    })
+}
+
+// The files of api_docs.swagger_ui_dir the page loads, with their types.
+pub const SWAGGER_UI_FILES: [(&str, &str); 2] = [
+    ("swagger-ui.css", "text/css"),
+    ("swagger-ui-bundle.js", "text/javascript"),
+];
+
+pub const SWAGGER_UI_HTML: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>tk-auth API</title>
+<link rel="stylesheet" href="/api/docs/swagger-ui.css">
+</head>
+<body>
+<div id="swagger-ui"></div>
+<script src="/api/docs/swagger-ui-bundle.js"></script>
+<script>
+window.onload = () => {
+    window.ui = SwaggerUIBundle({ url: "/api/openapi.json", dom_id: "#swagger-ui" });
+};
+</script>
+</body>
+</html>
+"##;
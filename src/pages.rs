@@ -0,0 +1,651 @@
+use std::sync::Arc;
+
+use subtle::ConstantTimeEq;
+
+use crate::audit;
+use crate::captcha;
+use crate::client_info::ClientInfo;
+use crate::config::{self, RiskDecision};
+use crate::forward_auth;
+use crate::geoip;
+use crate::i18n;
+use crate::jwt::base64url_encode;
+use crate::risk;
+use crate::session::Session;
+use crate::{
+    authenticate, enforce_memory_budget, error_response, expiry_policy, lookup_session,
+    serialized_response, AppState, Authentication,
+};
+
+const CSRF_COOKIE: &str = "tk_auth_csrf";
+const CSRF_TOKEN_BYTES: usize = 32;
+const CSRF_COOKIE_MAX_AGE_SECS: u64 = 60 * 60;
+const NONCE_BYTES: usize = 16;
+
+const STYLE: &str = "\
+body { font-family: system-ui, sans-serif; background: var(--background); color: #18181b; \
+margin: 0; }
+main { max-width: 22rem; margin: 10vh auto; padding: 2rem; background: #fff; \
+border-radius: 0.5rem; box-shadow: 0 1px 3px rgba(0, 0, 0, 0.15); }
+h1 { font-size: 1.4rem; margin-top: 0; }
+label { display: block; margin-top: 1rem; font-weight: 600; }
+input { box-sizing: border-box; width: 100%; padding: 0.5rem; margin-top: 0.25rem; font: inherit; }
+button { margin-top: 1.5rem; width: 100%; padding: 0.6rem; font: inherit; cursor: pointer; \
+background: var(--accent); color: #fff; border: none; border-radius: 0.25rem; }
+a { color: var(--accent); }
+.logo { display: block; max-width: 10rem; max-height: 4rem; margin: 0 auto 1.5rem; }
+.error { color: #b91c1c; }
+.website { position: absolute; left: -10000px; }
+footer { text-align: center; font-size: 0.875rem; }
+footer a { margin: 0 0.5rem; }";
+
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// scheme://authority of an absolute URL, if it's safe to put in a CSP.
+fn origin(url: &str) -> Option<&str> {
+    let scheme_len = url.find("://")? + 3;
+    let authority_len = url[scheme_len..]
+        .find(['/', '?', '#'])
+        .unwrap_or(url.len() - scheme_len);
+    let origin = &url[..scheme_len + authority_len];
+    origin[scheme_len..]
+        .bytes()
+        .all(|byte| byte.is_ascii_alphanumeric() || b"-.:[]".contains(&byte))
+        .then_some(origin)
+}
+
+// The inline CSS is only allowed through the nonce of the response, and
+// nothing is loaded or submitted anywhere the pages don't need. Redirects
+// after signing in count as form submissions, so the redirect hosts are
+// allowed as form targets.
+fn content_security_policy(config: &config::Config, nonce: &str, captcha: bool) -> String {
+    let mut img_src = String::from("'self'");
+    if let Some(logo) = config.branding.logo_url.as_deref().and_then(origin) {
+        img_src.push(' ');
+        img_src.push_str(logo);
+    }
+    let mut form_action = String::from("'self'");
+    for host in config.pages.iter().flat_map(|pages| &pages.redirect_hosts) {
+        match host.strip_prefix('.') {
+            Some(domain) => form_action.push_str(&format!(" {} *.{}", domain, domain)),
+            None => form_action.push_str(&format!(" {}", host)),
+        }
+    }
+    let mut policy = format!(
+        "default-src 'none'; style-src 'nonce-{}'; img-src {}; form-action {}; \
+         frame-ancestors 'none'; base-uri 'none'",
+        nonce, img_src, form_action
+    );
+    // The CAPTCHA widget is the only script, and only where it's shown.
+    if let Some(captcha) = config.captcha.as_ref().filter(|_| captcha) {
+        let sources = captcha::content_sources(captcha.provider);
+        policy = policy.replace(
+            &format!("style-src 'nonce-{}'", nonce),
+            &format!(
+                "style-src 'nonce-{}' {sources}; script-src {sources}; frame-src {sources}; \
+                 connect-src {sources}",
+                nonce
+            ),
+        );
+    }
+    policy
+}
+
+// `body` has to be escaped already. Branding colors were checked to be hex
+// colors when the config was loaded, so they can go into the CSS as is.
+pub fn page(
+    state: &AppState,
+    language: i18n::Language,
+    status: u16,
+    title: &str,
+    body: &str,
+    captcha: bool,
+) -> axum::response::Response {
+    let branding = &state.config.branding;
+    let mut nonce = [0u8; NONCE_BYTES];
+    state.rng.fill(&mut nonce);
+    let nonce = base64url_encode(&nonce);
+    let logo = branding
+        .logo_url
+        .as_ref()
+        .map(|url| {
+            format!(
+                "<img class=\"logo\" src=\"{}\" alt=\"{}\">\n",
+                escape(url),
+                escape(&branding.product_name)
+            )
+        })
+        .unwrap_or_default();
+    let mut footer = String::new();
+    if !branding.footer_links.is_empty() {
+        footer.push_str("<footer>\n");
+        for link in &branding.footer_links {
+            footer.push_str(&format!(
+                "<a href=\"{}\">{}</a>\n",
+                escape(&link.url),
+                escape(&link.label)
+            ));
+        }
+        footer.push_str("</footer>\n");
+    }
+    let script = state
+        .config
+        .captcha
+        .as_ref()
+        .filter(|_| captcha)
+        .map(|captcha| {
+            format!(
+                "<script src=\"{}\" async defer></script>\n",
+                captcha::script_url(captcha.provider)
+            )
+        })
+        .unwrap_or_default();
+    let html = format!(
+        "<!DOCTYPE html>\n\
+         <html lang=\"{}\">\n\
+         <head>\n\
+         <meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{} - {}</title>\n\
+         <style nonce=\"{}\">\n:root {{ --accent: {}; --background: {}; }}\n{}\n</style>\n\
+         {}\
+         </head>\n\
+         <body>\n<main>\n{}{}</main>\n{}</body>\n\
+         </html>\n",
+        escape(language.tag()),
+        escape(language.t(title)),
+        escape(&branding.product_name),
+        nonce,
+        branding.accent_color,
+        branding.background_color,
+        STYLE,
+        script,
+        logo,
+        body,
+        footer
+    );
+    let mut response = axum::response::IntoResponse::into_response(axum::response::Html(html));
+    *response.status_mut() = http::StatusCode::from_u16(status).unwrap();
+    let headers = response.headers_mut();
+    headers.insert(
+        http::header::CACHE_CONTROL,
+        http::HeaderValue::from_static("no-store"),
+    );
+    headers.insert(
+        http::header::CONTENT_SECURITY_POLICY,
+        http::HeaderValue::from_str(&content_security_policy(&state.config, &nonce, captcha))
+            .unwrap(),
+    );
+    if let Ok(tag) = http::HeaderValue::from_str(language.tag()) {
+        headers.insert(http::header::CONTENT_LANGUAGE, tag);
+    }
+    headers.insert(
+        http::header::VARY,
+        http::HeaderValue::from_static("accept-language"),
+    );
+    response
+}
+
+// Only paths on this host and URLs on pages.redirect_hosts are followed, so
+// the login page can't be used to send people to arbitrary sites.
+fn safe_redirect(pages: &config::PagesConfig, rd: &str) -> Option<http::HeaderValue> {
+    // Browsers drop tabs and newlines from URLs and read backslashes as
+    // slashes, so "/\t/evil.example" would leave the site.
+    if rd.is_empty()
+        || rd
+            .chars()
+            .any(|c| c == '\\' || c.is_ascii_control() || c.is_whitespace())
+    {
+        return None;
+    }
+    let allowed = match rd.strip_prefix('/') {
+        Some(path) => !path.starts_with('/'),
+        None => {
+            let rest = rd
+                .strip_prefix("https://")
+                .or_else(|| rd.strip_prefix("http://"))?;
+            let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+            let host = match authority.rsplit_once(':') {
+                Some((host, port)) if port.bytes().all(|byte| byte.is_ascii_digit()) => host,
+                _ => authority,
+            }
+            .to_ascii_lowercase();
+            !authority.contains('@')
+                && pages
+                    .redirect_hosts
+                    .iter()
+                    .map(|allowed| allowed.to_ascii_lowercase())
+                    .any(|allowed| match allowed.strip_prefix('.') {
+                        Some(domain) => host == domain || host.ends_with(&format!(".{}", domain)),
+                        None => host == allowed,
+                    })
+        }
+    };
+    allowed
+        .then(|| http::HeaderValue::from_str(rd).ok())
+        .flatten()
+}
+
+// Double-submit token: the form has to echo the cookie, which other sites
+// can neither read nor set.
+fn csrf_token(state: &AppState, headers: &http::HeaderMap) -> (String, Option<http::HeaderValue>) {
+    if let Some(token) = forward_auth::session_cookie(headers, CSRF_COOKIE)
+        .filter(|token| token.len() == (CSRF_TOKEN_BYTES * 4).div_ceil(3))
+    {
+        return (token, None);
+    }
+    let mut token = [0u8; CSRF_TOKEN_BYTES];
+    state.rng.fill(&mut token);
+    let token = base64url_encode(&token);
+    let mut cookie = format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict",
+        CSRF_COOKIE, token, CSRF_COOKIE_MAX_AGE_SECS
+    );
+    if state
+        .config
+        .forward_auth
+        .as_ref()
+        .is_some_and(|forward_auth| forward_auth.cookie_secure)
+    {
+        cookie.push_str("; Secure");
+    }
+    (token, Some(http::HeaderValue::from_str(&cookie).unwrap()))
+}
+
+fn csrf_valid(headers: &http::HeaderMap, submitted: &str) -> bool {
+    forward_auth::session_cookie(headers, CSRF_COOKIE).is_some_and(|token| {
+        !token.is_empty() && bool::from(token.as_bytes().ct_eq(submitted.as_bytes()))
+    })
+}
+
+fn login_form(
+    state: &AppState,
+    headers: &http::HeaderMap,
+    status: u16,
+    rd: &str,
+    user: &str,
+    error: Option<&str>,
+    captcha: bool,
+) -> axum::response::Response {
+    let language = state.translations.negotiate(headers);
+    let (token, set_cookie) = csrf_token(state, headers);
+    let error = error
+        .map(|error| {
+            format!(
+                "<p class=\"error\" role=\"alert\">{}</p>\n",
+                escape(language.t(error))
+            )
+        })
+        .unwrap_or_default();
+    let widget = state
+        .config
+        .captcha
+        .as_ref()
+        .filter(|_| captcha)
+        .map(|captcha| {
+            format!(
+                "<div class=\"{}\" data-sitekey=\"{}\"></div>\n",
+                captcha::widget_class(captcha.provider),
+                escape(&captcha.site_key)
+            )
+        })
+        .unwrap_or_default();
+    // Off screen rather than display: none, which bots look out for.
+    let honeypot = if state
+        .config
+        .pages
+        .as_ref()
+        .is_some_and(|pages| pages.honeypot)
+    {
+        format!(
+            "<div class=\"website\" aria-hidden=\"true\">\n\
+             <label for=\"website\">{}</label>\n\
+             <input id=\"website\" name=\"website\" tabindex=\"-1\" autocomplete=\"off\">\n\
+             </div>\n",
+            escape(language.t("Website"))
+        )
+    } else {
+        String::new()
+    };
+    let body = format!(
+        "<h1>{}</h1>\n\
+         {}\
+         <form method=\"post\" action=\"/login\">\n\
+         <input type=\"hidden\" name=\"csrf_token\" value=\"{}\">\n\
+         <input type=\"hidden\" name=\"rd\" value=\"{}\">\n\
+         <label for=\"user\">{}</label>\n\
+         <input id=\"user\" name=\"user\" value=\"{}\" autocomplete=\"username\" \
+         required autofocus>\n\
+         <label for=\"password\">{}</label>\n\
+         <input id=\"password\" name=\"password\" type=\"password\" \
+         autocomplete=\"current-password\" required>\n\
+         {}\
+         {}\
+         <button type=\"submit\">{}</button>\n\
+         </form>\n",
+        escape(language.t("Sign in")),
+        error,
+        escape(&token),
+        escape(rd),
+        escape(language.t("User")),
+        escape(user),
+        escape(language.t("Password")),
+        honeypot,
+        widget,
+        escape(language.t("Sign in"))
+    );
+    let mut response = page(state, language, status, "Sign in", &body, captcha);
+    if let Some(set_cookie) = set_cookie {
+        response
+            .headers_mut()
+            .append(http::header::SET_COOKIE, set_cookie);
+    }
+    response
+}
+
+fn signed_in(
+    state: &AppState,
+    headers: &http::HeaderMap,
+    pages: &config::PagesConfig,
+    rd: &str,
+    user: &str,
+) -> axum::response::Response {
+    if let Some(location) = safe_redirect(pages, rd) {
+        return axum::response::Response::builder()
+            .status(303)
+            .header(http::header::LOCATION, location)
+            .body(axum::body::Body::empty())
+            .unwrap();
+    }
+    let language = state.translations.negotiate(headers);
+    page(
+        state,
+        language,
+        200,
+        "Signed in",
+        &format!(
+            "<h1>{}</h1>\n<p>{}</p>\n",
+            escape(language.t("Signed in")),
+            escape(language.t("You are signed in as {user}.")).replace("{user}", &escape(user))
+        ),
+        false,
+    )
+}
+
+#[derive(serde::Deserialize)]
+pub struct LoginQuery {
+    #[serde(default)]
+    rd: String,
+}
+
+pub async fn get_login(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    headers: http::HeaderMap,
+    axum::extract::Query(query): axum::extract::Query<LoginQuery>,
+) -> axum::response::Response {
+    let (Some(pages), Some(forward_auth)) = (&state.config.pages, &state.config.forward_auth)
+    else {
+        return error_response(404, "the login page is not enabled");
+    };
+    if let Some(session_id) = forward_auth::session_cookie(&headers, &forward_auth.cookie_name) {
+        if let Ok((_, session)) = lookup_session(&state, &session_id, &client).await {
+            let session_locked = session.read().await;
+            if let (Some(user), true) = (&session_locked.user, session_locked.authenticated) {
+                return signed_in(&state, &headers, pages, &query.rd, user);
+            }
+        }
+    }
+    let captcha = state
+        .captcha
+        .required(None, client.ip, state.clock.now_secs());
+    login_form(&state, &headers, 200, &query.rd, "", None, captcha)
+}
+
+#[derive(serde::Deserialize)]
+pub struct LoginForm {
+    user: String,
+    password: zeroize::Zeroizing<String>,
+    csrf_token: String,
+    #[serde(default)]
+    rd: String,
+    // Each provider's widget adds its token to the form under its own name.
+    #[serde(
+        default,
+        alias = "h-captcha-response",
+        alias = "g-recaptcha-response",
+        alias = "cf-turnstile-response"
+    )]
+    captcha_token: Option<String>,
+    // The honeypot, named for what bots like to fill in.
+    #[serde(default)]
+    website: String,
+}
+
+pub async fn post_login(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    headers: http::HeaderMap,
+    axum::extract::Form(form): axum::extract::Form<LoginForm>,
+) -> axum::response::Response {
+    let Some(pages) = &state.config.pages else {
+        return error_response(404, "the login page is not enabled");
+    };
+    let now = state.clock.now_secs();
+    // Once the risk service asked for a CAPTCHA, the form has to show one
+    // from then on.
+    let form_again = |status, error, step_up: bool| {
+        let captcha = step_up || state.captcha.required(Some(&form.user), client.ip, now);
+        login_form(
+            &state, &headers, status, &form.rd, &form.user, error, captcha,
+        )
+    };
+    let retry = |status, error| form_again(status, error, false);
+    if let Some(message) = state.read_only.message() {
+        let message = message
+            .as_deref()
+            .unwrap_or("Signing in isn't possible during maintenance, try again later.");
+        return retry(503, Some(message));
+    }
+    if pages.honeypot && !form.website.is_empty() {
+        audit::record(audit::AuditEvent::HoneypotFilled {
+            user: &form.user,
+            ip: client.ip,
+        });
+        // Only the network is held against: the user name may be anyone's.
+        state.captcha.record_failure(None, client.ip, now);
+        state.tarpit.record_failure(None, client.ip, now);
+        return retry(401, Some("Invalid user or password."));
+    }
+    if !csrf_valid(&headers, &form.csrf_token) {
+        return retry(403, Some("The form expired, please try again."));
+    }
+    if !state.tarpit.wait(Some(&form.user), client.ip, now).await {
+        return retry(429, Some("Too many sign-in attempts, try again later."));
+    }
+    let step_up = match risk::assess(&state, &form.user, &client, now).await {
+        RiskDecision::Allow => false,
+        RiskDecision::StepUp => true,
+        RiskDecision::Deny => return retry(403, Some("Signing in was refused.")),
+    };
+    let retry = |status, error| form_again(status, error, step_up);
+    match state
+        .captcha
+        .check(
+            &form.user,
+            client.ip,
+            form.captcha_token.as_deref(),
+            step_up,
+            now,
+        )
+        .await
+    {
+        captcha::Check::Passed => {}
+        captcha::Check::Required => return retry(401, Some("Please complete the CAPTCHA.")),
+        captcha::Check::Invalid => {
+            return retry(
+                401,
+                Some("The CAPTCHA couldn't be verified, please try again."),
+            )
+        }
+        captcha::Check::Unavailable => {
+            return retry(
+                503,
+                Some("Signing in isn't possible right now, try again later."),
+            )
+        }
+    }
+    match state.auth.verify(&form.user, &form.password).await {
+        Ok(true) => {
+            state.captcha.record_success(&form.user);
+            state.tarpit.record_success(&form.user);
+        }
+        Ok(false) => {
+            audit::record(audit::AuditEvent::AuthenticationFailed {
+                user: &form.user,
+                ip: client.ip,
+            });
+            state
+                .captcha
+                .record_failure(Some(&form.user), client.ip, now);
+            state
+                .tarpit
+                .record_failure(Some(&form.user), client.ip, now);
+            state.admin_alerts.failed_sign_in(now);
+            return retry(401, Some("Invalid user or password."));
+        }
+        Err(err) => {
+            println!("Authentication backend error: {}", err);
+            return retry(
+                503,
+                Some("Signing in isn't possible right now, try again later."),
+            );
+        }
+    }
+
+    let mut session = Session::new(client.ip, client.user_agent.clone(), state.clock.now_secs());
+    if !enforce_memory_budget(&state, session.approx_bytes()).await {
+        return retry(503, Some("The server is busy, try again later."));
+    }
+    session.set_expiry(expiry_policy(&state, None).await, state.clock.now_secs());
+    let session_id = state.session_ids().generate(&state.rng);
+    let id_base64 = String::from(&session_id);
+    let session = state.sessions.insert(session_id.clone(), session);
+    let id_base64 = match authenticate(&state, &session, &id_base64, form.user.clone(), false).await
+    {
+        Ok(Authentication::Fresh { id_base64 }) => id_base64,
+        Ok(Authentication::Refreshed) => id_base64,
+        Err(response) => {
+            state.sessions.remove(&session_id);
+            let impossible_travel = response
+                .extensions()
+                .get::<geoip::ImpossibleTravel>()
+                .is_some();
+            let error = match response.status().as_u16() {
+                403 if impossible_travel => {
+                    "This sign-in is too far from your last one and was refused."
+                }
+                403 => "This account is deactivated.",
+                409 => "You have too many sessions, sign out of another one first.",
+                _ => "Signing in failed.",
+            };
+            return retry(response.status().as_u16(), Some(error));
+        }
+    };
+    println!("Session {} signed in on the login page", id_base64);
+    let mut response = signed_in(&state, &headers, pages, &form.rd, &form.user);
+    forward_auth::add_session_cookie(&state, &mut response, &id_base64);
+    response
+}
+
+// For the web app and other clients that want to look like the pages.
+pub async fn get_branding(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+) -> axum::response::Response {
+    serialized_response(200, &state.config.branding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restricts_pages_with_a_content_security_policy() {
+        let mut config = config::Config::default();
+        config.branding.logo_url = Some(String::from("https://cdn.example.com/logo.svg"));
+        config.pages = Some(config::PagesConfig {
+            redirect_hosts: vec![
+                String::from("app.example.com"),
+                String::from(".example.org"),
+            ],
+            ..config::PagesConfig::default()
+        });
+        assert_eq!(
+            content_security_policy(&config, "abc", false),
+            "default-src 'none'; style-src 'nonce-abc'; \
+             img-src 'self' https://cdn.example.com; \
+             form-action 'self' app.example.com example.org *.example.org; \
+             frame-ancestors 'none'; base-uri 'none'"
+        );
+        assert_eq!(origin("/logo.png"), None);
+        assert_eq!(origin("https://a.example;script-src *"), None);
+        assert_eq!(origin("http://[::1]:8080"), Some("http://[::1]:8080"));
+    }
+
+    #[test]
+    fn only_redirects_to_allowed_places() {
+        let pages = config::PagesConfig {
+            redirect_hosts: vec![
+                String::from("app.example.com"),
+                String::from(".example.org"),
+            ],
+            ..config::PagesConfig::default()
+        };
+        for allowed in [
+            "/web/",
+            "https://app.example.com/a?b=c",
+            "http://app.example.com:8080",
+            "https://example.org/",
+            "https://wiki.example.org#top",
+        ] {
+            assert!(safe_redirect(&pages, allowed).is_some(), "{}", allowed);
+        }
+        for denied in [
+            "",
+            "//evil.example/",
+            "/\\evil.example/",
+            "https://evil.example/",
+            "https://app.example.com@evil.example/",
+            "https://app.example.com.evil.example/",
+            "https://notexample.org/",
+            "javascript:alert(1)",
+            "/a\r\nSet-Cookie: x=y",
+            "/\t/evil.example/",
+            "/\n/evil.example/",
+            "/ /evil.example/",
+            "/\u{0}/evil.example/",
+            "/web\\/evil.example/",
+            "https://app.example.com\\@evil.example/",
+            "https://app.example.com/\u{7f}",
+        ] {
+            assert!(safe_redirect(&pages, denied).is_none(), "{}", denied);
+        }
+        assert_eq!(
+            escape("<a href=\"x\">'&'</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;"
+        );
+    }
+}
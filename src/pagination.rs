@@ -0,0 +1,128 @@
+use base64::Engine;
+
+use crate::jwt::base64url_encode;
+
+// Every list endpoint takes `limit`, `cursor` and `order` and answers with
+// `next_cursor`, null on the last page. Items are ordered by a key that
+// never changes, so pages neither skip nor repeat items when the list
+// changes in between. Filters, like `status` on deliveries, apply before
+// paging.
+pub const DEFAULT_LIMIT: usize = 50;
+pub const MAX_LIMIT: usize = 500;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+#[derive(Default, serde::Deserialize)]
+pub struct PageQuery {
+    limit: Option<usize>,
+    cursor: Option<String>,
+    order: Option<Order>,
+}
+
+// The cursor is the key of the last item handed out, with the order it was
+// handed out in. Clients treat it as opaque.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Cursor<K> {
+    order: Order,
+    after: K,
+}
+
+// Sorts `items` by `key` in the requested order, `default_order` if none, and
+// returns the page after the cursor with the cursor of the next one. Errors
+// are for a 400.
+pub fn page<T, K>(
+    mut items: Vec<T>,
+    key: impl Fn(&T) -> K,
+    query: &PageQuery,
+    default_order: Order,
+) -> Result<(Vec<T>, Option<String>), String>
+where
+    K: Ord + serde::Serialize + serde::de::DeserializeOwned,
+{
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(format!("limit must be between 1 and {}", MAX_LIMIT));
+    }
+    let order = query.order.unwrap_or(default_order);
+    let after = match &query.cursor {
+        Some(cursor) => {
+            let cursor: Cursor<K> = base64::engine::general_purpose::URL_SAFE_NO_PAD
+                .decode(cursor)
+                .ok()
+                .and_then(|json| serde_json::from_slice(&json).ok())
+                .ok_or("invalid cursor")?;
+            if cursor.order != order {
+                return Err(String::from("the cursor is for the other order"));
+            }
+            Some(cursor.after)
+        }
+        None => None,
+    };
+    items.sort_by_key(|item| key(item));
+    if order == Order::Desc {
+        items.reverse();
+    }
+    if let Some(after) = after {
+        items.retain(|item| match order {
+            Order::Asc => key(item) > after,
+            Order::Desc => key(item) < after,
+        });
+    }
+    let next_cursor = (items.len() > limit).then(|| {
+        let cursor = Cursor {
+            order,
+            after: key(&items[limit - 1]),
+        };
+        base64url_encode(&serde_json::to_vec(&cursor).unwrap())
+    });
+    items.truncate(limit);
+    Ok((items, next_cursor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbers(
+        items: &[u32],
+        limit: usize,
+        cursor: &Option<String>,
+        order: Option<Order>,
+    ) -> Option<(Vec<u32>, Option<String>)> {
+        let query = PageQuery {
+            limit: Some(limit),
+            cursor: cursor.clone(),
+            order,
+        };
+        page(items.to_vec(), |item| *item, &query, Order::Asc).ok()
+    }
+
+    #[test]
+    fn pages_stay_stable_while_the_list_changes() {
+        let (first, cursor) = numbers(&[3, 1, 2, 5, 4], 2, &None, None).unwrap();
+        assert_eq!(first, [1, 2]);
+        // An item removed from the first page and one added before the
+        // cursor don't shift the second page.
+        let (second, cursor) = numbers(&[0, 2, 3, 4, 5], 2, &cursor, None).unwrap();
+        assert_eq!(second, [3, 4]);
+        assert_eq!(
+            numbers(&[1, 2, 3, 4, 5], 2, &cursor, None),
+            Some((vec![5], None))
+        );
+
+        let (newest, cursor) = numbers(&[1, 2, 3, 4, 5], 3, &None, Some(Order::Desc)).unwrap();
+        assert_eq!(newest, [5, 4, 3]);
+        assert_eq!(
+            numbers(&[1, 2, 3, 4, 5], 3, &cursor, Some(Order::Desc)),
+            Some((vec![2, 1], None))
+        );
+        assert!(numbers(&[1, 2, 3], 3, &cursor, None).is_none());
+        assert!(numbers(&[1, 2, 3], 0, &None, None).is_none());
+        assert!(numbers(&[1, 2, 3], 1, &Some(String::from("x")), None).is_none());
+    }
+}
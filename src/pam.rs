@@ -0,0 +1,224 @@
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::sync::{mpsc, Arc, Mutex};
+
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::config;
+
+const PAM_SUCCESS: c_int = 0;
+const PAM_BUF_ERR: c_int = 5;
+const PAM_PERM_DENIED: c_int = 6;
+const PAM_AUTH_ERR: c_int = 7;
+const PAM_CRED_INSUFFICIENT: c_int = 8;
+const PAM_USER_UNKNOWN: c_int = 10;
+const PAM_MAXTRIES: c_int = 11;
+const PAM_NEW_AUTHTOK_REQD: c_int = 12;
+const PAM_ACCT_EXPIRED: c_int = 13;
+const PAM_CONV_ERR: c_int = 19;
+
+const PAM_PROMPT_ECHO_OFF: c_int = 1;
+const PAM_ERROR_MSG: c_int = 3;
+const PAM_TEXT_INFO: c_int = 4;
+
+const PAM_SILENT: c_int = 0x8000;
+const PAM_DISALLOW_NULL_AUTHTOK: c_int = 0x0001;
+
+#[repr(C)]
+struct PamHandle {
+    _private: [u8; 0],
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct PamResponse {
+    resp: *mut c_char,
+    resp_retcode: c_int,
+}
+
+#[repr(C)]
+struct PamConv {
+    conv: extern "C" fn(
+        num_msg: c_int,
+        msg: *mut *const PamMessage,
+        resp: *mut *mut PamResponse,
+        appdata_ptr: *mut c_void,
+    ) -> c_int,
+    appdata_ptr: *mut c_void,
+}
+
+#[link(name = "pam")]
+extern "C" {
+    fn pam_start(
+        service_name: *const c_char,
+        user: *const c_char,
+        pam_conversation: *const PamConv,
+        pamh: *mut *mut PamHandle,
+    ) -> c_int;
+    fn pam_authenticate(pamh: *mut PamHandle, flags: c_int) -> c_int;
+    fn pam_acct_mgmt(pamh: *mut PamHandle, flags: c_int) -> c_int;
+    fn pam_end(pamh: *mut PamHandle, pam_status: c_int) -> c_int;
+    fn pam_strerror(pamh: *mut PamHandle, errnum: c_int) -> *const c_char;
+}
+
+type Job = (
+    String,
+    Zeroizing<String>,
+    tokio::sync::oneshot::Sender<Result<bool, String>>,
+);
+
+pub struct PamAuthenticator {
+    jobs: Mutex<mpsc::Sender<Job>>,
+}
+
+impl PamAuthenticator {
+    pub fn new(config: &config::PamConfig) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..config.threads {
+            let receiver = receiver.clone();
+            let service = config.service.clone();
+            std::thread::Builder::new()
+                .name(format!("pam-{}", i))
+                .spawn(move || loop {
+                    let job = receiver.lock().unwrap().recv();
+                    let Ok((user, password, reply)) = job else {
+                        return;
+                    };
+                    let _ = reply.send(authenticate(&service, &user, &password));
+                })
+                .unwrap();
+        }
+        Self {
+            jobs: Mutex::new(sender),
+        }
+    }
+
+    pub async fn verify(&self, user: &str, password: &str) -> Result<bool, String> {
+        let (reply, result) = tokio::sync::oneshot::channel();
+        self.jobs
+            .lock()
+            .unwrap()
+            .send((
+                user.to_string(),
+                Zeroizing::new(password.to_string()),
+                reply,
+            ))
+            .map_err(|_| String::from("PAM worker threads are gone"))?;
+        result
+            .await
+            .map_err(|_| String::from("PAM worker thread panicked"))?
+    }
+}
+
+fn authenticate(service: &str, user: &str, password: &str) -> Result<bool, String> {
+    let service = CString::new(service).map_err(|_| String::from("invalid PAM service name"))?;
+    let Ok(user) = CString::new(user) else {
+        return Ok(false);
+    };
+    let Ok(password) = CString::new(password) else {
+        return Ok(false);
+    };
+    let mut password = password.into_bytes_with_nul();
+    let conversation = PamConv {
+        conv: converse,
+        appdata_ptr: password.as_ptr() as *mut c_void,
+    };
+
+    let mut handle: *mut PamHandle = std::ptr::null_mut();
+    // SAFETY: all pointers outlive the PAM transaction, which ends with
+    // pam_end below before `password` and `conversation` are dropped.
+    let result = unsafe {
+        let status = pam_start(service.as_ptr(), user.as_ptr(), &conversation, &mut handle);
+        if status != PAM_SUCCESS {
+            Err(format!("pam_start failed with status {}", status))
+        } else {
+            let mut status = pam_authenticate(handle, PAM_SILENT | PAM_DISALLOW_NULL_AUTHTOK);
+            if status == PAM_SUCCESS {
+                status = pam_acct_mgmt(handle, PAM_SILENT | PAM_DISALLOW_NULL_AUTHTOK);
+            }
+            let result = match status {
+                PAM_SUCCESS => Ok(true),
+                PAM_AUTH_ERR
+                | PAM_USER_UNKNOWN
+                | PAM_MAXTRIES
+                | PAM_PERM_DENIED
+                | PAM_ACCT_EXPIRED
+                | PAM_NEW_AUTHTOK_REQD
+                | PAM_CRED_INSUFFICIENT => Ok(false),
+                _ => {
+                    let message = pam_strerror(handle, status);
+                    Err(if message.is_null() {
+                        format!("PAM error {}", status)
+                    } else {
+                        CStr::from_ptr(message).to_string_lossy().into_owned()
+                    })
+                }
+            };
+            pam_end(handle, status);
+            result
+        }
+    };
+    password.zeroize();
+    result
+}
+
+extern "C" fn converse(
+    num_msg: c_int,
+    msg: *mut *const PamMessage,
+    resp: *mut *mut PamResponse,
+    appdata_ptr: *mut c_void,
+) -> c_int {
+    let Ok(count) = usize::try_from(num_msg) else {
+        return PAM_CONV_ERR;
+    };
+    if count == 0 || msg.is_null() || resp.is_null() {
+        return PAM_CONV_ERR;
+    }
+    // SAFETY: PAM hands us `num_msg` messages and expects a calloc'd array of
+    // responses whose strings it frees itself. `appdata_ptr` is the
+    // NUL-terminated password set up in `authenticate`.
+    unsafe {
+        let responses = libc::calloc(count, std::mem::size_of::<PamResponse>()) as *mut PamResponse;
+        if responses.is_null() {
+            return PAM_BUF_ERR;
+        }
+        for i in 0..count {
+            let message = &**msg.add(i);
+            match message.msg_style {
+                PAM_PROMPT_ECHO_OFF => {
+                    let answer = libc::strdup(appdata_ptr as *const c_char);
+                    if answer.is_null() {
+                        free_responses(responses, i);
+                        return PAM_BUF_ERR;
+                    }
+                    (*responses.add(i)).resp = answer;
+                }
+                PAM_ERROR_MSG | PAM_TEXT_INFO => {}
+                _ => {
+                    free_responses(responses, i);
+                    return PAM_CONV_ERR;
+                }
+            }
+        }
+        *resp = responses;
+    }
+    PAM_SUCCESS
+}
+
+unsafe fn free_responses(responses: *mut PamResponse, count: usize) {
+    for i in 0..count {
+        let answer = (*responses.add(i)).resp;
+        if !answer.is_null() {
+            libc::memset(answer as *mut c_void, 0, libc::strlen(answer));
+            libc::free(answer as *mut c_void);
+        }
+    }
+    libc::free(responses as *mut c_void);
+}
@@ -0,0 +1,751 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+use zeroize::Zeroizing;
+
+use crate::config;
+use crate::encryption::Encryption;
+use crate::session::{Change, SavedSession, SessionId, SessionStore};
+use crate::time;
+use crate::AppState;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Saved {
+    id: String,
+    session: SavedSession,
+}
+
+// A line of the journal, numbered so replaying can skip the ones a
+// snapshot already holds. Written from a &Record.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Entry<R = Record> {
+    seq: u64,
+    // When it was written, for tk-auth restore. Journals of earlier
+    // versions lack it.
+    #[serde(default)]
+    at: u64,
+    #[serde(flatten)]
+    record: R,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Record {
+    Put(Box<Saved>),
+    Remove { id: String },
+    // Another record, encrypted with the entry's number as its context.
+    Sealed(String),
+}
+
+// The first line of a snapshot, followed by a Line per session.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Header {
+    // The last journal entry the snapshot holds.
+    seq: u64,
+    // The journal holds every change since then, see keep_journal_secs.
+    #[serde(default)]
+    journal_since: Option<u64>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+enum Line {
+    // A Saved, encrypted.
+    Sealed { sealed: String },
+    Saved(Box<Saved>),
+}
+
+const SNAPSHOT_CONTEXT: &str = "sessions";
+// While the journal can't be written, changes queue up to this many and
+// are retried every RETRY_INTERVAL_SECS. Past it they're dropped, and only
+// the next snapshot saves them.
+const MAX_QUEUED_RECORDS: usize = 100_000;
+const RETRY_INTERVAL_SECS: u64 = 5;
+
+fn entry_context(seq: u64) -> String {
+    format!("sessions/{}", seq)
+}
+
+fn unseal<T: serde::de::DeserializeOwned>(
+    encryption: Option<&Encryption>,
+    context: &str,
+    sealed: &str,
+) -> Result<T, String> {
+    let encryption = encryption.ok_or_else(|| {
+        String::from("holds encrypted sessions, but encryption is not configured")
+    })?;
+    let opened = encryption.open(context, sealed)?;
+    serde_json::from_slice(&opened.plaintext).map_err(|err| err.to_string())
+}
+
+fn seal(encryption: &Encryption, context: &str, value: &impl serde::Serialize) -> String {
+    let plaintext = Zeroizing::new(serde_json::to_vec(value).unwrap());
+    encryption.seal(context, &plaintext)
+}
+
+// Writes sessions.persistence's journal and snapshots on a thread of its
+// own, so the disk never holds up requests.
+pub struct Journal {
+    config: config::SessionPersistenceConfig,
+    encryption: Option<Arc<Encryption>>,
+    path: PathBuf,
+    wal_path: PathBuf,
+    wal: std::fs::File,
+    seq: u64,
+    journal_since: u64,
+    // Changes that failed to be written, with when they were made.
+    queued: Vec<(u64, Record)>,
+}
+
+fn invalid_data(path: &Path, line: usize, err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}:{}: {}", path.display(), line, err),
+    )
+}
+
+fn read(path: &Path) -> io::Result<Vec<u8>> {
+    match std::fs::read(path) {
+        Ok(contents) => Ok(contents),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(io::Error::new(
+            err.kind(),
+            format!("{}: {}", path.display(), err),
+        )),
+    }
+}
+
+// Calls `record` with the number, time and record of each journal entry,
+// opening the encrypted ones.
+fn read_journal(
+    wal_path: &Path,
+    encryption: Option<&Encryption>,
+    mut record: impl FnMut(u64, u64, Record),
+) -> io::Result<()> {
+    let wal = read(wal_path)?;
+    let lines: Vec<&[u8]> = wal.split(|&byte| byte == b'\n').collect();
+    for (i, line) in lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+    {
+        let entry: Entry = match serde_json::from_slice(line) {
+            Ok(entry) => entry,
+            // Where a crash cut the last write short.
+            Err(_) if i == lines.len() - 1 => {
+                println!(
+                    "Ignoring the incomplete last line of {}",
+                    wal_path.display()
+                );
+                break;
+            }
+            Err(err) => return Err(invalid_data(wal_path, i + 1, err)),
+        };
+        let opened = match entry.record {
+            Record::Sealed(sealed) => unseal(encryption, &entry_context(entry.seq), &sealed)
+                .map_err(|err| invalid_data(wal_path, i + 1, err))?,
+            opened => opened,
+        };
+        if let Record::Sealed(_) = opened {
+            return Err(invalid_data(wal_path, i + 1, "nested encrypted entry"));
+        }
+        record(entry.seq, entry.at, opened);
+    }
+    Ok(())
+}
+
+fn apply(sessions: &mut HashMap<String, SavedSession>, record: Record) {
+    match record {
+        Record::Put(saved) => {
+            let Saved { id, session } = *saved;
+            sessions.insert(id, session);
+        }
+        Record::Remove { id } => {
+            sessions.remove(&id);
+        }
+        // read_journal opens these.
+        Record::Sealed(_) => {}
+    }
+}
+
+// Sessions by id as of the snapshot and the journal entries after it, and
+// the snapshot's header with the number of the last entry. Entries
+// encrypted with any of the keys are read.
+fn replay(
+    path: &Path,
+    wal_path: &Path,
+    encryption: Option<&Encryption>,
+) -> io::Result<(HashMap<String, SavedSession>, Header)> {
+    let mut sessions = HashMap::new();
+    let snapshot = read(path)?;
+    let mut lines = snapshot.split(|&byte| byte == b'\n').enumerate();
+    let mut header = match lines.next() {
+        Some((_, line)) if !line.is_empty() => {
+            serde_json::from_slice::<Header>(line).map_err(|err| invalid_data(path, 1, err))?
+        }
+        _ => Header {
+            seq: 0,
+            journal_since: None,
+        },
+    };
+    for (i, line) in lines.filter(|(_, line)| !line.is_empty()) {
+        let saved = match serde_json::from_slice(line) {
+            Ok(Line::Saved(saved)) => *saved,
+            Ok(Line::Sealed { sealed }) => unseal(encryption, SNAPSHOT_CONTEXT, &sealed)
+                .map_err(|err| invalid_data(path, i + 1, err))?,
+            Err(err) => return Err(invalid_data(path, i + 1, err)),
+        };
+        sessions.insert(saved.id, saved.session);
+    }
+    // With keep_journal_secs, the journal still holds entries the snapshot
+    // has.
+    read_journal(wal_path, encryption, |seq, _, record| {
+        if seq > header.seq {
+            header.seq = seq;
+            apply(&mut sessions, record);
+        }
+    })?;
+    Ok((sessions, header))
+}
+
+// The snapshot and the journal, if sessions.persistence is on.
+fn paths(config: &config::Config) -> Option<(PathBuf, PathBuf)> {
+    // Validated on load.
+    let path = config
+        .sessions
+        .persistence
+        .as_ref()?
+        .path(&config.users)
+        .unwrap();
+    let mut wal_path = path.clone().into_os_string();
+    wal_path.push(".wal");
+    Some((path, PathBuf::from(wal_path)))
+}
+
+fn not_configured() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "sessions.persistence is not configured",
+    )
+}
+
+// The unexpired sessions saved by sessions.persistence, by id. Reading them
+// while the server runs is safe, since files are only ever replaced or
+// appended to.
+pub fn load(
+    config: &config::Config,
+    encryption: Option<&Encryption>,
+    now: u64,
+) -> io::Result<Vec<(String, SavedSession)>> {
+    let (path, wal_path) = paths(config).ok_or_else(not_configured)?;
+    let (saved, _) = replay(&path, &wal_path, encryption)?;
+    Ok(saved
+        .into_iter()
+        .filter(|(_, session)| !session.is_expired(now))
+        .collect())
+}
+
+// Replays the journal's changes from `from` to `to` on top of `sessions`,
+// and returns how many there were. Fails unless the journal holds every
+// change since `from`.
+pub fn replay_journal(
+    config: &config::Config,
+    encryption: Option<&Encryption>,
+    sessions: &mut HashMap<String, SavedSession>,
+    from: u64,
+    to: u64,
+) -> io::Result<usize> {
+    let (path, wal_path) = paths(config).ok_or_else(not_configured)?;
+    let (_, header) = replay(&path, &wal_path, encryption)?;
+    match header.journal_since {
+        Some(since) if since <= from => {}
+        since => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{}: the journal only holds changes since {}, after {}; \
+                     sessions.persistence.keep_journal_secs sets how long they're kept",
+                    wal_path.display(),
+                    since.map_or_else(|| String::from("its last snapshot"), time::rfc3339),
+                    time::rfc3339(from)
+                ),
+            ));
+        }
+    }
+    let mut replayed = 0;
+    read_journal(&wal_path, encryption, |_, at, record| {
+        if (from..=to).contains(&at) {
+            apply(sessions, record);
+            replayed += 1;
+        }
+    })?;
+    Ok(replayed)
+}
+
+// Replaces what sessions.persistence saved with `sessions`. Only while the
+// server isn't running, which would write over them again.
+pub fn replace(
+    config: &config::Config,
+    encryption: Option<&Encryption>,
+    sessions: &[(String, SavedSession)],
+    now: u64,
+) -> io::Result<()> {
+    let (path, wal_path) = paths(config).ok_or_else(not_configured)?;
+    // The journal's entries would be replayed on top of the new snapshot.
+    // Removed first, so a crash leaves the old snapshot on its own.
+    match std::fs::remove_file(&wal_path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            return Err(io::Error::new(
+                err.kind(),
+                format!("{}: {}", wal_path.display(), err),
+            ))
+        }
+        _ => {}
+    }
+    // Validated on load.
+    let sync = config.sessions.persistence.as_ref().unwrap().sync;
+    let header = Header {
+        seq: 0,
+        journal_since: Some(now),
+    };
+    write_snapshot(
+        &path,
+        &header,
+        sessions.iter().map(|(id, session)| (id.as_str(), session)),
+        sync,
+        encryption,
+    )
+    .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))
+}
+
+// Replaces the snapshot the way users::write_private replaces files, but
+// without holding all of it in memory at once. Sessions are encrypted with
+// the active key, whichever they were read with.
+fn write_snapshot<'a>(
+    path: &Path,
+    header: &Header,
+    sessions: impl Iterator<Item = (&'a str, &'a SavedSession)>,
+    sync: bool,
+    encryption: Option<&Encryption>,
+) -> io::Result<()> {
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)?;
+    let mut writer = io::BufWriter::new(file);
+    serde_json::to_writer(&mut writer, header)?;
+    for (id, session) in sessions {
+        writer.write_all(b"\n")?;
+        let saved = serde_json::json!({ "id": id, "session": session });
+        match encryption {
+            Some(encryption) => serde_json::to_writer(
+                &mut writer,
+                &serde_json::json!({ "sealed": seal(encryption, SNAPSHOT_CONTEXT, &saved) }),
+            )?,
+            None => serde_json::to_writer(&mut writer, &saved)?,
+        }
+    }
+    writer.write_all(b"\n")?;
+    let file = writer
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    // The journal is only emptied once the rename is on disk too.
+    if sync {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::File::open(dir)?.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+impl Journal {
+    // Restores the sessions saved by the last run into `sessions`, skipping
+    // the ones that expired since, and compacts the files so the journal
+    // starts out empty.
+    pub fn open(
+        config: &config::Config,
+        encryption: Option<Arc<Encryption>>,
+        sessions: &SessionStore,
+        now: u64,
+    ) -> io::Result<Option<Self>> {
+        let Some(persistence) = &config.sessions.persistence else {
+            return Ok(None);
+        };
+        let (path, wal_path) = paths(config).unwrap();
+
+        let (mut saved, header) = replay(&path, &wal_path, encryption.as_deref())?;
+        saved.retain(|_, session| !session.is_expired(now));
+        let wal = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(&wal_path)
+            .map_err(|err| {
+                io::Error::new(err.kind(), format!("{}: {}", wal_path.display(), err))
+            })?;
+        let mut journal = Self {
+            config: persistence.clone(),
+            encryption,
+            path,
+            wal_path,
+            wal,
+            seq: header.seq,
+            journal_since: header.journal_since.unwrap_or(now),
+            queued: Vec::new(),
+        };
+        journal
+            .checkpoint(
+                saved.iter().map(|(id, session)| (id.as_str(), session)),
+                now,
+            )
+            .map_err(|err| {
+                io::Error::new(err.kind(), format!("{}: {}", journal.path.display(), err))
+            })?;
+
+        let restored: Vec<_> = saved
+            .into_iter()
+            .filter_map(|(id, session)| Some((SessionId::restore(&id)?, session.into())))
+            .collect();
+        println!(
+            "Restored {} sessions from {}",
+            restored.len(),
+            journal.path.display()
+        );
+        sessions.insert_batch(restored);
+        Ok(Some(journal))
+    }
+
+    fn append(&mut self, records: &[(u64, Record)]) -> io::Result<()> {
+        let mut writer = io::BufWriter::new(&self.wal);
+        for (at, record) in records {
+            self.seq += 1;
+            let sealed;
+            let record = match &self.encryption {
+                Some(encryption) => {
+                    sealed = Record::Sealed(seal(encryption, &entry_context(self.seq), record));
+                    &sealed
+                }
+                None => record,
+            };
+            serde_json::to_writer(
+                &mut writer,
+                &Entry {
+                    seq: self.seq,
+                    at: *at,
+                    record,
+                },
+            )?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        drop(writer);
+        if self.config.sync {
+            self.wal.sync_data()?;
+        }
+        Ok(())
+    }
+
+    // Writes a snapshot of `sessions`, then empties the journal, or with
+    // keep_journal_secs drops the entries older than that.
+    fn checkpoint<'a>(
+        &mut self,
+        sessions: impl Iterator<Item = (&'a str, &'a SavedSession)>,
+        now: u64,
+    ) -> io::Result<()> {
+        let keep = self.config.keep_journal_secs;
+        let journal_since = match keep {
+            0 => now,
+            keep => self.journal_since.max(now.saturating_sub(keep)),
+        };
+        let header = Header {
+            seq: self.seq,
+            journal_since: Some(journal_since),
+        };
+        write_snapshot(
+            &self.path,
+            &header,
+            sessions,
+            self.config.sync,
+            self.encryption.as_deref(),
+        )?;
+        if keep == 0 {
+            self.wal.set_len(0)?;
+        } else {
+            self.trim_journal(journal_since)?;
+        }
+        self.journal_since = journal_since;
+        Ok(())
+    }
+
+    // Rewrites the journal without the entries written before `since`, or a
+    // last line that a crash cut short.
+    fn trim_journal(&mut self, since: u64) -> io::Result<()> {
+        #[derive(serde::Deserialize)]
+        struct Written {
+            #[serde(default)]
+            at: u64,
+        }
+        let wal = read(&self.wal_path)?;
+        let mut tmp_path = self.wal_path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        let mut writer = io::BufWriter::new(file);
+        for line in wal.split(|&byte| byte == b'\n') {
+            if serde_json::from_slice::<Written>(line).is_ok_and(|written| written.at >= since) {
+                writer.write_all(line)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+        let file = writer
+            .into_inner()
+            .map_err(io::IntoInnerError::into_error)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, &self.wal_path)?;
+        self.wal = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&self.wal_path)?;
+        Ok(())
+    }
+
+    // Changes that come in while this runs are still queued, and go into
+    // the journal after it. Replaying them on top of the snapshot, which
+    // may hold them already, ends up in the same state.
+    // Returns the number of sessions in the snapshot.
+    fn compact(&mut self, state: &AppState) -> io::Result<usize> {
+        let now = state.clock.now_secs();
+        let saved: Vec<(String, SavedSession)> = state
+            .sessions
+            .snapshot()
+            .into_iter()
+            .filter_map(|(id, session)| {
+                let session = session.blocking_read();
+                (!session.is_expired(now)).then(|| (String::from(&id), (&*session).into()))
+            })
+            .collect();
+        self.checkpoint(
+            saved.iter().map(|(id, session)| (id.as_str(), session)),
+            now,
+        )?;
+        Ok(saved.len())
+    }
+
+    // Records that fail to be written stay queued, in order, for the next
+    // write. Sessions are served from memory meanwhile.
+    fn write_journal(&mut self, state: &AppState, records: Vec<Record>) {
+        let now = state.clock.now_secs();
+        let mut queued = std::mem::take(&mut self.queued);
+        queued.extend(records.into_iter().map(|record| (now, record)));
+        let started = Instant::now();
+        let result = self.append(&queued);
+        state.metrics.journal_writes.record(started, result.is_ok());
+        state.health.sessions.record(
+            result
+                .as_ref()
+                .map(|_| ())
+                .map_err(|err| format!("{}: {}", self.wal_path.display(), err)),
+        );
+        if let Err(err) = result {
+            if queued.len() > MAX_QUEUED_RECORDS {
+                println!(
+                    "Failed to write the session journal {}, dropping {} queued changes until \
+                     the next snapshot: {}",
+                    self.wal_path.display(),
+                    queued.len(),
+                    err
+                );
+                queued.clear();
+                // Restoring can't replay what was dropped.
+                self.journal_since = now;
+            } else {
+                self.queued = queued;
+            }
+        }
+        state
+            .metrics
+            .journal_queued
+            .store(self.queued.len() as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&mut self, state: &AppState) -> Result<usize, String> {
+        let started = Instant::now();
+        let result = self.compact(state);
+        state.metrics.snapshots.record(started, result.is_ok());
+        state.health.sessions.record(
+            result
+                .as_ref()
+                .map(|_| ())
+                .map_err(|err| format!("{}: {}", self.path.display(), err)),
+        );
+        result.map_err(|err| {
+            println!(
+                "Failed to write the session snapshot {}: {}",
+                self.path.display(),
+                err
+            );
+            err.to_string()
+        })
+    }
+
+    fn run(mut self, state: &AppState, changes: mpsc::Receiver<Change>) {
+        let interval = Duration::from_secs(self.config.snapshot_interval_secs);
+        let mut next_snapshot = Instant::now() + interval;
+        loop {
+            let mut wake_at = next_snapshot;
+            if !self.queued.is_empty() {
+                wake_at = wake_at.min(Instant::now() + Duration::from_secs(RETRY_INTERVAL_SECS));
+            }
+            match changes.recv_timeout(wake_at.saturating_duration_since(Instant::now())) {
+                Ok(change) => {
+                    // Whatever else queued up meanwhile goes in the same write.
+                    let mut records = Vec::new();
+                    let mut replies = Vec::new();
+                    for change in std::iter::once(change).chain(changes.try_iter()) {
+                        match change {
+                            Change::Put(id, session) | Change::Update(id, session) => {
+                                records.push(Record::Put(Box::new(Saved {
+                                    id: String::from(&id),
+                                    session: *session,
+                                })))
+                            }
+                            Change::Remove(id) => records.push(Record::Remove {
+                                id: String::from(&id),
+                            }),
+                            Change::Compact(reply) => replies.push(reply),
+                        }
+                    }
+                    if !records.is_empty() {
+                        self.write_journal(state, records);
+                    }
+                    if !replies.is_empty() {
+                        let result = self.snapshot(state);
+                        for reply in replies {
+                            let _ = reply.send(result.clone());
+                        }
+                        next_snapshot = Instant::now() + interval;
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) if Instant::now() < next_snapshot => {
+                    self.write_journal(state, Vec::new());
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    let _ = self.snapshot(state);
+                    next_snapshot = Instant::now() + interval;
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+}
+
+pub fn spawn(state: Arc<AppState>, journal: Option<Journal>) {
+    let Some(journal) = journal else {
+        return;
+    };
+    let (sender, receiver) = mpsc::channel();
+    state.sessions.record_changes(sender);
+    std::thread::Builder::new()
+        .name(String::from("session-journal"))
+        .spawn(move || journal.run(&state, receiver))
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replays_the_journal_after_the_snapshot() {
+        let dir = std::env::temp_dir().join(format!("tk-auth-persistence-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sessions");
+        let wal_path = dir.join("sessions.wal");
+        let session = |description: &str| -> SavedSession {
+            let mut session = crate::session::Session::new([127, 0, 0, 1].into(), None, 10);
+            session.description = description.to_string();
+            (&session).into()
+        };
+        let a = session("a");
+        let b = session("b");
+        let encryption = Encryption::new(vec![(1, Zeroizing::new(vec![b'k'; 32]))], 1);
+        let header = Header {
+            seq: 2,
+            journal_since: Some(10),
+        };
+        write_snapshot(
+            &path,
+            &header,
+            [("a", &a), ("b", &b)].into_iter(),
+            false,
+            Some(&encryption),
+        )
+        .unwrap();
+
+        let entry = |seq, record| {
+            let at = seq * 10;
+            let mut line = serde_json::to_vec(&Entry { seq, at, record }).unwrap();
+            line.push(b'\n');
+            line
+        };
+        let mut wal = Vec::new();
+        // Already in the snapshot, from before a crash during compaction.
+        wal.extend(entry(
+            1,
+            Record::Remove {
+                id: String::from("a"),
+            },
+        ));
+        wal.extend(entry(
+            3,
+            Record::Remove {
+                id: String::from("b"),
+            },
+        ));
+        let put = Record::Put(Box::new(Saved {
+            id: String::from("c"),
+            session: session("c"),
+        }));
+        wal.extend(entry(
+            4,
+            Record::Sealed(seal(&encryption, &entry_context(4), &put)),
+        ));
+        wal.extend_from_slice(br#"{"seq":5,"remove":{"i"#);
+        std::fs::write(&wal_path, &wal).unwrap();
+
+        assert!(replay(&path, &wal_path, None).is_err());
+        let (sessions, header) = replay(&path, &wal_path, Some(&encryption)).unwrap();
+        let mut ids: Vec<_> = sessions.keys().map(String::as_str).collect();
+        ids.sort();
+        assert_eq!(ids, ["a", "c"]);
+        assert_eq!(header.seq, 4);
+
+        // Restoring replays the entries written in the range, whatever the
+        // snapshot holds.
+        let mut config = config::Config::default();
+        config.sessions.persistence = Some(config::SessionPersistenceConfig {
+            path: Some(path.clone()),
+            ..Default::default()
+        });
+        let mut restored = HashMap::from([(String::from("b"), session("b"))]);
+        let replayed = replay_journal(&config, Some(&encryption), &mut restored, 10, 35).unwrap();
+        assert_eq!(replayed, 2);
+        assert!(restored.is_empty());
+        assert!(replay_journal(&config, Some(&encryption), &mut restored, 5, 35).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
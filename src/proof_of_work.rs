@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use base64::Engine;
+use tk_auth_types::ProofOfWorkChallenge;
+
+use crate::config;
+use crate::rate_limit::Quota;
+use crate::rng;
+
+const KEY_BYTES: usize = 32;
+const CHALLENGE_RANDOM_BYTES: usize = 16;
+const MAX_NONCE_CHARS: usize = 64;
+// Solved challenges are remembered until they expire so each one buys a
+// single session. Past this many, proofs are refused until some expire.
+const MAX_SPENT_CHALLENGES: usize = 100_000;
+
+#[derive(Default)]
+struct Rate {
+    second: u64,
+    count: u32,
+    previous_count: u32,
+}
+
+// Challenges are stateless and signed with a key that only lives in this
+// process, so a challenge has to be solved for the instance that issued it.
+pub struct ProofOfWork {
+    config: Option<config::ProofOfWorkConfig>,
+    key: ring::hmac::Key,
+    rate: Mutex<Rate>,
+    spent: Mutex<HashMap<String, u64>>,
+}
+
+impl ProofOfWork {
+    pub fn new(config: Option<&config::ProofOfWorkConfig>, rng: &dyn rng::Rng) -> Self {
+        let mut key = [0u8; KEY_BYTES];
+        rng.fill(&mut key);
+        Self {
+            config: config.cloned(),
+            key: ring::hmac::Key::new(ring::hmac::HMAC_SHA256, &key),
+            rate: Mutex::new(Rate::default()),
+            spent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Counts a request for a new session and tells whether it needs a proof
+    // of work, which it does while this or the last second was over the
+    // threshold.
+    pub fn required(&self, now: u64) -> bool {
+        let Some(config) = &self.config else {
+            return false;
+        };
+        let mut rate = self.rate.lock().unwrap();
+        if rate.second != now {
+            rate.previous_count = if rate.second + 1 == now {
+                rate.count
+            } else {
+                0
+            };
+            rate.second = now;
+            rate.count = 0;
+        }
+        rate.count = rate.count.saturating_add(1);
+        rate.count.max(rate.previous_count) > config.activate_above_per_sec
+    }
+
+    // The sessions left this second before they need a proof of work. The
+    // quota is back in full once neither this nor the last second counted
+    // any.
+    pub fn quota(&self, now: u64) -> Option<Quota> {
+        let config = self.config.as_ref()?;
+        let rate = self.rate.lock().unwrap();
+        let (count, previous_count) = match now.checked_sub(rate.second) {
+            Some(0) => (rate.count, rate.previous_count),
+            Some(1) => (0, rate.count),
+            _ => (0, 0),
+        };
+        Some(Quota {
+            limit: config.activate_above_per_sec,
+            remaining: config
+                .activate_above_per_sec
+                .saturating_sub(count.max(previous_count)),
+            reset_secs: if count > 0 {
+                2
+            } else {
+                u64::from(previous_count > 0)
+            },
+        })
+    }
+
+    pub fn challenge(&self, rng: &dyn rng::Rng, error: &str, now: u64) -> ProofOfWorkChallenge {
+        let config = self.config.as_ref().unwrap();
+        let mut random = [0u8; CHALLENGE_RANDOM_BYTES];
+        rng.fill(&mut random);
+        let expires_at = now + config.challenge_ttl_secs;
+        let payload = format!(
+            "{}.{}.{}",
+            expires_at,
+            config.difficulty_bits,
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(random)
+        );
+        let tag = ring::hmac::sign(&self.key, payload.as_bytes());
+        ProofOfWorkChallenge {
+            error: error.to_string(),
+            challenge: format!(
+                "{}.{}",
+                payload,
+                base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(tag.as_ref())
+            ),
+            difficulty_bits: config.difficulty_bits,
+            expires_at,
+        }
+    }
+
+    // A nonce solves a challenge when SHA-256 of "challenge:nonce" starts
+    // with the challenge's number of zero bits.
+    pub fn verify(&self, challenge: &str, nonce: &str, now: u64) -> bool {
+        if nonce.is_empty() || nonce.len() > MAX_NONCE_CHARS {
+            return false;
+        }
+        let Some((payload, tag)) = challenge.rsplit_once('.') else {
+            return false;
+        };
+        let Ok(tag) = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(tag) else {
+            return false;
+        };
+        if ring::hmac::verify(&self.key, payload.as_bytes(), &tag).is_err() {
+            return false;
+        }
+        let mut fields = payload.split('.');
+        let (Some(Ok(expires_at)), Some(Ok(difficulty_bits))) = (
+            fields.next().map(str::parse::<u64>),
+            fields.next().map(str::parse::<u8>),
+        ) else {
+            return false;
+        };
+        if expires_at <= now || !solves(challenge, nonce, difficulty_bits) {
+            return false;
+        }
+        let mut spent = self.spent.lock().unwrap();
+        if spent.len() >= MAX_SPENT_CHALLENGES {
+            spent.retain(|_, expires_at| *expires_at > now);
+            if spent.len() >= MAX_SPENT_CHALLENGES {
+                return false;
+            }
+        }
+        spent.insert(challenge.to_string(), expires_at).is_none()
+    }
+}
+
+fn solves(challenge: &str, nonce: &str, difficulty_bits: u8) -> bool {
+    let digest = ring::digest::digest(
+        &ring::digest::SHA256,
+        format!("{}:{}", challenge, nonce).as_bytes(),
+    );
+    let mut leading_zeros = 0;
+    for byte in digest.as_ref() {
+        leading_zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    leading_zeros >= u32::from(difficulty_bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proofs_of_work_are_checked_and_spent() {
+        let config = config::ProofOfWorkConfig {
+            difficulty_bits: 8,
+            activate_above_per_sec: 2,
+            challenge_ttl_secs: 60,
+        };
+        let rng = rng::SeededRng::new(1);
+        let proof_of_work = ProofOfWork::new(Some(&config), &rng);
+        assert!(!proof_of_work.required(100));
+        assert!(!proof_of_work.required(100));
+        assert!(proof_of_work.required(100));
+        assert_eq!(
+            proof_of_work
+                .quota(100)
+                .map(|quota| (quota.remaining, quota.reset_secs)),
+            Some((0, 2))
+        );
+        assert!(proof_of_work.required(101));
+        assert_eq!(proof_of_work.quota(102).unwrap().reset_secs, 1);
+        assert!(!proof_of_work.required(103));
+
+        let challenge = proof_of_work.challenge(&rng, "", 100).challenge;
+        let nonce = (0u32..)
+            .map(|nonce| nonce.to_string())
+            .find(|nonce| solves(&challenge, nonce, 8))
+            .unwrap();
+        let wrong = (0u32..)
+            .map(|nonce| nonce.to_string())
+            .find(|nonce| !solves(&challenge, nonce, 8))
+            .unwrap();
+        assert!(!proof_of_work.verify(&challenge, &wrong, 100));
+        assert!(!proof_of_work.verify(&challenge, &nonce, 160));
+        let tampered = challenge.replacen(".8.", ".0.", 1);
+        assert!(!proof_of_work.verify(&tampered, &nonce, 100));
+        assert!(proof_of_work.verify(&challenge, &nonce, 100));
+        assert!(!proof_of_work.verify(&challenge, &nonce, 100));
+    }
+}
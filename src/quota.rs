@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tk_auth_types::codes;
+
+use crate::config;
+use crate::rate_limit;
+use crate::request_signing;
+use crate::{error_response, json_response, AppState};
+
+const MINUTE_SECS: u64 = 60;
+const DAY_SECS: u64 = 24 * 60 * 60;
+// Past this many tracked clients, new ones aren't counted until old days
+// run out, rather than letting sessions that come and go grow the map.
+const MAX_TRACKED_CLIENTS: usize = 100_000;
+
+// Sessions are told apart by their list key, so the map holds no ids.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Client {
+    Session(String),
+    Key(String),
+}
+
+#[derive(Default)]
+struct Usage {
+    minute: u64,
+    minute_count: u64,
+    day: u64,
+    day_count: u64,
+}
+
+impl Usage {
+    // The counts of the windows `now` is in.
+    fn current(&self, now: u64) -> (u64, u64) {
+        let minute_count = if self.minute == now / MINUTE_SECS {
+            self.minute_count
+        } else {
+            0
+        };
+        let day_count = if self.day == now / DAY_SECS {
+            self.day_count
+        } else {
+            0
+        };
+        (minute_count, day_count)
+    }
+}
+
+// Request quotas of sessions and signing keys, which unlike the abuse
+// throttles are set per client and can be looked up by it. Counts live in
+// this instance's memory only.
+#[derive(Default)]
+pub struct Quotas {
+    usage: Mutex<HashMap<Client, Usage>>,
+}
+
+impl Quotas {
+    // Counts a request, unless it's over a limit, in which case the
+    // exhausted quota is returned. Refused requests don't count.
+    fn count(
+        &self,
+        client: &Client,
+        config: &config::QuotaConfig,
+        now: u64,
+    ) -> Result<(), rate_limit::Quota> {
+        let mut usage = self.usage.lock().unwrap();
+        if usage.len() >= MAX_TRACKED_CLIENTS && !usage.contains_key(client) {
+            usage.retain(|_, usage| usage.day == now / DAY_SECS);
+            if usage.len() >= MAX_TRACKED_CLIENTS {
+                return Ok(());
+            }
+        }
+        let entry = usage.entry(client.clone()).or_default();
+        let (minute_count, day_count) = entry.current(now);
+        // When both are exhausted, the client has to wait for the later.
+        let exhausted = [
+            (config.per_day, day_count, DAY_SECS),
+            (config.per_minute, minute_count, MINUTE_SECS),
+        ]
+        .into_iter()
+        .find(|(limit, count, _)| limit.is_some_and(|limit| *count >= limit));
+        if let Some((limit, _, window_secs)) = exhausted {
+            return Err(rate_limit::Quota {
+                limit: limit.unwrap().try_into().unwrap_or(u32::MAX),
+                remaining: 0,
+                reset_secs: window_secs - now % window_secs,
+            });
+        }
+        *entry = Usage {
+            minute: now / MINUTE_SECS,
+            minute_count: minute_count + 1,
+            day: now / DAY_SECS,
+            day_count: day_count + 1,
+        };
+        Ok(())
+    }
+
+    fn report(
+        &self,
+        client: &Client,
+        config: Option<&config::QuotaConfig>,
+        now: u64,
+    ) -> serde_json::Value {
+        let (minute_count, day_count) = self
+            .usage
+            .lock()
+            .unwrap()
+            .get(client)
+            .map_or((0, 0), |usage| usage.current(now));
+        let window = |limit: Option<u64>, used: u64, window_secs: u64| {
+            serde_json::json!({
+                "limit": limit,
+                "used": used,
+                "reset_secs": window_secs - now % window_secs,
+            })
+        };
+        serde_json::json!({
+            "client": match client {
+                Client::Session(_) => "session",
+                Client::Key(_) => "key",
+            },
+            "per_minute": window(config.and_then(|config| config.per_minute), minute_count, MINUTE_SECS),
+            "per_day": window(config.and_then(|config| config.per_day), day_count, DAY_SECS),
+        })
+    }
+}
+
+pub fn is_enabled(config: &config::Config) -> bool {
+    config.session_quota.is_some()
+        || config
+            .request_signing
+            .iter()
+            .flat_map(|signing| &signing.keys)
+            .any(|key| key.quota.is_some())
+}
+
+fn key_quota<'a>(state: &'a AppState, id: &str) -> Option<&'a config::QuotaConfig> {
+    state
+        .config
+        .request_signing
+        .iter()
+        .flat_map(|signing| &signing.keys)
+        .find(|key| key.id == id)
+        .and_then(|key| key.quota.as_ref())
+}
+
+fn is_form(headers: &http::HeaderMap) -> bool {
+    headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"))
+}
+
+// The session named by `session_id` in the query or the form. Only sessions
+// that exist count, so made-up ids can't use up the map.
+fn session_client(state: &AppState, query: Option<&str>, form: Option<&[u8]>) -> Option<Client> {
+    let session_id = form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .chain(form_urlencoded::parse(form.unwrap_or_default()))
+        .find(|(name, _)| name == "session_id")
+        .map(|(_, id)| id)?;
+    let id = state.session_ids().parse(&session_id).ok()?;
+    state.sessions.get(&id)?;
+    Some(Client::Session(id.list_key()))
+}
+
+// The signing key marker is only trusted on the routes where
+// request_signing::layer sets it and strips it from clients.
+fn client(state: &AppState, parts: &http::request::Parts, form: Option<&[u8]>) -> Option<Client> {
+    let key = request_signing::is_signed_route(parts.uri.path())
+        .then(|| state.request_signing.verified_key(&parts.headers))
+        .flatten();
+    match key {
+        Some(id) => Some(Client::Key(id.to_string())),
+        None => session_client(state, parts.uri.query(), form),
+    }
+}
+
+fn client_quota<'a>(state: &'a AppState, client: &Client) -> Option<&'a config::QuotaConfig> {
+    match client {
+        Client::Session(_) => state.config.session_quota.as_ref(),
+        Client::Key(id) => key_quota(state, id),
+    }
+}
+
+// Refuses requests of sessions and keys over their quota with a 429 that
+// says when to come back.
+pub async fn layer(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let (parts, body) = request.into_parts();
+    let (form, body) = if is_form(&parts.headers) {
+        // The route's body limit applies here already.
+        let Ok(form) = axum::body::to_bytes(body, usize::MAX).await else {
+            return error_response(413, "the request body is too large");
+        };
+        (Some(form.clone()), axum::body::Body::from(form))
+    } else {
+        (None, body)
+    };
+    let now = state.clock.now_secs();
+    if let Some(client) = client(&state, &parts, form.as_deref()) {
+        if let Some(config) = client_quota(&state, &client) {
+            if let Err(quota) = state.quotas.count(&client, config, now) {
+                let mut response = crate::coded_error_response(
+                    429,
+                    codes::QUOTA_EXCEEDED,
+                    "request quota exceeded",
+                );
+                quota.apply(&mut response);
+                return response;
+            }
+        }
+    }
+    next.run(axum::extract::Request::from_parts(parts, body))
+        .await
+}
+
+// Usage of the signing key the request is signed with, or else of the
+// session in `session_id`.
+pub async fn get_usage(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    request: axum::extract::Request,
+) -> axum::response::Response {
+    if !is_enabled(&state.config) {
+        return error_response(404, "quotas are not enabled");
+    }
+    let (parts, _) = request.into_parts();
+    let Some(client) = client(&state, &parts, None) else {
+        return error_response(
+            401,
+            "sign the request with a key or give an existing session_id",
+        );
+    };
+    let config = client_quota(&state, &client);
+    json_response(
+        200,
+        state.quotas.report(&client, config, state.clock.now_secs()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_requests_per_minute_and_day() {
+        let quotas = Quotas::default();
+        let config = config::QuotaConfig {
+            per_minute: Some(2),
+            per_day: Some(3),
+        };
+        let session = Client::Session(String::from("a"));
+        let key = Client::Key(String::from("a"));
+        let count = |client, now| {
+            quotas
+                .count(client, &config, now)
+                .map_err(|quota| quota.reset_secs)
+        };
+        let day = 10 * DAY_SECS;
+        assert_eq!(count(&session, day), Ok(()));
+        assert_eq!(count(&session, day + 1), Ok(()));
+        assert_eq!(count(&session, day + 2), Err(58));
+        assert_eq!(count(&key, day + 2), Ok(()));
+        assert_eq!(count(&session, day + 60), Ok(()));
+        // The day's requests ran out.
+        assert_eq!(count(&session, day + 61), Err(DAY_SECS - 61));
+        assert_eq!(count(&session, day + DAY_SECS), Ok(()));
+
+        let report = quotas.report(&session, Some(&config), day + DAY_SECS + 1);
+        assert_eq!(report["per_minute"]["used"], 1);
+        assert_eq!(report["per_day"]["limit"], 3);
+        assert_eq!(report["per_day"]["reset_secs"], DAY_SECS - 1);
+    }
+}
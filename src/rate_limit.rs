@@ -0,0 +1,119 @@
+use std::sync::Arc;
+
+use crate::client_info::ClientInfo;
+use crate::config;
+use crate::AppState;
+
+// The throttled routes: proof_of_work counts new sessions per second, and
+// tarpit counts failed sign-ins per user and network.
+pub const ROUTES: [&str; 3] = ["/api/new_session", "/api/authenticate", "/login"];
+
+// The state of a quota as sent in RateLimit-Limit, RateLimit-Remaining and
+// RateLimit-Reset, the last in seconds until the quota is back in full.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Quota {
+    pub limit: u32,
+    pub remaining: u32,
+    pub reset_secs: u64,
+}
+
+impl Quota {
+    // 429s also get Retry-After, unless they say when to retry already.
+    pub fn apply(&self, response: &mut axum::response::Response) {
+        let too_many = response.status() == http::StatusCode::TOO_MANY_REQUESTS;
+        let headers = response.headers_mut();
+        for (name, value) in [
+            ("ratelimit-limit", u64::from(self.limit)),
+            ("ratelimit-remaining", u64::from(self.remaining)),
+            ("ratelimit-reset", self.reset_secs),
+        ] {
+            headers.insert(name, http::HeaderValue::from(value));
+        }
+        if too_many && !headers.contains_key(http::header::RETRY_AFTER) {
+            headers.insert(
+                http::header::RETRY_AFTER,
+                http::HeaderValue::from(self.reset_secs),
+            );
+        }
+    }
+}
+
+pub fn is_limited_route(path: &str, config: &config::Config) -> bool {
+    match path {
+        "/api/new_session" => config.proof_of_work.is_some(),
+        "/api/authenticate" | "/login" => config.tarpit.is_some(),
+        _ => false,
+    }
+}
+
+// Adds the quota left after the request to every response, 429s included,
+// so clients can slow down before they run into it. Responses that report
+// a quota already, like quota::layer's 429s, keep theirs.
+pub async fn layer(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    if request.uri().path() == "/api/new_session" {
+        let mut response = next.run(request).await;
+        if let Some(quota) = state.proof_of_work.quota(state.clock.now_secs()) {
+            if !response.headers().contains_key("ratelimit-limit") {
+                quota.apply(&mut response);
+            }
+        }
+        return response;
+    }
+    if request.method() != http::Method::POST {
+        return next.run(request).await;
+    }
+    // The tarpit counts per user too, which comes in the form.
+    let (parts, body) = request.into_parts();
+    // The route's body limit applies here already.
+    let Ok(body) = axum::body::to_bytes(body, usize::MAX).await else {
+        return crate::error_response(413, "the request body is too large");
+    };
+    let user = form_urlencoded::parse(&body)
+        .find(|(name, _)| name == "user")
+        .map(|(_, user)| user.into_owned());
+    let request = axum::extract::Request::from_parts(parts, axum::body::Body::from(body));
+    let mut response = next.run(request).await;
+    if let Some(quota) = state
+        .tarpit
+        .quota(user.as_deref(), client.ip, state.clock.now_secs())
+    {
+        if !response.headers().contains_key("ratelimit-limit") {
+            quota.apply(&mut response);
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_the_quota_as_headers() {
+        let quota = Quota {
+            limit: 3,
+            remaining: 0,
+            reset_secs: 120,
+        };
+        let mut response = crate::error_response(401, "");
+        response
+            .headers_mut()
+            .insert("ratelimit-limit", http::HeaderValue::from_static("1"));
+        quota.apply(&mut response);
+        let headers = response.headers();
+        let header = |name| headers.get(name).unwrap().to_str().unwrap();
+        assert_eq!(header("RateLimit-Limit"), "3");
+        assert_eq!(header("RateLimit-Remaining"), "0");
+        assert_eq!(header("RateLimit-Reset"), "120");
+        assert!(!headers.contains_key(http::header::RETRY_AFTER));
+
+        let mut response = crate::error_response(429, "");
+        quota.apply(&mut response);
+        assert_eq!(response.headers()[http::header::RETRY_AFTER], "120");
+    }
+}
@@ -0,0 +1,157 @@
+use std::sync::{Arc, RwLock};
+
+use tk_auth_types::errors;
+
+use crate::admin;
+use crate::time;
+use crate::{error_response, json_response, AppState};
+
+// The routes that create sessions or sign them in. POST /login checks on
+// its own, so it can answer with the form.
+pub const ROUTES: [&str; 7] = [
+    "/api/new_session",
+    "/api/authenticate",
+    "/api/negotiate",
+    "/api/session/resume",
+    "/api/sessions/batch",
+    "/api/idp/:provider/login",
+    "/api/idp/:provider/callback",
+];
+
+#[derive(Clone)]
+struct Mode {
+    message: Option<String>,
+    since: u64,
+}
+
+// Turned on through the admin API while the stores are migrated: sessions
+// can still be looked up, checked and revoked, but none are created or
+// signed in. Only this instance's memory holds it, so it's off again after
+// a restart.
+#[derive(Default)]
+pub struct ReadOnly {
+    mode: RwLock<Option<Mode>>,
+}
+
+impl ReadOnly {
+    // The operator's message, if read-only mode is on.
+    pub fn message(&self) -> Option<Option<String>> {
+        self.mode
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|mode| mode.message.clone())
+    }
+
+    fn report(&self) -> serde_json::Value {
+        let mode = self.mode.read().unwrap();
+        serde_json::json!({
+            "read_only": mode.is_some(),
+            "message": mode.as_ref().and_then(|mode| mode.message.clone()),
+            "since": mode.as_ref().map(|mode| time::rfc3339(mode.since)),
+        })
+    }
+}
+
+// The operator's message goes in the envelope's data, the error is the
+// same for every request so clients can tell it by its code.
+pub fn response(message: Option<String>) -> axum::response::Response {
+    json_response(
+        503,
+        serde_json::json!({
+            "error": errors::READ_ONLY,
+            "maintenance_message": message,
+        }),
+    )
+}
+
+pub async fn layer(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    match state.read_only.message() {
+        Some(message) => response(message),
+        None => next.run(request).await,
+    }
+}
+
+pub async fn get_read_only(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: http::HeaderMap,
+) -> axum::response::Response {
+    if state.config.admin.is_none() {
+        return error_response(404, "the admin API is not enabled");
+    }
+    if !admin::authorized(&state, &headers) {
+        return error_response(401, "invalid admin token");
+    }
+    json_response(200, state.read_only.report())
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReadOnlyRequest {
+    read_only: bool,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+pub async fn put_read_only(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: http::HeaderMap,
+    body: axum::body::Bytes,
+) -> axum::response::Response {
+    if state.config.admin.is_none() {
+        return error_response(404, "the admin API is not enabled");
+    }
+    if !admin::authorized(&state, &headers) {
+        return error_response(401, "invalid admin token");
+    }
+    let Ok(request) = serde_json::from_slice::<ReadOnlyRequest>(&body) else {
+        return error_response(400, "send read_only and optionally a message");
+    };
+    {
+        let mut mode = state.read_only.mode.write().unwrap();
+        match (request.read_only, mode.as_mut()) {
+            // Turning it on again only changes the message.
+            (true, Some(mode)) => mode.message = request.message,
+            (true, None) => {
+                *mode = Some(Mode {
+                    message: request.message,
+                    since: state.clock.now_secs(),
+                });
+                println!("Read-only mode turned on");
+            }
+            (false, Some(_)) => {
+                *mode = None;
+                println!("Read-only mode turned off");
+            }
+            (false, None) => {}
+        }
+    }
+    json_response(200, state.read_only.report())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_the_mode() {
+        let read_only = ReadOnly::default();
+        assert_eq!(read_only.message(), None);
+        assert_eq!(read_only.report()["read_only"], false);
+        *read_only.mode.write().unwrap() = Some(Mode {
+            message: Some(String::from("Back at 10:00 UTC")),
+            since: 0,
+        });
+        assert_eq!(
+            read_only.message(),
+            Some(Some(String::from("Back at 10:00 UTC")))
+        );
+        let report = read_only.report();
+        assert_eq!(report["since"], "1970-01-01T00:00:00Z");
+        assert_eq!(report["message"], "Back at 10:00 UTC");
+    }
+}
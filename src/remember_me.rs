@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use subtle::ConstantTimeEq;
+use tokio::sync::RwLock as TokioRwLock;
+
+use crate::client_info::ClientInfo;
+use crate::config;
+use crate::forward_auth;
+use crate::jwt::{base64url_decode, base64url_encode};
+use crate::rng;
+use crate::session::Session;
+use crate::{
+    authenticate, authentication_response, enforce_memory_budget, error_response, AppState,
+    SESSION_STORE_FULL,
+};
+
+const SERIES_BYTES: usize = 16;
+const VALIDATOR_BYTES: usize = 32;
+
+struct StoredToken {
+    validator_hash: Vec<u8>,
+    user: String,
+    device: String,
+    expires_at: u64,
+}
+
+pub enum RedeemError {
+    Invalid,
+    // The series was right but the validator wasn't: an older token of the
+    // series was replayed, so one of the two holders most likely stole it.
+    Reused { user: String },
+}
+
+// Tokens are "series.validator". The series stays the same for the whole
+// chain of logins it grants, while the validator changes on every use. Only
+// a hash of the current validator is kept.
+pub struct RememberMeStore {
+    tokens: TokioRwLock<HashMap<String, StoredToken>>,
+}
+
+fn random_validator(rng: &dyn rng::Rng) -> [u8; VALIDATOR_BYTES] {
+    let mut validator = [0u8; VALIDATOR_BYTES];
+    rng.fill(&mut validator);
+    validator
+}
+
+impl RememberMeStore {
+    pub fn new() -> Self {
+        Self {
+            tokens: TokioRwLock::new(HashMap::new()),
+        }
+    }
+
+    pub async fn issue(
+        &self,
+        rng: &dyn rng::Rng,
+        user: &str,
+        device: &str,
+        lifetime_secs: u64,
+        now: u64,
+    ) -> String {
+        let mut series = [0u8; SERIES_BYTES];
+        rng.fill(&mut series);
+        let series = base64url_encode(&series);
+        let validator = random_validator(rng);
+        let mut tokens = self.tokens.write().await;
+        tokens.retain(|_, token| token.expires_at > now);
+        tokens.insert(
+            series.clone(),
+            StoredToken {
+                validator_hash: hash(&validator),
+                user: user.to_string(),
+                device: device.to_string(),
+                expires_at: now + lifetime_secs,
+            },
+        );
+        format!("{}.{}", series, base64url_encode(&validator))
+    }
+
+    // Checks the token and hands out its successor, which replaces it.
+    pub async fn redeem(
+        &self,
+        rng: &dyn rng::Rng,
+        token: &str,
+        lifetime_secs: u64,
+        now: u64,
+    ) -> Result<(String, String), RedeemError> {
+        let (series, validator) = token.split_once('.').ok_or(RedeemError::Invalid)?;
+        let validator = base64url_decode(validator).ok_or(RedeemError::Invalid)?;
+        let mut tokens = self.tokens.write().await;
+        let stored = tokens.get_mut(series).ok_or(RedeemError::Invalid)?;
+        if !bool::from(hash(&validator).ct_eq(&stored.validator_hash)) {
+            let user = stored.user.clone();
+            tokens.retain(|_, token| token.user != user);
+            return Err(RedeemError::Reused { user });
+        }
+        if stored.expires_at <= now {
+            tokens.remove(series);
+            return Err(RedeemError::Invalid);
+        }
+        let next = random_validator(rng);
+        stored.validator_hash = hash(&next);
+        stored.expires_at = now + lifetime_secs;
+        Ok((
+            stored.user.clone(),
+            format!("{}.{}", series, base64url_encode(&next)),
+        ))
+    }
+
+    pub async fn revoke(&self, token: &str) {
+        if let Some((series, _)) = token.split_once('.') {
+            self.tokens.write().await.remove(series);
+        }
+    }
+
+    pub async fn revoke_user(&self, user: &str) -> usize {
+        let mut tokens = self.tokens.write().await;
+        let before = tokens.len();
+        tokens.retain(|_, token| token.user != user);
+        before - tokens.len()
+    }
+
+    pub async fn revoke_device(&self, user: &str, device: &str) -> usize {
+        let mut tokens = self.tokens.write().await;
+        let before = tokens.len();
+        tokens.retain(|_, token| token.user != user || token.device != device);
+        before - tokens.len()
+    }
+}
+
+fn hash(validator: &[u8]) -> Vec<u8> {
+    ring::digest::digest(&ring::digest::SHA256, validator)
+        .as_ref()
+        .to_vec()
+}
+
+pub fn set_cookie_header(remember_me: &config::RememberMeConfig, token: &str) -> http::HeaderValue {
+    let mut cookie = format!(
+        "{}={}; Path=/api/session/resume; Max-Age={}; HttpOnly; SameSite=Strict",
+        remember_me.cookie_name, token, remember_me.lifetime_secs
+    );
+    if remember_me.cookie_secure {
+        cookie.push_str("; Secure");
+    }
+    http::HeaderValue::from_str(&cookie).unwrap()
+}
+
+#[derive(serde::Deserialize)]
+pub struct ResumeForm {
+    remember_token: Option<String>,
+}
+
+pub async fn post_resume_session(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    headers: http::HeaderMap,
+    form: Option<axum::extract::Form<ResumeForm>>,
+) -> axum::response::Response {
+    let Some(remember_me) = &state.config.remember_me else {
+        return error_response(404, "remember-me is not enabled");
+    };
+    let Some(token) = form
+        .and_then(|axum::extract::Form(form)| form.remember_token)
+        .or_else(|| forward_auth::session_cookie(&headers, &remember_me.cookie_name))
+    else {
+        return error_response(401, "no remember-me token");
+    };
+    let redeemed = state
+        .remember_me
+        .redeem(
+            &state.rng,
+            &token,
+            remember_me.lifetime_secs,
+            state.clock.now_secs(),
+        )
+        .await;
+    let (user, next_token) = match redeemed {
+        Ok(redeemed) => redeemed,
+        Err(RedeemError::Reused { user }) => {
+            println!(
+                "Remember-me token of {} was replayed, revoked all of the user's tokens",
+                user
+            );
+            return error_response(401, "invalid remember-me token");
+        }
+        Err(RedeemError::Invalid) => return error_response(401, "invalid remember-me token"),
+    };
+
+    let session = Session::new(client.ip, client.user_agent, state.clock.now_secs());
+    if !enforce_memory_budget(&state, session.approx_bytes()).await {
+        state.remember_me.revoke(&next_token).await;
+        return SESSION_STORE_FULL.response();
+    }
+    let session_id = state.session_ids().generate(&state.rng);
+    let id_base64 = String::from(&session_id);
+    let session = state.sessions.insert(session_id.clone(), session);
+    let outcome = authenticate(&state, &session, &id_base64, user.clone(), false).await;
+    if outcome.is_err() {
+        state.sessions.remove(&session_id);
+        state.remember_me.revoke(&next_token).await;
+        return authentication_response(&state, &id_base64, outcome, None);
+    }
+    println!("Resumed a session of {} with a remember-me token", user);
+    authentication_response(&state, &id_base64, outcome, Some(next_token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn replaying_a_rotated_token_revokes_the_user() {
+        let rng = ring::rand::SystemRandom::new();
+        let store = RememberMeStore::new();
+        let token = store.issue(&rng, "alice", "laptop", 60, 1000).await;
+        let other = store.issue(&rng, "alice", "laptop", 60, 1000).await;
+        let (user, next) = store.redeem(&rng, &token, 60, 1000).await.ok().unwrap();
+        assert_eq!(user, "alice");
+        assert_eq!(
+            token.split_once('.').unwrap().0,
+            next.split_once('.').unwrap().0
+        );
+
+        assert!(matches!(
+            store.redeem(&rng, &token, 60, 1000).await,
+            Err(RedeemError::Reused { user }) if user == "alice"
+        ));
+        assert!(matches!(
+            store.redeem(&rng, &next, 60, 1000).await,
+            Err(RedeemError::Invalid)
+        ));
+        assert!(matches!(
+            store.redeem(&rng, &other, 60, 1000).await,
+            Err(RedeemError::Invalid)
+        ));
+    }
+}
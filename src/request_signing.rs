@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use base64::Engine;
+
+use crate::aws::hex;
+use crate::config;
+use crate::AppState;
+
+// Authorization: TK-HMAC-SHA256 KeyId=<id>, Timestamp=<unix secs>,
+// Signature=<base64 of HMAC-SHA256 over string_to_sign>
+pub const SCHEME: &str = "TK-HMAC-SHA256";
+// What the layer replaces a verified signature with. Requests that come with
+// it are stripped of it, so only the layer can set it.
+const VERIFIED_SCHEME: &str = "TK-Verified-Key";
+// Past this many signatures within the skew window, signed requests are
+// refused rather than risking a replay going unnoticed.
+const MAX_SEEN_SIGNATURES: usize = 100_000;
+
+// Signatures already used are remembered in this instance's memory only.
+#[derive(Default)]
+pub struct RequestSigning {
+    keys: HashMap<String, (ring::hmac::Key, config::SigningScope)>,
+    max_skew_secs: u64,
+    // Signature to its timestamp.
+    seen: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+impl RequestSigning {
+    pub fn new(config: Option<&config::RequestSigningConfig>) -> Self {
+        let Some(config) = config else {
+            return Self::default();
+        };
+        let keys = config
+            .keys
+            .iter()
+            .map(|key| {
+                // Validated on load.
+                let secret = zeroize::Zeroizing::new(
+                    base64::engine::general_purpose::STANDARD
+                        .decode(key.secret_base64.trim())
+                        .unwrap(),
+                );
+                (
+                    key.id.clone(),
+                    (
+                        ring::hmac::Key::new(ring::hmac::HMAC_SHA256, &secret),
+                        key.scope,
+                    ),
+                )
+            })
+            .collect();
+        Self {
+            keys,
+            max_skew_secs: config.max_skew_secs,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // The key the layer verified the request as signed by.
+    pub fn verified_key<'a>(&self, headers: &'a http::HeaderMap) -> Option<&'a str> {
+        headers
+            .get(http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix(VERIFIED_SCHEME))
+            .and_then(|value| value.strip_prefix(' '))
+            .filter(|id| self.keys.contains_key(*id))
+    }
+
+    // Whether the layer verified the request as signed by a key for `scope`.
+    pub fn verified(&self, headers: &http::HeaderMap, scope: config::SigningScope) -> bool {
+        self.verified_key(headers)
+            .and_then(|id| self.keys.get(id))
+            .is_some_and(|(_, key_scope)| *key_scope == scope)
+    }
+
+    fn verify<'a>(
+        &self,
+        method: &http::Method,
+        uri: &http::Uri,
+        authorization: &'a str,
+        body: &[u8],
+        now: u64,
+    ) -> Result<&'a str, &'static str> {
+        let (id, timestamp, signature) = parse(authorization).ok_or("malformed signature")?;
+        let (key, _) = self.keys.get(id).ok_or("unknown key")?;
+        if timestamp.abs_diff(now) > self.max_skew_secs {
+            return Err("timestamp too far from the server's clock");
+        }
+        let path_and_query = uri.path_and_query().map_or("/", |path| path.as_str());
+        let string_to_sign = string_to_sign(method.as_str(), path_and_query, timestamp, body);
+        ring::hmac::verify(key, string_to_sign.as_bytes(), &signature)
+            .map_err(|_| "wrong signature")?;
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, seen_at| seen_at.abs_diff(now) <= self.max_skew_secs);
+        if seen.contains_key(&signature) {
+            return Err("signature already used");
+        }
+        if seen.len() >= MAX_SEEN_SIGNATURES {
+            return Err("too many signed requests");
+        }
+        seen.insert(signature, timestamp);
+        Ok(id)
+    }
+}
+
+pub fn is_signed_route(path: &str) -> bool {
+    path.starts_with("/api/admin/")
+        || path == "/api/sessions/batch"
+        || path == "/api/session_state/batch"
+        || path == "/api/usage"
+        || path.starts_with("/scim/")
+}
+
+pub fn string_to_sign(method: &str, path_and_query: &str, timestamp: u64, body: &[u8]) -> String {
+    let body_hash = ring::digest::digest(&ring::digest::SHA256, body);
+    format!(
+        "{}\n{}\n{}\n{}",
+        method,
+        path_and_query,
+        timestamp,
+        hex(body_hash.as_ref())
+    )
+}
+
+fn parse(authorization: &str) -> Option<(&str, u64, Vec<u8>)> {
+    let params = authorization.strip_prefix(SCHEME)?.strip_prefix(' ')?;
+    let (mut id, mut timestamp, mut signature) = (None, None, None);
+    for param in params.split(',') {
+        match param.trim().split_once('=')? {
+            ("KeyId", value) => id = Some(value),
+            ("Timestamp", value) => timestamp = Some(value.parse().ok()?),
+            ("Signature", value) => {
+                signature = Some(
+                    base64::engine::general_purpose::STANDARD
+                        .decode(value)
+                        .ok()?,
+                )
+            }
+            _ => return None,
+        }
+    }
+    Some((id?, timestamp?, signature?))
+}
+
+// Verifies signed requests to the admin and SCIM APIs. A valid signature is
+// passed on as a marker the APIs accept in place of their bearer token; an
+// invalid one is dropped, so the API answers as it does without credentials.
+// The secret never travels with the request, and each signature works once.
+pub async fn layer(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let (mut parts, body) = request.into_parts();
+    let authorization = parts
+        .headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    if authorization.starts_with(VERIFIED_SCHEME) {
+        parts.headers.remove(http::header::AUTHORIZATION);
+    }
+    if !authorization.starts_with(SCHEME) {
+        return next
+            .run(axum::extract::Request::from_parts(parts, body))
+            .await;
+    }
+    // The route's body limit applies here already.
+    let Ok(body) = axum::body::to_bytes(body, usize::MAX).await else {
+        return crate::error_response(413, "the request body is too large");
+    };
+    let now = state.clock.now_secs();
+    match state
+        .request_signing
+        .verify(&parts.method, &parts.uri, &authorization, &body, now)
+    {
+        Ok(id) => {
+            let verified = format!("{} {}", VERIFIED_SCHEME, id);
+            parts.headers.insert(
+                http::header::AUTHORIZATION,
+                http::HeaderValue::from_str(&verified).unwrap(),
+            );
+        }
+        Err(err) => {
+            println!("Rejected signed request to {}: {}", parts.uri.path(), err);
+            parts.headers.remove(http::header::AUTHORIZATION);
+        }
+    }
+    let request = axum::extract::Request::from_parts(parts, axum::body::Body::from(body));
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_signatures_once() {
+        let secret = [7u8; 32];
+        let signing = RequestSigning::new(Some(&config::RequestSigningConfig {
+            keys: vec![config::RequestSigningKey {
+                id: String::from("ci"),
+                secret_base64: zeroize::Zeroizing::new(
+                    base64::engine::general_purpose::STANDARD.encode(secret),
+                ),
+                scope: config::SigningScope::Admin,
+                quota: None,
+            }],
+            max_skew_secs: 300,
+        }));
+        let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, &secret);
+        let sign = |method: &str, uri: &str, timestamp: u64, body: &[u8]| {
+            let tag = ring::hmac::sign(
+                &key,
+                string_to_sign(method, uri, timestamp, body).as_bytes(),
+            );
+            format!(
+                "{} KeyId=ci, Timestamp={}, Signature={}",
+                SCHEME,
+                timestamp,
+                base64::engine::general_purpose::STANDARD.encode(tag)
+            )
+        };
+        let verify = |authorization: &str, uri: &str, body: &[u8], now| {
+            signing
+                .verify(
+                    &http::Method::POST,
+                    &uri.parse().unwrap(),
+                    authorization,
+                    body,
+                    now,
+                )
+                .map(str::to_string)
+        };
+        let uri = "/api/admin/import_users?dry_run=true";
+
+        let authorization = sign("POST", uri, 1000, b"[]");
+        assert_eq!(
+            verify(&authorization, uri, b"[]", 1100),
+            Ok(String::from("ci"))
+        );
+        assert_eq!(
+            verify(&authorization, uri, b"[]", 1100),
+            Err("signature already used")
+        );
+        assert_eq!(
+            verify(&authorization, uri, b"[1]", 1000),
+            Err("wrong signature")
+        );
+        assert_eq!(
+            verify(&authorization, "/api/admin/import_users", b"[]", 1000),
+            Err("wrong signature")
+        );
+        assert_eq!(
+            verify(&authorization, uri, b"[]", 1301),
+            Err("timestamp too far from the server's clock")
+        );
+        assert_eq!(
+            verify(&authorization.replace("ci", "cd"), uri, b"[]", 1000),
+            Err("unknown key")
+        );
+
+        let mut headers = http::HeaderMap::new();
+        headers.insert(
+            http::header::AUTHORIZATION,
+            http::HeaderValue::from_static("TK-Verified-Key ci"),
+        );
+        assert!(signing.verified(&headers, config::SigningScope::Admin));
+        assert!(!signing.verified(&headers, config::SigningScope::Scim));
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::audit;
+use crate::client_info::ClientInfo;
+use crate::config::{self, RiskDecision as Decision};
+use crate::devices;
+use crate::http_client;
+use crate::AppState;
+
+// Past this many tracked users and addresses, counting starts over rather
+// than letting made-up user names grow the map.
+const MAX_TRACKED_ATTEMPTS: usize = 100_000;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum AttemptKey {
+    User(String),
+    Ip(IpAddr),
+}
+
+struct Attempts {
+    count: u32,
+    window_start: u64,
+}
+
+#[derive(serde::Serialize)]
+struct Assessment<'a> {
+    user: &'a str,
+    ip: IpAddr,
+    user_agent: Option<&'a str>,
+    device_id: String,
+    known_device: bool,
+    country: Option<String>,
+    asn: Option<u32>,
+    // Sign-in attempts within velocity_window_secs, this one included.
+    attempts_by_user: u32,
+    attempts_by_ip: u32,
+    velocity_window_secs: u64,
+    time: u64,
+}
+
+#[derive(serde::Deserialize)]
+struct Verdict {
+    decision: Decision,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+// Lets an external service decide about sign-ins with the deployment's own
+// fraud logic. It is asked before the password is checked, so it sees
+// guessing as well.
+#[derive(Default)]
+pub struct Risk {
+    config: Option<config::RiskConfig>,
+    client: Option<http_client::Client>,
+    attempts: Mutex<HashMap<AttemptKey, Attempts>>,
+}
+
+impl Risk {
+    pub fn new(config: &config::Config) -> io::Result<Self> {
+        let client = match &config.risk {
+            Some(_) => Some(http_client::Client::new(&config.secrets.ca_path)?),
+            None => None,
+        };
+        Ok(Self {
+            config: config.risk.clone(),
+            client,
+            attempts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Counts the attempt for both keys and returns their counts.
+    fn count_attempt(&self, user: &str, ip: IpAddr, window_secs: u64, now: u64) -> (u32, u32) {
+        let mut attempts = self.attempts.lock().unwrap();
+        if attempts.len() >= MAX_TRACKED_ATTEMPTS {
+            attempts.retain(|_, attempts| now < attempts.window_start + window_secs);
+            if attempts.len() >= MAX_TRACKED_ATTEMPTS {
+                attempts.clear();
+            }
+        }
+        let mut count = |key| {
+            let entry = attempts.entry(key).or_insert(Attempts {
+                count: 0,
+                window_start: now,
+            });
+            if now >= entry.window_start + window_secs {
+                entry.count = 0;
+                entry.window_start = now;
+            }
+            entry.count = entry.count.saturating_add(1);
+            entry.count
+        };
+        (
+            count(AttemptKey::User(user.to_string())),
+            count(AttemptKey::Ip(ip)),
+        )
+    }
+}
+
+// Allows everything when no risk service is configured. When it can't be
+// asked or answers nonsense, risk.on_error decides.
+pub async fn assess(state: &AppState, user: &str, client: &ClientInfo, now: u64) -> Decision {
+    let risk = &state.risk;
+    let (Some(config), Some(http)) = (&risk.config, &risk.client) else {
+        return Decision::Allow;
+    };
+    let (attempts_by_user, attempts_by_ip) =
+        risk.count_attempt(user, client.ip, config.velocity_window_secs, now);
+    let user_agent = client.user_agent.as_deref();
+    let device_id = devices::fingerprint(user_agent);
+    let known_device = state
+        .devices
+        .of(user)
+        .await
+        .iter()
+        .any(|device| device.id == device_id);
+    let location = state.geoip.locate(client.ip);
+    let body = serde_json::to_vec(&Assessment {
+        user,
+        ip: client.ip,
+        user_agent,
+        device_id,
+        known_device,
+        country: location.country,
+        asn: location.asn,
+        attempts_by_user,
+        attempts_by_ip,
+        velocity_window_secs: config.velocity_window_secs,
+        time: now,
+    })
+    .unwrap();
+    let response = tokio::time::timeout(
+        Duration::from_millis(config.timeout_ms),
+        http.request(
+            "POST",
+            &config.url,
+            &[
+                ("Content-Type", "application/json"),
+                ("Accept", "application/json"),
+            ],
+            &body,
+        ),
+    )
+    .await;
+    let verdict = match response {
+        Ok(Ok(response)) if response.status == 200 => {
+            serde_json::from_slice::<Verdict>(&response.body).map_err(|err| err.to_string())
+        }
+        Ok(Ok(response)) => Err(format!("status {}", response.status)),
+        Ok(Err(err)) => Err(err.to_string()),
+        Err(_) => Err(String::from("timed out")),
+    };
+    let mut verdict = verdict.unwrap_or_else(|err| {
+        println!("Risk service {} failed: {}", config.url, err);
+        Verdict {
+            decision: config.on_error,
+            reason: Some(String::from("risk service unavailable")),
+        }
+    });
+    // Without a CAPTCHA to step up to, there's only refusing.
+    if verdict.decision == Decision::StepUp && state.config.captcha.is_none() {
+        verdict.decision = Decision::Deny;
+    }
+    if verdict.decision != Decision::Allow {
+        audit::record(audit::AuditEvent::RiskDecision {
+            user,
+            ip: client.ip,
+            step_up: verdict.decision == Decision::StepUp,
+            reason: verdict.reason.as_deref(),
+        });
+    }
+    verdict.decision
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_attempts_per_user_and_address() {
+        let risk = Risk::default();
+        let ip = IpAddr::from([192, 0, 2, 1]);
+        assert_eq!(risk.count_attempt("zed", ip, 60, 100), (1, 1));
+        assert_eq!(risk.count_attempt("amy", ip, 60, 110), (1, 2));
+        assert_eq!(risk.count_attempt("zed", ip, 60, 120), (2, 3));
+        assert_eq!(risk.count_attempt("zed", ip, 60, 160), (1, 1));
+    }
+}
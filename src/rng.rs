@@ -0,0 +1,88 @@
+// Where session ids, tokens and secrets get their randomness from. ring's
+// SecureRandom is sealed, so a seeded generator can't stand in for it.
+pub trait Rng: Send + Sync {
+    fn fill(&self, dest: &mut [u8]);
+}
+
+impl Rng for ring::rand::SystemRandom {
+    fn fill(&self, dest: &mut [u8]) {
+        ring::rand::SecureRandom::fill(self, dest).unwrap();
+    }
+}
+
+impl<R: Rng + ?Sized> Rng for Box<R> {
+    fn fill(&self, dest: &mut [u8]) {
+        (**self).fill(dest)
+    }
+}
+
+// The same seed gives the same bytes for the same sequence of calls, so ids
+// and tokens can be reproduced. Never meant for production, which is why
+// it's only built for tests and with the seeded-rng feature.
+#[cfg(any(test, feature = "seeded-rng"))]
+pub struct SeededRng {
+    key: ring::hmac::Key,
+    counter: std::sync::atomic::AtomicU64,
+}
+
+#[cfg(any(test, feature = "seeded-rng"))]
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            key: ring::hmac::Key::new(ring::hmac::HMAC_SHA256, &seed.to_be_bytes()),
+            counter: std::sync::atomic::AtomicU64::new(0),
+        }
+    }
+}
+
+#[cfg(any(test, feature = "seeded-rng"))]
+impl Rng for SeededRng {
+    // HMAC-SHA256 of a counter, one block per 32 bytes.
+    fn fill(&self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(32) {
+            let counter = self
+                .counter
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let block = ring::hmac::sign(&self.key, &counter.to_be_bytes());
+            chunk.copy_from_slice(&block.as_ref()[..chunk.len()]);
+        }
+    }
+}
+
+// The system RNG, unless this is a seeded-rng build and TK_AUTH_RNG_SEED is
+// set.
+pub fn from_env() -> Box<dyn Rng> {
+    #[cfg(feature = "seeded-rng")]
+    if let Some(seed) = std::env::var("TK_AUTH_RNG_SEED")
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+    {
+        println!(
+            "Using a seeded RNG from TK_AUTH_RNG_SEED, session ids and tokens are predictable"
+        );
+        return Box::new(SeededRng::new(seed));
+    }
+    Box::new(ring::rand::SystemRandom::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_rng_repeats_itself() {
+        let fill = |seed: u64| {
+            let rng = SeededRng::new(seed);
+            let mut first = [0u8; 40];
+            let mut second = [0u8; 16];
+            rng.fill(&mut first);
+            rng.fill(&mut second);
+            (first, second)
+        };
+        assert_eq!(fill(7), fill(7));
+        assert_ne!(fill(7), fill(8));
+        let (first, second) = fill(7);
+        assert_ne!(first[..16], second[..]);
+        assert_ne!(first[..8], first[32..]);
+    }
+}
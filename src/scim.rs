@@ -0,0 +1,1035 @@
+use std::sync::Arc;
+
+use subtle::ConstantTimeEq;
+use tk_auth_types::errors;
+
+use crate::cluster::Invalidation;
+use crate::config;
+use crate::session::SessionEvent;
+use crate::time;
+use crate::users::{self, Group, StoreError, User, UserData};
+use crate::AppState;
+
+const USER_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+const GROUP_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:Group";
+const LIST_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+const PATCH_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:PatchOp";
+const ERROR_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+const SERVICE_PROVIDER_CONFIG_SCHEMA: &str =
+    "urn:ietf:params:scim:schemas:core:2.0:ServiceProviderConfig";
+const DEFAULT_COUNT: usize = 100;
+const MAX_COUNT: usize = 1000;
+
+type ScimResult = Result<axum::response::Response, ScimError>;
+
+pub struct ScimError {
+    status: u16,
+    scim_type: Option<&'static str>,
+    detail: String,
+}
+
+fn scim_error(status: u16, scim_type: Option<&'static str>, detail: &str) -> ScimError {
+    ScimError {
+        status,
+        scim_type,
+        detail: detail.to_string(),
+    }
+}
+
+impl From<StoreError> for ScimError {
+    fn from(err: StoreError) -> Self {
+        match err {
+            StoreError::NotFound => scim_error(404, None, "resource not found"),
+            StoreError::Conflict(message) => scim_error(409, Some("uniqueness"), &message),
+            StoreError::Invalid(message) => scim_error(400, Some("invalidValue"), &message),
+            StoreError::Io(message) => {
+                println!("Failed to save users: {}", message);
+                scim_error(500, None, "failed to save changes")
+            }
+            StoreError::Unavailable(error) => {
+                println!("Refused to change users: {}", error);
+                scim_error(503, None, errors::STORAGE_UNAVAILABLE)
+            }
+        }
+    }
+}
+
+impl axum::response::IntoResponse for ScimError {
+    fn into_response(self) -> axum::response::Response {
+        let mut body = serde_json::json!({
+            "schemas": [ERROR_SCHEMA],
+            "status": self.status.to_string(),
+            "detail": self.detail,
+        });
+        if let Some(scim_type) = self.scim_type {
+            body["scimType"] = serde_json::Value::from(scim_type);
+        }
+        scim_response(self.status, body)
+    }
+}
+
+fn scim_response(status: u16, body: serde_json::Value) -> axum::response::Response {
+    axum::response::Response::builder()
+        .status(status)
+        .header("Content-Type", "application/scim+json")
+        .body(axum::body::Body::new(body.to_string()))
+        .unwrap()
+}
+
+fn authorize(state: &AppState, headers: &http::HeaderMap) -> Result<(), ScimError> {
+    let Some(scim) = &state.config.scim else {
+        return Err(scim_error(404, None, "SCIM provisioning is not enabled"));
+    };
+    let token = headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if bool::from(token.as_bytes().ct_eq(scim.bearer_token.as_bytes()))
+        || state
+            .request_signing
+            .verified(headers, config::SigningScope::Scim)
+    {
+        Ok(())
+    } else {
+        Err(scim_error(401, None, "invalid bearer token"))
+    }
+}
+
+fn parse_body(body: &[u8]) -> Result<serde_json::Map<String, serde_json::Value>, ScimError> {
+    match serde_json::from_slice(body) {
+        Ok(serde_json::Value::Object(object)) => Ok(object),
+        _ => Err(scim_error(
+            400,
+            Some("invalidSyntax"),
+            "request body must be a JSON object",
+        )),
+    }
+}
+
+fn meta(
+    resource_type: &str,
+    id: &str,
+    created: u64,
+    last_modified: u64,
+    version: u64,
+) -> serde_json::Value {
+    serde_json::json!({
+        "resourceType": resource_type,
+        "created": time::rfc3339(created),
+        "lastModified": time::rfc3339(last_modified),
+        "location": format!("/scim/v2/{}s/{}", resource_type, id),
+        "version": format!("W/\"{}\"", version),
+    })
+}
+
+fn user_resource(data: &UserData, user: &User) -> serde_json::Value {
+    let mut resource = serde_json::json!({
+        "schemas": [USER_SCHEMA],
+        "id": user.id,
+        "userName": user.user_name,
+        "active": user.active,
+        "emails": user
+            .emails
+            .iter()
+            .enumerate()
+            .map(|(i, email)| serde_json::json!({ "value": email, "type": "work", "primary": i == 0 }))
+            .collect::<Vec<_>>(),
+        "phoneNumbers": user
+            .phone_numbers
+            .iter()
+            .enumerate()
+            .map(|(i, number)| serde_json::json!({ "value": number, "type": "mobile", "primary": i == 0 }))
+            .collect::<Vec<_>>(),
+        "groups": data
+            .groups_of(&user.id)
+            .iter()
+            .map(|group| serde_json::json!({
+                "value": group.id,
+                "display": group.display_name,
+                "$ref": format!("/scim/v2/Groups/{}", group.id),
+            }))
+            .collect::<Vec<_>>(),
+        "meta": meta("User", &user.id, user.created, user.last_modified, user.version),
+    });
+    if let Some(external_id) = &user.external_id {
+        resource["externalId"] = serde_json::Value::from(external_id.as_str());
+    }
+    if let Some(display_name) = &user.display_name {
+        resource["displayName"] = serde_json::Value::from(display_name.as_str());
+    }
+    if let Some(preferred_language) = &user.preferred_language {
+        resource["preferredLanguage"] = serde_json::Value::from(preferred_language.as_str());
+    }
+    let mut name = serde_json::Map::new();
+    if let Some(given_name) = &user.given_name {
+        name.insert(String::from("givenName"), given_name.as_str().into());
+    }
+    if let Some(family_name) = &user.family_name {
+        name.insert(String::from("familyName"), family_name.as_str().into());
+    }
+    if !name.is_empty() {
+        resource["name"] = serde_json::Value::Object(name);
+    }
+    resource
+}
+
+fn group_resource(data: &UserData, group: &Group) -> serde_json::Value {
+    let mut resource = serde_json::json!({
+        "schemas": [GROUP_SCHEMA],
+        "id": group.id,
+        "displayName": group.display_name,
+        "members": group
+            .members
+            .iter()
+            .filter_map(|id| data.users.get(id))
+            .map(|user| serde_json::json!({
+                "value": user.id,
+                "display": user.user_name,
+                "$ref": format!("/scim/v2/Users/{}", user.id),
+            }))
+            .collect::<Vec<_>>(),
+        "meta": meta("Group", &group.id, group.created, group.last_modified, group.version),
+    });
+    if let Some(external_id) = &group.external_id {
+        resource["externalId"] = serde_json::Value::from(external_id.as_str());
+    }
+    resource
+}
+
+#[derive(Debug, PartialEq)]
+enum FilterOp {
+    Eq,
+    Ne,
+    Co,
+    Sw,
+    Ew,
+    Pr,
+}
+
+struct Filter {
+    clauses: Vec<(String, FilterOp, Option<serde_json::Value>)>,
+}
+
+impl Filter {
+    fn parse(filter: &str) -> Option<Self> {
+        let mut tokens = tokenize(filter)?.into_iter().peekable();
+        let mut clauses = Vec::new();
+        loop {
+            let attribute = tokens.next()?;
+            let op = match tokens.next()?.to_ascii_lowercase().as_str() {
+                "eq" => FilterOp::Eq,
+                "ne" => FilterOp::Ne,
+                "co" => FilterOp::Co,
+                "sw" => FilterOp::Sw,
+                "ew" => FilterOp::Ew,
+                "pr" => FilterOp::Pr,
+                _ => return None,
+            };
+            let value = if op == FilterOp::Pr {
+                None
+            } else {
+                let token = tokens.next()?;
+                Some(match token.strip_prefix('"') {
+                    Some(quoted) => serde_json::Value::from(quoted),
+                    None => serde_json::from_str(&token).ok()?,
+                })
+            };
+            clauses.push((attribute, op, value));
+            match tokens.next() {
+                None => return Some(Self { clauses }),
+                Some(token) if token.eq_ignore_ascii_case("and") => {}
+                Some(_) => return None,
+            }
+        }
+    }
+
+    fn matches(&self, resource: &serde_json::Value) -> bool {
+        self.clauses.iter().all(|(attribute, op, expected)| {
+            let case_exact = attribute.eq_ignore_ascii_case("id")
+                || attribute.eq_ignore_ascii_case("externalId");
+            let values = attribute_values(resource, attribute);
+            if *op == FilterOp::Pr {
+                return values.iter().any(|value| !value.is_null());
+            }
+            let expected = expected.as_ref().unwrap();
+            let matches = |value: &serde_json::Value| match (value, expected) {
+                (serde_json::Value::String(value), serde_json::Value::String(expected)) => {
+                    let (value, expected) = if case_exact {
+                        (value.clone(), expected.clone())
+                    } else {
+                        (value.to_lowercase(), expected.to_lowercase())
+                    };
+                    match op {
+                        FilterOp::Eq | FilterOp::Ne => value == expected,
+                        FilterOp::Co => value.contains(&expected),
+                        FilterOp::Sw => value.starts_with(&expected),
+                        FilterOp::Ew => value.ends_with(&expected),
+                        FilterOp::Pr => unreachable!(),
+                    }
+                }
+                (value, expected) => matches!(op, FilterOp::Eq | FilterOp::Ne) && value == expected,
+            };
+            let found = values.iter().any(|value| matches(value));
+            if *op == FilterOp::Ne {
+                !found
+            } else {
+                found
+            }
+        })
+    }
+}
+
+fn tokenize(filter: &str) -> Option<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut chars = filter.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut token = String::from("\"");
+            loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => token.push(chars.next()?),
+                    c => token.push(c),
+                }
+            }
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    Some(tokens)
+}
+
+fn get_ignore_case<'a>(value: &'a serde_json::Value, key: &str) -> Option<&'a serde_json::Value> {
+    value
+        .as_object()?
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(key))
+        .map(|(_, value)| value)
+}
+
+fn attribute_values<'a>(
+    resource: &'a serde_json::Value,
+    attribute: &str,
+) -> Vec<&'a serde_json::Value> {
+    let mut values = vec![resource];
+    for part in attribute.split('.') {
+        values = values
+            .into_iter()
+            .flat_map(|value| match value {
+                serde_json::Value::Array(items) => items.iter().collect(),
+                value => vec![value],
+            })
+            .filter_map(|value| get_ignore_case(value, part))
+            .collect();
+    }
+    values
+        .into_iter()
+        .flat_map(|value| match value {
+            serde_json::Value::Array(items) => items
+                .iter()
+                .map(|item| get_ignore_case(item, "value").unwrap_or(item))
+                .collect(),
+            value => vec![value],
+        })
+        .collect()
+}
+
+#[derive(serde::Deserialize)]
+pub struct ListQuery {
+    filter: Option<String>,
+    #[serde(rename = "startIndex")]
+    start_index: Option<usize>,
+    count: Option<usize>,
+}
+
+fn list_response(
+    resources: Vec<serde_json::Value>,
+    query: &ListQuery,
+) -> Result<axum::response::Response, ScimError> {
+    let filter = match &query.filter {
+        Some(filter) => Some(
+            Filter::parse(filter)
+                .ok_or_else(|| scim_error(400, Some("invalidFilter"), "unsupported filter"))?,
+        ),
+        None => None,
+    };
+    let matching: Vec<serde_json::Value> = resources
+        .into_iter()
+        .filter(|resource| {
+            filter
+                .as_ref()
+                .is_none_or(|filter| filter.matches(resource))
+        })
+        .collect();
+    let start_index = query.start_index.unwrap_or(1).max(1);
+    let count = query.count.unwrap_or(DEFAULT_COUNT).min(MAX_COUNT);
+    let page: Vec<serde_json::Value> = matching
+        .iter()
+        .skip(start_index - 1)
+        .take(count)
+        .cloned()
+        .collect();
+    Ok(scim_response(
+        200,
+        serde_json::json!({
+            "schemas": [LIST_SCHEMA],
+            "totalResults": matching.len(),
+            "startIndex": start_index,
+            "itemsPerPage": page.len(),
+            "Resources": page,
+        }),
+    ))
+}
+
+fn as_bool(value: &serde_json::Value) -> Option<bool> {
+    match value {
+        serde_json::Value::Bool(value) => Some(*value),
+        serde_json::Value::String(value) => value.to_ascii_lowercase().parse().ok(),
+        _ => None,
+    }
+}
+
+fn as_optional_string(value: &serde_json::Value) -> Result<Option<String>, StoreError> {
+    match value {
+        serde_json::Value::Null => Ok(None),
+        serde_json::Value::String(value) => Ok(Some(value.clone())),
+        _ => Err(StoreError::Invalid(String::from("expected a string"))),
+    }
+}
+
+// Values of a multi-valued attribute like emails, the primary one first.
+fn multi_values_from(value: &serde_json::Value) -> Vec<String> {
+    let items = match value {
+        serde_json::Value::Array(items) => items.iter().collect(),
+        serde_json::Value::Null => Vec::new(),
+        value => vec![value],
+    };
+    let mut values: Vec<(bool, String)> = items
+        .into_iter()
+        .filter_map(|item| match item {
+            serde_json::Value::String(value) => Some((false, value.clone())),
+            item => Some((
+                get_ignore_case(item, "primary")
+                    .and_then(as_bool)
+                    .unwrap_or(false),
+                get_ignore_case(item, "value")?.as_str()?.to_string(),
+            )),
+        })
+        .collect();
+    values.sort_by_key(|(primary, _)| !primary);
+    values.into_iter().map(|(_, value)| value).collect()
+}
+
+fn set_user_attribute(
+    user: &mut User,
+    path: &str,
+    value: Option<&serde_json::Value>,
+) -> Result<(), StoreError> {
+    let null = serde_json::Value::Null;
+    let value = value.unwrap_or(&null);
+    let path = path.to_ascii_lowercase();
+    match path.as_str() {
+        "username" => {
+            user.user_name = value
+                .as_str()
+                .filter(|user_name| !user_name.is_empty())
+                .ok_or_else(|| StoreError::Invalid(String::from("userName is required")))?
+                .to_string()
+        }
+        "externalid" => user.external_id = as_optional_string(value)?,
+        "displayname" => user.display_name = as_optional_string(value)?,
+        "preferredlanguage" => user.preferred_language = as_optional_string(value)?,
+        "name.givenname" => user.given_name = as_optional_string(value)?,
+        "name.familyname" => user.family_name = as_optional_string(value)?,
+        "name" => {
+            user.given_name = get_ignore_case(value, "givenName")
+                .map(as_optional_string)
+                .transpose()?
+                .flatten();
+            user.family_name = get_ignore_case(value, "familyName")
+                .map(as_optional_string)
+                .transpose()?
+                .flatten();
+        }
+        "active" => {
+            user.active = as_bool(value)
+                .ok_or_else(|| StoreError::Invalid(String::from("active must be a boolean")))?
+        }
+        "password" => {
+            user.password_hash =
+                as_optional_string(value)?.map(|password| users::hash_password(&password));
+            user.password_reset_required = false;
+        }
+        path if path == "emails" || path.starts_with("emails[") || path.starts_with("emails.") => {
+            user.emails = multi_values_from(value)
+        }
+        path if path == "phonenumbers"
+            || path.starts_with("phonenumbers[")
+            || path.starts_with("phonenumbers.") =>
+        {
+            user.phone_numbers = multi_values_from(value)
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn apply_user(
+    user: &mut User,
+    body: &serde_json::Map<String, serde_json::Value>,
+) -> Result<(), StoreError> {
+    for (key, value) in body {
+        if !key.eq_ignore_ascii_case("schemas") && !key.eq_ignore_ascii_case("id") {
+            set_user_attribute(user, key, Some(value))?;
+        }
+    }
+    Ok(())
+}
+
+fn check_user_name_unique(data: &UserData, user: &User) -> Result<(), StoreError> {
+    match data.user_by_name(&user.user_name) {
+        Some(other) if other.id != user.id => Err(StoreError::Conflict(format!(
+            "userName {} is already taken",
+            user.user_name
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn save_user(data: &mut UserData, mut user: User) -> Result<User, StoreError> {
+    check_user_name_unique(data, &user)?;
+    user.last_modified = time::now_secs();
+    data.users.insert(user.id.clone(), user.clone());
+    Ok(user)
+}
+
+async fn revoke_user_sessions(state: &AppState, user_name: &str) {
+    for session in state.sessions.remove_user(user_name).await {
+        session.write().await.publish(SessionEvent::Revoked);
+    }
+    state.cluster.publish(Invalidation::UserRevoked {
+        user: user_name.to_string(),
+    });
+    println!("Revoked sessions of deprovisioned user {}", user_name);
+}
+
+async fn respond_user(
+    state: &AppState,
+    status: u16,
+    previous: Option<User>,
+    user: &User,
+) -> axum::response::Response {
+    if let Some(previous) = previous {
+        if previous.active && !user.active {
+            revoke_user_sessions(state, &previous.user_name).await;
+        }
+    }
+    let data = state.users.read().await;
+    scim_response(status, user_resource(&data, user))
+}
+
+pub async fn get_users(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+    axum::extract::Query(query): axum::extract::Query<ListQuery>,
+) -> ScimResult {
+    authorize(&state, &headers)?;
+    let data = state.users.read().await;
+    let resources = data
+        .users
+        .values()
+        .map(|user| user_resource(&data, user))
+        .collect();
+    list_response(resources, &query)
+}
+
+pub async fn post_users(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+    body: axum::body::Bytes,
+) -> ScimResult {
+    authorize(&state, &headers)?;
+    let body = parse_body(&body)?;
+    let user = state
+        .users
+        .update(|data| {
+            let mut user = User::new(users::new_id(), String::new());
+            apply_user(&mut user, &body)?;
+            if user.user_name.is_empty() {
+                return Err(StoreError::Invalid(String::from("userName is required")));
+            }
+            save_user(data, user)
+        })
+        .await?;
+    println!("Provisioned user {}", user.user_name);
+    Ok(respond_user(&state, 201, None, &user).await)
+}
+
+pub async fn get_user(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> ScimResult {
+    authorize(&state, &headers)?;
+    let data = state.users.read().await;
+    let user = data.users.get(&id).ok_or(StoreError::NotFound)?;
+    Ok(scim_response(200, user_resource(&data, user)))
+}
+
+pub async fn put_user(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+    axum::extract::Path(id): axum::extract::Path<String>,
+    body: axum::body::Bytes,
+) -> ScimResult {
+    authorize(&state, &headers)?;
+    let body = parse_body(&body)?;
+    let (previous, user) = state
+        .users
+        .update(|data| {
+            let previous = data.users.get(&id).cloned().ok_or(StoreError::NotFound)?;
+            let mut user = User::new(previous.id.clone(), previous.user_name.clone());
+            user.created = previous.created;
+            user.version = previous.version + 1;
+            user.password_hash = previous.password_hash.clone();
+            user.notification_preferences = previous.notification_preferences.clone();
+            apply_user(&mut user, &body)?;
+            Ok((previous, save_user(data, user)?))
+        })
+        .await?;
+    Ok(respond_user(&state, 200, Some(previous), &user).await)
+}
+
+pub async fn patch_user(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+    axum::extract::Path(id): axum::extract::Path<String>,
+    body: axum::body::Bytes,
+) -> ScimResult {
+    authorize(&state, &headers)?;
+    let operations = patch_operations(&parse_body(&body)?)?;
+    let (previous, user) = state
+        .users
+        .update(|data| {
+            let previous = data.users.get(&id).cloned().ok_or(StoreError::NotFound)?;
+            let mut user = previous.clone();
+            for operation in &operations {
+                match (&operation.path, &operation.op[..]) {
+                    (Some(path), "remove") => set_user_attribute(&mut user, path, None)?,
+                    (Some(path), _) => {
+                        set_user_attribute(&mut user, path, operation.value.as_ref())?
+                    }
+                    (None, "remove") => {
+                        return Err(StoreError::Invalid(String::from(
+                            "remove operations need a path",
+                        )))
+                    }
+                    (None, _) => match &operation.value {
+                        Some(serde_json::Value::Object(values)) => apply_user(&mut user, values)?,
+                        _ => {
+                            return Err(StoreError::Invalid(String::from(
+                                "operations without a path need an object value",
+                            )))
+                        }
+                    },
+                }
+            }
+            user.version += 1;
+            Ok((previous, save_user(data, user)?))
+        })
+        .await?;
+    Ok(respond_user(&state, 200, Some(previous), &user).await)
+}
+
+pub async fn delete_user(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> ScimResult {
+    authorize(&state, &headers)?;
+    let user = state
+        .users
+        .update(|data| {
+            let user = data.users.remove(&id).ok_or(StoreError::NotFound)?;
+            for group in data.groups.values_mut() {
+                group.members.retain(|member| *member != id);
+            }
+            Ok(user)
+        })
+        .await?;
+    revoke_user_sessions(&state, &user.user_name).await;
+    println!("Deleted user {}", user.user_name);
+    Ok(axum::response::Response::builder()
+        .status(204)
+        .body(axum::body::Body::empty())
+        .unwrap())
+}
+
+struct PatchOperation {
+    op: String,
+    path: Option<String>,
+    value: Option<serde_json::Value>,
+}
+
+fn patch_operations(
+    body: &serde_json::Map<String, serde_json::Value>,
+) -> Result<Vec<PatchOperation>, ScimError> {
+    let invalid = || scim_error(400, Some("invalidSyntax"), "malformed PatchOp request");
+    let schemas = body.get("schemas").and_then(|schemas| schemas.as_array());
+    if !schemas.is_some_and(|schemas| schemas.iter().any(|schema| schema == PATCH_SCHEMA)) {
+        return Err(invalid());
+    }
+    let operations = get_ignore_case(&serde_json::Value::Object(body.clone()), "Operations")
+        .and_then(|operations| operations.as_array())
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|operation| {
+            let op = get_ignore_case(operation, "op")?
+                .as_str()?
+                .to_ascii_lowercase();
+            if !matches!(op.as_str(), "add" | "replace" | "remove") {
+                return None;
+            }
+            Some(PatchOperation {
+                op,
+                path: get_ignore_case(operation, "path")
+                    .and_then(|path| path.as_str())
+                    .map(String::from),
+                value: get_ignore_case(operation, "value").cloned(),
+            })
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(invalid)?;
+    Ok(operations)
+}
+
+fn member_ids(
+    data: &UserData,
+    value: Option<&serde_json::Value>,
+) -> Result<Vec<String>, StoreError> {
+    let items = match value {
+        Some(serde_json::Value::Array(items)) => items.iter().collect(),
+        Some(serde_json::Value::Null) | None => Vec::new(),
+        Some(value) => vec![value],
+    };
+    items
+        .into_iter()
+        .map(|item| {
+            let id = get_ignore_case(item, "value")
+                .and_then(|id| id.as_str())
+                .ok_or_else(|| StoreError::Invalid(String::from("members need a value")))?;
+            if data.users.contains_key(id) {
+                Ok(id.to_string())
+            } else {
+                Err(StoreError::Invalid(format!("unknown member {}", id)))
+            }
+        })
+        .collect()
+}
+
+fn member_filter_id(path: &str) -> Option<String> {
+    let filter = path
+        .strip_prefix("members[")
+        .or_else(|| path.strip_prefix("Members["))?
+        .strip_suffix(']')?;
+    let filter = Filter::parse(filter)?;
+    match &filter.clauses[..] {
+        [(attribute, FilterOp::Eq, Some(serde_json::Value::String(id)))]
+            if attribute.eq_ignore_ascii_case("value") =>
+        {
+            Some(id.clone())
+        }
+        _ => None,
+    }
+}
+
+fn set_group_attribute(
+    data: &UserData,
+    group: &mut Group,
+    op: &str,
+    path: &str,
+    value: Option<&serde_json::Value>,
+) -> Result<(), StoreError> {
+    let null = serde_json::Value::Null;
+    match path.to_ascii_lowercase().as_str() {
+        "displayname" => {
+            group.display_name = value
+                .unwrap_or(&null)
+                .as_str()
+                .filter(|display_name| !display_name.is_empty())
+                .ok_or_else(|| StoreError::Invalid(String::from("displayName is required")))?
+                .to_string()
+        }
+        "externalid" => group.external_id = as_optional_string(value.unwrap_or(&null))?,
+        "members" => {
+            let ids = member_ids(data, value)?;
+            match op {
+                "add" => {
+                    for id in ids {
+                        if !group.members.contains(&id) {
+                            group.members.push(id);
+                        }
+                    }
+                }
+                "remove" if value.is_some() => group.members.retain(|id| !ids.contains(id)),
+                _ => group.members = ids,
+            }
+        }
+        _ => {
+            if let Some(id) = member_filter_id(path) {
+                if op == "remove" {
+                    group.members.retain(|member| *member != id);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn apply_group(
+    data: &UserData,
+    group: &mut Group,
+    op: &str,
+    body: &serde_json::Map<String, serde_json::Value>,
+) -> Result<(), StoreError> {
+    for (key, value) in body {
+        if !key.eq_ignore_ascii_case("schemas") && !key.eq_ignore_ascii_case("id") {
+            set_group_attribute(data, group, op, key, Some(value))?;
+        }
+    }
+    Ok(())
+}
+
+fn save_group(data: &mut UserData, mut group: Group) -> Result<Group, StoreError> {
+    let taken = data.groups.values().any(|other| {
+        other.id != group.id && other.display_name.eq_ignore_ascii_case(&group.display_name)
+    });
+    if taken {
+        return Err(StoreError::Conflict(format!(
+            "displayName {} is already taken",
+            group.display_name
+        )));
+    }
+    group.last_modified = time::now_secs();
+    data.groups.insert(group.id.clone(), group.clone());
+    Ok(group)
+}
+
+pub async fn get_groups(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+    axum::extract::Query(query): axum::extract::Query<ListQuery>,
+) -> ScimResult {
+    authorize(&state, &headers)?;
+    let data = state.users.read().await;
+    let resources = data
+        .groups
+        .values()
+        .map(|group| group_resource(&data, group))
+        .collect();
+    list_response(resources, &query)
+}
+
+pub async fn post_groups(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+    body: axum::body::Bytes,
+) -> ScimResult {
+    authorize(&state, &headers)?;
+    let body = parse_body(&body)?;
+    let group = state
+        .users
+        .update(|data| {
+            let mut group = Group::new(users::new_id(), String::new());
+            apply_group(data, &mut group, "replace", &body)?;
+            if group.display_name.is_empty() {
+                return Err(StoreError::Invalid(String::from("displayName is required")));
+            }
+            save_group(data, group)
+        })
+        .await?;
+    let data = state.users.read().await;
+    Ok(scim_response(201, group_resource(&data, &group)))
+}
+
+pub async fn get_group(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> ScimResult {
+    authorize(&state, &headers)?;
+    let data = state.users.read().await;
+    let group = data.groups.get(&id).ok_or(StoreError::NotFound)?;
+    Ok(scim_response(200, group_resource(&data, group)))
+}
+
+pub async fn put_group(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+    axum::extract::Path(id): axum::extract::Path<String>,
+    body: axum::body::Bytes,
+) -> ScimResult {
+    authorize(&state, &headers)?;
+    let body = parse_body(&body)?;
+    let group = state
+        .users
+        .update(|data| {
+            let previous = data.groups.get(&id).cloned().ok_or(StoreError::NotFound)?;
+            let mut group = Group::new(previous.id.clone(), previous.display_name.clone());
+            group.created = previous.created;
+            group.version = previous.version + 1;
+            apply_group(data, &mut group, "replace", &body)?;
+            save_group(data, group)
+        })
+        .await?;
+    let data = state.users.read().await;
+    Ok(scim_response(200, group_resource(&data, &group)))
+}
+
+pub async fn patch_group(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+    axum::extract::Path(id): axum::extract::Path<String>,
+    body: axum::body::Bytes,
+) -> ScimResult {
+    authorize(&state, &headers)?;
+    let operations = patch_operations(&parse_body(&body)?)?;
+    let group = state
+        .users
+        .update(|data| {
+            let mut group = data.groups.get(&id).cloned().ok_or(StoreError::NotFound)?;
+            for operation in &operations {
+                match (&operation.path, &operation.value) {
+                    (Some(path), value) => {
+                        set_group_attribute(data, &mut group, &operation.op, path, value.as_ref())?
+                    }
+                    (None, Some(serde_json::Value::Object(values))) => {
+                        apply_group(data, &mut group, &operation.op, values)?
+                    }
+                    (None, _) => {
+                        return Err(StoreError::Invalid(String::from(
+                            "operations without a path need an object value",
+                        )))
+                    }
+                }
+            }
+            group.version += 1;
+            save_group(data, group)
+        })
+        .await?;
+    let data = state.users.read().await;
+    Ok(scim_response(200, group_resource(&data, &group)))
+}
+
+pub async fn delete_group(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> ScimResult {
+    authorize(&state, &headers)?;
+    state
+        .users
+        .update(|data| {
+            data.groups
+                .remove(&id)
+                .map(|_| ())
+                .ok_or(StoreError::NotFound)
+        })
+        .await?;
+    Ok(axum::response::Response::builder()
+        .status(204)
+        .body(axum::body::Body::empty())
+        .unwrap())
+}
+
+pub async fn get_service_provider_config(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+) -> ScimResult {
+    authorize(&state, &headers)?;
+    Ok(scim_response(
+        200,
+        serde_json::json!({
+            "schemas": [SERVICE_PROVIDER_CONFIG_SCHEMA],
+            "patch": { "supported": true },
+            "bulk": { "supported": false, "maxOperations": 0, "maxPayloadSize": 0 },
+            "filter": { "supported": true, "maxResults": MAX_COUNT },
+            "changePassword": { "supported": true },
+            "sort": { "supported": false },
+            "etag": { "supported": false },
+            "authenticationSchemes": [{
+                "type": "oauthbearertoken",
+                "name": "Bearer token",
+                "description": "Static bearer token from the scim.bearer_token setting",
+            }],
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resource() -> serde_json::Value {
+        serde_json::json!({
+            "id": "Abc",
+            "userName": "Alice",
+            "active": true,
+            "name": { "givenName": "Alice" },
+            "emails": [{ "value": "alice@example.com", "primary": true }],
+        })
+    }
+
+    #[test]
+    fn filters_case_insensitively_except_ids() {
+        let resource = resource();
+        let matches = |filter: &str| Filter::parse(filter).unwrap().matches(&resource);
+        assert!(matches(r#"userName eq "alice""#));
+        assert!(matches(r#"USERNAME Eq "ALICE""#));
+        assert!(!matches(r#"id eq "abc""#));
+        assert!(matches(r#"id eq "Abc""#));
+        assert!(matches(r#"userName ne "bob""#));
+    }
+
+    #[test]
+    fn filters_nested_and_multi_valued_attributes() {
+        let resource = resource();
+        let matches = |filter: &str| Filter::parse(filter).unwrap().matches(&resource);
+        assert!(matches(r#"name.givenName sw "al""#));
+        assert!(matches(r#"emails co "@example""#));
+        assert!(matches(r#"emails.value ew ".com" and active eq true"#));
+        assert!(!matches(r#"active eq false"#));
+        assert!(matches("name pr"));
+        assert!(!matches("externalId pr"));
+    }
+
+    #[test]
+    fn rejects_unsupported_filters() {
+        assert!(Filter::parse(r#"userName eq "alice" or userName eq "bob""#).is_none());
+        assert!(Filter::parse(r#"userName gt "a""#).is_none());
+        assert!(Filter::parse(r#"userName eq "unterminated"#).is_none());
+        assert!(Filter::parse("userName eq").is_none());
+    }
+
+    #[test]
+    fn parses_member_filter_paths() {
+        assert_eq!(
+            member_filter_id(r#"members[value eq "123"]"#).as_deref(),
+            Some("123")
+        );
+        assert_eq!(member_filter_id(r#"members[display eq "123"]"#), None);
+    }
+}
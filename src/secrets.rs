@@ -0,0 +1,211 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use zeroize::Zeroizing;
+
+use crate::aws;
+use crate::config;
+use crate::http_client;
+use crate::session;
+use crate::AppState;
+
+pub type Keys = Vec<(u8, Zeroizing<Vec<u8>>)>;
+pub type SigningKeys = Keys;
+
+pub struct SecretProvider {
+    config: config::SecretsConfig,
+    client: Option<http_client::Client>,
+}
+
+impl SecretProvider {
+    pub fn new(config: &config::SecretsConfig) -> io::Result<Self> {
+        let client = if config.vault.is_some() || config.aws.is_some() {
+            Some(http_client::Client::new(&config.ca_path)?)
+        } else {
+            None
+        };
+        Ok(Self {
+            config: config.clone(),
+            client,
+        })
+    }
+
+    pub async fn fetch(&self, source: &config::SecretSource) -> Result<Zeroizing<String>, String> {
+        match source {
+            config::SecretSource::File { path } => tokio::fs::read_to_string(path)
+                .await
+                .map(|value| Zeroizing::new(value.trim_end().to_string()))
+                .map_err(|err| format!("{}: {}", path.display(), err)),
+            config::SecretSource::Env { name } => std::env::var(name)
+                .map(Zeroizing::new)
+                .map_err(|_| format!("environment variable {} is not set", name)),
+            config::SecretSource::Vault { path, field } => self.fetch_vault(path, field).await,
+            config::SecretSource::AwsSecretsManager { secret_id } => {
+                let response = aws::json_request(
+                    self.client()?,
+                    self.aws()?,
+                    "secretsmanager",
+                    "secretsmanager.GetSecretValue",
+                    &serde_json::json!({ "SecretId": secret_id }),
+                )
+                .await?;
+                take_string(response, "/SecretString")
+                    .ok_or_else(|| format!("secret {} has no SecretString", secret_id))
+            }
+            config::SecretSource::AwsKms { ciphertext_base64 } => {
+                let response = aws::json_request(
+                    self.client()?,
+                    self.aws()?,
+                    "kms",
+                    "TrentService.Decrypt",
+                    &serde_json::json!({ "CiphertextBlob": ciphertext_base64 }),
+                )
+                .await?;
+                take_string(response, "/Plaintext")
+                    .ok_or_else(|| String::from("KMS Decrypt response has no Plaintext"))
+            }
+        }
+    }
+
+    async fn fetch_vault(&self, path: &str, field: &str) -> Result<Zeroizing<String>, String> {
+        let vault = self
+            .config
+            .vault
+            .as_ref()
+            .ok_or("secrets.vault is not configured")?;
+        let token = match &vault.token_path {
+            Some(token_path) => tokio::fs::read_to_string(token_path)
+                .await
+                .map(|token| Zeroizing::new(token.trim().to_string()))
+                .map_err(|err| format!("{}: {}", token_path.display(), err))?,
+            None => std::env::var(&vault.token_env)
+                .map(Zeroizing::new)
+                .map_err(|_| format!("environment variable {} is not set", vault.token_env))?,
+        };
+        let url = format!(
+            "{}/v1/{}",
+            vault.address.trim_end_matches('/'),
+            path.trim_start_matches('/')
+        );
+        let response = self
+            .client()?
+            .request("GET", &url, &[("X-Vault-Token", token.as_str())], &[])
+            .await
+            .map_err(|err| format!("vault {}: {}", path, err))?;
+        if response.status != 200 {
+            return Err(format!("vault {}: status {}", path, response.status));
+        }
+        let response: serde_json::Value = serde_json::from_slice(&response.body)
+            .map_err(|err| format!("vault {}: invalid response: {}", path, err))?;
+        // KV version 2 nests the secret under data.data, version 1 under data.
+        let kv2 = response["data"]["data"].is_object();
+        let pointer = if kv2 {
+            format!("/data/data/{}", field)
+        } else {
+            format!("/data/{}", field)
+        };
+        take_string(response, &pointer)
+            .ok_or_else(|| format!("vault {} has no field {}", path, field))
+    }
+
+    fn client(&self) -> Result<&http_client::Client, String> {
+        self.client
+            .as_ref()
+            .ok_or_else(|| String::from("no HTTP client configured"))
+    }
+
+    fn aws(&self) -> Result<&config::AwsConfig, String> {
+        self.config
+            .aws
+            .as_ref()
+            .ok_or_else(|| String::from("secrets.aws is not configured"))
+    }
+
+    pub async fn load_signing_keys(
+        &self,
+        sessions: &config::SessionsConfig,
+    ) -> Result<SigningKeys, String> {
+        self.load_keys("sessions.signing_keys", &sessions.signing_keys)
+            .await
+    }
+
+    pub async fn load_encryption_keys(
+        &self,
+        encryption: &config::EncryptionConfig,
+    ) -> Result<Keys, String> {
+        self.load_keys("encryption.keys", &encryption.keys).await
+    }
+
+    async fn load_keys(
+        &self,
+        setting: &str,
+        keys: &[config::SecretKeyConfig],
+    ) -> Result<Keys, String> {
+        let mut loaded = Vec::new();
+        for key in keys {
+            let secret = match (&key.secret_base64, &key.secret_source) {
+                (Some(secret_base64), _) => {
+                    config::SecretKeyConfig::decode_secret(setting, key.id, secret_base64)?
+                }
+                (None, Some(source)) => {
+                    let secret_base64 = self
+                        .fetch(source)
+                        .await
+                        .map_err(|err| format!("{} secret {}: {}", setting, key.id, err))?;
+                    config::SecretKeyConfig::decode_secret(setting, key.id, &secret_base64)?
+                }
+                (None, None) => unreachable!(),
+            };
+            loaded.push((key.id, secret));
+        }
+        Ok(loaded)
+    }
+}
+
+fn take_string(mut value: serde_json::Value, pointer: &str) -> Option<Zeroizing<String>> {
+    match value.pointer_mut(pointer)?.take() {
+        serde_json::Value::String(value) => Some(Zeroizing::new(value)),
+        _ => None,
+    }
+}
+
+pub fn spawn_refresh(
+    provider: SecretProvider,
+    state: Arc<AppState>,
+    mut signing_keys: SigningKeys,
+) {
+    let refresh_secs = state.config.secrets.refresh_secs;
+    let has_sources = state
+        .config
+        .sessions
+        .signing_keys
+        .iter()
+        .any(|key| key.secret_source.is_some());
+    if refresh_secs == 0 || !has_sources {
+        return;
+    }
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(refresh_secs)).await;
+            let refreshed = match provider.load_signing_keys(&state.config.sessions).await {
+                Ok(refreshed) => refreshed,
+                Err(err) => {
+                    println!("Failed to refresh secrets: {}", err);
+                    continue;
+                }
+            };
+            if refreshed == signing_keys {
+                continue;
+            }
+            let session_ids = session::SessionIdFormat::from_config(
+                &state.config.sessions,
+                refreshed.clone(),
+                &state.rng,
+            );
+            *state.session_ids.write().unwrap() = Arc::new(session_ids);
+            signing_keys = refreshed;
+            println!("Reloaded session signing keys");
+        }
+    });
+}
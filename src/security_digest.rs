@@ -0,0 +1,209 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::{self, NotificationChannel};
+use crate::email::Template;
+use crate::notify;
+use crate::time;
+use crate::AppState;
+
+// Past this, the oldest activity of the user is dropped.
+const MAX_ACTIVITY_PER_USER: usize = 200;
+
+pub enum Activity {
+    SignIn {
+        device_name: String,
+        ip: IpAddr,
+        country: Option<String>,
+    },
+    NewDevice {
+        device_name: String,
+    },
+    RememberMeToken {
+        device_name: String,
+    },
+}
+
+// Activity is kept in this instance's memory until the next digest, so it
+// is lost when the instance restarts, and each instance sends digests of
+// what it saw itself.
+#[derive(Default)]
+pub struct SecurityDigest {
+    enabled: bool,
+    activity: Mutex<HashMap<String, VecDeque<(u64, Activity)>>>,
+}
+
+impl SecurityDigest {
+    pub fn new(config: Option<&config::SecurityDigestConfig>) -> Self {
+        Self {
+            enabled: config.is_some(),
+            activity: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record(&self, user: &str, at: u64, activity: Activity) {
+        if !self.enabled {
+            return;
+        }
+        let mut users = self.activity.lock().unwrap();
+        let user_activity = users.entry(user.to_string()).or_default();
+        if user_activity.len() >= MAX_ACTIVITY_PER_USER {
+            user_activity.pop_front();
+        }
+        user_activity.push_back((at, activity));
+    }
+
+    fn take(&self) -> HashMap<String, VecDeque<(u64, Activity)>> {
+        std::mem::take(&mut *self.activity.lock().unwrap())
+    }
+}
+
+fn period_name(period: config::DigestPeriod) -> &'static str {
+    match period {
+        config::DigestPeriod::Weekly => "week",
+        config::DigestPeriod::Monthly => "month",
+    }
+}
+
+// The template details for one user's activity, one line per entry.
+fn details(
+    period: config::DigestPeriod,
+    activity: &VecDeque<(u64, Activity)>,
+) -> serde_json::Value {
+    let mut sign_ins = Vec::new();
+    let mut new_devices = Vec::new();
+    let mut remember_me_tokens = Vec::new();
+    for (at, activity) in activity {
+        let at = time::rfc3339(*at);
+        match activity {
+            Activity::SignIn {
+                device_name,
+                ip,
+                country,
+            } => sign_ins.push(match country {
+                Some(country) => format!("{} {}, {} ({})", at, device_name, ip, country),
+                None => format!("{} {}, {}", at, device_name, ip),
+            }),
+            Activity::NewDevice { device_name } => {
+                new_devices.push(format!("{} {}", at, device_name))
+            }
+            Activity::RememberMeToken { device_name } => {
+                remember_me_tokens.push(format!("{} {}", at, device_name))
+            }
+        }
+    }
+    let list = |lines: Vec<String>| {
+        if lines.is_empty() {
+            String::from("None")
+        } else {
+            lines.join("\n")
+        }
+    };
+    serde_json::json!({
+        "period": period_name(period),
+        "sign_in_count": sign_ins.len(),
+        "sign_ins": list(sign_ins),
+        "new_devices": list(new_devices),
+        "remember_me_tokens": list(remember_me_tokens),
+    })
+}
+
+// Every period, emails the users who turned the digest on a summary of what
+// happened since the last one. Users without activity get nothing.
+pub fn spawn(state: Arc<AppState>) {
+    let Some(config) = state.config.security_digest.clone() else {
+        return;
+    };
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(config.period.secs())).await;
+            let activity = state.security_digest.take();
+            let mut sent = 0;
+            for (user, activity) in &activity {
+                let opted_in = state
+                    .users
+                    .read()
+                    .await
+                    .user_by_name(user)
+                    .is_some_and(|user| {
+                        notify::chosen_channels(&state.config, user, Template::SecurityDigest)
+                            .contains(&NotificationChannel::Email)
+                    });
+                if !opted_in {
+                    continue;
+                }
+                let notification = notify::Notification {
+                    event: Template::SecurityDigest,
+                    user: user.clone(),
+                    details: details(config.period, activity),
+                };
+                notify::send(&state, &[NotificationChannel::Email], notification).await;
+                sent += 1;
+            }
+            if sent > 0 {
+                println!("Sending security digests to {} users", sent);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_activity_by_kind() {
+        let digest = SecurityDigest::new(Some(&config::SecurityDigestConfig::default()));
+        let device_name = || String::from("Firefox on Linux");
+        digest.record(
+            "zed",
+            0,
+            Activity::SignIn {
+                device_name: device_name(),
+                ip: "192.0.2.1".parse().unwrap(),
+                country: Some(String::from("DE")),
+            },
+        );
+        digest.record(
+            "zed",
+            60,
+            Activity::NewDevice {
+                device_name: device_name(),
+            },
+        );
+        for _ in 1..MAX_ACTIVITY_PER_USER {
+            digest.record(
+                "zed",
+                120,
+                Activity::SignIn {
+                    device_name: device_name(),
+                    ip: "192.0.2.2".parse().unwrap(),
+                    country: None,
+                },
+            );
+        }
+        let activity = digest.take();
+        assert!(digest.take().is_empty());
+        let details = details(config::DigestPeriod::Monthly, &activity["zed"]);
+        assert_eq!(details["period"], "month");
+        assert_eq!(details["sign_in_count"], MAX_ACTIVITY_PER_USER - 1);
+        assert!(!details["sign_ins"].as_str().unwrap().contains("192.0.2.1"));
+        assert_eq!(
+            details["new_devices"],
+            "1970-01-01T00:01:00Z Firefox on Linux"
+        );
+        assert_eq!(details["remember_me_tokens"], "None");
+
+        let disabled = SecurityDigest::default();
+        disabled.record(
+            "zed",
+            0,
+            Activity::NewDevice {
+                device_name: device_name(),
+            },
+        );
+        assert!(disabled.take().is_empty());
+    }
+}
@@ -0,0 +1,73 @@
+use std::sync::Arc;
+
+use crate::config;
+
+pub struct SecurityHeaders {
+    all: Vec<(http::HeaderName, http::HeaderValue)>,
+    api: Vec<(http::HeaderName, http::HeaderValue)>,
+}
+
+impl SecurityHeaders {
+    pub fn from_config(config: &config::SecurityHeadersConfig) -> Result<Self, String> {
+        let mut all = Vec::new();
+        let standard = [
+            (
+                http::header::STRICT_TRANSPORT_SECURITY,
+                &config.strict_transport_security,
+            ),
+            (
+                http::header::X_CONTENT_TYPE_OPTIONS,
+                &config.content_type_options,
+            ),
+            (http::header::X_FRAME_OPTIONS, &config.frame_options),
+            (
+                http::header::CONTENT_SECURITY_POLICY,
+                &config.content_security_policy,
+            ),
+            (http::header::REFERRER_POLICY, &config.referrer_policy),
+        ];
+        for (name, value) in standard {
+            if let Some(value) = value {
+                let value = parse_value(name.as_str(), value)?;
+                all.push((name, value));
+            }
+        }
+        for (name, value) in &config.extra {
+            let name = http::HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("security_headers.extra: invalid header name {}", name))?;
+            let value = parse_value(name.as_str(), value)?;
+            all.push((name, value));
+        }
+
+        let mut api = Vec::new();
+        if let Some(value) = &config.api_cache_control {
+            api.push((
+                http::header::CACHE_CONTROL,
+                parse_value("Cache-Control", value)?,
+            ));
+        }
+        Ok(Self { all, api })
+    }
+}
+
+fn parse_value(name: &str, value: &str) -> Result<http::HeaderValue, String> {
+    http::HeaderValue::from_str(value)
+        .map_err(|_| format!("security_headers: invalid value for {}", name))
+}
+
+pub async fn layer(
+    axum::extract::State(headers): axum::extract::State<Arc<SecurityHeaders>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let is_api = request.uri().path().starts_with("/api/");
+    let mut response = next.run(request).await;
+    let response_headers = response.headers_mut();
+    let api_headers = if is_api { &headers.api[..] } else { &[] };
+    for (name, value) in headers.all.iter().chain(api_headers) {
+        if !response_headers.contains_key(name) {
+            response_headers.insert(name.clone(), value.clone());
+        }
+    }
+    response
+}
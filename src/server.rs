@@ -0,0 +1,170 @@
+use std::convert::Infallible;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+
+use crate::client_cert;
+use crate::config;
+
+pub fn tls_acceptor(tls: &config::TlsConfig) -> io::Result<tokio_rustls::TlsAcceptor> {
+    let certs = tokio_rustls::rustls::pki_types::CertificateDer::pem_file_iter(&tls.cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| io::Error::other(format!("{}: {}", tls.cert_path.display(), err)))?;
+    let key = tokio_rustls::rustls::pki_types::PrivateKeyDer::from_pem_file(&tls.key_path)
+        .map_err(|err| io::Error::other(format!("{}: {}", tls.key_path.display(), err)))?;
+
+    let builder = tokio_rustls::rustls::ServerConfig::builder();
+    let builder = match &tls.client_auth {
+        Some(client_auth) => builder.with_client_cert_verifier(client_cert::verifier(client_auth)?),
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_config = builder
+        .with_single_cert(certs, key)
+        .map_err(io::Error::other)?;
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(tokio_rustls::TlsAcceptor::from(Arc::new(server_config)))
+}
+
+pub fn limit_route<S>(
+    path: &str,
+    method_router: axum::routing::MethodRouter<S>,
+    limits: &config::LimitsConfig,
+) -> axum::routing::MethodRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    method_router.layer::<_, Infallible>(
+        tower::ServiceBuilder::new()
+            .layer(tower_http::limit::RequestBodyLimitLayer::new(
+                limits.route_max_body_bytes(path),
+            ))
+            .layer(tower_http::timeout::TimeoutLayer::new(Duration::from_secs(
+                limits.route_timeout_secs(path),
+            )))
+            .layer(axum::extract::DefaultBodyLimit::disable()),
+    )
+}
+
+pub struct LoadShedding {
+    in_flight: AtomicUsize,
+    max_in_flight: usize,
+    retry_after: http::HeaderValue,
+}
+
+impl LoadShedding {
+    pub fn new(max_in_flight: usize, retry_after_secs: u64) -> Self {
+        Self {
+            in_flight: AtomicUsize::new(0),
+            max_in_flight,
+            retry_after: http::HeaderValue::from(retry_after_secs),
+        }
+    }
+}
+
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub async fn shed_load(
+    axum::extract::State(shedding): axum::extract::State<Arc<LoadShedding>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let in_flight = InFlight(&shedding.in_flight);
+    if shedding.in_flight.fetch_add(1, Ordering::Relaxed) >= shedding.max_in_flight {
+        drop(in_flight);
+        let mut response = crate::OVERLOADED.response();
+        response
+            .headers_mut()
+            .insert(http::header::RETRY_AFTER, shedding.retry_after.clone());
+        return response;
+    }
+    let response = next.run(request).await;
+    drop(in_flight);
+    response
+}
+
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    app: axum::Router,
+    tls: Option<tokio_rustls::TlsAcceptor>,
+    limits: config::LimitsConfig,
+) {
+    let connections = limits
+        .max_connections
+        .map(|max| Arc::new(tokio::sync::Semaphore::new(max)));
+    loop {
+        // Waiting for a free slot before accepting leaves further clients in
+        // the kernel's backlog instead of piling up tasks here.
+        let permit = match &connections {
+            Some(connections) => Some(connections.clone().acquire_owned().await.unwrap()),
+            None => None,
+        };
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                println!("Failed to accept connection: {}", err);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let app = app.clone();
+        let tls = tls.clone();
+        let limits = limits.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            match tls {
+                Some(acceptor) => {
+                    let handshake = tokio::time::timeout(
+                        Duration::from_secs(limits.header_read_timeout_secs),
+                        acceptor.accept(stream),
+                    );
+                    if let Ok(Ok(stream)) = handshake.await {
+                        let certificate =
+                            client_cert::ClientCertificate::from_connection(stream.get_ref().1);
+                        serve_connection(stream, remote_addr, certificate, app, &limits).await;
+                    }
+                }
+                None => serve_connection(stream, remote_addr, None, app, &limits).await,
+            }
+        });
+    }
+}
+
+async fn serve_connection<IO>(
+    io: IO,
+    remote_addr: SocketAddr,
+    certificate: Option<client_cert::ClientCertificate>,
+    app: axum::Router,
+    limits: &config::LimitsConfig,
+) where
+    IO: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let app = tower::ServiceExt::map_request(app, move |mut request: axum::extract::Request<_>| {
+        request
+            .extensions_mut()
+            .insert(axum::extract::ConnectInfo(remote_addr));
+        if let Some(certificate) = &certificate {
+            request.extensions_mut().insert(certificate.clone());
+        }
+        request
+    });
+    let service = hyper_util::service::TowerToHyperService::new(app);
+    let _ = hyper::server::conn::http1::Builder::new()
+        .timer(hyper_util::rt::TokioTimer::new())
+        .header_read_timeout(Duration::from_secs(limits.header_read_timeout_secs))
+        .max_buf_size(limits.max_header_bytes)
+        .max_headers(limits.max_headers)
+        .serve_connection(hyper_util::rt::TokioIo::new(io), service)
+        .with_upgrades()
+        .await;
+}
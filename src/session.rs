@@ -0,0 +1,1529 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use base64::Engine;
+use subtle::ConstantTimeEq;
+use tokio::sync::RwLock as TokioRwLock;
+use zeroize::Zeroize;
+
+use crate::config;
+use crate::geoip;
+use crate::memcached;
+use crate::rng;
+use crate::secrets;
+
+pub const MIN_SESSION_ID_BYTES: usize = 16;
+pub const MAX_SESSION_ID_BYTES: usize = 64;
+pub const SIGNATURE_BYTES: usize = 16;
+pub const MAX_DESCRIPTION_CHARS: usize = 100;
+// Sliding deadlines only move by a tenth of the idle timeout or more, up to
+// this, so that every request doesn't have to save the session.
+const MAX_TOUCH_GRANULARITY_SECS: u64 = 60;
+// last_seen is only kept this exact, for the same reason.
+const LAST_SEEN_GRANULARITY_SECS: u64 = 60;
+// What a stored session costs beyond its strings and data: the Session and
+// its lock, the id, the map and deadline entries and the event channel.
+const STORED_SESSION_OVERHEAD_BYTES: usize = std::mem::size_of::<Session>() + 384;
+
+pub fn valid_description(description: &str) -> bool {
+    !description.is_empty()
+        && description.chars().count() <= MAX_DESCRIPTION_CHARS
+        && !description.chars().any(char::is_control)
+}
+
+#[derive(serde::Serialize)]
+pub struct Session {
+    pub user: Option<String>,
+    pub description: String,
+    pub authenticated: bool,
+    #[serde(skip)]
+    pub authenticated_at: Option<u64>,
+    pub expires_at: Option<u64>,
+    #[serde(skip)]
+    idle_timeout_secs: Option<u64>,
+    #[serde(skip)]
+    absolute_expires_at: Option<u64>,
+    #[serde(skip)]
+    pub created: u64,
+    #[serde(skip)]
+    pub last_seen: u64,
+    #[serde(skip)]
+    pub ip: IpAddr,
+    #[serde(skip)]
+    pub user_agent: Option<String>,
+    // Where the user signed in from, when GeoIP databases are configured.
+    #[serde(skip)]
+    pub location: geoip::Location,
+    #[serde(skip)]
+    pub data: BTreeMap<String, serde_json::Value>,
+    #[serde(skip)]
+    pub version: u64,
+    #[serde(skip)]
+    pub events: tokio::sync::broadcast::Sender<SessionEvent>,
+}
+
+impl Session {
+    pub fn new(ip: IpAddr, user_agent: Option<String>, now: u64) -> Self {
+        Self {
+            user: None,
+            description: String::from("Some session..."),
+            authenticated: false,
+            authenticated_at: None,
+            expires_at: None,
+            idle_timeout_secs: None,
+            absolute_expires_at: None,
+            created: now,
+            last_seen: now,
+            ip,
+            user_agent,
+            location: geoip::Location::default(),
+            data: BTreeMap::new(),
+            version: 0,
+            events: tokio::sync::broadcast::channel(16).0,
+        }
+    }
+
+    pub fn publish(&mut self, event: SessionEvent) {
+        self.version += 1;
+        let _ = self.events.send(event);
+    }
+
+    // Moves the session's state into a new session with its own event
+    // channel, so listeners on the old id don't follow it to the new one.
+    pub fn rotate(&mut self) -> Session {
+        Session {
+            user: self.user.take(),
+            description: std::mem::take(&mut self.description),
+            authenticated: self.authenticated,
+            authenticated_at: self.authenticated_at,
+            expires_at: self.expires_at,
+            idle_timeout_secs: self.idle_timeout_secs,
+            absolute_expires_at: self.absolute_expires_at,
+            created: self.created,
+            last_seen: self.last_seen,
+            ip: self.ip,
+            user_agent: self.user_agent.clone(),
+            location: self.location.clone(),
+            data: std::mem::take(&mut self.data),
+            version: 0,
+            events: tokio::sync::broadcast::channel(16).0,
+        }
+    }
+
+    pub fn set_expiry(&mut self, policy: config::ExpiryPolicy, now: u64) {
+        self.idle_timeout_secs = policy.idle_timeout_secs;
+        self.absolute_expires_at = policy.absolute_lifetime_secs.map(|secs| now + secs);
+        self.expires_at = self.deadline(now);
+    }
+
+    pub fn is_sliding(&self) -> bool {
+        self.idle_timeout_secs.is_some()
+    }
+
+    fn deadline(&self, now: u64) -> Option<u64> {
+        let idle_expires_at = self.idle_timeout_secs.map(|secs| now + secs);
+        match (idle_expires_at, self.absolute_expires_at) {
+            (Some(idle), Some(absolute)) => Some(idle.min(absolute)),
+            (idle, absolute) => idle.or(absolute),
+        }
+    }
+
+    // Returns whether the deadline moved, in which case the session has to
+    // be persisted. A moved deadline is a new version, so ETags change and
+    // long polls wake up.
+    pub fn touch(&mut self, now: u64) -> bool {
+        let moved = self.deadline_moves(now);
+        if moved {
+            self.expires_at = self.deadline(now);
+            self.publish(SessionEvent::Updated);
+        }
+        moved
+    }
+
+    fn deadline_moves(&self, now: u64) -> bool {
+        let granularity = self
+            .idle_timeout_secs
+            .map_or(0, |secs| (secs / 10).min(MAX_TOUCH_GRANULARITY_SECS));
+        match (self.expires_at, self.deadline(now)) {
+            (Some(old), Some(new)) => new < old || new - old >= granularity.max(1),
+            (old, new) => old != new,
+        }
+    }
+
+    // Whether a lookup at now has anything to write.
+    fn needs_touch(&self, now: u64) -> bool {
+        (self.is_sliding() && self.deadline_moves(now))
+            || now >= self.last_seen + LAST_SEEN_GRANULARITY_SECS
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub fn data_bytes(&self) -> usize {
+        self.data
+            .iter()
+            .map(|(key, value)| key.len() + value.to_string().len())
+            .sum()
+    }
+
+    // Only an estimate, for sessions.memory_budget_bytes.
+    pub fn approx_bytes(&self) -> usize {
+        STORED_SESSION_OVERHEAD_BYTES
+            + self.description.len()
+            + self.user.as_ref().map_or(0, String::len)
+            + self.user_agent.as_ref().map_or(0, String::len)
+            + self.location.country.as_ref().map_or(0, String::len)
+            + self.data_bytes()
+    }
+
+    pub fn etag(&self) -> String {
+        format!("W/\"{}\"", self.version)
+    }
+
+    // Takes the state another instance saved, keeping this session's
+    // listeners.
+    pub fn replace(&mut self, saved: SavedSession) {
+        let events = self.events.clone();
+        *self = Session {
+            events,
+            ..saved.into()
+        };
+    }
+}
+
+// A session as sessions.persistence saves it, without its event channel.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SavedSession {
+    user: Option<String>,
+    description: String,
+    authenticated: bool,
+    authenticated_at: Option<u64>,
+    expires_at: Option<u64>,
+    idle_timeout_secs: Option<u64>,
+    absolute_expires_at: Option<u64>,
+    created: u64,
+    last_seen: u64,
+    ip: IpAddr,
+    user_agent: Option<String>,
+    location: geoip::Location,
+    data: BTreeMap<String, serde_json::Value>,
+    // Kept so ETags clients hold still tell versions apart.
+    version: u64,
+}
+
+impl SavedSession {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub fn expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+}
+
+impl From<&Session> for SavedSession {
+    fn from(session: &Session) -> Self {
+        Self {
+            user: session.user.clone(),
+            description: session.description.clone(),
+            authenticated: session.authenticated,
+            authenticated_at: session.authenticated_at,
+            expires_at: session.expires_at,
+            idle_timeout_secs: session.idle_timeout_secs,
+            absolute_expires_at: session.absolute_expires_at,
+            created: session.created,
+            last_seen: session.last_seen,
+            ip: session.ip,
+            user_agent: session.user_agent.clone(),
+            location: session.location.clone(),
+            data: session.data.clone(),
+            version: session.version,
+        }
+    }
+}
+
+impl From<SavedSession> for Session {
+    fn from(saved: SavedSession) -> Self {
+        Self {
+            user: saved.user,
+            description: saved.description,
+            authenticated: saved.authenticated,
+            authenticated_at: saved.authenticated_at,
+            expires_at: saved.expires_at,
+            idle_timeout_secs: saved.idle_timeout_secs,
+            absolute_expires_at: saved.absolute_expires_at,
+            created: saved.created,
+            last_seen: saved.last_seen,
+            ip: saved.ip,
+            user_agent: saved.user_agent,
+            location: saved.location,
+            data: saved.data,
+            version: saved.version,
+            events: tokio::sync::broadcast::channel(16).0,
+        }
+    }
+}
+
+// Changes to stored sessions, for sessions.persistence or
+// sessions.memcached to write down.
+pub enum Change {
+    Put(SessionId, Box<SavedSession>),
+    // A stored session changed in place, which isn't stored again if
+    // another instance removed it meanwhile.
+    Update(SessionId, Box<SavedSession>),
+    Remove(SessionId),
+    // Asks for a snapshot right away, which is answered with the number of
+    // sessions in it.
+    Compact(tokio::sync::oneshot::Sender<Result<usize, String>>),
+}
+
+#[derive(Clone, serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SessionEvent {
+    Authenticated { user: String },
+    Updated,
+    ExpiringSoon { expires_at: u64 },
+    Revoked,
+}
+
+#[derive(Clone)]
+pub struct SessionId {
+    id: Vec<u8>,
+}
+
+impl SessionId {
+    fn lookup_key(&self) -> LookupKey {
+        let digest = ring::digest::digest(&ring::digest::SHA256, &self.id);
+        LookupKey(digest.as_ref().try_into().unwrap())
+    }
+
+    // For ids read back from sessions.persistence's files, which were
+    // verified when they were first stored.
+    pub fn restore(encoded: &str) -> Option<Self> {
+        let id = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(encoded)
+            .ok()?;
+        (!id.is_empty()).then_some(Self { id })
+    }
+
+    // Tells sessions apart in list cursors without giving the id away.
+    pub fn list_key(&self) -> String {
+        crate::jwt::base64url_encode(&self.lookup_key().0[..12])
+    }
+
+    // Names the session in sessions.memcached without giving the id away.
+    pub fn storage_key(&self) -> String {
+        crate::jwt::base64url_encode(&self.lookup_key().0)
+    }
+}
+
+impl Drop for SessionId {
+    fn drop(&mut self) {
+        self.id.zeroize();
+    }
+}
+
+impl PartialEq for SessionId {
+    fn eq(&self, other: &Self) -> bool {
+        self.id.ct_eq(&other.id).into()
+    }
+}
+
+impl Eq for SessionId {}
+
+impl std::fmt::Debug for SessionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SessionId(..)")
+    }
+}
+
+impl From<&SessionId> for String {
+    fn from(value: &SessionId) -> Self {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(&value.id)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    Malformed,
+    InvalidSignature,
+}
+
+struct SigningKey {
+    id: u8,
+    key: ring::hmac::Key,
+}
+
+pub struct SessionIdFormat {
+    id_bytes: usize,
+    legacy_id_bytes: Vec<usize>,
+    signing_keys: Vec<SigningKey>,
+    active_signing_key: u8,
+}
+
+impl SessionIdFormat {
+    pub fn new(
+        id_bytes: usize,
+        legacy_id_bytes: Vec<usize>,
+        signing_keys: Vec<(u8, Vec<u8>)>,
+        active_signing_key: u8,
+    ) -> Self {
+        Self {
+            id_bytes,
+            legacy_id_bytes,
+            signing_keys: signing_keys
+                .into_iter()
+                .map(|(id, mut secret)| {
+                    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, &secret);
+                    secret.zeroize();
+                    SigningKey { id, key }
+                })
+                .collect(),
+            active_signing_key,
+        }
+    }
+
+    pub fn from_config(
+        config: &config::SessionsConfig,
+        signing_keys: secrets::SigningKeys,
+        rng: &dyn rng::Rng,
+    ) -> Self {
+        if signing_keys.is_empty() {
+            let mut secret = vec![0u8; 32];
+            rng.fill(&mut secret);
+            return Self::new(
+                config.id_bytes,
+                config.legacy_id_bytes.clone(),
+                vec![(0, secret)],
+                0,
+            );
+        }
+        let signing_keys = signing_keys
+            .into_iter()
+            .map(|(id, secret)| (id, secret.to_vec()))
+            .collect();
+        Self::new(
+            config.id_bytes,
+            config.legacy_id_bytes.clone(),
+            signing_keys,
+            config.active_signing_key(),
+        )
+    }
+
+    fn signed_len(&self) -> usize {
+        1 + self.id_bytes + SIGNATURE_BYTES
+    }
+
+    fn signature(key: &SigningKey, payload: &[u8]) -> ring::hmac::Tag {
+        ring::hmac::sign(&key.key, payload)
+    }
+
+    pub fn generate(&self, rng: &dyn rng::Rng) -> SessionId {
+        let key = self
+            .signing_keys
+            .iter()
+            .find(|key| key.id == self.active_signing_key)
+            .unwrap();
+        let mut id = vec![0u8; 1 + self.id_bytes];
+        id[0] = key.id;
+        rng.fill(&mut id[1..]);
+        let signature = Self::signature(key, &id);
+        id.extend_from_slice(&signature.as_ref()[..SIGNATURE_BYTES]);
+        SessionId { id }
+    }
+
+    pub fn parse(&self, value: &str) -> Result<SessionId, ParseError> {
+        if value.len() == encoded_len(self.signed_len()) {
+            let id = decode_exact(value, self.signed_len())?;
+            let (payload, signature) = id.split_at(1 + self.id_bytes);
+            let key = self
+                .signing_keys
+                .iter()
+                .find(|key| key.id == payload[0])
+                .ok_or(ParseError::InvalidSignature)?;
+            let expected = Self::signature(key, payload);
+            if bool::from(expected.as_ref()[..SIGNATURE_BYTES].ct_eq(signature)) {
+                return Ok(SessionId { id });
+            }
+            return Err(ParseError::InvalidSignature);
+        }
+        let id_bytes = self
+            .legacy_id_bytes
+            .iter()
+            .copied()
+            .find(|&id_bytes| encoded_len(id_bytes) == value.len())
+            .ok_or(ParseError::Malformed)?;
+        Ok(SessionId {
+            id: decode_exact(value, id_bytes)?,
+        })
+    }
+}
+
+fn decode_exact(value: &str, len: usize) -> Result<Vec<u8>, ParseError> {
+    let mut id = vec![0u8; len];
+    match base64::engine::general_purpose::URL_SAFE_NO_PAD.decode_slice(value, &mut id) {
+        Ok(decoded) if decoded == len => Ok(id),
+        _ => {
+            id.zeroize();
+            Err(ParseError::Malformed)
+        }
+    }
+}
+
+fn encoded_len(id_bytes: usize) -> usize {
+    (id_bytes * 4).div_ceil(3)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct LookupKey([u8; 32]);
+
+struct StoredSession {
+    id: SessionId,
+    session: Arc<TokioRwLock<Session>>,
+    // What was last added to the store's memory_bytes for this session.
+    bytes: usize,
+    // Who the session is filed under in the store's users index.
+    user: Option<String>,
+}
+
+// A session in the store's users index.
+struct Filed {
+    id: SessionId,
+    // When it signed in, None unless it's authenticated.
+    since: Option<u64>,
+    expires_at: Option<u64>,
+}
+
+impl Filed {
+    fn of(id: &SessionId, session: &Session) -> Option<(String, Filed)> {
+        let user = session.user.clone()?;
+        let since = session
+            .authenticated
+            .then(|| session.authenticated_at.unwrap_or(session.created));
+        Some((
+            user,
+            Filed {
+                id: id.clone(),
+                since,
+                expires_at: session.expires_at,
+            },
+        ))
+    }
+
+    fn is_active(&self, now: u64) -> bool {
+        self.since.is_some() && self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+type UsersIndex = HashMap<String, HashMap<LookupKey, Filed>>;
+
+// Sessions are spread over shards by lookup key, which is already a hash, so
+// requests for different sessions rarely contend. Shard locks are only held
+// for map operations and never across an await.
+const SHARDS: usize = 64;
+
+type Shard = std::sync::RwLock<HashMap<LookupKey, StoredSession>>;
+
+pub struct SessionStore {
+    shards: Box<[Shard]>,
+    // Session ids filed under the deadline they had when last filed. Sweeps
+    // only look at due deadlines, and sessions that were touched since are
+    // filed again under their new deadline, so a sweep costs O(due) rather
+    // than O(sessions). Stale entries of removed sessions are dropped when
+    // they come due.
+    deadlines: std::sync::Mutex<BTreeMap<u64, Vec<SessionId>>>,
+    // The same, for sessions.expiry.warn_before_secs: each deadline is
+    // warned about once, sessions touched since are filed again.
+    warnings: std::sync::Mutex<BTreeMap<u64, Vec<SessionId>>>,
+    warn_before: std::sync::OnceLock<u64>,
+    memory_bytes: AtomicUsize,
+    // The ids of each user's sessions, so that finding them doesn't go
+    // through every session. Only changed under the shard lock of the
+    // session, which is always taken first.
+    users: std::sync::Mutex<UsersIndex>,
+    // Set while sessions.persistence or sessions.memcached is on. Changes
+    // are sent under the shard lock, so they arrive in the order they were
+    // made.
+    changes: std::sync::OnceLock<std::sync::mpsc::Sender<Change>>,
+    shared: std::sync::OnceLock<Shared>,
+}
+
+// Set while sessions are kept in memcached, which other instances change
+// too. Counts the changes of each session that aren't written there yet:
+// until they are, this instance's copy is the latest.
+struct Shared {
+    memcached: Arc<memcached::Memcached>,
+    unwritten: std::sync::Mutex<HashMap<LookupKey, usize>>,
+}
+
+impl Default for SessionStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SessionStore {
+    pub fn new() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Shard::default()).collect(),
+            deadlines: std::sync::Mutex::new(BTreeMap::new()),
+            warnings: std::sync::Mutex::new(BTreeMap::new()),
+            warn_before: std::sync::OnceLock::new(),
+            memory_bytes: AtomicUsize::new(0),
+            users: std::sync::Mutex::new(HashMap::new()),
+            changes: std::sync::OnceLock::new(),
+            shared: std::sync::OnceLock::new(),
+        }
+    }
+
+    pub fn warn_before_expiry(&self, secs: u64) {
+        let _ = self.warn_before.set(secs);
+    }
+
+    pub fn record_changes(&self, changes: std::sync::mpsc::Sender<Change>) {
+        let _ = self.changes.set(changes);
+    }
+
+    pub(crate) fn share(
+        &self,
+        memcached: Arc<memcached::Memcached>,
+        changes: std::sync::mpsc::Sender<Change>,
+    ) {
+        let _ = self.shared.set(Shared {
+            memcached,
+            unwritten: std::sync::Mutex::new(HashMap::new()),
+        });
+        self.record_changes(changes);
+    }
+
+    pub(crate) fn memcached(&self) -> Option<&Arc<memcached::Memcached>> {
+        self.shared.get().map(|shared| &shared.memcached)
+    }
+
+    fn record(&self, change: Option<Change>) {
+        if let (Some(changes), Some(change)) = (self.changes.get(), change) {
+            if let Some(shared) = self.shared.get() {
+                if let Change::Put(id, _) | Change::Update(id, _) | Change::Remove(id) = &change {
+                    *shared
+                        .unwritten
+                        .lock()
+                        .unwrap()
+                        .entry(id.lookup_key())
+                        .or_default() += 1;
+                }
+            }
+            let _ = changes.send(change);
+        }
+    }
+
+    // Has to be called once changes of a session are written to memcached.
+    pub(crate) fn written(&self, id: &SessionId, changes: usize) {
+        let Some(shared) = self.shared.get() else {
+            return;
+        };
+        let mut unwritten = shared.unwritten.lock().unwrap();
+        if let std::collections::hash_map::Entry::Occupied(mut entry) =
+            unwritten.entry(id.lookup_key())
+        {
+            if *entry.get() <= changes {
+                entry.remove();
+            } else {
+                *entry.get_mut() -= changes;
+            }
+        }
+    }
+
+    fn is_unwritten(&self, id: &SessionId) -> bool {
+        self.shared.get().is_some_and(|shared| {
+            shared
+                .unwritten
+                .lock()
+                .unwrap()
+                .contains_key(&id.lookup_key())
+        })
+    }
+
+    // None unless sessions.persistence is on.
+    pub fn request_compaction(
+        &self,
+    ) -> Option<tokio::sync::oneshot::Receiver<Result<usize, String>>> {
+        if self.shared.get().is_some() {
+            return None;
+        }
+        let changes = self.changes.get()?;
+        let (reply, receiver) = tokio::sync::oneshot::channel();
+        let _ = changes.send(Change::Compact(reply));
+        Some(receiver)
+    }
+
+    // Has to be called after changing a stored session in place, while
+    // still holding its lock.
+    pub fn persist(&self, id: &SessionId, session: &Session) {
+        self.refile(id, session);
+        if self.changes.get().is_none() {
+            return;
+        }
+        let key = id.lookup_key();
+        let shard = self.shard(&key).read().unwrap();
+        if shard.get(&key).is_some_and(|stored| stored.id == *id) {
+            self.record(Some(Change::Update(id.clone(), Box::new(session.into()))));
+        }
+    }
+
+    // Keeps the users index up to date with a stored session that changed
+    // in place.
+    fn refile(&self, id: &SessionId, session: &Session) {
+        let key = id.lookup_key();
+        let mut shard = self.shard(&key).write().unwrap();
+        let Some(stored) = shard.get_mut(&key).filter(|stored| stored.id == *id) else {
+            return;
+        };
+        if stored.user.is_none() && session.user.is_none() {
+            return;
+        }
+        self.file_user(key, stored, Filed::of(id, session));
+    }
+
+    // Files a stored session under the user it has now, unfiling it from
+    // the one it had. Called under its shard lock.
+    fn file_user(
+        &self,
+        key: LookupKey,
+        stored: &mut StoredSession,
+        filed: Option<(String, Filed)>,
+    ) {
+        let mut users = self.users.lock().unwrap();
+        if let Some(before) = stored.user.take() {
+            unfile(&mut users, &before, &key);
+        }
+        if let Some((user, filed)) = filed {
+            users.entry(user.clone()).or_default().insert(key, filed);
+            stored.user = Some(user);
+        }
+    }
+
+    // Files a session that is about to sign in as `user` under them, if
+    // they have fewer than `max` active sessions. Otherwise, with evict,
+    // the sessions that signed in first are unfiled to make room and
+    // returned for the caller to remove; without it, None is returned and
+    // nothing is filed. Counting and filing under one lock keeps concurrent
+    // sign-ins from all getting the last place.
+    pub fn admit(
+        &self,
+        user: &str,
+        id: &SessionId,
+        max: usize,
+        evict: bool,
+        now: u64,
+    ) -> Option<Vec<SessionId>> {
+        let key = id.lookup_key();
+        let mut shard = self.shard(&key).write().unwrap();
+        let mut users = self.users.lock().unwrap();
+        let filed = users.entry(user.to_string()).or_default();
+        let mut active: Vec<_> = filed
+            .iter()
+            .filter(|(other, filed)| **other != key && filed.is_active(now))
+            .map(|(other, filed)| (filed.since, *other))
+            .collect();
+        let mut evicted = Vec::new();
+        if active.len() >= max {
+            if !evict {
+                return None;
+            }
+            active.sort_by_key(|(since, _)| *since);
+            for (_, other) in active.iter().take(active.len() + 1 - max) {
+                evicted.extend(filed.remove(other).map(|filed| filed.id));
+            }
+        }
+        filed.insert(
+            key,
+            Filed {
+                id: id.clone(),
+                since: Some(now),
+                expires_at: None,
+            },
+        );
+        // Sessions that are stored already are filed for good; new ones
+        // are filed again when they are inserted.
+        if let Some(stored) = shard.get_mut(&key).filter(|stored| stored.id == *id) {
+            if let Some(before) = stored.user.replace(user.to_string()) {
+                if before != user {
+                    unfile(&mut users, &before, &key);
+                }
+            }
+        }
+        Some(evicted)
+    }
+
+    // What a lookup does to a session besides checking its binding: slides
+    // its deadline and records when it was last seen. Only takes the write
+    // lock when one of them has to move, so concurrent reads of a session
+    // don't queue behind each other. Returns false for expired sessions.
+    pub async fn access(&self, id: &SessionId, session: &TokioRwLock<Session>, now: u64) -> bool {
+        {
+            let session = session.read().await;
+            if session.is_expired(now) {
+                return false;
+            }
+            if !session.needs_touch(now) {
+                return true;
+            }
+        }
+        let mut session = session.write().await;
+        if session.is_expired(now) {
+            return false;
+        }
+        session.last_seen = now;
+        // Saved so that other instances and restarts see the new deadline,
+        // and memcached keeps the session that much longer.
+        if session.is_sliding() && session.touch(now) {
+            self.persist(id, &session);
+        }
+        true
+    }
+
+    fn shard(&self, key: &LookupKey) -> &Shard {
+        &self.shards[key.0[0] as usize % SHARDS]
+    }
+
+    // Has to be called when a stored session gets an earlier deadline than it
+    // had, or one where it had none. Later deadlines are picked up by sweeps.
+    pub fn schedule_expiry(&self, id: &SessionId, deadline: u64) {
+        file(&self.deadlines, id, deadline);
+        if self.warn_before.get().is_some() {
+            file(&self.warnings, id, deadline);
+        }
+    }
+
+    pub fn insert(&self, id: SessionId, session: Session) -> Arc<TokioRwLock<Session>> {
+        self.insert_recorded(id, session, true)
+    }
+
+    // Inserts a session memcached has, which needn't be written back.
+    fn cache(&self, id: SessionId, session: Session) -> Arc<TokioRwLock<Session>> {
+        self.insert_recorded(id, session, false)
+    }
+
+    fn insert_recorded(
+        &self,
+        id: SessionId,
+        session: Session,
+        record: bool,
+    ) -> Arc<TokioRwLock<Session>> {
+        if let Some(deadline) = session.expires_at {
+            self.schedule_expiry(&id, deadline);
+        }
+        let bytes = session.approx_bytes();
+        let change = self
+            .changes
+            .get()
+            .filter(|_| record)
+            .map(|_| Change::Put(id.clone(), Box::new((&session).into())));
+        let filed = Filed::of(&id, &session);
+        let session = Arc::new(TokioRwLock::new(session));
+        let key = id.lookup_key();
+        let stored = StoredSession {
+            id,
+            session: session.clone(),
+            bytes,
+            user: None,
+        };
+        self.memory_bytes.fetch_add(bytes, Ordering::Relaxed);
+        let mut shard = self.shard(&key).write().unwrap();
+        self.store(&mut shard, key, stored, filed);
+        self.record(change);
+        session
+    }
+
+    // Called under the shard lock.
+    fn store(
+        &self,
+        shard: &mut HashMap<LookupKey, StoredSession>,
+        key: LookupKey,
+        stored: StoredSession,
+        filed: Option<(String, Filed)>,
+    ) {
+        if let Some(mut replaced) = shard.remove(&key) {
+            self.memory_bytes
+                .fetch_sub(replaced.bytes, Ordering::Relaxed);
+            self.file_user(key, &mut replaced, None);
+        }
+        let stored = shard.entry(key).or_insert(stored);
+        self.file_user(key, stored, filed);
+    }
+
+    // Takes each shard lock once for the whole batch instead of once per
+    // session.
+    pub fn insert_batch(&self, sessions: Vec<(SessionId, Session)>) {
+        type Batch = Vec<(
+            LookupKey,
+            StoredSession,
+            Option<(String, Filed)>,
+            Option<Change>,
+        )>;
+        let mut by_shard: Vec<Batch> = (0..SHARDS).map(|_| Vec::new()).collect();
+        for (id, session) in sessions {
+            if let Some(deadline) = session.expires_at {
+                self.schedule_expiry(&id, deadline);
+            }
+            let key = id.lookup_key();
+            let bytes = session.approx_bytes();
+            self.memory_bytes.fetch_add(bytes, Ordering::Relaxed);
+            let change = self
+                .changes
+                .get()
+                .map(|_| Change::Put(id.clone(), Box::new((&session).into())));
+            let filed = Filed::of(&id, &session);
+            let stored = StoredSession {
+                id,
+                session: Arc::new(TokioRwLock::new(session)),
+                bytes,
+                user: None,
+            };
+            by_shard[key.0[0] as usize % SHARDS].push((key, stored, filed, change));
+        }
+        for (shard, sessions) in self.shards.iter().zip(by_shard) {
+            if sessions.is_empty() {
+                continue;
+            }
+            let mut shard = shard.write().unwrap();
+            for (key, stored, filed, change) in sessions {
+                self.store(&mut shard, key, stored, filed);
+                self.record(change);
+            }
+        }
+    }
+
+    pub fn get(&self, id: &SessionId) -> Option<Arc<TokioRwLock<Session>>> {
+        let key = id.lookup_key();
+        let shard = self.shard(&key).read().unwrap();
+        shard
+            .get(&key)
+            .filter(|stored| stored.id == *id)
+            .map(|stored| stored.session.clone())
+    }
+
+    pub fn contains(&self, id: &SessionId) -> bool {
+        self.get(id).is_some()
+    }
+
+    pub fn remove(&self, id: &SessionId) -> Option<Arc<TokioRwLock<Session>>> {
+        self.remove_recorded(id, true)
+    }
+
+    // Removes this instance's copy of a session, leaving memcached's alone.
+    fn forget(&self, id: &SessionId) -> Option<Arc<TokioRwLock<Session>>> {
+        self.remove_recorded(id, false)
+    }
+
+    fn remove_recorded(&self, id: &SessionId, record: bool) -> Option<Arc<TokioRwLock<Session>>> {
+        let key = id.lookup_key();
+        let mut shard = self.shard(&key).write().unwrap();
+        if !shard.get(&key).is_some_and(|stored| stored.id == *id) {
+            return None;
+        }
+        let mut stored = shard.remove(&key)?;
+        self.memory_bytes.fetch_sub(stored.bytes, Ordering::Relaxed);
+        self.file_user(key, &mut stored, None);
+        self.record(
+            self.changes
+                .get()
+                .filter(|_| record)
+                .map(|_| Change::Remove(stored.id.clone())),
+        );
+        Some(stored.session)
+    }
+
+    // Brings this instance's copy of a session up to date while sessions are
+    // kept in memcached, as another instance may have created, changed or
+    // removed it. The copy is kept as it is while memcached can't be
+    // reached, or while it has changes that aren't written there yet.
+    pub async fn refresh(&self, id: &SessionId) {
+        let Some(shared) = self.shared.get() else {
+            return;
+        };
+        if self.is_unwritten(id) {
+            return;
+        }
+        let Ok(saved) = shared.memcached.fetch(id).await else {
+            return;
+        };
+        match (saved, self.get(id)) {
+            (Some(saved), Some(session)) => {
+                let mut session = session.write().await;
+                if self.is_unwritten(id) {
+                    return;
+                }
+                let changed = session.version != saved.version();
+                session.replace(saved);
+                self.refile(id, &session);
+                if let Some(deadline) = session.expires_at {
+                    self.schedule_expiry(id, deadline);
+                }
+                self.resize(id, session.approx_bytes());
+                if changed {
+                    let _ = session.events.send(SessionEvent::Updated);
+                }
+            }
+            (Some(saved), None) => {
+                self.cache(id.clone(), saved.into());
+            }
+            (None, Some(session)) => {
+                let mut session = session.write().await;
+                if self.is_unwritten(id) {
+                    return;
+                }
+                session.publish(SessionEvent::Revoked);
+                drop(session);
+                self.forget(id);
+            }
+            (None, None) => {}
+        }
+    }
+
+    // Brings the user's sessions other instances stored into memory, while
+    // sessions are kept in memcached.
+    pub async fn load_user(&self, user: &str) {
+        let Some(shared) = self.shared.get() else {
+            return;
+        };
+        let sessions = match shared.memcached.sessions_of(user).await {
+            Ok(sessions) => sessions,
+            Err(err) => return shared.memcached.health.record(Err(err)),
+        };
+        for (id, saved) in sessions {
+            if !self.contains(&id) && !self.is_unwritten(&id) {
+                self.cache(id, saved.into());
+            }
+        }
+    }
+
+    // Has to be called after a stored session grew or shrank, with its new
+    // approx_bytes().
+    pub fn resize(&self, id: &SessionId, bytes: usize) {
+        let key = id.lookup_key();
+        let mut shard = self.shard(&key).write().unwrap();
+        let Some(stored) = shard.get_mut(&key).filter(|stored| stored.id == *id) else {
+            return;
+        };
+        self.memory_bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.memory_bytes.fetch_sub(stored.bytes, Ordering::Relaxed);
+        stored.bytes = bytes;
+    }
+
+    pub fn memory_bytes(&self) -> usize {
+        self.memory_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn count(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap().len())
+            .sum()
+    }
+
+    pub fn snapshot(&self) -> Vec<(SessionId, Arc<TokioRwLock<Session>>)> {
+        let mut all = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.read().unwrap();
+            all.extend(
+                shard
+                    .values()
+                    .map(|stored| (stored.id.clone(), stored.session.clone())),
+            );
+        }
+        all
+    }
+
+    pub async fn sessions_of(&self, user: &str) -> Vec<(SessionId, Arc<TokioRwLock<Session>>)> {
+        self.load_user(user).await;
+        let ids: Vec<SessionId> = self
+            .users
+            .lock()
+            .unwrap()
+            .get(user)
+            .map(|filed| filed.values().map(|filed| filed.id.clone()).collect())
+            .unwrap_or_default();
+        ids.into_iter()
+            .filter_map(|id| {
+                let session = self.get(&id)?;
+                Some((id, session))
+            })
+            .collect()
+    }
+
+    pub async fn remove_user(&self, user: &str) -> Vec<Arc<TokioRwLock<Session>>> {
+        self.sessions_of(user)
+            .await
+            .into_iter()
+            .filter_map(|(id, _)| self.remove(&id))
+            .collect()
+    }
+
+    pub async fn remove_expired(&self, now: u64) -> Vec<Arc<TokioRwLock<Session>>> {
+        let due = {
+            let mut deadlines = self.deadlines.lock().unwrap();
+            let later = deadlines.split_off(&(now + 1));
+            std::mem::replace(&mut *deadlines, later)
+        };
+        let mut removed = Vec::new();
+        for id in due.into_values().flatten() {
+            let Some(session) = self.get(&id) else {
+                continue;
+            };
+            let (expired, expires_at) = {
+                let session = session.read().await;
+                (session.is_expired(now), session.expires_at)
+            };
+            if expired && self.shared.get().is_some() {
+                // memcached expires sessions itself, and may have a later
+                // expiry from another instance.
+                removed.extend(self.forget(&id));
+            } else if expired {
+                removed.extend(self.remove(&id));
+            } else if let Some(deadline) = expires_at {
+                file(&self.deadlines, &id, deadline);
+            }
+        }
+        removed
+    }
+
+    // Sessions that expire within sessions.expiry.warn_before_secs of now,
+    // with the deadline they expire at.
+    pub async fn expiring(&self, now: u64) -> Vec<(Arc<TokioRwLock<Session>>, u64)> {
+        let Some(warn_before) = self.warn_before.get() else {
+            return Vec::new();
+        };
+        let due = {
+            let mut warnings = self.warnings.lock().unwrap();
+            let later = warnings.split_off(&(now.saturating_add(*warn_before) + 1));
+            std::mem::replace(&mut *warnings, later)
+        };
+        let mut expiring = Vec::new();
+        let mut seen = HashSet::new();
+        for (deadline, ids) in due {
+            for id in ids {
+                let Some(session) = self.get(&id) else {
+                    continue;
+                };
+                let expires_at = session.read().await.expires_at;
+                match expires_at {
+                    Some(expires_at) if expires_at > deadline => {
+                        file(&self.warnings, &id, expires_at);
+                    }
+                    Some(expires_at)
+                        if expires_at == deadline
+                            && expires_at > now
+                            && seen.insert(id.lookup_key()) =>
+                    {
+                        expiring.push((session, expires_at));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        expiring
+    }
+}
+
+fn unfile(users: &mut UsersIndex, user: &str, key: &LookupKey) {
+    if let Some(filed) = users.get_mut(user) {
+        filed.remove(key);
+        if filed.is_empty() {
+            users.remove(user);
+        }
+    }
+}
+
+fn file(index: &std::sync::Mutex<BTreeMap<u64, Vec<SessionId>>>, id: &SessionId, deadline: u64) {
+    index
+        .lock()
+        .unwrap()
+        .entry(deadline)
+        .or_default()
+        .push(id.clone());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format() -> SessionIdFormat {
+        SessionIdFormat::new(32, vec![16], vec![(1, vec![1; 32]), (2, vec![2; 32])], 2)
+    }
+
+    fn id_from_bytes(bytes: &[u8]) -> SessionId {
+        SessionId { id: bytes.to_vec() }
+    }
+
+    fn generate(format: &SessionIdFormat) -> String {
+        String::from(&format.generate(&ring::rand::SystemRandom::new()))
+    }
+
+    fn decode(encoded: &str) -> Vec<u8> {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(encoded)
+            .unwrap()
+    }
+
+    #[test]
+    fn parse_round_trips() {
+        let id = format().generate(&ring::rand::SystemRandom::new());
+        let encoded = String::from(&id);
+        assert_eq!(encoded.len(), encoded_len(1 + 32 + SIGNATURE_BYTES));
+        assert_eq!(format().parse(&encoded), Ok(id));
+    }
+
+    #[test]
+    fn parse_rejects_tampered_ids() {
+        let mut raw = decode(&generate(&format()));
+        raw[5] ^= 1;
+        let tampered = String::from(&id_from_bytes(&raw));
+        assert_eq!(format().parse(&tampered), Err(ParseError::InvalidSignature));
+
+        let mut raw = decode(&generate(&format()));
+        *raw.last_mut().unwrap() ^= 1;
+        let tampered = String::from(&id_from_bytes(&raw));
+        assert_eq!(format().parse(&tampered), Err(ParseError::InvalidSignature));
+    }
+
+    #[test]
+    fn parse_accepts_ids_signed_with_retired_keys_until_removed() {
+        let old_format = SessionIdFormat::new(32, vec![], vec![(1, vec![1; 32])], 1);
+        let old_id = generate(&old_format);
+        assert!(format().parse(&old_id).is_ok());
+
+        let rotated = SessionIdFormat::new(32, vec![], vec![(2, vec![2; 32])], 2);
+        assert_eq!(rotated.parse(&old_id), Err(ParseError::InvalidSignature));
+    }
+
+    #[test]
+    fn parse_accepts_legacy_lengths_only_when_configured() {
+        let legacy = String::from(&id_from_bytes(&[5; 16]));
+        assert_eq!(format().parse(&legacy), Ok(id_from_bytes(&[5; 16])));
+        let without_legacy = SessionIdFormat::new(32, vec![], vec![(1, vec![1; 32])], 1);
+        assert_eq!(without_legacy.parse(&legacy), Err(ParseError::Malformed));
+    }
+
+    #[test]
+    fn parse_rejects_unsigned_ids_by_default() {
+        let format = SessionIdFormat::from_config(
+            &config::SessionsConfig::default(),
+            Vec::new(),
+            &ring::rand::SystemRandom::new(),
+        );
+        let unsigned = String::from(&id_from_bytes(&[5; 16]));
+        assert_eq!(format.parse(&unsigned), Err(ParseError::Malformed));
+        assert!(format.parse(&generate(&format)).is_ok());
+    }
+
+    #[test]
+    fn parse_rejects_wrong_lengths() {
+        for len in [0, 1, 8, 15, 17, 32, 48, 50, 64] {
+            let encoded = String::from(&id_from_bytes(&vec![1u8; len]));
+            assert_eq!(
+                format().parse(&encoded),
+                Err(ParseError::Malformed),
+                "length {}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn parse_rejects_invalid_encodings() {
+        let valid = generate(&format());
+        let padded = format!("{}=", &valid);
+        let standard_alphabet = format!("+{}", &valid[1..]);
+        let non_ascii = format!("ü{}", &valid[2..]);
+        assert_eq!(non_ascii.len(), valid.len());
+        assert_eq!(format().parse(&padded), Err(ParseError::Malformed));
+        assert_eq!(
+            format().parse(&standard_alphabet),
+            Err(ParseError::Malformed)
+        );
+        assert_eq!(format().parse(&non_ascii), Err(ParseError::Malformed));
+    }
+
+    #[test]
+    fn parse_rejects_non_canonical_trailing_bits() {
+        let mut encoded = String::from(&id_from_bytes(&[0; 16]));
+        encoded.pop();
+        encoded.push('B');
+        assert_eq!(format().parse(&encoded), Err(ParseError::Malformed));
+    }
+
+    // Seeded, so a failing case is the same on every run.
+    struct Xorshift(u64);
+
+    impl Xorshift {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    #[test]
+    fn parse_holds_up_against_random_input() {
+        let format = format();
+        let mut rng = Xorshift(0x9e37_79b9_7f4a_7c15);
+        let valid_lens = [encoded_len(format.signed_len()), encoded_len(16)];
+        let alphabet: Vec<char> =
+            "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_+/=ü\0\n"
+                .chars()
+                .collect();
+        let mut accepted = 0;
+        for _ in 0..20_000 {
+            let len = valid_lens[rng.below(2)] + rng.below(5) - 2;
+            let input: String = (0..len)
+                .map(|_| alphabet[rng.below(alphabet.len())])
+                .collect();
+            let Ok(id) = format.parse(&input) else {
+                continue;
+            };
+            // Only legacy ids get through without a signature, and anything
+            // accepted is the one canonical encoding of its bytes.
+            assert_eq!(input.len(), encoded_len(16), "{}", input);
+            assert_eq!(String::from(&id), input);
+            accepted += 1;
+        }
+        assert!(accepted > 0);
+
+        for _ in 0..2_000 {
+            let id = format.generate(&ring::rand::SystemRandom::new());
+            let encoded = String::from(&id);
+            assert_eq!(format.parse(&encoded).as_ref(), Ok(&id));
+            let mut chars: Vec<char> = encoded.chars().collect();
+            let at = rng.below(chars.len());
+            let replacement = alphabet[rng.below(64)];
+            if chars[at] == replacement {
+                continue;
+            }
+            chars[at] = replacement;
+            let tampered: String = chars.iter().collect();
+            assert!(format.parse(&tampered).is_err(), "{}", tampered);
+            assert!(format.parse(&encoded[1..]).is_err());
+            assert!(format.parse(&format!("{}A", encoded)).is_err());
+        }
+    }
+
+    #[test]
+    fn store_finds_only_exact_ids() {
+        let store = SessionStore::new();
+        let id = id_from_bytes(&[3; 32]);
+        let mut other_bytes = [3; 32];
+        other_bytes[31] = 4;
+        let other = id_from_bytes(&other_bytes);
+
+        let session = Session::new(IpAddr::from([127, 0, 0, 1]), None, 1000);
+        store.insert(id.clone(), session);
+        assert!(store.contains(&id));
+        assert!(!store.contains(&other));
+        assert!(store.remove(&other).is_none());
+        assert!(store.remove(&id).is_some());
+        assert!(!store.contains(&id));
+    }
+
+    #[test]
+    fn store_accounts_for_session_memory() {
+        let store = SessionStore::new();
+        let id = id_from_bytes(&[5; 32]);
+        let mut session = Session::new(IpAddr::from([127, 0, 0, 1]), None, 1000);
+        let bytes = session.approx_bytes();
+        session
+            .data
+            .insert(String::from("key"), serde_json::json!("value"));
+        let grown = session.approx_bytes();
+        assert_eq!(grown, bytes + "key".len() + "\"value\"".len());
+
+        store.insert_batch(vec![(id.clone(), session)]);
+        assert_eq!(store.memory_bytes(), grown);
+        store.resize(&id, bytes);
+        store.resize(&id_from_bytes(&[6; 32]), 1_000_000);
+        assert_eq!(store.memory_bytes(), bytes);
+        assert_eq!(store.count(), 1);
+        store.remove(&id);
+        assert_eq!(store.memory_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn sweeps_only_remove_sessions_that_are_due() {
+        let store = SessionStore::new();
+        let policy = config::ExpiryPolicy {
+            idle_timeout_secs: Some(100),
+            absolute_lifetime_secs: None,
+        };
+        let mut ids = Vec::new();
+        for byte in 0..3 {
+            let mut session = Session::new(IpAddr::from([127, 0, 0, 1]), None, 1000);
+            session.set_expiry(policy, 1000);
+            let id = id_from_bytes(&[byte; 32]);
+            store.insert(id.clone(), session);
+            ids.push(id);
+        }
+        let never = id_from_bytes(&[9; 32]);
+        store.insert(
+            never.clone(),
+            Session::new(IpAddr::from([127, 0, 0, 1]), None, 1000),
+        );
+
+        // Touched after being filed, so it has to be filed again.
+        store.get(&ids[1]).unwrap().write().await.touch(1050);
+        assert!(store.remove_expired(1099).await.is_empty());
+        assert_eq!(store.remove_expired(1100).await.len(), 2);
+        assert!(store.contains(&ids[1]) && store.contains(&never));
+        assert!(store.remove_expired(1149).await.is_empty());
+        assert_eq!(store.remove_expired(1150).await.len(), 1);
+        assert!(!store.contains(&ids[1]) && store.contains(&never));
+    }
+
+    #[tokio::test]
+    async fn warns_once_before_each_deadline() {
+        let store = SessionStore::new();
+        store.warn_before_expiry(30);
+        let policy = config::ExpiryPolicy {
+            idle_timeout_secs: Some(100),
+            absolute_lifetime_secs: None,
+        };
+        let mut session = Session::new(IpAddr::from([127, 0, 0, 1]), None, 1000);
+        session.set_expiry(policy, 1000);
+        let id = id_from_bytes(&[7; 32]);
+        store.insert(id.clone(), session);
+
+        assert!(store.expiring(1069).await.is_empty());
+        // Touched, so the warning moves to the new deadline.
+        store.get(&id).unwrap().write().await.touch(1050);
+        assert!(store.expiring(1070).await.is_empty());
+        assert!(store.expiring(1119).await.is_empty());
+        let expiring = store.expiring(1120).await;
+        assert_eq!(expiring.len(), 1);
+        assert_eq!(expiring[0].1, 1150);
+        assert!(store.expiring(1130).await.is_empty());
+        assert_eq!(store.remove_expired(1150).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn users_index_follows_inserts_changes_and_removals() {
+        let store = SessionStore::new();
+        let mut session = Session::new(IpAddr::from([127, 0, 0, 1]), None, 900);
+        session.user = Some(String::from("alice"));
+        session.authenticated = true;
+        let alice = id_from_bytes(&[1; 32]);
+        store.insert(alice.clone(), session);
+        let anonymous = id_from_bytes(&[2; 32]);
+        let session = store.insert(
+            anonymous.clone(),
+            Session::new(IpAddr::from([127, 0, 0, 1]), None, 1000),
+        );
+        assert_eq!(store.sessions_of("alice").await.len(), 1);
+
+        // Signing in files the session before it's changed, and the limit
+        // counts it from then on.
+        assert_eq!(
+            store.admit("alice", &anonymous, 2, false, 1000),
+            Some(vec![])
+        );
+        assert_eq!(store.sessions_of("alice").await.len(), 2);
+        let third = id_from_bytes(&[3; 32]);
+        assert_eq!(store.admit("alice", &third, 2, false, 1000), None);
+        assert_eq!(
+            store.admit("alice", &third, 2, true, 1000),
+            Some(vec![alice.clone()])
+        );
+
+        let mut session = session.write().await;
+        session.user = Some(String::from("bob"));
+        session.authenticated = true;
+        store.persist(&anonymous, &session);
+        drop(session);
+        assert_eq!(store.sessions_of("bob").await.len(), 1);
+        store.remove(&anonymous);
+        assert!(store.sessions_of("bob").await.is_empty());
+        assert!(store.users.lock().unwrap().get("bob").is_none());
+    }
+
+    #[tokio::test]
+    async fn lookups_only_write_when_something_moves() {
+        let store = SessionStore::new();
+        let policy = config::ExpiryPolicy {
+            idle_timeout_secs: Some(100),
+            absolute_lifetime_secs: None,
+        };
+        let mut session = Session::new(IpAddr::from([127, 0, 0, 1]), None, 1000);
+        session.set_expiry(policy, 1000);
+        let id = id_from_bytes(&[8; 32]);
+        let session = store.insert(id.clone(), session);
+
+        // Another reader holds the lock, which a write would wait for.
+        let reading = session.read().await;
+        let access = store.access(&id, &session, 1005);
+        assert_eq!(
+            tokio::time::timeout(std::time::Duration::from_secs(1), access).await,
+            Ok(true)
+        );
+        drop(reading);
+        assert!(store.access(&id, &session, 1060).await);
+        let session_locked = session.read().await;
+        assert_eq!(session_locked.expires_at, Some(1160));
+        assert_eq!(session_locked.last_seen, 1060);
+        drop(session_locked);
+        assert!(!store.access(&id, &session, 1160).await);
+    }
+
+    #[test]
+    fn sliding_expiry_is_capped_by_absolute_lifetime() {
+        let mut session = Session::new(IpAddr::from([127, 0, 0, 1]), None, 1000);
+        assert!(!session.is_expired(u64::MAX));
+
+        let policy = config::ExpiryPolicy {
+            idle_timeout_secs: Some(100),
+            absolute_lifetime_secs: Some(250),
+        };
+        session.set_expiry(policy, 1000);
+        assert_eq!(session.expires_at, Some(1100));
+        session.touch(1090);
+        assert_eq!(session.expires_at, Some(1190));
+        // Moves of less than a tenth of the idle timeout are left out.
+        assert!(!session.touch(1099));
+        assert_eq!(session.expires_at, Some(1190));
+        let etag = session.etag();
+        assert!(session.touch(1200));
+        assert_eq!(session.expires_at, Some(1250));
+        assert_ne!(session.etag(), etag);
+        let etag = session.etag();
+        assert!(!session.touch(1210));
+        assert_eq!(session.etag(), etag);
+        assert!(!session.is_expired(1249));
+        assert!(session.is_expired(1250));
+
+        let expiry: config::ExpiryConfig = serde_json::from_value(serde_json::json!({
+            "idle_timeout_secs": 1800,
+            "roles": {
+                "admins": { "idle_timeout_secs": 600, "absolute_lifetime_secs": 7200 },
+                "ops": { "idle_timeout_secs": 300 },
+            },
+        }))
+        .unwrap();
+        assert_eq!(expiry.policy_for(&[]).idle_timeout_secs, Some(1800));
+        assert_eq!(
+            expiry.policy_for(&[String::from("admins"), String::from("ops")]),
+            config::ExpiryPolicy {
+                idle_timeout_secs: Some(300),
+                absolute_lifetime_secs: Some(7200),
+            }
+        );
+    }
+}
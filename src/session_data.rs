@@ -0,0 +1,132 @@
+use std::sync::Arc;
+
+use crate::client_info::ClientInfo;
+use crate::i18n;
+use crate::{
+    enforce_memory_budget, error_response, json_response, lookup_session, message_error_response,
+    AppState, GetSessionQuery, SESSION_STORE_FULL,
+};
+
+const MAX_KEY_BYTES: usize = 128;
+
+fn valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= MAX_KEY_BYTES
+        && key
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"._-".contains(&byte))
+}
+
+pub async fn get_session_data(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    axum::extract::Path(key): axum::extract::Path<String>,
+    axum::extract::Query(query): axum::extract::Query<GetSessionQuery>,
+) -> axum::response::Response {
+    let session = match lookup_session(&state, &query.session_id, &client).await {
+        Ok((_, session)) => session,
+        Err(response) => return response,
+    };
+    let session_locked = session.read().await;
+    match session_locked.data.get(&key) {
+        Some(value) => json_response(200, value.clone()),
+        None => message_error_response(
+            404,
+            i18n::Message {
+                template: "no session data under {key}",
+                args: vec![("key", key.to_string())],
+            },
+        ),
+    }
+}
+
+pub async fn put_session_data(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    axum::extract::Path(key): axum::extract::Path<String>,
+    axum::extract::Query(query): axum::extract::Query<GetSessionQuery>,
+    body: axum::body::Bytes,
+) -> axum::response::Response {
+    if !valid_key(&key) {
+        return message_error_response(
+            400,
+            i18n::Message {
+                template: "keys must be 1 to {max} characters of A-Z, a-z, 0-9, '.', '_' and '-'",
+                args: vec![("max", MAX_KEY_BYTES.to_string())],
+            },
+        );
+    }
+    let Ok(value) = serde_json::from_slice::<serde_json::Value>(&body) else {
+        return error_response(400, "the body must be a JSON value");
+    };
+    let (session_id, session) = match lookup_session(&state, &query.session_id, &client).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    // Checked before locking the session, since making room may have to read
+    // every session. Assumes nothing is replaced, so it can only overestimate.
+    if !enforce_memory_budget(&state, key.len() + body.len()).await {
+        return SESSION_STORE_FULL.response();
+    }
+    let mut session_locked = session.write().await;
+    if !session_locked.authenticated {
+        return error_response(403, "session data can only be written once authenticated");
+    }
+    let previous = session_locked.data.insert(key.clone(), value);
+    if session_locked.data_bytes() > state.config.sessions.max_data_bytes {
+        match previous {
+            Some(previous) => session_locked.data.insert(key, previous),
+            None => session_locked.data.remove(&key),
+        };
+        return message_error_response(
+            413,
+            i18n::Message {
+                template: "session data can't exceed {max} bytes",
+                args: vec![("max", state.config.sessions.max_data_bytes.to_string())],
+            },
+        );
+    }
+    state
+        .sessions
+        .resize(&session_id, session_locked.approx_bytes());
+    state.sessions.persist(&session_id, &session_locked);
+    json_response(
+        200,
+        serde_json::json!({ "success": format!("session data {} stored", key) }),
+    )
+}
+
+pub async fn delete_session_data(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    client: ClientInfo,
+    axum::extract::Path(key): axum::extract::Path<String>,
+    axum::extract::Query(query): axum::extract::Query<GetSessionQuery>,
+) -> axum::response::Response {
+    let (session_id, session) = match lookup_session(&state, &query.session_id, &client).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    let mut session_locked = session.write().await;
+    if !session_locked.authenticated {
+        return error_response(403, "session data can only be written once authenticated");
+    }
+    match session_locked.data.remove(&key) {
+        Some(_) => {
+            state
+                .sessions
+                .resize(&session_id, session_locked.approx_bytes());
+            state.sessions.persist(&session_id, &session_locked);
+            json_response(
+                200,
+                serde_json::json!({ "success": format!("session data {} deleted", key) }),
+            )
+        }
+        None => message_error_response(
+            404,
+            i18n::Message {
+                template: "no session data under {key}",
+                args: vec![("key", key.to_string())],
+            },
+        ),
+    }
+}
@@ -0,0 +1,119 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crate::config;
+use crate::failures::FailureCounts;
+use crate::rate_limit::Quota;
+
+// Slows down sign-ins for users and networks with recent failures instead
+// of locking them out. The delay comes before the password is checked, so
+// giving up early doesn't tell whether a guess was right.
+pub struct Tarpit {
+    config: Option<config::TarpitConfig>,
+    failures: FailureCounts,
+    // Held while a request sleeps; when they run out, requests that would
+    // sleep are turned away instead of piling up.
+    slots: tokio::sync::Semaphore,
+}
+
+impl Default for Tarpit {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl Tarpit {
+    pub fn new(config: Option<&config::TarpitConfig>) -> Self {
+        Self {
+            config: config.cloned(),
+            failures: FailureCounts::new(config.map_or(0, |config| config.failure_window_secs)),
+            slots: tokio::sync::Semaphore::new(config.map_or(0, |config| config.max_concurrent)),
+        }
+    }
+
+    // Doubles with each failure past after_failures, up to max_delay_ms.
+    fn delay(&self, user: Option<&str>, ip: IpAddr, now: u64) -> Option<Duration> {
+        let config = self.config.as_ref()?;
+        let failures = self.failures.count(user, ip, now);
+        let excess = failures.checked_sub(config.after_failures)?;
+        let delay_ms = config
+            .base_delay_ms
+            .saturating_mul(1u64 << excess.min(32))
+            .min(config.max_delay_ms);
+        Some(Duration::from_millis(delay_ms))
+    }
+
+    // Returns false when too many requests are already being held back, in
+    // which case the sign-in should be refused.
+    pub async fn wait(&self, user: Option<&str>, ip: IpAddr, now: u64) -> bool {
+        let Some(delay) = self.delay(user, ip, now) else {
+            return true;
+        };
+        let Ok(_slot) = self.slots.try_acquire() else {
+            return false;
+        };
+        tokio::time::sleep(delay).await;
+        true
+    }
+
+    // The failures left before sign-ins get slowed down.
+    pub fn quota(&self, user: Option<&str>, ip: IpAddr, now: u64) -> Option<Quota> {
+        let config = self.config.as_ref()?;
+        let failures = self.failures.count(user, ip, now);
+        Some(Quota {
+            limit: config.after_failures,
+            remaining: config.after_failures.saturating_sub(failures),
+            reset_secs: self.failures.reset_in(user, ip, now),
+        })
+    }
+
+    // Without a user, only the network's count goes up.
+    pub fn record_failure(&self, user: Option<&str>, ip: IpAddr, now: u64) {
+        if self.config.is_some() {
+            self.failures.record(user, ip, now);
+        }
+    }
+
+    pub fn record_success(&self, user: &str) {
+        if self.config.is_some() {
+            self.failures.clear_user(user);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_grow_with_failures_up_to_a_bound() {
+        let tarpit = Tarpit::new(Some(&config::TarpitConfig {
+            after_failures: 2,
+            base_delay_ms: 100,
+            max_delay_ms: 500,
+            failure_window_secs: 60,
+            max_concurrent: 1,
+        }));
+        let ip = IpAddr::from([192, 0, 2, 1]);
+        let delay = |user| tarpit.delay(user, ip, 110).map(|delay| delay.as_millis());
+        tarpit.record_failure(Some("zed"), ip, 100);
+        assert_eq!(delay(Some("zed")), None);
+        let quota = |user| tarpit.quota(user, ip, 110).unwrap();
+        assert_eq!(
+            (quota(Some("zed")).remaining, quota(Some("zed")).reset_secs),
+            (1, 50)
+        );
+        tarpit.record_failure(Some("zed"), ip, 100);
+        assert_eq!(delay(Some("zed")), Some(100));
+        assert_eq!(quota(None).remaining, 0);
+        assert_eq!(quota(Some("amy")).reset_secs, 50);
+        assert_eq!(delay(None), Some(100));
+        tarpit.record_failure(Some("zed"), ip, 100);
+        assert_eq!(delay(Some("zed")), Some(200));
+        for _ in 0..10 {
+            tarpit.record_failure(Some("zed"), ip, 100);
+        }
+        assert_eq!(delay(Some("zed")), Some(500));
+        assert_eq!(tarpit.delay(Some("zed"), ip, 160), None);
+    }
+}
@@ -0,0 +1,467 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use argon2::{PasswordHasher, PasswordVerifier};
+use tokio::sync::RwLock as TokioRwLock;
+
+use crate::config;
+use crate::encryption::{self, Encryption};
+use crate::health;
+use crate::legacy_hash;
+use crate::metrics;
+use crate::time;
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct User {
+    pub id: String,
+    pub user_name: String,
+    #[serde(default)]
+    pub external_id: Option<String>,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub given_name: Option<String>,
+    #[serde(default)]
+    pub family_name: Option<String>,
+    #[serde(default)]
+    pub emails: Vec<String>,
+    #[serde(default)]
+    pub phone_numbers: Vec<String>,
+    // A language tag such as de or pt-BR, picking the email templates.
+    #[serde(default)]
+    pub preferred_language: Option<String>,
+    pub active: bool,
+    #[serde(default)]
+    pub password_hash: Option<String>,
+    #[serde(default)]
+    pub password_reset_required: bool,
+    #[serde(default)]
+    pub password_migrated_at: Option<u64>,
+    #[serde(default)]
+    pub identities: Vec<LinkedIdentity>,
+    // The channels the user chose per notification event; events that
+    // aren't here go to their default channels.
+    #[serde(default)]
+    pub notification_preferences: BTreeMap<String, Vec<config::NotificationChannel>>,
+    pub created: u64,
+    pub last_modified: u64,
+    pub version: u64,
+}
+
+impl User {
+    pub fn new(id: String, user_name: String) -> Self {
+        let now = time::now_secs();
+        Self {
+            id,
+            user_name,
+            external_id: None,
+            display_name: None,
+            given_name: None,
+            family_name: None,
+            emails: Vec::new(),
+            phone_numbers: Vec::new(),
+            preferred_language: None,
+            active: true,
+            password_hash: None,
+            password_reset_required: false,
+            password_migrated_at: None,
+            identities: Vec::new(),
+            notification_preferences: BTreeMap::new(),
+            created: now,
+            last_modified: now,
+            version: 1,
+        }
+    }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct LinkedIdentity {
+    pub provider: String,
+    pub subject: String,
+    pub linked_at: u64,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Group {
+    pub id: String,
+    pub display_name: String,
+    #[serde(default)]
+    pub external_id: Option<String>,
+    #[serde(default)]
+    pub members: Vec<String>,
+    pub created: u64,
+    pub last_modified: u64,
+    pub version: u64,
+}
+
+impl Group {
+    pub fn new(id: String, display_name: String) -> Self {
+        let now = time::now_secs();
+        Self {
+            id,
+            display_name,
+            external_id: None,
+            members: Vec::new(),
+            created: now,
+            last_modified: now,
+            version: 1,
+        }
+    }
+}
+
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct UserData {
+    #[serde(default)]
+    pub users: BTreeMap<String, User>,
+    #[serde(default)]
+    pub groups: BTreeMap<String, Group>,
+}
+
+impl UserData {
+    pub fn user_by_name(&self, user_name: &str) -> Option<&User> {
+        self.users
+            .values()
+            .find(|user| user.user_name.eq_ignore_ascii_case(user_name))
+    }
+
+    pub fn user_by_identity(&self, provider: &str, subject: &str) -> Option<&User> {
+        self.users.values().find(|user| {
+            user.identities
+                .iter()
+                .any(|identity| identity.provider == provider && identity.subject == subject)
+        })
+    }
+
+    pub fn groups_of(&self, user_id: &str) -> Vec<&Group> {
+        self.groups
+            .values()
+            .filter(|group| group.members.iter().any(|member| member == user_id))
+            .collect()
+    }
+}
+
+// The user fields encryption covers.
+const SEALED_FIELDS: [&str; 8] = [
+    "user_name",
+    "display_name",
+    "given_name",
+    "family_name",
+    "emails",
+    "phone_numbers",
+    "password_hash",
+    "identities",
+];
+
+// Fields are bound to their user, so they can't be swapped between users.
+fn sealed_context(id: &str, field: &str) -> String {
+    format!("users/{}/{}", id, field)
+}
+
+fn serialize(data: &UserData, encryption: Option<&Encryption>) -> Vec<u8> {
+    let Some(encryption) = encryption else {
+        return serde_json::to_vec_pretty(data).unwrap();
+    };
+    let mut value = serde_json::to_value(data).unwrap();
+    if let Some(users) = value["users"].as_object_mut() {
+        for (id, user) in users {
+            for field in SEALED_FIELDS {
+                if let Some(value) = user.get_mut(field) {
+                    *value = encryption.seal_value(&sealed_context(id, field), value);
+                }
+            }
+        }
+    }
+    serde_json::to_vec_pretty(&value).unwrap()
+}
+
+// Decrypts the fields serialize encrypted, and returns whether any are
+// stale: encrypted with an older key, or not at all while they should be.
+fn open_fields(
+    value: &mut serde_json::Value,
+    encryption: Option<&Encryption>,
+) -> Result<bool, String> {
+    let mut is_stale = false;
+    let Some(users) = value
+        .get_mut("users")
+        .and_then(|users| users.as_object_mut())
+    else {
+        return Ok(false);
+    };
+    for (id, user) in users {
+        for field in SEALED_FIELDS {
+            let Some(value) = user.get_mut(field) else {
+                continue;
+            };
+            let Some(sealed) = encryption::sealed(value).map(str::to_string) else {
+                is_stale |= encryption.is_some();
+                continue;
+            };
+            let encryption = encryption.ok_or_else(|| {
+                String::from("holds encrypted users, but encryption is not configured")
+            })?;
+            let (opened, stale) = encryption
+                .open_value(&sealed_context(id, field), &sealed)
+                .map_err(|err| format!("users.{}.{}: {}", id, field, err))?;
+            *value = opened;
+            is_stale |= stale;
+        }
+    }
+    Ok(is_stale)
+}
+
+// The users in users.path, decrypted, and whether any of their fields are
+// stale, see open_fields. A missing file holds no users.
+pub fn read_file(path: &Path, encryption: Option<&Encryption>) -> io::Result<(UserData, bool)> {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Ok((UserData::default(), false))
+        }
+        Err(err) => {
+            return Err(io::Error::new(
+                err.kind(),
+                format!("{}: {}", path.display(), err),
+            ))
+        }
+    };
+    let invalid_data = |err: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), err),
+        )
+    };
+    let mut value =
+        serde_json::from_slice(&contents).map_err(|err| invalid_data(err.to_string()))?;
+    let is_stale = open_fields(&mut value, encryption).map_err(invalid_data)?;
+    let data = serde_json::from_value(value).map_err(|err| invalid_data(err.to_string()))?;
+    Ok((data, is_stale))
+}
+
+pub fn write_file(path: &Path, data: &UserData, encryption: Option<&Encryption>) -> io::Result<()> {
+    write_private(path, &serialize(data, encryption))
+}
+
+// Replaces the file at path with contents that only the owner can read,
+// without readers ever seeing it half written.
+pub fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    NotFound,
+    Conflict(String),
+    Invalid(String),
+    Io(String),
+    // users.path is failing, so changes are refused without trying.
+    Unavailable(String),
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound => f.write_str("not found"),
+            Self::Conflict(message) | Self::Invalid(message) | Self::Io(message) => {
+                f.write_str(message)
+            }
+            Self::Unavailable(error) => write!(f, "the user store is unavailable: {}", error),
+        }
+    }
+}
+
+pub struct UserStore {
+    path: Option<PathBuf>,
+    encryption: Option<Arc<Encryption>>,
+    data: TokioRwLock<UserData>,
+    // Changes take turns, but aren't in the way of reads while one is saved.
+    writing: tokio::sync::Mutex<()>,
+    pub writes: metrics::Operation,
+    pub health: health::Backend,
+}
+
+impl UserStore {
+    // With encryption, fields that are stale are encrypted with the active
+    // key right away, which is also how existing files get encrypted.
+    pub fn open(
+        config: &config::UsersConfig,
+        encryption: Option<Arc<Encryption>>,
+    ) -> io::Result<Self> {
+        let data = match &config.path {
+            Some(path) => {
+                let (data, is_stale) = read_file(path, encryption.as_deref())?;
+                if is_stale {
+                    write_file(path, &data, encryption.as_deref()).map_err(|err| {
+                        io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+                    })?;
+                    println!(
+                        "Encrypted {} with the active encryption key",
+                        path.display()
+                    );
+                }
+                data
+            }
+            None => UserData::default(),
+        };
+        Ok(Self {
+            path: config.path.clone(),
+            encryption,
+            data: TokioRwLock::new(data),
+            writing: tokio::sync::Mutex::new(()),
+            writes: metrics::Operation::default(),
+            health: health::Backend::new("users"),
+        })
+    }
+
+    pub async fn read(&self) -> tokio::sync::RwLockReadGuard<'_, UserData> {
+        self.data.read().await
+    }
+
+    pub async fn update<T>(
+        &self,
+        update: impl FnOnce(&mut UserData) -> Result<T, StoreError>,
+    ) -> Result<T, StoreError> {
+        // Rather than waiting behind a write to a disk that hangs.
+        if let Some(error) = self.health.failure() {
+            return Err(StoreError::Unavailable(error));
+        }
+        let _writing = self.writing.lock().await;
+        let mut updated = self.data.read().await.clone();
+        let result = update(&mut updated)?;
+        self.persist(&updated).await?;
+        *self.data.write().await = updated;
+        Ok(result)
+    }
+
+    // On a blocking thread, so a slow disk doesn't hold up other requests.
+    async fn persist(&self, data: &UserData) -> Result<(), StoreError> {
+        let Some(path) = self.path.clone() else {
+            return Ok(());
+        };
+        let started = std::time::Instant::now();
+        let contents = serialize(data, self.encryption.as_deref());
+        let result = tokio::task::spawn_blocking(move || {
+            write_private(&path, &contents).map_err(|err| format!("{}: {}", path.display(), err))
+        })
+        .await
+        .unwrap_or_else(|err| Err(err.to_string()));
+        self.writes.record(started, result.is_ok());
+        self.health.record(result.clone());
+        result.map_err(StoreError::Io)
+    }
+
+    pub async fn is_active(&self, user_name: &str) -> bool {
+        self.read()
+            .await
+            .user_by_name(user_name)
+            .is_none_or(|user| user.active)
+    }
+
+    pub async fn roles(&self, user_name: &str) -> Vec<String> {
+        let data = self.read().await;
+        match data.user_by_name(user_name) {
+            Some(user) => data
+                .groups_of(&user.id)
+                .iter()
+                .map(|group| group.display_name.clone())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub async fn verify_password(&self, user_name: &str, password: &str) -> Result<bool, String> {
+        let found = match self.read().await.user_by_name(user_name) {
+            Some(user) if user.active && !user.password_reset_required => user
+                .password_hash
+                .clone()
+                .map(|password_hash| (user.id.clone(), password_hash)),
+            _ => None,
+        };
+        let Some((id, password_hash)) = found else {
+            return Ok(false);
+        };
+        let password = zeroize::Zeroizing::new(password.to_string());
+        let checked_hash = password_hash.clone();
+        let (valid, rehashed) = tokio::task::spawn_blocking(move || {
+            if is_argon2_hash(&checked_hash) {
+                (verify_password_hash(&password, &checked_hash), None)
+            } else if legacy_hash::verify(password.as_bytes(), &checked_hash) {
+                (true, Some(hash_password(&password)))
+            } else {
+                (false, None)
+            }
+        })
+        .await
+        .map_err(|err| err.to_string())?;
+
+        if let Some(rehashed) = rehashed {
+            let scheme = legacy_hash::scheme(&password_hash).unwrap_or_default();
+            let result = self
+                .update(|data| {
+                    // Leave the user alone if the hash changed while we were checking.
+                    if let Some(user) = data.users.get_mut(&id) {
+                        if user.password_hash.as_deref() == Some(password_hash.as_str()) {
+                            user.password_hash = Some(rehashed);
+                            user.password_migrated_at = Some(time::now_secs());
+                        }
+                    }
+                    Ok(())
+                })
+                .await;
+            match result {
+                Ok(()) => println!(
+                    "Migrated password of {} from {} to argon2id",
+                    user_name, scheme
+                ),
+                Err(err) => println!("Failed to save migrated password of {}: {}", user_name, err),
+            }
+        }
+        Ok(valid)
+    }
+}
+
+pub fn new_id() -> String {
+    let mut id = [0u8; 16];
+    ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut id).unwrap();
+    id.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn hash_password(password: &str) -> String {
+    let mut salt = [0u8; 16];
+    ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut salt).unwrap();
+    let salt = argon2::password_hash::SaltString::encode_b64(&salt).unwrap();
+    argon2::Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+}
+
+pub fn is_supported_hash(password_hash: &str) -> bool {
+    is_argon2_hash(password_hash) || legacy_hash::scheme(password_hash).is_some()
+}
+
+fn is_argon2_hash(password_hash: &str) -> bool {
+    argon2::password_hash::PasswordHash::new(password_hash)
+        .is_ok_and(|hash| hash.algorithm.as_str().starts_with("argon2"))
+}
+
+fn verify_password_hash(password: &str, password_hash: &str) -> bool {
+    argon2::password_hash::PasswordHash::new(password_hash).is_ok_and(|hash| {
+        argon2::Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
@@ -0,0 +1,102 @@
+use std::convert::Infallible;
+use std::path::Path;
+use std::sync::Arc;
+
+use tower::ServiceExt;
+
+use crate::config;
+
+pub const ROOT: &str = "web/build";
+// SvelteKit puts a hash of their content in the names of the files here.
+const IMMUTABLE_PREFIX: &str = "/_app/immutable/";
+
+// Paths whose last segment has no extension are client-side routes, which
+// the web app's router handles once index.html is loaded. Anything that
+// looks like a file is an asset and gets a real 404 when it's missing.
+fn is_client_route(path: &str) -> bool {
+    !path.rsplit('/').next().unwrap_or_default().contains('.')
+}
+
+pub async fn spa_fallback(
+    request: http::Request<axum::body::Body>,
+    precompressed: bool,
+) -> Result<axum::response::Response, Infallible> {
+    if !is_client_route(request.uri().path()) {
+        let mut response = axum::response::Response::default();
+        *response.status_mut() = http::StatusCode::NOT_FOUND;
+        return Ok(response);
+    }
+    let mut index = tower_http::services::ServeFile::new(Path::new(ROOT).join("index.html"));
+    if precompressed {
+        index = index.precompressed_br().precompressed_gzip();
+    }
+    let response = index.oneshot(request).await?;
+    Ok(response.map(axum::body::Body::new))
+}
+
+fn cache_control(config: &config::WebConfig, path: &str) -> String {
+    if path.starts_with(IMMUTABLE_PREFIX) && !is_client_route(path) {
+        format!(
+            "public, max-age={}, immutable",
+            config.immutable_max_age_secs
+        )
+    } else if config.max_age_secs == 0 {
+        String::from("no-cache")
+    } else {
+        format!("public, max-age={}", config.max_age_secs)
+    }
+}
+
+// Paths are relative to /web, which is stripped before the files are served.
+pub async fn cache_headers(
+    axum::extract::State(config): axum::extract::State<Arc<config::WebConfig>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let cache_control = cache_control(&config, request.uri().path());
+    let mut response = next.run(request).await;
+    let status = response.status();
+    if status.is_success() || status == http::StatusCode::NOT_MODIFIED {
+        response.headers_mut().insert(
+            http::header::CACHE_CONTROL,
+            http::HeaderValue::from_str(&cache_control).unwrap(),
+        );
+    }
+    if config.precompressed {
+        response.headers_mut().append(
+            http::header::VARY,
+            http::HeaderValue::from_static("accept-encoding"),
+        );
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_client_routes_from_assets() {
+        assert!(is_client_route("/authenticate"));
+        assert!(is_client_route("/sessions/abc-123/"));
+        assert!(!is_client_route("/_app/immutable/start.BkQ3.js"));
+        assert!(!is_client_route("/favicon.png"));
+    }
+
+    #[test]
+    fn caches_only_hashed_assets_for_long() {
+        let config = config::WebConfig::default();
+        assert_eq!(
+            cache_control(&config, "/_app/immutable/start.BkQ3.js"),
+            "public, max-age=31536000, immutable"
+        );
+        assert_eq!(cache_control(&config, "/_app/immutable/nope"), "no-cache");
+        assert_eq!(cache_control(&config, "/favicon.png"), "no-cache");
+        assert_eq!(cache_control(&config, "/"), "no-cache");
+        let config = config::WebConfig {
+            max_age_secs: 300,
+            ..config
+        };
+        assert_eq!(cache_control(&config, "/index.html"), "public, max-age=300");
+    }
+}
@@ -0,0 +1,336 @@
+use base64::Engine;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_FRAME_PAYLOAD: u64 = 64 * 1024;
+
+pub enum Message {
+    Data,
+    Ping(Vec<u8>),
+    Pong,
+    Close,
+}
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+pub fn accept(
+    request: &mut axum::extract::Request,
+) -> Result<(axum::response::Response, hyper::upgrade::OnUpgrade), &'static str> {
+    let headers = request.headers();
+    let header_contains = |name: http::HeaderName, token: &str| {
+        headers.get_all(name).iter().any(|value| {
+            value.to_str().is_ok_and(|value| {
+                value
+                    .split(',')
+                    .any(|part| part.trim().eq_ignore_ascii_case(token))
+            })
+        })
+    };
+    if request.method() != http::Method::GET
+        || !header_contains(http::header::CONNECTION, "upgrade")
+        || !header_contains(http::header::UPGRADE, "websocket")
+    {
+        return Err("expected websocket upgrade request");
+    }
+    if headers
+        .get(http::header::SEC_WEBSOCKET_VERSION)
+        .is_none_or(|version| version != "13")
+    {
+        return Err("unsupported websocket version");
+    }
+    let key = match headers.get(http::header::SEC_WEBSOCKET_KEY) {
+        Some(key) => key.as_bytes().to_vec(),
+        None => return Err("missing websocket key"),
+    };
+    let on_upgrade = match request
+        .extensions_mut()
+        .remove::<hyper::upgrade::OnUpgrade>()
+    {
+        Some(on_upgrade) => on_upgrade,
+        None => return Err("connection can't be upgraded"),
+    };
+
+    let response = axum::response::Response::builder()
+        .status(101)
+        .header(http::header::CONNECTION, "upgrade")
+        .header(http::header::UPGRADE, "websocket")
+        .header(http::header::SEC_WEBSOCKET_ACCEPT, accept_key(&key))
+        .body(axum::body::Body::empty())
+        .unwrap();
+    Ok((response, on_upgrade))
+}
+
+fn accept_key(key: &[u8]) -> String {
+    let mut digest = ring::digest::Context::new(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY);
+    digest.update(key);
+    digest.update(ACCEPT_GUID.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(digest.finish())
+}
+
+pub async fn read_message<R: AsyncReadExt + Unpin>(reader: &mut R) -> std::io::Result<Message> {
+    let mut fragments: Option<(u8, Vec<u8>)> = None;
+    loop {
+        let (fin, opcode, payload) = read_frame(reader).await?;
+        match opcode {
+            OPCODE_CLOSE => return Ok(Message::Close),
+            OPCODE_PING => return Ok(Message::Ping(payload)),
+            OPCODE_PONG => return Ok(Message::Pong),
+            OPCODE_TEXT | OPCODE_BINARY if fragments.is_none() => {
+                if fin {
+                    return data_message(opcode, payload);
+                }
+                fragments = Some((opcode, payload));
+            }
+            OPCODE_CONTINUATION if fragments.is_some() => {
+                let (first_opcode, mut buffer) = fragments.take().unwrap();
+                if buffer.len() as u64 + payload.len() as u64 > MAX_FRAME_PAYLOAD {
+                    return Err(protocol_error("message too large"));
+                }
+                buffer.extend_from_slice(&payload);
+                if fin {
+                    return data_message(first_opcode, buffer);
+                }
+                fragments = Some((first_opcode, buffer));
+            }
+            _ => return Err(protocol_error("unexpected opcode")),
+        }
+    }
+}
+
+fn data_message(opcode: u8, payload: Vec<u8>) -> std::io::Result<Message> {
+    if opcode == OPCODE_TEXT && std::str::from_utf8(&payload).is_err() {
+        return Err(protocol_error("text frame is not valid UTF-8"));
+    }
+    Ok(Message::Data)
+}
+
+async fn read_frame<R: AsyncReadExt + Unpin>(
+    reader: &mut R,
+) -> std::io::Result<(bool, u8, Vec<u8>)> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header).await?;
+    let fin = header[0] & 0x80 != 0;
+    let opcode = header[0] & 0x0F;
+    if header[0] & 0x70 != 0 {
+        return Err(protocol_error("reserved bits set"));
+    }
+    if header[1] & 0x80 == 0 {
+        return Err(protocol_error("client frames must be masked"));
+    }
+    let len = match header[1] & 0x7F {
+        126 => reader.read_u16().await? as u64,
+        127 => reader.read_u64().await?,
+        len => len as u64,
+    };
+    if opcode >= OPCODE_CLOSE && (len > 125 || !fin) {
+        return Err(protocol_error("invalid control frame"));
+    }
+    if len > MAX_FRAME_PAYLOAD {
+        return Err(protocol_error("frame too large"));
+    }
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask).await?;
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok((fin, opcode, payload))
+}
+
+pub async fn write_text<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    text: &str,
+) -> std::io::Result<()> {
+    write_frame(writer, OPCODE_TEXT, text.as_bytes()).await
+}
+
+pub async fn write_pong<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    payload: &[u8],
+) -> std::io::Result<()> {
+    write_frame(writer, OPCODE_PONG, payload).await
+}
+
+pub async fn write_close<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    code: u16,
+) -> std::io::Result<()> {
+    write_frame(writer, OPCODE_CLOSE, &code.to_be_bytes()).await
+}
+
+async fn write_frame<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    opcode: u8,
+    payload: &[u8],
+) -> std::io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    if payload.len() < 126 {
+        frame.push(payload.len() as u8);
+    } else if payload.len() <= u16::MAX as usize {
+        frame.push(126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        frame.push(127);
+        frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+fn protocol_error(message: &'static str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn masked_frame(first_byte: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![first_byte];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4]),
+        );
+        frame
+    }
+
+    async fn written(write: impl AsyncFnOnce(&mut Vec<u8>) -> std::io::Result<()>) -> Vec<u8> {
+        let mut frame = Vec::new();
+        write(&mut frame).await.unwrap();
+        frame
+    }
+
+    #[test]
+    fn computes_the_accept_key() {
+        // RFC 6455 section 1.3.
+        assert_eq!(
+            accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_masked_frames() {
+        // RFC 6455 section 5.7, a masked "Hello".
+        let hello = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let (fin, opcode, payload) = read_frame(&mut &hello[..]).await.unwrap();
+        assert!(fin);
+        assert_eq!(opcode, OPCODE_TEXT);
+        assert_eq!(payload, b"Hello");
+        assert!(matches!(
+            read_message(&mut &hello[..]).await,
+            Ok(Message::Data)
+        ));
+
+        // The same frame in two fragments.
+        let mut fragmented = masked_frame(OPCODE_TEXT, b"Hel");
+        fragmented.extend(masked_frame(0x80 | OPCODE_CONTINUATION, b"lo"));
+        assert!(matches!(
+            read_message(&mut &fragmented[..]).await,
+            Ok(Message::Data)
+        ));
+        let invalid_utf8 = masked_frame(0x80 | OPCODE_TEXT, &[0xff]);
+        assert!(read_message(&mut &invalid_utf8[..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_unmasked_frames() {
+        let unmasked = [0x81, 0x05, b'H', b'e', b'l', b'l', b'o'];
+        assert!(read_frame(&mut &unmasked[..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_extended_lengths() {
+        let payload = vec![b'a'; 300];
+        let frame = masked_frame(0x80 | OPCODE_BINARY, &payload);
+        assert_eq!(&frame[1..4], &[0x80 | 126, 0x01, 0x2c]);
+        assert_eq!(read_frame(&mut &frame[..]).await.unwrap().2, payload);
+
+        // A 64-bit length is accepted as long as the payload fits.
+        let mut frame = vec![0x80 | OPCODE_BINARY, 0x80 | 127];
+        frame.extend_from_slice(&300u64.to_be_bytes());
+        frame.extend_from_slice(&masked_frame(0x80 | OPCODE_BINARY, &payload)[4..]);
+        assert_eq!(read_frame(&mut &frame[..]).await.unwrap().2, payload);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversize_frames() {
+        let frame = masked_frame(
+            0x80 | OPCODE_BINARY,
+            &vec![0; MAX_FRAME_PAYLOAD as usize + 1],
+        );
+        assert_eq!(&frame[1..2], &[0x80 | 127]);
+        assert!(read_frame(&mut &frame[..]).await.is_err());
+        // Rejected from the header alone, before reading any payload.
+        let mut huge = vec![0x80 | OPCODE_BINARY, 0x80 | 127];
+        huge.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(read_frame(&mut &huge[..]).await.is_err());
+
+        // So are messages whose fragments add up to too much.
+        let half = vec![0; MAX_FRAME_PAYLOAD as usize / 2 + 1];
+        let mut fragmented = masked_frame(OPCODE_BINARY, &half);
+        fragmented.extend(masked_frame(0x80 | OPCODE_CONTINUATION, &half));
+        assert!(read_message(&mut &fragmented[..]).await.is_err());
+
+        // Control frames carry at most 125 bytes.
+        let ping = masked_frame(0x80 | OPCODE_PING, &[0; 126]);
+        assert!(read_frame(&mut &ping[..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn handles_close_frames() {
+        let close = masked_frame(0x80 | OPCODE_CLOSE, &1000u16.to_be_bytes());
+        assert!(matches!(
+            read_message(&mut &close[..]).await,
+            Ok(Message::Close)
+        ));
+        let fragmented_close = masked_frame(OPCODE_CLOSE, &[]);
+        assert!(read_frame(&mut &fragmented_close[..]).await.is_err());
+        assert_eq!(
+            written(async |frame| write_close(frame, 1000).await).await,
+            [0x88, 0x02, 0x03, 0xe8]
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_unmasked_frames() {
+        assert_eq!(
+            written(async |frame| write_text(frame, "Hello").await).await,
+            [0x81, 0x05, b'H', b'e', b'l', b'l', b'o']
+        );
+        let medium = "a".repeat(300);
+        let frame = written(async |frame| write_text(frame, &medium).await).await;
+        assert_eq!(&frame[..4], &[0x81, 126, 0x01, 0x2c]);
+        assert_eq!(frame.len(), 4 + 300);
+        let long = "a".repeat(70_000);
+        let frame = written(async |frame| write_text(frame, &long).await).await;
+        assert_eq!(&frame[..2], &[0x81, 127]);
+        assert_eq!(&frame[2..10], &70_000u64.to_be_bytes());
+        assert_eq!(frame.len(), 10 + 70_000);
+    }
+}
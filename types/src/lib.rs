@@ -0,0 +1,272 @@
+// The request and response bodies of the tk-auth API, shared by the server
+// and its clients so they can't drift apart. Only serde lives here.
+
+use std::borrow::Cow;
+use std::net::IpAddr;
+
+// The messages of errors that clients may want to tell apart. They are sent
+// as is unless the client asks for another language with Accept-Language.
+pub mod errors {
+    pub const MALFORMED_SESSION_ID: &str = "malformed session id";
+    pub const SESSION_BOUND_ELSEWHERE: &str = "session is bound to a different client";
+    pub const SESSION_NOT_AUTHENTICATED: &str = "session is not authenticated";
+    pub const NOT_AUTHENTICATED: &str = "not authenticated";
+    pub const OVERLOADED: &str = "server is overloaded, try again later";
+    pub const SESSION_STORE_FULL: &str = "the session store is full, try again later";
+    pub const CAPTCHA_REQUIRED: &str = "captcha required";
+    pub const CAPTCHA_INVALID: &str = "captcha verification failed";
+    pub const PROOF_OF_WORK_REQUIRED: &str = "proof of work required";
+    pub const PROOF_OF_WORK_INVALID: &str = "invalid or spent proof of work";
+    pub const IMPOSSIBLE_TRAVEL: &str = "sign-in refused, too far from the previous one";
+    pub const RISK_DENIED: &str = "sign-in refused";
+    pub const TOO_MANY_ATTEMPTS: &str = "too many sign-in attempts, try again later";
+    pub const READ_ONLY: &str = "the server is in read-only maintenance mode";
+    pub const STORAGE_UNAVAILABLE: &str = "storage is unavailable, try again later";
+}
+
+// The `code` of the response envelope, which clients branch on instead of
+// the message. Errors in `errors` have their own, as do a few whose message
+// names the session; the rest get the one for their status.
+pub mod codes {
+    use super::errors;
+
+    pub const OK: &str = "ok";
+    pub const MALFORMED_SESSION_ID: &str = "malformed_session_id";
+    pub const SESSION_BOUND_ELSEWHERE: &str = "session_bound_elsewhere";
+    pub const SESSION_NOT_AUTHENTICATED: &str = "session_not_authenticated";
+    pub const SESSION_NOT_FOUND: &str = "session_not_found";
+    pub const SESSION_EXPIRED: &str = "session_expired";
+    pub const NOT_AUTHENTICATED: &str = "not_authenticated";
+    pub const OVERLOADED: &str = "overloaded";
+    pub const SESSION_STORE_FULL: &str = "session_store_full";
+    pub const CAPTCHA_REQUIRED: &str = "captcha_required";
+    pub const CAPTCHA_INVALID: &str = "captcha_invalid";
+    pub const PROOF_OF_WORK_REQUIRED: &str = "proof_of_work_required";
+    pub const PROOF_OF_WORK_INVALID: &str = "proof_of_work_invalid";
+    pub const IMPOSSIBLE_TRAVEL: &str = "impossible_travel";
+    pub const RISK_DENIED: &str = "risk_denied";
+    pub const TOO_MANY_ATTEMPTS: &str = "too_many_attempts";
+    pub const QUOTA_EXCEEDED: &str = "quota_exceeded";
+    pub const READ_ONLY: &str = "read_only";
+    pub const STORAGE_UNAVAILABLE: &str = "storage_unavailable";
+
+    pub fn for_error(message: &str) -> Option<&'static str> {
+        Some(match message {
+            errors::MALFORMED_SESSION_ID => MALFORMED_SESSION_ID,
+            errors::SESSION_BOUND_ELSEWHERE => SESSION_BOUND_ELSEWHERE,
+            errors::SESSION_NOT_AUTHENTICATED => SESSION_NOT_AUTHENTICATED,
+            errors::NOT_AUTHENTICATED => NOT_AUTHENTICATED,
+            errors::OVERLOADED => OVERLOADED,
+            errors::SESSION_STORE_FULL => SESSION_STORE_FULL,
+            errors::CAPTCHA_REQUIRED => CAPTCHA_REQUIRED,
+            errors::CAPTCHA_INVALID => CAPTCHA_INVALID,
+            errors::PROOF_OF_WORK_REQUIRED => PROOF_OF_WORK_REQUIRED,
+            errors::PROOF_OF_WORK_INVALID => PROOF_OF_WORK_INVALID,
+            errors::IMPOSSIBLE_TRAVEL => IMPOSSIBLE_TRAVEL,
+            errors::RISK_DENIED => RISK_DENIED,
+            errors::TOO_MANY_ATTEMPTS => TOO_MANY_ATTEMPTS,
+            errors::READ_ONLY => READ_ONLY,
+            errors::STORAGE_UNAVAILABLE => STORAGE_UNAVAILABLE,
+            _ => return None,
+        })
+    }
+
+    pub fn for_status(status: u16) -> &'static str {
+        match status {
+            200..=299 => OK,
+            400 => "bad_request",
+            401 => "unauthorized",
+            403 => "forbidden",
+            404 => "not_found",
+            405 => "method_not_allowed",
+            408 => "request_timeout",
+            409 => "conflict",
+            413 => "payload_too_large",
+            415 => "unsupported_media_type",
+            422 => "unprocessable",
+            429 => "too_many_requests",
+            503 => "unavailable",
+            504 => "timeout",
+            _ => "error",
+        }
+    }
+}
+
+// What every JSON response of the API comes wrapped in. `data` is the body
+// of successful responses. Errors only have one when they carry more than
+// their message, like the challenge of a 429 asking for a proof of work.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Envelope<T> {
+    pub code: String,
+    pub message: String,
+    pub data: Option<T>,
+    pub request_id: String,
+}
+
+// The body handlers answer errors with, before it's wrapped in an Envelope.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ErrorBody<'a> {
+    #[serde(borrow)]
+    pub error: Cow<'a, str>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SuccessResponse {
+    pub success: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct NewSessionResponse {
+    pub id_base64: String,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct NewSessionQuery {
+    pub pow_challenge: Option<String>,
+    pub pow_nonce: Option<String>,
+}
+
+// Sent with 429 while new sessions need a proof of work: a nonce such that
+// SHA-256 of "challenge:nonce" starts with difficulty_bits zero bits.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ProofOfWorkChallenge {
+    pub error: String,
+    pub challenge: String,
+    pub difficulty_bits: u8,
+    pub expires_at: u64,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct AuthenticateForm {
+    pub session_id: String,
+    pub user: String,
+    pub password: zeroize::Zeroizing<String>,
+    #[serde(default)]
+    pub remember_me: bool,
+    // The token from the CAPTCHA widget, once the server asks for one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub captcha_token: Option<String>,
+}
+
+impl std::fmt::Debug for AuthenticateForm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthenticateForm")
+            .field("session_id", &"..")
+            .field("user", &self.user)
+            .field("password", &"..")
+            .field("remember_me", &self.remember_me)
+            .field("captcha_token", &self.captcha_token.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+// Sent with 401 when signing in needs a CAPTCHA, with what the client needs
+// to show the widget.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CaptchaChallenge {
+    pub error: String,
+    pub provider: String,
+    pub site_key: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RevokeSessionForm {
+    pub session_id: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct UpdateSessionForm {
+    pub session_id: String,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RevokeAllSessionsForm {
+    pub session_id: String,
+    #[serde(default)]
+    pub include_current: bool,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RevokeAllSessionsResponse {
+    pub success: String,
+    pub revoked: usize,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct TouchSessionForm {
+    pub session_id: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct TouchSessionResponse {
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct GetSessionQuery {
+    pub session_id: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SessionStateQuery {
+    pub session_id: String,
+    #[serde(default)]
+    pub wait: bool,
+    pub timeout: Option<u64>,
+}
+
+// What /api/session_state and /api/session_events report about a session.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SessionState {
+    pub user: Option<String>,
+    pub description: String,
+    pub authenticated: bool,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SessionStateBatchRequest {
+    pub session_ids: Vec<String>,
+}
+
+// One entry per requested id, in the same order. Ids that are malformed,
+// unknown, expired or bound to a different client aren't found.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SessionStateBatchResponse {
+    pub sessions: Vec<SessionStateBatchEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SessionStateBatchEntry {
+    pub found: bool,
+    #[serde(default)]
+    pub state: Option<SessionState>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SessionSummary {
+    pub current: bool,
+    pub description: String,
+    pub ip: IpAddr,
+    pub user_agent: Option<String>,
+    // ISO 3166-1 country code and autonomous system number of the address
+    // the session signed in from, if the server has GeoIP databases.
+    #[serde(default)]
+    pub country: Option<String>,
+    #[serde(default)]
+    pub asn: Option<u32>,
+    pub created: u64,
+    pub last_seen: u64,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SessionList {
+    pub sessions: Vec<SessionSummary>,
+    // Set while there are more sessions after this page.
+    #[serde(default)]
+    pub next_cursor: Option<String>,
+}
@@ -0,0 +1,691 @@
+// Generated by `tk-auth generate-client --lang typescript` from the OpenAPI
+// description. Don't edit it by hand, regenerate it instead.
+
+export class ApiError extends Error {
+	constructor(
+		readonly status: number,
+		readonly error: string,
+		// Machine-readable, unlike the message in `error`.
+		readonly code: string,
+		readonly data: unknown = null
+	) {
+		super(error);
+	}
+}
+
+let baseUrl = '';
+
+export function setBaseUrl(url: string) {
+	baseUrl = url.replace(/\/$/, '');
+}
+
+type Body = { type: string; data: unknown };
+
+async function call(
+	method: string,
+	path: string,
+	query: Record<string, unknown>,
+	headers: Record<string, unknown>,
+	body?: Body
+): Promise<Response> {
+	const search = new URLSearchParams();
+	for (const [name, value] of Object.entries(query)) {
+		if (value !== undefined && value !== null) search.append(name, String(value));
+	}
+	const init: RequestInit = { method, headers: {} };
+	const initHeaders = init.headers as Record<string, string>;
+	for (const [name, value] of Object.entries(headers)) {
+		if (value !== undefined && value !== null) initHeaders[name] = String(value);
+	}
+	if (body) {
+		initHeaders['Content-Type'] = body.type;
+		if (body.type === 'application/json') {
+			init.body = JSON.stringify(body.data);
+		} else if (body.type === 'application/x-www-form-urlencoded') {
+			const form = new URLSearchParams();
+			for (const [name, value] of Object.entries(body.data as object)) {
+				if (value !== undefined && value !== null) form.append(name, String(value));
+			}
+			init.body = form;
+		} else {
+			init.body = String(body.data);
+		}
+	}
+	const queryString = search.toString();
+	return fetch(baseUrl + path + (queryString ? '?' + queryString : ''), init);
+}
+
+async function decode<T>(response: Response): Promise<T> {
+	let envelope: { code?: string; message?: string; data?: unknown } = {};
+	try {
+		envelope = await response.json();
+	} catch {
+		// Not an envelope, like errors from a proxy in front.
+	}
+	if (!response.ok) {
+		throw new ApiError(
+			response.status,
+			envelope.message ?? response.statusText,
+			envelope.code ?? '',
+			envelope.data ?? null
+		);
+	}
+	return envelope.data as T;
+}
+
+export interface AuthenticateForm {
+	/** Token from the CAPTCHA widget, once a 401 asked for one */
+	captcha_token?: string;
+	password: string;
+	/** Also issue a remember-me token, if remember-me is enabled */
+	remember_me?: boolean;
+	session_id: string;
+	user: string;
+}
+
+export interface AuthenticateResponse {
+	/** Id of the authenticated session, which differs from the submitted one when ids are rotated on authentication */
+	id_base64?: string;
+	/** Remember-me token for `/api/session/resume`, also set as a cookie */
+	remember_token?: string;
+	success: string;
+}
+
+export interface BatchSessionsRequest {
+	count?: number;
+	sessions?: Array<{
+		description?: string;
+		user?: string;
+	}>;
+}
+
+export interface BatchSessionsResponse {
+	ids: string[];
+}
+
+export interface Branding {
+	accent_color: string;
+	background_color: string;
+	footer_links: Array<{
+		label: string;
+		url: string;
+	}>;
+	logo_url?: string | null;
+	product_name: string;
+}
+
+export interface CaptchaChallenge {
+	error: string;
+	/** Present when a CAPTCHA is needed */
+	provider?: 'hcaptcha' | 'recaptcha' | 'turnstile';
+	/** Present when a CAPTCHA is needed */
+	site_key?: string;
+}
+
+export interface Deliveries {
+	dead_lettered: number;
+	delivered: number;
+	deliveries: Delivery[];
+	/** Pass as `cursor` to get the next page; null on the last page */
+	next_cursor?: string | null;
+	pending: number;
+}
+
+export interface Delivery {
+	attempts: number;
+	channel: NotificationChannel;
+	created_at: number;
+	event: string;
+	id: string;
+	last_error?: string | null;
+	status: DeliveryStatus;
+	/** When the next attempt is due while pending, else when the notification was delivered or dead-lettered */
+	updated_at: number;
+	user: string;
+}
+
+export type DeliveryStatus = 'pending' | 'delivered' | 'dead_lettered';
+
+export interface DeprecatedRoute {
+	clients: DeprecatedRouteClient[];
+	deprecated: string;
+	last_used: string | null;
+	requests: number;
+	route: string;
+	sunset: string | null;
+}
+
+export interface DeprecatedRouteClient {
+	client: string;
+	first_used: string;
+	last_used: string;
+	requests: number;
+	user_agent: string | null;
+}
+
+export interface Deprecations {
+	routes: DeprecatedRoute[];
+}
+
+export interface DeviceList {
+	devices?: Array<{
+		/** Whether the session asking is on this device */
+		current?: boolean;
+		first_seen?: number;
+		id?: string;
+		last_seen?: number;
+		/** Browser and OS read from the user agent */
+		name?: string;
+		sessions?: number;
+	}>;
+	/** Pass as `cursor` to get the next page; null on the last page */
+	next_cursor?: string | null;
+}
+
+export interface ErrorResponse {
+	/** `ok`, or a machine-readable error code such as `session_not_found`, `quota_exceeded` or, for errors without their own, one for the status such as `bad_request` */
+	code: string;
+	/** Details of errors that have them, null otherwise */
+	data: unknown | null;
+	/** Human-readable, translated with Accept-Language */
+	message: string;
+	/** Also in X-Request-Id; taken from the request's X-Request-Id when that is up to 128 letters, digits, `-`, `_` and `.` */
+	request_id: string;
+}
+
+export interface IdpProviders {
+	providers?: Array<{
+		display_name?: string;
+		id?: string;
+		login_url?: string;
+	}>;
+}
+
+export interface ImportReport {
+	created?: number;
+	dry_run?: boolean;
+	failed?: number;
+	rows?: Array<{
+		error?: string;
+		row?: number;
+		status?: 'created' | 'updated' | 'unchanged' | 'error';
+		user_name?: string;
+	}>;
+	unchanged?: number;
+	updated?: number;
+}
+
+export interface LinkedIdentities {
+	identities?: Array<{
+		linked_at?: number;
+		provider?: string;
+		subject?: string;
+	}>;
+}
+
+export interface Maintenance {
+	compaction: {
+		duration_secs: number;
+		/** Sessions in the new snapshot */
+		sessions: number;
+	} | null;
+	sweep: {
+		duration_secs: number;
+		expired_sessions: number;
+	};
+}
+
+export interface MarkedRead {
+	/** How many notifications were unread before */
+	marked_read: number;
+}
+
+export interface NewSessionResponse {
+	/** URL-safe base64 session id without padding */
+	id_base64: string;
+}
+
+export type NotificationChannel = 'email' | 'webhook' | 'sms' | 'inbox';
+
+export interface NotificationInbox {
+	/** Pass as `cursor` to get the next page; null on the last page */
+	next_cursor?: string | null;
+	notifications: Array<{
+		created_at: number;
+		/** The same fields the webhook gets, such as device_name, ip and revoke_url for new_device */
+		details: unknown;
+		event: string;
+		id: number;
+		read: boolean;
+	}>;
+	unread: number;
+}
+
+export interface NotificationPreferences {
+	preferences: Array<{
+		available_channels: NotificationChannel[];
+		channels: NotificationChannel[];
+		/** Whether channels is the user's choice rather than the default */
+		chosen: boolean;
+		/** new_device, or security_digest, which is only sent once the user chooses email for it */
+		event: string;
+	}>;
+}
+
+export interface NotificationPreferencesUpdate {
+	/** Channels per notification; null goes back to the default */
+	preferences: Record<string, NotificationChannel[] | null>;
+}
+
+export interface PasswordMigration {
+	/** Number of users still on each legacy scheme */
+	legacy?: Record<string, number>;
+	migrated_on_login?: number;
+	pending?: Array<{
+		scheme?: string;
+		user_name?: string;
+	}>;
+	users_with_password?: number;
+}
+
+export interface ProofOfWorkChallenge {
+	challenge: string;
+	difficulty_bits: number;
+	error: string;
+	expires_at: number;
+}
+
+export interface QuotaUsage {
+	client: 'session' | 'key';
+	per_day: QuotaWindow;
+	per_minute: QuotaWindow;
+}
+
+export interface QuotaWindow {
+	limit: number | null;
+	/** Seconds until the window ends */
+	reset_secs: number;
+	used: number;
+}
+
+export interface ReadOnly {
+	message: string | null;
+	read_only: boolean;
+	since: string | null;
+}
+
+export interface Readiness {
+	backends: Array<{
+		backend: 'users' | 'sessions';
+		failing_since: string | null;
+		healthy: boolean;
+	}>;
+	queued_session_changes: number;
+	status: 'ready' | 'degraded';
+}
+
+export interface ResumeSessionForm {
+	remember_token?: string;
+}
+
+export interface RevokeAllSessionsForm {
+	include_current?: boolean;
+	session_id: string;
+}
+
+export interface RevokeAllSessionsResponse {
+	revoked: number;
+	success: string;
+}
+
+export interface RevokeSessionForm {
+	session_id: string;
+}
+
+export interface Session {
+	authenticated: boolean;
+	description: string;
+	/** Unix time at which the session expires, or null if it never does. Sessions with an idle timeout move this forward on every request that uses them. */
+	expires_at: number | null;
+	user: string | null;
+}
+
+export interface SessionEvent {
+	event: 'authenticated' | 'updated' | 'expiring_soon' | 'revoked';
+	/** Present on expiring_soon events, sent sessions.expiry.warn_before_secs before the session expires */
+	expires_at?: number;
+	/** Present on authenticated events */
+	user?: string;
+}
+
+export interface SessionExpiry {
+	expires_at: number | null;
+}
+
+export interface SessionList {
+	/** Pass as `cursor` to get the next page; null on the last page */
+	next_cursor?: string | null;
+	sessions?: Array<{
+		/** Autonomous system signed in from */
+		asn?: number | null;
+		/** ISO 3166-1 code of the country signed in from */
+		country?: string | null;
+		created?: number;
+		/** Whether this is the session asking */
+		current?: boolean;
+		description?: string;
+		expires_at?: number | null;
+		ip?: string;
+		last_seen?: number;
+		user_agent?: string | null;
+	}>;
+}
+
+export interface SessionStateBatchRequest {
+	session_ids: string[];
+}
+
+export interface SessionStateBatchResponse {
+	sessions: Array<{
+		found: boolean;
+		state: Session | null;
+	}>;
+}
+
+export interface SuccessResponse {
+	success: string;
+}
+
+export interface TouchSessionForm {
+	session_id: string;
+}
+
+export interface UnlinkIdentityForm {
+	provider: string;
+	session_id: string;
+	subject: string;
+}
+
+export interface UpdateSessionForm {
+	description: string;
+	session_id: string;
+}
+
+/** List queued notifications */
+export async function listDeliveries(params: { status?: DeliveryStatus; 'If-None-Match'?: string; limit?: number; cursor?: string; order?: 'asc' | 'desc' } = {}): Promise<Deliveries> {
+	return decode(await call('GET', '/api/admin/deliveries', { status: params['status'], limit: params['limit'], cursor: params['cursor'], order: params['order'] }, { 'If-None-Match': params['If-None-Match'] }, undefined));
+}
+
+/** Retry a pending or dead-lettered notification now */
+export async function retryDelivery(params: { id: string }): Promise<Delivery> {
+	return decode(await call('POST', '/api/admin/deliveries/{id}/retry'.replace('{id}', encodeURIComponent(String(params['id']))), {}, {}, undefined));
+}
+
+/** Report use of deprecated routes */
+export async function deprecations(): Promise<Deprecations> {
+	return decode(await call('GET', '/api/admin/deprecations', {}, {}, undefined));
+}
+
+/** Import users in bulk */
+export async function importUsers(params: { dry_run?: boolean; format?: 'csv' | 'json' } = {}, body: unknown[]): Promise<ImportReport> {
+	return decode(await call('POST', '/api/admin/import_users', { dry_run: params['dry_run'], format: params['format'] }, {}, { type: 'application/json', data: body }));
+}
+
+/** Run storage maintenance now */
+export async function runMaintenance(): Promise<Maintenance> {
+	return decode(await call('POST', '/api/admin/maintenance', {}, {}, undefined));
+}
+
+/** Metrics in the Prometheus text format */
+export async function metrics(): Promise<Response> {
+	return call('GET', '/api/admin/metrics', {}, {}, undefined);
+}
+
+/** Report progress of migrating imported password hashes */
+export async function passwordMigration(): Promise<PasswordMigration> {
+	return decode(await call('GET', '/api/admin/password_migration', {}, {}, undefined));
+}
+
+/** Tell whether read-only mode is on */
+export async function getReadOnly(): Promise<ReadOnly> {
+	return decode(await call('GET', '/api/admin/read_only', {}, {}, undefined));
+}
+
+/** Turn read-only mode on or off */
+export async function setReadOnly(body: {
+	/** Shown to users who try to sign in */
+	message?: string;
+	read_only: boolean;
+}): Promise<ReadOnly> {
+	return decode(await call('PUT', '/api/admin/read_only', {}, {}, { type: 'application/json', data: body }));
+}
+
+/** Authenticate a session with user name and password */
+export async function authenticate(params: { 'Idempotency-Key'?: string } = {}, body: AuthenticateForm): Promise<AuthenticateResponse> {
+	return decode(await call('POST', '/api/authenticate', {}, { 'Idempotency-Key': params['Idempotency-Key'] }, { type: 'application/x-www-form-urlencoded', data: body }));
+}
+
+/** Get the configured branding */
+export async function branding(params: { 'If-None-Match'?: string; 'If-Modified-Since'?: string } = {}): Promise<Branding> {
+	return decode(await call('GET', '/api/branding', {}, { 'If-None-Match': params['If-None-Match'], 'If-Modified-Since': params['If-Modified-Since'] }, undefined));
+}
+
+/** List the devices of the current user */
+export async function myDevices(params: { session_id: string; 'If-None-Match'?: string; limit?: number; cursor?: string; order?: 'asc' | 'desc' }): Promise<DeviceList> {
+	return decode(await call('GET', '/api/devices', { session_id: params['session_id'], limit: params['limit'], cursor: params['cursor'], order: params['order'] }, { 'If-None-Match': params['If-None-Match'] }, undefined));
+}
+
+/** Remove a device */
+export async function removeDevice(params: { id: string; session_id: string }): Promise<RevokeAllSessionsResponse> {
+	return decode(await call('DELETE', '/api/devices/{id}'.replace('{id}', encodeURIComponent(String(params['id']))), { session_id: params['session_id'] }, {}, undefined));
+}
+
+/** Check a request for a reverse proxy (forward auth) */
+export async function forwardAuth(): Promise<Response> {
+	return call('GET', '/api/forward_auth', {}, {}, undefined);
+}
+
+/** List the identities linked to the session's user */
+export async function idpIdentities(params: { session_id: string; 'If-None-Match'?: string }): Promise<LinkedIdentities> {
+	return decode(await call('GET', '/api/idp/identities', { session_id: params['session_id'] }, { 'If-None-Match': params['If-None-Match'] }, undefined));
+}
+
+/** List the configured upstream identity providers */
+export async function idpProviders(params: { 'If-None-Match'?: string; 'If-Modified-Since'?: string } = {}): Promise<IdpProviders> {
+	return decode(await call('GET', '/api/idp/providers', {}, { 'If-None-Match': params['If-None-Match'], 'If-Modified-Since': params['If-Modified-Since'] }, undefined));
+}
+
+/** Unlink an identity from the session's user */
+export async function idpUnlink(body: UnlinkIdentityForm): Promise<SuccessResponse> {
+	return decode(await call('POST', '/api/idp/unlink', {}, {}, { type: 'application/x-www-form-urlencoded', data: body }));
+}
+
+/** Finish a login through an upstream identity provider */
+export async function idpCallback(params: { provider: string; state: string; code?: string }): Promise<AuthenticateResponse> {
+	return decode(await call('GET', '/api/idp/{provider}/callback'.replace('{provider}', encodeURIComponent(String(params['provider']))), { state: params['state'], code: params['code'] }, {}, undefined));
+}
+
+/** Start a login through an upstream identity provider */
+export async function idpLogin(params: { provider: string; session_id: string; link?: boolean }): Promise<Response> {
+	return call('GET', '/api/idp/{provider}/login'.replace('{provider}', encodeURIComponent(String(params['provider']))), { session_id: params['session_id'], link: params['link'] }, {}, undefined);
+}
+
+/** Authenticate a session with Kerberos (SPNEGO) */
+export async function negotiate(params: { session_id: string }): Promise<AuthenticateResponse> {
+	return decode(await call('GET', '/api/negotiate', { session_id: params['session_id'] }, {}, undefined));
+}
+
+/** Create a new anonymous session */
+export async function newSession(params: { pow_challenge?: string; pow_nonce?: string; 'Idempotency-Key'?: string } = {}): Promise<NewSessionResponse> {
+	return decode(await call('POST', '/api/new_session', { pow_challenge: params['pow_challenge'], pow_nonce: params['pow_nonce'] }, { 'Idempotency-Key': params['Idempotency-Key'] }, undefined));
+}
+
+/** Show the current user's notification preferences */
+export async function notificationPreferences(params: { session_id: string; 'If-None-Match'?: string }): Promise<NotificationPreferences> {
+	return decode(await call('GET', '/api/notification_preferences', { session_id: params['session_id'] }, { 'If-None-Match': params['If-None-Match'] }, undefined));
+}
+
+/** Choose notification channels */
+export async function updateNotificationPreferences(params: { session_id: string }, body: NotificationPreferencesUpdate): Promise<NotificationPreferences> {
+	return decode(await call('PUT', '/api/notification_preferences', { session_id: params['session_id'] }, {}, { type: 'application/json', data: body }));
+}
+
+/** List the current user's notifications */
+export async function notifications(params: { session_id: string; unread_only?: boolean; 'If-None-Match'?: string; limit?: number; cursor?: string; order?: 'asc' | 'desc' }): Promise<NotificationInbox> {
+	return decode(await call('GET', '/api/notifications', { session_id: params['session_id'], unread_only: params['unread_only'], limit: params['limit'], cursor: params['cursor'], order: params['order'] }, { 'If-None-Match': params['If-None-Match'] }, undefined));
+}
+
+/** Mark all of the current user's notifications as read */
+export async function markAllNotificationsRead(params: { session_id: string }): Promise<MarkedRead> {
+	return decode(await call('POST', '/api/notifications/read_all', { session_id: params['session_id'] }, {}, undefined));
+}
+
+/** Mark a notification as read */
+export async function markNotificationRead(params: { id: number; session_id: string }): Promise<MarkedRead> {
+	return decode(await call('POST', '/api/notifications/{id}/read'.replace('{id}', encodeURIComponent(String(params['id']))), { session_id: params['session_id'] }, {}, undefined));
+}
+
+/** Revoke a session */
+export async function revokeSession(body: RevokeSessionForm): Promise<SuccessResponse> {
+	return decode(await call('POST', '/api/revoke_session', {}, {}, { type: 'application/x-www-form-urlencoded', data: body }));
+}
+
+/** Update a session */
+export async function updateSession(body: UpdateSessionForm): Promise<SuccessResponse> {
+	return decode(await call('PATCH', '/api/session', {}, {}, { type: 'application/x-www-form-urlencoded', data: body }));
+}
+
+/** Resume a session with a remember-me token */
+export async function resumeSession(params: { 'Idempotency-Key'?: string } = {}, body: ResumeSessionForm): Promise<AuthenticateResponse> {
+	return decode(await call('POST', '/api/session/resume', {}, { 'Idempotency-Key': params['Idempotency-Key'] }, { type: 'application/x-www-form-urlencoded', data: body }));
+}
+
+/** Keep a session alive */
+export async function touchSession(body: TouchSessionForm): Promise<SessionExpiry> {
+	return decode(await call('POST', '/api/session/touch', {}, {}, { type: 'application/x-www-form-urlencoded', data: body }));
+}
+
+/** Delete a value stored on a session */
+export async function deleteSessionData(params: { key: string; session_id: string }): Promise<SuccessResponse> {
+	return decode(await call('DELETE', '/api/session_data/{key}'.replace('{key}', encodeURIComponent(String(params['key']))), { session_id: params['session_id'] }, {}, undefined));
+}
+
+/** Read a value stored on a session */
+export async function getSessionData(params: { key: string; session_id: string }): Promise<unknown> {
+	return decode(await call('GET', '/api/session_data/{key}'.replace('{key}', encodeURIComponent(String(params['key']))), { session_id: params['session_id'] }, {}, undefined));
+}
+
+/** Store a value on a session */
+export async function putSessionData(params: { key: string; session_id: string }, body: unknown): Promise<SuccessResponse> {
+	return decode(await call('PUT', '/api/session_data/{key}'.replace('{key}', encodeURIComponent(String(params['key']))), { session_id: params['session_id'] }, {}, { type: 'application/json', data: body }));
+}
+
+/** Stream events of a session over WebSocket */
+export async function sessionEvents(params: { session_id: string }): Promise<Response> {
+	return call('GET', '/api/session_events', { session_id: params['session_id'] }, {}, undefined);
+}
+
+/** Get the state of a session */
+export async function sessionState(params: { session_id: string; wait?: boolean; timeout?: number; 'If-None-Match'?: string }): Promise<Session> {
+	return decode(await call('GET', '/api/session_state', { session_id: params['session_id'], wait: params['wait'], timeout: params['timeout'] }, { 'If-None-Match': params['If-None-Match'] }, undefined));
+}
+
+/** Get the state of many sessions */
+export async function sessionStateBatch(body: SessionStateBatchRequest): Promise<SessionStateBatchResponse> {
+	return decode(await call('POST', '/api/session_state/batch', {}, {}, { type: 'application/json', data: body }));
+}
+
+/** Stream the state of a session as server-sent events */
+export async function sessionStream(params: { session_id: string }): Promise<Response> {
+	return call('GET', '/api/session_stream', { session_id: params['session_id'] }, {}, undefined);
+}
+
+/** Create sessions in bulk */
+export async function batchSessions(body: BatchSessionsRequest): Promise<BatchSessionsResponse> {
+	return decode(await call('POST', '/api/sessions/batch', {}, {}, { type: 'application/json', data: body }));
+}
+
+/** List the sessions of the current user */
+export async function mySessions(params: { session_id: string; 'If-None-Match'?: string; limit?: number; cursor?: string; order?: 'asc' | 'desc' }): Promise<SessionList> {
+	return decode(await call('GET', '/api/sessions/mine', { session_id: params['session_id'], limit: params['limit'], cursor: params['cursor'], order: params['order'] }, { 'If-None-Match': params['If-None-Match'] }, undefined));
+}
+
+/** Log out everywhere */
+export async function revokeAllSessions(body: RevokeAllSessionsForm): Promise<RevokeAllSessionsResponse> {
+	return decode(await call('POST', '/api/sessions/revoke_all', {}, {}, { type: 'application/x-www-form-urlencoded', data: body }));
+}
+
+/** Get the request quota usage of the caller */
+export async function usage(params: { session_id?: string } = {}): Promise<QuotaUsage> {
+	return decode(await call('GET', '/api/usage', { session_id: params['session_id'] }, {}, undefined));
+}
+
+/** Tell whether this instance is ready to serve */
+export async function readiness(): Promise<Readiness> {
+	return decode(await call('GET', '/readyz', {}, {}, undefined));
+}
+
+/** List groups */
+export async function listScimGroups(params: { 'If-None-Match'?: string } = {}): Promise<Response> {
+	return call('GET', '/scim/v2/Groups', {}, { 'If-None-Match': params['If-None-Match'] }, undefined);
+}
+
+/** Create a group */
+export async function createScimGroup(): Promise<Response> {
+	return call('POST', '/scim/v2/Groups', {}, {}, undefined);
+}
+
+/** Delete a group */
+export async function deleteScimGroup(params: { id: string }): Promise<Response> {
+	return call('DELETE', '/scim/v2/Groups/{id}'.replace('{id}', encodeURIComponent(String(params['id']))), {}, {}, undefined);
+}
+
+/** Get a group */
+export async function getScimGroup(params: { id: string; 'If-None-Match'?: string }): Promise<Response> {
+	return call('GET', '/scim/v2/Groups/{id}'.replace('{id}', encodeURIComponent(String(params['id']))), {}, { 'If-None-Match': params['If-None-Match'] }, undefined);
+}
+
+/** Modify a group with a PatchOp request */
+export async function patchScimGroup(params: { id: string }): Promise<Response> {
+	return call('PATCH', '/scim/v2/Groups/{id}'.replace('{id}', encodeURIComponent(String(params['id']))), {}, {}, undefined);
+}
+
+/** Replace a group */
+export async function replaceScimGroup(params: { id: string }): Promise<Response> {
+	return call('PUT', '/scim/v2/Groups/{id}'.replace('{id}', encodeURIComponent(String(params['id']))), {}, {}, undefined);
+}
+
+/** Describe the supported SCIM features */
+export async function getScimServiceProviderConfig(params: { 'If-None-Match'?: string; 'If-Modified-Since'?: string } = {}): Promise<Response> {
+	return call('GET', '/scim/v2/ServiceProviderConfig', {}, { 'If-None-Match': params['If-None-Match'], 'If-Modified-Since': params['If-Modified-Since'] }, undefined);
+}
+
+/** List users */
+export async function listScimUsers(params: { 'If-None-Match'?: string } = {}): Promise<Response> {
+	return call('GET', '/scim/v2/Users', {}, { 'If-None-Match': params['If-None-Match'] }, undefined);
+}
+
+/** Provision a user */
+export async function createScimUser(): Promise<Response> {
+	return call('POST', '/scim/v2/Users', {}, {}, undefined);
+}
+
+/** Deprovision a user */
+export async function deleteScimUser(params: { id: string }): Promise<Response> {
+	return call('DELETE', '/scim/v2/Users/{id}'.replace('{id}', encodeURIComponent(String(params['id']))), {}, {}, undefined);
+}
+
+/** Get a user */
+export async function getScimUser(params: { id: string; 'If-None-Match'?: string }): Promise<Response> {
+	return call('GET', '/scim/v2/Users/{id}'.replace('{id}', encodeURIComponent(String(params['id']))), {}, { 'If-None-Match': params['If-None-Match'] }, undefined);
+}
+
+/** Modify a user with a PatchOp request */
+export async function patchScimUser(params: { id: string }): Promise<Response> {
+	return call('PATCH', '/scim/v2/Users/{id}'.replace('{id}', encodeURIComponent(String(params['id']))), {}, {}, undefined);
+}
+
+/** Replace a user */
+export async function replaceScimUser(params: { id: string }): Promise<Response> {
+	return call('PUT', '/scim/v2/Users/{id}'.replace('{id}', encodeURIComponent(String(params['id']))), {}, {}, undefined);
+}
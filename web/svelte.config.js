@@ -11,7 +11,12 @@ const config = {
 		// adapter-auto only supports some environments, see https://svelte.dev/docs/kit/adapter-auto for a list.
 		// If your environment is not supported, or you settled on a specific environment, switch out the adapter.
 		// See https://svelte.dev/docs/kit/adapters for more information about adapters.
-		adapter: adapter()
+		// precompress writes .br and .gz next to each file, which the server
+		// hands out to clients that accept them (web.precompressed).
+		adapter: adapter({ precompress: true }),
+		// tk-auth serves the app under /web, whether from build/ or, with
+		// --dev-proxy, from the dev server.
+		paths: { base: '/web' }
 	}
 };
 
@@ -2,5 +2,10 @@ import { sveltekit } from '@sveltejs/kit/vite';
 import { defineConfig } from 'vite';
 
 export default defineConfig({
-	plugins: [sveltekit()]
+	plugins: [sveltekit()],
+	server: {
+		// tk-auth --dev-proxy doesn't forward WebSockets, so the browser
+		// connects to the dev server directly for hot reloading.
+		hmr: { clientPort: 5173 }
+	}
 });